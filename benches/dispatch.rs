@@ -0,0 +1,136 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Benchmark of the receive hot path: remote-to-local ID translation plus dispatch,
+//! with few and many registered types.
+//!
+//! Run with `cargo bench --features vrpn-async-std --bench dispatch`.
+//! The time per message should not grow with the number of registered types.
+
+extern crate bytes;
+extern crate vrpn;
+
+use bytes::Bytes;
+use std::{
+    hint::black_box,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use vrpn::{
+    data_types::{
+        id_types::{MessageTypeId, RemoteId, SenderId},
+        ClassOfService, Description, GenericBody, GenericMessage, Message, MessageHeader,
+    },
+    handle_system_command,
+    handler::HandlerCode,
+    translation_table::TranslationTables,
+    Endpoint, EndpointGeneric, Handler, Result, SystemCommand, TypeDispatcher,
+};
+
+const ITERATIONS: usize = 200_000;
+
+/// An endpoint that only translates, never sends.
+struct NullEndpoint {
+    translation: TranslationTables,
+}
+
+impl Endpoint for NullEndpoint {
+    fn translation_tables(&self) -> &TranslationTables {
+        &self.translation
+    }
+    fn translation_tables_mut(&mut self) -> &mut TranslationTables {
+        &mut self.translation
+    }
+    fn send_system_change(&self, _message: SystemCommand) -> Result<()> {
+        Ok(())
+    }
+    fn buffer_generic_message(
+        &mut self,
+        _msg: GenericMessage,
+        _class: ClassOfService,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct CountingHandler(Arc<AtomicUsize>);
+
+impl Handler for CountingHandler {
+    fn handle(&mut self, _msg: &GenericMessage) -> Result<HandlerCode> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
+/// Set up a dispatcher and endpoint as if the remote had described `num_types` types,
+/// with a handler on each, and return remote-ID messages cycling through all of them.
+fn setup(
+    num_types: i32,
+    count: &Arc<AtomicUsize>,
+) -> Result<(TypeDispatcher, NullEndpoint, Vec<GenericMessage>)> {
+    let mut dispatcher = TypeDispatcher::new();
+    let mut endpoint = NullEndpoint {
+        translation: TranslationTables::new(),
+    };
+    let sender = SenderId(0);
+    handle_system_command(
+        &mut dispatcher,
+        endpoint.translation_tables_mut(),
+        SystemCommand::SenderDescription(Description::from_id_and_name(
+            sender,
+            Bytes::from_static(b"Bench0"),
+        )),
+    )?;
+    let mut messages = Vec::new();
+    for i in 0..num_types {
+        let name = Bytes::from(format!("vrpn_Bench type {}", i));
+        let remote_type = MessageTypeId(i);
+        handle_system_command(
+            &mut dispatcher,
+            endpoint.translation_tables_mut(),
+            SystemCommand::TypeDescription(Description::from_id_and_name(remote_type, name)),
+        )?;
+        let local_type = endpoint
+            .map_to_local_id(RemoteId(remote_type))
+            .expect("type was just described");
+        dispatcher.add_handler(
+            Box::new(CountingHandler(Arc::clone(count))),
+            Some(local_type),
+            None,
+        )?;
+        messages.push(GenericMessage::from_header_and_body(
+            MessageHeader::new(None, remote_type, sender),
+            GenericBody::new(Bytes::from_static(b"payload")),
+        ));
+    }
+    Ok((dispatcher, endpoint, messages))
+}
+
+fn bench(num_types: i32) -> Result<Duration> {
+    let count = Arc::new(AtomicUsize::new(0));
+    let (mut dispatcher, endpoint, messages) = setup(num_types, &count)?;
+    let start = Instant::now();
+    for msg in messages.iter().cycle().take(ITERATIONS) {
+        let msg = endpoint.map_remote_message_to_local(black_box(msg.clone()))?;
+        dispatcher.call(&msg)?;
+    }
+    let elapsed = start.elapsed();
+    assert_eq!(count.load(Ordering::Relaxed), ITERATIONS);
+    Ok(elapsed / ITERATIONS as u32)
+}
+
+fn main() -> Result<()> {
+    for &num_types in &[5, 50, 500] {
+        let per_message = bench(num_types)?;
+        println!(
+            "translate + dispatch, {:>3} registered types: {:>6} ns/message",
+            num_types,
+            per_message.as_nanos()
+        );
+    }
+    Ok(())
+}
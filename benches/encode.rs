@@ -0,0 +1,84 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Benchmark of the send hot path: appending serialized messages to an outgoing buffer.
+//!
+//! Run with `cargo bench --features vrpn-async-std --bench encode`.
+//! Compares serializing each message to its own buffer and copying that in,
+//! with serializing straight into the outgoing buffer's spare capacity.
+//! The difference grows with the body size: the large case is the size of an imager region.
+
+extern crate bytes;
+extern crate vrpn;
+
+use bytes::{Bytes, BytesMut};
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+use vrpn::{
+    buffer_unbuffer::BytesMutExtras,
+    data_types::{
+        id_types::{MessageTypeId, SenderId, SequenceNumber},
+        GenericBody, GenericMessage, Message, MessageHeader, SequencedGenericMessage,
+    },
+    Result,
+};
+
+/// Total body bytes to encode per case, so each case does comparable work.
+const TOTAL_BYTES: usize = 512 * 1024 * 1024;
+
+/// Messages are flushed (the buffer cleared) once it holds this much.
+const FLUSH_THRESHOLD: usize = 256 * 1024;
+
+fn message(body_len: usize) -> SequencedGenericMessage {
+    GenericMessage::from_header_and_body(
+        MessageHeader::new(None, MessageTypeId(1), SenderId(2)),
+        GenericBody::new(Bytes::from(vec![0xa5u8; body_len])),
+    )
+    .into_sequenced_message(SequenceNumber(0))
+}
+
+fn bench(
+    body_len: usize,
+    encode: impl Fn(&mut BytesMut, &SequencedGenericMessage) -> Result<()>,
+) -> Result<Duration> {
+    let msg = message(body_len);
+    let iterations = (TOTAL_BYTES / body_len).max(1);
+    let mut buf = BytesMut::with_capacity(FLUSH_THRESHOLD * 2);
+    let start = Instant::now();
+    for _ in 0..iterations {
+        encode(&mut buf, black_box(&msg))?;
+        if buf.len() >= FLUSH_THRESHOLD {
+            black_box(&buf[..]);
+            buf.clear();
+        }
+    }
+    Ok(start.elapsed() / iterations as u32)
+}
+
+fn copy_in(buf: &mut BytesMut, msg: &SequencedGenericMessage) -> Result<()> {
+    buf.extend_from_slice(&msg.clone().try_into_buf()?);
+    Ok(())
+}
+
+fn direct(buf: &mut BytesMut, msg: &SequencedGenericMessage) -> Result<()> {
+    buf.reserve_and_buffer(msg)?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    for &body_len in &[64, 4 * 1024, 64 * 1024] {
+        let copied = bench(body_len, copy_in)?;
+        let written = bench(body_len, direct)?;
+        println!(
+            "{:>6} byte body: {:>7} ns/message via temporary buffer, {:>7} ns/message direct ({:.2}x)",
+            body_len,
+            copied.as_nanos(),
+            written.as_nanos(),
+            copied.as_secs_f64() / written.as_secs_f64()
+        );
+    }
+    Ok(())
+}
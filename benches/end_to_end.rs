@@ -0,0 +1,229 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Benchmark of end-to-end latency and throughput, from a server writing analog reports
+//! to a client application receiving them, in-process over loopback TCP.
+//!
+//! Run with `cargo bench --features vrpn-async-std,analog --bench end_to_end`.
+//! Each case streams reports of one size, either paced at a fixed rate or as fast as possible.
+//! The client is a `ThreadedConnection`, so latency includes handing each report over
+//! to the application thread.
+//!
+//! To track regressions, also write the results as JSON:
+//! `cargo bench --features vrpn-async-std,analog --bench end_to_end -- --report results.json`
+
+extern crate async_std;
+extern crate vrpn;
+
+use async_std::{net::TcpListener, task};
+use futures::SinkExt;
+use std::{
+    convert::TryFrom,
+    env, fs,
+    sync::{mpsc, Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+use vrpn::{
+    analog::AnalogReport,
+    data_types::{id_types::SequenceNumber, GenericMessage, TypedMessage, TypedMessageBody},
+    handshake::{futures_io::perform_handshake, Handshake},
+    subscription::{Subscription, SubscriptionState},
+    vrpn_async::AsyncWriteMessagesExt,
+    vrpn_async_std::{connection_ip::ConnectionIp, threaded::ThreadedConnection},
+    Connection, Result, ServerInfo, TypeDispatcher, VrpnError,
+};
+
+const MESSAGES: usize = 2_000;
+const DEVICE: &str = "Analog0";
+
+#[derive(Debug, Clone, Copy)]
+struct Case {
+    channels: usize,
+    /// Messages per second, or as fast as possible.
+    rate: Option<u32>,
+}
+
+#[derive(Debug)]
+struct Results {
+    case: Case,
+    message_bytes: usize,
+    received: usize,
+    p50: Duration,
+    p99: Duration,
+    max: Duration,
+    messages_per_sec: f64,
+}
+
+/// Accept one client, describe the device, and once told to go, stream reports,
+/// recording when each was written. The first channel of each report is its index.
+async fn serve(
+    listener: TcpListener,
+    case: Case,
+    go: mpsc::Receiver<()>,
+    sent: Arc<Mutex<Vec<Instant>>>,
+) -> Result<()> {
+    let (mut stream, _) = listener.accept().await?;
+    stream.set_nodelay(true)?;
+    let mut dispatcher = TypeDispatcher::new();
+    let sender = dispatcher.register_sender(DEVICE)?.into_inner();
+    let message_type = dispatcher.type_id_for(AnalogReport::MESSAGE_IDENTIFIER)?;
+    let mut handshake = Handshake::server().with_descriptions(dispatcher.pack_all_descriptions()?);
+    perform_handshake(&mut stream, &mut handshake).await?;
+    go.recv()
+        .map_err(|e| VrpnError::OtherMessage(e.to_string()))?;
+
+    let mut sink = stream.clone().message_sink();
+    let interval = case.rate.map(|rate| Duration::from_secs(1) / rate);
+    let start = Instant::now();
+    for i in 0..MESSAGES {
+        if let Some(interval) = interval {
+            let due = start + interval * i as u32;
+            let now = Instant::now();
+            if due > now {
+                task::sleep(due - now).await;
+            }
+        }
+        let mut values = vec![0.0; case.channels];
+        values[0] = i as f64;
+        let msg = TypedMessage::new(None, message_type, sender, AnalogReport { values });
+        let msg = GenericMessage::try_from(msg)?.into_sequenced_message(SequenceNumber(i as u32));
+        sent.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Instant::now());
+        sink.send(msg).await?;
+    }
+    // Stay open until the client has had time to receive everything.
+    task::sleep(Duration::from_millis(500)).await;
+    Ok(())
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    sorted[((sorted.len() - 1) as f64 * p) as usize]
+}
+
+fn run(case: Case) -> Result<Results> {
+    let listener = task::block_on(TcpListener::bind("127.0.0.1:0"))?;
+    let server_info = format!("tcp://{}", listener.local_addr()?).parse::<ServerInfo>()?;
+    let (go_tx, go) = mpsc::channel();
+    let sent = Arc::new(Mutex::new(Vec::with_capacity(MESSAGES)));
+    let server = {
+        let sent = Arc::clone(&sent);
+        thread::spawn(move || task::block_on(serve(listener, case, go, sent)))
+    };
+
+    let connection = ConnectionIp::new_client(server_info, None, None)?;
+    let sender = connection.register_sender(DEVICE)?;
+    let threaded = ThreadedConnection::spawn(connection)?;
+    let (rx, _) = threaded.typed_channel::<AnalogReport>(Some(sender))?;
+    // Only start once the device has been described.
+    let described = Subscription::<AnalogReport>::new(threaded.connection(), DEVICE)?;
+    while described.state() == SubscriptionState::Waiting {
+        thread::sleep(Duration::from_millis(1));
+    }
+    drop(described);
+    go_tx
+        .send(())
+        .map_err(|e| VrpnError::OtherMessage(e.to_string()))?;
+
+    let mut received = Vec::with_capacity(MESSAGES);
+    let mut message_bytes = 0;
+    while received.len() < MESSAGES {
+        let msg = match rx.recv_timeout(Duration::from_secs(2)) {
+            Ok(msg) => msg,
+            Err(_) => break,
+        };
+        let now = Instant::now();
+        message_bytes = GenericMessage::try_from(&msg)?
+            .into_sequenced_message(SequenceNumber(0))
+            .try_into_buf()?
+            .len();
+        received.push((msg.body.values[0] as usize, now));
+    }
+    server
+        .join()
+        .map_err(|_| VrpnError::OtherMessage("server thread panicked".to_string()))??;
+    threaded.stop()?;
+
+    let sent = sent.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut latencies: Vec<Duration> = received
+        .iter()
+        .filter_map(|&(i, at)| sent.get(i).map(|&sent| at - sent))
+        .collect();
+    if latencies.is_empty() {
+        return Err(VrpnError::OtherMessage("nothing received".to_string()));
+    }
+    latencies.sort();
+    let elapsed = received[received.len() - 1].1 - sent[0];
+    Ok(Results {
+        case,
+        message_bytes,
+        received: received.len(),
+        p50: percentile(&latencies, 0.5),
+        p99: percentile(&latencies, 0.99),
+        max: latencies[latencies.len() - 1],
+        messages_per_sec: received.len() as f64 / elapsed.as_secs_f64(),
+    })
+}
+
+fn to_json(results: &[Results]) -> String {
+    let cases: Vec<String> = results
+        .iter()
+        .map(|r| {
+            format!(
+                "    {{\"channels\": {}, \"message_bytes\": {}, \"rate\": {}, \"sent\": {}, \
+                 \"received\": {}, \"p50_ns\": {}, \"p99_ns\": {}, \"max_ns\": {}, \
+                 \"messages_per_sec\": {:.1}}}",
+                r.case.channels,
+                r.message_bytes,
+                r.case
+                    .rate
+                    .map_or_else(|| "null".to_string(), |r| r.to_string()),
+                MESSAGES,
+                r.received,
+                r.p50.as_nanos(),
+                r.p99.as_nanos(),
+                r.max.as_nanos(),
+                r.messages_per_sec
+            )
+        })
+        .collect();
+    format!(
+        "{{\n  \"benchmark\": \"end_to_end\",\n  \"cases\": [\n{}\n  ]\n}}\n",
+        cases.join(",\n")
+    )
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let report = args
+        .iter()
+        .position(|arg| arg == "--report")
+        .and_then(|i| args.get(i + 1));
+
+    let mut results = Vec::new();
+    for &channels in &[1, 16, 128] {
+        for &rate in &[Some(1000), None] {
+            let r = run(Case { channels, rate })?;
+            println!(
+                "{:>3} channels ({:>4} bytes), rate {:>5}: received {}/{}, latency median {:>7} ns, \
+                 p99 {:>8} ns, max {:>8} ns, {:>9.1} messages/s",
+                channels,
+                r.message_bytes,
+                rate.map_or_else(|| "max".to_string(), |rate| rate.to_string()),
+                r.received,
+                MESSAGES,
+                r.p50.as_nanos(),
+                r.p99.as_nanos(),
+                r.max.as_nanos(),
+                r.messages_per_sec
+            );
+            results.push(r);
+        }
+    }
+    if let Some(path) = report {
+        fs::write(path, to_json(&results))?;
+    }
+    Ok(())
+}
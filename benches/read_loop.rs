@@ -0,0 +1,95 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Benchmark of receive latency over loopback TCP, with and without busy-polling.
+//!
+//! Run with `cargo bench --features vrpn-async-std --bench read_loop`.
+//! Each round trip sends a message to an echo thread and waits for it to be decoded.
+//! Busy-polling should lower the latency (most visibly in the tail),
+//! at the cost of a spinning core.
+
+extern crate async_std;
+extern crate vrpn;
+
+use async_std::{net::TcpStream, task::block_on};
+use futures::{AsyncWriteExt, StreamExt};
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    thread,
+    time::{Duration, Instant},
+};
+use vrpn::{vrpn_async::MessageStream, Result, VrpnError};
+
+const ROUND_TRIPS: usize = 5_000;
+
+// A sender description message, 40 bytes
+const MSG: [u8; 40] = [
+    0x00, 0x00, 0x00, 0x25, 0x5b, 0xeb, 0x33, 0x2e, 0x00, 0x0c, 0x58, 0xb1, 0x00, 0x00, 0x00, 0x01,
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x09, 0x54, 0x72, 0x61, 0x63,
+    0x6b, 0x65, 0x72, 0x30, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Echo everything back on the first connection.
+fn spawn_echo(listener: TcpListener) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let (mut sock, _) = listener.accept().expect("accept failed");
+        sock.set_nodelay(true).expect("could not set nodelay");
+        let mut buf = [0u8; 1024];
+        loop {
+            match sock.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => {
+                    if sock.write_all(&buf[..n]).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn bench(busy_poll: Option<Duration>) -> Result<Vec<Duration>> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let echo = spawn_echo(listener);
+    let samples = block_on(async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let mut messages = MessageStream::new(stream.clone()).with_busy_poll(busy_poll);
+        let mut samples = Vec::with_capacity(ROUND_TRIPS);
+        for _ in 0..ROUND_TRIPS {
+            let start = Instant::now();
+            stream.write_all(&MSG).await?;
+            messages.next().await.ok_or(VrpnError::EndpointClosed)??;
+            samples.push(start.elapsed());
+        }
+        Ok::<_, VrpnError>(samples)
+    })?;
+    // Closing the socket ends the echo thread.
+    echo.join().expect("echo thread panicked");
+    Ok(samples)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    sorted[((sorted.len() - 1) as f64 * p) as usize]
+}
+
+fn main() -> Result<()> {
+    for &busy_poll in &[
+        None,
+        Some(Duration::from_micros(50)),
+        Some(Duration::from_micros(500)),
+    ] {
+        let mut samples = bench(busy_poll)?;
+        samples.sort();
+        println!(
+            "busy poll {:>8}: round trip median {:>6} ns, p99 {:>7} ns",
+            busy_poll.map_or_else(|| "off".to_string(), |d| format!("{:?}", d)),
+            percentile(&samples, 0.5).as_nanos(),
+            percentile(&samples, 0.99).as_nanos()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,200 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! A custom device class, "Wand", written outside the crate with only its public API:
+//! a message body, a server streaming it, and a client subscribing to it.
+//!
+//! Run with `cargo run --features vrpn-async-std --example custom_device`.
+//! Both sides run in this process, over loopback TCP: the server sends a few reports
+//! and the client prints them as they arrive.
+
+extern crate async_std;
+extern crate bytes;
+extern crate vrpn;
+
+use async_std::{net::TcpListener, task};
+use bytes::{Buf, BufMut};
+use futures::{SinkExt, StreamExt};
+use std::{convert::TryFrom, time::Duration};
+use vrpn::{
+    buffer_unbuffer::{
+        check_buffer_remaining, check_unbuffer_remaining, BufferResult, BufferTo,
+        ConstantBufferSize, UnbufferFrom, UnbufferResult,
+    },
+    data_types::{
+        id_types::SequenceNumber, GenericMessage, MessageTypeIdentifier, Quat,
+        StaticMessageTypeName, TypedMessage, TypedMessageBody, Vec3,
+    },
+    handshake::{futures_io, Handshake},
+    subscription::{Subscription, SubscriptionEvent},
+    vrpn_async::AsyncWriteMessagesExt,
+    vrpn_async_std::connection_ip::{ConnectionIp, ConnectionIpStream},
+    Result, ServerInfo, TypeDispatcher,
+};
+
+const DEVICE: &str = "Wand0";
+const REPORTS: u32 = 5;
+
+/// The state of a wand: `Wand Report`.
+///
+/// Both ends find the message type by this name, so it must be unique to the layout:
+/// change the name if the layout changes.
+///
+/// # Wire format
+///
+/// Position and orientation as in `vrpn_Tracker Pos_Quat`, trigger as `f64`,
+/// then wand index, button bit mask, and low battery flag (1 or 0), as `i32`.
+/// Fields are big-endian, and the `f64`s come first to keep them 8-byte aligned.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WandReport {
+    pub pos: Vec3,
+    pub quat: Quat,
+    /// How far the trigger is pulled, from 0 to 1.
+    pub trigger: f64,
+    pub wand: i32,
+    /// Bit `i` is set while button `i` is pressed.
+    pub buttons: u32,
+    pub battery_low: bool,
+}
+
+/// The message type name: the part of a message the remote end identifies it by.
+const WAND_REPORT: StaticMessageTypeName = StaticMessageTypeName(b"Wand Report");
+
+impl TypedMessageBody for WandReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(WAND_REPORT);
+}
+
+// The body has the same size every time, so `ConstantBufferSize` provides `BufferSize`.
+impl ConstantBufferSize for WandReport {
+    fn constant_buffer_size() -> usize {
+        Vec3::constant_buffer_size()
+            + Quat::constant_buffer_size()
+            + f64::constant_buffer_size()
+            + i32::constant_buffer_size() * 3
+    }
+}
+
+impl BufferTo for WandReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        // Check the space for the whole body up front, so nothing is half-written.
+        check_buffer_remaining(buf, Self::constant_buffer_size())?;
+        self.pos.buffer_to(buf)?;
+        self.quat.buffer_to(buf)?;
+        self.trigger.buffer_to(buf)?;
+        self.wand.buffer_to(buf)?;
+        self.buttons.buffer_to(buf)?;
+        i32::from(self.battery_low).buffer_to(buf)
+    }
+}
+
+impl UnbufferFrom for WandReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        // Errors, rather than panics, on a short body.
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        Ok(WandReport {
+            pos: Vec3::unbuffer_from(buf)?,
+            quat: Quat::unbuffer_from(buf)?,
+            trigger: f64::unbuffer_from(buf)?,
+            wand: i32::unbuffer_from(buf)?,
+            buttons: u32::unbuffer_from(buf)?,
+            battery_low: i32::unbuffer_from(buf)? != 0,
+        })
+    }
+}
+
+/// The server side: accept one client, describe the device, then stream reports.
+async fn serve(listener: TcpListener) -> Result<()> {
+    let (mut stream, _) = listener.accept().await?;
+    stream.set_nodelay(true)?;
+
+    // Register the device and message type, so the handshake can describe them:
+    // the client maps them to its own IDs by name.
+    let mut dispatcher = TypeDispatcher::new();
+    let sender = dispatcher.register_sender(DEVICE)?.into_inner();
+    let message_type = dispatcher.type_id_for(WandReport::MESSAGE_IDENTIFIER)?;
+    let handshake = Handshake::server().with_descriptions(dispatcher.pack_all_descriptions()?);
+    // Don't wait forever on a client that connects but says nothing.
+    futures_io::perform_with_timeout(&mut stream, handshake, Some(Duration::from_secs(5))).await?;
+    // Give the client a moment to see the descriptions and subscribe.
+    task::sleep(Duration::from_millis(100)).await;
+
+    let mut sink = stream.message_sink();
+    for i in 0..REPORTS {
+        let report = WandReport {
+            pos: Vec3::new(0.0, 1.5, -0.1 * f64::from(i)),
+            quat: Quat::identity(),
+            trigger: f64::from(i) / f64::from(REPORTS - 1),
+            wand: 0,
+            buttons: 1 << (i % 3),
+            battery_low: i == REPORTS - 1,
+        };
+        // A time of None stamps it with the current time.
+        let msg = GenericMessage::try_from(TypedMessage::new(None, message_type, sender, report))?;
+        sink.send(msg.into_sequenced_message(SequenceNumber(i)))
+            .await?;
+        task::sleep(Duration::from_millis(10)).await;
+    }
+    // Stay open until the client has had time to receive everything.
+    task::sleep(Duration::from_millis(200)).await;
+    Ok(())
+}
+
+/// The client side: subscribe to the device and print its reports.
+async fn client(server: ServerInfo) -> Result<()> {
+    let connection = ConnectionIp::new_client(server, None, None)?;
+    // Subscribing registers the message type, so received messages of it decode as `WandReport`.
+    let mut subscription = Subscription::<WandReport>::new(&connection, DEVICE)?;
+
+    // Something must drive the connection for anything to be received.
+    task::spawn(async move {
+        let mut stream = ConnectionIpStream::new(connection);
+        while let Some(result) = stream.next().await {
+            if let Err(e) = result {
+                eprintln!("Connection error: {}", e);
+                break;
+            }
+        }
+    });
+
+    let mut received = 0;
+    while received < REPORTS {
+        match subscription.next().await {
+            Some(SubscriptionEvent::Message(msg)) => {
+                let report = msg.body;
+                println!(
+                    "{} at {}: pos ({:.2}, {:.2}, {:.2}), trigger {:.2}, buttons {:03b}{}",
+                    DEVICE,
+                    msg.header.time,
+                    report.pos.x,
+                    report.pos.y,
+                    report.pos.z,
+                    report.trigger,
+                    report.buttons,
+                    if report.battery_low {
+                        ", battery low"
+                    } else {
+                        ""
+                    }
+                );
+                received += 1;
+            }
+            Some(event) => println!("{}: {:?}", DEVICE, event),
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+async fn async_main() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let server = format!("tcp://{}", listener.local_addr()?).parse::<ServerInfo>()?;
+    let serving = task::spawn(serve(listener));
+    client(server).await?;
+    serving.await
+}
+
+fn main() -> Result<()> {
+    task::block_on(async_main())
+}
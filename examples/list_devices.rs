@@ -0,0 +1,34 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Lists the devices a server offers, like `vrpn_print_devices --list`.
+//!
+//! Run with, e.g.,
+//! `cargo run --features vrpn-async-std --example list_devices -- tcp://localhost:3883`
+
+extern crate async_std;
+extern crate vrpn;
+
+use async_std::task;
+use std::time::Duration;
+use vrpn::{vrpn_async_std::connection_ip::ConnectionIp, Result, ServerInfo};
+
+/// How long to wait for the server to describe its devices.
+const LIST_WINDOW: Duration = Duration::from_secs(2);
+
+async fn async_main() -> Result<()> {
+    let server = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "tcp://127.0.0.1:3883".to_string())
+        .parse::<ServerInfo>()?;
+    let connection = ConnectionIp::new_client(server, None, None)?;
+    for name in connection.list_devices(LIST_WINDOW).await? {
+        println!("{}", String::from_utf8_lossy(&name.0));
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    task::block_on(async_main())
+}
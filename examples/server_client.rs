@@ -0,0 +1,141 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! A server and a client in one process, connected over loopback TCP:
+//! the server streams synthetic poses from a tracker, and the client checks it gets them all.
+//!
+//! Run with `cargo run --features vrpn-async-std --example server_client`.
+//! Also run by `cargo test --features vrpn-async-std --examples`, as a smoke test
+//! of the whole path from `Connection::pack_message_body` to a `Subscription`.
+
+extern crate async_std;
+extern crate vrpn;
+
+use async_std::{future::timeout, net::TcpListener, task};
+use futures::StreamExt;
+use std::{sync::Arc, time::Duration};
+use vrpn::{
+    data_types::{id_types::Sensor, ClassOfService, Quat, Vec3},
+    device::DeviceExt,
+    subscription::SubscriptionEvent,
+    tracker::{PoseReport, Tracker},
+    vrpn_async_std::connection_ip::{ConnectionIp, ConnectionIpStream},
+    Connection, Result, ServerInfo, VrpnError,
+};
+
+const DEVICE: &str = "Tracker0";
+const SENSORS: i32 = 2;
+const FRAMES: u32 = 20;
+/// How long the client waits for all the poses before giving up.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The synthetic pose of a sensor in a frame: each sensor moves along its own line.
+fn pose(frame: u32, sensor: i32) -> PoseReport {
+    let t = f64::from(frame) * 0.01;
+    PoseReport {
+        sensor: Sensor(sensor),
+        pos: Vec3::new(f64::from(sensor), 1.5, -t),
+        quat: Quat::identity(),
+    }
+}
+
+/// Drive a connection until it ends, so it sends and receives.
+fn drive(connection: Arc<ConnectionIp>) {
+    task::spawn(async move {
+        let mut stream = ConnectionIpStream::new(connection);
+        while let Some(result) = stream.next().await {
+            if let Err(e) = result {
+                eprintln!("Connection error: {}", e);
+                break;
+            }
+        }
+    });
+}
+
+/// The server side: accept one client, then stream poses for each sensor.
+async fn serve(listener: TcpListener) -> Result<()> {
+    let server = ConnectionIp::new_server(None, None)?;
+    // Registered before the client connects, so it is described during setup.
+    let sender = server.register_sender(DEVICE)?;
+
+    let (stream, _) = listener.accept().await?;
+    server.accept_client(stream).await?;
+    drive(Arc::clone(&server));
+
+    for frame in 0..FRAMES {
+        for sensor in 0..SENSORS {
+            // A time of None stamps it with the connection's clock.
+            server.pack_message_body(
+                None,
+                sender,
+                pose(frame, sensor),
+                ClassOfService::RELIABLE,
+            )?;
+        }
+        task::sleep(Duration::from_millis(5)).await;
+    }
+    Ok(())
+}
+
+/// The client side: subscribe to the tracker and check every pose arrives, in order.
+async fn client(server: ServerInfo) -> Result<()> {
+    let connection = ConnectionIp::new_client(server, None, None)?;
+    // Subscribe before driving the connection, so nothing is missed.
+    let mut poses = connection.device::<Tracker>(DEVICE)?.poses()?;
+    drive(connection);
+
+    let receive = async {
+        for frame in 0..FRAMES {
+            for sensor in 0..SENSORS {
+                let msg = loop {
+                    match poses.next().await {
+                        Some(SubscriptionEvent::Message(msg)) => break msg,
+                        Some(event) => println!("{}: {:?}", DEVICE, event),
+                        None => {
+                            return Err(VrpnError::OtherMessage(
+                                "subscription ended early".to_string(),
+                            ))
+                        }
+                    }
+                };
+                let expected = pose(frame, sensor);
+                if msg.body != expected {
+                    return Err(VrpnError::OtherMessage(format!(
+                        "expected {:?}, got {:?}",
+                        expected, msg.body
+                    )));
+                }
+            }
+        }
+        Ok(())
+    };
+    timeout(RECEIVE_TIMEOUT, receive)
+        .await
+        .map_err(|_| VrpnError::OtherMessage("timed out waiting for poses".to_string()))??;
+    println!(
+        "Received all {} poses of {} sensors from {}",
+        FRAMES, SENSORS, DEVICE
+    );
+    Ok(())
+}
+
+async fn async_main() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let server = format!("tcp://{}", listener.local_addr()?).parse::<ServerInfo>()?;
+    let serving = task::spawn(serve(listener));
+    client(server).await?;
+    serving.await
+}
+
+fn main() -> Result<()> {
+    task::block_on(async_main())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn server_to_client() {
+        super::main().unwrap();
+    }
+}
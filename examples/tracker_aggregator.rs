@@ -0,0 +1,102 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Motion capture aggregator: merges poses from trackers on any number of servers.
+//!
+//! Run with, e.g.,
+//! `cargo run --features vrpn-async-std --example tracker_aggregator -- head=Tracker0@localhost hands=Tracker1@otherhost:3884`
+//!
+//! Each argument is `label=Device@server`. Devices on the same server share a connection.
+//! Every 100ms, prints the latest pose of each labeled sensor as of 20ms ago,
+//! giving slower servers a moment to catch up.
+
+extern crate async_std;
+extern crate vrpn;
+
+use async_std::task;
+use futures::StreamExt;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use vrpn::{
+    tracker::aggregate::PoseAggregator,
+    vrpn_async_std::connection_ip::{ConnectionIp, ConnectionIpStream},
+    DeviceInfo, Result, VrpnError,
+};
+
+const PRINT_INTERVAL: Duration = Duration::from_millis(100);
+const ALIGNMENT_DELAY: Duration = Duration::from_millis(20);
+
+fn parse_arg(arg: &str) -> Result<(String, DeviceInfo)> {
+    let (label, device) = arg.split_once('=').ok_or_else(|| {
+        VrpnError::OtherMessage(format!("expected label=Device@server, got {}", arg))
+    })?;
+    let device: DeviceInfo = device.parse()?;
+    if device.device.is_none() {
+        return Err(VrpnError::OtherMessage(format!(
+            "no device name given in {}",
+            arg
+        )));
+    }
+    Ok((label.to_string(), device))
+}
+
+async fn async_main() -> Result<()> {
+    let trackers = std::env::args()
+        .skip(1)
+        .map(|arg| parse_arg(&arg))
+        .collect::<Result<Vec<_>>>()?;
+    if trackers.is_empty() {
+        eprintln!("usage: tracker_aggregator label=Device@server...");
+        return Ok(());
+    }
+
+    let mut connections = HashMap::new();
+    let mut aggregator = PoseAggregator::new();
+    for (label, info) in trackers {
+        let connection = match connections.get(&info.server) {
+            Some(connection) => Arc::clone(connection),
+            None => {
+                let connection = ConnectionIp::new_client(info.server.clone(), None, None)?;
+                connections.insert(info.server.clone(), Arc::clone(&connection));
+                connection
+            }
+        };
+        let device = info.device.unwrap_or_default();
+        println!("{}: {} on {}", label, device, info.server.socket_addr);
+        aggregator.add_tracker(label, &connection, device)?;
+    }
+
+    // Each connection gets its own task to drive it.
+    for connection in connections.into_values() {
+        task::spawn(async move {
+            let mut stream = ConnectionIpStream::new(connection);
+            while let Some(result) = stream.next().await {
+                if let Err(e) = result {
+                    eprintln!("Connection error: {}", e);
+                    break;
+                }
+            }
+        });
+    }
+
+    loop {
+        task::sleep(PRINT_INTERVAL).await;
+        let frame = aggregator.frame_at((SystemTime::now() - ALIGNMENT_DELAY).into())?;
+        println!("Frame at {}", frame.time);
+        for ((label, sensor), pose) in &frame.poses {
+            let p = pose.report.pos;
+            println!(
+                "  {}/{}: ({:.3}, {:.3}, {:.3}) at {}",
+                label, sensor.0, p.x, p.y, p.z, pose.time
+            );
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    task::block_on(async_main())
+}
@@ -0,0 +1,116 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Live tracker positions in a web page, from a VRPN server reached over WebSocket.
+//!
+//! Browsers can't open TCP connections, so put a WebSocket-to-TCP proxy in front of the server:
+//!
+//! ```sh
+//! websockify 3884 localhost:3883
+//! cargo build --release --target wasm32-unknown-unknown --features websocket --example web_tracker
+//! wasm-bindgen --target web --out-dir examples/web_tracker/pkg \
+//!     target/wasm32-unknown-unknown/release/examples/web_tracker.wasm
+//! python3 -m http.server --directory examples/web_tracker
+//! ```
+//!
+//! Then open <http://localhost:8000>, which shows the position of each sensor
+//! of every tracker on the server as poses arrive.
+
+#[cfg(target_arch = "wasm32")]
+mod web {
+    use futures::future::poll_fn;
+    use std::{
+        collections::BTreeMap,
+        fmt::Write,
+        sync::{Arc, Mutex},
+    };
+    use vrpn::{
+        data_types::{
+            id_types::{LocalId, SenderId},
+            TypedMessage, Vec3,
+        },
+        handler::{HandlerCode, TypedHandler},
+        tracker::PoseReport,
+        websocket, Result, TypeDispatcher, VrpnError,
+    };
+
+    /// The WebSocket proxy in front of the VRPN server.
+    const URL: &str = "ws://localhost:3884";
+
+    /// Latest position of each sensor, by sender and sensor number.
+    type Positions = Arc<Mutex<BTreeMap<(SenderId, i32), Vec3>>>;
+
+    #[derive(Debug)]
+    struct Record(Positions);
+
+    impl TypedHandler for Record {
+        type Item = PoseReport;
+        fn handle_typed(&mut self, msg: &TypedMessage<PoseReport>) -> Result<HandlerCode> {
+            self.0
+                .lock()?
+                .insert((msg.header.sender, msg.body.sensor.0), msg.body.pos);
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    fn show(text: &str) {
+        if let Some(element) = web_sys::window()
+            .and_then(|window| window.document())
+            .and_then(|document| document.get_element_by_id("poses"))
+        {
+            element.set_text_content(Some(text));
+        }
+    }
+
+    fn render(dispatcher: &TypeDispatcher, positions: &Positions) -> Result<()> {
+        let mut text = String::new();
+        for ((sender, sensor), pos) in positions.lock()?.iter() {
+            let name = dispatcher
+                .get_sender_name(LocalId(*sender))
+                .map(|name| name.to_string())
+                .unwrap_or_default();
+            writeln!(
+                text,
+                "{} sensor {}: ({:8.3}, {:8.3}, {:8.3})",
+                name, sensor, pos.x, pos.y, pos.z
+            )
+            .map_err(|e| VrpnError::OtherMessage(e.to_string()))?;
+        }
+        show(&text);
+        Ok(())
+    }
+
+    async fn run() -> Result<()> {
+        let mut endpoint = websocket::connect(URL).await?;
+        let mut dispatcher = TypeDispatcher::new();
+        let positions = Positions::default();
+        let _ = dispatcher.add_typed_handler(Box::new(Record(Arc::clone(&positions))), None)?;
+        show("Connected, waiting for poses...");
+        poll_fn(|cx| {
+            let status = endpoint.poll_endpoint(&mut dispatcher, cx);
+            render(&dispatcher, &positions)?;
+            status
+        })
+        .await
+    }
+
+    pub fn start() {
+        wasm_bindgen_futures::spawn_local(async {
+            match run().await {
+                Ok(()) => show("The server closed the connection."),
+                Err(e) => show(&format!("Error: {}", e)),
+            }
+        });
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {
+    web::start();
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    eprintln!("This example runs in a browser: build it for wasm32-unknown-unknown.");
+}
@@ -0,0 +1,80 @@
+/*
+ * Copyright 2018-2022, Collabora, Ltd.
+ * SPDX-License-Identifier: BSL-1.0
+ * Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+ */
+
+/*
+ * C API of the vrpn crate, built with the "capi" feature: see src/capi.rs.
+ *
+ * Create a connection, register callbacks, then call vrpn_rs_connection_poll
+ * regularly: callbacks only run inside it, on the calling thread.
+ * Functions returning int return 0 on success and -1 on error.
+ */
+
+#ifndef VRPN_RS_H
+#define VRPN_RS_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct VrpnRsConnection VrpnRsConnection;
+
+typedef struct VrpnRsTime {
+    int64_t sec;
+    int64_t usec;
+} VrpnRsTime;
+
+typedef struct VrpnRsTrackerReport {
+    VrpnRsTime time;
+    int32_t sensor;
+    double pos[3];
+    /* x, y, z, w */
+    double quat[4];
+} VrpnRsTrackerReport;
+
+typedef struct VrpnRsButtonReport {
+    VrpnRsTime time;
+    int32_t button;
+    /* 1 for pressed, 0 for released */
+    int32_t state;
+} VrpnRsButtonReport;
+
+typedef struct VrpnRsAnalogReport {
+    VrpnRsTime time;
+    int32_t num_channels;
+    /* Valid only during the callback. */
+    const double *channels;
+} VrpnRsAnalogReport;
+
+typedef void (*VrpnRsTrackerCallback)(void *userdata, const VrpnRsTrackerReport *report);
+typedef void (*VrpnRsButtonCallback)(void *userdata, const VrpnRsButtonReport *report);
+typedef void (*VrpnRsAnalogCallback)(void *userdata, const VrpnRsAnalogReport *report);
+
+/* Connect to a server such as "localhost" or "tcp://host:3883", reconnecting as needed.
+ * Returns NULL on error. */
+VrpnRsConnection *vrpn_rs_connection_new(const char *server);
+
+void vrpn_rs_connection_free(VrpnRsConnection *connection);
+
+/* Call the callbacks for everything received since the last call. */
+int vrpn_rs_connection_poll(VrpnRsConnection *connection);
+
+/* Registering a NULL callback fails. */
+int vrpn_rs_register_tracker_callback(VrpnRsConnection *connection, const char *device,
+                                      VrpnRsTrackerCallback callback, void *userdata);
+
+int vrpn_rs_register_button_callback(VrpnRsConnection *connection, const char *device,
+                                     VrpnRsButtonCallback callback, void *userdata);
+
+int vrpn_rs_register_analog_callback(VrpnRsConnection *connection, const char *device,
+                                     VrpnRsAnalogCallback callback, void *userdata);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* VRPN_RS_H */
@@ -0,0 +1,725 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Types related to the `vrpn_Analog` and `vrpn_Analog_Output` device classes,
+//! and a server for the latter.
+//!
+//! An analog output device lets clients set channel values, typically to control actuators.
+//! The server clamps requested values to each channel's configured range,
+//! passes them to a user callback, and reports the resulting values back
+//! in a `vrpn_Analog Channel` message.
+
+pub mod calibration;
+
+use crate::{
+    buffer_unbuffer::{
+        check_buffer_remaining, check_unbuffer_remaining, BufferResult, BufferSize, BufferTo,
+        BufferUnbufferError, ConstantBufferSize, UnbufferFrom, UnbufferResult,
+    },
+    data_types::{
+        id_types::*, ClassOfService, MessageTypeIdentifier, SenderName, StaticMessageTypeName,
+        TypedMessage, TypedMessageBody,
+    },
+    device::DeviceClass,
+    handler::{CallbackHandler, HandlerCode, HandlerHandle, TypedHandler},
+    subscription::Subscription,
+    Connection, Result, VrpnError,
+};
+use bytes::{Buf, BufMut};
+use std::{
+    convert::TryFrom,
+    fmt,
+    sync::{Arc, Mutex, Weak},
+};
+
+pub(crate) const ANALOG_CHANNEL: StaticMessageTypeName =
+    StaticMessageTypeName(b"vrpn_Analog Channel");
+const CHANGE_CHANNEL_REQUEST: StaticMessageTypeName =
+    StaticMessageTypeName(b"vrpn_Analog_Output Change_Channel_Request");
+const CHANGE_CHANNELS_REQUEST: StaticMessageTypeName =
+    StaticMessageTypeName(b"vrpn_Analog_Output Change_Channels_Request");
+const NUM_CHANNELS: StaticMessageTypeName =
+    StaticMessageTypeName(b"vrpn_Analog_Output Num_Channels");
+
+/// Maximum number of channels in an analog device, matching `vrpn_CHANNEL_MAX`.
+pub const MAX_CHANNELS: usize = 128;
+
+fn check_channel_count(count: usize) -> UnbufferResult<()> {
+    if count > MAX_CHANNELS {
+        return Err(BufferUnbufferError::ParseError {
+            parsing_kind: "analog channel count".to_string(),
+            s: format!("{} exceeds maximum of {}", count, MAX_CHANNELS),
+        });
+    }
+    Ok(())
+}
+
+/// Current values of all channels of an analog device: `vrpn_Analog Channel`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnalogReport {
+    pub values: Vec<f64>,
+}
+
+impl TypedMessageBody for AnalogReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(ANALOG_CHANNEL);
+}
+
+impl BufferSize for AnalogReport {
+    fn buffer_size(&self) -> usize {
+        // Channel count is sent as a double.
+        f64::constant_buffer_size() * (1 + self.values.len())
+    }
+}
+
+impl BufferTo for AnalogReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        check_buffer_remaining(buf, self.buffer_size())?;
+        (self.values.len() as f64).buffer_to(buf)?;
+        for v in &self.values {
+            v.buffer_to(buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for AnalogReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        let count = f64::unbuffer_from(buf)?;
+        if !(0.0..=MAX_CHANNELS as f64).contains(&count) {
+            return Err(BufferUnbufferError::ParseError {
+                parsing_kind: "analog channel count".to_string(),
+                s: count.to_string(),
+            });
+        }
+        let count = count as usize;
+        check_unbuffer_remaining(buf, count * f64::constant_buffer_size())?;
+        let values = (0..count)
+            .map(|_| f64::unbuffer_from(buf))
+            .collect::<UnbufferResult<Vec<f64>>>()?;
+        Ok(AnalogReport { values })
+    }
+}
+
+/// Request to set a single analog output channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChangeChannelRequest {
+    pub channel: i32,
+    pub value: f64,
+}
+
+impl TypedMessageBody for ChangeChannelRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(CHANGE_CHANNEL_REQUEST);
+}
+
+impl ConstantBufferSize for ChangeChannelRequest {
+    fn constant_buffer_size() -> usize {
+        i32::constant_buffer_size() * 2 + f64::constant_buffer_size()
+    }
+}
+
+impl BufferTo for ChangeChannelRequest {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        check_buffer_remaining(buf, Self::constant_buffer_size())?;
+        self.channel.buffer_to(buf)?;
+        // padding
+        0_i32.buffer_to(buf)?;
+        self.value.buffer_to(buf)
+    }
+}
+
+impl UnbufferFrom for ChangeChannelRequest {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        let channel = i32::unbuffer_from(buf)?;
+        let _ = i32::unbuffer_from(buf)?;
+        let value = f64::unbuffer_from(buf)?;
+        Ok(ChangeChannelRequest { channel, value })
+    }
+}
+
+/// Request to set analog output channels, starting at channel 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeChannelsRequest {
+    pub values: Vec<f64>,
+}
+
+impl TypedMessageBody for ChangeChannelsRequest {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(CHANGE_CHANNELS_REQUEST);
+}
+
+impl BufferSize for ChangeChannelsRequest {
+    fn buffer_size(&self) -> usize {
+        i32::constant_buffer_size() * 2 + f64::constant_buffer_size() * self.values.len()
+    }
+}
+
+impl BufferTo for ChangeChannelsRequest {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        check_buffer_remaining(buf, self.buffer_size())?;
+        let count =
+            i32::try_from(self.values.len()).map_err(|_| BufferUnbufferError::OutOfBuffer)?;
+        count.buffer_to(buf)?;
+        // padding
+        0_i32.buffer_to(buf)?;
+        for v in &self.values {
+            v.buffer_to(buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for ChangeChannelsRequest {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, i32::constant_buffer_size() * 2)?;
+        let count = i32::unbuffer_from(buf)?;
+        let _ = i32::unbuffer_from(buf)?;
+        let count = usize::try_from(count).map_err(|_| BufferUnbufferError::ParseError {
+            parsing_kind: "analog channel count".to_string(),
+            s: count.to_string(),
+        })?;
+        check_channel_count(count)?;
+        check_unbuffer_remaining(buf, count * f64::constant_buffer_size())?;
+        let values = (0..count)
+            .map(|_| f64::unbuffer_from(buf))
+            .collect::<UnbufferResult<Vec<f64>>>()?;
+        Ok(ChangeChannelsRequest { values })
+    }
+}
+
+/// Report of how many channels an analog output server has.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NumChannelsReport {
+    pub num_channels: i32,
+}
+
+impl TypedMessageBody for NumChannelsReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(NUM_CHANNELS);
+}
+
+impl ConstantBufferSize for NumChannelsReport {
+    fn constant_buffer_size() -> usize {
+        i32::constant_buffer_size() * 2
+    }
+}
+
+impl BufferTo for NumChannelsReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        check_buffer_remaining(buf, Self::constant_buffer_size())?;
+        self.num_channels.buffer_to(buf)?;
+        // padding
+        0_i32.buffer_to(buf)
+    }
+}
+
+impl UnbufferFrom for NumChannelsReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        let num_channels = i32::unbuffer_from(buf)?;
+        let _ = i32::unbuffer_from(buf)?;
+        Ok(NumChannelsReport { num_channels })
+    }
+}
+
+/// The range of values accepted by one analog output channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl ChannelRange {
+    pub fn new(min: f64, max: f64) -> ChannelRange {
+        ChannelRange { min, max }
+    }
+
+    /// Clamp a value into this range. Returns None for NaN, which cannot be meaningfully clamped.
+    pub fn clamp(&self, value: f64) -> Option<f64> {
+        if value.is_nan() {
+            None
+        } else {
+            Some(value.max(self.min).min(self.max))
+        }
+    }
+}
+
+impl Default for ChannelRange {
+    /// An unrestricted range.
+    fn default() -> ChannelRange {
+        ChannelRange::new(f64::NEG_INFINITY, f64::INFINITY)
+    }
+}
+
+/// Callback invoked by an `AnalogOutputServer` for each channel change,
+/// with the channel index and the already-clamped value.
+pub type ChannelCallback = Box<dyn FnMut(usize, f64) + Send>;
+
+struct AnalogOutputState {
+    ranges: Vec<ChannelRange>,
+    values: Vec<f64>,
+    callback: ChannelCallback,
+}
+
+impl fmt::Debug for AnalogOutputState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AnalogOutputState")
+            .field("ranges", &self.ranges)
+            .field("values", &self.values)
+            .finish()
+    }
+}
+
+impl AnalogOutputState {
+    /// Clamp and apply a value, returning whether the channel was valid.
+    fn set(&mut self, channel: usize, value: f64) -> bool {
+        let value = match self.ranges.get(channel).and_then(|r| r.clamp(value)) {
+            Some(v) => v,
+            None => return false,
+        };
+        self.values[channel] = value;
+        (self.callback)(channel, value);
+        true
+    }
+}
+
+/// Sends the report of current values, shared by the server and its handlers.
+fn send_report<T: Connection>(
+    connection: &T,
+    report_type: LocalId<MessageTypeId>,
+    sender: LocalId<SenderId>,
+    values: Vec<f64>,
+) -> Result<()> {
+    connection.pack_message(
+        TypedMessage::new(
+            Some(connection.message_clock().time_of_day()),
+            report_type,
+            sender,
+            AnalogReport { values },
+        ),
+        ClassOfService::RELIABLE,
+    )
+}
+
+#[derive(Debug)]
+struct ChangeChannelHandler<T: Connection> {
+    connection: Weak<T>,
+    state: Arc<Mutex<AnalogOutputState>>,
+    report_type: LocalId<MessageTypeId>,
+    sender: LocalId<SenderId>,
+}
+
+impl<T: Connection> ChangeChannelHandler<T> {
+    /// Apply changes to the state, then report back the values.
+    fn apply<F>(&self, f: F) -> Result<HandlerCode>
+    where
+        F: FnOnce(&mut AnalogOutputState),
+    {
+        match self.connection.upgrade() {
+            Some(connection) => {
+                let values = {
+                    let mut state = self.state.lock()?;
+                    f(&mut state);
+                    state.values.clone()
+                };
+                send_report(&*connection, self.report_type, self.sender, values)?;
+                Ok(HandlerCode::ContinueProcessing)
+            }
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SingleChannelHandler<T: Connection>(ChangeChannelHandler<T>);
+
+impl<T: Connection> TypedHandler for SingleChannelHandler<T> {
+    type Item = ChangeChannelRequest;
+    fn handle_typed(&mut self, msg: &TypedMessage<ChangeChannelRequest>) -> Result<HandlerCode> {
+        let ChangeChannelRequest { channel, value } = msg.body;
+        self.0.apply(|state| {
+            let valid = usize::try_from(channel)
+                .map(|c| state.set(c, value))
+                .unwrap_or(false);
+            if !valid {
+                eprintln!(
+                    "Ignoring analog output request for channel {} with value {}",
+                    channel, value
+                );
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+struct MultipleChannelsHandler<T: Connection>(ChangeChannelHandler<T>);
+
+impl<T: Connection> TypedHandler for MultipleChannelsHandler<T> {
+    type Item = ChangeChannelsRequest;
+    fn handle_typed(&mut self, msg: &TypedMessage<ChangeChannelsRequest>) -> Result<HandlerCode> {
+        self.0.apply(|state| {
+            if msg.body.values.len() > state.values.len() {
+                eprintln!(
+                    "Analog output request for {} channels, but only {} exist: ignoring the extras",
+                    msg.body.values.len(),
+                    state.values.len()
+                );
+            }
+            for (channel, value) in msg.body.values.iter().enumerate() {
+                if channel < state.values.len() && !state.set(channel, *value) {
+                    eprintln!(
+                        "Ignoring invalid analog output value {} for channel {}",
+                        value, channel
+                    );
+                }
+            }
+        })
+    }
+}
+
+/// The server side of a `vrpn_Analog_Output` device.
+///
+/// Handles channel-set requests from clients: each requested value is clamped to its
+/// channel's range and passed to the callback, then all current values are reported back.
+#[derive(Debug)]
+pub struct AnalogOutputServer<T: Connection + 'static> {
+    connection: Weak<T>,
+    state: Arc<Mutex<AnalogOutputState>>,
+    sender: LocalId<SenderId>,
+    report_type: LocalId<MessageTypeId>,
+    num_channels_type: LocalId<MessageTypeId>,
+    handlers: [HandlerHandle; 2],
+}
+
+impl<T: Connection + 'static> AnalogOutputServer<T> {
+    /// Create a server with one channel per range, all starting at 0 (clamped into range).
+    pub fn new(
+        sender: LocalId<SenderId>,
+        connection: Arc<T>,
+        ranges: Vec<ChannelRange>,
+        callback: ChannelCallback,
+    ) -> Result<AnalogOutputServer<T>> {
+        if ranges.len() > MAX_CHANNELS {
+            return Err(VrpnError::OtherMessage(format!(
+                "Analog output servers may have at most {} channels, not {}",
+                MAX_CHANNELS,
+                ranges.len()
+            )));
+        }
+        let values = ranges.iter().map(|r| r.clamp(0.0).unwrap_or(0.0)).collect();
+        let state = Arc::new(Mutex::new(AnalogOutputState {
+            ranges,
+            values,
+            callback,
+        }));
+        let report_type = connection.register_type(ANALOG_CHANNEL)?;
+        let num_channels_type = connection.register_type(NUM_CHANNELS)?;
+        let make_handler = || ChangeChannelHandler {
+            connection: Arc::downgrade(&connection),
+            state: Arc::clone(&state),
+            report_type,
+            sender,
+        };
+        let handlers = [
+            connection
+                .add_typed_handler(Box::new(SingleChannelHandler(make_handler())), Some(sender))?,
+            connection.add_typed_handler(
+                Box::new(MultipleChannelsHandler(make_handler())),
+                Some(sender),
+            )?,
+        ];
+        Ok(AnalogOutputServer {
+            connection: Arc::downgrade(&connection),
+            state,
+            sender,
+            report_type,
+            num_channels_type,
+            handlers,
+        })
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName>,
+        connection: Arc<T>,
+        ranges: Vec<ChannelRange>,
+        callback: ChannelCallback,
+    ) -> Result<AnalogOutputServer<T>> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection, ranges, callback)
+    }
+
+    /// Number of channels.
+    pub fn num_channels(&self) -> Result<usize> {
+        Ok(self.state.lock()?.values.len())
+    }
+
+    /// Current value of all channels.
+    pub fn values(&self) -> Result<Vec<f64>> {
+        Ok(self.state.lock()?.values.clone())
+    }
+
+    /// Send the number of channels, as clients expect when they connect.
+    pub fn report_num_channels(&self) -> Result<()> {
+        let connection = self.connection.upgrade().ok_or(VrpnError::EndpointClosed)?;
+        let num_channels = NumChannelsReport {
+            num_channels: self.num_channels()? as i32,
+        };
+        connection.pack_message(
+            TypedMessage::new(
+                Some(connection.message_clock().time_of_day()),
+                self.num_channels_type,
+                self.sender,
+                num_channels,
+            ),
+            ClassOfService::RELIABLE,
+        )
+    }
+
+    /// Send the current values of all channels.
+    pub fn report_values(&self) -> Result<()> {
+        let connection = self.connection.upgrade().ok_or(VrpnError::EndpointClosed)?;
+        send_report(&*connection, self.report_type, self.sender, self.values()?)
+    }
+
+    /// Stop handling requests.
+    pub fn shutdown(self) -> Result<()> {
+        if let Some(connection) = self.connection.upgrade() {
+            for handler in self.handlers {
+                connection.remove_handler(handler)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `vrpn_Analog` device class, for `DeviceExt::device`.
+#[derive(Debug, Clone, Copy)]
+pub struct Analog;
+
+impl<C: Connection + 'static> DeviceClass<C> for Analog {
+    type Proxy = AnalogProxy<C>;
+    fn proxy(
+        connection: &Arc<C>,
+        name: SenderName,
+        sender: LocalId<SenderId>,
+    ) -> Result<AnalogProxy<C>> {
+        Ok(AnalogProxy {
+            connection: Arc::downgrade(connection),
+            name,
+            sender,
+        })
+    }
+}
+
+/// An analog device on a connection: see `DeviceExt::device`.
+#[derive(Debug)]
+pub struct AnalogProxy<C: Connection + 'static> {
+    connection: Weak<C>,
+    name: SenderName,
+    sender: LocalId<SenderId>,
+}
+
+impl<C: Connection + 'static> AnalogProxy<C> {
+    /// The local ID of the device's sender.
+    pub fn sender(&self) -> LocalId<SenderId> {
+        self.sender
+    }
+
+    /// Call `callback` with each report of the channel values, until removed with the returned handle.
+    pub fn on_report<F>(&self, callback: F) -> Result<HandlerHandle>
+    where
+        F: FnMut(&TypedMessage<AnalogReport>) + Send + Sync + 'static,
+    {
+        self.connection()?
+            .add_typed_handler(Box::new(CallbackHandler::new(callback)), Some(self.sender))
+    }
+
+    /// A stream of the reports of the channel values, with lifecycle events.
+    pub fn reports(&self) -> Result<Subscription<AnalogReport>> {
+        Subscription::new(&self.connection()?, self.name.clone())
+    }
+
+    fn connection(&self) -> Result<Arc<C>> {
+        self.connection.upgrade().ok_or(VrpnError::EndpointClosed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{Bytes, BytesMut};
+
+    #[test]
+    fn wire_format() {
+        let req = ChangeChannelRequest {
+            channel: 2,
+            value: 1.5,
+        };
+        let mut buf = BytesMut::new();
+        req.buffer_to(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..],
+            &hex!("00 00 00 02 00 00 00 00 3f f8 00 00 00 00 00 00")[..]
+        );
+        assert_eq!(
+            ChangeChannelRequest::unbuffer_from(&mut buf.freeze()).unwrap(),
+            req
+        );
+
+        let report = AnalogReport {
+            values: vec![1.0, -1.0],
+        };
+        let mut buf = BytesMut::new();
+        report.buffer_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), report.buffer_size());
+        assert_eq!(&buf[..8], &hex!("40 00 00 00 00 00 00 00")[..]);
+        assert_eq!(
+            AnalogReport::unbuffer_from(&mut buf.freeze()).unwrap(),
+            report
+        );
+
+        let req = ChangeChannelsRequest {
+            values: vec![0.25; 3],
+        };
+        let mut buf = BytesMut::new();
+        req.buffer_to(&mut buf).unwrap();
+        assert_eq!(
+            ChangeChannelsRequest::unbuffer_from(&mut buf.freeze()).unwrap(),
+            req
+        );
+
+        // Negative or excessive counts
+        let mut buf = Bytes::from_static(&hex!("ff ff ff ff 00 00 00 00"));
+        assert!(ChangeChannelsRequest::unbuffer_from(&mut buf).is_err());
+        let mut buf = Bytes::from_static(&hex!("00 00 10 00 00 00 00 00"));
+        assert!(ChangeChannelsRequest::unbuffer_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn clamping() {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&changes);
+        let mut state = AnalogOutputState {
+            ranges: vec![ChannelRange::new(-1.0, 1.0), ChannelRange::default()],
+            values: vec![0.0; 2],
+            callback: Box::new(move |channel, value| {
+                recorded.lock().unwrap().push((channel, value));
+            }),
+        };
+        assert!(state.set(0, 5.0));
+        assert!(state.set(0, -0.5));
+        assert!(state.set(1, 1e9));
+        assert!(!state.set(0, f64::NAN));
+        assert!(!state.set(2, 0.0));
+        assert_eq!(state.values, vec![-0.5, 1e9]);
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![(0, 1.0), (0, -0.5), (1, 1e9)]
+        );
+    }
+
+    #[test]
+    fn requests_through_connection() {
+        use crate::testing::MockConnection;
+        let connection = MockConnection::new();
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&changes);
+        let server = AnalogOutputServer::new_from_name(
+            "Analog0",
+            Arc::clone(&connection),
+            vec![ChannelRange::new(-1.0, 1.0), ChannelRange::new(0.0, 10.0)],
+            Box::new(move |channel, value| recorded.lock().unwrap().push((channel, value))),
+        )
+        .unwrap();
+        let sender = connection.register_sender("Analog0").unwrap();
+        let single = connection.register_type(CHANGE_CHANNEL_REQUEST).unwrap();
+        let multiple = connection.register_type(CHANGE_CHANNELS_REQUEST).unwrap();
+        let reports = || {
+            connection
+                .sent_typed::<AnalogReport>()
+                .unwrap()
+                .into_iter()
+                .map(|msg| msg.body.values)
+                .collect::<Vec<_>>()
+        };
+
+        connection
+            .receive(TypedMessage::new(
+                None,
+                single,
+                sender,
+                ChangeChannelRequest {
+                    channel: 1,
+                    value: 12.0,
+                },
+            ))
+            .unwrap();
+        assert_eq!(server.values().unwrap(), vec![0.0, 10.0]);
+        assert_eq!(reports(), vec![vec![0.0, 10.0]]);
+
+        // Extra values are ignored, the rest applied.
+        connection
+            .receive(TypedMessage::new(
+                None,
+                multiple,
+                sender,
+                ChangeChannelsRequest {
+                    values: vec![-0.5, 2.0, 3.0],
+                },
+            ))
+            .unwrap();
+        assert_eq!(server.values().unwrap(), vec![-0.5, 2.0]);
+        assert_eq!(reports().last(), Some(&vec![-0.5, 2.0]));
+
+        // An invalid channel changes nothing, but the values are still reported.
+        connection
+            .receive(TypedMessage::new(
+                None,
+                single,
+                sender,
+                ChangeChannelRequest {
+                    channel: 5,
+                    value: 1.0,
+                },
+            ))
+            .unwrap();
+        assert_eq!(server.values().unwrap(), vec![-0.5, 2.0]);
+        assert_eq!(reports().len(), 3);
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![(1, 10.0), (0, -0.5), (1, 2.0)]
+        );
+
+        // Requests for other devices are not handled.
+        let other = connection.register_sender("Analog1").unwrap();
+        connection
+            .receive(TypedMessage::new(
+                None,
+                single,
+                other,
+                ChangeChannelRequest {
+                    channel: 0,
+                    value: 1.0,
+                },
+            ))
+            .unwrap();
+        assert_eq!(server.values().unwrap(), vec![-0.5, 2.0]);
+
+        server.shutdown().unwrap();
+        connection
+            .receive(TypedMessage::new(
+                None,
+                multiple,
+                sender,
+                ChangeChannelsRequest {
+                    values: vec![1.0, 1.0],
+                },
+            ))
+            .unwrap();
+        assert_eq!(reports().len(), 3);
+        assert_eq!(changes.lock().unwrap().len(), 3);
+    }
+}
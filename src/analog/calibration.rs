@@ -0,0 +1,270 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Per-channel calibration of analog values, e.g. to center and scale a joystick.
+//!
+//! Each channel gets an affine transform with a deadband (`ChannelCalibration`).
+//! A set of them (`AnalogCalibration`) can be applied
+//! to a handler (`CalibratedAnalogHandler`) or a stream of reports (`calibrate_analog_stream`),
+//! so downstream code only ever sees calibrated values.
+
+use super::AnalogReport;
+use crate::{
+    data_types::TypedMessage,
+    handler::{HandlerCode, TypedHandler},
+    Result, VrpnError,
+};
+use futures::{Stream, StreamExt};
+
+/// Calibration of one channel: `(raw - offset) * scale`,
+/// with results no further than `deadband` from zero reported as exactly zero.
+///
+/// The deadband is in calibrated units, so it doesn't need changing along with the scale.
+/// The default passes values through unchanged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct ChannelCalibration {
+    /// Raw value that maps to zero, e.g. a joystick's rest position.
+    pub offset: f64,
+    /// Factor applied after subtracting the offset: negative to invert the channel.
+    pub scale: f64,
+    /// Half-width of the range around zero that is snapped to zero: never negative.
+    pub deadband: f64,
+}
+
+impl Default for ChannelCalibration {
+    fn default() -> ChannelCalibration {
+        ChannelCalibration {
+            offset: 0.0,
+            scale: 1.0,
+            deadband: 0.0,
+        }
+    }
+}
+
+impl ChannelCalibration {
+    pub fn new(offset: f64, scale: f64) -> ChannelCalibration {
+        ChannelCalibration {
+            offset,
+            scale,
+            deadband: 0.0,
+        }
+    }
+
+    /// Set the deadband, in calibrated units.
+    pub fn with_deadband(self, deadband: f64) -> ChannelCalibration {
+        ChannelCalibration { deadband, ..self }
+    }
+
+    /// Calibrate a raw value.
+    pub fn apply(&self, raw: f64) -> f64 {
+        let value = (raw - self.offset) * self.scale;
+        if value.abs() <= self.deadband {
+            0.0
+        } else {
+            value
+        }
+    }
+
+    /// Check that all parameters are finite, and the deadband isn't negative.
+    pub fn check_valid(&self) -> Result<()> {
+        if !(self.offset.is_finite() && self.scale.is_finite() && self.deadband.is_finite()) {
+            return Err(VrpnError::Config(format!(
+                "calibration parameters must be finite, got {:?}",
+                self
+            )));
+        }
+        if self.deadband < 0.0 {
+            return Err(VrpnError::Config(format!(
+                "calibration deadband must not be negative, got {}",
+                self.deadband
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Calibration of each channel of an analog device.
+///
+/// Channels beyond those given are passed through unchanged.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AnalogCalibration {
+    channels: Vec<ChannelCalibration>,
+}
+
+impl AnalogCalibration {
+    /// Calibrate the first channels, in order.
+    pub fn new(channels: Vec<ChannelCalibration>) -> AnalogCalibration {
+        AnalogCalibration { channels }
+    }
+
+    /// Calibrate one channel, leaving any earlier ones not yet given unchanged.
+    pub fn with_channel(
+        mut self,
+        channel: usize,
+        calibration: ChannelCalibration,
+    ) -> AnalogCalibration {
+        if self.channels.len() <= channel {
+            self.channels
+                .resize(channel + 1, ChannelCalibration::default());
+        }
+        self.channels[channel] = calibration;
+        self
+    }
+
+    /// The calibration of a channel.
+    pub fn channel(&self, channel: usize) -> ChannelCalibration {
+        self.channels.get(channel).copied().unwrap_or_default()
+    }
+
+    /// Check the calibration of every channel.
+    pub fn check_valid(&self) -> Result<()> {
+        self.channels
+            .iter()
+            .try_for_each(ChannelCalibration::check_valid)
+    }
+
+    /// Calibrate the values of a report.
+    pub fn calibrate_report(&self, report: &AnalogReport) -> AnalogReport {
+        AnalogReport {
+            values: report
+                .values
+                .iter()
+                .enumerate()
+                .map(|(i, &raw)| self.channel(i).apply(raw))
+                .collect(),
+        }
+    }
+
+    /// Calibrate a report message, keeping its header.
+    pub fn calibrate_message(
+        &self,
+        msg: &TypedMessage<AnalogReport>,
+    ) -> TypedMessage<AnalogReport> {
+        TypedMessage::from_header_and_body(msg.header.clone(), self.calibrate_report(&msg.body))
+    }
+}
+
+/// Wraps an analog report handler, so it receives calibrated reports.
+#[derive(Debug)]
+pub struct CalibratedAnalogHandler<H> {
+    inner: H,
+    calibration: AnalogCalibration,
+}
+
+impl<H: TypedHandler<Item = AnalogReport>> CalibratedAnalogHandler<H> {
+    pub fn new(inner: H, calibration: AnalogCalibration) -> CalibratedAnalogHandler<H> {
+        CalibratedAnalogHandler { inner, calibration }
+    }
+}
+
+impl<H: TypedHandler<Item = AnalogReport>> TypedHandler for CalibratedAnalogHandler<H> {
+    type Item = AnalogReport;
+    fn handle_typed(&mut self, msg: &TypedMessage<AnalogReport>) -> Result<HandlerCode> {
+        let calibrated = self.calibration.calibrate_message(msg);
+        self.inner.handle_typed(&calibrated)
+    }
+}
+
+/// Adapt a stream of analog reports to yield calibrated reports.
+pub fn calibrate_analog_stream<S>(
+    stream: S,
+    calibration: AnalogCalibration,
+) -> impl Stream<Item = TypedMessage<AnalogReport>>
+where
+    S: Stream<Item = TypedMessage<AnalogReport>>,
+{
+    stream.map(move |msg| calibration.calibrate_message(&msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{
+        id_types::{LocalId, MessageTypeId, SenderId},
+        TimeVal,
+    };
+    use futures::{executor::block_on, stream};
+    use std::sync::{Arc, Mutex};
+
+    fn report_message(values: &[f64]) -> TypedMessage<AnalogReport> {
+        TypedMessage::new(
+            Some(TimeVal::default()),
+            LocalId(MessageTypeId(1)),
+            LocalId(SenderId(0)),
+            AnalogReport {
+                values: values.to_vec(),
+            },
+        )
+    }
+
+    #[test]
+    fn channel() {
+        let identity = ChannelCalibration::default();
+        assert_eq!(identity.apply(0.25), 0.25);
+
+        // Centered on 0.5, spanning [-1, 1], inverted, with a deadband of 0.1.
+        let cal = ChannelCalibration::new(0.5, -2.0).with_deadband(0.1);
+        assert_eq!(cal.apply(0.5), 0.0);
+        assert_eq!(cal.apply(0.0), 1.0);
+        assert_eq!(cal.apply(1.0), -1.0);
+        assert_eq!(cal.apply(0.52), 0.0);
+        assert!((cal.apply(0.6) + 0.2).abs() < 1.0e-12);
+        cal.check_valid().unwrap();
+
+        assert!(ChannelCalibration::new(f64::NAN, 1.0)
+            .check_valid()
+            .is_err());
+        assert!(ChannelCalibration::new(0.0, f64::INFINITY)
+            .check_valid()
+            .is_err());
+        assert!(identity.with_deadband(-0.1).check_valid().is_err());
+    }
+
+    #[test]
+    fn per_channel() {
+        let calibration = AnalogCalibration::default()
+            .with_channel(1, ChannelCalibration::new(1.0, 10.0))
+            .with_channel(0, ChannelCalibration::new(0.0, 2.0));
+        assert_eq!(calibration.channel(2), ChannelCalibration::default());
+        calibration.check_valid().unwrap();
+
+        let msg = report_message(&[1.0, 2.0, 3.0]);
+        let calibrated = calibration.calibrate_message(&msg);
+        assert_eq!(calibrated.body.values, vec![2.0, 10.0, 3.0]);
+        assert_eq!(calibrated.header, msg.header);
+
+        let streamed: Vec<_> = block_on(
+            calibrate_analog_stream(stream::iter(vec![msg]), calibration).collect::<Vec<_>>(),
+        );
+        assert_eq!(streamed, vec![calibrated]);
+    }
+
+    #[derive(Debug)]
+    struct Recorder(Arc<Mutex<Vec<Vec<f64>>>>);
+
+    impl TypedHandler for Recorder {
+        type Item = AnalogReport;
+        fn handle_typed(&mut self, msg: &TypedMessage<AnalogReport>) -> Result<HandlerCode> {
+            self.0.lock().unwrap().push(msg.body.values.clone());
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    #[test]
+    fn handler() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut handler = CalibratedAnalogHandler::new(
+            Recorder(Arc::clone(&received)),
+            AnalogCalibration::new(vec![ChannelCalibration::new(0.5, 2.0).with_deadband(0.5)]),
+        );
+        handler.handle_typed(&report_message(&[0.6, 0.6])).unwrap();
+        handler.handle_typed(&report_message(&[1.0])).unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![vec![0.0, 0.6], vec![1.0]]);
+    }
+}
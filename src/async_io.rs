@@ -0,0 +1,171 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Helpers for reading from async streams, the counterparts of those in `sync_io`.
+//!
+//! Based only on the `futures::io` traits, so they work with any runtime:
+//! async-std streams implement those directly, and Tokio streams can be adapted
+//! with `tokio_util::compat`.
+
+use crate::{data_types::constants::COOKIE_SIZE, VrpnError};
+use bytes::BytesMut;
+use futures::{AsyncRead, AsyncReadExt};
+use std::io;
+
+/// Reads a cookie's worth of data into a temporary buffer.
+pub async fn read_cookie<T>(stream: &mut T) -> Result<Vec<u8>, VrpnError>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut buf = [0u8; COOKIE_SIZE];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf.to_vec())
+}
+
+/// Read whatever is available, up to the buffer's spare capacity, appending it to the buffer.
+///
+/// Returns the number of bytes read: 0 at end of stream, or if the buffer has no spare capacity.
+pub async fn read_into_bytes_mut<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    buf: &mut BytesMut,
+) -> io::Result<usize> {
+    let start = buf.len();
+    buf.resize(buf.capacity(), 0);
+    let result = stream.read(&mut buf[start..]).await;
+    let n = *result.as_ref().unwrap_or(&0);
+    buf.truncate(start + n);
+    result
+}
+
+/// Read exactly `len` bytes, appending them to the buffer.
+///
+/// On error, including reaching the end of the stream first, the buffer is left as it was.
+pub async fn read_n_into_bytes_mut<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    buf: &mut BytesMut,
+    len: usize,
+) -> io::Result<usize> {
+    let start = buf.len();
+    buf.resize(start + len, 0);
+    let result = stream.read_exact(&mut buf[start..]).await;
+    if result.is_err() {
+        buf.truncate(start);
+    }
+    result.map(|()| len)
+}
+
+/// A growable buffer that accumulates data read from a stream, to be taken in chunks.
+#[derive(Debug, Default)]
+pub struct BytesMutReader(BytesMut);
+
+impl BytesMutReader {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self(BytesMut::with_capacity(capacity))
+    }
+
+    /// Read whatever is available, up to the spare capacity: see `read_into_bytes_mut`.
+    pub async fn read_from<T: AsyncRead + Unpin>(mut self, stream: &mut T) -> io::Result<Self> {
+        read_into_bytes_mut(stream, &mut self.0).await?;
+        Ok(self)
+    }
+
+    /// Read exactly `len` bytes: see `read_n_into_bytes_mut`.
+    pub async fn read_n_from<T: AsyncRead + Unpin>(
+        mut self,
+        stream: &mut T,
+        len: usize,
+    ) -> io::Result<Self> {
+        read_n_into_bytes_mut(stream, &mut self.0, len).await?;
+        Ok(self)
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Take everything read so far, leaving the reader empty.
+    pub fn take_contents(&mut self) -> BytesMut {
+        self.0.split()
+    }
+
+    /// Return (what's left of) contents taken earlier, ahead of anything read since.
+    pub fn give_back_contents(self, contents: BytesMut) -> Self {
+        let mut contents = contents;
+        contents.unsplit(self.0);
+        Self(contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{buffer_unbuffer::BytesMutExtras, data_types::CookieData};
+    use futures::{executor::block_on, io::Cursor};
+
+    #[test]
+    fn cookie() {
+        let cookie = BytesMut::allocate_and_buffer(CookieData::make_cookie()).unwrap();
+        let mut data = cookie.to_vec();
+        data.extend_from_slice(b"next");
+        let mut reader = Cursor::new(data);
+        assert_eq!(block_on(read_cookie(&mut reader)).unwrap(), cookie.to_vec());
+        assert!(block_on(read_cookie(&mut reader)).is_err());
+    }
+
+    #[test]
+    fn read_n() {
+        let mut reader = Cursor::new(b"abcdefg".to_vec());
+        let mut buf = BytesMut::from(&b"xy"[..]);
+        assert_eq!(
+            block_on(read_n_into_bytes_mut(&mut reader, &mut buf, 3)).unwrap(),
+            3
+        );
+        assert_eq!(&buf[..], b"xyabc");
+        // Not enough left: nothing is appended.
+        assert!(block_on(read_n_into_bytes_mut(&mut reader, &mut buf, 5)).is_err());
+        assert_eq!(&buf[..], b"xyabc");
+    }
+
+    #[test]
+    fn read_available() {
+        let mut reader = Cursor::new(b"abcdefg".to_vec());
+        let mut buf = BytesMut::with_capacity(4);
+        buf.extend_from_slice(b"x");
+        let n = block_on(read_into_bytes_mut(&mut reader, &mut buf)).unwrap();
+        assert_eq!(&buf[..], &b"xabcdefg"[..1 + n]);
+        assert!(n > 0);
+
+        let mut buf = BytesMut::with_capacity(16);
+        let mut empty = Cursor::new(Vec::new());
+        assert_eq!(
+            block_on(read_into_bytes_mut(&mut empty, &mut buf)).unwrap(),
+            0
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn reader() {
+        let mut stream = Cursor::new(b"abcdefg".to_vec());
+        let mut reader =
+            block_on(BytesMutReader::with_capacity(16).read_n_from(&mut stream, 3)).unwrap();
+        assert_eq!(reader.len(), 3);
+        let mut taken = reader.take_contents();
+        assert!(reader.is_empty());
+        assert_eq!(&taken[..], b"abc");
+
+        let _ = taken.split_to(1);
+        reader = block_on(reader.read_from(&mut stream)).unwrap();
+        reader = reader.give_back_contents(taken);
+        assert_eq!(&reader.take_contents()[..], b"bcdefg");
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! A Bevy plugin delivering tracker and button reports from a VRPN server.
+//!
+//! The connection runs on its own thread (see `vrpn_async_std::threaded`).
+//! By default, reports are delivered frame-synchronized: once per frame, in `PreUpdate`,
+//! everything received since the last frame becomes `TrackerPose`, `ButtonPress`,
+//! and `VrpnConnectionEvent` events, and `LatestPoses` is updated,
+//! so every system in a frame sees the same reports. See `DeliveryMode` for the alternative.
+//!
+//! ```no_run
+//! use bevy_app::{App, Update};
+//! use bevy_ecs::prelude::*;
+//! use vrpn::bevy_plugin::{TrackerPose, VrpnPlugin};
+//!
+//! fn print_poses(mut poses: EventReader<TrackerPose>) {
+//!     for pose in poses.read() {
+//!         println!("{:?}: {:?}", pose.device, pose.report.body.pos);
+//!     }
+//! }
+//!
+//! App::new()
+//!     .add_plugins(VrpnPlugin::new("localhost".parse().unwrap()).tracker("Tracker0"))
+//!     .add_systems(Update, print_poses)
+//!     .run();
+//! ```
+
+use crate::{
+    button::ButtonChange,
+    connection::{Connection, ConnectionEvent},
+    data_types::{id_types::Sensor, SenderName, TypedMessage},
+    tracker::PoseReport,
+    vrpn_async_std::{connection_ip::ConnectionIp, threaded::ThreadedConnection},
+    Result, ServerInfo,
+};
+use bevy_app::{App, Plugin, PostUpdate, PreUpdate};
+use bevy_ecs::prelude::*;
+use std::{
+    collections::HashMap,
+    sync::{mpsc::Receiver, Mutex, PoisonError},
+};
+
+/// When during a frame received reports are delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeliveryMode {
+    /// Once per frame, in `PreUpdate`: every system in a frame sees the same reports.
+    #[default]
+    FrameSynchronized,
+    /// In `PreUpdate`, then again in `PostUpdate`, so systems there, e.g. preparing rendering,
+    /// see the poses received during the frame: lower latency, at the cost of
+    /// `PostUpdate` systems seeing reports that `Update` systems did not.
+    LateLatched,
+}
+
+/// Connects to a server when added to an app, delivering the reports of the given devices.
+#[derive(Debug, Clone)]
+pub struct VrpnPlugin {
+    server: ServerInfo,
+    trackers: Vec<SenderName>,
+    buttons: Vec<SenderName>,
+    delivery: DeliveryMode,
+}
+
+impl VrpnPlugin {
+    /// Connect to a server, reconnecting whenever the connection is lost.
+    pub fn new(server: ServerInfo) -> VrpnPlugin {
+        VrpnPlugin {
+            server,
+            trackers: Vec::new(),
+            buttons: Vec::new(),
+            delivery: DeliveryMode::default(),
+        }
+    }
+
+    /// Deliver the poses reported by a tracker.
+    pub fn tracker(mut self, name: impl Into<SenderName>) -> VrpnPlugin {
+        self.trackers.push(name.into());
+        self
+    }
+
+    /// Deliver the changes reported by a button device.
+    pub fn button(mut self, name: impl Into<SenderName>) -> VrpnPlugin {
+        self.buttons.push(name.into());
+        self
+    }
+
+    /// Choose when reports are delivered: frame-synchronized by default.
+    pub fn delivery(mut self, mode: DeliveryMode) -> VrpnPlugin {
+        self.delivery = mode;
+        self
+    }
+
+    fn connect(&self) -> Result<VrpnConnection> {
+        let connection = ConnectionIp::client_builder(self.server.clone())
+            .reconnect(true)
+            .build()?;
+        let threaded = ThreadedConnection::spawn(connection)?;
+        let trackers = self
+            .trackers
+            .iter()
+            .map(|name| {
+                let sender = threaded.connection().register_sender(name.clone())?;
+                let (rx, _) = threaded.typed_channel(Some(sender))?;
+                Ok((name.clone(), rx))
+            })
+            .collect::<Result<_>>()?;
+        let buttons = self
+            .buttons
+            .iter()
+            .map(|name| {
+                let sender = threaded.connection().register_sender(name.clone())?;
+                let (rx, _) = threaded.typed_channel(Some(sender))?;
+                Ok((name.clone(), rx))
+            })
+            .collect::<Result<_>>()?;
+        Ok(VrpnConnection(Mutex::new(Channels {
+            threaded,
+            trackers,
+            buttons,
+        })))
+    }
+}
+
+impl Plugin for VrpnPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<TrackerPose>()
+            .add_event::<ButtonPress>()
+            .add_event::<VrpnConnectionEvent>()
+            .init_resource::<LatestPoses>();
+        match self.connect() {
+            Ok(connection) => {
+                app.insert_resource(connection)
+                    .add_systems(PreUpdate, deliver_reports);
+                if self.delivery == DeliveryMode::LateLatched {
+                    app.add_systems(PostUpdate, deliver_reports);
+                }
+            }
+            Err(e) => eprintln!("Could not connect to {:?}: {}", self.server, e),
+        }
+    }
+}
+
+/// A pose reported by a tracker.
+#[derive(Event, Debug, Clone)]
+pub struct TrackerPose {
+    pub device: SenderName,
+    pub report: TypedMessage<PoseReport>,
+}
+
+/// A button pressed or released.
+#[derive(Event, Debug, Clone)]
+pub struct ButtonPress {
+    pub device: SenderName,
+    pub change: TypedMessage<ButtonChange>,
+}
+
+/// Something that happened to the connection, such as losing the server.
+#[derive(Event, Debug, Clone)]
+pub struct VrpnConnectionEvent(pub ConnectionEvent);
+
+/// The most recent pose of each tracker sensor, as of the latest delivery.
+#[derive(Resource, Debug, Default)]
+pub struct LatestPoses(pub HashMap<(SenderName, Sensor), PoseReport>);
+
+impl LatestPoses {
+    pub fn get(&self, device: &SenderName, sensor: Sensor) -> Option<&PoseReport> {
+        self.0.get(&(device.clone(), sensor))
+    }
+}
+
+struct Channels {
+    threaded: ThreadedConnection,
+    trackers: Vec<(SenderName, Receiver<TypedMessage<PoseReport>>)>,
+    buttons: Vec<(SenderName, Receiver<TypedMessage<ButtonChange>>)>,
+}
+
+/// The connection, as a resource.
+#[derive(Resource)]
+pub struct VrpnConnection(Mutex<Channels>);
+
+impl VrpnConnection {
+    /// Whether the connection thread is still running: it stops only on error.
+    pub fn is_running(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .threaded
+            .is_running()
+    }
+}
+
+fn deliver_reports(
+    connection: Res<VrpnConnection>,
+    mut latest: ResMut<LatestPoses>,
+    mut poses: EventWriter<TrackerPose>,
+    mut presses: EventWriter<ButtonPress>,
+    mut events: EventWriter<VrpnConnectionEvent>,
+) {
+    let channels = connection.0.lock().unwrap_or_else(PoisonError::into_inner);
+    for (device, rx) in &channels.trackers {
+        for report in rx.try_iter() {
+            latest
+                .0
+                .insert((device.clone(), report.body.sensor), report.body.clone());
+            poses.write(TrackerPose {
+                device: device.clone(),
+                report,
+            });
+        }
+    }
+    for (device, rx) in &channels.buttons {
+        for change in rx.try_iter() {
+            presses.write(ButtonPress {
+                device: device.clone(),
+                change,
+            });
+        }
+    }
+    match channels.threaded.poll_events() {
+        Ok(new_events) => {
+            events.write_batch(new_events.into_iter().map(VrpnConnectionEvent));
+        }
+        Err(e) => eprintln!("VRPN connection stopped: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        button::ButtonChange,
+        data_types::{ClassOfService, Quat, Vec3},
+        testing::LoopbackServer,
+    };
+    use bevy_ecs::event::Events;
+    use std::time::{Duration, Instant};
+
+    /// Update the app until it has delivered a pose and a button press, or give up,
+    /// collecting the events it delivered.
+    fn update_until_delivered(app: &mut App) -> (Vec<TrackerPose>, Vec<ButtonPress>) {
+        let mut pose_cursor = app.world().resource::<Events<TrackerPose>>().get_cursor();
+        let mut press_cursor = app.world().resource::<Events<ButtonPress>>().get_cursor();
+        let (mut poses, mut presses) = (Vec::new(), Vec::new());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while (poses.is_empty() || presses.is_empty()) && Instant::now() < deadline {
+            app.update();
+            let world = app.world();
+            poses.extend(pose_cursor.read(world.resource()).cloned());
+            presses.extend(press_cursor.read(world.resource()).cloned());
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        (poses, presses)
+    }
+
+    fn delivers(mode: DeliveryMode) {
+        let server = LoopbackServer::start().unwrap();
+        let tracker = server.register::<PoseReport>("Tracker0").unwrap();
+        let button = server.register::<ButtonChange>("Button0").unwrap();
+        let mut app = App::new();
+        app.add_plugins(
+            VrpnPlugin::new(server.url().parse().unwrap())
+                .tracker("Tracker0")
+                .button("Button0")
+                .delivery(mode),
+        );
+        app.update();
+        let late = app.get_schedule(PostUpdate).map_or(0, |s| s.systems_len());
+        assert_eq!(late, usize::from(mode == DeliveryMode::LateLatched));
+        assert!(app.world().resource::<VrpnConnection>().is_running());
+        assert!(app.world().resource::<LatestPoses>().0.is_empty());
+
+        server.wait_for_client(Duration::from_secs(5)).unwrap();
+        let pose = PoseReport {
+            sensor: Sensor(1),
+            pos: Vec3::new(1.0, 2.0, 3.0),
+            quat: Quat::identity(),
+        };
+        let change = ButtonChange {
+            button: 2,
+            pressed: true,
+        };
+        let connection = server.connection();
+        connection
+            .pack_message_body(None, button, change, ClassOfService::RELIABLE)
+            .unwrap();
+        connection
+            .pack_message_body(None, tracker, pose.clone(), ClassOfService::RELIABLE)
+            .unwrap();
+        let (poses, presses) = update_until_delivered(&mut app);
+
+        assert_eq!(poses.len(), 1);
+        assert_eq!(poses[0].device, SenderName::from("Tracker0"));
+        assert_eq!(poses[0].report.body, pose);
+        assert_eq!(presses.len(), 1);
+        assert_eq!(presses[0].device, SenderName::from("Button0"));
+        assert_eq!(presses[0].change.body, change);
+        assert_eq!(
+            app.world()
+                .resource::<LatestPoses>()
+                .get(&SenderName::from("Tracker0"), Sensor(1)),
+            Some(&pose)
+        );
+    }
+
+    #[test]
+    fn frame_synchronized() {
+        delivers(DeliveryMode::FrameSynchronized);
+    }
+
+    #[test]
+    fn late_latched() {
+        delivers(DeliveryMode::LateLatched);
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Measure the throughput of message decoding alone, without sockets.
+//!
+//! Usage: `codec_bench [messages] [body_bytes]`, by default a million messages
+//! with bodies the size of a tracker pose. Build in release mode for meaningful numbers.
+//!
+//! Generates an in-memory capture of the messages as they would arrive on a TCP connection,
+//! then decodes it with a `MessageDecoder`, fed in chunks the size of a typical socket read:
+//! once into owned messages, and once into borrowed views, as a forwarding proxy might.
+//! Reports the best of several runs of each in messages and megabytes per second,
+//! for comparison with the C++ implementation or between versions of this one.
+
+extern crate bytes;
+extern crate vrpn;
+
+use bytes::{Bytes, BytesMut};
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+use vrpn::{
+    buffer_unbuffer::BytesMutExtras,
+    codec::MessageDecoder,
+    data_types::{
+        id_types::{MessageTypeId, SenderId, SequenceNumber},
+        GenericBody, GenericMessage, Message, MessageHeader,
+    },
+    Result, VrpnError,
+};
+
+const DEFAULT_MESSAGES: usize = 1_000_000;
+/// Sensor, padding, position, and orientation.
+const DEFAULT_BODY_BYTES: usize = 68;
+/// Bytes handed to the decoder at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+const RUNS: usize = 5;
+
+/// Decodes a capture, returning how many messages and body bytes were decoded.
+type DecodeFn = fn(&[u8]) -> Result<(usize, usize)>;
+
+fn arg(args: &[String], index: usize, default: usize) -> Result<usize> {
+    match args.get(index) {
+        Some(arg) => arg
+            .parse()
+            .map_err(|_| VrpnError::OtherMessage(format!("not a number: {}", arg))),
+        None => Ok(default),
+    }
+}
+
+/// Serialize `count` messages as they would be received, from a few senders and types.
+fn capture(count: usize, body_len: usize) -> Result<Bytes> {
+    let body = Bytes::from(vec![0xa5u8; body_len]);
+    let mut buf = BytesMut::new();
+    for i in 0..count {
+        let header = MessageHeader::new(
+            None,
+            MessageTypeId((i % 3) as i32),
+            SenderId((i % 4) as i32),
+        );
+        let msg = GenericMessage::from_header_and_body(header, GenericBody::new(body.clone()))
+            .into_sequenced_message(SequenceNumber(i as u32));
+        buf.reserve_and_buffer(&msg)?;
+    }
+    Ok(buf.freeze())
+}
+
+/// Decode the whole capture, returning how many messages and body bytes were decoded.
+fn decode(capture: &[u8]) -> Result<(usize, usize)> {
+    let mut decoder = MessageDecoder::with_capacity(CHUNK_SIZE * 2);
+    let mut messages = 0;
+    let mut body_bytes = 0;
+    for chunk in capture.chunks(CHUNK_SIZE) {
+        decoder.extend_from_slice(chunk);
+        while let Some(msg) = decoder.decode_next()? {
+            messages += 1;
+            body_bytes += black_box(msg).into_inner().body.into_inner().len();
+        }
+    }
+    Ok((messages, body_bytes))
+}
+
+/// Like `decode`, but only borrowing each message from the decoder's buffer.
+fn decode_views(capture: &[u8]) -> Result<(usize, usize)> {
+    let mut decoder = MessageDecoder::with_capacity(CHUNK_SIZE * 2);
+    let mut messages = 0;
+    let mut body_bytes = 0;
+    for chunk in capture.chunks(CHUNK_SIZE) {
+        decoder.extend_from_slice(chunk);
+        while let Some(view) = decoder.decode_next_view()? {
+            messages += 1;
+            body_bytes += black_box(view).body().len();
+        }
+    }
+    Ok((messages, body_bytes))
+}
+
+/// Time the best of several runs of a decoding function over the capture, checking its results.
+fn bench(capture: &Bytes, count: usize, body_len: usize, decode: DecodeFn) -> Result<Duration> {
+    let mut best = Duration::MAX;
+    for run in 1..=RUNS {
+        let start = Instant::now();
+        let (messages, body_bytes) = decode(black_box(capture))?;
+        let elapsed = start.elapsed();
+        if messages != count || body_bytes != count * body_len {
+            return Err(VrpnError::OtherMessage(format!(
+                "decoded {} messages and {} body bytes, expected {} and {}",
+                messages,
+                body_bytes,
+                count,
+                count * body_len
+            )));
+        }
+        println!("run {}: {:.3} s", run, elapsed.as_secs_f64());
+        best = best.min(elapsed);
+    }
+    Ok(best)
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let count = arg(&args, 0, DEFAULT_MESSAGES)?;
+    let body_len = arg(&args, 1, DEFAULT_BODY_BYTES)?;
+    let capture = capture(count, body_len)?;
+    println!(
+        "Decoding {} messages with {} byte bodies: {:.1} MB captured",
+        count,
+        body_len,
+        capture.len() as f64 / 1e6
+    );
+
+    let decoders: [(&str, DecodeFn); 2] =
+        [("owned messages", decode), ("borrowed views", decode_views)];
+    for (name, decode) in decoders {
+        println!("{}:", name);
+        let secs = bench(&capture, count, body_len, decode)?.as_secs_f64();
+        println!(
+            "best: {:.0} messages/s, {:.1} MB/s",
+            count as f64 / secs,
+            capture.len() as f64 / 1e6 / secs
+        );
+    }
+    Ok(())
+}
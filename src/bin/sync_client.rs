@@ -12,13 +12,12 @@
 extern crate bytes;
 extern crate vrpn;
 
-use bytes::Bytes;
 use std::net::{SocketAddr, TcpStream};
 use vrpn::{
-    buffer_unbuffer::UnbufferFrom,
-    data_types::{cookie::check_ver_nonfile_compatible, CookieData, TypedMessage},
+    data_types::TypedMessage,
     handler::{HandlerCode, TypedHandler},
-    sync_io::{read_cookie, write_cookie, EndpointSyncTcp},
+    handshake::Handshake,
+    sync_io::{perform_handshake, EndpointSyncTcp},
     tracker::PoseReport,
     Result, TypeDispatcher,
 };
@@ -39,12 +38,7 @@ fn main() -> Result<()> {
     stream.set_nodelay(true)?;
 
     // We first write our cookie, then read and check the server's cookie, before the loop.
-    write_cookie(&mut stream, CookieData::make_cookie())?;
-    let cookie_buf = read_cookie(&mut stream)?;
-    let mut cookie_buf = Bytes::copy_from_slice(&cookie_buf[..]);
-
-    let msg = CookieData::unbuffer_from(&mut cookie_buf)?;
-    check_ver_nonfile_compatible(msg.version)?;
+    perform_handshake(&mut stream, &mut Handshake::client())?;
 
     let mut endpoint = EndpointSyncTcp::new(stream);
     let mut dispatcher = TypeDispatcher::new();
@@ -42,9 +42,9 @@ fn main() -> Result<()> {
     // Most of this should be handled by SequencedGenericMessage
     loop {
         // Read the message header and padding
-        let mut buf = BytesMut::with_capacity(24);
+        let mut buf = BytesMut::with_capacity(MessageSize::PADDED_HEADER_SIZE);
 
-        buf.resize(24, 0);
+        buf.resize(MessageSize::PADDED_HEADER_SIZE, 0);
         stream.read_exact(buf.as_mut())?;
 
         // Peek the size field, to compute the MessageSize.
@@ -24,7 +24,7 @@ use vrpn::{
     data_types::{
         cookie::check_ver_nonfile_compatible, CookieData, MessageSize, SequencedGenericMessage,
     },
-    vrpn_async_std::cookie::read_cookie,
+    handshake::futures_io::read_cookie,
     Result,
 };
 
@@ -84,7 +84,7 @@ fn try_decode(bytes_mut: &mut BytesMut) -> Result<Option<SequencedGenericMessage
 
 async fn try_read_header(stream: &mut TcpStream, bytes_mut: &mut BytesMut) -> Result<MessageSize> {
     assert!(bytes_mut.is_empty());
-    let mut header_buf = [0u8; 24];
+    let mut header_buf = [0u8; MessageSize::PADDED_HEADER_SIZE];
     AsyncReadExt::read_exact(stream, &mut header_buf).await?;
     let size = {
         let mut size_buf = std::io::Cursor::new(&header_buf);
@@ -21,9 +21,9 @@ use futures::StreamExt;
 use vrpn::{
     data_types::TypedMessage,
     handler::{HandlerCode, TypedHandler},
+    handshake::futures_io::{read_and_check_nonfile_cookie, send_nonfile_cookie},
     tracker::PoseReport,
-    vrpn_async_std::cookie::{read_and_check_nonfile_cookie, send_nonfile_cookie},
-    vrpn_async_std::AsyncReadMessagesExt,
+    vrpn_async::AsyncReadMessagesExt,
     Result,
 };
 
@@ -19,7 +19,7 @@ use async_std::{
 use futures::StreamExt;
 
 use vrpn::{
-    vrpn_async_std::cookie::{read_and_check_nonfile_cookie, send_nonfile_cookie},
+    handshake::futures_io::{read_and_check_nonfile_cookie, send_nonfile_cookie},
     TypeDispatcher,
 };
 
@@ -27,7 +27,7 @@ use vrpn::{
     data_types::TypedMessage,
     handler::{HandlerCode, TypedHandler},
     tracker::PoseReport,
-    vrpn_async_std::AsyncReadMessagesExt,
+    vrpn_async::AsyncReadMessagesExt,
     Result,
 };
 
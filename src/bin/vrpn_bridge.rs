@@ -0,0 +1,467 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Bridge from a VRPN server to clients at another site, forwarding selected devices
+//! in either direction.
+//!
+//! Run with, e.g.,
+//! `cargo run --features vrpn-async-std --bin vrpn_bridge -- otherhost 0.0.0.0:3884 bridge.map`
+//!
+//! Connects to the server as a client, and serves clients of its own on the given address:
+//! to them, the bridge is the server. Each line of the mapping file names a device
+//! on one side and the name to forward it as on the other:
+//!
+//! ```text
+//! # Tracker0 on the server, as SiteA_Tracker0 to the bridge's clients.
+//! Tracker0 -> SiteA_Tracker0
+//! # Head, sent by a client of the bridge, as SiteB_Head to the server.
+//! SiteB_Head <- Head
+//! ```
+//!
+//! Messages are re-sent with the new sender name, keeping their type and time stamp.
+//! Messages forwarded to the server are only dispatched there, like those from any client:
+//! its own clients don't see them, unless it relays them as a forwarding server does.
+//!
+//! A name forwarded *to* a side may not also be forwarded *from* it. That can't catch
+//! loops through other bridges or forwarding servers, so each forwarded message is also
+//! remembered for a while, by its time stamp, type and body: should it come back,
+//! from either side, it is dropped.
+
+extern crate async_std;
+extern crate vrpn;
+
+use async_std::{net::TcpListener, task};
+use futures::{
+    channel::mpsc::{unbounded, UnboundedSender},
+    future::{select, select_all, Either},
+    pin_mut, FutureExt, StreamExt,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+use vrpn::{
+    data_types::{
+        id_types::LocalId, ClassOfService, GenericMessage, Message, MessageHeader, MessageTypeName,
+    },
+    handler::{Handler, HandlerCode},
+    vrpn_async_std::connection_ip::{ConnectionIp, ConnectionIpStream},
+    Connection, Result, ServerInfo, VrpnError,
+};
+
+/// How many forwarded messages are remembered, to recognize them should they come back.
+const REMEMBERED: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    ServerToClients,
+    ClientsToServer,
+}
+
+/// One device to forward, from its name on one side to a name on the other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Route {
+    direction: Direction,
+    from: String,
+    to: String,
+}
+
+fn parse_mapping(text: &str) -> Result<Vec<Route>> {
+    let mut routes = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let route = if let Some((server, clients)) = line.split_once("->") {
+            Route {
+                direction: Direction::ServerToClients,
+                from: server.trim().to_string(),
+                to: clients.trim().to_string(),
+            }
+        } else if let Some((server, clients)) = line.split_once("<-") {
+            Route {
+                direction: Direction::ClientsToServer,
+                from: clients.trim().to_string(),
+                to: server.trim().to_string(),
+            }
+        } else {
+            return Err(VrpnError::OtherMessage(format!(
+                "line {}: expected `Name -> Name` or `Name <- Name`, got {}",
+                i + 1,
+                line
+            )));
+        };
+        if route.from.is_empty() || route.to.is_empty() {
+            return Err(VrpnError::OtherMessage(format!(
+                "line {}: missing device name",
+                i + 1
+            )));
+        }
+        routes.push(route);
+    }
+    check_loops(&routes)?;
+    Ok(routes)
+}
+
+/// Refuse any mapping that would forward a device back to the side it was forwarded from.
+fn check_loops(routes: &[Route]) -> Result<()> {
+    for route in routes {
+        let back = routes
+            .iter()
+            .find(|other| other.direction != route.direction && other.from == route.to);
+        if let Some(back) = back {
+            return Err(VrpnError::OtherMessage(format!(
+                "{} is forwarded as {}, which would be forwarded back as {}",
+                route.from, route.to, back.to
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The messages forwarded lately, to drop any that come back around a loop.
+#[derive(Debug, Default)]
+struct Forwarded {
+    order: VecDeque<u64>,
+    seen: HashSet<u64>,
+}
+
+impl Forwarded {
+    fn fingerprint(msg: &GenericMessage, type_name: &MessageTypeName) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        msg.header.time.hash(&mut hasher);
+        type_name.hash(&mut hasher);
+        msg.body.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Remember a message about to be forwarded, returning false if it was forwarded before.
+    fn remember(&mut self, msg: &GenericMessage, type_name: &MessageTypeName) -> bool {
+        let fingerprint = Forwarded::fingerprint(msg, type_name);
+        if !self.seen.insert(fingerprint) {
+            return false;
+        }
+        self.order.push_back(fingerprint);
+        if self.order.len() > REMEMBERED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Queues every message from one sender for forwarding along a route.
+#[derive(Debug)]
+struct ForwardHandler {
+    route: usize,
+    tx: UnboundedSender<(usize, GenericMessage)>,
+}
+
+impl Handler for ForwardHandler {
+    fn handle(&mut self, msg: &GenericMessage) -> Result<HandlerCode> {
+        if self.tx.unbounded_send((self.route, msg.clone())).is_err() {
+            return Ok(HandlerCode::RemoveThisHandler);
+        }
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
+/// The connection to the server and the one serving the bridge's clients.
+struct Bridge {
+    upstream: Arc<ConnectionIp>,
+    served: Arc<ConnectionIp>,
+    routes: Vec<Route>,
+}
+
+impl Bridge {
+    /// The source and destination connections of a route.
+    fn ends(&self, route: &Route) -> (&ConnectionIp, &ConnectionIp) {
+        match route.direction {
+            Direction::ServerToClients => (&self.upstream, &self.served),
+            Direction::ClientsToServer => (&self.served, &self.upstream),
+        }
+    }
+
+    /// Re-send a message from a route's source connection on its destination connection.
+    fn forward(&self, route: &Route, msg: GenericMessage, forwarded: &mut Forwarded) -> Result<()> {
+        let (source, destination) = self.ends(route);
+        let type_name = source
+            .dispatcher()
+            .lock()?
+            .get_type_name(LocalId(msg.header.message_type));
+        let type_name = match type_name {
+            Some(name) => name,
+            None => {
+                eprintln!("Dropping message of unknown type from {}", route.from);
+                return Ok(());
+            }
+        };
+        if !forwarded.remember(&msg, &type_name) {
+            eprintln!(
+                "Dropping message from {} that came back around a loop",
+                route.from
+            );
+            return Ok(());
+        }
+        let message_type = destination.register_type(type_name)?;
+        let sender = destination.register_sender(route.to.as_str())?;
+        let header = MessageHeader::new(Some(msg.header.time), message_type, sender);
+        destination.pack_generic_message(
+            GenericMessage::from_header_and_body(header, msg.body),
+            ClassOfService::RELIABLE,
+        )
+    }
+
+    /// Accept clients and forward messages until a connection fails.
+    async fn run(self, listener: TcpListener) -> Result<()> {
+        let (tx, mut rx) = unbounded();
+        for (i, route) in self.routes.iter().enumerate() {
+            let (source, destination) = self.ends(route);
+            let sender = source.register_sender(route.from.as_str())?;
+            source.add_handler(
+                Box::new(ForwardHandler {
+                    route: i,
+                    tx: tx.clone(),
+                }),
+                None,
+                Some(sender),
+            )?;
+            // Registered now, so it is described to each client as it connects.
+            destination.register_sender(route.to.as_str())?;
+            println!("{:?}: {} as {}", route.direction, route.from, route.to);
+        }
+        drop(tx);
+
+        let served = Arc::clone(&self.served);
+        let accepting = task::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => return Err(VrpnError::from(e)),
+                };
+                if let Err(e) = served.accept_client(stream).await {
+                    eprintln!("Could not accept client: {}", e);
+                }
+            }
+        });
+        // Each connection gets its own task to drive it.
+        let drive = |connection| {
+            task::spawn(async move {
+                let mut stream = ConnectionIpStream::new(connection);
+                while let Some(result) = stream.next().await {
+                    result?;
+                }
+                Ok(())
+            })
+            .boxed()
+        };
+        let forwarding = async {
+            let mut forwarded = Forwarded::default();
+            while let Some((i, msg)) = rx.next().await {
+                self.forward(&self.routes[i], msg, &mut forwarded)?;
+            }
+            Ok(())
+        };
+        pin_mut!(forwarding);
+        let tasks = select_all(vec![
+            drive(Arc::clone(&self.upstream)),
+            drive(Arc::clone(&self.served)),
+            accepting.boxed(),
+        ]);
+        match select(forwarding, tasks).await {
+            Either::Left((result, _)) => result,
+            Either::Right(((result, _, _), _)) => result,
+        }
+    }
+}
+
+async fn async_main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() != 3 {
+        eprintln!("usage: vrpn_bridge server listen_address mapping_file");
+        return Ok(());
+    }
+    let server: ServerInfo = args[0].parse()?;
+    let listener = TcpListener::bind(&args[1]).await?;
+    let routes = parse_mapping(&std::fs::read_to_string(&args[2])?)?;
+
+    let bridge = Bridge {
+        upstream: ConnectionIp::client_builder(server)
+            .reconnect(true)
+            .build()?,
+        served: ConnectionIp::new_server(None, None)?,
+        routes,
+    };
+    println!("Serving on {}", listener.local_addr()?);
+    bridge.run(listener).await
+}
+
+fn main() -> Result<()> {
+    task::block_on(async_main())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use vrpn::{
+        data_types::{
+            id_types::SenderId, GenericBody, MessageTypeId, Microseconds, Seconds,
+            StaticMessageTypeName, TimeVal,
+        },
+        ConnectionStatus,
+    };
+
+    fn route(direction: Direction, from: &str, to: &str) -> Route {
+        Route {
+            direction,
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    #[test]
+    fn mapping() {
+        let routes = parse_mapping(
+            "# A comment\n\
+             Tracker0 -> SiteA_Tracker0  # trailing comment\n\
+             \n\
+             SiteB_Head <- Head\n",
+        )
+        .unwrap();
+        assert_eq!(
+            routes,
+            vec![
+                route(Direction::ServerToClients, "Tracker0", "SiteA_Tracker0"),
+                route(Direction::ClientsToServer, "Head", "SiteB_Head"),
+            ]
+        );
+        assert!(parse_mapping("").unwrap().is_empty());
+        assert!(parse_mapping("Tracker0 SiteA_Tracker0").is_err());
+        assert!(parse_mapping("Tracker0 ->").is_err());
+        assert!(parse_mapping("<- Head").is_err());
+    }
+
+    #[test]
+    fn loops() {
+        let there = route(Direction::ServerToClients, "Tracker0", "Remote0");
+        let back = route(Direction::ClientsToServer, "Remote0", "Tracker1");
+        assert!(check_loops(std::slice::from_ref(&there)).is_ok());
+        assert!(check_loops(&[there.clone(), back]).is_err());
+        // The same name on both sides is fine, as long as only one of them is forwarded.
+        let other = route(Direction::ClientsToServer, "Tracker0", "Head");
+        assert!(check_loops(&[there.clone(), other]).is_ok());
+        assert!(parse_mapping("Tracker0 -> Remote0\nTracker1 <- Remote0").is_err());
+    }
+
+    fn message(sec: i32) -> GenericMessage {
+        GenericMessage::from_header_and_body(
+            MessageHeader::new(
+                Some(TimeVal::new(Seconds(sec), Microseconds(0))),
+                MessageTypeId(5),
+                SenderId(1),
+            ),
+            GenericBody::new(Bytes::from_static(b"body")),
+        )
+    }
+
+    #[test]
+    fn forwarded_once() {
+        let type_name = MessageTypeName::from(&b"vrpn_Tracker Pos_Quat"[..]);
+        let mut forwarded = Forwarded::default();
+        assert!(forwarded.remember(&message(0), &type_name));
+        assert!(!forwarded.remember(&message(0), &type_name));
+        // Another time stamp or type makes another message.
+        assert!(forwarded.remember(&message(1), &type_name));
+        let other_type = MessageTypeName::from(&b"vrpn_Button Change"[..]);
+        assert!(forwarded.remember(&message(0), &other_type));
+        // Only so many are remembered.
+        for sec in 2..=REMEMBERED as i32 {
+            assert!(forwarded.remember(&message(sec), &type_name));
+        }
+        assert!(forwarded.remember(&message(0), &type_name));
+    }
+
+    #[cfg(feature = "tracker")]
+    #[test]
+    fn serves_clients() {
+        use std::time::Duration;
+        use vrpn::{
+            data_types::{id_types::Sensor, Quat, Vec3},
+            subscription::{Subscription, SubscriptionEvent},
+            tracker::PoseReport,
+        };
+
+        /// Drive a connection in the background.
+        fn drive(connection: &Arc<ConnectionIp>) {
+            let mut stream = ConnectionIpStream::new(Arc::clone(connection));
+            task::spawn(async move { while stream.next().await.is_some() {} });
+        }
+
+        /// Wait until a server has a client.
+        async fn connected(server: &ConnectionIp) {
+            while server.status() == ConnectionStatus::Server(0) {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        task::block_on(async {
+            // The server the bridge connects to, with a tracker.
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server_info = format!("tcp://{}", listener.local_addr().unwrap());
+            let server = ConnectionIp::new_server(None, None).unwrap();
+            let tracker = server.register_sender("Tracker0").unwrap();
+            server
+                .register_type(StaticMessageTypeName(b"vrpn_Tracker Pos_Quat"))
+                .unwrap();
+            let accepting = Arc::clone(&server);
+            task::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                accepting.accept_client(stream).await.unwrap();
+            });
+            drive(&server);
+
+            let bridge = Bridge {
+                upstream: ConnectionIp::new_client(server_info.parse().unwrap(), None, None)
+                    .unwrap(),
+                served: ConnectionIp::new_server(None, None).unwrap(),
+                routes: parse_mapping("Tracker0 -> SiteA_Tracker0").unwrap(),
+            };
+            let served = Arc::clone(&bridge.served);
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let bridge_info = format!("tcp://{}", listener.local_addr().unwrap());
+            task::spawn(bridge.run(listener));
+
+            let client =
+                ConnectionIp::new_client(bridge_info.parse().unwrap(), None, None).unwrap();
+            let mut poses = Subscription::<PoseReport>::new(&client, "SiteA_Tracker0").unwrap();
+            drive(&client);
+            connected(&server).await;
+            connected(&served).await;
+
+            let pose = PoseReport {
+                sensor: Sensor(1),
+                pos: Vec3::new(1.0, 2.0, 3.0),
+                quat: Quat::identity(),
+            };
+            server
+                .pack_message_body(None, tracker, pose.clone(), ClassOfService::RELIABLE)
+                .unwrap();
+            let received = async {
+                loop {
+                    if let Some(SubscriptionEvent::Message(msg)) = poses.next().await {
+                        return msg;
+                    }
+                }
+            };
+            let msg = async_std::future::timeout(Duration::from_secs(5), received)
+                .await
+                .unwrap();
+            assert_eq!(msg.body, pose);
+        });
+    }
+}
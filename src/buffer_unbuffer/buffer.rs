@@ -4,7 +4,7 @@
 
 //! Extension traits related to buffering types.
 
-use bytes::{BufMut, BytesMut};
+use bytes::{buf::UninitSlice, BufMut, BytesMut};
 
 use super::{BufferSize, BufferUnbufferError, WrappedConstantSize};
 
@@ -18,14 +18,33 @@ where
     /// # Errors
     /// If buffering fails.
     fn allocate_and_buffer<T: BufferTo>(v: T) -> std::result::Result<Self, BufferUnbufferError>;
+
+    /// Reserve enough space at the end of the buffer for the given value, then serialize it there.
+    ///
+    /// The value is written straight into the spare (uninitialized) capacity,
+    /// with no zero-filling and no intermediate buffer.
+    ///
+    /// # Errors
+    /// If buffering fails, in which case the buffer's length is unchanged.
+    fn reserve_and_buffer<T: BufferTo>(&mut self, v: &T) -> BufferResult;
 }
 
 impl BytesMutExtras for BytesMut {
     fn allocate_and_buffer<T: BufferTo>(v: T) -> std::result::Result<Self, BufferUnbufferError> {
         let mut buf = Self::with_capacity(v.buffer_size());
-        v.buffer_to(&mut buf)?;
+        debug_checked_buffer_to(&v, &mut buf)?;
         Ok(buf)
     }
+
+    fn reserve_and_buffer<T: BufferTo>(&mut self, v: &T) -> BufferResult {
+        let initial_len = self.len();
+        self.reserve(v.buffer_size());
+        let result = debug_checked_buffer_to(v, self);
+        if result.is_err() {
+            self.truncate(initial_len);
+        }
+        result
+    }
 }
 
 /// Shorthand name for what a buffering operation should return.
@@ -51,7 +70,10 @@ impl<T: WrappedConstantSize> BufferTo for T {
 }
 
 /// Check whether a buffer has enough bytes remaining to unbuffer a given length
-pub fn check_buffer_remaining<T: BufMut>(buf: &mut T, required_len: usize) -> BufferResult {
+pub fn check_buffer_remaining<T: BufMut + ?Sized>(
+    buf: &mut T,
+    required_len: usize,
+) -> BufferResult {
     let bytes_len = buf.remaining_mut();
     if bytes_len < required_len {
         Err(BufferUnbufferError::OutOfBuffer)
@@ -59,3 +81,115 @@ pub fn check_buffer_remaining<T: BufMut>(buf: &mut T, required_len: usize) -> Bu
         Ok(())
     }
 }
+
+/// Wraps a `BufMut`, counting the bytes written through it.
+///
+/// Used by `checked_buffer_to` to check that a value writes exactly as many bytes
+/// as its `BufferSize` impl claims.
+#[derive(Debug)]
+pub struct CountingBufMut<'a, B: BufMut> {
+    inner: &'a mut B,
+    written: usize,
+}
+
+impl<'a, B: BufMut> CountingBufMut<'a, B> {
+    pub fn new(inner: &'a mut B) -> CountingBufMut<'a, B> {
+        CountingBufMut { inner, written: 0 }
+    }
+
+    /// Number of bytes written so far.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+}
+
+// Safety: only forwards to the wrapped buffer, adding the count.
+unsafe impl<B: BufMut> BufMut for CountingBufMut<'_, B> {
+    fn remaining_mut(&self) -> usize {
+        self.inner.remaining_mut()
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        self.inner.advance_mut(cnt);
+        self.written += cnt;
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        self.inner.chunk_mut()
+    }
+}
+
+/// Serialize a value, failing with `BufferUnbufferError::SizeMismatch`
+/// if it wrote a different number of bytes than its `buffer_size()`.
+///
+/// For testing `BufferTo` and `BufferSize` impls, e.g. of custom message bodies:
+/// the crate makes this check itself (as a debug assertion) when it serializes bodies.
+pub fn checked_buffer_to<T: BufferTo + ?Sized, B: BufMut>(v: &T, buf: &mut B) -> BufferResult {
+    let expected = v.buffer_size();
+    let mut counting = CountingBufMut::new(buf);
+    v.buffer_to(&mut counting)?;
+    let written = counting.written();
+    if written != expected {
+        return Err(BufferUnbufferError::SizeMismatch { expected, written });
+    }
+    Ok(())
+}
+
+/// Serialize a value, asserting in debug builds that it wrote exactly `buffer_size()` bytes.
+pub(crate) fn debug_checked_buffer_to<T: BufferTo + ?Sized, B: BufMut>(
+    v: &T,
+    buf: &mut B,
+) -> BufferResult {
+    if cfg!(debug_assertions) {
+        match checked_buffer_to(v, buf) {
+            Err(BufferUnbufferError::SizeMismatch { expected, written }) => panic!(
+                "buffer_size() of {} does not match the {} bytes written by buffer_to()",
+                expected, written
+            ),
+            result => result,
+        }
+    } else {
+        v.buffer_to(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_unbuffer::BufferSize;
+
+    /// Claims one more byte than it writes.
+    struct Undersized;
+    impl BufferSize for Undersized {
+        fn buffer_size(&self) -> usize {
+            5
+        }
+    }
+    impl BufferTo for Undersized {
+        fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+            1u32.buffer_to(buf)
+        }
+    }
+
+    #[test]
+    fn size_checking() {
+        let mut buf = BytesMut::new();
+        checked_buffer_to(&1u32, &mut buf).unwrap();
+        checked_buffer_to(&vec![2u16, 3u16], &mut buf).unwrap();
+        assert_eq!(buf.len(), 12);
+        assert_eq!(
+            checked_buffer_to(&Undersized, &mut buf),
+            Err(BufferUnbufferError::SizeMismatch {
+                expected: 5,
+                written: 4
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn debug_assertion() {
+        let _ = BytesMut::allocate_and_buffer(Undersized);
+    }
+}
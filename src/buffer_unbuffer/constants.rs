@@ -18,4 +18,7 @@ pub const CNAME_LEN: usize = 100;
 /// default port to use
 pub const DEFAULT_PORT: u16 = 3883;
 
+/// Alignment, in bytes, of message headers and bodies on the wire (`vrpn_ALIGN`).
+///
+/// See `compute_padding()` and `padded()`.
 pub const ALIGN: usize = 8;
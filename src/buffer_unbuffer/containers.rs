@@ -0,0 +1,169 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Buffer/unbuffer support for common container shapes used in message bodies.
+//!
+//! - Fixed-size arrays `[T; N]` are just `N` consecutive values, with no prefix.
+//! - `Vec<T>` is preceded by its element count as a `u32`.
+//! - `Option<T>` is for an optional **trailing** field: see below.
+//!
+//! # Optional trailing fields
+//!
+//! Some VRPN messages have gained extra fields at the end over time,
+//! which older senders do not include.
+//! Wrap such a field in `Option` and put it last in your message body:
+//! `None` is written as nothing at all,
+//! and reading produces `None` if (and only if) the buffer is already empty.
+//! This only works for the final field(s) of a body, since it relies on
+//! the body being exactly the size of the data.
+//!
+//! ```
+//! use bytes::BytesMut;
+//! use vrpn::buffer_unbuffer::{BufferTo, UnbufferFrom};
+//!
+//! let mut buf = BytesMut::new();
+//! 5u32.buffer_to(&mut buf).unwrap();
+//! None::<f64>.buffer_to(&mut buf).unwrap();
+//! let mut buf = buf.freeze();
+//! assert_eq!(u32::unbuffer_from(&mut buf).unwrap(), 5);
+//! assert_eq!(Option::<f64>::unbuffer_from(&mut buf).unwrap(), None);
+//! ```
+
+use std::convert::{TryFrom, TryInto};
+
+use bytes::{Buf, BufMut};
+
+use super::{
+    check_buffer_remaining, check_unbuffer_remaining, size::ConstantBufferSize,
+    size_requirement::ExpandSizeRequirement, BufferResult, BufferSize, BufferTo,
+    BufferUnbufferError, UnbufferFrom, UnbufferResult,
+};
+
+impl<T: ConstantBufferSize, const N: usize> ConstantBufferSize for [T; N] {
+    fn constant_buffer_size() -> usize {
+        N * T::constant_buffer_size()
+    }
+}
+
+impl<T: ConstantBufferSize + BufferTo, const N: usize> BufferTo for [T; N] {
+    fn buffer_to<U: BufMut>(&self, buf: &mut U) -> BufferResult {
+        check_buffer_remaining(buf, Self::constant_buffer_size())?;
+        for elt in self.iter() {
+            elt.buffer_to(buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: ConstantBufferSize + UnbufferFrom, const N: usize> UnbufferFrom for [T; N] {
+    fn unbuffer_from<U: Buf>(buf: &mut U) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        let elts = (0..N)
+            .map(|_| T::unbuffer_from(buf))
+            .collect::<UnbufferResult<Vec<T>>>()?;
+        // Can't fail: we made exactly N elements.
+        elts.try_into()
+            .map_err(|_| BufferUnbufferError::HeaderSizeMismatch(N.to_string()))
+    }
+}
+
+impl<T: BufferSize> BufferSize for Vec<T> {
+    fn buffer_size(&self) -> usize {
+        u32::constant_buffer_size() + self.iter().map(BufferSize::buffer_size).sum::<usize>()
+    }
+}
+
+impl<T: BufferTo> BufferTo for Vec<T> {
+    fn buffer_to<U: BufMut>(&self, buf: &mut U) -> BufferResult {
+        check_buffer_remaining(buf, self.buffer_size())?;
+        let count = u32::try_from(self.len()).map_err(|_| BufferUnbufferError::OutOfBuffer)?;
+        count.buffer_to(buf)?;
+        for elt in self.iter() {
+            elt.buffer_to(buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: UnbufferFrom> UnbufferFrom for Vec<T> {
+    fn unbuffer_from<U: Buf>(buf: &mut U) -> UnbufferResult<Self> {
+        let count = u32::unbuffer_from(buf)? as usize;
+        // Don't trust the count when allocating: it came off the wire.
+        let mut ret = Vec::with_capacity(count.min(buf.remaining()));
+        for _ in 0..count {
+            ret.push(
+                T::unbuffer_from(buf).map_err(ExpandSizeRequirement::expand_size_requirement)?,
+            );
+        }
+        Ok(ret)
+    }
+}
+
+impl<T: BufferSize> BufferSize for Option<T> {
+    fn buffer_size(&self) -> usize {
+        self.as_ref().map_or(0, BufferSize::buffer_size)
+    }
+}
+
+impl<T: BufferTo> BufferTo for Option<T> {
+    fn buffer_to<U: BufMut>(&self, buf: &mut U) -> BufferResult {
+        match self {
+            Some(v) => v.buffer_to(buf),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: UnbufferFrom> UnbufferFrom for Option<T> {
+    fn unbuffer_from<U: Buf>(buf: &mut U) -> UnbufferResult<Self> {
+        if buf.has_remaining() {
+            T::unbuffer_from(buf).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{Bytes, BytesMut};
+
+    fn roundtrip<T: BufferTo + UnbufferFrom + PartialEq + std::fmt::Debug>(v: T) {
+        let mut buf = BytesMut::with_capacity(v.buffer_size());
+        v.buffer_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), v.buffer_size());
+        let mut buf = buf.freeze();
+        assert_eq!(T::unbuffer_from(&mut buf).unwrap(), v);
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn arrays() {
+        assert_eq!(<[f64; 3]>::constant_buffer_size(), 24);
+        roundtrip([1.0_f64, -2.5, 3.25]);
+        roundtrip([[1_i32, 2], [3, 4]]);
+
+        let mut buf = Bytes::from_static(&hex!("00 00 00 01 00 00"));
+        assert!(<[i32; 2]>::unbuffer_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn vecs() {
+        roundtrip(Vec::<u32>::new());
+        roundtrip(vec![1_u16, 2, 3]);
+        assert_eq!(vec![1_u16, 2, 3].buffer_size(), 4 + 6);
+
+        // Count claims more elements than are present
+        let mut buf = Bytes::from_static(&hex!("ff ff ff ff 00 01"));
+        assert!(Vec::<u16>::unbuffer_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn optional_trailing() {
+        roundtrip(Some(5_i32));
+        roundtrip(None::<i32>);
+        assert_eq!(None::<i32>.buffer_size(), 0);
+    }
+}
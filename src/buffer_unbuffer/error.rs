@@ -16,7 +16,7 @@ pub struct MessageSizeInvalid(pub u32);
 
 impl std::fmt::Display for MessageSizeInvalid {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Message size field {} is smaller than minimum", self.0)
+        write!(f, "Message size field {} is out of range", self.0)
     }
 }
 
@@ -31,12 +31,16 @@ pub enum BufferUnbufferError {
     UnexpectedAsciiData { actual: Bytes, expected: Bytes },
     #[error("buffering ran out of buffer space")]
     OutOfBuffer,
+    #[error("buffering wrote {written} bytes, but the value's buffer_size() is {expected}")]
+    SizeMismatch { expected: usize, written: usize },
     #[error("according to a length field we have complete data, but we need at least {0} additional bytes")]
     HeaderSizeMismatch(String),
     #[error("Error parsing {parsing_kind}: {s}")]
     ParseError { parsing_kind: String, s: String },
     #[error("{}", .0)]
     MessageSizeInvalid(MessageSizeInvalid),
+    #[error("message claims a size of {claimed} bytes, more than the maximum of {max}")]
+    MessageTooLarge { claimed: usize, max: usize },
 }
 
 impl From<SizeRequirement> for BufferUnbufferError {
@@ -6,6 +6,7 @@
 
 pub mod buffer;
 pub mod constants;
+mod containers;
 mod error;
 mod primitives;
 pub(crate) mod size;
@@ -16,11 +17,17 @@ pub mod unbuffer;
 pub use crate::buffer_unbuffer::{
     error::{BufferUnbufferError, MessageSizeInvalid},
     primitives::*,
-    size::{BufferSize, ConstantBufferSize, EmptyMessage, WrappedConstantSize},
+    size::{
+        compute_padding, padded, BufferSize, ConstantBufferSize, EmptyMessage, WrappedConstantSize,
+    },
 };
 
 pub use crate::buffer_unbuffer::{
-    buffer::{check_buffer_remaining, BufferResult, BufferTo, BytesMutExtras},
+    buffer::{
+        check_buffer_remaining, checked_buffer_to, BufferResult, BufferTo, BytesMutExtras,
+        CountingBufMut,
+    },
+    constants::ALIGN,
     size_requirement::SizeRequirement,
     unbuffer::{
         check_unbuffer_remaining, consume_expected, peek_u32, unbuffer_decimal_digits,
@@ -2,7 +2,28 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
+//! Buffering of numeric primitives, all in network byte order (big-endian).
+//!
+//! Some devices put little-endian fields in their message bodies:
+//! wrap those fields in [`Le`] to declare that, rather than swapping bytes by hand.
+//! [`Be`] is the same as the bare type, for bodies that want to be explicit throughout.
+//!
+//! ```
+//! use bytes::BytesMut;
+//! use vrpn::buffer_unbuffer::{Be, BufferTo, Le, UnbufferFrom};
+//!
+//! let mut buf = BytesMut::new();
+//! Le(0x1234u16).buffer_to(&mut buf).unwrap();
+//! Be(0x1234u16).buffer_to(&mut buf).unwrap();
+//! assert_eq!(&buf[..], &[0x34, 0x12, 0x12, 0x34]);
+//!
+//! let mut buf = buf.freeze();
+//! assert_eq!(Le::<u16>::unbuffer_from(&mut buf).unwrap(), Le(0x1234));
+//! assert_eq!(u16::unbuffer_from(&mut buf).unwrap(), 0x1234);
+//! ```
+
 use super::{
+    buffer::check_buffer_remaining,
     size::ConstantBufferSize,
     unbuffer::{check_unbuffer_remaining, UnbufferFrom},
     BufferResult, BufferTo, UnbufferResult,
@@ -15,6 +36,7 @@ macro_rules! buffer_primitive {
 
         impl BufferTo for $t {
             fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+                check_buffer_remaining(buf, Self::constant_buffer_size())?;
                 buf.$put(*self);
                 Ok(())
             }
@@ -29,15 +51,63 @@ macro_rules! buffer_primitive {
     };
 }
 
+/// A primitive stored in little-endian byte order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Hash)]
+pub struct Le<T>(pub T);
+
+/// A primitive stored in big-endian (network) byte order, like the bare type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Hash)]
+pub struct Be<T>(pub T);
+
+macro_rules! buffer_endian_wrapped {
+    ($wrapper:ident, $t:ty, $put:ident, $get:ident) => {
+        impl ConstantBufferSize for $wrapper<$t> {
+            fn constant_buffer_size() -> usize {
+                <$t>::constant_buffer_size()
+            }
+        }
+
+        impl BufferTo for $wrapper<$t> {
+            fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+                check_buffer_remaining(buf, Self::constant_buffer_size())?;
+                buf.$put(self.0);
+                Ok(())
+            }
+        }
+
+        impl UnbufferFrom for $wrapper<$t> {
+            fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+                check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+                Ok($wrapper(buf.$get()))
+            }
+        }
+
+        impl From<$t> for $wrapper<$t> {
+            fn from(v: $t) -> Self {
+                $wrapper(v)
+            }
+        }
+    };
+}
+
+macro_rules! buffer_primitive_and_wrappers {
+    ($t:ty, $put:ident, $get:ident, $put_le:ident, $get_le:ident) => {
+        buffer_primitive!($t, $put, $get);
+        buffer_endian_wrapped!(Be, $t, $put, $get);
+        buffer_endian_wrapped!(Le, $t, $put_le, $get_le);
+    };
+}
+
 buffer_primitive!(i8, put_i8, get_i8);
-buffer_primitive!(i16, put_i16, get_i16);
-buffer_primitive!(u16, put_u16, get_u16);
-buffer_primitive!(i32, put_i32, get_i32);
-buffer_primitive!(u32, put_u32, get_u32);
-buffer_primitive!(i64, put_i64, get_i64);
-buffer_primitive!(u64, put_u64, get_u64);
-buffer_primitive!(f32, put_f32, get_f32);
-buffer_primitive!(f64, put_f64, get_f64);
+buffer_primitive!(u8, put_u8, get_u8);
+buffer_primitive_and_wrappers!(i16, put_i16, get_i16, put_i16_le, get_i16_le);
+buffer_primitive_and_wrappers!(u16, put_u16, get_u16, put_u16_le, get_u16_le);
+buffer_primitive_and_wrappers!(i32, put_i32, get_i32, put_i32_le, get_i32_le);
+buffer_primitive_and_wrappers!(u32, put_u32, get_u32, put_u32_le, get_u32_le);
+buffer_primitive_and_wrappers!(i64, put_i64, get_i64, put_i64_le, get_i64_le);
+buffer_primitive_and_wrappers!(u64, put_u64, get_u64, put_u64_le, get_u64_le);
+buffer_primitive_and_wrappers!(f32, put_f32, get_f32, put_f32_le, get_f32_le);
+buffer_primitive_and_wrappers!(f64, put_f64, get_f64, put_f64_le, get_f64_le);
 
 impl ConstantBufferSize for () {
     fn constant_buffer_size() -> usize {
@@ -56,3 +126,61 @@ impl UnbufferFrom for () {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_unbuffer::BufferUnbufferError;
+    use bytes::{Bytes, BytesMut};
+
+    fn roundtrip<T>(val: T, expected: &[u8])
+    where
+        T: BufferTo + UnbufferFrom + ConstantBufferSize + PartialEq + std::fmt::Debug,
+    {
+        assert_eq!(T::constant_buffer_size(), expected.len());
+        let mut buf = BytesMut::new();
+        val.buffer_to(&mut buf).unwrap();
+        assert_eq!(&buf[..], expected);
+        assert_eq!(T::unbuffer_from(&mut buf.freeze()).unwrap(), val);
+
+        // One byte short, either way.
+        let mut short = vec![0u8; expected.len() - 1];
+        assert!(matches!(
+            val.buffer_to(&mut &mut short[..]),
+            Err(BufferUnbufferError::OutOfBuffer)
+        ));
+        let mut short = Bytes::copy_from_slice(&expected[1..]);
+        assert!(T::unbuffer_from(&mut short).is_err());
+    }
+
+    #[test]
+    fn network_byte_order() {
+        roundtrip(-2i8, &hex!("fe"));
+        roundtrip(0xfeu8, &hex!("fe"));
+        roundtrip(-2i16, &hex!("ff fe"));
+        roundtrip(0x1234u16, &hex!("12 34"));
+        roundtrip(-2i32, &hex!("ff ff ff fe"));
+        roundtrip(0x1234_5678u32, &hex!("12 34 56 78"));
+        roundtrip(-2i64, &hex!("ff ff ff ff ff ff ff fe"));
+        roundtrip(0x0102_0304_0506_0708u64, &hex!("01 02 03 04 05 06 07 08"));
+        roundtrip(1.5f32, &hex!("3f c0 00 00"));
+        roundtrip(1.5f64, &hex!("3f f8 00 00 00 00 00 00"));
+    }
+
+    #[test]
+    fn explicit_byte_order() {
+        roundtrip(Le(-2i16), &hex!("fe ff"));
+        roundtrip(Le(0x1234u16), &hex!("34 12"));
+        roundtrip(Le(0x1234_5678u32), &hex!("78 56 34 12"));
+        roundtrip(
+            Le(0x0102_0304_0506_0708u64),
+            &hex!("08 07 06 05 04 03 02 01"),
+        );
+        roundtrip(Le(1.5f64), &hex!("00 00 00 00 00 00 f8 3f"));
+        roundtrip(Be(0x1234_5678u32), &hex!("12 34 56 78"));
+        roundtrip(Be(1.5f32), &hex!("3f c0 00 00"));
+
+        // Mixed within one body.
+        roundtrip([Le(0x0102u16), Le(0x0304u16)], &hex!("02 01 04 03"));
+    }
+}
@@ -4,7 +4,42 @@
 
 //! Traits describing the size of things we can read from or write to a buffer.
 
-use super::{BufferTo, UnbufferFrom};
+use super::{constants::ALIGN, BufferTo, UnbufferFrom};
+
+/// Number of padding bytes needed after `len` bytes to reach a multiple of `ALIGN`.
+///
+/// Message bodies on the wire are padded this way,
+/// so variable-size bodies may need it to compute their `buffer_size()`
+/// if they contain nested aligned data.
+///
+/// ```
+/// use vrpn::buffer_unbuffer::{compute_padding, ALIGN};
+/// assert_eq!(ALIGN, 8);
+/// assert_eq!(compute_padding(0), 0);
+/// assert_eq!(compute_padding(13), 3);
+/// assert_eq!(compute_padding(16), 0);
+/// ```
+#[inline]
+pub const fn compute_padding(len: usize) -> usize {
+    let remainder = len % ALIGN;
+    if remainder != 0 {
+        ALIGN - remainder
+    } else {
+        0
+    }
+}
+
+/// Round `len` up to the next multiple of `ALIGN`.
+///
+/// ```
+/// use vrpn::buffer_unbuffer::padded;
+/// assert_eq!(padded(13), 16);
+/// assert_eq!(padded(24), 24);
+/// ```
+#[inline]
+pub const fn padded(len: usize) -> usize {
+    len + compute_padding(len)
+}
 
 /// Optional trait for things that always take the same amount of space in a buffer.
 ///
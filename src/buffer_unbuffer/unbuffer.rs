@@ -38,7 +38,7 @@ impl<T: WrappedConstantSize> UnbufferFrom for T {
 }
 
 /// Check whether a buffer has enough bytes remaining to unbuffer a given length
-pub fn check_unbuffer_remaining<T: Buf>(
+pub fn check_unbuffer_remaining<T: Buf + ?Sized>(
     buf: &T,
     required_len: usize,
 ) -> std::result::Result<(), BufferUnbufferError> {
@@ -60,8 +60,8 @@ pub fn check_unbuffer_remaining<T: Buf>(
 /// assert!(consume_expected(&mut buf, &b"hello"[..]).is_ok());
 /// assert_eq!(buf.remaining(), 6);
 /// ```
-pub fn consume_expected<T: Buf>(
-    buf: &mut T,
+pub fn consume_expected(
+    buf: &mut dyn Buf,
     expected: &'static [u8],
 ) -> std::result::Result<(), BufferUnbufferError> {
     let expected_len = expected.len();
@@ -127,7 +127,7 @@ fn from_dec(input: Bytes) -> std::result::Result<u8, ParseIntError> {
 }
 
 #[inline]
-pub fn unbuffer_decimal_digits<T: Buf>(buf: &mut T, n: usize) -> UnbufferResult<u8> {
+pub fn unbuffer_decimal_digits(buf: &mut dyn Buf, n: usize) -> UnbufferResult<u8> {
     let val = from_dec(buf.copy_to_bytes(n))?;
 
     Ok(val)
@@ -0,0 +1,229 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Types related to the `vrpn_Button` device class.
+
+use crate::{
+    buffer_unbuffer::{
+        check_buffer_remaining, check_unbuffer_remaining, BufferResult, BufferSize, BufferTo,
+        BufferUnbufferError, ConstantBufferSize, UnbufferFrom, UnbufferResult,
+    },
+    data_types::{
+        id_types::{LocalId, SenderId},
+        MessageTypeIdentifier, SenderName, StaticMessageTypeName, TypedMessage, TypedMessageBody,
+    },
+    device::DeviceClass,
+    handler::{CallbackHandler, HandlerHandle},
+    subscription::Subscription,
+    Connection, Result, VrpnError,
+};
+use bytes::{Buf, BufMut};
+use std::{
+    convert::TryFrom,
+    sync::{Arc, Weak},
+};
+
+pub(crate) const BUTTON_CHANGE: StaticMessageTypeName =
+    StaticMessageTypeName(b"vrpn_Button Change");
+pub(crate) const BUTTON_STATES: StaticMessageTypeName =
+    StaticMessageTypeName(b"vrpn_Button States");
+
+/// Maximum number of buttons in a button device, matching `vrpn_BUTTON_MAX_BUTTONS`.
+pub const MAX_BUTTONS: usize = 256;
+
+fn state_to_i32(pressed: bool) -> i32 {
+    if pressed {
+        1
+    } else {
+        0
+    }
+}
+
+/// A single button was pressed or released: `vrpn_Button Change`.
+///
+/// # Wire format
+///
+/// Button index, then state (1 for pressed, 0 for released), as `i32`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ButtonChange {
+    pub button: i32,
+    pub pressed: bool,
+}
+
+impl TypedMessageBody for ButtonChange {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(BUTTON_CHANGE);
+}
+
+impl ConstantBufferSize for ButtonChange {
+    fn constant_buffer_size() -> usize {
+        i32::constant_buffer_size() * 2
+    }
+}
+
+impl BufferTo for ButtonChange {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        check_buffer_remaining(buf, Self::constant_buffer_size())?;
+        self.button.buffer_to(buf)?;
+        state_to_i32(self.pressed).buffer_to(buf)
+    }
+}
+
+impl UnbufferFrom for ButtonChange {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        let button = i32::unbuffer_from(buf)?;
+        let pressed = i32::unbuffer_from(buf)? != 0;
+        Ok(ButtonChange { button, pressed })
+    }
+}
+
+/// Current state of all buttons of a device: `vrpn_Button States`.
+///
+/// # Wire format
+///
+/// Number of buttons, then the state of each, as `i32`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ButtonStates {
+    pub pressed: Vec<bool>,
+}
+
+impl TypedMessageBody for ButtonStates {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(BUTTON_STATES);
+}
+
+impl BufferSize for ButtonStates {
+    fn buffer_size(&self) -> usize {
+        i32::constant_buffer_size() * (1 + self.pressed.len())
+    }
+}
+
+impl BufferTo for ButtonStates {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        check_buffer_remaining(buf, self.buffer_size())?;
+        let count =
+            i32::try_from(self.pressed.len()).map_err(|_| BufferUnbufferError::OutOfBuffer)?;
+        count.buffer_to(buf)?;
+        for pressed in &self.pressed {
+            state_to_i32(*pressed).buffer_to(buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for ButtonStates {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        let count = i32::unbuffer_from(buf)?;
+        let count = usize::try_from(count)
+            .ok()
+            .filter(|&c| c <= MAX_BUTTONS)
+            .ok_or_else(|| BufferUnbufferError::ParseError {
+                parsing_kind: "button count".to_string(),
+                s: count.to_string(),
+            })?;
+        check_unbuffer_remaining(buf, count * i32::constant_buffer_size())?;
+        let pressed = (0..count)
+            .map(|_| i32::unbuffer_from(buf).map(|state| state != 0))
+            .collect::<UnbufferResult<Vec<bool>>>()?;
+        Ok(ButtonStates { pressed })
+    }
+}
+
+/// The `vrpn_Button` device class, for `DeviceExt::device`.
+#[derive(Debug, Clone, Copy)]
+pub struct Button;
+
+impl<C: Connection + 'static> DeviceClass<C> for Button {
+    type Proxy = ButtonProxy<C>;
+    fn proxy(
+        connection: &Arc<C>,
+        name: SenderName,
+        sender: LocalId<SenderId>,
+    ) -> Result<ButtonProxy<C>> {
+        Ok(ButtonProxy {
+            connection: Arc::downgrade(connection),
+            name,
+            sender,
+        })
+    }
+}
+
+/// A button device on a connection: see `DeviceExt::device`.
+#[derive(Debug)]
+pub struct ButtonProxy<C: Connection + 'static> {
+    connection: Weak<C>,
+    name: SenderName,
+    sender: LocalId<SenderId>,
+}
+
+impl<C: Connection + 'static> ButtonProxy<C> {
+    /// The local ID of the device's sender.
+    pub fn sender(&self) -> LocalId<SenderId> {
+        self.sender
+    }
+
+    /// Call `callback` with each button press or release, until removed with the returned handle.
+    pub fn on_change<F>(&self, callback: F) -> Result<HandlerHandle>
+    where
+        F: FnMut(&TypedMessage<ButtonChange>) + Send + Sync + 'static,
+    {
+        self.connection()?
+            .add_typed_handler(Box::new(CallbackHandler::new(callback)), Some(self.sender))
+    }
+
+    /// Call `callback` with each report of the state of all buttons,
+    /// until removed with the returned handle.
+    pub fn on_states<F>(&self, callback: F) -> Result<HandlerHandle>
+    where
+        F: FnMut(&TypedMessage<ButtonStates>) + Send + Sync + 'static,
+    {
+        self.connection()?
+            .add_typed_handler(Box::new(CallbackHandler::new(callback)), Some(self.sender))
+    }
+
+    /// A stream of the button presses and releases, with lifecycle events.
+    pub fn changes(&self) -> Result<Subscription<ButtonChange>> {
+        Subscription::new(&self.connection()?, self.name.clone())
+    }
+
+    fn connection(&self) -> Result<Arc<C>> {
+        self.connection.upgrade().ok_or(VrpnError::EndpointClosed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{Bytes, BytesMut};
+
+    #[test]
+    fn wire_format() {
+        let change = ButtonChange {
+            button: 3,
+            pressed: true,
+        };
+        let mut buf = BytesMut::new();
+        change.buffer_to(&mut buf).unwrap();
+        assert_eq!(&buf[..], &hex!("00 00 00 03 00 00 00 01")[..]);
+        assert_eq!(
+            ButtonChange::unbuffer_from(&mut buf.freeze()).unwrap(),
+            change
+        );
+
+        let states = ButtonStates {
+            pressed: vec![false, true],
+        };
+        let mut buf = BytesMut::new();
+        states.buffer_to(&mut buf).unwrap();
+        assert_eq!(&buf[..], &hex!("00 00 00 02 00 00 00 00 00 00 00 01")[..]);
+        assert_eq!(
+            ButtonStates::unbuffer_from(&mut buf.freeze()).unwrap(),
+            states
+        );
+
+        let mut buf = Bytes::from_static(&hex!("00 00 01 01"));
+        assert!(ButtonStates::unbuffer_from(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,441 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! A C API for clients, modeled on the callback style of the C++ VRPN remotes.
+//!
+//! A connection is an opaque handle: create it with `vrpn_rs_connection_new`,
+//! register tracker, button, and analog callbacks, then call `vrpn_rs_connection_poll`
+//! regularly, like `mainloop()` in VRPN. The connection runs on a background thread,
+//! but callbacks only ever run inside `vrpn_rs_connection_poll`, on the calling thread.
+//!
+//! The declarations are in `include/vrpn_rs.h`. To build the shared library:
+//!
+//! ```sh
+//! cargo rustc --lib --release --features capi --crate-type cdylib
+//! ```
+//!
+//! Functions returning `int` return 0 on success and -1 on error.
+//! Nothing is printed: errors are only reported through return values.
+
+use crate::{
+    analog::AnalogReport,
+    button::ButtonChange,
+    connection::Connection,
+    data_types::{SenderName, TimeVal, TypedMessage, TypedMessageBody},
+    tracker::PoseReport,
+    vrpn_async_std::{connection_ip::ConnectionIp, threaded::ThreadedConnection},
+    Result, ServerInfo, VrpnError,
+};
+use std::{
+    ffi::{c_void, CStr},
+    os::raw::{c_char, c_int},
+    ptr,
+    sync::mpsc::Receiver,
+};
+
+/// Time of a report, as in `struct timeval`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VrpnRsTime {
+    pub sec: i64,
+    pub usec: i64,
+}
+
+impl From<TimeVal> for VrpnRsTime {
+    fn from(time: TimeVal) -> VrpnRsTime {
+        VrpnRsTime {
+            sec: time.seconds().0.into(),
+            usec: time.microseconds().0.into(),
+        }
+    }
+}
+
+/// A tracker pose: position, and orientation as a quaternion in `x, y, z, w` order.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VrpnRsTrackerReport {
+    pub time: VrpnRsTime,
+    pub sensor: i32,
+    pub pos: [f64; 3],
+    pub quat: [f64; 4],
+}
+
+/// A button pressed (state 1) or released (state 0).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VrpnRsButtonReport {
+    pub time: VrpnRsTime,
+    pub button: i32,
+    pub state: i32,
+}
+
+/// Analog channel values, valid only during the callback.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VrpnRsAnalogReport {
+    pub time: VrpnRsTime,
+    pub num_channels: i32,
+    pub channels: *const f64,
+}
+
+pub type VrpnRsTrackerCallback =
+    extern "C" fn(userdata: *mut c_void, report: *const VrpnRsTrackerReport);
+pub type VrpnRsButtonCallback =
+    extern "C" fn(userdata: *mut c_void, report: *const VrpnRsButtonReport);
+pub type VrpnRsAnalogCallback =
+    extern "C" fn(userdata: *mut c_void, report: *const VrpnRsAnalogReport);
+
+/// Messages of one type from one device, and the callback to call with each.
+struct Callback<T: TypedMessageBody, F> {
+    rx: Receiver<TypedMessage<T>>,
+    callback: F,
+    userdata: *mut c_void,
+}
+
+/// The handle passed to C: a connection and its callbacks.
+pub struct VrpnRsConnection {
+    threaded: ThreadedConnection,
+    trackers: Vec<Callback<PoseReport, VrpnRsTrackerCallback>>,
+    buttons: Vec<Callback<ButtonChange, VrpnRsButtonCallback>>,
+    analogs: Vec<Callback<AnalogReport, VrpnRsAnalogCallback>>,
+}
+
+impl VrpnRsConnection {
+    fn new(server: &str) -> Result<VrpnRsConnection> {
+        let connection = ConnectionIp::client_builder(server.parse::<ServerInfo>()?)
+            .reconnect(true)
+            .build()?;
+        Ok(VrpnRsConnection {
+            threaded: ThreadedConnection::spawn(connection)?,
+            trackers: Vec::new(),
+            buttons: Vec::new(),
+            analogs: Vec::new(),
+        })
+    }
+
+    fn subscribe<T, F>(
+        &self,
+        device: &str,
+        callback: F,
+        userdata: *mut c_void,
+    ) -> Result<Callback<T, F>>
+    where
+        T: TypedMessageBody
+            + crate::buffer_unbuffer::UnbufferFrom
+            + std::fmt::Debug
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+    {
+        let sender = self
+            .threaded
+            .connection()
+            .register_sender(SenderName::from(device))?;
+        let (rx, _) = self.threaded.typed_channel(Some(sender))?;
+        Ok(Callback {
+            rx,
+            callback,
+            userdata,
+        })
+    }
+
+    fn poll(&self) -> Result<()> {
+        for tracker in &self.trackers {
+            for msg in tracker.rx.try_iter() {
+                let PoseReport { sensor, pos, quat } = msg.body;
+                let report = VrpnRsTrackerReport {
+                    time: msg.header.time.into(),
+                    sensor: sensor.0,
+                    pos: [pos.x, pos.y, pos.z],
+                    quat: [quat.v.x, quat.v.y, quat.v.z, quat.s],
+                };
+                (tracker.callback)(tracker.userdata, &report);
+            }
+        }
+        for button in &self.buttons {
+            for msg in button.rx.try_iter() {
+                let report = VrpnRsButtonReport {
+                    time: msg.header.time.into(),
+                    button: msg.body.button,
+                    state: msg.body.pressed.into(),
+                };
+                (button.callback)(button.userdata, &report);
+            }
+        }
+        for analog in &self.analogs {
+            for msg in analog.rx.try_iter() {
+                let report = VrpnRsAnalogReport {
+                    time: msg.header.time.into(),
+                    num_channels: msg.body.values.len() as i32,
+                    channels: msg.body.values.as_ptr(),
+                };
+                (analog.callback)(analog.userdata, &report);
+            }
+        }
+        // Events aren't exposed, but an error stopping the connection is.
+        self.threaded.poll_events().map(|_| ())
+    }
+}
+
+/// Turn a result into a return code.
+fn status(result: Result<()>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Borrow a C string as UTF-8.
+///
+/// # Safety
+///
+/// `s` must be null or point to a nul-terminated string that outlives the result.
+unsafe fn borrow_str<'a>(s: *const c_char) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(VrpnError::OtherMessage("null string".to_string()));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|e| VrpnError::OtherMessage(e.to_string()))
+}
+
+/// Connect to a server, named as in VRPN, e.g. `localhost` or `tcp://host:3883`.
+///
+/// Reconnects whenever the connection is lost. Returns null on error.
+///
+/// # Safety
+///
+/// `server` must be null or a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn vrpn_rs_connection_new(server: *const c_char) -> *mut VrpnRsConnection {
+    match borrow_str(server).and_then(VrpnRsConnection::new) {
+        Ok(connection) => Box::into_raw(Box::new(connection)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Disconnect, and free the connection.
+///
+/// # Safety
+///
+/// `connection` must be null or returned by `vrpn_rs_connection_new`, and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn vrpn_rs_connection_free(connection: *mut VrpnRsConnection) {
+    if !connection.is_null() {
+        drop(Box::from_raw(connection));
+    }
+}
+
+/// Call the registered callbacks for everything received since the last call.
+///
+/// Returns -1 once the connection has stopped because of an error.
+///
+/// # Safety
+///
+/// `connection` must be null or a live connection from `vrpn_rs_connection_new`.
+#[no_mangle]
+pub unsafe extern "C" fn vrpn_rs_connection_poll(connection: *mut VrpnRsConnection) -> c_int {
+    match connection.as_ref() {
+        Some(connection) => status(connection.poll()),
+        None => -1,
+    }
+}
+
+/// Call `callback` with each pose reported by the named tracker.
+///
+/// Returns -1 if `callback` is null.
+///
+/// # Safety
+///
+/// `connection` must be null or a live connection, and `device` a nul-terminated string.
+/// `userdata` is passed to the callback as-is.
+#[no_mangle]
+pub unsafe extern "C" fn vrpn_rs_register_tracker_callback(
+    connection: *mut VrpnRsConnection,
+    device: *const c_char,
+    callback: Option<VrpnRsTrackerCallback>,
+    userdata: *mut c_void,
+) -> c_int {
+    let (connection, callback) = match (connection.as_mut(), callback) {
+        (Some(connection), Some(callback)) => (connection, callback),
+        _ => return -1,
+    };
+    status(borrow_str(device).and_then(|device| {
+        let tracker = connection.subscribe(device, callback, userdata)?;
+        connection.trackers.push(tracker);
+        Ok(())
+    }))
+}
+
+/// Call `callback` with each change reported by the named button device.
+///
+/// # Safety
+///
+/// As for `vrpn_rs_register_tracker_callback`.
+#[no_mangle]
+pub unsafe extern "C" fn vrpn_rs_register_button_callback(
+    connection: *mut VrpnRsConnection,
+    device: *const c_char,
+    callback: Option<VrpnRsButtonCallback>,
+    userdata: *mut c_void,
+) -> c_int {
+    let (connection, callback) = match (connection.as_mut(), callback) {
+        (Some(connection), Some(callback)) => (connection, callback),
+        _ => return -1,
+    };
+    status(borrow_str(device).and_then(|device| {
+        let button = connection.subscribe(device, callback, userdata)?;
+        connection.buttons.push(button);
+        Ok(())
+    }))
+}
+
+/// Call `callback` with each report from the named analog device.
+///
+/// # Safety
+///
+/// As for `vrpn_rs_register_tracker_callback`.
+#[no_mangle]
+pub unsafe extern "C" fn vrpn_rs_register_analog_callback(
+    connection: *mut VrpnRsConnection,
+    device: *const c_char,
+    callback: Option<VrpnRsAnalogCallback>,
+    userdata: *mut c_void,
+) -> c_int {
+    let (connection, callback) = match (connection.as_mut(), callback) {
+        (Some(connection), Some(callback)) => (connection, callback),
+        _ => return -1,
+    };
+    status(borrow_str(device).and_then(|device| {
+        let analog = connection.subscribe(device, callback, userdata)?;
+        connection.analogs.push(analog);
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{id_types::Sensor, ClassOfService, Microseconds, Quat, Seconds, Vec3},
+        testing::LoopbackServer,
+    };
+    use std::{
+        ffi::CString,
+        time::{Duration, Instant},
+    };
+
+    extern "C" fn count_trackers(userdata: *mut c_void, _report: *const VrpnRsTrackerReport) {
+        unsafe { *(userdata as *mut u32) += 1 };
+    }
+
+    extern "C" fn collect_trackers(userdata: *mut c_void, report: *const VrpnRsTrackerReport) {
+        unsafe { (*(userdata as *mut Vec<VrpnRsTrackerReport>)).push(*report) };
+    }
+
+    #[test]
+    fn lifecycle() {
+        let server = LoopbackServer::start().unwrap();
+        let url = CString::new(server.url()).unwrap();
+        let device = CString::new("Tracker0").unwrap();
+        let mut count = 0u32;
+        unsafe {
+            assert!(vrpn_rs_connection_new(ptr::null()).is_null());
+            let bad = CString::new("not a server name!").unwrap();
+            assert!(vrpn_rs_connection_new(bad.as_ptr()).is_null());
+
+            let connection = vrpn_rs_connection_new(url.as_ptr());
+            assert!(!connection.is_null());
+            assert_eq!(
+                vrpn_rs_register_tracker_callback(
+                    connection,
+                    device.as_ptr(),
+                    Some(count_trackers),
+                    &mut count as *mut u32 as *mut c_void,
+                ),
+                0
+            );
+            assert_eq!(
+                vrpn_rs_register_tracker_callback(
+                    connection,
+                    ptr::null(),
+                    Some(count_trackers),
+                    ptr::null_mut(),
+                ),
+                -1
+            );
+            assert_eq!(
+                vrpn_rs_register_tracker_callback(
+                    connection,
+                    device.as_ptr(),
+                    None,
+                    ptr::null_mut(),
+                ),
+                -1
+            );
+            assert_eq!(vrpn_rs_connection_poll(connection), 0);
+            assert_eq!(vrpn_rs_connection_poll(ptr::null_mut()), -1);
+            vrpn_rs_connection_free(connection);
+            vrpn_rs_connection_free(ptr::null_mut());
+        }
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn tracker_report() {
+        let server = LoopbackServer::start().unwrap();
+        let sender = server.register::<PoseReport>("Tracker0").unwrap();
+        let url = CString::new(server.url()).unwrap();
+        let device = CString::new("Tracker0").unwrap();
+        let mut reports = Vec::<VrpnRsTrackerReport>::new();
+        unsafe {
+            let connection = vrpn_rs_connection_new(url.as_ptr());
+            assert_eq!(
+                vrpn_rs_register_tracker_callback(
+                    connection,
+                    device.as_ptr(),
+                    Some(collect_trackers),
+                    &mut reports as *mut Vec<VrpnRsTrackerReport> as *mut c_void,
+                ),
+                0
+            );
+            server.wait_for_client(Duration::from_secs(5)).unwrap();
+            server
+                .connection()
+                .pack_message_body(
+                    Some(TimeVal::new(Seconds(5), Microseconds(6))),
+                    sender,
+                    PoseReport {
+                        sensor: Sensor(1),
+                        pos: Vec3::new(1.0, 2.0, 3.0),
+                        quat: Quat::new(0.5, 0.1, 0.2, 0.3),
+                    },
+                    ClassOfService::RELIABLE,
+                )
+                .unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while reports.is_empty() && Instant::now() < deadline {
+                assert_eq!(vrpn_rs_connection_poll(connection), 0);
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            vrpn_rs_connection_free(connection);
+        }
+        assert_eq!(
+            reports,
+            vec![VrpnRsTrackerReport {
+                time: VrpnRsTime { sec: 5, usec: 6 },
+                sensor: 1,
+                pos: [1.0, 2.0, 3.0],
+                quat: [0.1, 0.2, 0.3, 0.5],
+            }]
+        );
+    }
+
+    #[test]
+    fn time() {
+        let time = VrpnRsTime::from(TimeVal::new(Seconds(5), Microseconds(6)));
+        assert_eq!(time, VrpnRsTime { sec: 5, usec: 6 });
+    }
+}
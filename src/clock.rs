@@ -0,0 +1,297 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Time sources, so that timestamps and timeouts can be controlled in tests.
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::data_types::TimeVal;
+
+// The std types panic on wasm32-unknown-unknown, which has no clock of its own:
+// there, the same API reads the browser's clock instead.
+#[cfg(not(target_arch = "wasm32"))]
+pub use std::time::{Instant, SystemTime};
+#[cfg(target_arch = "wasm32")]
+pub use web_time::{Instant, SystemTime};
+
+/// A source of both monotonic time (for intervals and timeouts)
+/// and wall-clock time (for message timestamps).
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Get the current monotonic time.
+    fn now(&self) -> Instant;
+
+    /// Get the current wall-clock time, as used in message headers.
+    fn time_of_day(&self) -> TimeVal;
+}
+
+/// A reference-counted, type-erased clock, as stored by connections.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The real system clock: the default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn time_of_day(&self) -> TimeVal {
+        TimeVal::get_time_of_day()
+    }
+}
+
+impl SystemClock {
+    /// Get a shared handle to the system clock.
+    pub fn shared() -> SharedClock {
+        Arc::new(SystemClock)
+    }
+}
+
+/// Wall-clock time that advances with the monotonic clock from a chosen start,
+/// so timestamps are unaffected by later adjustments of the system clock.
+///
+/// Start it ahead of or behind the current time to shift timestamps by a fixed offset,
+/// e.g. to match another machine. Intervals and timeouts use the real monotonic clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MonotonicClock {
+    base_instant: Instant,
+    base_time_of_day: SystemTime,
+}
+
+impl MonotonicClock {
+    /// Start at the current system time.
+    pub fn new() -> MonotonicClock {
+        MonotonicClock::starting_at(SystemTime::now())
+    }
+
+    /// Start at the given wall-clock time, which need not be the current time.
+    pub fn starting_at(time_of_day: SystemTime) -> MonotonicClock {
+        MonotonicClock {
+            base_instant: Instant::now(),
+            base_time_of_day: time_of_day,
+        }
+    }
+
+    /// Get a shared handle to this clock, suitable for passing to a connection.
+    pub fn shared(&self) -> SharedClock {
+        Arc::new(*self)
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> MonotonicClock {
+        MonotonicClock::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn time_of_day(&self) -> TimeVal {
+        TimeVal::from(self.base_time_of_day + self.base_instant.elapsed())
+    }
+}
+
+/// Wall-clock time set by the application, e.g. the time within a simulation or a recording
+/// being played back, while intervals and timeouts use the real monotonic clock.
+///
+/// Clones share the same underlying time.
+///
+/// ```
+/// use std::time::{Duration, SystemTime};
+/// use vrpn::{
+///     clock::{Clock, SimulationClock},
+///     data_types::Seconds,
+/// };
+///
+/// let clock = SimulationClock::starting_at(SystemTime::UNIX_EPOCH);
+/// clock.advance(Duration::from_secs(5));
+/// assert_eq!(clock.time_of_day().seconds(), Seconds(5));
+/// ```
+#[derive(Debug, Clone)]
+pub struct SimulationClock {
+    time_of_day: Arc<Mutex<SystemTime>>,
+}
+
+impl SimulationClock {
+    /// Create a clock whose wall-clock time starts at the given time, and stays there until changed.
+    pub fn starting_at(time_of_day: SystemTime) -> SimulationClock {
+        SimulationClock {
+            time_of_day: Arc::new(Mutex::new(time_of_day)),
+        }
+    }
+
+    /// Jump to a time, forward or back.
+    pub fn set_time(&self, time_of_day: SystemTime) {
+        *self
+            .time_of_day
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = time_of_day;
+    }
+
+    /// Move time forward.
+    pub fn advance(&self, duration: Duration) {
+        *self
+            .time_of_day
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) += duration;
+    }
+
+    /// Get a shared handle to this clock, suitable for passing to a connection.
+    pub fn shared(&self) -> SharedClock {
+        Arc::new(self.clone())
+    }
+}
+
+impl Clock for SimulationClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn time_of_day(&self) -> TimeVal {
+        TimeVal::from(
+            *self
+                .time_of_day
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner),
+        )
+    }
+}
+
+#[derive(Debug)]
+struct MockClockInner {
+    base_instant: Instant,
+    base_time_of_day: SystemTime,
+    elapsed: Duration,
+}
+
+/// A clock that only moves when told to, for deterministic tests.
+///
+/// Clones share the same underlying time.
+///
+/// ```
+/// use std::time::Duration;
+/// use vrpn::clock::{Clock, MockClock};
+///
+/// let clock = MockClock::new();
+/// let start = clock.now();
+/// clock.advance(Duration::from_secs(5));
+/// assert_eq!(clock.now() - start, Duration::from_secs(5));
+/// ```
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    inner: Arc<Mutex<MockClockInner>>,
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl MockClock {
+    /// Create a mock clock, starting at the current time.
+    pub fn new() -> MockClock {
+        MockClock::starting_at(SystemTime::now())
+    }
+
+    /// Create a mock clock whose wall-clock time starts at the given time.
+    pub fn starting_at(time_of_day: SystemTime) -> MockClock {
+        MockClock {
+            inner: Arc::new(Mutex::new(MockClockInner {
+                base_instant: Instant::now(),
+                base_time_of_day: time_of_day,
+                elapsed: Duration::default(),
+            })),
+        }
+    }
+
+    /// Move time forward.
+    pub fn advance(&self, duration: Duration) {
+        let mut inner = self
+            .inner
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        inner.elapsed += duration;
+    }
+
+    /// Get a shared handle to this clock, suitable for passing to a connection.
+    pub fn shared(&self) -> SharedClock {
+        Arc::new(self.clone())
+    }
+
+    fn elapsed_and_bases(&self) -> (Duration, Instant, SystemTime) {
+        let inner = self
+            .inner
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        (inner.elapsed, inner.base_instant, inner.base_time_of_day)
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        let (elapsed, base, _) = self.elapsed_and_bases();
+        base + elapsed
+    }
+
+    fn time_of_day(&self) -> TimeVal {
+        let (elapsed, _, base) = self.elapsed_and_bases();
+        TimeVal::from(base + elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{Microseconds, Seconds};
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::starting_at(SystemTime::UNIX_EPOCH + Duration::from_secs(100));
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        assert_eq!(
+            clock.time_of_day(),
+            TimeVal::new(Seconds(100), Microseconds(0))
+        );
+
+        let shared = clock.shared();
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(shared.now() - start, Duration::from_millis(1500));
+        assert_eq!(
+            shared.time_of_day(),
+            TimeVal::new(Seconds(101), Microseconds(500_000))
+        );
+    }
+
+    #[test]
+    fn monotonic_and_simulation_clocks() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let monotonic = MonotonicClock::starting_at(start);
+        let time = monotonic.time_of_day();
+        assert!(time >= TimeVal::from(start));
+        assert!(time < TimeVal::new(Seconds(101), Microseconds(0)));
+
+        let simulation = SimulationClock::starting_at(start);
+        let shared = simulation.shared();
+        let before = shared.now();
+        simulation.advance(Duration::from_millis(1500));
+        assert_eq!(
+            shared.time_of_day(),
+            TimeVal::new(Seconds(101), Microseconds(500_000))
+        );
+        simulation.set_time(SystemTime::UNIX_EPOCH);
+        assert_eq!(shared.time_of_day(), TimeVal::default());
+        // Monotonic time is real.
+        assert!(shared.now() >= before);
+    }
+}
@@ -2,18 +2,137 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-use bytes::Buf;
+//! IO-independent message framing (decoding) shared by all the runtime-specific glue.
+
+use bytes::{Buf, BytesMut};
 
 use crate::{
-    buffer_unbuffer::{BufferUnbufferError, UnbufferResult},
-    data_types::SequencedGenericMessage,
+    buffer_unbuffer::{peek_u32, BufferUnbufferError, UnbufferResult},
+    data_types::{message::LengthField, MessageView, ProtocolProfile, SequencedGenericMessage},
 };
 
-/// Decode at most 1 message. Returns Ok(None) if we don't have enough data.
-pub(crate) fn maybe_decode_one<T: Buf + Clone>(
+/// Default maximum size of a single incoming message, including header and padding.
+///
+/// Far larger than anything a VRPN device normally sends.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// What to do when an incoming message claims to be larger than the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// Report an error and decode nothing further: the connection is unusable.
+    Reject,
+    /// Report an error, then discard the claimed number of bytes as they arrive,
+    /// and resume decoding after them.
+    Skip,
+}
+
+/// Limit on the size of incoming messages, to guard against a peer claiming a huge size.
+///
+/// Only limits what this side accepts: it isn't negotiated, as VRPN has no message
+/// to tell the other side about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageSizeLimit {
+    /// Maximum size of a message, including header and padding.
+    pub max_message_size: usize,
+    /// What to do when a message exceeds the maximum.
+    pub oversize: OversizePolicy,
+}
+
+impl Default for MessageSizeLimit {
+    fn default() -> Self {
+        MessageSizeLimit {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            oversize: OversizePolicy::Reject,
+        }
+    }
+}
+
+impl MessageSizeLimit {
+    /// A limit of the given size, rejecting oversized messages.
+    pub fn new(max_message_size: usize) -> MessageSizeLimit {
+        MessageSizeLimit {
+            max_message_size,
+            oversize: OversizePolicy::Reject,
+        }
+    }
+
+    /// Change the oversize policy.
+    pub fn with_policy(self, oversize: OversizePolicy) -> MessageSizeLimit {
+        MessageSizeLimit { oversize, ..self }
+    }
+}
+
+/// Enforces a `MessageSizeLimit` on a receive buffer, ahead of decoding from it.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SizeCheck {
+    limit: MessageSizeLimit,
+    /// Bytes of an oversized message still to be discarded.
+    skip_remaining: usize,
+}
+
+impl SizeCheck {
+    pub(crate) fn new(limit: MessageSizeLimit) -> SizeCheck {
+        SizeCheck {
+            limit,
+            skip_remaining: 0,
+        }
+    }
+
+    pub(crate) fn limit(&self) -> MessageSizeLimit {
+        self.limit
+    }
+
+    pub(crate) fn is_skipping(&self) -> bool {
+        self.skip_remaining > 0
+    }
+
+    /// Discard any remaining bytes of an oversized message, and check the size of the next.
+    ///
+    /// Returns Ok(false) if still discarding.
+    pub(crate) fn check(
+        &mut self,
+        buf: &mut BytesMut,
+        profile: &ProtocolProfile,
+    ) -> UnbufferResult<bool> {
+        if self.skip_remaining > 0 {
+            let n = self.skip_remaining.min(buf.len());
+            buf.advance(n);
+            self.skip_remaining -= n;
+            if self.skip_remaining > 0 {
+                return Ok(false);
+            }
+        }
+        // Check the length before peeking, since peeking logs if there isn't enough data.
+        let length_field = if buf.len() >= std::mem::size_of::<LengthField>() {
+            peek_u32(buf)
+        } else {
+            None
+        };
+        if let Some(length_field) = length_field {
+            let size = profile.try_size_from_length_field(length_field)?;
+            let padded_size = profile.padded_message_size(size);
+            let max = self.limit.max_message_size;
+            if padded_size > max {
+                if self.limit.oversize == OversizePolicy::Skip {
+                    self.skip_remaining = padded_size;
+                }
+                return Err(BufferUnbufferError::MessageTooLarge {
+                    claimed: padded_size,
+                    max,
+                });
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Decode at most 1 message framed according to `profile`.
+/// Returns Ok(None) if we don't have enough data.
+fn maybe_decode_one_with_profile<T: Buf + Clone>(
     buf: &mut T,
+    profile: &ProtocolProfile,
 ) -> UnbufferResult<Option<SequencedGenericMessage>> {
-    match SequencedGenericMessage::try_read_from_buf(buf) {
+    match SequencedGenericMessage::try_read_from_buf_with_profile(buf, profile) {
         Ok(v) => Ok(Some(v)),
         // Not enough data in the buffer - here, that's not an error.
         Err(BufferUnbufferError::NeedMoreData(_)) => Ok(None),
@@ -21,6 +140,198 @@ pub(crate) fn maybe_decode_one<T: Buf + Clone>(
     }
 }
 
+/// Decode at most 1 message from the front of a `BytesMut`, consuming its bytes only on success.
+///
+/// Returns Ok(None) if we don't have enough data, leaving the buffer untouched.
+#[cfg(feature = "async-tokio")]
+pub(crate) fn decode_one_from_bytes_mut(
+    buf: &mut BytesMut,
+) -> UnbufferResult<Option<SequencedGenericMessage>> {
+    decode_one_from_bytes_mut_with_profile(buf, &ProtocolProfile::VRPN)
+}
+
+/// Like `decode_one_from_bytes_mut`, for messages framed according to `profile`.
+pub(crate) fn decode_one_from_bytes_mut_with_profile(
+    buf: &mut BytesMut,
+    profile: &ProtocolProfile,
+) -> UnbufferResult<Option<SequencedGenericMessage>> {
+    if buf.is_empty() {
+        // short-circuit if we have run out of stuff.
+        return Ok(None);
+    }
+    let mut existing_bytes = std::io::Cursor::new(&buf[..]);
+    let result = maybe_decode_one_with_profile(&mut existing_bytes, profile)?;
+    if result.is_some() {
+        // consume the bytes from the original buffer.
+        let consumed = existing_bytes.position() as usize;
+        buf.advance(consumed);
+    }
+    Ok(result)
+}
+
+/// A decoder for framed messages that owns its receive buffer but performs no IO.
+///
+/// Feed it bytes as they arrive (in whatever sized pieces), then pull complete messages out.
+///
+/// ```
+/// use vrpn::codec::MessageDecoder;
+/// let mut decoder = MessageDecoder::new();
+/// decoder.extend_from_slice(&[0, 0, 0]);
+/// assert!(decoder.decode_next().unwrap().is_none());
+/// assert_eq!(decoder.buffered_len(), 3);
+/// ```
+///
+/// Messages larger than its `MessageSizeLimit` are not buffered up:
+/// the error is reported as soon as the length field arrives.
+///
+/// ```
+/// use vrpn::{buffer_unbuffer::BufferUnbufferError, codec::{MessageDecoder, MessageSizeLimit}};
+/// let mut decoder = MessageDecoder::new().with_limit(MessageSizeLimit::new(1024));
+/// // A length field claiming almost 4 GB
+/// decoder.extend_from_slice(&[0xff, 0xff, 0xff, 0xf0]);
+/// assert!(matches!(
+///     decoder.decode_next(),
+///     Err(BufferUnbufferError::MessageTooLarge { max: 1024, .. })
+/// ));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MessageDecoder {
+    buf: BytesMut,
+    size_check: SizeCheck,
+    profile: ProtocolProfile,
+    /// Size of the message last returned by `decode_next_view`,
+    /// consumed from the buffer once that view is no longer borrowed.
+    view_len: usize,
+}
+
+impl MessageDecoder {
+    /// Create a decoder with an empty buffer and the default size limit.
+    pub fn new() -> MessageDecoder {
+        MessageDecoder::default()
+    }
+
+    /// Create a decoder with the given initial buffer capacity.
+    pub fn with_capacity(capacity: usize) -> MessageDecoder {
+        MessageDecoder {
+            buf: BytesMut::with_capacity(capacity),
+            ..Default::default()
+        }
+    }
+
+    /// Change the limit on incoming message size.
+    pub fn with_limit(self, limit: MessageSizeLimit) -> MessageDecoder {
+        MessageDecoder {
+            size_check: SizeCheck::new(limit),
+            ..self
+        }
+    }
+
+    /// The limit on incoming message size.
+    pub fn limit(&self) -> MessageSizeLimit {
+        self.size_check.limit()
+    }
+
+    /// Change the alignment and header layout expected of incoming messages.
+    ///
+    /// Only needed when the remote side uses something other than `ProtocolProfile::VRPN`.
+    pub fn with_profile(self, profile: ProtocolProfile) -> MessageDecoder {
+        MessageDecoder { profile, ..self }
+    }
+
+    /// The alignment and header layout expected of incoming messages.
+    pub fn profile(&self) -> ProtocolProfile {
+        self.profile
+    }
+
+    /// True if currently discarding the bytes of an oversized message.
+    pub fn is_skipping(&self) -> bool {
+        self.size_check.is_skipping()
+    }
+
+    /// Append received bytes to the internal buffer.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.release_view();
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The number of bytes received but not yet consumed by a decoded message.
+    pub fn buffered_len(&self) -> usize {
+        self.buf.len() - self.view_len
+    }
+
+    /// Consume the message last returned by `decode_next_view`, if any.
+    fn release_view(&mut self) {
+        self.buf.advance(self.view_len);
+        self.view_len = 0;
+    }
+
+    /// Discard any remaining bytes of an oversized message, and check the size of the next.
+    ///
+    /// Returns Ok(false) if still discarding.
+    fn check_next_size(&mut self) -> UnbufferResult<bool> {
+        self.size_check.check(&mut self.buf, &self.profile)
+    }
+
+    /// Decode the next complete message, if one is buffered.
+    ///
+    /// Returns Ok(None) if more data is needed.
+    ///
+    /// Returns `BufferUnbufferError::MessageTooLarge` for a message exceeding the limit.
+    /// With `OversizePolicy::Skip`, that is reported once and decoding may continue;
+    /// with `OversizePolicy::Reject`, it will be reported on every call.
+    pub fn decode_next(&mut self) -> UnbufferResult<Option<SequencedGenericMessage>> {
+        self.release_view();
+        if !self.check_next_size()? {
+            return Ok(None);
+        }
+        decode_one_from_bytes_mut_with_profile(&mut self.buf, &self.profile)
+    }
+
+    /// Like `decode_next`, but returning a view of the next message borrowed from the buffer,
+    /// rather than copying it out.
+    ///
+    /// The message is consumed by the next call taking `&mut self`.
+    ///
+    /// ```
+    /// use vrpn::{
+    ///     codec::MessageDecoder,
+    ///     data_types::{GenericBody, GenericMessage, Message, MessageHeader, MessageTypeId},
+    ///     data_types::id_types::{SenderId, SequenceNumber},
+    /// };
+    /// let msg = GenericMessage::from_header_and_body(
+    ///     MessageHeader::new(None, MessageTypeId(2), SenderId(1)),
+    ///     GenericBody::new(vec![1, 2, 3].into()),
+    /// )
+    /// .into_sequenced_message(SequenceNumber(0));
+    /// let bytes = msg.try_into_buf()?;
+    /// let mut decoder = MessageDecoder::new();
+    /// decoder.extend_from_slice(&bytes);
+    /// decoder.extend_from_slice(&bytes);
+    /// let mut forwarded = Vec::new();
+    /// while let Some(view) = decoder.decode_next_view()? {
+    ///     forwarded.extend_from_slice(view.frame());
+    /// }
+    /// assert_eq!(forwarded.len(), 2 * bytes.len());
+    /// assert_eq!(decoder.buffered_len(), 0);
+    /// # Ok::<(), vrpn::VrpnError>(())
+    /// ```
+    pub fn decode_next_view(&mut self) -> UnbufferResult<Option<MessageView<'_>>> {
+        self.release_view();
+        if !self.check_next_size()? || self.buf.is_empty() {
+            return Ok(None);
+        }
+        match MessageView::try_read_from_slice(&self.buf, &self.profile) {
+            Ok(view) => {
+                self.view_len = view.frame().len();
+                Ok(Some(view))
+            }
+            // Not enough data in the buffer - here, that's not an error.
+            Err(BufferUnbufferError::NeedMoreData(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
 // pub(crate) fn decode_one_mut(buf: &mut BytesMut) -> Result<Option<SequencedGenericMessage>> {
 //     let initial_len = buf.len();
 //     if let Some(combined_size) = peek_u32_bytes_mut(buf)? {
@@ -47,62 +358,62 @@ pub(crate) fn maybe_decode_one<T: Buf + Clone>(
 // }
 #[cfg(test)]
 mod tests {
-    use bytes::Bytes;
+
+    use crate::data_types::MessageSize;
 
     use super::*;
 
+    const MSG1: [u8; 48] = hex!(
+        // length is 0x29 = 41
+        "00 00 00 29"
+        // timestamp seconds 1542140718
+        "5b eb 33 2e"
+        // timestamp useconds 809137
+        "00 0c 58 b1"
+        // sender 0
+        "00 00 00 00"
+        // message type -1
+        "ff ff ff ff"
+        // sequence/padding
+        "00 00 00 00"
+        // body
+        "00 00 00 0d 56 52 50 4e 20 43 6f 6e 74 72 6f 6c 00 00 00 00 00 00 00 00");
+    const MSG2: [u8; 40] = hex!(
+        // length is 0x25 = 37
+        "00 00 00 25"
+        // timestamp seconds 1542140718
+        "5b eb 33 2e"
+        // timestamp useconds 809137
+        "00 0c 58 b1"
+        // sender 1
+        "00 00 00 01"
+        // message type -1
+        "ff ff ff ff"
+        // sequence/padding
+        "00 00 00 01"
+        // body
+        "00 00 00 09 54 72 61 63 6b 65 72 30 00 00 00 00");
+    const MSG3: [u8; 72] = hex!(
+        // length is 0x41 = 65
+        "00 00 00 41"
+        // timestamp seconds 1542140718
+        "5b eb 33 2e"
+        // timestamp useconds 809138
+        "00 0c 58 b2"
+        // sender 0
+        "00 00 00 00"
+        // message type
+        "ff ff ff fe"
+        // sequence/padding
+        "00 00 00 02"
+        // body
+        "00 00 00 25 56 52 50 4e 5f 43 6f 6e 6e 65 63 74 69 6f 6e 5f 47 6f 74 5f 46 69 72 73 74 5f 43 6f 6e 6e 65 63 74 69 6f 6e 00 00 00 00 00 00 00 00");
+
     #[test]
     fn individual_decode_one() {
-        const MSG1: [u8; 48] = hex!(
-            // length is 0x29 = 41
-            "00 00 00 29"
-            // timestamp seconds 1542140718
-            "5b eb 33 2e"
-            // timestamp useconds 809137
-            "00 0c 58 b1"
-            // sender 0
-            "00 00 00 00"
-            // message type -1
-            "ff ff ff ff"
-            // sequence/padding
-            "00 00 00 00"
-            // body
-            "00 00 00 0d 56 52 50 4e 20 43 6f 6e 74 72 6f 6c 00 00 00 00 00 00 00 00");
-        const MSG2: [u8; 40] = hex!(
-            // length is 0x25 = 37
-            "00 00 00 25"
-            // timestamp seconds 1542140718
-            "5b eb 33 2e"
-            // timestamp useconds 809137
-            "00 0c 58 b1"
-            // sender 1
-            "00 00 00 01"
-            // message type -1
-            "ff ff ff ff"
-            // sequence/padding
-            "00 00 00 01"
-            // body
-            "00 00 00 09 54 72 61 63 6b 65 72 30 00 00 00 00");
-        const MSG3: [u8; 72] = hex!(
-            // length is 0x41 = 65
-            "00 00 00 41"
-            // timestamp seconds 1542140718
-            "5b eb 33 2e"
-            // timestamp useconds 809138
-            "00 0c 58 b2"
-            // sender 0
-            "00 00 00 00"
-            // message type
-            "ff ff ff fe"
-            // sequence/padding
-            "00 00 00 02"
-            // body
-            "00 00 00 25 56 52 50 4e 5f 43 6f 6e 6e 65 63 74 69 6f 6e 5f 47 6f 74 5f 46 69 72 73 74 5f 43 6f 6e 6e 65 63 74 69 6f 6e 00 00 00 00 00 00 00 00");
-
-        // const test_messages = ;
         for msg_bytes in [Vec::from(MSG1), Vec::from(MSG2), Vec::from(MSG3)] {
-            let mut data = Bytes::copy_from_slice(&msg_bytes);
-            let decoded = maybe_decode_one(&mut data);
+            let mut data = BytesMut::from(&msg_bytes[..]);
+            let decoded = decode_one_from_bytes_mut_with_profile(&mut data, &ProtocolProfile::VRPN);
             assert!(decoded.is_ok());
             let decoded = decoded.unwrap();
             assert!(decoded.is_some());
@@ -110,4 +421,195 @@ mod tests {
             assert_eq!(data.len(), 0);
         }
     }
+
+    fn decode_all(decoder: &mut MessageDecoder) -> Vec<SequencedGenericMessage> {
+        let mut ret = Vec::new();
+        while let Some(msg) = decoder.decode_next().expect("decoding should succeed") {
+            ret.push(msg);
+        }
+        ret
+    }
+
+    fn reference_messages() -> Vec<SequencedGenericMessage> {
+        [&MSG1[..], &MSG2[..], &MSG3[..]]
+            .iter()
+            .map(|msg_bytes| {
+                let mut data = BytesMut::from(*msg_bytes);
+                decode_one_from_bytes_mut_with_profile(&mut data, &ProtocolProfile::VRPN)
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn partial_frames() {
+        for msg_bytes in [&MSG1[..], &MSG2[..], &MSG3[..]] {
+            let mut reference = BytesMut::from(msg_bytes);
+            let reference =
+                decode_one_from_bytes_mut_with_profile(&mut reference, &ProtocolProfile::VRPN)
+                    .unwrap()
+                    .unwrap();
+            for split in 0..msg_bytes.len() {
+                let mut decoder = MessageDecoder::new();
+                decoder.extend_from_slice(&msg_bytes[..split]);
+                assert!(decoder.decode_next().unwrap().is_none());
+                // Nothing consumed from an incomplete frame
+                assert_eq!(decoder.buffered_len(), split);
+                decoder.extend_from_slice(&msg_bytes[split..]);
+                let decoded = decode_all(&mut decoder);
+                assert_eq!(decoded, vec![reference.clone()]);
+                assert_eq!(decoder.buffered_len(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn byte_at_a_time() {
+        let mut decoder = MessageDecoder::new();
+        let mut decoded = Vec::new();
+        for b in MSG1.iter().chain(MSG2.iter()).chain(MSG3.iter()) {
+            decoder.extend_from_slice(&[*b]);
+            decoded.extend(decode_all(&mut decoder));
+        }
+        assert_eq!(decoded, reference_messages());
+        assert_eq!(decoder.buffered_len(), 0);
+    }
+
+    #[test]
+    fn coalesced_frames() {
+        let all: Vec<u8> = MSG1
+            .iter()
+            .chain(MSG2.iter())
+            .chain(MSG3.iter())
+            .copied()
+            .collect();
+        let expected = reference_messages();
+
+        // All at once
+        let mut decoder = MessageDecoder::new();
+        decoder.extend_from_slice(&all);
+        assert_eq!(decode_all(&mut decoder), expected);
+        assert_eq!(decoder.buffered_len(), 0);
+
+        // Coalesced, with a split at every possible position
+        for split in 0..all.len() {
+            let mut decoder = MessageDecoder::with_capacity(all.len());
+            decoder.extend_from_slice(&all[..split]);
+            let mut decoded = decode_all(&mut decoder);
+            decoder.extend_from_slice(&all[split..]);
+            decoded.extend(decode_all(&mut decoder));
+            assert_eq!(decoded, expected);
+            assert_eq!(decoder.buffered_len(), 0);
+        }
+    }
+
+    /// A message with a valid header and a body of the given (padded) size.
+    fn oversized_message(body_size: usize) -> Vec<u8> {
+        let size = MessageSize::from_unpadded_body_size(body_size);
+        let mut msg = Vec::from(&MSG2[..MessageSize::PADDED_HEADER_SIZE]);
+        msg[..4].copy_from_slice(&size.length_field().to_be_bytes());
+        msg.resize(size.padded_message_size(), 0);
+        msg
+    }
+
+    #[test]
+    fn oversized_rejected() {
+        let mut decoder = MessageDecoder::new().with_limit(MessageSizeLimit::new(64));
+        decoder.extend_from_slice(&hex!("ff ff ff f0"));
+        for _ in 0..2 {
+            match decoder.decode_next() {
+                Err(BufferUnbufferError::MessageTooLarge { claimed, max }) => {
+                    assert!(claimed >= 0xffff_fff0);
+                    assert_eq!(max, 64);
+                }
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+
+        // Exactly at the limit is fine.
+        let mut decoder = MessageDecoder::new().with_limit(MessageSizeLimit::new(MSG3.len()));
+        decoder.extend_from_slice(&MSG3);
+        assert_eq!(decode_all(&mut decoder).len(), 1);
+    }
+
+    #[test]
+    fn oversized_skipped() {
+        let big = oversized_message(200);
+        let all: Vec<u8> = MSG1
+            .iter()
+            .chain(big.iter())
+            .chain(MSG2.iter())
+            .chain(MSG3.iter())
+            .copied()
+            .collect();
+        let limit = MessageSizeLimit::new(128).with_policy(OversizePolicy::Skip);
+        for chunk_size in [1, 7, all.len()] {
+            let mut decoder = MessageDecoder::new().with_limit(limit);
+            let mut decoded = Vec::new();
+            let mut errors = 0;
+            for chunk in all.chunks(chunk_size) {
+                decoder.extend_from_slice(chunk);
+                loop {
+                    match decoder.decode_next() {
+                        Ok(Some(msg)) => decoded.push(msg),
+                        Ok(None) => break,
+                        Err(BufferUnbufferError::MessageTooLarge { claimed, .. }) => {
+                            assert_eq!(claimed, big.len());
+                            errors += 1;
+                        }
+                        Err(e) => panic!("unexpected error {:?}", e),
+                    }
+                }
+            }
+            assert_eq!(errors, 1);
+            assert_eq!(decoded, reference_messages());
+            assert!(!decoder.is_skipping());
+        }
+    }
+
+    #[test]
+    fn borrowed_views() {
+        let big = oversized_message(200);
+        let all: Vec<u8> = MSG1
+            .iter()
+            .chain(big.iter())
+            .chain(MSG2.iter())
+            .chain(MSG3.iter())
+            .copied()
+            .collect();
+        let limit = MessageSizeLimit::new(128).with_policy(OversizePolicy::Skip);
+        for chunk_size in [1, 7, all.len()] {
+            let mut decoder = MessageDecoder::new().with_limit(limit);
+            let mut decoded = Vec::new();
+            let mut frames = Vec::new();
+            for chunk in all.chunks(chunk_size) {
+                decoder.extend_from_slice(chunk);
+                loop {
+                    match decoder.decode_next_view() {
+                        Ok(Some(view)) => {
+                            decoded.push(view.to_sequenced_message());
+                            frames.extend_from_slice(view.frame());
+                        }
+                        Ok(None) => break,
+                        Err(BufferUnbufferError::MessageTooLarge { .. }) => {}
+                        Err(e) => panic!("unexpected error {:?}", e),
+                    }
+                }
+            }
+            assert_eq!(decoded, reference_messages());
+            assert_eq!(frames, [&MSG1[..], &MSG2[..], &MSG3[..]].concat());
+            assert_eq!(decoder.buffered_len(), 0);
+        }
+
+        // Mixed with owned decoding, which consumes the last view first.
+        let mut decoder = MessageDecoder::new();
+        decoder.extend_from_slice(&MSG1);
+        decoder.extend_from_slice(&MSG2);
+        let view = decoder.decode_next_view().unwrap().unwrap();
+        assert_eq!(view.frame(), &MSG1[..]);
+        assert_eq!(decoder.buffered_len(), MSG2.len());
+        assert_eq!(decode_all(&mut decoder), reference_messages()[1..2]);
+        assert!(decoder.decode_next_view().unwrap().is_none());
+    }
 }
@@ -0,0 +1,579 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Server configuration files: the devices to serve, and how.
+//!
+//! The counterpart of mainline VRPN's `vrpn.cfg`, in TOML:
+//!
+//! ```toml
+//! [server]
+//! port = 3883
+//!
+//! [transport]
+//! max_send_age_ms = 50
+//!
+//! [[device]]
+//! name = "Tracker0"
+//! type = "tracker"
+//! rate_hz = 60.0
+//! count = 2
+//!
+//! [[device]]
+//! name = "Buttons0"
+//! type = "button"
+//! count = 8
+//!
+//! [[device]]
+//! name = "Joystick0"
+//! type = "analog"
+//! count = 3
+//! # Per channel, in order: any channels not listed are passed through unchanged.
+//! calibration = [
+//!     { offset = 0.5, scale = 2.0, deadband = 0.05 },
+//!     { offset = 0.5, scale = -2.0, deadband = 0.05 },
+//! ]
+//! ```
+//!
+//! Every section is optional. Devices get their sender IDs in the order listed:
+//! see `ServerConfig::id_assignment`.
+//!
+//! A running server can pick up edits with a `ConfigWatcher`,
+//! applying the resulting `ConfigChanges` without dropping its clients.
+
+#[cfg(feature = "analog")]
+use crate::analog::calibration::{AnalogCalibration, ChannelCalibration};
+use crate::{
+    constants::DEFAULT_PORT, data_types::SenderName, type_dispatcher::IdAssignment,
+    vrpn_async::LowLatencyConfig, Connection, Result, VrpnError,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// The kind of device, determining the messages it sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    /// Poses of `count` sensors.
+    Tracker,
+    /// Values of `count` channels.
+    Analog,
+    /// States of `count` buttons.
+    Button,
+    /// Text messages.
+    Text,
+}
+
+impl DeviceKind {
+    /// The most sensors, channels, or buttons a device of this kind may have, if limited.
+    fn max_count(self) -> Option<usize> {
+        match self {
+            #[cfg(feature = "analog")]
+            DeviceKind::Analog => Some(crate::analog::MAX_CHANNELS),
+            #[cfg(feature = "button")]
+            DeviceKind::Button => Some(crate::button::MAX_BUTTONS),
+            _ => None,
+        }
+    }
+}
+
+/// One device to serve.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Sender name, e.g. `Tracker0`.
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: DeviceKind,
+    /// How often to send reports, if periodically.
+    #[serde(default)]
+    pub rate_hz: Option<f64>,
+    /// Number of sensors, channels, or buttons.
+    #[serde(default = "default_count")]
+    pub count: usize,
+    /// Calibration of the channels of an analog device, applied on receive:
+    /// see `DeviceConfig::analog_calibration`.
+    #[cfg(feature = "analog")]
+    #[serde(default)]
+    pub calibration: Vec<ChannelCalibration>,
+}
+
+fn default_count() -> usize {
+    1
+}
+
+impl DeviceConfig {
+    pub fn sender_name(&self) -> SenderName {
+        SenderName::from(self.name.as_str())
+    }
+
+    /// Time between periodic reports, if any.
+    pub fn update_interval(&self) -> Option<Duration> {
+        self.rate_hz.map(|rate| Duration::from_secs_f64(1.0 / rate))
+    }
+
+    /// Calibration to apply to received reports from this device, if any was given.
+    ///
+    /// Wrap report handlers in a `CalibratedAnalogHandler` with it, so they see calibrated values.
+    #[cfg(feature = "analog")]
+    pub fn analog_calibration(&self) -> Option<AnalogCalibration> {
+        (!self.calibration.is_empty()).then(|| AnalogCalibration::new(self.calibration.clone()))
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.sender_name().check_valid()?;
+        if let Some(rate) = self.rate_hz {
+            if !(rate.is_finite() && rate > 0.0) {
+                return Err(VrpnError::Config(format!(
+                    "{}: rate_hz must be positive, got {}",
+                    self.name, rate
+                )));
+            }
+        }
+        if self.count == 0 {
+            return Err(VrpnError::Config(format!(
+                "{}: count must be at least 1",
+                self.name
+            )));
+        }
+        if let Some(max) = self.kind.max_count() {
+            if self.count > max {
+                return Err(VrpnError::Config(format!(
+                    "{}: count {} exceeds maximum of {}",
+                    self.name, self.count, max
+                )));
+            }
+        }
+        #[cfg(feature = "analog")]
+        self.validate_calibration()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "analog")]
+    fn validate_calibration(&self) -> Result<()> {
+        if self.calibration.is_empty() {
+            return Ok(());
+        }
+        if self.kind != DeviceKind::Analog {
+            return Err(VrpnError::Config(format!(
+                "{}: calibration is only for analog devices",
+                self.name
+            )));
+        }
+        if self.calibration.len() > self.count {
+            return Err(VrpnError::Config(format!(
+                "{}: calibration given for {} channels, but count is {}",
+                self.name,
+                self.calibration.len(),
+                self.count
+            )));
+        }
+        for (i, channel) in self.calibration.iter().enumerate() {
+            channel.check_valid().map_err(|e| match e {
+                VrpnError::Config(msg) => {
+                    VrpnError::Config(format!("{}: channel {}: {}", self.name, i, msg))
+                }
+                e => e,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Where to listen for clients.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenConfig {
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Address of the interface to listen on, or all of them if not given.
+    #[serde(default)]
+    pub interface: Option<IpAddr>,
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+impl Default for ListenConfig {
+    fn default() -> ListenConfig {
+        ListenConfig {
+            port: DEFAULT_PORT,
+            interface: None,
+        }
+    }
+}
+
+/// Transport options for connections to clients.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransportConfig {
+    /// Refuse UDP, so clients send and receive everything over TCP.
+    #[serde(default)]
+    pub tcp_only: bool,
+    /// See `LowLatencyConfig::recv_buffer_size`.
+    #[serde(default)]
+    pub recv_buffer_size: Option<usize>,
+    /// See `LowLatencyConfig::send_buffer_size`.
+    #[serde(default)]
+    pub send_buffer_size: Option<usize>,
+    /// See `LowLatencyConfig::max_send_age`, in milliseconds.
+    #[serde(default)]
+    pub max_send_age_ms: Option<u64>,
+}
+
+impl TransportConfig {
+    /// The socket and queue settings to use for each client.
+    pub fn low_latency(&self) -> LowLatencyConfig {
+        LowLatencyConfig {
+            recv_buffer_size: self.recv_buffer_size,
+            send_buffer_size: self.send_buffer_size,
+            max_send_age: self.max_send_age_ms.map(Duration::from_millis),
+            ..Default::default()
+        }
+    }
+}
+
+/// Contents of a server configuration file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    #[serde(default)]
+    pub server: ListenConfig,
+    #[serde(default)]
+    pub transport: TransportConfig,
+    #[serde(default, rename = "device")]
+    pub devices: Vec<DeviceConfig>,
+}
+
+impl ServerConfig {
+    /// Parse and validate a configuration.
+    pub fn from_toml_str(text: &str) -> Result<ServerConfig> {
+        let config: ServerConfig =
+            toml::from_str(text).map_err(|e| VrpnError::Config(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Read, parse, and validate a configuration file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<ServerConfig> {
+        ServerConfig::from_toml_str(&std::fs::read_to_string(path)?)
+    }
+
+    /// Check each device, and that no two share a name.
+    pub fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for device in &self.devices {
+            device.validate()?;
+            if !names.insert(device.name.as_str()) {
+                return Err(VrpnError::Config(format!(
+                    "device {} is listed more than once",
+                    device.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get a device by name.
+    pub fn device(&self, name: &str) -> Option<&DeviceConfig> {
+        self.devices.iter().find(|device| device.name == name)
+    }
+
+    /// Sender IDs for the devices, in the order listed, for a server's `TypeDispatcher`.
+    pub fn id_assignment(&self) -> IdAssignment {
+        self.devices
+            .iter()
+            .fold(IdAssignment::new(), |ids, device| {
+                ids.with_sender(device.sender_name())
+            })
+    }
+
+    /// Compare with an updated configuration, matching devices by name.
+    pub fn changes_to(&self, new: &ServerConfig) -> ConfigChanges {
+        let mut changes = ConfigChanges {
+            server_changed: self.server != new.server || self.transport != new.transport,
+            ..Default::default()
+        };
+        for device in &new.devices {
+            match self.device(&device.name) {
+                None => changes.added.push(device.clone()),
+                Some(old) if old != device => changes.modified.push(device.clone()),
+                Some(_) => {}
+            }
+        }
+        changes.removed = self
+            .devices
+            .iter()
+            .filter(|device| new.device(&device.name).is_none())
+            .cloned()
+            .collect();
+        changes
+    }
+}
+
+/// Differences between two configurations.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigChanges {
+    pub added: Vec<DeviceConfig>,
+    pub removed: Vec<DeviceConfig>,
+    /// The new settings of devices that changed: their instances need re-creating.
+    pub modified: Vec<DeviceConfig>,
+    /// Whether the listen or transport settings changed, which only take effect on restart.
+    pub server_changed: bool,
+}
+
+impl ConfigChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+            && !self.server_changed
+    }
+
+    /// Update the senders of a connection: register (and so describe) those of added devices,
+    /// and retire those of removed devices.
+    ///
+    /// Creating and dropping the device instances themselves is up to the server.
+    pub fn apply_to_connection<C: Connection>(&self, connection: &C) -> Result<()> {
+        for device in &self.removed {
+            let sender = connection.register_sender(device.sender_name())?;
+            connection.retire_sender(sender)?;
+        }
+        for device in &self.added {
+            connection.register_sender(device.sender_name())?;
+        }
+        Ok(())
+    }
+}
+
+/// Reloads a configuration file when it changes, by polling its modification time.
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    config: ServerConfig,
+}
+
+fn modified_time(path: &Path) -> Result<SystemTime> {
+    Ok(std::fs::metadata(path)?.modified()?)
+}
+
+impl ConfigWatcher {
+    /// Load the initial configuration.
+    pub fn new(path: impl Into<PathBuf>) -> Result<ConfigWatcher> {
+        let path = path.into();
+        let modified = modified_time(&path).ok();
+        let config = ServerConfig::from_file(&path)?;
+        Ok(ConfigWatcher {
+            path,
+            modified,
+            config,
+        })
+    }
+
+    /// The configuration currently in effect.
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Reload the file if it has been modified, returning what changed, if anything.
+    ///
+    /// If the modified file is invalid, returns the error and keeps the current configuration,
+    /// without trying again until the file is modified again.
+    pub fn poll(&mut self) -> Result<Option<ConfigChanges>> {
+        let modified = modified_time(&self.path)?;
+        if self.modified == Some(modified) {
+            return Ok(None);
+        }
+        self.modified = Some(modified);
+        let new = ServerConfig::from_file(&self.path)?;
+        let changes = self.config.changes_to(&new);
+        self.config = new;
+        Ok(Some(changes).filter(|changes| !changes.is_empty()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data_types::ClassOfService, testing::MockConnection, TypeDispatcher};
+    use std::fs::File;
+
+    #[test]
+    fn parse_example() {
+        let config = ServerConfig::from_toml_str(
+            r#"
+            [server]
+            port = 3884
+
+            [transport]
+            max_send_age_ms = 50
+
+            [[device]]
+            name = "Tracker0"
+            type = "tracker"
+            rate_hz = 60.0
+            count = 2
+
+            [[device]]
+            name = "Text0"
+            type = "text"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 3884);
+        assert_eq!(config.server.interface, None);
+        assert_eq!(
+            config.transport.low_latency().max_send_age,
+            Some(Duration::from_millis(50))
+        );
+        let tracker = config.device("Tracker0").unwrap();
+        assert_eq!(tracker.kind, DeviceKind::Tracker);
+        assert_eq!(tracker.count, 2);
+        assert_eq!(
+            tracker.update_interval(),
+            Some(Duration::from_secs_f64(1.0 / 60.0))
+        );
+        assert_eq!(config.device("Text0").unwrap().update_interval(), None);
+
+        let dispatcher = TypeDispatcher::with_id_assignment(&config.id_assignment()).unwrap();
+        let tracker_id = dispatcher.get_sender_id("Tracker0").unwrap();
+        let text_id = dispatcher.get_sender_id("Text0").unwrap();
+        assert_eq!(text_id.0 .0, tracker_id.0 .0 + 1);
+    }
+
+    #[test]
+    fn defaults_and_errors() {
+        let config = ServerConfig::from_toml_str("").unwrap();
+        assert_eq!(config.server.port, DEFAULT_PORT);
+        assert!(config.devices.is_empty());
+
+        for bad in [
+            // Unknown device type
+            "[[device]]\nname = \"A\"\ntype = \"haptic\"",
+            // Misspelled field
+            "[[device]]\nname = \"A\"\ntype = \"analog\"\nrate = 10.0",
+            "[[device]]\nname = \"A\"\ntype = \"analog\"\nrate_hz = -1.0",
+            "[[device]]\nname = \"A\"\ntype = \"tracker\"\n[[device]]\nname = \"A\"\ntype = \"text\"",
+            "[[device]]\nname = \"A\"\ntype = \"button\"\ncount = 0",
+        ] {
+            assert!(ServerConfig::from_toml_str(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[cfg(feature = "analog")]
+    #[test]
+    fn calibration() {
+        use crate::analog::calibration::ChannelCalibration;
+        let config = ServerConfig::from_toml_str(
+            r#"
+            [[device]]
+            name = "Joystick0"
+            type = "analog"
+            count = 3
+            calibration = [
+                { offset = 0.5, scale = 2.0, deadband = 0.05 },
+                { scale = -1.0 },
+            ]
+
+            [[device]]
+            name = "Analog1"
+            type = "analog"
+            "#,
+        )
+        .unwrap();
+        let calibration = config
+            .device("Joystick0")
+            .unwrap()
+            .analog_calibration()
+            .unwrap();
+        assert_eq!(
+            calibration.channel(0),
+            ChannelCalibration::new(0.5, 2.0).with_deadband(0.05)
+        );
+        assert_eq!(calibration.channel(1), ChannelCalibration::new(0.0, -1.0));
+        assert_eq!(calibration.channel(2), ChannelCalibration::default());
+        assert_eq!(config.device("Analog1").unwrap().analog_calibration(), None);
+
+        for bad in [
+            "[[device]]\nname = \"A\"\ntype = \"tracker\"\ncalibration = [{ scale = 2.0 }]",
+            "[[device]]\nname = \"A\"\ntype = \"analog\"\ncalibration = [{}, {}]",
+            "[[device]]\nname = \"A\"\ntype = \"analog\"\ncalibration = [{ deadband = -1.0 }]",
+            "[[device]]\nname = \"A\"\ntype = \"analog\"\ncalibration = [{ scale = inf }]",
+            "[[device]]\nname = \"A\"\ntype = \"analog\"\ncalibration = [{ gain = 2.0 }]",
+        ] {
+            assert!(ServerConfig::from_toml_str(bad).is_err(), "{}", bad);
+        }
+    }
+
+    fn write_config(path: &Path, text: &str, modified: SystemTime) -> Result<()> {
+        std::fs::write(path, text)?;
+        File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(modified)?;
+        Ok(())
+    }
+
+    #[test]
+    fn reload() {
+        let path = std::env::temp_dir().join(format!("vrpn-config-{}.toml", std::process::id()));
+        let start = SystemTime::now();
+        let two_devices = "[[device]]\nname = \"A\"\ntype = \"tracker\"\n\
+                           [[device]]\nname = \"B\"\ntype = \"text\"";
+        write_config(&path, two_devices, start).unwrap();
+        let mut watcher = ConfigWatcher::new(&path).unwrap();
+        assert_eq!(watcher.config().devices.len(), 2);
+        assert_eq!(watcher.poll().unwrap(), None);
+
+        let connection = MockConnection::new();
+        connection
+            .assign_ids(&watcher.config().id_assignment())
+            .unwrap();
+        let a = connection.register_sender("A").unwrap();
+
+        // Remove A, change B, add C.
+        write_config(
+            &path,
+            "[[device]]\nname = \"B\"\ntype = \"text\"\nrate_hz = 1.0\n\
+             [[device]]\nname = \"C\"\ntype = \"analog\"",
+            start + Duration::from_secs(1),
+        )
+        .unwrap();
+        let changes = watcher.poll().unwrap().unwrap();
+        assert_eq!(changes.removed[0].name, "A");
+        assert_eq!(changes.modified[0].name, "B");
+        assert_eq!(changes.added[0].name, "C");
+        assert!(!changes.server_changed);
+        changes.apply_to_connection(&*connection).unwrap();
+        assert!(matches!(
+            connection.pack_message_body(None, a, crate::ping::Ping, ClassOfService::RELIABLE),
+            Err(VrpnError::SenderRetired(_))
+        ));
+
+        // An invalid edit is reported once, and the last good configuration kept.
+        write_config(&path, "[[device]]", start + Duration::from_secs(2)).unwrap();
+        assert!(watcher.poll().is_err());
+        assert_eq!(watcher.poll().unwrap(), None);
+        assert!(watcher.config().device("C").is_some());
+
+        // Adding A back revives its sender, re-describing it.
+        write_config(&path, two_devices, start + Duration::from_secs(3)).unwrap();
+        let sent_before = connection.sent_messages().len();
+        let changes = watcher.poll().unwrap().unwrap();
+        changes.apply_to_connection(&*connection).unwrap();
+        assert_eq!(connection.sent_messages().len(), sent_before + 1);
+        assert_eq!(connection.register_sender("A").unwrap(), a);
+        connection
+            .pack_message_body(None, a, crate::ping::Ping, ClassOfService::RELIABLE)
+            .unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -3,20 +3,37 @@
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
 use std::{
+    collections::VecDeque,
     convert::TryFrom,
-    sync::{Arc, Mutex},
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
 };
 
 use crate::{
     buffer_unbuffer::BufferTo,
+    clock::{Instant, SharedClock, SystemClock},
     data_types::{
         id_types::*,
         name_types::{MessageTypeIdentifier, NameIntoBytes},
-        ClassOfService, GenericMessage, LogFileNames, MessageTypeId, MessageTypeName, SenderName,
-        TimeVal, TypedMessage, TypedMessageBody,
+        ClassOfService, GenericMessage, LogFileNames, MessageHeader, MessageTypeId,
+        MessageTypeName, SenderName, TimeVal, TypedMessage, TypedMessageBody,
     },
-    type_dispatcher::HandlerHandle,
+    dispatch_executor::DispatchQueueOverflow,
+    extensions::AgreedExtensions,
+    handler::{DescriptionHandler, DescriptionHandlerHandle},
+    health::Health,
+    ping::PingEvent,
+    stats::{ConnectionStats, EndpointDiagnostics},
+    translation_table::InvalidatedMappings,
+    type_check::TypeMismatch,
+    type_dispatcher::{
+        DispatchMarker, DuplicateNamePolicy, HandlerHandle, IdAssignment, LatestValueOnly,
+        RegisteredIds,
+    },
+    validation::{ValidationPolicy, Validator},
+    vrpn_async::parse_errors::Quarantined,
     Endpoint, EndpointGeneric, Handler, RegisterMapping, Result, TypeDispatcher, TypedHandler,
+    VrpnError,
 };
 
 pub type EndpointVec<EP> = Vec<Option<EP>>;
@@ -32,6 +49,66 @@ pub enum ConnectionStatus {
     Server(usize),
 }
 
+/// What a server does when a client connects from a host it already has an endpoint for,
+/// e.g. a client restarted before its old connection timed out.
+///
+/// Clients are identified by host address alone: the port changes with each connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateClientPolicy {
+    /// Keep both, since several clients may legitimately run on one host.
+    #[default]
+    KeepBoth,
+    /// Shut down the existing endpoints for the host, letting the new one take over.
+    DropOld,
+    /// Close the new connection, keeping the existing endpoints.
+    RejectNew,
+}
+
+/// A client connected from a host a server already had endpoints for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DuplicateClient {
+    /// The address of the host.
+    pub host: IpAddr,
+    /// How many endpoints the server already had for the host.
+    pub existing: usize,
+    /// What was done about it.
+    pub policy: DuplicateClientPolicy,
+}
+
+/// Events reported by a connection, other than received messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConnectionEvent {
+    /// An event from the ping client owned by the connection, if enabled.
+    Ping(PingEvent),
+    /// The remote end went away, so the IDs it assigned are no longer valid.
+    ///
+    /// Handlers are keyed on local IDs, so they stay registered,
+    /// and are re-bound by name once a new remote end describes its senders and types.
+    RemoteIdsInvalidated(InvalidatedMappings),
+    /// A client connection was re-established and our descriptions were sent again.
+    Reconnected,
+    /// An endpoint closed, cleanly or not, with what it was doing at the time.
+    EndpointClosed(EndpointDiagnostics),
+    /// In strict mode, messages of a type didn't decode as the local type of the same name.
+    TypeMismatch(TypeMismatch),
+    /// Messages found their queue to the dispatch workers full.
+    DispatchQueueOverflow(DispatchQueueOverflow),
+    /// The problems found by the connection's health check changed,
+    /// e.g. it stopped or started doing okay.
+    HealthChanged(Health),
+    /// A server got a client from a host it already had endpoints for,
+    /// and applied its `DuplicateClientPolicy`.
+    DuplicateClient(DuplicateClient),
+    /// An endpoint was shut down for exceeding its `ParseErrorLimit`.
+    ///
+    /// Followed by `EndpointClosed` for the same endpoint.
+    EndpointQuarantined(Quarantined),
+    /// Extensions were negotiated with an endpoint's remote end:
+    /// only those agreed may be used with it.
+    ExtensionsAgreed(AgreedExtensions),
+}
+
 pub trait Connection: Send + Sync {
     type SpecificEndpoint: Endpoint + EndpointGeneric;
 
@@ -48,15 +125,15 @@ pub trait Connection: Send + Sync {
     /// If the string is already registered, the returned ID will be the previously-assigned one.
     fn register_type<T>(&self, name: T) -> Result<LocalId<MessageTypeId>>
     where
-        T: Into<MessageTypeName> + Clone,
+        T: Into<MessageTypeName>,
     {
-        let mut dispatcher = self.connection_core().type_dispatcher.lock()?;
+        let (mut endpoints, mut dispatcher) =
+            self.connection_core().lock_endpoints_and_dispatcher()?;
         let name: MessageTypeName = name.into();
-        match dispatcher.register_type(name.clone())? {
+        match dispatcher.register_local_type(name.clone())? {
             RegisterMapping::Found(id) => Ok(id),
             RegisterMapping::NewMapping(id) => {
                 eprintln!("New mapping (coming from our side): {:?} -> {:?}", name, id);
-                let mut endpoints = self.connection_core().endpoints.lock()?;
                 let name = name.into_bytes();
                 for ep in endpoints.iter_mut().flatten() {
                     ep.new_local_id(&name, id)?;
@@ -68,16 +145,20 @@ pub trait Connection: Send + Sync {
 
     /// Register a sender name string and get a local ID for it.
     ///
-    /// If the string is already registered, the returned ID will be the previously-assigned one.
+    /// If the string is already registered, the returned ID will be the previously-assigned one,
+    /// unless `set_duplicate_name_policy` made registering it twice an error.
+    /// Registering a retired sender puts it back in use.
     fn register_sender<T>(&self, name: T) -> Result<LocalId<SenderId>>
     where
-        T: Into<SenderName> + Clone + NameIntoBytes,
+        T: Into<SenderName>,
     {
-        let mut dispatcher = self.connection_core().type_dispatcher.lock()?;
-        match dispatcher.register_sender(name.clone())? {
-            RegisterMapping::Found(id) => Ok(id),
-            RegisterMapping::NewMapping(id) => {
-                let mut endpoints = self.connection_core().endpoints.lock()?;
+        let (mut endpoints, mut dispatcher) =
+            self.connection_core().lock_endpoints_and_dispatcher()?;
+        let name: SenderName = name.into();
+        match dispatcher.register_local_sender(name.clone())? {
+            RegisterMapping::Found(id) if !dispatcher.revive_sender(id) => Ok(id),
+            // Describe a revived sender again, for any endpoint that forgot it.
+            RegisterMapping::Found(id) | RegisterMapping::NewMapping(id) => {
                 let name = name.into_bytes();
                 for ep in endpoints.iter_mut().flatten() {
                     ep.new_local_id(&name, id)?;
@@ -87,6 +168,124 @@ pub trait Connection: Send + Sync {
         }
     }
 
+    /// Register several sender and message type names at once, getting their local IDs.
+    ///
+    /// Every name is checked before any is registered, so one that is invalid,
+    /// or refused by the `DuplicateNamePolicy`, fails the whole batch.
+    /// The descriptions of new names are queued on each endpoint together,
+    /// senders then message types in the order listed, so they are sent as one batch:
+    /// e.g. for a server hosting many devices, or for a predictable order in golden tests.
+    fn register_all(&self, names: &IdAssignment) -> Result<RegisteredIds> {
+        let (mut endpoints, mut dispatcher) =
+            self.connection_core().lock_endpoints_and_dispatcher()?;
+        dispatcher.check_registrable(names)?;
+        let mut ids = RegisteredIds::default();
+        let mut new_senders = Vec::new();
+        for name in names.senders() {
+            let id = match dispatcher.register_local_sender(name.clone())? {
+                RegisterMapping::Found(id) if !dispatcher.revive_sender(id) => id,
+                RegisterMapping::Found(id) | RegisterMapping::NewMapping(id) => {
+                    new_senders.push((name.clone().into_bytes(), id));
+                    id
+                }
+            };
+            ids.senders.push(id);
+        }
+        let mut new_types = Vec::new();
+        for name in names.message_types() {
+            let id = match dispatcher.register_local_type(name.clone())? {
+                RegisterMapping::Found(id) => id,
+                RegisterMapping::NewMapping(id) => {
+                    new_types.push((name.clone().into_bytes(), id));
+                    id
+                }
+            };
+            ids.message_types.push(id);
+        }
+        for ep in endpoints.iter_mut().flatten() {
+            for (name, id) in &new_senders {
+                ep.new_local_id(name, *id)?;
+            }
+            for (name, id) in &new_types {
+                ep.new_local_id(name, *id)?;
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Keep only the newest pending message of a type from each sender,
+    /// in the queues chosen, or in none for `None`.
+    ///
+    /// Superseded messages are dropped without being sent or dispatched,
+    /// and counted in `ConnectionStats::superseded_messages`.
+    fn set_latest_value_only(
+        &self,
+        message_type: LocalId<MessageTypeId>,
+        mode: Option<LatestValueOnly>,
+    ) -> Result<()> {
+        self.connection_core()
+            .lock_dispatcher()?
+            .set_latest_value_only(message_type, mode);
+        Ok(())
+    }
+
+    /// Check that received messages decode as the local type of the same name before calling
+    /// their typed handlers: see `TypeDispatcher::set_strict_types`.
+    ///
+    /// Mismatches found are reported as `ConnectionEvent::TypeMismatch`.
+    fn set_strict_types(&self, strict: bool) -> Result<()> {
+        self.connection_core()
+            .lock_dispatcher()?
+            .set_strict_types(strict);
+        Ok(())
+    }
+
+    /// Choose what `register_sender` does with a name already registered on this side.
+    fn set_duplicate_name_policy(&self, policy: DuplicateNamePolicy) -> Result<()> {
+        self.connection_core()
+            .lock_dispatcher()?
+            .set_duplicate_name_policy(policy);
+        Ok(())
+    }
+
+    /// Stop sending messages from a sender, e.g. because its device was removed at runtime.
+    ///
+    /// VRPN has no way to tell the remote side to forget a name, so the ID stays reserved for it:
+    /// packing messages from the sender fails until it is registered again.
+    fn retire_sender(&self, sender: LocalId<SenderId>) -> Result<()> {
+        self.connection_core()
+            .lock_dispatcher()?
+            .retire_sender(sender)
+    }
+
+    /// Register the names in an `IdAssignment`, so their local IDs are predictable.
+    ///
+    /// Call this before creating any devices: fails if any of the names are already registered.
+    fn assign_ids(&self, ids: &IdAssignment) -> Result<()> {
+        {
+            let dispatcher = self.connection_core().lock_dispatcher()?;
+            let already = ids
+                .senders()
+                .iter()
+                .filter(|name| dispatcher.get_sender_id((*name).clone()).is_some())
+                .map(|name| name.0.clone())
+                .chain(
+                    ids.message_types()
+                        .iter()
+                        .filter(|name| dispatcher.get_type_id((*name).clone()).is_some())
+                        .map(|name| name.0.clone()),
+                )
+                .next();
+            if let Some(name) = already {
+                return Err(VrpnError::AlreadyRegistered(
+                    String::from_utf8_lossy(&name).into_owned(),
+                ));
+            }
+        }
+        self.register_all(ids)?;
+        Ok(())
+    }
+
     /// Add a generic handler, with optional filters on message type and sender.
     ///
     /// Returns a struct usable to remove the handler later.
@@ -96,7 +295,7 @@ pub trait Connection: Send + Sync {
         message_type_filter: Option<LocalId<MessageTypeId>>,
         sender_filter: Option<LocalId<SenderId>>,
     ) -> Result<HandlerHandle> {
-        let mut dispatcher = self.connection_core().type_dispatcher.lock()?;
+        let mut dispatcher = self.connection_core().lock_dispatcher()?;
         dispatcher.add_handler(handler, message_type_filter, sender_filter)
     }
 
@@ -113,19 +312,65 @@ pub trait Connection: Send + Sync {
     where
         T: TypedHandler + Handler + Sized,
     {
-        let message_type_filter = match T::Item::MESSAGE_IDENTIFIER {
-            MessageTypeIdentifier::UserMessageName(name) => Some(self.register_type(name)?),
-            MessageTypeIdentifier::SystemMessageId(id) => Some(LocalId(id)),
+        let message_type = match T::Item::MESSAGE_IDENTIFIER {
+            MessageTypeIdentifier::UserMessageName(name) => self.register_type(name)?,
+            MessageTypeIdentifier::SystemMessageId(id) => LocalId(id),
         };
-        self.add_handler(handler, message_type_filter, sender_filter)
+        self.connection_core()
+            .lock_dispatcher()?
+            .add_type_check::<T::Item>(message_type);
+        self.add_handler(handler, Some(message_type), sender_filter)
+    }
+
+    /// Check every incoming message of the validator's type before its handlers are called.
+    ///
+    /// Returns the message type, to pass to `remove_validators`.
+    fn add_validator<V: Validator + 'static>(
+        &self,
+        validator: Box<V>,
+        policy: ValidationPolicy,
+    ) -> Result<LocalId<MessageTypeId>> {
+        let mut dispatcher = self.connection_core().lock_dispatcher()?;
+        dispatcher.add_validator(validator, policy)
+    }
+
+    /// Remove all validators for a message type.
+    fn remove_validators(&self, message_type: LocalId<MessageTypeId>) -> Result<()> {
+        let mut dispatcher = self.connection_core().lock_dispatcher()?;
+        dispatcher.remove_validators(message_type);
+        Ok(())
     }
 
     /// Remove a handler previously added with add_handler() or add_typed_handler()
     fn remove_handler(&self, handler_handle: HandlerHandle) -> Result<()> {
-        let mut dispatcher = self.connection_core().type_dispatcher.lock()?;
+        let mut dispatcher = self.connection_core().lock_dispatcher()?;
         dispatcher.remove_handler(handler_handle)
     }
 
+    /// Whether a handler is still registered: neither removed with `remove_handler`,
+    /// nor having returned `HandlerCode::RemoveThisHandler`.
+    fn has_handler(&self, handler_handle: HandlerHandle) -> Result<bool> {
+        let dispatcher = self.connection_core().lock_dispatcher()?;
+        Ok(dispatcher.has_handler(handler_handle))
+    }
+
+    /// Add a handler called whenever a remote endpoint describes a sender or message type.
+    ///
+    /// Lets applications discover the devices a server offers without knowing their names.
+    fn add_description_handler(
+        &self,
+        handler: Box<dyn DescriptionHandler + Send>,
+    ) -> Result<DescriptionHandlerHandle> {
+        let mut dispatcher = self.connection_core().lock_dispatcher()?;
+        dispatcher.add_description_handler(handler)
+    }
+
+    /// Remove a handler previously added with add_description_handler()
+    fn remove_description_handler(&self, handle: DescriptionHandlerHandle) -> Result<()> {
+        let mut dispatcher = self.connection_core().lock_dispatcher()?;
+        dispatcher.remove_description_handler(handle)
+    }
+
     /// Pack a message to send to all connected endpoints.
     ///
     /// May not actually send immediately, might need to poll the connection somehow.
@@ -133,11 +378,60 @@ pub trait Connection: Send + Sync {
     where
         T: TypedMessageBody + BufferTo,
     {
-        let generic_msg = GenericMessage::try_from(msg)?;
+        self.pack_message_ref(&msg, class)
+    }
 
-        let mut endpoints = self.connection_core().endpoints.lock()?;
-        for ep in endpoints.iter_mut().flatten() {
-            ep.buffer_generic_message(generic_msg.clone(), class)?;
+    /// Pack a message to send to all connected endpoints, without consuming it.
+    ///
+    /// May not actually send immediately, might need to poll the connection somehow.
+    fn pack_message_ref<T>(&self, msg: &TypedMessage<T>, class: ClassOfService) -> Result<()>
+    where
+        T: TypedMessageBody + BufferTo,
+    {
+        self.pack_generic_message(GenericMessage::try_from(msg)?, class)
+    }
+
+    /// Pack an already-serialized message to send to all connected endpoints.
+    ///
+    /// May not actually send immediately, might need to poll the connection somehow.
+    ///
+    /// Handlers may call this (and the other `pack_message` methods taking a registered
+    /// message type) on the connection dispatching to them: the message is queued,
+    /// and sent in order once dispatching is done. Anything that registers a name,
+    /// including `pack_message_body` for a type not yet registered, fails with
+    /// `VrpnError::CalledDuringDispatch` instead: register ahead of time.
+    fn pack_generic_message(&self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
+        let core = self.connection_core();
+        if core.dispatching.is_current_thread() {
+            // The dispatcher, and perhaps the endpoints, are locked further up this thread's stack.
+            core.deferred.lock()?.push_back((msg, class));
+            return Ok(());
+        }
+        // Keep messages in order.
+        self.send_deferred()?;
+        core.send_now(msg, class)
+    }
+
+    /// Send the messages packed by handlers during dispatch.
+    ///
+    /// Connections call this once done dispatching, or it happens with the next message packed.
+    /// A message from a sender retired in the meantime is dropped.
+    fn send_deferred(&self) -> Result<()> {
+        let core = self.connection_core();
+        if core.dispatching.is_current_thread() {
+            return Ok(());
+        }
+        let deferred: Vec<_> = core.deferred.lock()?.drain(..).collect();
+        for (msg, class) in deferred {
+            match core.send_now(msg, class) {
+                Err(VrpnError::SenderRetired(id)) => {
+                    eprintln!(
+                        "Dropping message queued during dispatch from retired sender {}",
+                        id
+                    );
+                }
+                result => result?,
+            }
         }
         Ok(())
     }
@@ -146,6 +440,7 @@ pub trait Connection: Send + Sync {
     ///
     /// Generates the header automatically from the supplied parameters as well as
     /// the MESSAGE_IDENTIFIER constant in the TypedMessageBody implementation.
+    /// If no time is supplied, the connection's `message_clock` is used.
     ///
     /// May not actually send immediately, might need to poll the connection somehow.
     fn pack_message_body<T: TypedMessageBody>(
@@ -155,6 +450,25 @@ pub trait Connection: Send + Sync {
         body: T,
         class: ClassOfService,
     ) -> Result<()>
+    where
+        T: TypedMessageBody + BufferTo,
+    {
+        self.pack_message_body_ref(timeval, sender, &body, class)
+    }
+
+    /// Pack a message body to send to all connected endpoints, buffering it directly from a reference.
+    ///
+    /// Like `pack_message_body()`, but the body need not be cloned or moved,
+    /// which suits sending the same (or mostly the same) data every frame.
+    ///
+    /// May not actually send immediately, might need to poll the connection somehow.
+    fn pack_message_body_ref<T>(
+        &self,
+        timeval: Option<TimeVal>,
+        sender: LocalId<SenderId>,
+        body: &T,
+        class: ClassOfService,
+    ) -> Result<()>
     where
         T: TypedMessageBody + BufferTo,
     {
@@ -162,8 +476,12 @@ pub trait Connection: Send + Sync {
             MessageTypeIdentifier::UserMessageName(name) => self.register_type(name)?,
             MessageTypeIdentifier::SystemMessageId(id) => LocalId(id),
         };
-        let message: TypedMessage<T> = TypedMessage::new(timeval, message_type, sender, body);
-        self.pack_message(message, class)
+        let timeval = timeval.unwrap_or_else(|| self.message_clock().time_of_day());
+        let header = MessageHeader::new(Some(timeval), message_type, sender);
+        self.pack_generic_message(
+            GenericMessage::from_header_and_typed_body(header, body)?,
+            class,
+        )
     }
 
     // /// Pack an ID description (either message type or sender) on all endpoints.
@@ -187,14 +505,74 @@ pub trait Connection: Send + Sync {
     ///
     /// May not actually send immediately, might need to poll the connection somehow.
     fn send_all_descriptions(&self) -> Result<()> {
-        let mut endpoints = self.connection_core().endpoints.lock()?;
-        let dispatcher = self.connection_core().type_dispatcher.lock()?;
+        let (mut endpoints, dispatcher) = self.connection_core().lock_endpoints_and_dispatcher()?;
         for ep in endpoints.iter_mut().flatten() {
             ep.send_all_descriptions(&dispatcher)?;
         }
         Ok(())
     }
 
+    /// Enable or disable per-message latency instrumentation on the receive path.
+    ///
+    /// When enabled, the time from reading a message's bytes until its handlers complete
+    /// is recorded into a histogram, available through `stats()`.
+    fn set_latency_instrumentation(&self, enabled: bool) -> Result<()> {
+        let mut dispatcher = self.connection_core().lock_dispatcher()?;
+        dispatcher.stats_mut().set_latency_instrumentation(enabled);
+        Ok(())
+    }
+
+    /// Get a snapshot of the statistics gathered by this connection,
+    /// including counts of errors by kind.
+    fn stats(&self) -> Result<ConnectionStats> {
+        let dispatcher = self.connection_core().lock_dispatcher()?;
+        Ok(dispatcher.stats().clone())
+    }
+
+    /// When a message of any type was last received from the named sender, or None if never,
+    /// e.g. to show how long a device has been silent with `Instant::elapsed`.
+    ///
+    /// Per message type, see `ConnectionStats::last_received_times`.
+    fn last_received_from<T>(&self, sender: T) -> Result<Option<Instant>>
+    where
+        T: Into<SenderName>,
+    {
+        let dispatcher = self.connection_core().lock_dispatcher()?;
+        Ok(dispatcher
+            .get_sender_id(sender)
+            .and_then(|id| dispatcher.stats().last_received_from(id)))
+    }
+
+    /// Gets a reference-counted handle to the time source used by this connection.
+    fn clock(&self) -> SharedClock {
+        Arc::clone(&self.connection_core().clock)
+    }
+
+    /// Gets the time source used to timestamp outgoing messages packed without a time:
+    /// the one set with `set_message_clock`, or else `clock()`.
+    fn message_clock(&self) -> SharedClock {
+        let core = self.connection_core();
+        match &*core
+            .message_clock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            Some(clock) => Arc::clone(clock),
+            None => Arc::clone(&core.clock),
+        }
+    }
+
+    /// Timestamp outgoing messages packed without a time using a different time source
+    /// than the rest of the connection, or go back to `clock()` with `None`.
+    ///
+    /// For instance, a simulation or playback server sends the simulated or recorded time
+    /// with a `SimulationClock`, while pings and timeouts keep using real time.
+    /// Also applies to the messages sent by the device types in this crate, but not to pings.
+    fn set_message_clock(&self, clock: Option<SharedClock>) -> Result<()> {
+        *self.connection_core().message_clock.lock()? = clock;
+        Ok(())
+    }
+
     /// Gets a reference-counted handle to the mutex-protected endpoint vector.
     fn endpoints(&self) -> SharedEndpointVec<Self::SpecificEndpoint> {
         Arc::clone(&self.connection_core().endpoints)
@@ -213,8 +591,14 @@ where
 {
     pub(crate) endpoints: SharedEndpointVec<EP>,
     pub(crate) type_dispatcher: Arc<Mutex<TypeDispatcher>>,
+    pub(crate) clock: SharedClock,
+    /// Overrides `clock` for timestamping outgoing messages, if set.
+    message_clock: Mutex<Option<SharedClock>>,
     remote_log_names: LogFileNames,
     local_log_names: LogFileNames,
+    dispatching: Arc<DispatchMarker>,
+    /// Messages packed by handlers during dispatch, not yet sent.
+    deferred: Mutex<VecDeque<(GenericMessage, ClassOfService)>>,
 }
 impl<EP> ConnectionCore<EP>
 where
@@ -225,11 +609,363 @@ where
         local_log_names: Option<LogFileNames>,
         remote_log_names: Option<LogFileNames>,
     ) -> ConnectionCore<EP> {
+        ConnectionCore::new_with_clock(
+            endpoints,
+            local_log_names,
+            remote_log_names,
+            SystemClock::shared(),
+        )
+    }
+
+    /// Like `new()`, but with a specific time source, e.g. a `MockClock` in tests.
+    pub fn new_with_clock(
+        endpoints: Vec<Option<EP>>,
+        local_log_names: Option<LogFileNames>,
+        remote_log_names: Option<LogFileNames>,
+        clock: SharedClock,
+    ) -> ConnectionCore<EP> {
+        let dispatcher = TypeDispatcher::new();
         ConnectionCore {
             endpoints: Arc::new(Mutex::new(endpoints)),
-            type_dispatcher: Arc::new(Mutex::new(TypeDispatcher::new())),
+            dispatching: dispatcher.dispatch_marker(),
+            type_dispatcher: Arc::new(Mutex::new(dispatcher)),
+            clock,
+            message_clock: Mutex::new(None),
             remote_log_names: LogFileNames::from(remote_log_names),
             local_log_names: LogFileNames::from(local_log_names),
+            deferred: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Lock the dispatcher, failing rather than deadlocking if called from one of its handlers.
+    pub(crate) fn lock_dispatcher(&self) -> Result<MutexGuard<'_, TypeDispatcher>> {
+        if self.dispatching.is_current_thread() {
+            return Err(VrpnError::CalledDuringDispatch);
+        }
+        Ok(self.type_dispatcher.lock()?)
+    }
+
+    /// Lock the endpoints then the dispatcher, in the order polling takes them,
+    /// failing rather than deadlocking if called from one of the dispatcher's handlers.
+    pub(crate) fn lock_endpoints_and_dispatcher(
+        &self,
+    ) -> Result<(
+        MutexGuard<'_, EndpointVec<EP>>,
+        MutexGuard<'_, TypeDispatcher>,
+    )> {
+        if self.dispatching.is_current_thread() {
+            return Err(VrpnError::CalledDuringDispatch);
+        }
+        let endpoints = self.endpoints.lock()?;
+        Ok((endpoints, self.type_dispatcher.lock()?))
+    }
+
+    /// Queue a message on all endpoints.
+    fn send_now(&self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
+        let sender = LocalId(msg.header.sender);
+        let latest_only = {
+            let dispatcher = self.lock_dispatcher()?;
+            if dispatcher.is_sender_retired(sender) {
+                return Err(VrpnError::SenderRetired(sender.get()));
+            }
+            dispatcher
+                .latest_value_only(LocalId(msg.header.message_type))
+                .is_some()
+        };
+        let mut endpoints = self.endpoints.lock()?;
+        for ep in endpoints.iter_mut().flatten() {
+            // Cheap: the body is reference-counted.
+            if latest_only {
+                ep.buffer_latest_value(msg.clone(), class)?;
+            } else {
+                ep.buffer_generic_message(msg.clone(), class)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "tracker"))]
+mod tests {
+    use super::*;
+    use crate::testing::MockConnection;
+    use crate::{
+        clock::{Clock, MockClock, SimulationClock},
+        data_types::{id_types::Sensor, Quat, StaticMessageTypeName, Vec3},
+        tracker::PoseReport,
+    };
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn pack_borrowed_and_owned() {
+        let clock = MockClock::starting_at(SystemTime::UNIX_EPOCH + Duration::from_secs(1000));
+        let connection = MockConnection::with_clock(clock.shared());
+        let sender = connection.register_sender("Tracker0").unwrap();
+        let report = PoseReport {
+            sensor: Sensor(0),
+            pos: Vec3::new(1.0, 2.0, 3.0),
+            quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+        };
+        connection
+            .pack_message_body_ref(None, sender, &report, ClassOfService::RELIABLE)
+            .unwrap();
+        connection
+            .pack_message_body(None, sender, report.clone(), ClassOfService::RELIABLE)
+            .unwrap();
+        let message_type = connection
+            .register_type(StaticMessageTypeName(b"vrpn_Tracker Pos_Quat"))
+            .unwrap();
+        let msg = TypedMessage::new(Some(clock.time_of_day()), message_type, sender, report);
+        connection
+            .pack_message_ref(&msg, ClassOfService::RELIABLE)
+            .unwrap();
+
+        let sent = connection.sent_user_messages();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[0], sent[1]);
+        assert_eq!(sent[0], sent[2]);
+        assert_eq!(sent[0], GenericMessage::try_from(msg).unwrap());
+    }
+
+    #[test]
+    fn message_clock() {
+        let clock = MockClock::starting_at(SystemTime::UNIX_EPOCH + Duration::from_secs(1000));
+        let connection = MockConnection::with_clock(clock.shared());
+        let sender = connection.register_sender("Tracker0").unwrap();
+        let simulation = SimulationClock::starting_at(SystemTime::UNIX_EPOCH);
+        let pack = || {
+            connection
+                .pack_message_body(None, sender, crate::ping::Ping, ClassOfService::RELIABLE)
+                .unwrap();
+            connection.sent_user_messages().pop().unwrap().header.time
+        };
+        assert_eq!(pack(), clock.time_of_day());
+
+        connection
+            .set_message_clock(Some(simulation.shared()))
+            .unwrap();
+        assert_eq!(pack(), TimeVal::default());
+        simulation.advance(Duration::from_secs(5));
+        assert_eq!(
+            pack(),
+            TimeVal::from(SystemTime::UNIX_EPOCH + Duration::from_secs(5))
+        );
+        // Only message times follow it.
+        assert_eq!(connection.clock().time_of_day(), clock.time_of_day());
+
+        connection.set_message_clock(None).unwrap();
+        assert_eq!(pack(), clock.time_of_day());
+    }
+
+    #[test]
+    fn register_all() {
+        use crate::type_dispatcher::TryIntoDescriptionMessage;
+        let connection = MockConnection::new();
+        let tracker0 = connection.register_sender("Tracker0").unwrap();
+        connection.clear_sent();
+
+        let names = IdAssignment::new()
+            .with_sender("Tracker1")
+            .with_sender("Tracker0")
+            .with_message_type("vrpn_Tracker Pos_Quat");
+        let ids = connection.register_all(&names).unwrap();
+        assert_eq!(ids.senders.len(), 2);
+        assert_eq!(ids.senders[1], tracker0);
+        assert_eq!(
+            ids.message_types,
+            vec![connection
+                .register_type(StaticMessageTypeName(b"vrpn_Tracker Pos_Quat"))
+                .unwrap()]
+        );
+        // Only the new names are described, in order.
+        let expected = [
+            ids.senders[0].try_into_description_message(&b"Tracker1"[..]),
+            ids.message_types[0].try_into_description_message(&b"vrpn_Tracker Pos_Quat"[..]),
+        ];
+        let sent = connection.sent_messages();
+        assert_eq!(sent.len(), expected.len());
+        for (sent, expected) in sent.iter().zip(expected) {
+            let expected = expected.unwrap();
+            assert_eq!(sent.header.message_type, expected.header.message_type);
+            assert_eq!(sent.header.sender, expected.header.sender);
+            assert_eq!(sent.body, expected.body);
+        }
+        assert_eq!(connection.register_all(&names).unwrap(), ids);
+
+        // All or nothing.
+        connection.clear_sent();
+        connection
+            .set_duplicate_name_policy(DuplicateNamePolicy::Error)
+            .unwrap();
+        let names = IdAssignment::new()
+            .with_sender("Tracker2")
+            .with_sender("Tracker0");
+        assert!(matches!(
+            connection.register_all(&names),
+            Err(VrpnError::AlreadyRegistered(_))
+        ));
+        let twice = IdAssignment::new()
+            .with_sender("Tracker2")
+            .with_sender("Tracker2");
+        assert!(connection.register_all(&twice).is_err());
+        assert_eq!(
+            connection
+                .dispatcher()
+                .lock()
+                .unwrap()
+                .get_sender_id("Tracker2"),
+            None
+        );
+        assert!(connection.sent_messages().is_empty());
+    }
+
+    #[test]
+    fn describe_names_first_described_remotely() {
+        use crate::{
+            data_types::Description,
+            endpoint::{handle_system_command, SystemCommand},
+            type_dispatcher::TryIntoDescriptionMessage,
+        };
+        let connection = MockConnection::new();
+        {
+            let endpoints = connection.endpoints();
+            let mut endpoints = endpoints.lock().unwrap();
+            let endpoint = endpoints[0].as_mut().unwrap();
+            let dispatcher = connection.dispatcher();
+            let mut dispatcher = dispatcher.lock().unwrap();
+            handle_system_command(
+                &mut dispatcher,
+                endpoint.translation_tables_mut(),
+                SystemCommand::TypeDescription(Description::from_id_and_name(
+                    MessageTypeId(9),
+                    bytes::Bytes::from_static(b"vrpn_Tracker Pos_Quat"),
+                )),
+            )
+            .unwrap();
+        }
+        connection.clear_sent();
+
+        // The remote end knows its own ID for the type, but not ours yet.
+        let name = StaticMessageTypeName(b"vrpn_Tracker Pos_Quat");
+        let id = connection.register_type(name.clone()).unwrap();
+        let expected = id
+            .try_into_description_message(&b"vrpn_Tracker Pos_Quat"[..])
+            .unwrap();
+        let sent = connection.sent_messages();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].header.sender, expected.header.sender);
+        assert_eq!(sent[0].body, expected.body);
+        connection.clear_sent();
+        connection.register_type(name).unwrap();
+        assert!(connection.sent_messages().is_empty());
+    }
+
+    #[test]
+    fn last_received() {
+        let connection = MockConnection::new();
+        let tracker = connection.register_sender("Tracker0").unwrap();
+        let other = connection.register_sender("Tracker1").unwrap();
+        let pose = connection
+            .register_type(StaticMessageTypeName(b"vrpn_Tracker Pos_Quat"))
+            .unwrap();
+        let ping = connection.register_type(crate::ping::PING_MESSAGE).unwrap();
+        assert_eq!(connection.last_received_from("Tracker0").unwrap(), None);
+        assert_eq!(connection.last_received_from("Unknown").unwrap(), None);
+
+        let before = Instant::now();
+        let report = PoseReport {
+            sensor: Sensor(0),
+            pos: Vec3::new(0.0, 0.0, 0.0),
+            quat: Quat::identity(),
+        };
+        connection
+            .receive(TypedMessage::new(None, pose, tracker, report))
+            .unwrap();
+        connection
+            .receive(TypedMessage::new(None, ping, tracker, crate::ping::Ping))
+            .unwrap();
+        let stats = connection.stats().unwrap();
+        let at_pose = stats.last_received(tracker, pose).unwrap();
+        let at_ping = stats.last_received(tracker, ping).unwrap();
+        assert!(before <= at_pose && at_pose <= at_ping);
+        assert_eq!(stats.last_received(other, pose), None);
+        assert_eq!(
+            connection.last_received_from("Tracker0").unwrap(),
+            Some(at_ping)
+        );
+        assert_eq!(connection.last_received_from("Tracker1").unwrap(), None);
+        let pairs: Vec<_> = stats
+            .last_received_times()
+            .map(|(sender, message_type, _)| (sender, message_type))
+            .collect();
+        let mut expected = vec![(tracker, pose), (tracker, ping)];
+        expected.sort();
+        assert_eq!(pairs, expected);
+    }
+
+    /// Sends every message it gets back out, then tries (and fails) to register a name.
+    #[derive(Debug)]
+    struct Echo {
+        connection: std::sync::Weak<MockConnection>,
+        register_result: Arc<Mutex<Option<Result<()>>>>,
+    }
+
+    impl Handler for Echo {
+        fn handle(&mut self, msg: &GenericMessage) -> Result<crate::handler::HandlerCode> {
+            let connection = self
+                .connection
+                .upgrade()
+                .ok_or(VrpnError::GenericErrorReturn)?;
+            connection.pack_generic_message(msg.clone(), ClassOfService::RELIABLE)?;
+            *self.register_result.lock()? = Some(connection.register_sender("Other").map(|_| ()));
+            Ok(crate::handler::HandlerCode::ContinueProcessing)
+        }
+    }
+
+    #[test]
+    fn handler_sends_during_dispatch() {
+        let connection = MockConnection::new();
+        let sender = connection.register_sender("Tracker0").unwrap();
+        let message_type = connection
+            .register_type(StaticMessageTypeName(b"vrpn_Tracker Pos_Quat"))
+            .unwrap();
+        let register_result = Arc::new(Mutex::new(None));
+        connection
+            .add_handler(
+                Box::new(Echo {
+                    connection: Arc::downgrade(&connection),
+                    register_result: Arc::clone(&register_result),
+                }),
+                Some(message_type),
+                None,
+            )
+            .unwrap();
+
+        let reports: Vec<_> = (0..3)
+            .map(|i| {
+                TypedMessage::new(
+                    Some(TimeVal::default()),
+                    message_type,
+                    sender,
+                    PoseReport {
+                        sensor: Sensor(i),
+                        pos: Vec3::default(),
+                        quat: Quat::identity(),
+                    },
+                )
+            })
+            .collect();
+        for report in &reports {
+            connection.receive(report.clone()).unwrap();
         }
+        let expected: Vec<_> = reports
+            .iter()
+            .map(|report| GenericMessage::try_from(report).unwrap())
+            .collect();
+        assert_eq!(connection.sent_user_messages(), expected);
+        assert!(matches!(
+            *register_result.lock().unwrap(),
+            Some(Err(VrpnError::CalledDuringDispatch))
+        ));
     }
 }
@@ -6,7 +6,9 @@
 //!
 //! Constants in this file must remain unchanged so that they match the C++ implementation.
 
-use crate::buffer_unbuffer::constants::ALIGN;
+use std::convert::TryFrom;
+
+use crate::{buffer_unbuffer::constants::ALIGN, VrpnError};
 
 use super::{MessageTypeId, StaticMessageTypeName, StaticSenderName, Version};
 
@@ -21,11 +23,114 @@ pub const DROPPED_LAST_CONNECTION: StaticMessageTypeName =
 
 pub const CONTROL: StaticSenderName = StaticSenderName(b"VRPN Control");
 
-pub const SENDER_DESCRIPTION: MessageTypeId = MessageTypeId(-1);
-pub const TYPE_DESCRIPTION: MessageTypeId = MessageTypeId(-2);
-pub const UDP_DESCRIPTION: MessageTypeId = MessageTypeId(-3);
-pub const LOG_DESCRIPTION: MessageTypeId = MessageTypeId(-4);
-pub const DISCONNECT_MESSAGE: MessageTypeId = MessageTypeId(-5);
+/// The system messages, which have fixed (negative) message type IDs rather than names.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum SystemMessageType {
+    SenderDescription = -1,
+    TypeDescription = -2,
+    UdpDescription = -3,
+    LogDescription = -4,
+    DisconnectMessage = -5,
+}
+
+impl SystemMessageType {
+    /// All the system message types, in order of ID from -1 down.
+    pub const ALL: [SystemMessageType; 5] = [
+        SystemMessageType::SenderDescription,
+        SystemMessageType::TypeDescription,
+        SystemMessageType::UdpDescription,
+        SystemMessageType::LogDescription,
+        SystemMessageType::DisconnectMessage,
+    ];
+
+    /// The message type ID for this system message.
+    pub const fn id(self) -> MessageTypeId {
+        MessageTypeId(self as i32)
+    }
+}
+
+impl From<SystemMessageType> for MessageTypeId {
+    fn from(val: SystemMessageType) -> MessageTypeId {
+        val.id()
+    }
+}
+
+impl TryFrom<MessageTypeId> for SystemMessageType {
+    type Error = VrpnError;
+
+    /// Fails with `VrpnError::UnrecognizedSystemMessage` for IDs of no known system message,
+    /// including all user message IDs.
+    fn try_from(id: MessageTypeId) -> Result<SystemMessageType, VrpnError> {
+        SystemMessageType::ALL
+            .iter()
+            .copied()
+            .find(|t| t.id() == id)
+            .ok_or(VrpnError::UnrecognizedSystemMessage(id.0))
+    }
+}
+
+/// A system message type reserved for extensions between peers that both use this crate.
+///
+/// IDs -1000 down to -1999 are set aside for these. The remote end hands them unchanged
+/// to the handlers added for their ID, with no sender or type mapping,
+/// so they suit things like negotiating compression or exchanging metadata.
+/// Give a message body `MessageTypeIdentifier::SystemMessageId(...id())` as its identifier
+/// to pack and handle it like any other typed message.
+/// The C++ implementation knows none of them: only send them to peers known to expect them.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct UserSystemMessageType(u16);
+
+impl UserSystemMessageType {
+    /// The number of reserved IDs.
+    pub const COUNT: u16 = 1000;
+
+    const FIRST_ID: i32 = -1000;
+
+    /// The reserved message type with this index, counting from 0.
+    ///
+    /// Panics if the index isn't below `COUNT`: at compile time, when used for a constant.
+    pub const fn new(index: u16) -> UserSystemMessageType {
+        assert!(
+            index < UserSystemMessageType::COUNT,
+            "user system message index out of range"
+        );
+        UserSystemMessageType(index)
+    }
+
+    pub const fn index(self) -> u16 {
+        self.0
+    }
+
+    /// The message type ID for this system message.
+    pub const fn id(self) -> MessageTypeId {
+        MessageTypeId(UserSystemMessageType::FIRST_ID - self.0 as i32)
+    }
+}
+
+impl From<UserSystemMessageType> for MessageTypeId {
+    fn from(val: UserSystemMessageType) -> MessageTypeId {
+        val.id()
+    }
+}
+
+impl TryFrom<MessageTypeId> for UserSystemMessageType {
+    type Error = VrpnError;
+
+    /// Fails with `VrpnError::UnrecognizedSystemMessage` for IDs outside the reserved range.
+    fn try_from(id: MessageTypeId) -> Result<UserSystemMessageType, VrpnError> {
+        u16::try_from(UserSystemMessageType::FIRST_ID - id.0)
+            .ok()
+            .filter(|&index| index < UserSystemMessageType::COUNT)
+            .map(UserSystemMessageType)
+            .ok_or(VrpnError::UnrecognizedSystemMessage(id.0))
+    }
+}
+
+pub const SENDER_DESCRIPTION: MessageTypeId = SystemMessageType::SenderDescription.id();
+pub const TYPE_DESCRIPTION: MessageTypeId = SystemMessageType::TypeDescription.id();
+pub const UDP_DESCRIPTION: MessageTypeId = SystemMessageType::UdpDescription.id();
+pub const LOG_DESCRIPTION: MessageTypeId = SystemMessageType::LogDescription.id();
+pub const DISCONNECT_MESSAGE: MessageTypeId = SystemMessageType::DisconnectMessage.id();
 
 // Based on vrpn_MAGIC_DATA
 pub const MAGIC_DATA: Version = Version {
@@ -51,4 +156,31 @@ mod tests {
         assert_eq!(MAGICLEN % ALIGN, 0);
         assert_eq!(COOKIE_SIZE % ALIGN, 0);
     }
+
+    #[test]
+    fn system_message_ids() {
+        for t in SystemMessageType::ALL {
+            assert!(t.id().is_system_message());
+            assert_eq!(SystemMessageType::try_from(t.id()).unwrap(), t);
+        }
+        assert_eq!(LOG_DESCRIPTION, MessageTypeId(-4));
+        assert!(SystemMessageType::try_from(MessageTypeId(-6)).is_err());
+        assert!(SystemMessageType::try_from(MessageTypeId(0)).is_err());
+    }
+
+    #[test]
+    fn user_system_message_ids() {
+        let first = UserSystemMessageType::new(0);
+        let last = UserSystemMessageType::new(UserSystemMessageType::COUNT - 1);
+        assert_eq!(first.id(), MessageTypeId(-1000));
+        assert_eq!(last.id(), MessageTypeId(-1999));
+        for t in [first, last] {
+            assert!(t.id().is_system_message());
+            assert!(SystemMessageType::try_from(t.id()).is_err());
+            assert_eq!(UserSystemMessageType::try_from(t.id()).unwrap(), t);
+        }
+        for id in [-999, -2000, -1, 0, 1000] {
+            assert!(UserSystemMessageType::try_from(MessageTypeId(id)).is_err());
+        }
+    }
 }
@@ -9,7 +9,10 @@ use crate::buffer_unbuffer::{
 
 use super::{constants, LogMode};
 use bytes::{Buf, BufMut};
-use std::fmt::{self, Display, Formatter};
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
 
 const COOKIE_PADDING: &[u8] = b"\0\0\0\0\0";
 
@@ -32,6 +35,15 @@ impl Default for Version {
     }
 }
 
+/// The "magic cookie" exchanged at the start of a connection or log file.
+///
+/// # Wire format
+///
+/// ASCII version string and log mode, null-padded. For example, `CookieData::make_cookie()`:
+///
+/// ```text
+#[doc = include_str!("../../fixtures/wire/cookie.hex")]
+/// ```
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct CookieData {
     pub version: Version,
@@ -78,47 +90,58 @@ impl ConstantBufferSize for CookieData {
     }
 }
 
+// The cookie is only exchanged once per connection,
+// so its (un)buffering is not generic over the buffer type.
+
 impl BufferTo for CookieData {
     fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
-        check_buffer_remaining(buf, Self::constant_buffer_size())?;
-        buf.put(self.to_string().as_bytes());
-        buf.put(COOKIE_PADDING);
-        Ok(())
+        buffer_cookie(self, buf)
     }
 }
 
+fn buffer_cookie(cookie: &CookieData, buf: &mut dyn BufMut) -> BufferResult {
+    check_buffer_remaining(buf, CookieData::constant_buffer_size())?;
+    buf.put_slice(cookie.to_string().as_bytes());
+    buf.put_slice(COOKIE_PADDING);
+    Ok(())
+}
+
 fn u8_to_log_mode(v: u8) -> LogMode {
     LogMode::from_bits_truncate(v)
 }
 
 impl UnbufferFrom for CookieData {
     fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
-        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        unbuffer_cookie(buf)
+    }
+}
 
-        // remove "vrpn: ver. "
-        consume_expected(buf, constants::MAGIC_PREFIX)?;
+fn unbuffer_cookie(buf: &mut dyn Buf) -> UnbufferResult<CookieData> {
+    check_unbuffer_remaining(buf, CookieData::constant_buffer_size())?;
 
-        let major: u8 = unbuffer_decimal_digits(buf, 2)?;
+    // remove "vrpn: ver. "
+    consume_expected(buf, constants::MAGIC_PREFIX)?;
 
-        // remove dot
-        consume_expected(buf, b".")?;
+    let major: u8 = unbuffer_decimal_digits(buf, 2)?;
 
-        let minor: u8 = unbuffer_decimal_digits(buf, 2)?;
+    // remove dot
+    consume_expected(buf, b".")?;
 
-        // remove spaces
-        consume_expected(buf, b"  ")?;
+    let minor: u8 = unbuffer_decimal_digits(buf, 2)?;
 
-        let log_mode: u8 = unbuffer_decimal_digits(buf, 1)?;
-        let log_mode = u8_to_log_mode(log_mode);
+    // remove spaces
+    consume_expected(buf, b"  ")?;
 
-        // remove padding
-        consume_expected(buf, COOKIE_PADDING)?;
+    let log_mode: u8 = unbuffer_decimal_digits(buf, 1)?;
+    let log_mode = u8_to_log_mode(log_mode);
 
-        Ok(CookieData {
-            version: Version { major, minor },
-            log_mode: Some(log_mode),
-        })
-    }
+    // remove padding
+    consume_expected(buf, COOKIE_PADDING)?;
+
+    Ok(CookieData {
+        version: Version { major, minor },
+        log_mode: Some(log_mode),
+    })
 }
 
 impl From<CookieData> for Version {
@@ -161,6 +184,72 @@ impl Display for CookieData {
     }
 }
 
+/// Error from parsing the text form of a `Version` or `CookieData`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseCookieError {
+    text: String,
+}
+
+impl ParseCookieError {
+    fn new(text: &str) -> ParseCookieError {
+        ParseCookieError {
+            text: text.to_string(),
+        }
+    }
+}
+
+impl Display for ParseCookieError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "could not parse VRPN cookie text {:?}", self.text)
+    }
+}
+
+impl std::error::Error for ParseCookieError {}
+
+fn parse_decimal<T: FromStr>(s: &str) -> Option<T> {
+    if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+impl FromStr for Version {
+    type Err = ParseCookieError;
+
+    /// Parse the `major.minor` form written by `Display`, e.g. `07.35`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (major, minor) = s.split_once('.').ok_or_else(|| ParseCookieError::new(s))?;
+        Ok(Version {
+            major: parse_decimal(major).ok_or_else(|| ParseCookieError::new(s))?,
+            minor: parse_decimal(minor).ok_or_else(|| ParseCookieError::new(s))?,
+        })
+    }
+}
+
+impl FromStr for CookieData {
+    type Err = ParseCookieError;
+
+    /// Parse the cookie text written by `Display`, e.g. `vrpn: ver. 07.35  0`.
+    ///
+    /// Trailing null padding, as found on the wire, is accepted and ignored.
+    /// Unlike unbuffering, unknown log mode bits are an error.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseCookieError::new(s);
+        let text = s.trim_end_matches('\0');
+        let prefix = std::str::from_utf8(constants::MAGIC_PREFIX).map_err(|_| err())?;
+        let rest = text.strip_prefix(prefix).ok_or_else(err)?;
+        let (version, log_mode) = rest.split_once("  ").ok_or_else(err)?;
+        let version = version.parse().map_err(|_| err())?;
+        let log_mode = parse_decimal(log_mode)
+            .and_then(LogMode::from_bits)
+            .ok_or_else(err)?;
+        Ok(CookieData {
+            version,
+            log_mode: Some(log_mode),
+        })
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct VersionMismatch {
     actual: Version,
@@ -204,6 +293,7 @@ pub fn check_ver_file_compatible(ver: Version) -> Result<(), VersionMismatch> {
 #[cfg(test)]
 mod tests {
     use bytes::BytesMut;
+    use proptest::prelude::*;
 
     use crate::buffer_unbuffer::BytesMutExtras;
 
@@ -271,4 +361,49 @@ mod tests {
         assert_eq!(CookieData::unbuffer_from(&mut buf).unwrap(), magic_cookie);
         assert_eq!(buf.len(), 0);
     }
+
+    #[test]
+    fn parsing() {
+        assert_eq!("07.35".parse::<Version>().unwrap(), constants::MAGIC_DATA);
+        let mut cookie = CookieData::make_cookie();
+        cookie.log_mode = Some(LogMode::NONE);
+        assert_eq!("vrpn: ver. 07.35  0".parse::<CookieData>().unwrap(), cookie);
+        assert_eq!(
+            "vrpn: ver. 07.35  0\0\0\0\0\0"
+                .parse::<CookieData>()
+                .unwrap(),
+            cookie
+        );
+
+        // The parsed text matches what goes on the wire.
+        let buf = BytesMut::allocate_and_buffer(cookie).unwrap();
+        let wire = std::str::from_utf8(&buf).unwrap();
+        assert_eq!(wire.parse::<CookieData>().unwrap(), cookie);
+
+        for bad in &[
+            "",
+            "vrpn: ver. 07.35",
+            "vrpn: ver. 07.35 0",
+            "vrpn: ver. 07.35  4",
+            "vrpn: ver. 07:35  0",
+            "vrpn: ver. 07.+5  0",
+            "vrpn ver. 07.35  0",
+        ] {
+            assert!(bad.parse::<CookieData>().is_err(), "{:?}", bad);
+        }
+        assert!("7".parse::<Version>().is_err());
+        assert!("300.1".parse::<Version>().is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn display_parse_roundtrip(major in any::<u8>(), minor in any::<u8>(), mode in 0u8..4) {
+            let cookie = CookieData {
+                version: Version { major, minor },
+                log_mode: Some(LogMode::from_bits_truncate(mode)),
+            };
+            prop_assert_eq!(cookie.to_string().parse::<CookieData>().unwrap(), cookie);
+            prop_assert_eq!(cookie.version.to_string().parse::<Version>().unwrap(), cookie.version);
+        }
+    }
 }
@@ -5,13 +5,13 @@
 use bytes::{Buf, BufMut, Bytes};
 
 use std::{
-    io::BufRead,
     marker::PhantomData,
     net::{IpAddr, SocketAddr},
 };
 
 use crate::buffer_unbuffer::{
-    check_buffer_remaining, BufferResult, BufferSize, BufferTo, UnbufferFrom, UnbufferResult,
+    check_buffer_remaining, check_unbuffer_remaining, size_requirement::ExpandSizeRequirement,
+    BufferResult, BufferSize, BufferTo, UnbufferFrom, UnbufferResult,
 };
 
 use super::{
@@ -54,6 +54,16 @@ impl<I: IdWithNameAndDescription> From<TypedMessage<InnerDescription<I>>> for De
 /// Typed description of a sender or type.
 ///
 /// Converted to a Message<InnerDescription> before being sent.
+///
+/// # Wire format
+///
+/// The ID goes in the sender field of the header, and the body is the length-prefixed,
+/// null-terminated name. For example, describing sender 0 as "Tracker0",
+/// as a complete message at time 1000s:
+///
+/// ```text
+#[doc = include_str!("../../fixtures/wire/description.hex")]
+/// ```
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct Description<T> {
     /// The ID
@@ -170,12 +180,16 @@ impl<I: IdWithNameAndDescription> UnbufferFrom for InnerDescription<I> {
 impl UnbufferFrom for UdpInnerDescription {
     fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
         let mut ip_buf: Vec<u8> = Vec::default();
-        // ok to unwrap: a buf reader is infallible. The reader also prevents us from modifying the buffer state.
-        let length = buf.reader().read_until(0, &mut ip_buf).unwrap();
-        // let ip_buf: Vec<u8> = buf.into_iter().take_while(|b| **b != 0).cloned().collect();
+        loop {
+            check_unbuffer_remaining(buf, 1)
+                .map_err(ExpandSizeRequirement::expand_size_requirement)?;
+            match buf.get_u8() {
+                0 => break,
+                b => ip_buf.push(b),
+            }
+        }
         let ip_str = String::from_utf8_lossy(&ip_buf);
         let addr: IpAddr = ip_str.parse()?;
-        buf.advance(length);
 
         Ok(UdpInnerDescription::new(addr))
     }
@@ -196,3 +210,27 @@ impl BufferTo for UdpInnerDescription {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn udp_description() {
+        let mut buf = Bytes::from_static(b"127.0.0.1\0trailing");
+        let desc = UdpInnerDescription::unbuffer_from(&mut buf).unwrap();
+        assert_eq!(desc.address, IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(&buf[..], &b"trailing"[..]);
+
+        // Missing null terminator
+        let mut buf = Bytes::from_static(b"127.0.0.1");
+        assert!(UdpInnerDescription::unbuffer_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn zero_length_description() {
+        // The length is supposed to include the null terminator, so can't be zero.
+        let mut buf = Bytes::from_static(&hex!("00 00 00 00 00"));
+        assert!(InnerDescription::<SenderId>::unbuffer_from(&mut buf).is_err());
+    }
+}
@@ -3,12 +3,13 @@
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
 use bytes::{Buf, BufMut, Bytes};
-use std::mem::size_of;
+use std::{convert::TryFrom, mem::size_of};
 
 use crate::buffer_unbuffer::{
     buffer::{self, BufferTo},
     size_requirement::*,
     unbuffer::{self, UnbufferFrom},
+    BufferUnbufferError,
 };
 
 /// Does the "length prefix" value include a trailing null character (strlen() + 1)?
@@ -37,44 +38,80 @@ pub fn buffer_size(s: &[u8], termination: NullTermination) -> usize {
 }
 
 /// Buffer a string, preceded by its length and followed by a null bytes.
-pub fn buffer_string<T: BufMut>(
+///
+/// Takes a `dyn BufMut`, as names are only buffered when described: not worth a copy per buffer type.
+pub fn buffer_string(
     s: &[u8],
-    buf: &mut T,
+    mut buf: &mut dyn BufMut,
     termination: NullTermination,
     null_in_len: LengthBehavior,
 ) -> buffer::BufferResult {
-    let mut buf_size = buffer_size(s, termination);
-
-    buffer::check_buffer_remaining(buf, buf_size)?;
+    buffer::check_buffer_remaining(buf, buffer_size(s, termination))?;
+    // The transmitted length covers the string and maybe its null terminator, not the length field itself.
+    let mut len = buffer_size(s, termination) - size_of::<u32>();
     if termination == NullTermination::AddTrailingNull && null_in_len == LengthBehavior::ExcludeNull
     {
         // Decrement the length that we transmit if we're adding a null terminator but not including it in the length.
-        buf_size -= 1;
+        len -= 1;
     }
-    let buf_size = buf_size as u32;
-    buf_size.buffer_to(buf)?;
+    let len = u32::try_from(len).map_err(|_| BufferUnbufferError::OutOfBuffer)?;
+    len.buffer_to(&mut buf)?;
 
-    buf.put(s);
-    buf.put_u8(0);
+    buf.put_slice(s);
+    if termination == NullTermination::AddTrailingNull {
+        buf.put_u8(0);
+    }
     Ok(())
 }
 
 /// Unbuffer a string, preceded by its length and followed by a null bytes.
-pub fn unbuffer_string<T: Buf>(buf: &mut T) -> unbuffer::UnbufferResult<Bytes> {
+pub fn unbuffer_string(mut buf: &mut dyn Buf) -> unbuffer::UnbufferResult<Bytes> {
     let buf_size =
-        u32::unbuffer_from(buf).map_err(ExpandSizeRequirement::expand_size_requirement)?;
+        u32::unbuffer_from(&mut buf).map_err(ExpandSizeRequirement::expand_size_requirement)?;
 
     let buf_size = buf_size as usize;
     unbuffer::check_unbuffer_remaining(buf, buf_size)?;
-    assert_ne!(
-        buf_size, 0,
-        "length-prefixed string size is expected to be greater than 0"
-    );
     // Subtract null-terminator from length we want.
-    let buf_size = buf_size - 1;
+    let buf_size = buf_size
+        .checked_sub(1)
+        .ok_or_else(|| BufferUnbufferError::ParseError {
+            parsing_kind: "length-prefixed string".to_string(),
+            s: "length must include the null terminator, but was 0".to_string(),
+        })?;
 
     let s = buf.copy_to_bytes(buf_size);
     // Grab null terminator
     unbuffer::consume_expected(buf, b"\0")?;
     Ok(s)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    #[test]
+    fn roundtrip() {
+        let mut buf = BytesMut::new();
+        buffer_string(
+            b"Tracker0",
+            &mut buf,
+            NullTermination::AddTrailingNull,
+            LengthBehavior::IncludeNull,
+        )
+        .unwrap();
+        assert_eq!(
+            buf.len(),
+            buffer_size(b"Tracker0", NullTermination::AddTrailingNull)
+        );
+        // Length is strlen + 1, as in the C++ implementation.
+        assert_eq!(
+            &buf[..],
+            &hex!("00 00 00 09 54 72 61 63 6b 65 72 30 00")[..]
+        );
+        assert_eq!(
+            unbuffer_string(&mut buf.freeze()).unwrap(),
+            Bytes::from_static(b"Tracker0")
+        );
+    }
+}
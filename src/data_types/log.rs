@@ -173,7 +173,9 @@ impl TypedMessageBody for LogFileNames {
         MessageTypeIdentifier::SystemMessageId(constants::LOG_DESCRIPTION);
 }
 
-fn unbuffer_logname<T: Buf>(len: usize, buf: &mut T) -> unbuffer::UnbufferResult<Option<Bytes>> {
+fn unbuffer_logname(len: usize, buf: &mut dyn Buf) -> unbuffer::UnbufferResult<Option<Bytes>> {
+    // Name plus null terminator
+    unbuffer::check_unbuffer_remaining(buf, len + 1)?;
     let name = if len > 0 {
         Some(buf.copy_to_bytes(len))
     } else {
@@ -205,6 +207,7 @@ impl unbuffer::UnbufferFrom for LogFileNames {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::buffer_unbuffer::UnbufferFrom;
     #[test]
     fn log_names() {
         // turbofish required here because None doesn't suggest a type for Some
@@ -232,4 +235,11 @@ mod tests {
             LogMode::INCOMING_OUTGOING
         );
     }
+
+    #[test]
+    fn truncated_names() {
+        // Claims a 100-byte incoming name, but the buffer is much shorter.
+        let mut buf = Bytes::from_static(&hex!("00 00 00 64 00 00 00 00 61 00"));
+        assert!(LogFileNames::unbuffer_from(&mut buf).is_err());
+    }
 }
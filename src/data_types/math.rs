@@ -6,6 +6,7 @@
 
 use crate::buffer_unbuffer::{buffer, unbuffer, ConstantBufferSize};
 use bytes::{Buf, BufMut};
+use std::ops::Mul;
 
 /// A 3D vector of 64-bit floats
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -18,6 +19,26 @@ impl Vec3 {
     pub fn new(x: f64, y: f64, z: f64) -> Self {
         Vec3 { x, y, z }
     }
+
+    fn dot(&self, other: &Vec3) -> f64 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    fn cross(&self, other: &Vec3) -> Vec3 {
+        Vec3::new(
+            self.y * other.z - self.z * other.y,
+            self.z * other.x - self.x * other.z,
+            self.x * other.y - self.y * other.x,
+        )
+    }
+
+    fn scaled(&self, factor: f64) -> Vec3 {
+        Vec3::new(self.x * factor, self.y * factor, self.z * factor)
+    }
+
+    fn plus(&self, other: &Vec3) -> Vec3 {
+        Vec3::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
 }
 
 impl Default for Vec3 {
@@ -84,6 +105,65 @@ impl Quat {
             v: Vec3::new(0.0, 0.0, 0.0),
         }
     }
+
+    /// Dot product of the coefficients: for unit quaternions, the cosine of half the angle between.
+    pub fn dot(&self, other: &Quat) -> f64 {
+        self.s * other.s + self.v.dot(&other.v)
+    }
+
+    pub fn norm_squared(&self) -> f64 {
+        self.dot(self)
+    }
+
+    pub fn norm(&self) -> f64 {
+        self.norm_squared().sqrt()
+    }
+
+    /// Scale to unit length.
+    ///
+    /// Returns None if the norm is zero or not finite, so there is no rotation to recover.
+    pub fn normalize(&self) -> Option<Quat> {
+        let norm = self.norm();
+        if norm.is_normal() {
+            Some(Quat::from_sv(self.s / norm, self.v.scaled(1.0 / norm)))
+        } else {
+            None
+        }
+    }
+
+    /// Is this within `epsilon` of unit length (in squared norm)?
+    ///
+    /// False for any non-finite coefficient.
+    pub fn is_normalized(&self, epsilon: f64) -> bool {
+        (self.norm_squared() - 1.0).abs() <= epsilon
+    }
+
+    /// The conjugate: for a unit quaternion, the inverse rotation.
+    pub fn conjugate(&self) -> Quat {
+        Quat::from_sv(self.s, self.v.scaled(-1.0))
+    }
+
+    /// Rotate a vector by this (unit) quaternion.
+    pub fn rotate(&self, v: Vec3) -> Vec3 {
+        // v + 2s(u × v) + 2u × (u × v), with u the vector part.
+        let t = self.v.cross(&v).scaled(2.0);
+        v.plus(&t.scaled(self.s)).plus(&self.v.cross(&t))
+    }
+}
+
+impl Mul for Quat {
+    type Output = Quat;
+
+    /// Hamilton product: the rotation `rhs` followed by `self`.
+    fn mul(self, rhs: Quat) -> Quat {
+        Quat::from_sv(
+            self.s * rhs.s - self.v.dot(&rhs.v),
+            rhs.v
+                .scaled(self.s)
+                .plus(&self.v.scaled(rhs.s))
+                .plus(&self.v.cross(&rhs.v)),
+        )
+    }
 }
 
 impl ConstantBufferSize for Quat {
@@ -109,3 +189,73 @@ impl unbuffer::UnbufferFrom for Quat {
         Ok(Quat::from_sv(w, v))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f64 = 1e-12;
+
+    fn assert_vec3_near(a: Vec3, b: Vec3) {
+        assert!(
+            (a.x - b.x).abs() < EPSILON
+                && (a.y - b.y).abs() < EPSILON
+                && (a.z - b.z).abs() < EPSILON,
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    /// Rotation by `angle` radians about the z axis.
+    fn about_z(angle: f64) -> Quat {
+        Quat::new((angle / 2.0).cos(), 0.0, 0.0, (angle / 2.0).sin())
+    }
+
+    #[test]
+    fn normalize() {
+        let q = Quat::new(2.0, 0.0, 0.0, 0.0);
+        assert!(!q.is_normalized(1e-6));
+        assert_eq!(q.norm(), 2.0);
+        let unit = q.normalize().unwrap();
+        assert_eq!(unit, Quat::identity());
+        assert!(unit.is_normalized(1e-6));
+
+        assert!(Quat::new(0.0, 0.0, 0.0, 0.0).normalize().is_none());
+        assert!(Quat::new(f64::NAN, 0.0, 0.0, 1.0).normalize().is_none());
+        assert!(!Quat::new(f64::NAN, 0.0, 0.0, 1.0).is_normalized(1e-6));
+    }
+
+    #[test]
+    fn rotation() {
+        let quarter = about_z(std::f64::consts::FRAC_PI_2);
+        assert_vec3_near(
+            quarter.rotate(Vec3::new(1.0, 0.0, 0.0)),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        assert_vec3_near(
+            quarter.conjugate().rotate(Vec3::new(1.0, 0.0, 0.0)),
+            Vec3::new(0.0, -1.0, 0.0),
+        );
+
+        // Composing two quarter turns is a half turn.
+        let half = quarter * quarter;
+        assert_vec3_near(
+            half.rotate(Vec3::new(1.0, 2.0, 3.0)),
+            Vec3::new(-1.0, -2.0, 3.0),
+        );
+        let identity = quarter * quarter.conjugate();
+        assert!((identity.s - 1.0).abs() < EPSILON);
+        assert_vec3_near(identity.v, Vec3::default());
+
+        // The right-hand side is applied first.
+        let tilt = Quat::new(
+            std::f64::consts::FRAC_PI_4.cos(),
+            std::f64::consts::FRAC_PI_4.sin(),
+            0.0,
+            0.0,
+        );
+        let v = Vec3::new(0.0, 1.0, 0.0);
+        assert_vec3_near((quarter * tilt).rotate(v), quarter.rotate(tilt.rotate(v)));
+    }
+}
@@ -10,6 +10,9 @@ use std::convert::TryFrom;
 use crate::{
     buffer_unbuffer::{
         buffer::{self},
+        compute_padding,
+        constants::ALIGN,
+        padded,
         size_requirement::*,
         unbuffer::{self, UnbufferFrom},
         BufferSize, BufferUnbufferError, ConstantBufferSize, MessageSizeInvalid,
@@ -18,14 +21,59 @@ use crate::{
 };
 
 use super::{
-    descriptions::InnerDescription, id_types::*, name_types::MessageTypeIdentifier,
+    descriptions::InnerDescription,
+    id_types::*,
+    name_types::MessageTypeIdentifier,
+    profile::{ProtocolProfile, UNPADDED_HEADER_SIZE},
     IdWithNameAndDescription, TimeVal,
 };
 
+/// What to do with bytes left in a message body after unbuffering a typed body from it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum TrailingBytes {
+    /// Leftover bytes are an error: the default.
+    Strict,
+    /// Leftover bytes are silently dropped, e.g. for peers that pad bodies differently.
+    Ignore,
+    /// Leftover bytes are passed to `TypedMessageBody::capture_trailing_bytes()`.
+    Capture,
+}
+
 /// Trait for typed message bodies.
 pub trait TypedMessageBody: std::fmt::Debug {
     /// The name string (for user messages) or type ID (for system messages) used to identify this message type.
     const MESSAGE_IDENTIFIER: MessageTypeIdentifier;
+
+    /// How to handle unconsumed bytes after unbuffering this body from a message.
+    const TRAILING_BYTES: TrailingBytes = TrailingBytes::Strict;
+
+    /// Receives the unconsumed bytes, if `TRAILING_BYTES` is `TrailingBytes::Capture`.
+    ///
+    /// Only called if there are any.
+    fn capture_trailing_bytes(&mut self, _trailing: Bytes) {}
+}
+
+/// Unbuffer a typed body from a generic one, applying the type's trailing-byte policy.
+pub fn unbuffer_typed_message_body<T: TypedMessageBody + UnbufferFrom>(
+    body: &GenericBody,
+) -> Result<T> {
+    let mut buf = body.inner.clone();
+    let mut typed_body = T::unbuffer_from(&mut buf)
+        .map_err(BufferUnbufferError::map_bytes_required_to_size_mismatch)?;
+    if !buf.is_empty() {
+        match T::TRAILING_BYTES {
+            TrailingBytes::Strict => {
+                return Err(VrpnError::OtherMessage(format!(
+                    "message body length was indicated as {}, but {} bytes remain unconsumed",
+                    body.inner.len(),
+                    buf.len()
+                )))
+            }
+            TrailingBytes::Ignore => {}
+            TrailingBytes::Capture => typed_body.capture_trailing_bytes(buf),
+        }
+    }
+    Ok(typed_body)
 }
 
 // Implementation for all IdWithNameAndDescription
@@ -107,6 +155,27 @@ where
     /// Access the body
     fn body_ref(&self) -> &Self::Body;
 
+    /// Access the header mutably
+    fn header_mut(&mut self) -> &mut MessageHeader;
+
+    /// Replace the timestamp, keeping the rest of the message.
+    fn with_time(mut self, time: TimeVal) -> Self {
+        self.header_mut().time = time;
+        self
+    }
+
+    /// Replace the sender, keeping the rest of the message.
+    fn with_sender(mut self, sender: impl IntoId<BaseId = SenderId>) -> Self {
+        self.header_mut().sender = sender.into_id();
+        self
+    }
+
+    /// Replace the message type, keeping the rest of the message.
+    fn with_message_type(mut self, message_type: impl IntoId<BaseId = MessageTypeId>) -> Self {
+        self.header_mut().message_type = message_type.into_id();
+        self
+    }
+
     /// true if the message type indicates that it is a "system" message (type ID < 0)
     fn is_system_message(&self) -> bool {
         self.header_ref().message_type.is_system_message()
@@ -137,6 +206,28 @@ impl<T: TypedMessageBody> TypedMessage<T> {
     pub fn from_header_and_body(header: MessageHeader, body: T) -> TypedMessage<T> {
         TypedMessage { header, body }
     }
+
+    /// Transform the body, keeping the header.
+    ///
+    /// The message type ID is kept too: if `U` is a different message type,
+    /// follow up with `with_message_type()`.
+    pub fn map_body<U: TypedMessageBody>(self, f: impl FnOnce(T) -> U) -> TypedMessage<U> {
+        TypedMessage {
+            header: self.header,
+            body: f(self.body),
+        }
+    }
+
+    /// Like `map_body()`, but for a transformation that can fail.
+    pub fn try_map_body<U: TypedMessageBody, E>(
+        self,
+        f: impl FnOnce(T) -> std::result::Result<U, E>,
+    ) -> std::result::Result<TypedMessage<U>, E> {
+        Ok(TypedMessage {
+            header: self.header,
+            body: f(self.body)?,
+        })
+    }
 }
 impl<T: TypedMessageBody> Message for TypedMessage<T> {
     type Body = T;
@@ -152,6 +243,10 @@ impl<T: TypedMessageBody> Message for TypedMessage<T> {
     fn body_ref(&self) -> &Self::Body {
         &self.body
     }
+
+    fn header_mut(&mut self) -> &mut MessageHeader {
+        &mut self.header
+    }
 }
 
 impl<T: TypedMessageBody + unbuffer::UnbufferFrom> TryFrom<&GenericMessage> for TypedMessage<T> {
@@ -159,20 +254,21 @@ impl<T: TypedMessageBody + unbuffer::UnbufferFrom> TryFrom<&GenericMessage> for
 
     /// Try parsing a generic message into a typed message
     ///
+    /// For user messages, the type ID cannot be checked here, since it depends on registration:
+    /// use `TypeDispatcher::try_decode()` if the message might be of some other type.
+    ///
     /// # Errors
+    /// - If `T` is a system message type and the message type ID doesn't match
     /// - If the unbuffering of the given type fails
-    /// - If the generic message's body isn't fully consumed by the typed message body
+    /// - If the generic message's body isn't fully consumed by the typed message body,
+    ///   and the type's `TRAILING_BYTES` policy is `TrailingBytes::Strict`
     fn try_from(msg: &GenericMessage) -> std::result::Result<Self, Self::Error> {
-        let mut buf = msg.body.inner.clone();
-        let body = T::unbuffer_from(&mut buf)
-            .map_err(BufferUnbufferError::map_bytes_required_to_size_mismatch)?;
-        if !buf.is_empty() {
-            return Err(VrpnError::OtherMessage(format!(
-                "message body length was indicated as {}, but {} bytes remain unconsumed",
-                msg.body.inner.len(),
-                buf.len()
-            )));
+        if let MessageTypeIdentifier::SystemMessageId(id) = T::MESSAGE_IDENTIFIER {
+            if msg.header.message_type != id {
+                return Err(VrpnError::WrongMessageType(msg.header.message_type.get()));
+            }
         }
+        let body = unbuffer_typed_message_body(&msg.body)?;
         Ok(TypedMessage::from_header_and_body(msg.header.clone(), body))
     }
 }
@@ -180,16 +276,7 @@ impl<T: TypedMessageBody + unbuffer::UnbufferFrom> TryFrom<&GenericMessage> for
 impl<T: TypedMessageBody + unbuffer::UnbufferFrom> TypedMessage<T> {
     #[deprecated]
     pub fn try_from_generic(msg: &GenericMessage) -> Result<TypedMessage<T>> {
-        let mut buf = msg.body.inner.clone();
-        let body = T::unbuffer_from(&mut buf)
-            .map_err(BufferUnbufferError::map_bytes_required_to_size_mismatch)?;
-        if !buf.is_empty() {
-            return Err(VrpnError::OtherMessage(format!(
-                "message body length was indicated as {}, but {} bytes remain unconsumed",
-                msg.body.inner.len(),
-                buf.len()
-            )));
-        }
+        let body = unbuffer_typed_message_body(&msg.body)?;
         Ok(TypedMessage::from_header_and_body(msg.header.clone(), body))
     }
 }
@@ -202,6 +289,19 @@ pub struct GenericMessage {
 }
 
 impl GenericMessage {
+    /// Create a message by buffering a typed body directly, without needing an owned `TypedMessage`.
+    pub fn from_header_and_typed_body<T: buffer::BufferTo>(
+        header: MessageHeader,
+        body: &T,
+    ) -> std::result::Result<GenericMessage, BufferUnbufferError> {
+        let mut buf = BytesMut::with_capacity(body.buffer_size());
+        buffer::debug_checked_buffer_to(body, &mut buf)?;
+        Ok(GenericMessage::from_header_and_body(
+            header,
+            GenericBody::new(buf.freeze()),
+        ))
+    }
+
     /// Consumes this message and returns a new SequencedMessage, which the supplied sequence number has been added to.
     pub fn into_sequenced_message(
         self,
@@ -210,6 +310,7 @@ impl GenericMessage {
         SequencedGenericMessage {
             message: self,
             sequence_number,
+            numbered: true,
         }
     }
 }
@@ -227,33 +328,34 @@ impl Message for GenericMessage {
     fn body_ref(&self) -> &Self::Body {
         &self.body
     }
+
+    fn header_mut(&mut self) -> &mut MessageHeader {
+        &mut self.header
+    }
 }
 
 impl<T: TypedMessageBody + buffer::BufferTo> TryFrom<TypedMessage<T>> for GenericMessage {
     type Error = BufferUnbufferError;
 
     fn try_from(value: TypedMessage<T>) -> std::result::Result<Self, Self::Error> {
-        let old_body = value.body;
-        let header = value.header;
-        let mut buf = BytesMut::with_capacity(old_body.buffer_size());
-        old_body.buffer_to(&mut buf)?;
-        Ok(GenericMessage::from_header_and_body(
-            header,
-            GenericBody::new(buf.freeze()),
-        ))
+        GenericMessage::try_from(&value)
     }
 }
 
-impl<T: TypedMessageBody + unbuffer::UnbufferFrom> TryFrom<GenericMessage> for TypedMessage<T> {
+impl<T: TypedMessageBody + buffer::BufferTo> TryFrom<&TypedMessage<T>> for GenericMessage {
     type Error = BufferUnbufferError;
 
+    fn try_from(value: &TypedMessage<T>) -> std::result::Result<Self, Self::Error> {
+        GenericMessage::from_header_and_typed_body(value.header.clone(), &value.body)
+    }
+}
+
+impl<T: TypedMessageBody + unbuffer::UnbufferFrom> TryFrom<GenericMessage> for TypedMessage<T> {
+    type Error = VrpnError;
+
+    /// Same as the conversion from `&GenericMessage`.
     fn try_from(value: GenericMessage) -> std::result::Result<Self, Self::Error> {
-        let mut buf = value.body.clone().into_inner();
-        let typed_body = T::unbuffer_from(&mut buf)?;
-        Ok(TypedMessage {
-            header: value.header,
-            body: typed_body,
-        })
+        TypedMessage::try_from(&value)
     }
 }
 
@@ -263,7 +365,9 @@ impl<T: TypedMessageBody + unbuffer::UnbufferFrom> TryFrom<GenericMessage> for T
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct SequencedGenericMessage {
     message: GenericMessage,
+    /// Zero if the sender didn't number this message: see `sequence`.
     pub sequence_number: SequenceNumber,
+    numbered: bool,
 }
 
 impl SequencedGenericMessage {
@@ -276,21 +380,30 @@ impl SequencedGenericMessage {
         &self.message
     }
 
-    /// Serialize to a buffer.
-    pub fn try_into_buf(self) -> std::result::Result<Bytes, BufferUnbufferError> {
-        let mut buf = BytesMut::with_capacity(self.buffer_size());
-
-        let size = generic_message_size(&self);
-        let length_field = size.length_field() as u32;
+    /// The sequence number, or None if the message was received without one,
+    /// as determined by the `SequenceNumberPolicy` of the profile it was decoded with.
+    pub fn sequence(&self) -> Option<SequenceNumber> {
+        if self.numbered {
+            Some(self.sequence_number)
+        } else {
+            None
+        }
+    }
 
-        buffer::BufferTo::buffer_to(&length_field, &mut buf)?;
-        buffer::BufferTo::buffer_to(&self.message.header, &mut buf)?;
-        buffer::BufferTo::buffer_to(&self.sequence_number, &mut buf)?;
+    /// The size of this message, from which its padded size under any profile follows.
+    ///
+    /// Fails with `BufferUnbufferError::MessageTooLarge` if the body is too large to send.
+    pub fn message_size(&self) -> std::result::Result<MessageSize, BufferUnbufferError> {
+        generic_message_size(self)
+    }
 
-        buf.put_slice(&self.message.body.inner);
-        for _ in 0..size.body_padding() {
-            buf.put_u8(0);
-        }
+    /// Serialize to a new buffer.
+    ///
+    /// To append to an existing buffer without an intermediate copy,
+    /// use `BytesMutExtras::reserve_and_buffer` or `BufferTo::buffer_to` instead.
+    pub fn try_into_buf(self) -> std::result::Result<Bytes, BufferUnbufferError> {
+        let mut buf = BytesMut::with_capacity(self.message_size()?.padded_message_size());
+        buffer::BufferTo::buffer_to(&self, &mut buf)?;
         Ok(buf.freeze())
     }
 
@@ -300,17 +413,16 @@ impl SequencedGenericMessage {
         local_buf: T,
         size: &MessageSize,
         initial_remaining: usize,
+        profile: &ProtocolProfile,
     ) -> unbuffer::UnbufferResult<Self> {
         let mut local_buf = local_buf;
         let header = MessageHeader::unbuffer_from(&mut local_buf)?;
-        assert_ne!(local_buf.remaining(), 0);
+        let sequence_slot = u32::unbuffer_from(&mut local_buf)?;
+        local_buf.advance(profile.header_padding());
 
-        let sequence_number = SequenceNumber::unbuffer_from(&mut local_buf)?;
-        assert_ne!(local_buf.remaining(), 0);
-
-        // Assert that handling the sequence number meant we're now aligned again.
-        assert_eq!(
-            (initial_remaining - local_buf.remaining()) % crate::buffer_unbuffer::constants::ALIGN,
+        // Assert that handling the sequence number and header padding meant we're now aligned again.
+        debug_assert_eq!(
+            (initial_remaining - local_buf.remaining()) % profile.align(),
             0
         );
 
@@ -318,19 +430,41 @@ impl SequencedGenericMessage {
             let mut body_buf = local_buf.copy_to_bytes(size.unpadded_body_size());
             let my_body = GenericBody::unbuffer_from(&mut body_buf)
                 .map_err(ExpandSizeRequirement::expand_size_requirement)?;
-            assert_eq!(body_buf.remaining(), 0);
+            debug_assert_eq!(body_buf.remaining(), 0);
             my_body
         };
-        Ok(SequencedGenericMessage {
-            message: GenericMessage { header, body },
-            sequence_number,
-        })
+        Ok(SequencedGenericMessage::from_received(
+            GenericMessage { header, body },
+            profile.sequence_number_from_slot(sequence_slot),
+        ))
+    }
+
+    /// Wrap a received message with its sequence number, or None if it had none.
+    pub(crate) fn from_received(
+        message: GenericMessage,
+        sequence_number: Option<SequenceNumber>,
+    ) -> SequencedGenericMessage {
+        SequencedGenericMessage {
+            message,
+            sequence_number: sequence_number.unwrap_or(SequenceNumber(0)),
+            numbered: sequence_number.is_some(),
+        }
     }
 
     /// Deserialize from a buffer.
     ///
     /// In case of error, your buffer is unmodified.
     pub fn try_read_from_buf<T: Buf + Clone>(buf: &mut T) -> unbuffer::UnbufferResult<Self> {
+        Self::try_read_from_buf_with_profile(buf, &ProtocolProfile::VRPN)
+    }
+
+    /// Deserialize from a buffer, using the padding and header layout of the given profile.
+    ///
+    /// In case of error, your buffer is unmodified.
+    pub fn try_read_from_buf_with_profile<T: Buf + Clone>(
+        buf: &mut T,
+        profile: &ProtocolProfile,
+    ) -> unbuffer::UnbufferResult<Self> {
         let u32_size = u32::constant_buffer_size();
         let initial_remaining = buf.remaining();
         if initial_remaining < u32_size {
@@ -342,22 +476,52 @@ impl SequencedGenericMessage {
         // we have at least a length field.
         let mut local_buf = buf.clone();
         let length_field = u32::unbuffer_from(&mut local_buf)?;
-        let size = MessageSize::try_from_length_field(length_field)?;
+        let size = profile.try_size_from_length_field(length_field)?;
 
         // make sure our original buf has enough for an entire padded message
-        unbuffer::check_unbuffer_remaining(buf, size.padded_message_size())?;
+        unbuffer::check_unbuffer_remaining(buf, profile.padded_message_size(size))?;
         let seq_generic_message =
-            Self::try_finish_read_from_local_buf(local_buf, &size, initial_remaining)?;
+            Self::try_finish_read_from_local_buf(local_buf, &size, initial_remaining, profile)?;
 
         // We can advance the buf now that we know we succeed.
-        buf.advance(size.padded_message_size());
+        buf.advance(profile.padded_message_size(size));
         Ok(seq_generic_message)
     }
 }
 
 impl BufferSize for SequencedGenericMessage {
     fn buffer_size(&self) -> usize {
-        generic_message_size(self).padded_message_size()
+        // Not bounded like a MessageSize: buffer_to fails for a body too large to send,
+        // which message_size reports up front.
+        padded(self.message.body.inner.len()).saturating_add(MessageSize::PADDED_HEADER_SIZE)
+    }
+}
+
+impl SequencedGenericMessage {
+    /// Serialize, using the padding and header layout of the given profile.
+    pub fn buffer_to_with_profile<T: BufMut>(
+        &self,
+        buf: &mut T,
+        profile: &ProtocolProfile,
+    ) -> buffer::BufferResult {
+        let size = generic_message_size(self)?;
+        let length_field = profile.length_field(size)?;
+        buffer::check_buffer_remaining(buf, profile.padded_message_size(size))?;
+
+        buffer::BufferTo::buffer_to(&length_field, buf)?;
+        buffer::BufferTo::buffer_to(&self.message.header, buf)?;
+        buffer::BufferTo::buffer_to(&self.sequence_number, buf)?;
+        buf.put_bytes(0, profile.header_padding());
+
+        buf.put_slice(&self.message.body.inner);
+        buf.put_bytes(0, profile.body_padding(size));
+        Ok(())
+    }
+}
+
+impl buffer::BufferTo for SequencedGenericMessage {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> buffer::BufferResult {
+        self.buffer_to_with_profile(buf, &ProtocolProfile::VRPN)
     }
 }
 
@@ -379,21 +543,6 @@ impl GenericBody {
     }
 }
 
-#[inline]
-const fn compute_padding(len: usize) -> usize {
-    let remainder = len % crate::buffer_unbuffer::constants::ALIGN;
-    if remainder != 0 {
-        crate::buffer_unbuffer::constants::ALIGN - remainder
-    } else {
-        0
-    }
-}
-
-#[inline]
-const fn padded(len: usize) -> usize {
-    len + compute_padding(len)
-}
-
 /// Simple struct for wrapping all calculations related to Message<T> size.
 ///
 /// Header is 5 i32s (padded to `vrpn_ALIGN`):
@@ -406,13 +555,17 @@ const fn padded(len: usize) -> usize {
 /// which are not "officially" part of the header.
 ///
 /// body is padded out to `vrpn_ALIGN`
+///
+/// A `MessageSize` is always small enough that its length field fits in a `LengthField`
+/// and its padded size in a `usize`, so none of the computations on it can overflow:
+/// sizes from untrusted sources should go through `try_from_unpadded_body_size`
+/// or `try_from_length_field`.
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct MessageSize {
     // The unpadded size of a message body only
-    pub unpadded_body_size: usize,
+    unpadded_body_size: usize,
 }
 
-const UNPADDED_HEADER_SIZE: usize = 5 * 4;
 /// Padded size of `UNPADDED_HEADER_SIZE`
 const MINIMUM_SIZE_FIELD: u32 = 6 * 4;
 
@@ -420,29 +573,60 @@ const MINIMUM_SIZE_FIELD: u32 = 6 * 4;
 pub type LengthField = u32;
 
 impl MessageSize {
-    /// Get a MessageSize from the unpadded size of a message body only.
+    /// Size of the header including the sequence number and padding: the smallest message.
+    pub const PADDED_HEADER_SIZE: usize = padded(UNPADDED_HEADER_SIZE);
+
+    /// The largest body whose length field fits in a `LengthField`.
+    ///
+    /// A multiple of the alignment, so the padded body is no larger.
+    pub const MAX_UNPADDED_BODY_SIZE: usize =
+        (LengthField::MAX as usize - MessageSize::PADDED_HEADER_SIZE) & !(ALIGN - 1);
+
+    /// Get a MessageSize from the unpadded size of a message body only,
+    /// already known not to exceed `MAX_UNPADDED_BODY_SIZE`.
+    ///
+    /// Outside the crate, use `try_from_unpadded_body_size`.
     #[inline]
-    pub const fn from_unpadded_body_size(unpadded_body_size: usize) -> MessageSize {
+    pub(crate) const fn from_unpadded_body_size(unpadded_body_size: usize) -> MessageSize {
+        assert!(
+            unpadded_body_size <= MessageSize::MAX_UNPADDED_BODY_SIZE,
+            "message body too large"
+        );
         MessageSize { unpadded_body_size }
     }
 
-    /// Get a MessageSize from the total unpadded size of a message (header plus body)
+    /// Get a MessageSize from the unpadded size of a message body only,
+    /// failing with `BufferUnbufferError::MessageTooLarge` if its length field wouldn't fit.
     #[inline]
-    #[deprecated = "possible to fail, looks unused so would rather remove than change"]
-    pub const fn from_unpadded_message_size(unpadded_message_size: usize) -> MessageSize {
-        MessageSize::from_unpadded_body_size(unpadded_message_size - UNPADDED_HEADER_SIZE)
+    pub fn try_from_unpadded_body_size(
+        unpadded_body_size: usize,
+    ) -> std::result::Result<MessageSize, BufferUnbufferError> {
+        if unpadded_body_size > MessageSize::MAX_UNPADDED_BODY_SIZE {
+            Err(BufferUnbufferError::MessageTooLarge {
+                claimed: unpadded_body_size,
+                max: MessageSize::MAX_UNPADDED_BODY_SIZE,
+            })
+        } else {
+            Ok(MessageSize { unpadded_body_size })
+        }
     }
+
     /// Get a MessageSize from the length field of a message (padded header plus unpadded body)
+    ///
+    /// Fails if the length field doesn't cover the header, or the body is larger than
+    /// `MAX_UNPADDED_BODY_SIZE` (which only the last few values of a `LengthField` exceed).
     #[inline]
     pub const fn try_from_length_field(
         length_field: LengthField,
     ) -> std::result::Result<MessageSize, MessageSizeInvalid> {
         if length_field < MINIMUM_SIZE_FIELD {
-            Err(MessageSizeInvalid(length_field as u32))
+            return Err(MessageSizeInvalid(length_field));
+        }
+        let unpadded_body_size = length_field as usize - MessageSize::PADDED_HEADER_SIZE;
+        if unpadded_body_size > MessageSize::MAX_UNPADDED_BODY_SIZE {
+            Err(MessageSizeInvalid(length_field))
         } else {
-            Ok(MessageSize::from_unpadded_body_size(
-                length_field as usize - padded(UNPADDED_HEADER_SIZE),
-            ))
+            Ok(MessageSize { unpadded_body_size })
         }
     }
 
@@ -457,7 +641,8 @@ impl MessageSize {
     /// This is the value put in the message header's length field.
     #[inline]
     pub const fn length_field(&self) -> LengthField {
-        (self.unpadded_body_size + padded(UNPADDED_HEADER_SIZE)) as LengthField
+        // Can't truncate: bounded by MAX_UNPADDED_BODY_SIZE.
+        (self.unpadded_body_size + MessageSize::PADDED_HEADER_SIZE) as LengthField
     }
 
     /// The size of the body plus padding (multiple of ALIGN)
@@ -477,12 +662,14 @@ impl MessageSize {
     /// This is the size of buffer actually required for this message.
     #[inline]
     pub const fn padded_message_size(&self) -> usize {
-        self.padded_body_size() + padded(UNPADDED_HEADER_SIZE)
+        self.padded_body_size() + MessageSize::PADDED_HEADER_SIZE
     }
 }
 
-fn generic_message_size(msg: &SequencedGenericMessage) -> MessageSize {
-    MessageSize::from_unpadded_body_size(msg.message.body.inner.len())
+fn generic_message_size(
+    msg: &SequencedGenericMessage,
+) -> std::result::Result<MessageSize, BufferUnbufferError> {
+    MessageSize::try_from_unpadded_body_size(msg.message.body.inner.len())
 }
 
 impl unbuffer::UnbufferFrom for GenericBody {
@@ -510,7 +697,10 @@ impl buffer::BufferTo for GenericBody {
 mod tests {
     use std::mem::size_of;
 
-    use crate::buffer_unbuffer::{constants::ALIGN, ConstantBufferSize};
+    use crate::{
+        buffer_unbuffer::{constants::ALIGN, ConstantBufferSize},
+        data_types::SequenceNumberPolicy,
+    };
 
     use super::*;
 
@@ -535,6 +725,173 @@ mod tests {
         );
     }
 
+    #[test]
+    fn buffer_in_place() {
+        use crate::buffer_unbuffer::BytesMutExtras;
+        let messages: Vec<_> = (0..5u32)
+            .map(|len| {
+                GenericMessage::from_header_and_body(
+                    MessageHeader::new(None, MessageTypeId(1), SenderId(2)),
+                    GenericBody::new(Bytes::from(vec![7u8; len as usize])),
+                )
+                .into_sequenced_message(SequenceNumber(len))
+            })
+            .collect();
+        let mut appended = BytesMut::from(&b"prefix"[..]);
+        let mut expected = appended.clone();
+        for msg in &messages {
+            appended.reserve_and_buffer(msg).unwrap();
+            expected.extend_from_slice(&msg.clone().try_into_buf().unwrap());
+        }
+        assert_eq!(appended, expected);
+    }
+
+    #[test]
+    fn unnumbered_messages() {
+        let msg = GenericMessage::from_header_and_body(
+            MessageHeader::new(None, MessageTypeId(1), SenderId(2)),
+            GenericBody::new(Bytes::from_static(b"body")),
+        );
+        let zero = msg
+            .clone()
+            .into_sequenced_message(SequenceNumber(0))
+            .try_into_buf()
+            .unwrap();
+        let five = msg
+            .into_sequenced_message(SequenceNumber(5))
+            .try_into_buf()
+            .unwrap();
+
+        let decoded = SequencedGenericMessage::try_read_from_buf(&mut zero.clone()).unwrap();
+        assert_eq!(decoded.sequence(), Some(SequenceNumber(0)));
+
+        let profile =
+            ProtocolProfile::VRPN.with_sequence_numbers(SequenceNumberPolicy::ZeroIsMissing);
+        let decoded =
+            SequencedGenericMessage::try_read_from_buf_with_profile(&mut zero.clone(), &profile)
+                .unwrap();
+        assert_eq!(decoded.sequence(), None);
+        assert_eq!(decoded.message().body.inner, &b"body"[..]);
+        let decoded =
+            SequencedGenericMessage::try_read_from_buf_with_profile(&mut five.clone(), &profile)
+                .unwrap();
+        assert_eq!(decoded.sequence(), Some(SequenceNumber(5)));
+
+        let profile = profile.with_sequence_numbers(SequenceNumberPolicy::Ignored);
+        let decoded =
+            SequencedGenericMessage::try_read_from_buf_with_profile(&mut five.clone(), &profile)
+                .unwrap();
+        assert_eq!(decoded.sequence(), None);
+    }
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Padded<const P: u8> {
+        value: u32,
+        extra: Bytes,
+    }
+
+    const STRICT: u8 = 0;
+    const IGNORE: u8 = 1;
+    const CAPTURE: u8 = 2;
+
+    impl<const P: u8> TypedMessageBody for Padded<P> {
+        const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+            crate::data_types::StaticMessageTypeName(b"padded"),
+        );
+        const TRAILING_BYTES: TrailingBytes = match P {
+            STRICT => TrailingBytes::Strict,
+            IGNORE => TrailingBytes::Ignore,
+            _ => TrailingBytes::Capture,
+        };
+        fn capture_trailing_bytes(&mut self, trailing: Bytes) {
+            self.extra = trailing;
+        }
+    }
+
+    impl<const P: u8> UnbufferFrom for Padded<P> {
+        fn unbuffer_from<T: Buf>(buf: &mut T) -> unbuffer::UnbufferResult<Self> {
+            Ok(Padded {
+                value: u32::unbuffer_from(buf)?,
+                extra: Bytes::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_policy() {
+        let exact = GenericBody::new(Bytes::from_static(&hex!("00 00 00 05")));
+        let padded = GenericBody::new(Bytes::from_static(&hex!("00 00 00 05 00 00 00 00")));
+
+        for body in &[&exact, &padded] {
+            assert_eq!(
+                unbuffer_typed_message_body::<Padded<IGNORE>>(body)
+                    .unwrap()
+                    .value,
+                5
+            );
+        }
+        assert!(unbuffer_typed_message_body::<Padded<STRICT>>(&exact).is_ok());
+        assert!(unbuffer_typed_message_body::<Padded<STRICT>>(&padded).is_err());
+
+        assert_eq!(
+            unbuffer_typed_message_body::<Padded<CAPTURE>>(&exact).unwrap(),
+            Padded {
+                value: 5,
+                extra: Bytes::new()
+            }
+        );
+        assert_eq!(
+            unbuffer_typed_message_body::<Padded<CAPTURE>>(&padded).unwrap(),
+            Padded {
+                value: 5,
+                extra: Bytes::from_static(&[0; 4])
+            }
+        );
+    }
+
+    #[test]
+    fn transform_preserving_header() {
+        use crate::data_types::time::{Microseconds, Seconds};
+        let msg = TypedMessage::new(
+            Some(TimeVal::new(Seconds(12), Microseconds(34))),
+            MessageTypeId(2),
+            SenderId(3),
+            Padded::<STRICT> {
+                value: 5,
+                extra: Bytes::new(),
+            },
+        );
+        let header = msg.header.clone();
+
+        let mapped = msg.map_body(|body| Padded::<IGNORE> {
+            value: body.value * 2,
+            extra: body.extra,
+        });
+        assert_eq!(mapped.header, header);
+        assert_eq!(mapped.body.value, 10);
+
+        let moved = mapped
+            .with_time(TimeVal::new(Seconds(56), Microseconds(78)))
+            .with_sender(SenderId(4))
+            .with_message_type(MessageTypeId(5));
+        assert_eq!(
+            moved.header.time,
+            TimeVal::new(Seconds(56), Microseconds(78))
+        );
+        assert_eq!(moved.header.sender, SenderId(4));
+        assert_eq!(moved.header.message_type, MessageTypeId(5));
+        assert_eq!(moved.body.value, 10);
+
+        let failed: std::result::Result<TypedMessage<Padded<STRICT>>, &str> =
+            moved.try_map_body(|_| Err("nope"));
+        assert_eq!(failed.unwrap_err(), "nope");
+
+        let generic = GenericMessage::from_header_and_body(header, GenericBody::default())
+            .with_sender(SenderId(6));
+        assert_eq!(generic.header.sender, SenderId(6));
+        assert_eq!(generic.header.message_type, MessageTypeId(2));
+    }
+
     #[test]
     fn invalid_msg_size() {
         assert!(MessageSize::try_from_length_field(20).is_err())
@@ -619,6 +976,51 @@ mod tests {
         fn roundtrip(len in 24u32..10000)  {
             prop_assert_eq!(MessageSize::try_from_length_field(len).unwrap().length_field(), len);
         }
+
+        #[test]
+        fn size_identities(len in 0usize..=MessageSize::MAX_UNPADDED_BODY_SIZE) {
+            let size = MessageSize::try_from_unpadded_body_size(len).unwrap();
+            prop_assert!(size.padded_body_size() >= size.unpadded_body_size());
+            prop_assert!(size.body_padding() < ALIGN);
+            prop_assert_eq!(size.padded_body_size() % ALIGN, 0);
+            prop_assert_eq!(size.padded_message_size() % ALIGN, 0);
+            prop_assert_eq!(
+                size.padded_message_size(),
+                MessageSize::PADDED_HEADER_SIZE + size.unpadded_body_size() + size.body_padding()
+            );
+            prop_assert_eq!(MessageSize::try_from_length_field(size.length_field()), Ok(size));
+        }
+
+        #[test]
+        fn any_length_field(len: u32) {
+            if let Ok(size) = MessageSize::try_from_length_field(len) {
+                prop_assert_eq!(size.length_field(), len);
+                prop_assert!(size.padded_message_size() >= len as usize);
+            } else {
+                prop_assert!(
+                    len < MINIMUM_SIZE_FIELD
+                        || len as usize - MessageSize::PADDED_HEADER_SIZE > MessageSize::MAX_UNPADDED_BODY_SIZE
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn size_limits() {
+        let max = MessageSize::MAX_UNPADDED_BODY_SIZE;
+        assert_eq!(max % ALIGN, 0);
+        let size = MessageSize::try_from_unpadded_body_size(max).unwrap();
+        assert_eq!(size.padded_body_size(), max);
+        assert!(size.length_field() > u32::MAX - ALIGN as u32);
+        assert!(size.padded_message_size() <= u32::MAX as usize);
+
+        assert!(matches!(
+            MessageSize::try_from_unpadded_body_size(max + 1),
+            Err(BufferUnbufferError::MessageTooLarge { claimed, .. }) if claimed == max + 1
+        ));
+        assert!(MessageSize::try_from_length_field(u32::MAX).is_err());
+        assert!(MessageSize::try_from_length_field(size.length_field()).is_ok());
+        assert!(MessageSize::try_from_length_field(size.length_field() + 1).is_err());
     }
 
     struct Lengths {
@@ -646,4 +1048,31 @@ mod tests {
             len_field: (header_len + len) as u32,
         }
     }
+
+    #[test]
+    fn empty_body_at_end_of_buffer() {
+        // A message with no body, and nothing after it in the buffer.
+        let mut buf = Bytes::from_static(&hex!(
+            "00 00 00 18 5b eb 33 2e 00 0c 58 b1 00 00 00 00 00 00 00 02 00 00 00 07"
+        ));
+        let msg = SequencedGenericMessage::try_read_from_buf(&mut buf).unwrap();
+        assert_eq!(msg.message.body.inner.len(), 0);
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn invalid_length_field() {
+        let mut buf = Bytes::from_static(&hex!("00 00 00 04 00 00 00 00"));
+        assert!(SequencedGenericMessage::try_read_from_buf(&mut buf).is_err());
+        // Untouched on error
+        assert_eq!(buf.remaining(), 8);
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_bytes_do_not_panic(data in proptest::collection::vec(proptest::num::u8::ANY, 0..128)) {
+            let mut buf = Bytes::from(data);
+            let _ = SequencedGenericMessage::try_read_from_buf(&mut buf);
+        }
+    }
 }
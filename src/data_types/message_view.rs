@@ -0,0 +1,193 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Borrowed views of received messages, decoded without copying their bodies.
+
+use bytes::Bytes;
+
+use crate::buffer_unbuffer::{
+    size_requirement::SizeRequirement,
+    unbuffer::{check_unbuffer_remaining, UnbufferFrom, UnbufferResult},
+    BufferUnbufferError, ConstantBufferSize,
+};
+
+use super::{
+    id_types::SequenceNumber,
+    message::{GenericBody, GenericMessage, Message, MessageHeader, SequencedGenericMessage},
+    profile::ProtocolProfile,
+};
+
+/// A message decoded in place: its header fields, with its body borrowed from the receive buffer.
+///
+/// For code that only inspects or forwards messages, such as a proxy,
+/// this avoids allocating and copying for each message.
+/// Convert with `to_sequenced_message` or `to_generic_message` to keep one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageView<'a> {
+    header: MessageHeader,
+    sequence_number: Option<SequenceNumber>,
+    body: &'a [u8],
+    frame: &'a [u8],
+}
+
+impl<'a> MessageView<'a> {
+    /// Decode the message at the start of a buffer, framed according to `profile`.
+    ///
+    /// Fails with `BufferUnbufferError::NeedMoreData` if the buffer ends partway through it.
+    ///
+    /// ```
+    /// use vrpn::{
+    ///     data_types::{
+    ///         GenericBody, GenericMessage, Message, MessageHeader, MessageTypeId, MessageView,
+    ///         ProtocolProfile,
+    ///     },
+    ///     data_types::id_types::{SenderId, SequenceNumber},
+    /// };
+    /// let msg = GenericMessage::from_header_and_body(
+    ///     MessageHeader::new(None, MessageTypeId(2), SenderId(1)),
+    ///     GenericBody::new(vec![1, 2, 3].into()),
+    /// )
+    /// .into_sequenced_message(SequenceNumber(7));
+    /// let bytes = msg.clone().try_into_buf()?;
+    /// let view = MessageView::try_read_from_slice(&bytes, &ProtocolProfile::VRPN)?;
+    /// assert_eq!(view.body(), &[1, 2, 3]);
+    /// assert_eq!(view.frame().len(), bytes.len());
+    /// assert_eq!(view.to_sequenced_message(), msg);
+    /// # Ok::<(), vrpn::VrpnError>(())
+    /// ```
+    pub fn try_read_from_slice(
+        buf: &'a [u8],
+        profile: &ProtocolProfile,
+    ) -> UnbufferResult<MessageView<'a>> {
+        let u32_size = u32::constant_buffer_size();
+        if buf.len() < u32_size {
+            return Err(BufferUnbufferError::from(SizeRequirement::AtLeast(
+                u32_size,
+            )));
+        }
+        let mut fields = buf;
+        let length_field = u32::unbuffer_from(&mut fields)?;
+        let size = profile.try_size_from_length_field(length_field)?;
+        let frame_len = profile.padded_message_size(size);
+        check_unbuffer_remaining(&buf, frame_len)?;
+
+        let header = MessageHeader::unbuffer_from(&mut fields)?;
+        let sequence_slot = u32::unbuffer_from(&mut fields)?;
+        let body_start = profile.header_size();
+        Ok(MessageView {
+            header,
+            sequence_number: profile.sequence_number_from_slot(sequence_slot),
+            body: &buf[body_start..body_start + size.unpadded_body_size()],
+            frame: &buf[..frame_len],
+        })
+    }
+
+    pub fn header(&self) -> &MessageHeader {
+        &self.header
+    }
+
+    /// The sequence number, or None if the message was received without one,
+    /// as determined by the `SequenceNumberPolicy` of the profile it was decoded with.
+    pub fn sequence(&self) -> Option<SequenceNumber> {
+        self.sequence_number
+    }
+
+    /// The body, without padding.
+    pub fn body(&self) -> &'a [u8] {
+        self.body
+    }
+
+    /// The whole message as received, including its length field and padding.
+    ///
+    /// May be written as-is to a peer using the same profile, though it keeps
+    /// the sender's sequence number, and IDs assigned by the sender.
+    pub fn frame(&self) -> &'a [u8] {
+        self.frame
+    }
+
+    /// Copy into an owned message, keeping the sequence number.
+    pub fn to_sequenced_message(&self) -> SequencedGenericMessage {
+        SequencedGenericMessage::from_received(self.to_generic_message(), self.sequence_number)
+    }
+
+    /// Copy into an owned message.
+    pub fn to_generic_message(&self) -> GenericMessage {
+        GenericMessage::from_header_and_body(
+            self.header.clone(),
+            GenericBody::new(Bytes::copy_from_slice(self.body)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer_unbuffer::BytesMutExtras,
+        data_types::{
+            id_types::{MessageTypeId, SenderId},
+            SequenceNumberPolicy,
+        },
+    };
+    use bytes::BytesMut;
+
+    fn test_message(seq: u32, body_len: usize) -> SequencedGenericMessage {
+        GenericMessage::from_header_and_body(
+            MessageHeader::new(None, MessageTypeId(3), SenderId(seq as i32)),
+            GenericBody::new(Bytes::from(vec![seq as u8; body_len])),
+        )
+        .into_sequenced_message(SequenceNumber(seq))
+    }
+
+    #[test]
+    fn matches_owned_decoding() {
+        let messages: Vec<_> = (0..4).map(|i| test_message(i, 5 * i as usize)).collect();
+        let mut buf = BytesMut::new();
+        for msg in &messages {
+            buf.reserve_and_buffer(msg).unwrap();
+        }
+        let mut rest = &buf[..];
+        for msg in &messages {
+            let view = MessageView::try_read_from_slice(rest, &ProtocolProfile::VRPN).unwrap();
+            assert_eq!(view.header(), &msg.message().header);
+            assert_eq!(view.sequence(), Some(msg.sequence_number));
+            assert_eq!(view.body(), &msg.message().body.clone().into_inner()[..]);
+            assert_eq!(view.frame(), &msg.clone().try_into_buf().unwrap()[..]);
+            assert_eq!(&view.to_sequenced_message(), msg);
+            rest = &rest[view.frame().len()..];
+        }
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn incomplete() {
+        let bytes = test_message(1, 12).try_into_buf().unwrap();
+        for len in 0..bytes.len() {
+            assert!(matches!(
+                MessageView::try_read_from_slice(&bytes[..len], &ProtocolProfile::VRPN),
+                Err(BufferUnbufferError::NeedMoreData(_))
+            ));
+        }
+        // A length field too small to cover the header.
+        assert!(matches!(
+            MessageView::try_read_from_slice(&[0, 0, 0, 4, 0, 0, 0, 0], &ProtocolProfile::VRPN),
+            Err(BufferUnbufferError::MessageSizeInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn other_profile() {
+        let profile = ProtocolProfile::with_alignment(16)
+            .unwrap()
+            .with_sequence_numbers(SequenceNumberPolicy::Ignored);
+        let msg = test_message(2, 3);
+        let mut buf = BytesMut::new();
+        msg.buffer_to_with_profile(&mut buf, &profile).unwrap();
+        let view = MessageView::try_read_from_slice(&buf, &profile).unwrap();
+        assert_eq!(view.body(), &[2, 2, 2]);
+        assert_eq!(view.frame().len(), 48);
+        assert_eq!(view.sequence(), None);
+        assert_eq!(view.to_generic_message(), msg.into_inner());
+    }
+}
@@ -12,30 +12,35 @@ pub(crate) mod length_prefixed;
 pub(crate) mod log;
 mod math;
 pub(crate) mod message;
+mod message_view;
 pub mod name_types;
+mod profile;
 mod time;
 
 #[cfg(cgmath)]
 pub mod math_cgmath;
 
-#[doc(inline)]
-pub use crate::data_types::{
-    cookie::{CookieData, Version},
-    descriptions::{Description, UdpDescription},
-    math::{Quat, Vec3},
-    time::TimeVal,
-};
 pub use crate::data_types::{
+    constants::{SystemMessageType, UserSystemMessageType},
     id_types::MessageTypeId,
     message::{
-        GenericBody, GenericMessage, Message, MessageHeader, MessageSize, SequencedGenericMessage,
-        TypedMessage, TypedMessageBody,
+        unbuffer_typed_message_body, GenericBody, GenericMessage, Message, MessageHeader,
+        MessageSize, SequencedGenericMessage, TrailingBytes, TypedMessage, TypedMessageBody,
     },
+    message_view::MessageView,
     name_types::{
         IdWithNameAndDescription, MessageTypeIdentifier, MessageTypeName, SenderName,
         StaticMessageTypeName, StaticSenderName,
     },
 };
+#[doc(inline)]
+pub use crate::data_types::{
+    cookie::{CookieData, ParseCookieError, Version},
+    descriptions::{Description, UdpDescription},
+    math::{Quat, Vec3},
+    profile::{ProtocolProfile, SequenceNumberPolicy},
+    time::{Microseconds, Seconds, TimeVal},
+};
 
 pub(crate) use crate::data_types::log::{LogFileNames, LogMode};
 
@@ -4,6 +4,8 @@
 
 //! Name types used across VRPN
 
+use std::fmt;
+
 use bytes::Bytes;
 
 use super::{
@@ -11,6 +13,7 @@ use super::{
     id_types::{SenderId, UnwrappedId},
     MessageTypeId,
 };
+use crate::VrpnError;
 
 /// The identification (name or ID) used for a typed message body type.
 #[derive(Debug)]
@@ -175,3 +178,154 @@ impl IdWithNameAndDescription for MessageTypeId {
 
     const DESCRIPTION_MESSAGE_TYPE: MessageTypeId = constants::TYPE_DESCRIPTION;
 }
+
+/// Shared comparison and display impls for all name types.
+macro_rules! name_type_common {
+    ($t:ty) => {
+        impl $t {
+            /// Access the raw bytes of the name (without any null terminator).
+            pub fn as_bytes(&self) -> &[u8] {
+                self.0.as_ref()
+            }
+
+            /// Returns an error if the name contains an embedded null byte,
+            /// which cannot be represented on the wire.
+            pub fn check_valid(&self) -> Result<(), VrpnError> {
+                if self.as_bytes().contains(&0) {
+                    Err(VrpnError::InvalidName(
+                        String::from_utf8_lossy(self.as_bytes()).into_owned(),
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        impl fmt::Display for $t {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", String::from_utf8_lossy(self.as_bytes()))
+            }
+        }
+
+        impl std::cmp::PartialEq<[u8]> for $t {
+            fn eq(&self, other: &[u8]) -> bool {
+                self.as_bytes() == other
+            }
+        }
+
+        impl std::cmp::PartialEq<&[u8]> for $t {
+            fn eq(&self, other: &&[u8]) -> bool {
+                self.as_bytes() == *other
+            }
+        }
+
+        impl std::cmp::PartialEq<str> for $t {
+            fn eq(&self, other: &str) -> bool {
+                self.as_bytes() == other.as_bytes()
+            }
+        }
+
+        impl std::cmp::PartialEq<&str> for $t {
+            fn eq(&self, other: &&str) -> bool {
+                self.as_bytes() == other.as_bytes()
+            }
+        }
+    };
+}
+
+name_type_common!(StaticSenderName);
+name_type_common!(SenderName);
+name_type_common!(StaticMessageTypeName);
+name_type_common!(MessageTypeName);
+
+/// Conversions from strings for the dynamic (owned) name types.
+macro_rules! name_type_from_str {
+    ($t:ident, $static_t:ident) => {
+        impl From<&str> for $t {
+            fn from(val: &str) -> $t {
+                $t(Bytes::copy_from_slice(val.as_bytes()))
+            }
+        }
+
+        impl From<String> for $t {
+            fn from(val: String) -> $t {
+                $t(Bytes::from(val.into_bytes()))
+            }
+        }
+
+        impl From<Vec<u8>> for $t {
+            fn from(val: Vec<u8>) -> $t {
+                $t(Bytes::from(val))
+            }
+        }
+
+        impl From<&$static_t> for $t {
+            fn from(val: &$static_t) -> $t {
+                $t(Bytes::from_static(val.0))
+            }
+        }
+
+        impl From<&$t> for $t {
+            fn from(val: &$t) -> $t {
+                val.clone()
+            }
+        }
+
+        impl From<&'static str> for $static_t {
+            fn from(val: &'static str) -> $static_t {
+                $static_t(val.as_bytes())
+            }
+        }
+    };
+}
+
+name_type_from_str!(SenderName, StaticSenderName);
+name_type_from_str!(MessageTypeName, StaticMessageTypeName);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        let from_str = SenderName::from("Tracker0");
+        let from_string = SenderName::from(String::from("Tracker0"));
+        let from_static = SenderName::from(StaticSenderName(b"Tracker0"));
+        assert_eq!(from_str, from_string);
+        assert_eq!(from_str, from_static);
+        assert_eq!(from_str, StaticSenderName::from("Tracker0"));
+        assert_eq!(StaticSenderName(b"Tracker0"), from_str);
+
+        let type_name = MessageTypeName::from("vrpn_Tracker Pos_Quat");
+        assert_eq!(type_name, StaticMessageTypeName(b"vrpn_Tracker Pos_Quat"));
+        assert_eq!(
+            MessageTypeName::from(&StaticMessageTypeName(b"vrpn_Tracker Pos_Quat")),
+            type_name
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        let name = SenderName::from("Tracker0");
+        assert_eq!(name, "Tracker0");
+        assert_eq!(name, &b"Tracker0"[..]);
+        assert!(name != "Tracker1");
+        assert_eq!(StaticMessageTypeName(b"asdf"), "asdf");
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(SenderName::from("Tracker0").to_string(), "Tracker0");
+        assert_eq!(
+            StaticMessageTypeName(b"vrpn_Base ping").to_string(),
+            "vrpn_Base ping"
+        );
+    }
+
+    #[test]
+    fn validation() {
+        assert!(SenderName::from("Tracker0").check_valid().is_ok());
+        assert!(SenderName::from("Track\0er0").check_valid().is_err());
+        assert!(StaticMessageTypeName(b"a\0b").check_valid().is_err());
+    }
+}
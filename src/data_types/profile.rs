@@ -0,0 +1,258 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Wire-format parameters that the padding and framing of messages depend on.
+
+use crate::buffer_unbuffer::{constants::ALIGN, BufferUnbufferError, MessageSizeInvalid};
+
+use super::{
+    id_types::SequenceNumber,
+    message::{LengthField, MessageSize},
+};
+
+/// Size of the header fields before the sequence number:
+/// length field, time stamp (2 fields), sender, and type, each 4 bytes.
+pub(crate) const UNPADDED_HEADER_SIZE: usize = 5 * 4;
+
+/// Size of the sequence number that follows the header fields.
+const SEQUENCE_NUMBER_SIZE: usize = 4;
+
+/// How the sequence number slot of incoming message headers is interpreted.
+///
+/// Mainline VRPN numbers every message, but some minimal implementations
+/// (e.g. on embedded devices) leave the slot as zero padding.
+/// The slot is always present: only its meaning changes, so the framing is the same.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub enum SequenceNumberPolicy {
+    /// Every value, including zero, is a sequence number.
+    #[default]
+    Meaningful,
+    /// Zero means the peer didn't number the message.
+    ZeroIsMissing,
+    /// The slot is padding: no message has a sequence number.
+    Ignored,
+}
+
+/// Alignment and header layout of messages on a connection.
+///
+/// `ProtocolProfile::VRPN` (the default) matches the C++ implementation:
+/// everything else is for experimenting with protocol variants, and will only talk to peers
+/// using the same profile.
+///
+/// The header is always the length field, time stamp, sender, type, and sequence number,
+/// in that order, followed by zero padding up to a multiple of the alignment.
+/// The body follows, also padded to a multiple of the alignment.
+/// The length field holds the padded header size plus the unpadded body size.
+///
+/// How the sequence number of received messages is interpreted is set separately,
+/// with `with_sequence_numbers`, since it doesn't affect the framing.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct ProtocolProfile {
+    align: usize,
+    sequence_numbers: SequenceNumberPolicy,
+}
+
+impl Default for ProtocolProfile {
+    fn default() -> ProtocolProfile {
+        ProtocolProfile::VRPN
+    }
+}
+
+impl ProtocolProfile {
+    /// The profile of mainline VRPN (`vrpn_ALIGN` of 8).
+    pub const VRPN: ProtocolProfile = ProtocolProfile {
+        align: ALIGN,
+        sequence_numbers: SequenceNumberPolicy::Meaningful,
+    };
+
+    /// Create a profile with the given alignment, which must be a non-zero multiple of 4
+    /// so that the 4-byte fields stay aligned.
+    pub const fn with_alignment(align: usize) -> Option<ProtocolProfile> {
+        if align == 0 || align & 0b11 != 0 {
+            None
+        } else {
+            Some(ProtocolProfile {
+                align,
+                sequence_numbers: SequenceNumberPolicy::Meaningful,
+            })
+        }
+    }
+
+    /// Change how the sequence numbers of incoming messages are interpreted.
+    pub const fn with_sequence_numbers(self, policy: SequenceNumberPolicy) -> ProtocolProfile {
+        ProtocolProfile {
+            sequence_numbers: policy,
+            ..self
+        }
+    }
+
+    /// How the sequence numbers of incoming messages are interpreted.
+    pub const fn sequence_numbers(&self) -> SequenceNumberPolicy {
+        self.sequence_numbers
+    }
+
+    /// Interpret the sequence number slot of a received header, or None if it holds no number.
+    pub const fn sequence_number_from_slot(&self, slot: u32) -> Option<SequenceNumber> {
+        match self.sequence_numbers {
+            SequenceNumberPolicy::Meaningful => Some(SequenceNumber(slot)),
+            SequenceNumberPolicy::ZeroIsMissing if slot != 0 => Some(SequenceNumber(slot)),
+            SequenceNumberPolicy::ZeroIsMissing | SequenceNumberPolicy::Ignored => None,
+        }
+    }
+
+    /// Alignment, in bytes, of message headers and bodies.
+    pub const fn align(&self) -> usize {
+        self.align
+    }
+
+    /// Number of padding bytes needed after `len` bytes to reach a multiple of the alignment.
+    pub const fn padding(&self, len: usize) -> usize {
+        let remainder = len % self.align;
+        if remainder == 0 {
+            0
+        } else {
+            self.align - remainder
+        }
+    }
+
+    /// Round `len` up to the next multiple of the alignment.
+    pub const fn padded(&self, len: usize) -> usize {
+        len + self.padding(len)
+    }
+
+    /// Size of the header including the sequence number and any padding.
+    pub const fn header_size(&self) -> usize {
+        self.padded(UNPADDED_HEADER_SIZE + SEQUENCE_NUMBER_SIZE)
+    }
+
+    /// Zero bytes between the sequence number and the body.
+    pub const fn header_padding(&self) -> usize {
+        self.header_size() - UNPADDED_HEADER_SIZE - SEQUENCE_NUMBER_SIZE
+    }
+
+    /// The largest body whose length field fits in a `LengthField` with this profile's header.
+    ///
+    /// A multiple of the alignment, so the padded body is no larger.
+    pub const fn max_unpadded_body_size(&self) -> usize {
+        let max = (LengthField::MAX as usize).saturating_sub(self.header_size());
+        max - max % self.align
+    }
+
+    /// The value of the length field for a message of this size.
+    ///
+    /// Fails with `BufferUnbufferError::MessageTooLarge` if the body exceeds
+    /// `max_unpadded_body_size`, which is smaller than `MessageSize::MAX_UNPADDED_BODY_SIZE`
+    /// for alignments wider than mainline VRPN's.
+    pub fn length_field(
+        &self,
+        size: MessageSize,
+    ) -> std::result::Result<LengthField, BufferUnbufferError> {
+        let max = self.max_unpadded_body_size();
+        if size.unpadded_body_size() > max {
+            return Err(BufferUnbufferError::MessageTooLarge {
+                claimed: size.unpadded_body_size(),
+                max,
+            });
+        }
+        Ok((size.unpadded_body_size() + self.header_size()) as LengthField)
+    }
+
+    /// Get the message size from the length field, which must at least cover the header,
+    /// and not describe a body larger than `max_unpadded_body_size`.
+    pub const fn try_size_from_length_field(
+        &self,
+        length_field: LengthField,
+    ) -> std::result::Result<MessageSize, MessageSizeInvalid> {
+        if (length_field as usize) < self.header_size()
+            || length_field as usize - self.header_size() > self.max_unpadded_body_size()
+        {
+            Err(MessageSizeInvalid(length_field))
+        } else {
+            Ok(MessageSize::from_unpadded_body_size(
+                length_field as usize - self.header_size(),
+            ))
+        }
+    }
+
+    /// Number of padding bytes following the body.
+    pub const fn body_padding(&self, size: MessageSize) -> usize {
+        self.padding(size.unpadded_body_size())
+    }
+
+    /// The total size of a message on the wire: padded header plus padded body.
+    pub const fn padded_message_size(&self, size: MessageSize) -> usize {
+        self.header_size() + self.padded(size.unpadded_body_size())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vrpn_profile_matches_constants() {
+        let profile = ProtocolProfile::default();
+        assert_eq!(profile.header_size(), 24);
+        assert_eq!(profile.header_padding(), 0);
+        for len in 0..64 {
+            let size = MessageSize::from_unpadded_body_size(len);
+            assert_eq!(profile.length_field(size).unwrap(), size.length_field());
+            assert_eq!(
+                profile.padded_message_size(size),
+                size.padded_message_size()
+            );
+            assert_eq!(profile.body_padding(size), size.body_padding());
+        }
+    }
+
+    #[test]
+    fn wider_alignment() {
+        assert!(ProtocolProfile::with_alignment(6).is_none());
+        let profile = ProtocolProfile::with_alignment(16).unwrap();
+        assert_eq!(profile.header_size(), 32);
+        assert_eq!(profile.header_padding(), 8);
+        let size = profile.try_size_from_length_field(37).unwrap();
+        assert_eq!(size.unpadded_body_size(), 5);
+        assert_eq!(profile.padded_message_size(size), 48);
+        assert!(profile.try_size_from_length_field(24).is_err());
+
+        // The wider header leaves less room for the body.
+        let max = profile.max_unpadded_body_size();
+        assert_eq!(max % 16, 0);
+        assert!(max < MessageSize::MAX_UNPADDED_BODY_SIZE);
+        let largest = MessageSize::from_unpadded_body_size(max);
+        let length_field = profile.length_field(largest).unwrap();
+        assert_eq!(
+            profile.try_size_from_length_field(length_field),
+            Ok(largest)
+        );
+        assert!(profile
+            .try_size_from_length_field(length_field + 1)
+            .is_err());
+        assert!(profile
+            .length_field(MessageSize::from_unpadded_body_size(max + 1))
+            .is_err());
+    }
+
+    #[test]
+    fn sequence_number_policies() {
+        let profile = ProtocolProfile::default();
+        assert_eq!(profile.sequence_numbers(), SequenceNumberPolicy::Meaningful);
+        assert_eq!(
+            profile.sequence_number_from_slot(0),
+            Some(SequenceNumber(0))
+        );
+
+        let profile = profile.with_sequence_numbers(SequenceNumberPolicy::ZeroIsMissing);
+        assert_eq!(profile.header_size(), 24);
+        assert_eq!(profile.sequence_number_from_slot(0), None);
+        assert_eq!(
+            profile.sequence_number_from_slot(7),
+            Some(SequenceNumber(7))
+        );
+
+        let profile = profile.with_sequence_numbers(SequenceNumberPolicy::Ignored);
+        assert_eq!(profile.sequence_number_from_slot(7), None);
+    }
+}
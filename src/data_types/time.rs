@@ -6,12 +6,15 @@
  * Structures corresponding to time related types used by the original c++ implementation of VRPN.
  */
 
-use crate::buffer_unbuffer::{buffer, unbuffer, ConstantBufferSize, WrappedConstantSize};
+use crate::{
+    buffer_unbuffer::{buffer, unbuffer, ConstantBufferSize, WrappedConstantSize},
+    clock::SystemTime,
+};
 
 use bytes::{Buf, BufMut};
 use std::{
     fmt::{Debug, Display},
-    time::{Duration, SystemTime},
+    time::Duration,
 };
 
 /// Structure corresponding to the C struct time_val type.
@@ -62,8 +65,8 @@ impl Default for TimeVal {
 
 impl From<SystemTime> for TimeVal {
     fn from(v: SystemTime) -> Self {
-        // In practice this should always work.
-        let since_epoch = v.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        // Times before the epoch can't be represented, so clamp them to the epoch.
+        let since_epoch = v.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
 
         TimeVal::new(
             Seconds(since_epoch.as_secs() as i32),
@@ -0,0 +1,139 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! An optional integrity check for datagrams on unreliable transports (UDP, multicast),
+//! negotiated between peers that both use this crate.
+//!
+//! The UDP checksum is only 16 bits (and optional over IPv4), so corruption can slip through,
+//! and a corrupted pose looks just like a real one to whatever consumes it.
+//! With checks on, each datagram carries a trailing CRC-32 of its contents,
+//! verified before any message in it is decoded: a datagram that fails is dropped whole.
+//!
+//! The C++ implementation would see the trailer as a truncated message, so checks are
+//! only to be used once negotiated as `Extensions::DATAGRAM_CHECK`: see `extensions`.
+//! That extension is reserved until there is a datagram transport to use them on.
+
+use crate::{codec::MessageDecoder, data_types::SequencedGenericMessage, Result, VrpnError};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// Size of the trailer holding a datagram's CRC-32.
+pub const DATAGRAM_CHECK_LEN: usize = 4;
+
+/// CRC-32 (IEEE 802.3, as used by Ethernet and zlib) lookup table.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Compute the CRC-32 (IEEE) of some data.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        CRC32_TABLE[((crc ^ u32::from(b)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Append the check trailer to a datagram's contents, before sending it.
+pub fn seal(datagram: &mut BytesMut) {
+    let crc = crc32(datagram);
+    datagram.put_u32(crc);
+}
+
+/// Verify a received datagram against its check trailer, returning its contents without it.
+///
+/// Fails with `VrpnError::CorruptDatagram` if it is too short to have a trailer,
+/// or the trailer doesn't match.
+pub fn check(mut datagram: Bytes) -> Result<Bytes> {
+    if datagram.len() < DATAGRAM_CHECK_LEN {
+        return Err(VrpnError::CorruptDatagram);
+    }
+    let mut trailer = datagram.split_off(datagram.len() - DATAGRAM_CHECK_LEN);
+    if trailer.get_u32() != crc32(&datagram) {
+        return Err(VrpnError::CorruptDatagram);
+    }
+    Ok(datagram)
+}
+
+/// Decode all the messages in a received datagram, first verifying it if `checked`.
+///
+/// A datagram ending partway through a message is an error, as datagrams never split messages.
+pub fn decode_datagram(datagram: Bytes, checked: bool) -> Result<Vec<SequencedGenericMessage>> {
+    let datagram = if checked { check(datagram)? } else { datagram };
+    let mut decoder = MessageDecoder::with_capacity(datagram.len());
+    decoder.extend_from_slice(&datagram);
+    let mut messages = Vec::new();
+    while let Some(msg) = decoder.decode_next()? {
+        messages.push(msg);
+    }
+    if decoder.buffered_len() > 0 {
+        return Err(VrpnError::OtherMessage(format!(
+            "datagram ended with {} bytes of a partial message",
+            decoder.buffered_len()
+        )));
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer_unbuffer::BytesMutExtras,
+        data_types::{
+            id_types::{MessageTypeId, SenderId, SequenceNumber},
+            GenericBody, GenericMessage, Message, MessageHeader,
+        },
+    };
+
+    #[test]
+    fn crc32_check_value() {
+        // The standard check value for CRC-32.
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn checked_datagrams() {
+        let mut datagram = BytesMut::new();
+        for i in 0..3 {
+            let msg = GenericMessage::from_header_and_body(
+                MessageHeader::new(None, MessageTypeId(i), SenderId(0)),
+                GenericBody::new(Bytes::from_static(&[1, 2, 3, 4, 5, 6, 7, 8])),
+            )
+            .into_sequenced_message(SequenceNumber(i as u32));
+            datagram.reserve_and_buffer(&msg).unwrap();
+        }
+        let unchecked = datagram.clone().freeze();
+        seal(&mut datagram);
+        let sealed = datagram.freeze();
+        assert_eq!(sealed.len(), unchecked.len() + DATAGRAM_CHECK_LEN);
+
+        let messages = decode_datagram(sealed.clone(), true).unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(decode_datagram(unchecked, false).unwrap(), messages);
+
+        // Flip one bit of a body: it would decode fine, but fails the check.
+        let mut corrupted = BytesMut::from(&sealed[..]);
+        corrupted[30] ^= 0x10;
+        assert!(matches!(
+            decode_datagram(corrupted.freeze(), true),
+            Err(VrpnError::CorruptDatagram)
+        ));
+        assert!(check(Bytes::from_static(&[1, 2])).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Typed proxies for the devices on a connection, gathering what each device class can do
+//! (callbacks, streams, requests) behind one discoverable object per device.
+//!
+//! Pick the class with a marker type, such as `tracker::Tracker`:
+//!
+//! ```no_run
+//! # #[cfg(all(feature = "vrpn-async-std", feature = "tracker"))]
+//! # fn main() -> vrpn::Result<()> {
+//! use vrpn::{device::DeviceExt, tracker::Tracker, vrpn_async_std::connection_ip::ConnectionIp};
+//!
+//! let connection = ConnectionIp::new_client("tcp://localhost:3883".parse()?, None, None)?;
+//! let tracker = connection.device::<Tracker>("Tracker0")?;
+//! tracker.on_pose(|msg| println!("{:?}", msg.body))?;
+//! let workspace = tracker.request_workspace()?;
+//! # Ok(())
+//! # }
+//! # #[cfg(not(all(feature = "vrpn-async-std", feature = "tracker")))]
+//! # fn main() {}
+//! ```
+//!
+//! Proxies only hold a weak reference to the connection,
+//! which must keep being driven for callbacks to be called and streams to yield.
+
+use crate::{
+    data_types::{
+        id_types::{LocalId, SenderId},
+        SenderName,
+    },
+    Connection, Result,
+};
+use std::sync::Arc;
+
+/// A device class, such as `tracker::Tracker`, with the proxy type used to access such devices.
+pub trait DeviceClass<C: Connection + 'static> {
+    type Proxy;
+
+    /// Create a proxy for the device with the given sender name, already registered as `sender`.
+    fn proxy(
+        connection: &Arc<C>,
+        name: SenderName,
+        sender: LocalId<SenderId>,
+    ) -> Result<Self::Proxy>;
+}
+
+/// Get typed proxies for the devices on a connection.
+pub trait DeviceExt<C: Connection + 'static> {
+    /// Access the named device as the device class `D`.
+    fn device<D: DeviceClass<C>>(&self, name: impl Into<SenderName>) -> Result<D::Proxy>;
+}
+
+impl<C: Connection + 'static> DeviceExt<C> for Arc<C> {
+    fn device<D: DeviceClass<C>>(&self, name: impl Into<SenderName>) -> Result<D::Proxy> {
+        let name = name.into();
+        let sender = self.register_sender(name.clone())?;
+        D::proxy(self, name, sender)
+    }
+}
@@ -0,0 +1,408 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Calling handlers somewhere other than the task decoding messages: see `DispatchPolicy`.
+//!
+//! Messages wait for the workers in bounded queues, so a slow handler cannot make memory grow
+//! without bound: see `DispatchQueueConfig` for what happens when a queue is full.
+
+use crate::{
+    data_types::{id_types::SenderId, GenericMessage},
+    handler::{Handler, HandlerCode},
+    Result, VrpnError,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+};
+
+/// Where a `TypeDispatcher` calls the handlers for messages it dispatches.
+///
+/// Validators always run inline, as do handlers for user system messages.
+/// Off the decoding task, a handler error can't be returned from `TypeDispatcher::call`:
+/// it is printed instead, and recorded in the stats on the next call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DispatchPolicy {
+    /// In the task decoding messages, one after the other:
+    /// a slow handler delays every later message, from every sender.
+    #[default]
+    Inline,
+    /// On a pool of this many worker threads.
+    ///
+    /// Each handler is only called with one message at a time,
+    /// but messages may be handled in a different order than they were received.
+    ThreadPool(usize),
+    /// On a worker thread per sender, started when it first sends a message:
+    /// each sender's messages are handled in order, and a slow handler
+    /// only delays the senders it handles, as chosen with its sender filter.
+    PerSender,
+}
+
+/// What to do with a message for a dispatch worker whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OverflowPolicy {
+    /// Wait for the worker to make room. Decoding stops meanwhile,
+    /// so the remote end is slowed down by transport backpressure.
+    #[default]
+    Block,
+    /// Drop the oldest queued message of a type that only needs its latest value
+    /// (`LatestValueOnly::OutgoingAndIncoming`) to make room, or wait if there is none.
+    DropOldestLatestValue,
+    /// Fail dispatching with `VrpnError::DispatchQueueFull`, closing the endpoint it came from.
+    Disconnect,
+}
+
+/// Bounds on the queue of messages waiting for each worker (or the thread pool)
+/// when handlers are not called inline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DispatchQueueConfig {
+    /// How many messages may wait in each queue. Must not be zero.
+    pub capacity: usize,
+    /// What to do with a message when its queue is full.
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for DispatchQueueConfig {
+    fn default() -> DispatchQueueConfig {
+        DispatchQueueConfig {
+            capacity: 1024,
+            overflow: OverflowPolicy::Block,
+        }
+    }
+}
+
+impl DispatchQueueConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.capacity == 0 {
+            return Err(VrpnError::Config(
+                "a dispatch queue needs room for at least one message".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Messages found their dispatch queue full, reported as `ConnectionEvent::DispatchQueueOverflow`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DispatchQueueOverflow {
+    /// How many messages found their queue full since the last report.
+    pub messages: u64,
+    /// What was done with them, or with older messages to make room.
+    pub policy: OverflowPolicy,
+}
+
+/// A handler that may be called from worker threads.
+pub(crate) struct SharedHandler {
+    handler: Mutex<Box<dyn Handler + Send>>,
+    removed: AtomicBool,
+}
+
+impl SharedHandler {
+    pub(crate) fn new(handler: Box<dyn Handler + Send>) -> Arc<SharedHandler> {
+        Arc::new(SharedHandler {
+            handler: Mutex::new(handler),
+            removed: AtomicBool::new(false),
+        })
+    }
+
+    /// Whether the handler has asked to be removed.
+    pub(crate) fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Relaxed)
+    }
+
+    /// Call the handler, unless it has already asked to be removed.
+    fn call(&self, msg: &GenericMessage) -> Result<HandlerCode> {
+        if self.is_removed() {
+            return Ok(HandlerCode::RemoveThisHandler);
+        }
+        let code = self
+            .handler
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .handle(msg)?;
+        if code == HandlerCode::RemoveThisHandler {
+            self.removed.store(true, Ordering::Relaxed);
+        }
+        Ok(code)
+    }
+}
+
+/// A message to hand to a handler on a worker thread.
+struct Job {
+    handler: Arc<SharedHandler>,
+    msg: GenericMessage,
+    /// Whether a newer message may take its place: see `OverflowPolicy::DropOldestLatestValue`.
+    replaceable: bool,
+}
+
+#[derive(Default)]
+struct QueueState {
+    jobs: VecDeque<Job>,
+    closed: bool,
+}
+
+/// A bounded queue of jobs for one or more workers.
+#[derive(Default)]
+struct JobQueue {
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+/// How a job made it into a queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Queued {
+    Immediately,
+    /// The queue was full, and the policy made room.
+    AfterOverflow,
+}
+
+impl JobQueue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn len(&self) -> usize {
+        self.lock().jobs.len()
+    }
+
+    fn push(&self, job: Job, config: &DispatchQueueConfig) -> Result<Queued> {
+        let mut state = self.lock();
+        let mut queued = Queued::Immediately;
+        while state.jobs.len() >= config.capacity && !state.closed {
+            queued = Queued::AfterOverflow;
+            match config.overflow {
+                OverflowPolicy::Disconnect => return Err(VrpnError::DispatchQueueFull),
+                OverflowPolicy::DropOldestLatestValue => {
+                    if let Some(i) = state.jobs.iter().position(|job| job.replaceable) {
+                        state.jobs.remove(i);
+                        continue;
+                    }
+                }
+                OverflowPolicy::Block => {}
+            }
+            state = self
+                .not_full
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        if state.closed {
+            return Err(VrpnError::OtherMessage(
+                "dispatch worker has stopped".to_string(),
+            ));
+        }
+        state.jobs.push_back(job);
+        self.not_empty.notify_one();
+        Ok(queued)
+    }
+
+    /// Wait for a job, or None once closed and empty.
+    fn pop(&self) -> Option<Job> {
+        let mut state = self.lock();
+        loop {
+            if let Some(job) = state.jobs.pop_front() {
+                self.not_full.notify_one();
+                return Some(job);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self
+                .not_empty
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Let the workers finish the jobs already queued, then stop.
+    fn close(&self) {
+        self.lock().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+}
+
+/// Calls handlers as chosen by a `DispatchPolicy`.
+pub(crate) struct Executor {
+    policy: DispatchPolicy,
+    queue_config: DispatchQueueConfig,
+    /// Feeds the workers of a thread pool.
+    pool: Option<Arc<JobQueue>>,
+    /// Feeds the worker of each sender seen so far.
+    per_sender: HashMap<SenderId, Arc<JobQueue>>,
+    /// Errors returned by handlers on workers, not yet taken.
+    errors: Arc<AtomicU64>,
+    /// Messages that found their queue full, not yet taken.
+    overflows: u64,
+}
+
+impl fmt::Debug for Executor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Executor")
+            .field("policy", &self.policy)
+            .field("queue_config", &self.queue_config)
+            .field("senders", &self.per_sender.len())
+            .finish()
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Executor {
+        Executor {
+            policy: DispatchPolicy::Inline,
+            queue_config: DispatchQueueConfig::default(),
+            pool: None,
+            per_sender: HashMap::new(),
+            errors: Arc::default(),
+            overflows: 0,
+        }
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        for queue in self.pool.iter().chain(self.per_sender.values()) {
+            queue.close();
+        }
+    }
+}
+
+impl Executor {
+    /// Start an executor, including the workers of a thread pool.
+    pub(crate) fn new(
+        policy: DispatchPolicy,
+        queue_config: DispatchQueueConfig,
+    ) -> Result<Executor> {
+        queue_config.validate()?;
+        let mut executor = Executor {
+            policy,
+            queue_config,
+            pool: None,
+            per_sender: HashMap::new(),
+            errors: Arc::default(),
+            overflows: 0,
+        };
+        if let DispatchPolicy::ThreadPool(threads) = policy {
+            if threads == 0 {
+                return Err(VrpnError::Config(
+                    "a dispatch thread pool needs at least one thread".to_string(),
+                ));
+            }
+            let queue = Arc::new(JobQueue::default());
+            for i in 0..threads {
+                executor.spawn_worker(format!("vrpn-dispatch-{}", i), Arc::clone(&queue))?;
+            }
+            executor.pool = Some(queue);
+        }
+        Ok(executor)
+    }
+
+    pub(crate) fn policy(&self) -> DispatchPolicy {
+        self.policy
+    }
+
+    pub(crate) fn queue_config(&self) -> DispatchQueueConfig {
+        self.queue_config
+    }
+
+    /// Change the bounds of the queues, including those already started.
+    pub(crate) fn set_queue_config(&mut self, queue_config: DispatchQueueConfig) -> Result<()> {
+        queue_config.validate()?;
+        self.queue_config = queue_config;
+        Ok(())
+    }
+
+    /// Take the number of errors returned by handlers on workers since last time.
+    pub(crate) fn take_errors(&self) -> u64 {
+        self.errors.swap(0, Ordering::Relaxed)
+    }
+
+    /// Take the number of messages that found their queue full since last time.
+    pub(crate) fn take_overflows(&mut self) -> u64 {
+        std::mem::take(&mut self.overflows)
+    }
+
+    /// Number of messages waiting for workers, in all queues.
+    pub(crate) fn queue_depth(&self) -> usize {
+        self.pool
+            .iter()
+            .chain(self.per_sender.values())
+            .map(|queue| queue.len())
+            .sum()
+    }
+
+    /// Call a handler with a message, or queue it for a worker to do so.
+    ///
+    /// A queued call is assumed to continue processing,
+    /// unless the handler has already asked to be removed.
+    /// If the queue is full, `replaceable` says whether the message may be dropped
+    /// to make room for a later one.
+    pub(crate) fn call(
+        &mut self,
+        handler: &Arc<SharedHandler>,
+        msg: &GenericMessage,
+        replaceable: bool,
+    ) -> Result<HandlerCode> {
+        let queue = match self.policy {
+            DispatchPolicy::Inline => return handler.call(msg),
+            DispatchPolicy::ThreadPool(_) => match &self.pool {
+                Some(queue) => Arc::clone(queue),
+                None => {
+                    return Err(VrpnError::OtherMessage(
+                        "dispatch worker has stopped".to_string(),
+                    ))
+                }
+            },
+            DispatchPolicy::PerSender => self.sender_worker(msg.header.sender)?,
+        };
+        let job = Job {
+            handler: Arc::clone(handler),
+            msg: msg.clone(),
+            replaceable,
+        };
+        let queued = queue.push(job, &self.queue_config);
+        if !matches!(queued, Ok(Queued::Immediately)) {
+            self.overflows += 1;
+        }
+        queued?;
+        Ok(if handler.is_removed() {
+            HandlerCode::RemoveThisHandler
+        } else {
+            HandlerCode::ContinueProcessing
+        })
+    }
+
+    /// The queue of the worker for a sender, starting it if needed.
+    fn sender_worker(&mut self, sender: SenderId) -> Result<Arc<JobQueue>> {
+        if let Some(queue) = self.per_sender.get(&sender) {
+            return Ok(Arc::clone(queue));
+        }
+        let queue = Arc::new(JobQueue::default());
+        self.spawn_worker(
+            format!("vrpn-dispatch-sender-{}", sender.0),
+            Arc::clone(&queue),
+        )?;
+        self.per_sender.insert(sender, Arc::clone(&queue));
+        Ok(queue)
+    }
+
+    /// Start a thread calling handlers with jobs from `queue` until it is closed and empty.
+    fn spawn_worker(&self, name: String, queue: Arc<JobQueue>) -> Result<()> {
+        let errors = Arc::clone(&self.errors);
+        thread::Builder::new().name(name).spawn(move || {
+            while let Some(job) = queue.pop() {
+                if let Err(e) = job.handler.call(&job.msg) {
+                    eprintln!("Error in message handler: {}", e);
+                    errors.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        })?;
+        Ok(())
+    }
+}
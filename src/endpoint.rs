@@ -9,10 +9,13 @@ use bytes::Bytes;
 use crate::{
     buffer_unbuffer::BufferTo,
     data_types::{
-        constants, id_types::*, message::Message, ClassOfService, Description, GenericMessage,
-        IdWithNameAndDescription, LogFileNames, MessageHeader, MessageTypeId, MessageTypeName,
-        SenderName, TypedMessage, TypedMessageBody, UdpDescription,
+        constants::{SystemMessageType, UserSystemMessageType},
+        id_types::*,
+        message::Message,
+        ClassOfService, Description, GenericMessage, IdWithNameAndDescription, LogFileNames,
+        MessageTypeId, MessageTypeName, SenderName, TypedMessage, TypedMessageBody, UdpDescription,
     },
+    handler::RemoteDescription,
     translation_table::{TranslationTable, TranslationTableExt},
     type_dispatcher::TryIntoDescriptionMessage,
     Result, TranslationTables, TypeDispatcher, VrpnError,
@@ -27,6 +30,8 @@ use crate::{
 pub enum SystemCommand {
     SenderDescription(Description<SenderId>),
     TypeDescription(Description<MessageTypeId>),
+    /// A message in the range reserved by `UserSystemMessageType`, for its handlers.
+    UserSystemMessage(GenericMessage),
     Extended(ExtendedSystemCommand),
 }
 
@@ -45,32 +50,32 @@ pub fn parse_system_message(msg: GenericMessage) -> Result<SystemCommand> {
     if !msg.is_system_message() {
         return Err(VrpnError::NotSystemMessage);
     }
-    Ok(match msg.header.message_type {
-        constants::TYPE_DESCRIPTION => {
-            let msg = TypedMessage::try_from(&msg)?;
-            SystemCommand::TypeDescription(msg.into())
-        }
-        constants::SENDER_DESCRIPTION => {
-            let msg = TypedMessage::try_from(&msg)?;
-            SystemCommand::SenderDescription(msg.into())
-        }
-        constants::UDP_DESCRIPTION => {
-            let msg = TypedMessage::try_from(&msg)?;
-            SystemCommand::Extended(ExtendedSystemCommand::UdpDescription(msg.into()))
-        }
-        constants::LOG_DESCRIPTION => {
-            let msg = TypedMessage::try_from(&msg)?;
-            SystemCommand::Extended(ExtendedSystemCommand::LogDescription(msg.body))
-        }
-        constants::DISCONNECT_MESSAGE => {
-            SystemCommand::Extended(ExtendedSystemCommand::DisconnectMessage)
-        }
-        _ => {
-            return Err(VrpnError::UnrecognizedSystemMessage(
-                msg.header.message_type.get(),
-            ));
-        }
-    })
+    if UserSystemMessageType::try_from(msg.header.message_type).is_ok() {
+        return Ok(SystemCommand::UserSystemMessage(msg));
+    }
+    Ok(
+        match SystemMessageType::try_from(msg.header.message_type)? {
+            SystemMessageType::TypeDescription => {
+                let msg = TypedMessage::try_from(&msg)?;
+                SystemCommand::TypeDescription(msg.into())
+            }
+            SystemMessageType::SenderDescription => {
+                let msg = TypedMessage::try_from(&msg)?;
+                SystemCommand::SenderDescription(msg.into())
+            }
+            SystemMessageType::UdpDescription => {
+                let msg = TypedMessage::try_from(&msg)?;
+                SystemCommand::Extended(ExtendedSystemCommand::UdpDescription(msg.into()))
+            }
+            SystemMessageType::LogDescription => {
+                let msg = TypedMessage::try_from(&msg)?;
+                SystemCommand::Extended(ExtendedSystemCommand::LogDescription(msg.body))
+            }
+            SystemMessageType::DisconnectMessage => {
+                SystemCommand::Extended(ExtendedSystemCommand::DisconnectMessage)
+            }
+        },
+    )
 }
 
 /// Apply the changes from a system command to your TypeDispatcher and TranslationTables.
@@ -91,7 +96,12 @@ pub fn handle_system_command(
                 desc.name, local_id, desc.which
             );
             let table: &mut TranslationTable<SenderId> = translation_tables.as_mut();
-            let _ = table.add_remote_entry(desc.name, RemoteId(desc.which), local_id)?;
+            let _ = table.add_remote_entry(desc.name.clone(), RemoteId(desc.which), local_id)?;
+            dispatcher.call_description_handlers(&RemoteDescription::Sender {
+                name: SenderName(desc.name),
+                local_id,
+                remote_id: RemoteId(desc.which),
+            })?;
             Ok(None)
         }
         SystemCommand::TypeDescription(desc) => {
@@ -103,7 +113,16 @@ pub fn handle_system_command(
                 desc.name, local_id, desc.which
             );
             let table: &mut TranslationTable<MessageTypeId> = translation_tables.as_mut();
-            let _ = table.add_remote_entry(desc.name, RemoteId(desc.which), local_id)?;
+            let _ = table.add_remote_entry(desc.name.clone(), RemoteId(desc.which), local_id)?;
+            dispatcher.call_description_handlers(&RemoteDescription::MessageType {
+                name: MessageTypeName(desc.name),
+                local_id,
+                remote_id: RemoteId(desc.which),
+            })?;
+            Ok(None)
+        }
+        SystemCommand::UserSystemMessage(msg) => {
+            dispatcher.call_user_system_message(&msg)?;
             Ok(None)
         }
         SystemCommand::Extended(cmd) => Ok(Some(cmd)),
@@ -128,6 +147,15 @@ pub trait Endpoint /* : AsMut<TranslationTables> + AsRef<TranslationTables> */ {
     /// Queue up a generic message for sending.
     fn buffer_generic_message(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()>;
 
+    /// Queue up a generic message of a "latest value only" type for sending.
+    ///
+    /// Endpoints with an outgoing queue should drop it if a newer message
+    /// of the same type and sender is queued before it is sent:
+    /// by default, it's just queued like any other.
+    fn buffer_latest_value(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
+        self.buffer_generic_message(msg, class)
+    }
+
     /// Pack all descriptions from the dispatcher and send them.
     fn send_all_descriptions(&mut self, dispatcher: &TypeDispatcher) -> Result<()> {
         for msg in dispatcher.pack_all_descriptions()? {
@@ -226,25 +254,6 @@ where
 
     /// Convert a message with remote sender and type ID to one with local.
     fn map_remote_message_to_local(&self, msg: GenericMessage) -> Result<GenericMessage> {
-        if msg.is_system_message() {
-            // no mapping applied to system messages
-            Ok(msg)
-        } else {
-            let remote_type = RemoteId(msg.header.message_type);
-            let LocalId(new_type) = self.map_to_local_id(remote_type).ok_or_else(|| {
-                VrpnError::OtherMessage("Could not map sender to local".to_string())
-            })?;
-            let remote_sender = RemoteId(msg.header.sender);
-            let LocalId(new_sender) = self.map_to_local_id(remote_sender).ok_or_else(|| {
-                VrpnError::OtherMessage("Could not map type to local".to_string())
-            })?;
-
-            // eprintln!("user message: {:?}", msg.header);
-            let msg = GenericMessage::from_header_and_body(
-                MessageHeader::new(Some(msg.header.time), new_type, new_sender),
-                msg.body,
-            );
-            Ok(msg)
-        }
+        self.translation_tables().map_remote_message_to_local(msg)
     }
 }
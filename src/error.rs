@@ -27,16 +27,42 @@ pub enum VrpnError {
     TooManyMappings,
     #[error("handler not found")]
     HandlerNotFound,
+    #[error("cancelled before completing")]
+    Cancelled,
     #[error("could not connect")]
     CouldNotConnect,
+    #[error("timed out")]
+    Timeout,
     #[error("handler returned an error")]
     GenericErrorReturn,
     #[error("a non-system message was forwarded to Endpoint::handle_message_as_system()")]
     NotSystemMessage,
     #[error("un-recognized system message id {0}")]
     UnrecognizedSystemMessage(IdType),
+    #[error("message type {0} does not match the requested typed message body")]
+    WrongMessageType(IdType),
+    #[error("{0} was already registered, so its id cannot be assigned deterministically")]
+    AlreadyRegistered(String),
+    #[error("invalid name (contains an embedded null): {0}")]
+    InvalidName(String),
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    #[error("cannot lock the dispatcher from one of its own handlers")]
+    CalledDuringDispatch,
+    #[error("too many messages are waiting to be dispatched")]
+    DispatchQueueFull,
+    #[error("sender {0} has been retired")]
+    SenderRetired(IdType),
     #[error("endpoint is closed or closing")]
     EndpointClosed,
+    #[error("datagram failed its integrity check")]
+    CorruptDatagram,
+    #[error(
+        "quarantined after more than {} parse errors within {:?}",
+        .0.max_errors,
+        .0.window
+    )]
+    Quarantined(crate::vrpn_async::ParseErrorLimit),
     #[error("{0}")]
     MessageSizeInvalid(MessageSizeInvalid),
     #[error("{0}")]
@@ -100,6 +126,15 @@ impl VrpnError {
     pub fn is_need_more_data(&self) -> bool {
         self.try_get_size_requirement().is_some()
     }
+
+    /// Whether the operation that failed might succeed if tried again,
+    /// as with I/O errors and timeouts, but not protocol errors like version mismatches.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            VrpnError::IoError(_) | VrpnError::CouldNotConnect | VrpnError::Timeout
+        )
+    }
 }
 
 impl<T> From<std::sync::PoisonError<T>> for VrpnError {
@@ -109,8 +144,8 @@ impl<T> From<std::sync::PoisonError<T>> for VrpnError {
 }
 
 impl From<MessageSizeInvalid> for VrpnError {
-    fn from(_: MessageSizeInvalid) -> Self {
-        todo!()
+    fn from(v: MessageSizeInvalid) -> Self {
+        VrpnError::MessageSizeInvalid(v)
     }
 }
 
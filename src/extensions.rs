@@ -0,0 +1,180 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Negotiating optional extensions to the protocol between peers that both use this crate.
+//!
+//! After the handshake, each side with any extensions enabled sends one `ExtensionOffer`
+//! over the reliable channel, listing them. Those offered by both sides are agreed,
+//! and only agreed extensions may change what goes over the wire:
+//! see `ConnectionIp::set_extensions` and `ConnectionIp::agreed_extensions`.
+//!
+//! This keeps C++ peers working:
+//!
+//! - The offer is a message of a named type, which the C++ implementation drops
+//!   as it has no handler for it.
+//! - A C++ peer never offers anything, so nothing is agreed with it,
+//!   and every extension stays off.
+//! - Extensions are bits, and an offer's unknown bits are ignored,
+//!   so newer peers can add extensions without confusing older ones.
+
+use crate::{
+    buffer_unbuffer::{BufferResult, BufferTo, ConstantBufferSize, UnbufferFrom, UnbufferResult},
+    data_types::{MessageTypeIdentifier, StaticMessageTypeName, TypedMessageBody},
+};
+use bytes::{Buf, BufMut};
+use std::net::SocketAddr;
+
+/// Type name of `ExtensionOffer`.
+pub const EXTENSION_OFFER: StaticMessageTypeName = StaticMessageTypeName(b"vrpn_Rust Extensions");
+
+bitflags! {
+    /// Optional extensions to the protocol, only used once both peers offer them.
+    #[derive(Default)]
+    pub struct Extensions : u32 {
+        /// Compressing message bodies.
+        ///
+        /// Reserved: nothing in this crate compresses yet.
+        const COMPRESSION = (1 << 0);
+        /// A CRC-32 trailing each datagram: see `datagram_check`.
+        ///
+        /// Reserved: nothing in this crate sends or receives datagrams yet.
+        const DATAGRAM_CHECK = (1 << 1);
+        /// Sending `DeviceMetadata` describing each device: see `metadata`.
+        const METADATA = (1 << 2);
+        /// Dropping messages superseded by newer ones before sending them,
+        /// for the types chosen with `Connection::set_latest_value_only`.
+        const LATEST_VALUE = (1 << 3);
+    }
+}
+
+/// Sent over the reliable channel, once, to list the extensions a peer would use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtensionOffer {
+    pub extensions: Extensions,
+}
+
+impl ExtensionOffer {
+    pub fn new(extensions: Extensions) -> ExtensionOffer {
+        ExtensionOffer { extensions }
+    }
+}
+
+impl TypedMessageBody for ExtensionOffer {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(EXTENSION_OFFER);
+}
+
+impl ConstantBufferSize for ExtensionOffer {
+    fn constant_buffer_size() -> usize {
+        u32::constant_buffer_size()
+    }
+}
+
+impl BufferTo for ExtensionOffer {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        self.extensions.bits().buffer_to(buf)
+    }
+}
+
+impl UnbufferFrom for ExtensionOffer {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        let bits = u32::unbuffer_from(buf)?;
+        Ok(ExtensionOffer {
+            extensions: Extensions::from_bits_truncate(bits),
+        })
+    }
+}
+
+/// Negotiation state of extensions with one peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExtensionNegotiation {
+    offered: Option<Extensions>,
+    peer_offered: Option<Extensions>,
+    reported: bool,
+}
+
+impl ExtensionNegotiation {
+    /// Note that we sent an offer.
+    pub fn offered(&mut self, extensions: Extensions) {
+        self.offered = Some(extensions);
+    }
+
+    /// Note an offer received from the peer.
+    pub fn peer_offered(&mut self, offer: &ExtensionOffer) {
+        self.peer_offered = Some(offer.extensions);
+    }
+
+    /// The extensions both sides offered, once both have sent their offer.
+    ///
+    /// Stays `None` if either side never offers, as a C++ peer never does.
+    pub fn agreed(&self) -> Option<Extensions> {
+        Some(self.offered? & self.peer_offered?)
+    }
+
+    /// The agreed extensions, only the first time they are known.
+    pub fn take_newly_agreed(&mut self) -> Option<Extensions> {
+        if self.reported {
+            return None;
+        }
+        let agreed = self.agreed()?;
+        self.reported = true;
+        Some(agreed)
+    }
+}
+
+/// The extensions agreed with one peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AgreedExtensions {
+    /// The address of the remote end, if connected over TCP.
+    pub peer: Option<SocketAddr>,
+    pub extensions: Extensions,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_unbuffer::BytesMutExtras;
+    use bytes::{Bytes, BytesMut};
+
+    #[test]
+    fn negotiation() {
+        let mut negotiation = ExtensionNegotiation::default();
+        negotiation.peer_offered(&ExtensionOffer::new(
+            Extensions::DATAGRAM_CHECK | Extensions::METADATA,
+        ));
+        assert_eq!(negotiation.agreed(), None);
+        assert_eq!(negotiation.take_newly_agreed(), None);
+        negotiation.offered(Extensions::DATAGRAM_CHECK | Extensions::LATEST_VALUE);
+        assert_eq!(negotiation.agreed(), Some(Extensions::DATAGRAM_CHECK));
+        assert_eq!(
+            negotiation.take_newly_agreed(),
+            Some(Extensions::DATAGRAM_CHECK)
+        );
+        assert_eq!(negotiation.take_newly_agreed(), None);
+
+        // Offering nothing still completes the negotiation, agreeing on nothing.
+        let mut negotiation = ExtensionNegotiation::default();
+        negotiation.offered(Extensions::all());
+        negotiation.peer_offered(&ExtensionOffer::new(Extensions::empty()));
+        assert_eq!(negotiation.agreed(), Some(Extensions::empty()));
+    }
+
+    #[test]
+    fn unknown_extensions_ignored() {
+        let offer = ExtensionOffer::new(Extensions::COMPRESSION | Extensions::METADATA);
+        let mut buf = BytesMut::new();
+        buf.reserve_and_buffer(&offer).unwrap();
+        assert_eq!(
+            ExtensionOffer::unbuffer_from(&mut buf.freeze()).unwrap(),
+            offer
+        );
+
+        // From a newer peer.
+        let mut buf = Bytes::from_static(&[0x80, 0, 0, 0x04]);
+        assert_eq!(
+            ExtensionOffer::unbuffer_from(&mut buf).unwrap().extensions,
+            Extensions::METADATA
+        );
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! A connection that writes everything packed on it to a log file, without any network.
+//!
+//! Useful for generating `.vrpn` logs offline, e.g. from a simulation,
+//! and for testing code that sends messages without a peer.
+
+use crate::{
+    buffer_unbuffer::BytesMutExtras,
+    clock::{SharedClock, SystemClock},
+    connection::{Connection, ConnectionCore, ConnectionStatus},
+    data_types::{id_types::SequenceNumber, ClassOfService, CookieData, GenericMessage},
+    endpoint::{Endpoint, SystemCommand},
+    Result, TranslationTables, VrpnError,
+};
+use bytes::BytesMut;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::Arc,
+};
+
+/// Endpoint that serializes every message it is given to a writer, in log file format.
+#[derive(Debug)]
+pub struct EndpointFileSink<W: Write> {
+    translation: TranslationTables,
+    writer: W,
+    seq: u32,
+}
+
+impl<W: Write> EndpointFileSink<W> {
+    /// Wrap a writer, writing the log file cookie to it first.
+    pub fn new(mut writer: W) -> Result<EndpointFileSink<W>> {
+        let buf = BytesMut::allocate_and_buffer(CookieData::make_file_cookie())?;
+        writer.write_all(&buf)?;
+        Ok(EndpointFileSink {
+            translation: TranslationTables::new(),
+            writer,
+            seq: 0,
+        })
+    }
+}
+
+impl<W: Write> Endpoint for EndpointFileSink<W> {
+    fn translation_tables(&self) -> &TranslationTables {
+        &self.translation
+    }
+
+    fn translation_tables_mut(&mut self) -> &mut TranslationTables {
+        &mut self.translation
+    }
+
+    fn send_system_change(&self, _message: SystemCommand) -> Result<()> {
+        // Nobody on the other side to tell.
+        Ok(())
+    }
+
+    fn buffer_generic_message(
+        &mut self,
+        msg: GenericMessage,
+        _class: ClassOfService,
+    ) -> Result<()> {
+        let buf = msg
+            .into_sequenced_message(SequenceNumber(self.seq))
+            .try_into_buf()?;
+        self.seq = self.seq.wrapping_add(1);
+        self.writer.write_all(&buf)?;
+        Ok(())
+    }
+}
+
+/// A connection whose only "endpoint" is a log file.
+///
+/// Registering senders and types writes their descriptions,
+/// and packing a message writes it immediately (through any buffering in `W`).
+#[derive(Debug)]
+pub struct FileSinkConnection<W: Write + Send> {
+    core: ConnectionCore<EndpointFileSink<W>>,
+}
+
+/// A connection that discards everything packed on it.
+pub type NullConnection = FileSinkConnection<io::Sink>;
+
+impl<W: Write + Send> FileSinkConnection<W> {
+    /// Create a connection writing to the given writer, stamping messages with the system clock.
+    pub fn new(writer: W) -> Result<Arc<FileSinkConnection<W>>> {
+        Self::new_with_clock(writer, SystemClock::shared())
+    }
+
+    /// Like `new()`, but with a specific time source, e.g. simulation time.
+    pub fn new_with_clock(writer: W, clock: SharedClock) -> Result<Arc<FileSinkConnection<W>>> {
+        let endpoint = EndpointFileSink::new(writer)?;
+        Ok(Arc::new(FileSinkConnection {
+            core: ConnectionCore::new_with_clock(vec![Some(endpoint)], None, None, clock),
+        }))
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&self) -> Result<()> {
+        let endpoints = self.endpoints();
+        let mut endpoints = endpoints.lock()?;
+        for ep in endpoints.iter_mut().flatten() {
+            ep.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flush and return the underlying writer.
+    ///
+    /// Requires the only reference to the connection: drop any device objects holding it first.
+    pub fn into_writer(self) -> Result<W> {
+        let endpoints = Arc::try_unwrap(self.core.endpoints)
+            .map_err(|_| VrpnError::OtherMessage("endpoint list is still shared".to_string()))?
+            .into_inner()?;
+        let mut ep = endpoints
+            .into_iter()
+            .flatten()
+            .next()
+            .ok_or(VrpnError::EndpointClosed)?;
+        ep.writer.flush()?;
+        Ok(ep.writer)
+    }
+}
+
+impl FileSinkConnection<BufWriter<File>> {
+    /// Create (or truncate) a log file at the given path and write to it.
+    pub fn create(path: impl AsRef<Path>) -> Result<Arc<FileSinkConnection<BufWriter<File>>>> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl NullConnection {
+    /// Create a connection that discards everything.
+    pub fn null() -> Result<Arc<NullConnection>> {
+        Self::new(io::sink())
+    }
+}
+
+impl<W: Write + Send> Connection for FileSinkConnection<W> {
+    type SpecificEndpoint = EndpointFileSink<W>;
+
+    fn connection_core(&self) -> &ConnectionCore<Self::SpecificEndpoint> {
+        &self.core
+    }
+
+    fn status(&self) -> ConnectionStatus {
+        // Like a server with a single client that is always there.
+        ConnectionStatus::Server(1)
+    }
+}
+
+#[cfg(all(test, feature = "tracker"))]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer_unbuffer::UnbufferFrom,
+        codec::MessageDecoder,
+        data_types::{
+            cookie::check_ver_file_compatible, id_types::Sensor, Message, Quat, TypedMessage, Vec3,
+            Version,
+        },
+        tracker::PoseReport,
+    };
+    use bytes::Bytes;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn writes_log() {
+        let connection = FileSinkConnection::new(Vec::new()).unwrap();
+        let sender = connection.register_sender("Tracker0").unwrap();
+        let report = PoseReport {
+            sensor: Sensor(0),
+            pos: Vec3::new(1.0, 2.0, 3.0),
+            quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+        };
+        connection
+            .pack_message_body(None, sender, report.clone(), ClassOfService::RELIABLE)
+            .unwrap();
+        let written = Arc::try_unwrap(connection).unwrap().into_writer().unwrap();
+
+        let mut data = Bytes::from(written);
+        let cookie = CookieData::unbuffer_from(&mut data).unwrap();
+        check_ver_file_compatible(Version::from(cookie)).unwrap();
+
+        let mut decoder = MessageDecoder::new();
+        decoder.extend_from_slice(&data);
+        let mut messages = Vec::new();
+        while let Some(msg) = decoder.decode_next().unwrap() {
+            messages.push(msg.into_inner());
+        }
+        // Sender description, type description, then the report.
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].is_system_message());
+        assert!(messages[1].is_system_message());
+        let msg = TypedMessage::<PoseReport>::try_from(&messages[2]).unwrap();
+        assert_eq!(msg.body, report);
+    }
+
+    #[test]
+    fn null() {
+        let connection = NullConnection::null().unwrap();
+        let sender = connection.register_sender("Tracker0").unwrap();
+        connection
+            .pack_message_body(
+                None,
+                sender,
+                PoseReport {
+                    sensor: Sensor(0),
+                    pos: Vec3::new(0.0, 0.0, 0.0),
+                    quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+                },
+                ClassOfService::RELIABLE,
+            )
+            .unwrap();
+        connection.flush().unwrap();
+    }
+}
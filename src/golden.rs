@@ -0,0 +1,145 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Golden wire-format fixtures.
+//!
+//! Each example below is encoded and compared against a hex dump in `fixtures/wire/`.
+//! The same files are included in the rustdoc of the corresponding types,
+//! so the documented wire format is always the tested one.
+//!
+//! After an intentional wire format change (or to add an example),
+//! regenerate the files with `VRPN_BLESS_FIXTURES=1 cargo test golden`,
+//! and review the diff.
+
+use crate::{
+    buffer_unbuffer::BufferTo,
+    data_types::{
+        id_types::{SenderId, Sensor, SequenceNumber},
+        CookieData, Description, GenericMessage, MessageTypeId, Microseconds, Quat, Seconds,
+        TimeVal, TypedMessage, TypedMessageBody, Vec3,
+    },
+    text::{TextMessage, TextSeverity},
+    tracker::PoseReport,
+};
+use bytes::{Bytes, BytesMut};
+use std::{convert::TryFrom, fs, path::PathBuf};
+
+const BYTES_PER_LINE: usize = 16;
+
+fn fixture_path(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "fixtures", "wire", name]
+        .iter()
+        .collect::<PathBuf>()
+        .with_extension("hex")
+}
+
+/// Render bytes as a hex dump: space-separated, a fixed number of bytes per line.
+pub(crate) fn to_hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(BYTES_PER_LINE)
+        .map(|line| {
+            line.iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ")
+                + "\n"
+        })
+        .collect()
+}
+
+/// Parse a hex dump as produced by `to_hex_dump`, ignoring whitespace.
+pub(crate) fn from_hex_dump(dump: &str) -> Vec<u8> {
+    dump.split_whitespace()
+        .map(|b| u8::from_str_radix(b, 16).expect("invalid hex in fixture"))
+        .collect()
+}
+
+/// Compare bytes to the named fixture, or write the fixture if blessing.
+pub(crate) fn check_fixture(name: &str, bytes: &[u8]) {
+    let path = fixture_path(name);
+    if std::env::var_os("VRPN_BLESS_FIXTURES").is_some() {
+        fs::write(&path, to_hex_dump(bytes)).expect("could not write fixture");
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("could not read fixture {}: {}", path.display(), e));
+    assert_eq!(
+        to_hex_dump(bytes),
+        to_hex_dump(&from_hex_dump(&expected)),
+        "wire format of {} differs from fixture",
+        name
+    );
+}
+
+/// Encode a full message the way it goes on the wire, with a fixed time and sequence number.
+fn encode_message<T: TypedMessageBody + BufferTo>(
+    message_type: MessageTypeId,
+    sender: SenderId,
+    body: T,
+) -> Bytes {
+    let time = TimeVal::new(Seconds(1000), Microseconds(0));
+    let msg = TypedMessage::new(Some(time), message_type, sender, body);
+    GenericMessage::try_from(msg)
+        .unwrap()
+        .into_sequenced_message(SequenceNumber(0))
+        .try_into_buf()
+        .unwrap()
+}
+
+fn encode_body<T: BufferTo>(body: &T) -> Bytes {
+    let mut buf = BytesMut::with_capacity(body.buffer_size());
+    body.buffer_to(&mut buf).unwrap();
+    buf.freeze()
+}
+
+/// All examples, by fixture name.
+fn examples() -> Vec<(&'static str, Bytes)> {
+    vec![
+        ("cookie", encode_body(&CookieData::make_cookie())),
+        ("description", {
+            let desc = Description::from_id_and_name(SenderId(0), Bytes::from_static(b"Tracker0"));
+            let msg = TypedMessage::from(desc);
+            encode_message(msg.header.message_type, msg.header.sender, msg.body)
+        }),
+        (
+            "pose",
+            encode_message(
+                MessageTypeId(1),
+                SenderId(0),
+                PoseReport {
+                    sensor: Sensor(0),
+                    pos: Vec3::new(1.0, 2.0, 3.0),
+                    quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+                },
+            ),
+        ),
+        (
+            "text",
+            encode_message(
+                MessageTypeId(2),
+                SenderId(0),
+                TextMessage {
+                    severity: TextSeverity::Warning,
+                    level: 0,
+                    text: Bytes::from_static(b"Hello"),
+                },
+            ),
+        ),
+    ]
+}
+
+#[test]
+fn golden_wire_format() {
+    for (name, bytes) in examples() {
+        check_fixture(name, &bytes);
+    }
+}
+
+#[test]
+fn hex_dump_roundtrip() {
+    let bytes: Vec<u8> = (0..40).collect();
+    let dump = to_hex_dump(&bytes);
+    assert_eq!(dump.lines().count(), 3);
+    assert_eq!(from_hex_dump(&dump), bytes);
+}
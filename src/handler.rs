@@ -2,13 +2,17 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-pub use crate::type_dispatcher::HandlerHandle;
+pub use crate::type_dispatcher::{DescriptionHandlerHandle, HandlerHandle};
 use crate::{
     buffer_unbuffer::{EmptyMessage, UnbufferFrom},
-    data_types::{GenericMessage, MessageHeader, TypedMessage, TypedMessageBody},
+    data_types::{
+        id_types::{LocalId, MessageTypeId, RemoteId, SenderId},
+        GenericMessage, MessageHeader, MessageTypeName, SenderName, TypedMessage, TypedMessageBody,
+    },
+    translation_table::InvalidatedMappings,
     Result,
 };
-use std::{convert::TryFrom, fmt};
+use std::{convert::TryFrom, fmt, marker::PhantomData};
 
 /// Return from a Handler (or its related traits),
 /// indicating whether the handler that just executed should be kept around for the future.
@@ -16,7 +20,12 @@ use std::{convert::TryFrom, fmt};
 pub enum HandlerCode {
     /// Keeps the handler in the list.
     ContinueProcessing,
-    /// Removes the handler.
+    /// Removes the handler, e.g. once it has received what it was waiting for.
+    ///
+    /// The dispatcher drops it and never calls it again. Under a `DispatchPolicy` that calls
+    /// handlers on worker threads, it is dropped when the next message of its type is dispatched,
+    /// but is not called for messages already queued for it.
+    /// Its handle is no longer valid: `remove_handler` then fails with `HandlerNotFound`.
     RemoveThisHandler,
 }
 
@@ -46,6 +55,42 @@ where
     }
 }
 
+/// A typed handler calling a closure with each message, and never removing itself.
+pub struct CallbackHandler<T, F> {
+    callback: F,
+    item: PhantomData<fn(&T)>,
+}
+
+impl<T, F> CallbackHandler<T, F>
+where
+    F: FnMut(&TypedMessage<T>) + Send + Sync,
+{
+    pub fn new(callback: F) -> CallbackHandler<T, F> {
+        CallbackHandler {
+            callback,
+            item: PhantomData,
+        }
+    }
+}
+
+impl<T, F> fmt::Debug for CallbackHandler<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CallbackHandler").finish_non_exhaustive()
+    }
+}
+
+impl<T, F> TypedHandler for CallbackHandler<T, F>
+where
+    T: TypedMessageBody + UnbufferFrom + fmt::Debug,
+    F: FnMut(&TypedMessage<T>) + Send + Sync,
+{
+    type Item = T;
+    fn handle_typed(&mut self, msg: &TypedMessage<T>) -> Result<HandlerCode> {
+        (self.callback)(msg);
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
 /// A trait implemented by structs that can handle typed messages with no body.
 ///
 /// A blanket impl for Handler exists for all types implementing this trait,
@@ -66,3 +111,28 @@ where
         self.handle_typed_bodyless(&msg.header)
     }
 }
+
+/// A sender or message type described by a remote endpoint, once mapped to a local ID.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum RemoteDescription {
+    Sender {
+        name: SenderName,
+        local_id: LocalId<SenderId>,
+        remote_id: RemoteId<SenderId>,
+    },
+    MessageType {
+        name: MessageTypeName,
+        local_id: LocalId<MessageTypeId>,
+        remote_id: RemoteId<MessageTypeId>,
+    },
+    /// A remote endpoint went away, so nothing it described maps to a local ID any more.
+    ///
+    /// If it comes back, it describes its senders and types again.
+    RemoteLost(InvalidatedMappings),
+}
+
+/// A trait implemented by structs that want to know about senders and message types
+/// as remote endpoints describe them, e.g. to discover what devices a server offers.
+pub trait DescriptionHandler: Send + Sync {
+    fn handle_description(&mut self, desc: &RemoteDescription) -> Result<HandlerCode>;
+}
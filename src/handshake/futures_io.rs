@@ -0,0 +1,270 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! The handshake over a `futures` stream, e.g. a TCP or Unix socket from async-std or smol.
+
+use super::Handshake;
+#[cfg(feature = "async-std")]
+use super::Role;
+use crate::{
+    buffer_unbuffer::{BytesMutExtras, UnbufferFrom},
+    data_types::cookie::{check_ver_file_compatible, check_ver_nonfile_compatible, CookieData},
+    VrpnError,
+};
+use bytes::{Bytes, BytesMut};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(feature = "async-std")]
+use std::time::Duration;
+
+/// Writes the supplied cookie to a stream.
+async fn write_cookie<T>(stream: &mut T, cookie: CookieData) -> Result<(), VrpnError>
+where
+    T: AsyncWrite + Unpin,
+{
+    let buf = BytesMut::allocate_and_buffer(cookie)?.freeze();
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+pub use crate::async_io::read_cookie;
+
+/// Writes the "non-file" magic cookie to the stream.
+pub async fn send_nonfile_cookie<T>(stream: &mut T) -> Result<(), VrpnError>
+where
+    T: AsyncWrite + Unpin,
+{
+    write_cookie(stream, CookieData::make_cookie()).await
+}
+
+/// Writes the "file" magic cookie to the stream.
+pub async fn send_file_cookie<T>(stream: &mut T) -> Result<(), VrpnError>
+where
+    T: AsyncWrite + Unpin,
+{
+    write_cookie(stream, CookieData::make_file_cookie()).await
+}
+
+/// Reads a cookie's worth of data from the stream, and checks to make sure it is the right version.
+pub async fn read_and_check_nonfile_cookie<T>(stream: &mut T) -> Result<(), VrpnError>
+where
+    T: AsyncRead + Unpin,
+{
+    let read_buf: Vec<u8> = read_cookie(stream).await?;
+    let mut buf = Bytes::from(read_buf);
+    let msg = CookieData::unbuffer_from(&mut buf)?;
+    check_ver_nonfile_compatible(msg.version)?;
+    Ok(())
+}
+
+/// Reads a cookie's worth of data from the stream, and checks to make sure it is the right version.
+pub async fn read_and_check_file_cookie<T>(stream: &mut T) -> Result<(), VrpnError>
+where
+    T: AsyncRead + Unpin,
+{
+    let read_buf: Vec<u8> = read_cookie(stream).await?;
+    let mut buf = Bytes::from(read_buf);
+    let msg = CookieData::unbuffer_from(&mut buf)?;
+    check_ver_file_compatible(msg.version)?;
+    Ok(())
+}
+
+/// Drives a handshake to completion over a stream.
+///
+/// Reads only as much as the handshake needs, so the stream is left at the first message.
+pub async fn perform_handshake<T>(
+    stream: &mut T,
+    handshake: &mut Handshake,
+) -> Result<(), VrpnError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut incoming = Vec::new();
+    loop {
+        let outgoing = handshake.advance(&incoming)?;
+        if !outgoing.is_empty() {
+            stream.write_all(&outgoing).await?;
+        }
+        if handshake.is_complete() {
+            stream.flush().await?;
+            return Ok(());
+        }
+        incoming.resize(handshake.bytes_needed(), 0);
+        stream.read_exact(&mut incoming).await?;
+    }
+}
+
+/// Perform the client side of the handshake, the side that connected,
+/// failing with `VrpnError::Timeout` if it takes longer than `timeout`.
+///
+/// The completed handshake has the remote cookie, and the sequence number to continue from.
+#[cfg(feature = "async-std")]
+pub async fn client<T>(stream: &mut T, timeout: Option<Duration>) -> Result<Handshake, VrpnError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    perform_with_timeout(stream, Handshake::new(Role::Client), timeout).await
+}
+
+/// Perform the server side of the handshake, the side that accepted the connection,
+/// failing with `VrpnError::Timeout` if it takes longer than `timeout`.
+///
+/// To describe senders and message types during the handshake, configure a `Handshake::server`
+/// and use `perform_with_timeout` instead.
+///
+/// A custom server accepting connections itself performs the server side, then frames messages:
+///
+/// ```no_run
+/// # async fn serve(listener: async_std::net::TcpListener) -> vrpn::Result<()> {
+/// use std::time::Duration;
+/// use vrpn::{handshake::futures_io, vrpn_async::framed_messages};
+/// let (mut stream, _) = listener.accept().await?;
+/// futures_io::server(&mut stream, Some(Duration::from_secs(5))).await?;
+/// let (messages, sink) = framed_messages(stream);
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "async-std")]
+pub async fn server<T>(stream: &mut T, timeout: Option<Duration>) -> Result<Handshake, VrpnError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    perform_with_timeout(stream, Handshake::new(Role::Server), timeout).await
+}
+
+/// Drive a handshake to completion, or fail with `VrpnError::Timeout` once `timeout` has passed.
+///
+/// A stream that timed out may be part way through the handshake: close it.
+#[cfg(feature = "async-std")]
+pub async fn perform_with_timeout<T>(
+    stream: &mut T,
+    mut handshake: Handshake,
+    timeout: Option<Duration>,
+) -> Result<Handshake, VrpnError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let performed = perform_handshake(stream, &mut handshake);
+    match timeout {
+        Some(timeout) => crate::vrpn_async_std::retry::with_timeout(timeout, performed).await?,
+        None => performed.await?,
+    }
+    Ok(handshake)
+}
+
+#[cfg(test)]
+#[cfg(feature = "async-std")]
+mod tests {
+    use crate::{
+        buffer_unbuffer::{BytesMutExtras, ConstantBufferSize},
+        data_types::{constants::COOKIE_SIZE, CookieData},
+    };
+    use async_std::task;
+    use bytes::{Bytes, BytesMut};
+    use futures::io::Cursor;
+
+    fn get_cookie_buf(file_cookie: bool) -> Bytes {
+        assert_eq!(CookieData::constant_buffer_size(), COOKIE_SIZE);
+        BytesMut::allocate_and_buffer(if file_cookie {
+            CookieData::make_file_cookie()
+        } else {
+            CookieData::make_cookie()
+        })
+        .expect("should buffer cookie")
+        .freeze()
+    }
+
+    #[test]
+    fn read_cookie() {
+        {
+            let cookie = get_cookie_buf(false);
+            let mut reader = Cursor::new(&cookie[..]);
+            let read_buf = task::block_on(super::read_cookie(&mut reader)).unwrap();
+            assert_eq!(CookieData::constant_buffer_size(), read_buf.len());
+            assert_eq!(&cookie[..], &read_buf[..]);
+        }
+        {
+            let cookie = get_cookie_buf(true);
+            let mut reader = Cursor::new(&cookie[..]);
+            let read_buf = task::block_on(super::read_cookie(&mut reader)).unwrap();
+            assert_eq!(CookieData::constant_buffer_size(), read_buf.len());
+            assert_eq!(&cookie[..], &read_buf[..]);
+        }
+    }
+
+    #[test]
+    fn check_cookie() {
+        {
+            let cookie = get_cookie_buf(false);
+            let mut reader = Cursor::new(&cookie[..]);
+            task::block_on(super::read_and_check_nonfile_cookie(&mut reader))
+                .expect("checking cookie should pass");
+        }
+        {
+            let cookie = get_cookie_buf(true);
+            let mut reader = Cursor::new(&cookie[..]);
+            task::block_on(super::read_and_check_file_cookie(&mut reader))
+                .expect("checking cookie should pass");
+        }
+    }
+
+    #[test]
+    fn write_cookie() {
+        {
+            let mut writer = Cursor::new(vec![0u8; COOKIE_SIZE]);
+            task::block_on(super::send_nonfile_cookie(&mut writer)).unwrap();
+            let write_buf = writer.into_inner();
+            assert_eq!(&get_cookie_buf(false), &write_buf);
+        }
+        {
+            let mut writer = Cursor::new(vec![0u8; COOKIE_SIZE]);
+            task::block_on(super::send_file_cookie(&mut writer)).unwrap();
+            let write_buf = writer.into_inner();
+            assert_eq!(&get_cookie_buf(true), &write_buf);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn handshake_over_socket() {
+        use async_std::os::unix::net::UnixStream;
+        use futures::join;
+        task::block_on(async {
+            let (mut a, mut b) = UnixStream::pair().unwrap();
+            let mut client = super::Handshake::client();
+            let mut server = super::Handshake::server();
+            let (client_result, server_result) = join!(
+                super::perform_handshake(&mut a, &mut client),
+                super::perform_handshake(&mut b, &mut server)
+            );
+            client_result.unwrap();
+            server_result.unwrap();
+            assert!(client.remote_cookie().is_some());
+            assert!(server.remote_cookie().is_some());
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timed_client_and_server() {
+        use crate::VrpnError;
+        use async_std::os::unix::net::UnixStream;
+        use futures::join;
+        use std::time::Duration;
+        task::block_on(async {
+            let (mut a, mut b) = UnixStream::pair().unwrap();
+            let timeout = Some(Duration::from_secs(5));
+            let (client, server) = join!(
+                super::client(&mut a, timeout),
+                super::server(&mut b, timeout)
+            );
+            assert_eq!(client.unwrap().role(), super::Role::Client);
+            assert!(server.unwrap().remote_cookie().is_some());
+
+            // A server that never answers.
+            let (mut a, _b) = UnixStream::pair().unwrap();
+            let result = super::client(&mut a, Some(Duration::from_millis(50))).await;
+            assert!(matches!(result, Err(VrpnError::Timeout)));
+        });
+    }
+}
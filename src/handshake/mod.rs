@@ -0,0 +1,318 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! The connection handshake, as a state machine independent of any IO.
+//!
+//! Each side sends a magic cookie and checks the one it gets back.
+//! Once the cookies check out, each side may ask the other to log (a log description message),
+//! and sends descriptions of the senders and message types it already knows about.
+//!
+//! Transports feed whatever bytes they receive to `Handshake::advance`,
+//! and write out whatever it returns, until `Handshake::is_complete`.
+//! Reading no more than `Handshake::bytes_needed` at a time means nothing
+//! past the handshake is consumed from the stream.
+//!
+//! Custom integrations rarely need to drive it by hand: `futures_io` performs it over
+//! any `futures` stream (such as async-std's), and `tokio_io` over any tokio stream,
+//! as either side, optionally with a time limit.
+//! For blocking IO, see `crate::sync_io::perform_handshake`.
+
+use crate::{
+    buffer_unbuffer::{BytesMutExtras, UnbufferFrom},
+    data_types::{
+        constants::{COOKIE_SIZE, LOG_DESCRIPTION},
+        cookie::check_ver_nonfile_compatible,
+        id_types::{SenderId, SequenceNumber},
+        CookieData, GenericMessage, LogFileNames, MessageHeader, ProtocolProfile, TimeVal,
+    },
+    Result,
+};
+use bytes::{Bytes, BytesMut};
+
+pub mod futures_io;
+#[cfg(feature = "async-tokio")]
+pub mod tokio_io;
+
+/// Which side of the connection we are, which determines who sends their cookie first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    /// Sends the cookie right away.
+    Client,
+    /// Waits for the client's cookie before sending its own.
+    Server,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    AwaitingCookie,
+    Complete,
+}
+
+/// Sans-IO handshake state machine.
+#[derive(Debug)]
+pub struct Handshake {
+    role: Role,
+    state: State,
+    log_request: Option<LogFileNames>,
+    descriptions: Vec<GenericMessage>,
+    profile: ProtocolProfile,
+    received: BytesMut,
+    remote_cookie: Option<CookieData>,
+    leftover: Bytes,
+    next_sequence: u32,
+}
+
+impl Handshake {
+    pub fn new(role: Role) -> Handshake {
+        Handshake {
+            role,
+            state: State::Start,
+            log_request: None,
+            descriptions: Vec::new(),
+            profile: ProtocolProfile::VRPN,
+            received: BytesMut::with_capacity(COOKIE_SIZE),
+            remote_cookie: None,
+            leftover: Bytes::new(),
+            next_sequence: 0,
+        }
+    }
+
+    /// Handshake for the side that initiated the connection.
+    pub fn client() -> Handshake {
+        Handshake::new(Role::Client)
+    }
+
+    /// Handshake for the side that accepted the connection.
+    pub fn server() -> Handshake {
+        Handshake::new(Role::Server)
+    }
+
+    /// Ask the remote side to log to the given files.
+    ///
+    /// Nothing is sent if no names are set.
+    pub fn with_log_request(mut self, names: LogFileNames) -> Handshake {
+        self.log_request = Some(names);
+        self
+    }
+
+    /// Send these description messages once the cookies have been exchanged.
+    pub fn with_descriptions(
+        mut self,
+        descriptions: impl IntoIterator<Item = GenericMessage>,
+    ) -> Handshake {
+        self.descriptions.extend(descriptions);
+        self
+    }
+
+    /// Frame the messages following the cookie according to this profile instead of
+    /// `ProtocolProfile::VRPN`.
+    pub fn with_profile(mut self, profile: ProtocolProfile) -> Handshake {
+        self.profile = profile;
+        self
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// True once our cookie and follow-up messages have been produced and the remote cookie checked.
+    pub fn is_complete(&self) -> bool {
+        self.state == State::Complete
+    }
+
+    /// The cookie received from the remote side, once it has been checked.
+    pub fn remote_cookie(&self) -> Option<CookieData> {
+        self.remote_cookie
+    }
+
+    /// Number of incoming bytes required before the handshake can make progress.
+    ///
+    /// Zero if the handshake can advance without any more input, or is complete.
+    pub fn bytes_needed(&self) -> usize {
+        match self.state {
+            State::Start if self.role == Role::Client => 0,
+            State::Start | State::AwaitingCookie => COOKIE_SIZE - self.received.len(),
+            State::Complete => 0,
+        }
+    }
+
+    /// The sequence number to use for the next message sent after the handshake.
+    pub fn next_sequence_number(&self) -> SequenceNumber {
+        SequenceNumber(self.next_sequence)
+    }
+
+    /// Take any bytes received beyond the end of the remote cookie.
+    ///
+    /// These are the start of the message stream, and must be handed to its parser.
+    pub fn take_leftover(&mut self) -> Bytes {
+        std::mem::take(&mut self.leftover)
+    }
+
+    /// Feed in newly received bytes, and get back the bytes to send, if any.
+    ///
+    /// Call with an empty slice to get a client's initial cookie.
+    /// Once complete, any further input is kept as leftover.
+    pub fn advance(&mut self, incoming: &[u8]) -> Result<Vec<u8>> {
+        let mut outgoing = Vec::new();
+        if self.state == State::Complete {
+            self.append_leftover(incoming);
+            return Ok(outgoing);
+        }
+        if self.state == State::Start {
+            if self.role == Role::Client {
+                outgoing
+                    .extend_from_slice(&BytesMut::allocate_and_buffer(CookieData::make_cookie())?);
+            }
+            self.state = State::AwaitingCookie;
+        }
+
+        let wanted = (COOKIE_SIZE - self.received.len()).min(incoming.len());
+        self.received.extend_from_slice(&incoming[..wanted]);
+        if self.received.len() < COOKIE_SIZE {
+            return Ok(outgoing);
+        }
+
+        let mut cookie_buf = self.received.split().freeze();
+        let cookie = CookieData::unbuffer_from(&mut cookie_buf)?;
+        check_ver_nonfile_compatible(cookie.version)?;
+        self.remote_cookie = Some(cookie);
+
+        if self.role == Role::Server {
+            outgoing.extend_from_slice(&BytesMut::allocate_and_buffer(CookieData::make_cookie())?);
+        }
+        self.append_followup(&mut outgoing)?;
+        self.state = State::Complete;
+        self.append_leftover(&incoming[wanted..]);
+        Ok(outgoing)
+    }
+
+    fn append_leftover(&mut self, extra: &[u8]) {
+        if extra.is_empty() {
+            return;
+        }
+        let mut leftover = BytesMut::from(&self.leftover[..]);
+        leftover.extend_from_slice(extra);
+        self.leftover = leftover.freeze();
+    }
+
+    /// Serialize the log request and descriptions, in that order.
+    fn append_followup(&mut self, outgoing: &mut Vec<u8>) -> Result<()> {
+        let mut messages = Vec::new();
+        if let Some(names) = self.log_request.take() {
+            let mode = names.log_mode();
+            if !mode.is_empty() {
+                // The log mode goes in the sender field.
+                messages.push(GenericMessage::from_header_and_typed_body(
+                    MessageHeader::new(
+                        Some(TimeVal::get_time_of_day()),
+                        LOG_DESCRIPTION,
+                        SenderId(i32::from(mode.bits())),
+                    ),
+                    &names,
+                )?);
+            }
+        }
+        messages.append(&mut self.descriptions);
+        for msg in messages {
+            let seq = SequenceNumber(self.next_sequence);
+            self.next_sequence += 1;
+            msg.into_sequenced_message(seq)
+                .buffer_to_with_profile(outgoing, &self.profile)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{Message, SequencedGenericMessage, Version},
+        TypeDispatcher, VrpnError,
+    };
+
+    /// Run two handshakes against each other, returning what each sent after its cookie.
+    fn exchange(client: &mut Handshake, server: &mut Handshake) -> (Bytes, Bytes) {
+        let to_server = client.advance(&[]).unwrap();
+        assert_eq!(to_server.len(), COOKIE_SIZE);
+        assert!(!client.is_complete());
+        assert_eq!(client.bytes_needed(), COOKIE_SIZE);
+
+        let to_client = server.advance(&to_server).unwrap();
+        assert!(server.is_complete());
+        let client_extra = client.advance(&to_client).unwrap();
+        assert!(client.is_complete());
+        assert_eq!(client.bytes_needed(), 0);
+        (Bytes::from(client_extra), client.take_leftover())
+    }
+
+    #[test]
+    fn client_server_pair() {
+        let mut client = Handshake::client()
+            .with_log_request(LogFileNames::from_names(Some("in.vrpn"), None::<&str>));
+        let mut dispatcher = TypeDispatcher::new();
+        dispatcher.register_sender("Tracker0").unwrap();
+        let descriptions: Vec<_> = dispatcher.pack_all_descriptions().unwrap().collect();
+        let num_descriptions = descriptions.len() as u32;
+        let mut server = Handshake::server().with_descriptions(descriptions);
+
+        let (mut client_extra, server_extra) = exchange(&mut client, &mut server);
+        assert_eq!(
+            client.remote_cookie().unwrap().version,
+            CookieData::make_cookie().version
+        );
+        assert!(server.remote_cookie().is_some());
+
+        // The client asked the server to log.
+        let log = SequencedGenericMessage::try_read_from_buf(&mut client_extra).unwrap();
+        assert!(client_extra.is_empty());
+        assert_eq!(log.message().header.message_type, LOG_DESCRIPTION);
+        assert_eq!(log.message().header.sender, SenderId(1));
+        assert_eq!(client.next_sequence_number(), SequenceNumber(1));
+
+        // The server's descriptions followed its cookie, so they were leftover for the client.
+        let mut server_extra = server_extra;
+        for i in 0..num_descriptions {
+            let desc = SequencedGenericMessage::try_read_from_buf(&mut server_extra).unwrap();
+            assert_eq!(desc.sequence_number, SequenceNumber(i));
+            assert!(desc.message().is_system_message());
+        }
+        assert!(server_extra.is_empty());
+        assert_eq!(
+            server.next_sequence_number(),
+            SequenceNumber(num_descriptions)
+        );
+    }
+
+    #[test]
+    fn split_input() {
+        let mut client = Handshake::client();
+        let mut server = Handshake::server();
+        let to_server = client.advance(&[]).unwrap();
+        for (i, byte) in to_server.iter().enumerate() {
+            assert_eq!(server.bytes_needed(), COOKIE_SIZE - i);
+            let out = server.advance(std::slice::from_ref(byte)).unwrap();
+            assert_eq!(out.is_empty(), i + 1 < COOKIE_SIZE);
+        }
+        assert!(server.is_complete());
+        assert!(server.take_leftover().is_empty());
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut server = Handshake::server();
+        let mut cookie = CookieData::make_cookie();
+        cookie.version = Version {
+            major: cookie.version.major + 1,
+            minor: 0,
+        };
+        let buf = BytesMut::allocate_and_buffer(cookie).unwrap();
+        assert!(matches!(
+            server.advance(&buf),
+            Err(VrpnError::VersionMismatch(_))
+        ));
+        assert!(!server.is_complete());
+    }
+}
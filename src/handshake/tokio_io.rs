@@ -0,0 +1,157 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! The handshake over a tokio stream: the counterpart of `super::futures_io`.
+
+use super::{Handshake, Role};
+use crate::{
+    buffer_unbuffer::{BytesMutExtras, ConstantBufferSize, UnbufferFrom},
+    data_types::cookie::{check_ver_file_compatible, check_ver_nonfile_compatible, CookieData},
+    VrpnError,
+};
+use bytes::{Bytes, BytesMut};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Writes the supplied cookie to a stream.
+async fn write_cookie<T>(stream: &mut T, cookie: CookieData) -> Result<(), VrpnError>
+where
+    T: AsyncWrite + Unpin,
+{
+    let buf = BytesMut::allocate_and_buffer(cookie)?.freeze();
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Reads a cookie's worth of data into a temporary buffer.
+pub async fn read_cookie<T>(stream: &mut T) -> Result<Vec<u8>, VrpnError>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut buf = vec![0u8; CookieData::constant_buffer_size()];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Writes the "non-file" magic cookie to the stream.
+pub async fn send_nonfile_cookie<T>(stream: &mut T) -> Result<(), VrpnError>
+where
+    T: AsyncWrite + Unpin,
+{
+    write_cookie(stream, CookieData::make_cookie()).await
+}
+
+/// Writes the "file" magic cookie to the stream.
+pub async fn send_file_cookie<T>(stream: &mut T) -> Result<(), VrpnError>
+where
+    T: AsyncWrite + Unpin,
+{
+    write_cookie(stream, CookieData::make_file_cookie()).await
+}
+
+/// Reads a cookie's worth of data from the stream, and checks to make sure it is the right version.
+pub async fn read_and_check_nonfile_cookie<T>(stream: &mut T) -> Result<(), VrpnError>
+where
+    T: AsyncRead + Unpin,
+{
+    let read_buf: Vec<u8> = read_cookie(stream).await?;
+    let mut buf = Bytes::from(read_buf);
+    let msg = CookieData::unbuffer_from(&mut buf)?;
+    check_ver_nonfile_compatible(msg.version)?;
+    Ok(())
+}
+
+/// Reads a cookie's worth of data from the stream, and checks to make sure it is the right version.
+pub async fn read_and_check_file_cookie<T>(stream: &mut T) -> Result<(), VrpnError>
+where
+    T: AsyncRead + Unpin,
+{
+    let read_buf: Vec<u8> = read_cookie(stream).await?;
+    let mut buf = Bytes::from(read_buf);
+    let msg = CookieData::unbuffer_from(&mut buf)?;
+    check_ver_file_compatible(msg.version)?;
+    Ok(())
+}
+
+/// Drives a handshake to completion over a stream.
+///
+/// Reads only as much as the handshake needs, so the stream is left at the first message.
+pub async fn perform_handshake<T>(
+    stream: &mut T,
+    handshake: &mut Handshake,
+) -> Result<(), VrpnError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut incoming = Vec::new();
+    loop {
+        let outgoing = handshake.advance(&incoming)?;
+        if !outgoing.is_empty() {
+            stream.write_all(&outgoing).await?;
+        }
+        if handshake.is_complete() {
+            stream.flush().await?;
+            return Ok(());
+        }
+        incoming.resize(handshake.bytes_needed(), 0);
+        stream.read_exact(&mut incoming).await?;
+    }
+}
+
+/// Perform the client side of the handshake, the side that connected,
+/// failing with `VrpnError::Timeout` if it takes longer than `timeout`.
+pub async fn client<T>(stream: &mut T, timeout: Option<Duration>) -> Result<Handshake, VrpnError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    perform_with_timeout(stream, Handshake::new(Role::Client), timeout).await
+}
+
+/// Perform the server side of the handshake, the side that accepted the connection,
+/// failing with `VrpnError::Timeout` if it takes longer than `timeout`.
+pub async fn server<T>(stream: &mut T, timeout: Option<Duration>) -> Result<Handshake, VrpnError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    perform_with_timeout(stream, Handshake::new(Role::Server), timeout).await
+}
+
+/// Drive a handshake to completion, or fail with `VrpnError::Timeout` once `timeout` has passed.
+///
+/// Must be called within a tokio runtime with timers enabled.
+pub async fn perform_with_timeout<T>(
+    stream: &mut T,
+    mut handshake: Handshake,
+    timeout: Option<Duration>,
+) -> Result<Handshake, VrpnError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let performed = perform_handshake(stream, &mut handshake);
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, performed)
+            .await
+            .unwrap_or(Err(VrpnError::Timeout))?,
+        None => performed.await?,
+    }
+    Ok(handshake)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn timed_client_and_server() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        let timeout = Some(Duration::from_secs(5));
+        let (client, server) = tokio::join!(client(&mut a, timeout), server(&mut b, timeout));
+        assert!(client.unwrap().remote_cookie().is_some());
+        assert!(server.unwrap().remote_cookie().is_some());
+
+        let (mut a, _b) = tokio::io::duplex(1024);
+        let result = client(&mut a, Some(Duration::from_millis(50))).await;
+        assert!(matches!(result, Err(VrpnError::Timeout)));
+    }
+}
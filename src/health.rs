@@ -0,0 +1,172 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! A composite health check for a connection, like `vrpn_Connection::doing_okay()` in VRPN.
+//!
+//! A connection is doing okay if none of its checks find a problem:
+//! a client must have completed its handshake and still have its server,
+//! the remote side must have been heard from recently (if `HealthConfig::max_silence` is set),
+//! and its ping client, if any, must not consider the remote side unresponsive.
+//! A server is doing okay while listening, even with no clients.
+
+use crate::{connection::ConnectionStatus, VrpnError};
+use std::{fmt, time::Duration};
+
+/// Which checks a health assessment makes, beyond the state of the sockets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HealthConfig {
+    /// How long the remote side may go without sending anything, if limited.
+    ///
+    /// Off by default: a device with nothing to report may legitimately be silent.
+    /// Pongs count as traffic, so with pinging on, this can be shorter than the ping interval.
+    pub max_silence: Option<Duration>,
+}
+
+impl HealthConfig {
+    /// Check that the maximum silence, if any, is non-zero.
+    pub fn validate(&self) -> Result<(), VrpnError> {
+        if self.max_silence == Some(Duration::ZERO) {
+            return Err(VrpnError::Config(
+                "maximum silence must be non-zero".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Something keeping a connection from doing okay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HealthProblem {
+    /// A client is still connecting or handshaking, possibly again after losing its server.
+    Connecting,
+    /// A client lost its server, and isn't reconnecting.
+    Disconnected,
+    /// Nothing was received for longer than `HealthConfig::max_silence`.
+    Silent,
+    /// The ping client considers the remote side unresponsive.
+    Unresponsive,
+}
+
+impl fmt::Display for HealthProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HealthProblem::Connecting => "connecting",
+            HealthProblem::Disconnected => "disconnected",
+            HealthProblem::Silent => "silent",
+            HealthProblem::Unresponsive => "unresponsive",
+        })
+    }
+}
+
+/// The outcome of a health assessment: the problems found, if any, in a consistent order.
+///
+/// Reported as `ConnectionEvent::HealthChanged` whenever the problems found change.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Health {
+    problems: Vec<HealthProblem>,
+}
+
+impl Health {
+    /// Assess a connection from its status, number of open endpoints,
+    /// time since it last received anything (None if not applicable),
+    /// and whether its ping client considers the remote side unresponsive.
+    pub fn assess(
+        config: &HealthConfig,
+        status: ConnectionStatus,
+        endpoints: usize,
+        silence: Option<Duration>,
+        unresponsive: bool,
+    ) -> Health {
+        let mut problems = Vec::new();
+        match status {
+            ConnectionStatus::ClientConnecting => problems.push(HealthProblem::Connecting),
+            ConnectionStatus::ClientConnected if endpoints == 0 => {
+                problems.push(HealthProblem::Disconnected)
+            }
+            _ => {}
+        }
+        if let (Some(max), Some(silence)) = (config.max_silence, silence) {
+            if silence > max {
+                problems.push(HealthProblem::Silent);
+            }
+        }
+        if unresponsive {
+            problems.push(HealthProblem::Unresponsive);
+        }
+        Health { problems }
+    }
+
+    /// True if no problems were found.
+    pub fn doing_okay(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// The problems found.
+    pub fn problems(&self) -> &[HealthProblem] {
+        &self.problems
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.problems.is_empty() {
+            return f.write_str("okay");
+        }
+        for (i, problem) in self.problems.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assess() {
+        let config = HealthConfig::default();
+        let okay = Health::assess(&config, ConnectionStatus::ClientConnected, 1, None, false);
+        assert!(okay.doing_okay());
+        assert_eq!(okay.to_string(), "okay");
+
+        // Servers are fine without clients, clients aren't without their server.
+        assert!(Health::assess(&config, ConnectionStatus::Server(0), 0, None, false).doing_okay());
+        let lost = Health::assess(&config, ConnectionStatus::ClientConnected, 0, None, false);
+        assert_eq!(lost.problems(), &[HealthProblem::Disconnected]);
+        let connecting = Health::assess(&config, ConnectionStatus::ClientConnecting, 0, None, true);
+        assert_eq!(
+            connecting.problems(),
+            &[HealthProblem::Connecting, HealthProblem::Unresponsive]
+        );
+        assert_eq!(connecting.to_string(), "connecting, unresponsive");
+
+        // Silence only counts once limited.
+        let silence = Some(Duration::from_secs(5));
+        assert!(Health::assess(
+            &config,
+            ConnectionStatus::ClientConnected,
+            1,
+            silence,
+            false
+        )
+        .doing_okay());
+        let config = HealthConfig {
+            max_silence: Some(Duration::from_secs(2)),
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            Health::assess(&config, ConnectionStatus::Server(1), 1, silence, false).problems(),
+            &[HealthProblem::Silent]
+        );
+        assert!(HealthConfig {
+            max_silence: Some(Duration::ZERO)
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Desktop input devices: `vrpn_Mouse` and `vrpn_Keyboard`.
+//!
+//! Neither has message types of its own.
+//! A mouse reports its position as analog channels 0 and 1 (each normalized to `[0, 1]`
+//! across the screen) and its buttons as button changes.
+//! A keyboard is a button device whose button indices are key scan codes.
+//! The remotes here subscribe to those messages from one sender,
+//! and present them as a stream of events.
+
+use crate::{
+    analog::AnalogReport,
+    buffer_unbuffer::UnbufferFrom,
+    button::ButtonChange,
+    data_types::{
+        id_types::{LocalId, SenderId},
+        SenderName, TypedMessage, TypedMessageBody,
+    },
+    handler::{HandlerCode, HandlerHandle, TypedHandler},
+    Connection, Result,
+};
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    Stream, StreamExt,
+};
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{Context, Poll},
+};
+
+/// A mouse button, by the index `vrpn_Mouse` uses for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+    Other(i32),
+}
+
+impl From<i32> for MouseButton {
+    fn from(button: i32) -> MouseButton {
+        match button {
+            0 => MouseButton::Left,
+            1 => MouseButton::Middle,
+            2 => MouseButton::Right,
+            other => MouseButton::Other(other),
+        }
+    }
+}
+
+/// Something that happened to a remote mouse.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MouseEvent {
+    /// The pointer moved, to normalized screen coordinates.
+    Motion {
+        x: f64,
+        y: f64,
+    },
+    Button {
+        button: MouseButton,
+        pressed: bool,
+    },
+}
+
+/// A key of a remote keyboard was pressed or released.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct KeyEvent {
+    /// Platform-specific scan code.
+    pub scan_code: i32,
+    pub pressed: bool,
+}
+
+/// Forwards the messages of one type that convert to events into a channel.
+struct ForwardHandler<B, E> {
+    tx: UnboundedSender<E>,
+    convert: fn(&B) -> Option<E>,
+}
+
+impl<B, E> fmt::Debug for ForwardHandler<B, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ForwardHandler").finish()
+    }
+}
+
+impl<B, E> TypedHandler for ForwardHandler<B, E>
+where
+    B: TypedMessageBody + UnbufferFrom + fmt::Debug + Send + Sync,
+    E: Send + Sync,
+{
+    type Item = B;
+    fn handle_typed(&mut self, msg: &TypedMessage<B>) -> Result<HandlerCode> {
+        if let Some(event) = (self.convert)(&msg.body) {
+            if self.tx.unbounded_send(event).is_err() {
+                // The remote was dropped.
+                return Ok(HandlerCode::RemoveThisHandler);
+            }
+        }
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
+fn add_forward_handler<T, B, E>(
+    connection: &T,
+    sender: LocalId<SenderId>,
+    tx: &UnboundedSender<E>,
+    convert: fn(&B) -> Option<E>,
+) -> Result<HandlerHandle>
+where
+    T: Connection,
+    B: TypedMessageBody + UnbufferFrom + fmt::Debug + Send + Sync + 'static,
+    E: Send + Sync + 'static,
+{
+    connection.add_typed_handler(
+        Box::new(ForwardHandler {
+            tx: tx.clone(),
+            convert,
+        }),
+        Some(sender),
+    )
+}
+
+fn mouse_motion(report: &AnalogReport) -> Option<MouseEvent> {
+    match report.values[..] {
+        [x, y, ..] => Some(MouseEvent::Motion { x, y }),
+        _ => None,
+    }
+}
+
+fn mouse_button(change: &ButtonChange) -> Option<MouseEvent> {
+    Some(MouseEvent::Button {
+        button: MouseButton::from(change.button),
+        pressed: change.pressed,
+    })
+}
+
+fn key(change: &ButtonChange) -> Option<KeyEvent> {
+    Some(KeyEvent {
+        scan_code: change.button,
+        pressed: change.pressed,
+    })
+}
+
+/// Events from a remote `vrpn_Mouse`, in the order received.
+///
+/// Only holds a weak reference to the connection, which must keep being driven for events to arrive.
+#[derive(Debug)]
+pub struct MouseRemote<T: Connection + 'static> {
+    connection: Weak<T>,
+    events: UnboundedReceiver<MouseEvent>,
+    handlers: [HandlerHandle; 2],
+}
+
+impl<T: Connection + 'static> MouseRemote<T> {
+    pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<MouseRemote<T>> {
+        let (tx, events) = unbounded();
+        let handlers = [
+            add_forward_handler(&*connection, sender, &tx, mouse_motion)?,
+            add_forward_handler(&*connection, sender, &tx, mouse_button)?,
+        ];
+        Ok(MouseRemote {
+            connection: Arc::downgrade(&connection),
+            events,
+            handlers,
+        })
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName>,
+        connection: Arc<T>,
+    ) -> Result<MouseRemote<T>> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection)
+    }
+
+    /// The next event already received, if any.
+    pub fn try_next_event(&mut self) -> Option<MouseEvent> {
+        self.events.try_next().ok().flatten()
+    }
+
+    /// Stop receiving events.
+    pub fn shutdown(self) -> Result<()> {
+        if let Some(connection) = self.connection.upgrade() {
+            for handler in self.handlers {
+                connection.remove_handler(handler)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Connection + 'static> Stream for MouseRemote<T> {
+    type Item = MouseEvent;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<MouseEvent>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+/// Events from a remote `vrpn_Keyboard`, in the order received.
+///
+/// Only holds a weak reference to the connection, which must keep being driven for events to arrive.
+#[derive(Debug)]
+pub struct KeyboardRemote<T: Connection + 'static> {
+    connection: Weak<T>,
+    events: UnboundedReceiver<KeyEvent>,
+    handler: HandlerHandle,
+}
+
+impl<T: Connection + 'static> KeyboardRemote<T> {
+    pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<KeyboardRemote<T>> {
+        let (tx, events) = unbounded();
+        let handler = add_forward_handler(&*connection, sender, &tx, key)?;
+        Ok(KeyboardRemote {
+            connection: Arc::downgrade(&connection),
+            events,
+            handler,
+        })
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName>,
+        connection: Arc<T>,
+    ) -> Result<KeyboardRemote<T>> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection)
+    }
+
+    /// The next event already received, if any.
+    pub fn try_next_event(&mut self) -> Option<KeyEvent> {
+        self.events.try_next().ok().flatten()
+    }
+
+    /// Stop receiving events.
+    pub fn shutdown(self) -> Result<()> {
+        if let Some(connection) = self.connection.upgrade() {
+            connection.remove_handler(self.handler)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Connection + 'static> Stream for KeyboardRemote<T> {
+    type Item = KeyEvent;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<KeyEvent>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analog::ANALOG_CHANNEL, button::BUTTON_CHANGE, testing::MockConnection};
+
+    #[test]
+    fn mouse_and_keyboard_events() {
+        let connection = MockConnection::new();
+        let mut mouse = MouseRemote::new_from_name("Mouse0", Arc::clone(&connection)).unwrap();
+        let mut keyboard =
+            KeyboardRemote::new_from_name("Keyboard0", Arc::clone(&connection)).unwrap();
+        let mouse_id = connection.register_sender("Mouse0").unwrap();
+        let keyboard_id = connection.register_sender("Keyboard0").unwrap();
+        let analog_type = connection.register_type(ANALOG_CHANNEL).unwrap();
+        let button_type = connection.register_type(BUTTON_CHANGE).unwrap();
+
+        connection
+            .receive(TypedMessage::new(
+                None,
+                analog_type,
+                mouse_id,
+                AnalogReport {
+                    values: vec![0.25, 0.75],
+                },
+            ))
+            .unwrap();
+        let click = ButtonChange {
+            button: 2,
+            pressed: true,
+        };
+        connection
+            .receive(TypedMessage::new(None, button_type, mouse_id, click))
+            .unwrap();
+        let key_press = ButtonChange {
+            button: 30,
+            pressed: false,
+        };
+        connection
+            .receive(TypedMessage::new(None, button_type, keyboard_id, key_press))
+            .unwrap();
+
+        assert_eq!(
+            mouse.try_next_event(),
+            Some(MouseEvent::Motion { x: 0.25, y: 0.75 })
+        );
+        assert_eq!(
+            mouse.try_next_event(),
+            Some(MouseEvent::Button {
+                button: MouseButton::Right,
+                pressed: true
+            })
+        );
+        assert_eq!(mouse.try_next_event(), None);
+        assert_eq!(
+            keyboard.try_next_event(),
+            Some(KeyEvent {
+                scan_code: 30,
+                pressed: false
+            })
+        );
+        assert_eq!(keyboard.try_next_event(), None);
+
+        mouse.shutdown().unwrap();
+        keyboard.shutdown().unwrap();
+    }
+}
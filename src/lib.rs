@@ -2,6 +2,9 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
+// Protocol code must report malformed input as an error, not panic.
+#![warn(clippy::unwrap_used, clippy::expect_used)]
+
 extern crate bytes;
 extern crate url;
 
@@ -33,33 +36,80 @@ pub mod vrpn_tokio;
 #[cfg(feature = "async-std")]
 pub mod vrpn_async_std;
 
+#[cfg(feature = "analog")]
+pub mod analog;
+pub mod async_io;
+#[cfg(feature = "bevy")]
+pub mod bevy_plugin;
 pub mod buffer_unbuffer;
+#[cfg(feature = "button")]
+pub mod button;
 pub mod data_types;
+pub mod datagram_check;
+pub mod device;
+pub mod dispatch_executor;
 
-mod codec;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod clock;
+pub mod codec;
+#[cfg(feature = "config")]
+pub mod config;
 pub mod connection;
 pub mod constants;
 pub mod endpoint;
 pub mod error;
+pub mod extensions;
+pub mod file_sink;
+#[cfg(test)]
+#[cfg(all(feature = "text", feature = "tracker"))]
+mod golden;
 pub mod handler;
+pub mod handshake;
+pub mod health;
+#[cfg(feature = "input")]
+pub mod input;
+pub mod message_display;
+#[cfg(feature = "metadata")]
+pub mod metadata;
 mod name_registration;
 mod parse_name;
 pub mod ping;
 #[deprecated]
 pub mod prelude;
+pub mod protocol;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod reorder;
+#[doc(hidden)]
+pub mod schema;
+pub mod stats;
+pub mod subscription;
 pub mod sync_io;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+#[cfg(feature = "text")]
+pub mod text;
+#[cfg(feature = "tracker")]
 pub mod tracker;
 pub mod translation_table;
+pub mod type_check;
 pub mod type_dispatcher;
+pub mod validation;
 pub mod vrpn_async;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 pub use crate::{
-    connection::{Connection, ConnectionStatus},
+    connection::{Connection, ConnectionEvent, ConnectionStatus},
+    dispatch_executor::{DispatchPolicy, DispatchQueueConfig, OverflowPolicy},
     endpoint::*,
     error::{Result, VrpnError},
-    handler::{Handler, TypedBodylessHandler, TypedHandler},
-    parse_name::{Scheme, ServerInfo},
-    type_dispatcher::{RegisterMapping, TypeDispatcher},
+    handler::{DescriptionHandler, Handler, TypedBodylessHandler, TypedHandler},
+    parse_name::{DeviceInfo, Scheme, ServerInfo},
+    type_dispatcher::{
+        DuplicateNamePolicy, LatestValueOnly, RegisterMapping, RegisteredIds, TypeDispatcher,
+    },
 };
 
 pub(crate) use crate::translation_table::TranslationTables;
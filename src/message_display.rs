@@ -0,0 +1,311 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Human-readable formatting of messages, showing sender and type names rather than numeric IDs.
+//!
+//! Names are resolved when formatting with `WithNames::with_names`, from anything implementing
+//! `NameLookup`: a `TypeDispatcher` for messages with local IDs,
+//! or an endpoint's `TranslationTables` for messages still carrying the remote end's IDs.
+//!
+//! ```
+//! use vrpn::{
+//!     data_types::{GenericBody, GenericMessage, Message, MessageHeader},
+//!     message_display::WithNames,
+//!     TypeDispatcher,
+//! };
+//! let mut dispatcher = TypeDispatcher::new();
+//! let sender = dispatcher.register_sender("Tracker0")?.into_inner();
+//! let message_type = dispatcher.register_type("vrpn_Tracker Pos_Quat")?.into_inner();
+//! let msg = GenericMessage::from_header_and_body(
+//!     MessageHeader::new(None, message_type, sender),
+//!     GenericBody::default(),
+//! );
+//! let summary = msg.with_names(&dispatcher).to_string();
+//! assert!(summary.starts_with("vrpn_Tracker Pos_Quat from Tracker0 at "));
+//! # Ok::<(), vrpn::VrpnError>(())
+//! ```
+
+use crate::{
+    buffer_unbuffer::BufferSize,
+    data_types::{
+        constants::{SystemMessageType, UserSystemMessageType},
+        id_types::{LocalId, MessageTypeId, RemoteId, SenderId},
+        GenericBody, Message, MessageTypeName, SenderName, TypedMessageBody,
+    },
+    translation_table::TranslationTable,
+    TranslationTables, TypeDispatcher,
+};
+use std::{convert::TryFrom, fmt};
+
+/// Something that knows the names of sender and message type IDs.
+pub trait NameLookup {
+    /// The name of a sender ID, if known.
+    fn sender_name(&self, id: SenderId) -> Option<SenderName>;
+
+    /// The name of a (non-system) message type ID, if known.
+    fn message_type_name(&self, id: MessageTypeId) -> Option<MessageTypeName>;
+}
+
+/// Names nothing: IDs are shown as numbers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoNames;
+
+impl NameLookup for NoNames {
+    fn sender_name(&self, _id: SenderId) -> Option<SenderName> {
+        None
+    }
+
+    fn message_type_name(&self, _id: MessageTypeId) -> Option<MessageTypeName> {
+        None
+    }
+}
+
+/// Looks up local IDs.
+impl NameLookup for TypeDispatcher {
+    fn sender_name(&self, id: SenderId) -> Option<SenderName> {
+        self.get_sender_name(LocalId(id))
+    }
+
+    fn message_type_name(&self, id: MessageTypeId) -> Option<MessageTypeName> {
+        self.get_type_name(LocalId(id))
+    }
+}
+
+/// Looks up remote IDs, as described by the remote end.
+impl NameLookup for TranslationTables {
+    fn sender_name(&self, id: SenderId) -> Option<SenderName> {
+        let table: &TranslationTable<SenderId> = self.as_ref();
+        table.remote_name(RemoteId(id)).cloned().map(SenderName)
+    }
+
+    fn message_type_name(&self, id: MessageTypeId) -> Option<MessageTypeName> {
+        let table: &TranslationTable<MessageTypeId> = self.as_ref();
+        table
+            .remote_name(RemoteId(id))
+            .cloned()
+            .map(MessageTypeName)
+    }
+}
+
+/// A sender ID with its name, if known: displays as the name, or the ID if unnamed.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SenderLabel {
+    pub id: SenderId,
+    pub name: Option<SenderName>,
+}
+
+impl SenderLabel {
+    pub fn new<N: NameLookup + ?Sized>(id: SenderId, names: &N) -> SenderLabel {
+        SenderLabel {
+            id,
+            name: names.sender_name(id),
+        }
+    }
+}
+
+impl fmt::Display for SenderLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "sender {}", self.id.0),
+        }
+    }
+}
+
+impl fmt::Debug for SenderLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{:?} ({})", name.to_string(), self.id.0),
+            None => write!(f, "{}", self.id.0),
+        }
+    }
+}
+
+/// A message type ID with its name, if known: displays as the name,
+/// the kind of system message, or the ID if unnamed.
+#[derive(Clone, PartialEq, Eq)]
+pub struct TypeLabel {
+    pub id: MessageTypeId,
+    pub name: Option<MessageTypeName>,
+}
+
+impl TypeLabel {
+    pub fn new<N: NameLookup + ?Sized>(id: MessageTypeId, names: &N) -> TypeLabel {
+        let name = if id.is_system_message() {
+            None
+        } else {
+            names.message_type_name(id)
+        };
+        TypeLabel { id, name }
+    }
+}
+
+impl fmt::Display for TypeLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.name {
+            return write!(f, "{}", name);
+        }
+        if let Ok(system) = SystemMessageType::try_from(self.id) {
+            return write!(f, "{:?}", system);
+        }
+        if let Ok(user) = UserSystemMessageType::try_from(self.id) {
+            return write!(f, "user system message {}", user.index());
+        }
+        write!(f, "type {}", self.id.0)
+    }
+}
+
+impl fmt::Debug for TypeLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} ({})", self.to_string(), self.id.0)
+    }
+}
+
+/// How a message body appears in a message's summary.
+pub trait BodySummary {
+    fn fmt_summary(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+/// Not yet decoded: only the size is shown.
+impl BodySummary for GenericBody {
+    fn fmt_summary(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes", self.buffer_size())
+    }
+}
+
+impl<T: TypedMessageBody + fmt::Debug> BodySummary for T {
+    fn fmt_summary(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+struct Summary<'a, B>(&'a B);
+
+impl<B: BodySummary> fmt::Debug for Summary<'_, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt_summary(f)
+    }
+}
+
+/// A message with its sender and type names resolved, for formatting.
+///
+/// `Display` gives a one-line summary, e.g. `vrpn_Tracker Pos_Quat from Tracker0 at 12.5: ...`,
+/// and `Debug` the same fields as a struct.
+pub struct NamedMessage<'a, M> {
+    message: &'a M,
+    pub sender: SenderLabel,
+    pub message_type: TypeLabel,
+}
+
+impl<M: Message> fmt::Display for NamedMessage<'_, M>
+where
+    M::Body: BodySummary,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} from {} at {}: ",
+            self.message_type,
+            self.sender,
+            self.message.header_ref().time
+        )?;
+        self.message.body_ref().fmt_summary(f)
+    }
+}
+
+impl<M: Message> fmt::Debug for NamedMessage<'_, M>
+where
+    M::Body: BodySummary,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Message")
+            .field("time", &self.message.header_ref().time)
+            .field("sender", &self.sender)
+            .field("message_type", &self.message_type)
+            .field("body", &Summary(self.message.body_ref()))
+            .finish()
+    }
+}
+
+/// Format messages with resolved names: see `NamedMessage`.
+pub trait WithNames: Message {
+    /// Resolve the names of this message's sender and type, for formatting.
+    fn with_names<N: NameLookup + ?Sized>(&self, names: &N) -> NamedMessage<'_, Self> {
+        let header = self.header_ref();
+        NamedMessage {
+            message: self,
+            sender: SenderLabel::new(header.sender, names),
+            message_type: TypeLabel::new(header.message_type, names),
+        }
+    }
+}
+
+impl<M: Message> WithNames for M {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{
+        constants, GenericMessage, MessageHeader, Microseconds, Seconds, TimeVal,
+    };
+    use bytes::Bytes;
+
+    #[test]
+    fn resolved_names() {
+        let mut dispatcher = TypeDispatcher::new();
+        let sender = dispatcher.register_sender("Tracker0").unwrap().into_inner();
+        let message_type = dispatcher.register_type("my_type").unwrap().into_inner();
+        let time = Some(TimeVal::new(Seconds(12), Microseconds(500)));
+        let msg = GenericMessage::from_header_and_body(
+            MessageHeader::new(time, message_type, sender),
+            GenericBody::new(Bytes::from_static(b"abcd")),
+        );
+        let named = msg.with_names(&dispatcher);
+        assert_eq!(named.sender.name.as_ref().unwrap(), "Tracker0");
+        let summary = named.to_string();
+        assert!(summary.starts_with("my_type from Tracker0 at 12."));
+        assert!(summary.ends_with(": 4 bytes"));
+        let debug = format!("{:?}", named);
+        assert!(debug.contains(r#"sender: "Tracker0" ("#));
+        assert!(debug.contains("body: 4 bytes"));
+
+        // Unknown IDs and system messages.
+        assert_eq!(
+            msg.with_names(&NoNames).to_string(),
+            summary
+                .replace("my_type", &format!("type {}", message_type.0 .0))
+                .replace("Tracker0", &format!("sender {}", sender.0 .0))
+        );
+        let description = GenericMessage::from_header_and_body(
+            MessageHeader::new(time, constants::SENDER_DESCRIPTION, SenderId(3)),
+            GenericBody::new(Bytes::from_static(b"x")),
+        );
+        assert!(description
+            .with_names(&dispatcher)
+            .to_string()
+            .starts_with("SenderDescription from sender 3 at "));
+    }
+
+    #[test]
+    fn remote_names() {
+        let mut tables = TranslationTables::new();
+        let types: &mut TranslationTable<MessageTypeId> = tables.as_mut();
+        types
+            .add_remote_entry(
+                Bytes::from_static(b"remote_type"),
+                RemoteId(MessageTypeId(2)),
+                LocalId(MessageTypeId(7)),
+            )
+            .unwrap();
+        assert_eq!(
+            TypeLabel::new(MessageTypeId(2), &tables).to_string(),
+            "remote_type"
+        );
+        assert_eq!(
+            TypeLabel::new(MessageTypeId(7), &tables).to_string(),
+            "type 7"
+        );
+        assert_eq!(format!("{:?}", SenderLabel::new(SenderId(0), &tables)), "0");
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! An extension message for structured device metadata, exchanged between Rust peers.
+//!
+//! Servers may send a `DeviceMetadata` message describing a device:
+//! its sensor count, and the names and units of its channels, so that client UIs
+//! can label things without out-of-band configuration.
+//!
+//! This degrades gracefully in mixed deployments:
+//!
+//! - The C++ implementation drops messages of types it has no handler for,
+//!   so sending this to a C++ client is harmless.
+//! - A C++ server never sends it, so clients must treat metadata as optional:
+//!   `MetadataClient::latest()` just stays `None`.
+//! - The body is a sequence of tagged records, and unknown tags are skipped,
+//!   so newer peers can add fields without breaking older ones.
+
+use crate::{
+    buffer_unbuffer::{
+        check_buffer_remaining, check_unbuffer_remaining, BufferResult, BufferSize, BufferTo,
+        BufferUnbufferError, ConstantBufferSize, UnbufferFrom, UnbufferResult,
+    },
+    data_types::{
+        id_types::*,
+        length_prefixed::{self, LengthBehavior, NullTermination},
+        ClassOfService, MessageTypeIdentifier, SenderName, StaticMessageTypeName, TypedMessage,
+        TypedMessageBody,
+    },
+    handler::{HandlerCode, HandlerHandle, TypedHandler},
+    Connection, Result,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{
+    convert::TryFrom,
+    sync::{Arc, Mutex, Weak},
+};
+
+const DEVICE_METADATA: StaticMessageTypeName = StaticMessageTypeName(b"vrpn_Rust Device_Metadata");
+
+const TAG_SENSOR_COUNT: u32 = 1;
+const TAG_CHANNEL: u32 = 2;
+const TAG_DESCRIPTION: u32 = 3;
+
+/// Name and units of one channel of a device.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChannelMetadata {
+    pub name: String,
+    /// Units, such as "m" or "deg", or empty if unitless or unknown.
+    pub units: String,
+}
+
+impl ChannelMetadata {
+    pub fn new(name: impl Into<String>, units: impl Into<String>) -> ChannelMetadata {
+        ChannelMetadata {
+            name: name.into(),
+            units: units.into(),
+        }
+    }
+}
+
+/// Structured description of a device.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceMetadata {
+    /// Free-form human-readable description of the device, if any.
+    pub description: Option<String>,
+    /// Number of sensors, for devices (like trackers) that have them.
+    pub sensor_count: Option<u32>,
+    /// Per-channel information, in channel order.
+    pub channels: Vec<ChannelMetadata>,
+}
+
+impl DeviceMetadata {
+    /// Get a label for a channel: its name if known, otherwise a generic one.
+    pub fn channel_label(&self, channel: usize) -> String {
+        match self.channels.get(channel) {
+            Some(c) if !c.name.is_empty() => c.name.clone(),
+            _ => format!("Channel {}", channel),
+        }
+    }
+}
+
+impl TypedMessageBody for DeviceMetadata {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(DEVICE_METADATA);
+}
+
+fn string_size(s: &str) -> usize {
+    length_prefixed::buffer_size(s.as_bytes(), NullTermination::AddTrailingNull)
+}
+
+fn buffer_string(s: &str, buf: &mut dyn BufMut) -> BufferResult {
+    length_prefixed::buffer_string(
+        s.as_bytes(),
+        buf,
+        NullTermination::AddTrailingNull,
+        LengthBehavior::IncludeNull,
+    )
+}
+
+fn unbuffer_string(buf: &mut dyn Buf) -> UnbufferResult<String> {
+    let s = length_prefixed::unbuffer_string(buf)?;
+    Ok(String::from_utf8_lossy(&s).into_owned())
+}
+
+/// Size of a record with the given payload size: tag, length, payload.
+fn record_size(payload_size: usize) -> usize {
+    u32::constant_buffer_size() * 2 + payload_size
+}
+
+fn buffer_record_header(tag: u32, payload_size: usize, mut buf: &mut dyn BufMut) -> BufferResult {
+    tag.buffer_to(&mut buf)?;
+    u32::try_from(payload_size)
+        .map_err(|_| BufferUnbufferError::OutOfBuffer)?
+        .buffer_to(&mut buf)
+}
+
+impl BufferSize for DeviceMetadata {
+    fn buffer_size(&self) -> usize {
+        self.description
+            .as_ref()
+            .map_or(0, |d| record_size(string_size(d)))
+            + self
+                .sensor_count
+                .map_or(0, |_| record_size(u32::constant_buffer_size()))
+            + self
+                .channels
+                .iter()
+                .map(|c| record_size(string_size(&c.name) + string_size(&c.units)))
+                .sum::<usize>()
+    }
+}
+
+impl BufferTo for DeviceMetadata {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        check_buffer_remaining(buf, self.buffer_size())?;
+        if let Some(description) = &self.description {
+            buffer_record_header(TAG_DESCRIPTION, string_size(description), buf)?;
+            buffer_string(description, buf)?;
+        }
+        if let Some(sensor_count) = self.sensor_count {
+            buffer_record_header(TAG_SENSOR_COUNT, u32::constant_buffer_size(), buf)?;
+            sensor_count.buffer_to(buf)?;
+        }
+        for channel in &self.channels {
+            buffer_record_header(
+                TAG_CHANNEL,
+                string_size(&channel.name) + string_size(&channel.units),
+                buf,
+            )?;
+            buffer_string(&channel.name, buf)?;
+            buffer_string(&channel.units, buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for DeviceMetadata {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        let mut ret = DeviceMetadata::default();
+        while buf.has_remaining() {
+            let tag = u32::unbuffer_from(buf)?;
+            let len = u32::unbuffer_from(buf)? as usize;
+            check_unbuffer_remaining(buf, len)?;
+            let mut payload = buf.copy_to_bytes(len);
+            match tag {
+                TAG_DESCRIPTION => ret.description = Some(unbuffer_string(&mut payload)?),
+                TAG_SENSOR_COUNT => ret.sensor_count = Some(u32::unbuffer_from(&mut payload)?),
+                TAG_CHANNEL => {
+                    let name = unbuffer_string(&mut payload)?;
+                    let units = unbuffer_string(&mut payload)?;
+                    ret.channels.push(ChannelMetadata { name, units });
+                }
+                // From a newer peer: skip it.
+                _ => {}
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// Send metadata for a device, e.g. when a client connects.
+pub fn send_device_metadata<T: Connection>(
+    connection: &T,
+    sender: LocalId<SenderId>,
+    metadata: &DeviceMetadata,
+) -> Result<()> {
+    connection.pack_message_body_ref(None, sender, metadata, ClassOfService::RELIABLE)
+}
+
+#[derive(Debug)]
+struct MetadataHandler {
+    latest: Weak<Mutex<Option<DeviceMetadata>>>,
+}
+
+impl TypedHandler for MetadataHandler {
+    type Item = DeviceMetadata;
+    fn handle_typed(&mut self, msg: &TypedMessage<DeviceMetadata>) -> Result<HandlerCode> {
+        match self.latest.upgrade() {
+            Some(latest) => {
+                *latest.lock()? = Some(msg.body.clone());
+                Ok(HandlerCode::ContinueProcessing)
+            }
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Receives device metadata for a sender, if the server provides it.
+#[derive(Debug)]
+pub struct MetadataClient {
+    latest: Arc<Mutex<Option<DeviceMetadata>>>,
+    handler: HandlerHandle,
+}
+
+impl MetadataClient {
+    pub fn new<T: Connection>(sender: LocalId<SenderId>, connection: &T) -> Result<MetadataClient> {
+        let latest = Arc::new(Mutex::new(None));
+        let handler = connection.add_typed_handler(
+            Box::new(MetadataHandler {
+                latest: Arc::downgrade(&latest),
+            }),
+            Some(sender),
+        )?;
+        Ok(MetadataClient { latest, handler })
+    }
+
+    pub fn new_from_name<T: Connection>(
+        sender: impl Into<SenderName>,
+        connection: &T,
+    ) -> Result<MetadataClient> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection)
+    }
+
+    /// The most recently received metadata,
+    /// or None if none has arrived (always the case with a C++ server).
+    pub fn latest(&self) -> Result<Option<DeviceMetadata>> {
+        Ok(self.latest.lock()?.clone())
+    }
+
+    /// Stop listening for metadata.
+    pub fn shutdown<T: Connection>(self, connection: &T) -> Result<()> {
+        connection.remove_handler(self.handler)
+    }
+}
+
+/// Serialize metadata to a standalone buffer, e.g. for storing alongside a log.
+pub fn metadata_to_bytes(metadata: &DeviceMetadata) -> Result<Bytes> {
+    let mut buf = BytesMut::with_capacity(metadata.buffer_size());
+    metadata.buffer_to(&mut buf)?;
+    Ok(buf.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> DeviceMetadata {
+        DeviceMetadata {
+            description: Some("Two-axis joystick".to_string()),
+            sensor_count: Some(1),
+            channels: vec![ChannelMetadata::new("X", ""), ChannelMetadata::new("Y", "")],
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        for metadata in &[DeviceMetadata::default(), sample()] {
+            let mut buf = metadata_to_bytes(metadata).unwrap();
+            assert_eq!(buf.len(), metadata.buffer_size());
+            assert_eq!(&DeviceMetadata::unbuffer_from(&mut buf).unwrap(), metadata);
+        }
+        assert_eq!(sample().channel_label(1), "Y");
+        assert_eq!(sample().channel_label(5), "Channel 5");
+    }
+
+    #[test]
+    fn skips_unknown_records() {
+        let mut buf = BytesMut::new();
+        // A record from some future version, with a 3-byte payload
+        buf.put_u32(99);
+        buf.put_u32(3);
+        buf.put_slice(b"abc");
+        buf.put_slice(&metadata_to_bytes(&sample()).unwrap());
+        assert_eq!(
+            DeviceMetadata::unbuffer_from(&mut buf.freeze()).unwrap(),
+            sample()
+        );
+    }
+
+    #[test]
+    fn truncated_record() {
+        let full = metadata_to_bytes(&sample()).unwrap();
+        let mut buf = full.slice(..full.len() - 1);
+        assert!(DeviceMetadata::unbuffer_from(&mut buf).is_err());
+    }
+}
@@ -138,6 +138,12 @@ impl<I: IdWithNameAndDescription> LocalNameRegistration for NameRegistrationCont
 }
 
 impl<I: RegisterableId> NameRegistrationContainer<I> {
+    /// Get the name registered for an ID, if any.
+    pub(crate) fn try_get_name(&self, id: LocalId<I>) -> Option<&Name> {
+        let index: usize = id.get().try_into().ok()?;
+        self.names.get(index)
+    }
+
     fn try_insert(&mut self, name: &Name) -> Result<LocalId<I>> {
         if self.names.len() > MAX_VEC_USIZE {
             return Err(VrpnError::TooManyMappings);
@@ -166,7 +172,7 @@ impl<'a, I: RegisterableId> Iterator for NameRegIter<'a, I> {
         match self.container.names.get(id as usize) {
             Some(name) => {
                 self.i += 1;
-                Some((LocalId(I::new(id.try_into().unwrap())), name))
+                Some((LocalId(I::new(id.try_into().ok()?)), name))
             }
             None => None,
         }
@@ -205,7 +211,6 @@ impl<T: LocalNameRegistration, U: std::fmt::Debug> PerIdData<T, U> {
         let id = id.get();
 
         let index: usize = id.try_into().map_err(|_| VrpnError::InvalidId(id))?;
-        let x = &self.data[index];
         self.data.get_mut(index).ok_or(VrpnError::InvalidId(id))
     }
 }
@@ -224,8 +229,13 @@ impl<T: LocalNameRegistration<CategorizedId = CategorizedId>, U: std::fmt::Debug
         Ok(match self.inner.try_insert_or_get(name.clone())? {
             InsertOrGet::Found(id) => InsertOrGet::Found(id),
             InsertOrGet::New(id) => {
-                let index: usize = id.get().try_into().unwrap();
-                assert_eq!(self.data.len(), index);
+                let raw_id = id.get();
+                let index: usize = raw_id
+                    .try_into()
+                    .map_err(|_| VrpnError::InvalidId(raw_id))?;
+                if self.data.len() != index {
+                    return Err(VrpnError::InvalidId(raw_id));
+                }
                 self.data.push(U::default());
                 InsertOrGet::New(id)
             }
@@ -116,7 +116,15 @@ impl FromStr for DeviceInfo {
                 )));
             }
         };
-        let server = parts.last().unwrap().parse::<ServerInfo>()?;
+        let server = match parts.last() {
+            Some(server) => server.parse::<ServerInfo>()?,
+            None => {
+                return Err(VrpnError::OtherMessage(format!(
+                    "could not parse address {}",
+                    url
+                )))
+            }
+        };
 
         Ok(DeviceInfo { device, server })
     }
@@ -6,17 +6,19 @@
 
 use crate::{
     buffer_unbuffer::EmptyMessage,
+    clock::{Instant, SharedClock},
     data_types::{
-        id_types::*, name_types::NameIntoBytes, ClassOfService, MessageHeader, MessageTypeId,
-        MessageTypeIdentifier, SenderName, StaticMessageTypeName, TypedMessage, TypedMessageBody,
+        id_types::*, ClassOfService, MessageHeader, MessageTypeId, MessageTypeIdentifier,
+        SenderName, StaticMessageTypeName, TypedMessage, TypedMessageBody,
     },
     handler::{HandlerCode, HandlerHandle, TypedBodylessHandler},
     Connection, VrpnError,
 };
 use std::{
+    collections::VecDeque,
     fmt,
     sync::{Arc, Mutex, Weak},
-    time::{Duration, Instant},
+    time::Duration,
 };
 
 /// Periodic "Ping" message.
@@ -24,7 +26,8 @@ use std::{
 /// Has no body.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct Ping;
-const PING_MESSAGE: StaticMessageTypeName = StaticMessageTypeName(b"vrpn_Base ping_message");
+pub(crate) const PING_MESSAGE: StaticMessageTypeName =
+    StaticMessageTypeName(b"vrpn_Base ping_message");
 impl Default for Ping {
     fn default() -> Ping {
         Ping
@@ -42,7 +45,8 @@ impl TypedMessageBody for Ping {
 /// Has no body.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct Pong;
-const PONG_MESSAGE: StaticMessageTypeName = StaticMessageTypeName(b"vrpn_Base pong_message");
+pub(crate) const PONG_MESSAGE: StaticMessageTypeName =
+    StaticMessageTypeName(b"vrpn_Base pong_message");
 impl Default for Pong {
     fn default() -> Pong {
         Pong
@@ -54,8 +58,81 @@ impl TypedMessageBody for Pong {
         MessageTypeIdentifier::UserMessageName(PONG_MESSAGE);
 }
 
+/// Notable changes in the state of a ping `Client`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingEvent {
+    /// A pong was received: latency is measured from the first unanswered ping.
+    Pong { latency: Duration },
+    /// The remote side has not answered pings for longer than the unresponsive threshold.
+    Unresponsive { silence: Duration },
+    /// The remote side started answering pings again after being unresponsive.
+    Responsive,
+}
+
+/// Time between re-sending unanswered pings, by default.
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time without a pong before the remote side is considered unresponsive, by default.
+pub const DEFAULT_UNRESPONSIVE_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// What a ping `Client` does once the remote side becomes unresponsive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnresponsiveAction {
+    /// Only report `PingEvent::Unresponsive`.
+    #[default]
+    Report,
+    /// Also print a warning.
+    Warn,
+    /// Also drop the connection to the remote side, where the owner of the client supports it:
+    /// a `ConnectionIp` shuts down its endpoints, and reconnects if configured to.
+    Disconnect,
+}
+
+/// Timing of the ping/pong exchange, and what to do when pongs stop arriving.
+///
+/// Can be changed while running with `Client::set_config`,
+/// e.g. to ping more often while debugging a flaky server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingConfig {
+    /// Time between re-sending unanswered pings.
+    pub interval: Duration,
+    /// Time without a pong before the remote side is considered unresponsive.
+    pub unresponsive_threshold: Duration,
+    /// What to do once it is.
+    pub action: UnresponsiveAction,
+}
+
+impl Default for PingConfig {
+    fn default() -> PingConfig {
+        PingConfig {
+            interval: DEFAULT_PING_INTERVAL,
+            unresponsive_threshold: DEFAULT_UNRESPONSIVE_THRESHOLD,
+            action: UnresponsiveAction::default(),
+        }
+    }
+}
+
+impl PingConfig {
+    /// Check that the interval is non-zero, and no longer than the unresponsive threshold.
+    pub fn validate(&self) -> Result<(), VrpnError> {
+        if self.interval.is_zero() {
+            return Err(VrpnError::Config(
+                "ping interval must be non-zero".to_string(),
+            ));
+        }
+        if self.unresponsive_threshold < self.interval {
+            return Err(VrpnError::Config(format!(
+                "unresponsive threshold {:?} is shorter than the ping interval {:?}",
+                self.unresponsive_threshold, self.interval
+            )));
+        }
+        Ok(())
+    }
+}
+
 struct PongHandler {
     inner: Weak<Mutex<ClientInner>>,
+    clock: SharedClock,
 }
 
 impl fmt::Debug for PongHandler {
@@ -70,11 +147,15 @@ impl TypedBodylessHandler for PongHandler {
         match self.inner.upgrade() {
             Some(inner) => {
                 let mut inner = inner.lock()?;
-                inner.unanswered_ping = None;
+                if let Some(unanswered) = inner.unanswered_ping.take() {
+                    let latency = self.clock.now().saturating_duration_since(unanswered);
+                    inner.events.push_back(PingEvent::Pong { latency });
+                }
                 inner.last_warning = None;
                 if inner.flatlined {
                     eprintln!("Remote host started responding again");
                     inner.flatlined = false;
+                    inner.events.push_back(PingEvent::Responsive);
                 }
                 Ok(HandlerCode::ContinueProcessing)
             }
@@ -85,44 +166,66 @@ impl TypedBodylessHandler for PongHandler {
     }
 }
 
+/// The client side of the ping/pong exchange: sends pings and watches for pongs.
+///
+/// Only holds a weak reference to the connection, so it may be owned by the connection itself.
 pub struct Client<T: Connection + 'static> {
-    connection: Arc<T>,
+    connection: Weak<T>,
+    clock: SharedClock,
     inner: Arc<Mutex<ClientInner>>,
     ping_type: LocalId<MessageTypeId>,
     sender: LocalId<SenderId>,
 }
 
 struct ClientInner {
+    config: PingConfig,
     /// The time of the first unanswered ping.
     unanswered_ping: Option<Instant>,
     /// The time of the last warning message and unanswered ping.
     last_warning: Option<Instant>,
     /// whether the server seems disconnected or unresponsive
     flatlined: bool,
+    /// Events not yet retrieved with `Client::take_events()`
+    events: VecDeque<PingEvent>,
 }
 
 impl ClientInner {
-    fn new() -> Arc<Mutex<ClientInner>> {
+    fn new(config: PingConfig) -> Arc<Mutex<ClientInner>> {
         Arc::new(Mutex::new(ClientInner {
+            config,
             unanswered_ping: None,
             last_warning: None,
             flatlined: false,
+            events: VecDeque::new(),
         }))
     }
 }
 impl<T: Connection + 'static> Client<T> {
     pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<Client<T>, VrpnError> {
+        Self::with_config(sender, connection, PingConfig::default())
+    }
+
+    /// Create a client with the given timing and unresponsive action, after validating them.
+    pub fn with_config(
+        sender: LocalId<SenderId>,
+        connection: Arc<T>,
+        config: PingConfig,
+    ) -> Result<Client<T>, VrpnError> {
+        config.validate()?;
         let ping_type = connection.register_type(PING_MESSAGE)?;
-        let inner = ClientInner::new();
+        let inner = ClientInner::new(config);
 
+        let clock = connection.clock();
         let _ = connection.add_typed_handler(
             Box::new(PongHandler {
                 inner: Arc::downgrade(&inner),
+                clock: Arc::clone(&clock),
             }),
             Some(sender),
         )?;
         let client = Client {
-            connection,
+            clock,
+            connection: Arc::downgrade(&connection),
             inner,
             ping_type,
             sender,
@@ -131,7 +234,7 @@ impl<T: Connection + 'static> Client<T> {
         Ok(client)
     }
     pub fn new_from_name(
-        sender: impl Into<SenderName> + NameIntoBytes + Clone,
+        sender: impl Into<SenderName>,
         connection: Arc<T>,
     ) -> Result<Client<T>, VrpnError> {
         let sender_id = connection.register_sender(sender)?;
@@ -141,7 +244,9 @@ impl<T: Connection + 'static> Client<T> {
     pub fn initiate_ping_cycle(&self) -> Result<(), VrpnError> {
         {
             let mut inner = self.inner.lock()?;
-            inner.unanswered_ping = Some(Instant::now());
+            let now = self.clock.now();
+            inner.unanswered_ping = Some(now);
+            inner.last_warning = Some(now);
         }
         self.send_ping()
     }
@@ -152,15 +257,25 @@ impl<T: Connection + 'static> Client<T> {
     /// or None if there are no unanswered pings.
     pub fn check_ping_cycle(&self) -> Result<Option<Duration>, VrpnError> {
         let mut inner = self.inner.lock()?;
+        let config = inner.config;
         if let (Some(unanswered), Some(last_warning)) =
             (inner.unanswered_ping, &mut inner.last_warning)
         {
-            let now = Instant::now();
-            let radio_silence = now.checked_duration_since(unanswered).unwrap();
-            if now.checked_duration_since(*last_warning).unwrap() > Duration::from_secs(1) {
+            let now = self.clock.now();
+            let radio_silence = now.saturating_duration_since(unanswered);
+            if now.saturating_duration_since(*last_warning) > config.interval {
                 *last_warning = now;
-                if radio_silence > Duration::from_secs(10) {
+                if radio_silence > config.unresponsive_threshold && !inner.flatlined {
+                    if config.action != UnresponsiveAction::Report {
+                        eprintln!(
+                            "Remote host has not answered pings for {} seconds",
+                            radio_silence.as_secs_f32()
+                        );
+                    }
                     inner.flatlined = true;
+                    inner.events.push_back(PingEvent::Unresponsive {
+                        silence: radio_silence,
+                    });
                 }
                 self.send_ping()?;
             }
@@ -170,10 +285,39 @@ impl<T: Connection + 'static> Client<T> {
         }
     }
 
+    /// The current timing and unresponsive action.
+    pub fn config(&self) -> Result<PingConfig, VrpnError> {
+        Ok(self.inner.lock()?.config)
+    }
+
+    /// Change the timing and unresponsive action, after validating them.
+    ///
+    /// Takes effect from the next `check_ping_cycle`.
+    pub fn set_config(&self, config: PingConfig) -> Result<(), VrpnError> {
+        config.validate()?;
+        self.inner.lock()?.config = config;
+        Ok(())
+    }
+
+    /// Whether the remote side is currently considered unresponsive.
+    pub fn is_unresponsive(&self) -> Result<bool, VrpnError> {
+        Ok(self.inner.lock()?.flatlined)
+    }
+
+    /// Remove and return all events that occurred since the last call.
+    pub fn take_events(&self) -> Result<Vec<PingEvent>, VrpnError> {
+        Ok(self.inner.lock()?.events.drain(..).collect())
+    }
+
     fn send_ping(&self) -> Result<(), VrpnError> {
-        let msg = TypedMessage::new(None, self.ping_type, self.sender, Pong::default());
-        self.connection
-            .pack_message(msg, ClassOfService::RELIABLE)?;
+        let connection = self.connection.upgrade().ok_or(VrpnError::EndpointClosed)?;
+        let msg = TypedMessage::new(
+            Some(self.clock.time_of_day()),
+            self.ping_type,
+            self.sender,
+            Ping,
+        );
+        connection.pack_message(msg, ClassOfService::RELIABLE)?;
         Ok(())
     }
 }
@@ -191,7 +335,12 @@ impl<T: Connection + Send> TypedBodylessHandler for PingHandler<T> {
         // TODO use sender from header?
         match self.connection.upgrade() {
             Some(connection) => {
-                let msg = TypedMessage::new(None, self.pong_type, self.sender, Pong::default());
+                let msg = TypedMessage::new(
+                    Some(connection.clock().time_of_day()),
+                    self.pong_type,
+                    self.sender,
+                    Pong,
+                );
                 connection.pack_message(msg, ClassOfService::RELIABLE)?;
                 Ok(HandlerCode::ContinueProcessing)
             }
@@ -225,10 +374,125 @@ impl Server {
     }
 
     pub fn new_from_name<T: Connection + 'static>(
-        sender: impl Into<SenderName> + NameIntoBytes + Clone,
+        sender: impl Into<SenderName>,
         connection: Arc<T>,
     ) -> Result<Server, VrpnError> {
         let sender_id = connection.register_sender(sender)?;
         Self::new(sender_id, connection)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, MockClock},
+        testing::MockConnection,
+    };
+    use std::time::SystemTime;
+
+    #[test]
+    fn ping_cycle_with_mock_clock() {
+        let clock = MockClock::starting_at(SystemTime::UNIX_EPOCH + Duration::from_secs(1000));
+        let connection = MockConnection::with_clock(clock.shared());
+        let client = Client::new_from_name("Tracker0", Arc::clone(&connection)).unwrap();
+        let pings = || connection.sent_user_messages();
+
+        // Initial ping, stamped with the mock time.
+        assert_eq!(pings().len(), 1);
+        assert_eq!(pings()[0].header.time, clock.time_of_day());
+
+        assert_eq!(
+            client.check_ping_cycle().unwrap(),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(pings().len(), 1);
+
+        // More than a second without a pong: ping again.
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(
+            client.check_ping_cycle().unwrap(),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(pings().len(), 2);
+        assert!(!client.is_unresponsive().unwrap());
+
+        clock.advance(Duration::from_secs(10));
+        client.check_ping_cycle().unwrap();
+        assert!(client.is_unresponsive().unwrap());
+        assert_eq!(
+            client.take_events().unwrap(),
+            vec![PingEvent::Unresponsive {
+                silence: Duration::from_millis(11500)
+            }]
+        );
+        assert!(client.take_events().unwrap().is_empty());
+
+        // Simulate the pong arriving.
+        let mut handler = PongHandler {
+            inner: Arc::downgrade(&client.inner),
+            clock: clock.shared(),
+        };
+        clock.advance(Duration::from_millis(500));
+        let header = MessageHeader::new(None, LocalId(MessageTypeId(0)), LocalId(SenderId(0)));
+        handler.handle_typed_bodyless(&header).unwrap();
+        assert!(!client.is_unresponsive().unwrap());
+        assert_eq!(
+            client.take_events().unwrap(),
+            vec![
+                PingEvent::Pong {
+                    latency: Duration::from_secs(12)
+                },
+                PingEvent::Responsive
+            ]
+        );
+        assert_eq!(client.check_ping_cycle().unwrap(), None);
+    }
+
+    #[test]
+    fn config() {
+        assert!(PingConfig::default().validate().is_ok());
+        let fast = PingConfig {
+            interval: Duration::from_millis(100),
+            unresponsive_threshold: Duration::from_millis(500),
+            action: UnresponsiveAction::Warn,
+        };
+        assert!(fast.validate().is_ok());
+        assert!(PingConfig {
+            interval: Duration::ZERO,
+            ..fast
+        }
+        .validate()
+        .is_err());
+        assert!(PingConfig {
+            unresponsive_threshold: Duration::from_millis(50),
+            ..fast
+        }
+        .validate()
+        .is_err());
+
+        let clock = MockClock::starting_at(SystemTime::UNIX_EPOCH);
+        let connection = MockConnection::with_clock(clock.shared());
+        let client = Client::new_from_name("Tracker0", Arc::clone(&connection)).unwrap();
+        let pings = || connection.sent_user_messages().len();
+        assert_eq!(pings(), 1);
+        clock.advance(Duration::from_millis(200));
+        client.check_ping_cycle().unwrap();
+        assert_eq!(pings(), 1);
+
+        // Raise the ping rate while running.
+        assert!(client
+            .set_config(PingConfig {
+                interval: Duration::ZERO,
+                ..fast
+            })
+            .is_err());
+        client.set_config(fast).unwrap();
+        assert_eq!(client.config().unwrap(), fast);
+        client.check_ping_cycle().unwrap();
+        assert_eq!(pings(), 2);
+        clock.advance(Duration::from_millis(400));
+        client.check_ping_cycle().unwrap();
+        assert!(client.is_unresponsive().unwrap());
+    }
+}
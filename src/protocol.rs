@@ -0,0 +1,392 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! The protocol logic of one connection, driven by bytes in and bytes out rather than by IO.
+//!
+//! `ProtocolReceiver` frames incoming bytes into messages, maintains the translation tables,
+//! applies system messages, dispatches user messages, and quarantines a misbehaving remote end.
+//! `ProtocolSender` queues outgoing messages, drops those that expired or were superseded,
+//! holds back user messages to stay within a bandwidth limit, and sequences and frames the rest.
+//! `ProtocolCore` adds the handshake to the two, for a connection over a single byte pipe.
+//!
+//! None of them touch a socket or a timer: each runtime's endpoint is a driver,
+//! feeding them the bytes it reads along with the time, and writing out what they produce.
+//! This keeps runtime-specific glue thin, and lets the protocol be tested
+//! (and used) anywhere there's a byte pipe.
+
+mod incoming;
+mod outgoing;
+pub(crate) mod outgoing_trace;
+pub(crate) mod parse_error_window;
+pub(crate) mod shaper;
+
+pub(crate) use self::outgoing::{QueuedMessage, SendCounters};
+pub use self::{
+    incoming::ProtocolReceiver,
+    outgoing::{ProtocolSender, Transmit},
+};
+
+use crate::{
+    clock::Instant,
+    codec::MessageDecoder,
+    data_types::{id_types::SequenceNumber, ClassOfService, GenericMessage, ProtocolProfile},
+    endpoint::{ExtendedSystemCommand, SystemCommand},
+    handshake::Handshake,
+    stats::ErrorKind,
+    Endpoint, Result, TranslationTables, TypeDispatcher,
+};
+use bytes::{Bytes, BytesMut};
+
+/// Sans-IO core of a reliable (stream-oriented) VRPN connection.
+#[derive(Debug)]
+pub struct ProtocolCore {
+    /// None once the handshake is complete.
+    handshake: Option<Handshake>,
+    profile: ProtocolProfile,
+    receiver: ProtocolReceiver,
+    /// Messages queued before the handshake completed wait here: they're sent after it.
+    sender: ProtocolSender,
+    outgoing: BytesMut,
+}
+
+impl ProtocolCore {
+    /// Create a core that will perform the given handshake first.
+    ///
+    /// A client's cookie is ready to send immediately.
+    pub fn new(mut handshake: Handshake) -> Result<ProtocolCore> {
+        let initial = handshake.advance(&[])?;
+        let mut core = ProtocolCore::after_handshake(SequenceNumber(0));
+        core.handshake = Some(handshake);
+        core.outgoing.extend_from_slice(&initial);
+        Ok(core)
+    }
+
+    /// Create a core for a stream whose handshake was already performed elsewhere.
+    ///
+    /// `next_sequence` continues the numbering of any messages sent during the handshake:
+    /// see `Handshake::next_sequence_number`.
+    pub fn after_handshake(next_sequence: SequenceNumber) -> ProtocolCore {
+        ProtocolCore {
+            handshake: None,
+            profile: ProtocolProfile::VRPN,
+            receiver: ProtocolReceiver::new(),
+            sender: ProtocolSender::new(next_sequence),
+            outgoing: BytesMut::new(),
+        }
+    }
+
+    /// Replace the message decoder, e.g. to change its size limit.
+    ///
+    /// The decoder is switched to this connection's protocol profile.
+    pub fn with_decoder(mut self, decoder: MessageDecoder) -> ProtocolCore {
+        let decoder = decoder.with_profile(self.profile);
+        self.receiver = self.receiver.with_decoder(decoder);
+        self
+    }
+
+    /// Use a protocol profile other than `ProtocolProfile::VRPN` for messages
+    /// sent and received, including those following the cookies in the handshake.
+    ///
+    /// Both sides must agree: the profile is not negotiated.
+    pub fn with_profile(mut self, profile: ProtocolProfile) -> ProtocolCore {
+        self.receiver = self.receiver.with_profile(profile);
+        self.sender = self.sender.with_profile(profile);
+        self.handshake = self.handshake.map(|h| h.with_profile(profile));
+        self.profile = profile;
+        self
+    }
+
+    /// The alignment and header layout used by this connection.
+    pub fn profile(&self) -> ProtocolProfile {
+        self.profile
+    }
+
+    /// True once the handshake is complete, and messages are flowing.
+    pub fn is_connected(&self) -> bool {
+        self.handshake.is_none()
+    }
+
+    /// True if there are bytes waiting to be sent.
+    pub fn has_outgoing(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+
+    /// Take the bytes that should be sent to the remote side.
+    pub fn take_outgoing(&mut self) -> Bytes {
+        self.outgoing.split().freeze()
+    }
+
+    /// Process bytes received from the remote side.
+    ///
+    /// Completes the handshake if possible, then decodes every complete message:
+    /// sender and type descriptions update `dispatcher` and the translation tables,
+    /// user messages are translated to local IDs and dispatched,
+    /// and any other system commands are returned for the driver to act on.
+    ///
+    /// Errors are also counted in the dispatcher's statistics.
+    pub fn receive(
+        &mut self,
+        data: &[u8],
+        dispatcher: &mut TypeDispatcher,
+    ) -> Result<Vec<ExtendedSystemCommand>> {
+        let now = Instant::now();
+        match self.handshake.as_mut() {
+            None => self.receiver.receive(data, now),
+            Some(handshake) => {
+                let out = handshake
+                    .advance(data)
+                    .inspect_err(|_| dispatcher.stats_mut().record_error(ErrorKind::Handshake))?;
+                self.outgoing.extend_from_slice(&out);
+                if !handshake.is_complete() {
+                    return Ok(Vec::new());
+                }
+                self.sender
+                    .set_next_sequence(handshake.next_sequence_number());
+                let leftover = handshake.take_leftover();
+                self.handshake = None;
+                self.receiver.receive(&leftover, now);
+                self.flush_sender()
+                    .inspect_err(|e| dispatcher.stats_mut().record_vrpn_error(e))?;
+            }
+        }
+        self.receiver.dispatch(dispatcher, now, |_| {})
+    }
+
+    /// Frame everything the sender will let out into the outgoing bytes, once connected.
+    fn flush_sender(&mut self) -> Result<()> {
+        if !self.is_connected() {
+            return Ok(());
+        }
+        while let Transmit::Frame(frame) = self.sender.poll_transmit(Instant::now())? {
+            self.outgoing.extend_from_slice(&frame);
+        }
+        Ok(())
+    }
+}
+
+impl Endpoint for ProtocolCore {
+    fn translation_tables(&self) -> &TranslationTables {
+        self.receiver.translation_tables()
+    }
+
+    fn translation_tables_mut(&mut self) -> &mut TranslationTables {
+        self.receiver.translation_tables_mut()
+    }
+
+    fn send_system_change(&self, message: SystemCommand) -> Result<()> {
+        self.receiver.send_system_change(message)
+    }
+
+    fn buffer_generic_message(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
+        // Only a reliable channel here: class of service only affects expiry.
+        self.sender.queue(msg, class, Instant::now());
+        self.flush_sender()
+    }
+
+    fn buffer_latest_value(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
+        self.sender.queue_latest_value(msg, class, Instant::now());
+        self.flush_sender()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{GenericBody, Message, MessageHeader, TimeVal},
+        handler::{Handler, HandlerCode},
+        EndpointGeneric,
+    };
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug)]
+    struct Collect(Arc<Mutex<Vec<GenericMessage>>>);
+    impl Handler for Collect {
+        fn handle(&mut self, msg: &GenericMessage) -> Result<HandlerCode> {
+            self.0.lock()?.push(msg.clone());
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    /// Move bytes both ways until neither side has anything left to say.
+    fn pump(
+        a: &mut ProtocolCore,
+        a_dispatcher: &mut TypeDispatcher,
+        b: &mut ProtocolCore,
+        b_dispatcher: &mut TypeDispatcher,
+    ) {
+        while a.has_outgoing() || b.has_outgoing() {
+            let to_b = a.take_outgoing();
+            b.receive(&to_b, b_dispatcher).unwrap();
+            let to_a = b.take_outgoing();
+            a.receive(&to_a, a_dispatcher).unwrap();
+        }
+    }
+
+    #[test]
+    fn handshake_descriptions_and_dispatch() {
+        let mut client_dispatcher = TypeDispatcher::new();
+        let mut server_dispatcher = TypeDispatcher::new();
+        let mut client = ProtocolCore::new(Handshake::client()).unwrap();
+        let mut server = ProtocolCore::new(Handshake::server()).unwrap();
+        assert!(client.has_outgoing());
+        assert!(!server.has_outgoing());
+
+        // The server registers a sender and type, and sends before the handshake is done.
+        let sender = server_dispatcher
+            .register_sender("Tracker0")
+            .unwrap()
+            .into_inner();
+        let message_type = server_dispatcher
+            .register_type("custom")
+            .unwrap()
+            .into_inner();
+        server
+            .new_local_id(&Bytes::from_static(b"Tracker0"), sender)
+            .unwrap();
+        server
+            .new_local_id(&Bytes::from_static(b"custom"), message_type)
+            .unwrap();
+        server
+            .buffer_generic_message(
+                GenericMessage::from_header_and_body(
+                    MessageHeader::new(Some(TimeVal::default()), message_type.0, sender.0),
+                    GenericBody::new(Bytes::from_static(b"data")),
+                ),
+                ClassOfService::RELIABLE,
+            )
+            .unwrap();
+
+        // The client registers its own sender first, so its local IDs differ from the server's.
+        let _ = client_dispatcher.register_sender("Other").unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        client_dispatcher
+            .add_handler(Box::new(Collect(Arc::clone(&received))), None, None)
+            .unwrap();
+        client_dispatcher
+            .stats_mut()
+            .set_latency_instrumentation(true);
+
+        pump(
+            &mut client,
+            &mut client_dispatcher,
+            &mut server,
+            &mut server_dispatcher,
+        );
+        assert!(client.is_connected());
+        assert!(server.is_connected());
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let local_sender = client_dispatcher.get_sender_id("Tracker0").unwrap();
+        let local_type = client_dispatcher.get_type_id("custom").unwrap();
+        assert_ne!(local_sender, sender);
+        assert_eq!(received[0].header.sender, local_sender.0);
+        assert_eq!(received[0].header.message_type, local_type.0);
+        assert_eq!(
+            received[0].body,
+            GenericBody::new(Bytes::from_static(b"data"))
+        );
+        // Only user messages are timed.
+        assert_eq!(
+            client_dispatcher
+                .stats()
+                .decode_latency()
+                .map(|h| h.count()),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn wider_alignment_profile() {
+        let profile = ProtocolProfile::with_alignment(16).unwrap();
+        let mut client_dispatcher = TypeDispatcher::new();
+        let mut server_dispatcher = TypeDispatcher::new();
+        let mut client = ProtocolCore::new(Handshake::client())
+            .unwrap()
+            .with_profile(profile);
+        let mut server = ProtocolCore::new(Handshake::server())
+            .unwrap()
+            .with_profile(profile);
+        // The descriptions queued before the handshake are sent with the wider alignment too.
+        let sender = client_dispatcher
+            .register_sender("Tracker0")
+            .unwrap()
+            .into_inner();
+        let message_type = client_dispatcher
+            .register_type("custom")
+            .unwrap()
+            .into_inner();
+        client
+            .new_local_id(&Bytes::from_static(b"Tracker0"), sender)
+            .unwrap();
+        client
+            .new_local_id(&Bytes::from_static(b"custom"), message_type)
+            .unwrap();
+        pump(
+            &mut client,
+            &mut client_dispatcher,
+            &mut server,
+            &mut server_dispatcher,
+        );
+        assert!(client.is_connected());
+        assert!(server_dispatcher.get_sender_id("Tracker0").is_some());
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        server_dispatcher
+            .add_handler(Box::new(Collect(Arc::clone(&received))), None, None)
+            .unwrap();
+        client
+            .buffer_generic_message(
+                GenericMessage::from_header_and_body(
+                    MessageHeader::new(Some(TimeVal::default()), message_type.0, sender.0),
+                    GenericBody::new(Bytes::from_static(b"hello")),
+                ),
+                ClassOfService::RELIABLE,
+            )
+            .unwrap();
+        let sent = client.take_outgoing();
+        // 32 bytes of padded header, then the body padded to 16.
+        assert_eq!(sent.len(), 48);
+        assert_eq!(&sent[..4], &37u32.to_be_bytes());
+        assert_eq!(&sent[32..37], b"hello");
+
+        server.receive(&sent, &mut server_dispatcher).unwrap();
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(
+            received[0].body,
+            GenericBody::new(Bytes::from_static(b"hello"))
+        );
+    }
+
+    #[test]
+    fn errors_counted() {
+        let mut dispatcher = TypeDispatcher::new();
+        let mut server = ProtocolCore::new(Handshake::server()).unwrap();
+        assert!(server.receive(&[b'x'; 24], &mut dispatcher).is_err());
+        assert_eq!(dispatcher.stats().errors().count(ErrorKind::Handshake), 1);
+
+        // A message from a sender that was never described.
+        let mut core = ProtocolCore::after_handshake(SequenceNumber(0));
+        let mut sender = ProtocolCore::after_handshake(SequenceNumber(0));
+        sender
+            .buffer_generic_message(
+                GenericMessage::from_header_and_body(
+                    MessageHeader::new(
+                        Some(TimeVal::default()),
+                        crate::data_types::id_types::MessageTypeId(0),
+                        crate::data_types::id_types::SenderId(0),
+                    ),
+                    GenericBody::new(Bytes::new()),
+                ),
+                ClassOfService::RELIABLE,
+            )
+            .unwrap();
+        assert!(core
+            .receive(&sender.take_outgoing(), &mut dispatcher)
+            .is_err());
+        assert_eq!(dispatcher.stats().errors().total(), 2);
+    }
+}
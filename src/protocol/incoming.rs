@@ -0,0 +1,406 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Decoding, translating and dispatching the messages an endpoint receives.
+
+use super::parse_error_window::ParseErrorWindow;
+use crate::{
+    buffer_unbuffer::BufferUnbufferError,
+    clock::Instant,
+    codec::MessageDecoder,
+    data_types::{
+        id_types::{LocalId, SenderId},
+        GenericMessage, Message, MessageHeader, MessageTypeId, ProtocolProfile,
+    },
+    endpoint::{ExtendedSystemCommand, SystemCommand},
+    handle_system_command, parse_system_message,
+    stats::ErrorKind,
+    type_dispatcher::LatestValueOnly,
+    vrpn_async::ParseErrorLimit,
+    Result, TranslationTables, TypeDispatcher, VrpnError,
+};
+use std::{collections::HashSet, sync::Mutex};
+
+/// Remove each item that has a key, if a later item has the same key, returning how many were removed.
+///
+/// Used to keep only the newest pending message of a "latest value only" type from each sender.
+fn drop_superseded<T>(
+    items: &mut Vec<T>,
+    key: impl Fn(&T) -> Option<(MessageTypeId, SenderId)>,
+) -> u64 {
+    let mut newest = HashSet::new();
+    let keep: Vec<bool> = items
+        .iter()
+        .rev()
+        .map(|item| key(item).is_none_or(|key| newest.insert(key)))
+        .collect();
+    let mut keep = keep.into_iter().rev();
+    let before = items.len();
+    items.retain(|_| keep.next().unwrap_or(true));
+    (before - items.len()) as u64
+}
+
+/// Sans-IO receiving side of a reliable connection: decodes received bytes,
+/// maintains the translation tables, applies system messages, and dispatches user messages.
+///
+/// A driver passes it each chunk of data with `receive`, then calls `dispatch`
+/// once it has read everything available, so superseded values can be skipped.
+/// Errors are counted in the dispatcher's statistics where they occur,
+/// and if the remote end causes too many parse errors, it is quarantined.
+#[derive(Debug)]
+pub struct ProtocolReceiver {
+    decoder: MessageDecoder,
+    translation: TranslationTables,
+    system_commands: Mutex<Vec<SystemCommand>>,
+    /// When the last data arrived: the arrival time of the messages it completes.
+    read_at: Option<Instant>,
+    /// Recent parse errors caused by the remote end, to quarantine it if there are too many.
+    parse_errors: ParseErrorWindow,
+    received: u64,
+    skipped: u64,
+    last_header: Option<MessageHeader>,
+}
+
+impl Default for ProtocolReceiver {
+    fn default() -> ProtocolReceiver {
+        ProtocolReceiver::new()
+    }
+}
+
+impl ProtocolReceiver {
+    /// Create a receiver with empty translation tables and the default message decoder.
+    pub fn new() -> ProtocolReceiver {
+        ProtocolReceiver {
+            decoder: MessageDecoder::new(),
+            translation: TranslationTables::new(),
+            system_commands: Mutex::default(),
+            read_at: None,
+            parse_errors: ParseErrorWindow::default(),
+            received: 0,
+            skipped: 0,
+            last_header: None,
+        }
+    }
+
+    /// Replace the message decoder, e.g. to change its size limit.
+    pub fn with_decoder(self, decoder: MessageDecoder) -> ProtocolReceiver {
+        ProtocolReceiver { decoder, ..self }
+    }
+
+    /// Expect messages with a protocol profile other than `ProtocolProfile::VRPN`.
+    pub fn with_profile(mut self, profile: ProtocolProfile) -> ProtocolReceiver {
+        self.decoder = std::mem::take(&mut self.decoder).with_profile(profile);
+        self
+    }
+
+    /// Quarantine the remote end if it causes too many parse errors:
+    /// see `ConnectionIp::set_parse_error_limit`.
+    pub fn set_parse_error_limit(&mut self, limit: ParseErrorLimit) {
+        self.parse_errors.set_limit(limit);
+    }
+
+    pub fn translation_tables(&self) -> &TranslationTables {
+        &self.translation
+    }
+
+    pub fn translation_tables_mut(&mut self) -> &mut TranslationTables {
+        &mut self.translation
+    }
+
+    /// Queue a system command, applied the next time messages are dispatched.
+    pub fn send_system_change(&self, message: SystemCommand) -> Result<()> {
+        self.system_commands.lock()?.push(message);
+        Ok(())
+    }
+
+    /// Buffer data received from the remote end at `now`.
+    pub fn receive(&mut self, data: &[u8], now: Instant) {
+        self.decoder.extend_from_slice(data);
+        self.read_at = Some(now);
+    }
+
+    /// Number of bytes received but not yet decoded into a complete message.
+    pub fn buffered_len(&self) -> usize {
+        self.decoder.buffered_len()
+    }
+
+    /// Number of messages decoded.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Number of oversized messages skipped, with `OversizePolicy::Skip`.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Header of the last message decoded, before translating its IDs.
+    pub fn last_header(&self) -> Option<&MessageHeader> {
+        self.last_header.as_ref()
+    }
+
+    /// Decode and dispatch every complete message buffered.
+    ///
+    /// Sender and type descriptions update `dispatcher` and the translation tables
+    /// as soon as they are decoded, so the messages after them can be mapped.
+    /// Each user message is translated to local IDs and passed to `inspect` before dispatching;
+    /// those of a type that only wants its latest value are dropped if a newer one follows.
+    /// Any other system commands are returned for the driver to act on.
+    ///
+    /// Returns `VrpnError::Quarantined` if the remote end has now caused too many parse errors.
+    pub fn dispatch(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+        now: Instant,
+        mut inspect: impl FnMut(&GenericMessage),
+    ) -> Result<Vec<ExtendedSystemCommand>> {
+        let parse_errors = dispatcher.stats().errors().count(ErrorKind::Parse);
+        let mut extended = self.apply_system_commands(dispatcher);
+        self.dispatch_buffered(dispatcher, &mut inspect, &mut extended)?;
+        // Everything counted while dispatching this endpoint's messages is on its account.
+        let new_parse_errors = dispatcher
+            .stats()
+            .errors()
+            .count(ErrorKind::Parse)
+            .saturating_sub(parse_errors);
+        if self.parse_errors.record(new_parse_errors, now) {
+            return Err(VrpnError::Quarantined(self.parse_errors.limit()));
+        }
+        Ok(extended)
+    }
+
+    fn dispatch_buffered(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+        inspect: &mut impl FnMut(&GenericMessage),
+        extended: &mut Vec<ExtendedSystemCommand>,
+    ) -> Result<()> {
+        let mut batch = Vec::new();
+        loop {
+            let msg = match self.decoder.decode_next() {
+                Ok(Some(msg)) => msg.into_inner(),
+                Ok(None) => break,
+                Err(BufferUnbufferError::MessageTooLarge { .. }) if self.decoder.is_skipping() => {
+                    // Recoverable: the decoder discards the message as it arrives.
+                    self.skipped += 1;
+                    dispatcher.stats_mut().record_error(ErrorKind::Parse);
+                    continue;
+                }
+                Err(e) => {
+                    let e = VrpnError::from(e);
+                    dispatcher.stats_mut().record_vrpn_error(&e);
+                    return Err(e);
+                }
+            };
+            self.received += 1;
+            self.last_header = Some(msg.header.clone());
+            if msg.is_system_message() {
+                // Dispatch what came before it, then apply it right away.
+                Self::dispatch_batch(dispatcher, &mut batch)?;
+                let cmd = parse_system_message(msg)
+                    .inspect_err(|e| dispatcher.stats_mut().record_vrpn_error(e))?;
+                self.send_system_change(cmd)?;
+                extended.append(&mut self.apply_system_commands(dispatcher));
+            } else {
+                let msg = match self.translation.map_remote_message_to_local(msg) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        // Refers to an ID the remote side never described.
+                        dispatcher.stats_mut().record_error(ErrorKind::Parse);
+                        return Err(e);
+                    }
+                };
+                // Only user messages are timed, from when they arrived rather than when decoded.
+                let start = dispatcher
+                    .stats()
+                    .start_timing()
+                    .map(|now| self.read_at.unwrap_or(now));
+                inspect(&msg);
+                batch.push((start, msg));
+            }
+        }
+        Self::dispatch_batch(dispatcher, &mut batch)
+    }
+
+    /// Dispatch user messages, skipping those superseded by a newer one.
+    fn dispatch_batch(
+        dispatcher: &mut TypeDispatcher,
+        batch: &mut Vec<(Option<Instant>, GenericMessage)>,
+    ) -> Result<()> {
+        let superseded = drop_superseded(batch, |(_, msg)| {
+            let message_type = LocalId(msg.header.message_type);
+            match dispatcher.latest_value_only(message_type) {
+                Some(LatestValueOnly::OutgoingAndIncoming) => {
+                    Some((msg.header.message_type, msg.header.sender))
+                }
+                _ => None,
+            }
+        });
+        if superseded > 0 {
+            dispatcher.stats_mut().record_superseded(superseded);
+        }
+        for (start, msg) in batch.drain(..) {
+            dispatcher.call(&msg)?;
+            dispatcher.stats_mut().finish_timing(start);
+        }
+        Ok(())
+    }
+
+    /// Apply queued system commands, returning those left for the driver.
+    ///
+    /// A command that fails to apply is counted, but doesn't stop the others.
+    fn apply_system_commands(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+    ) -> Vec<ExtendedSystemCommand> {
+        let commands = match self.system_commands.get_mut() {
+            Ok(commands) => std::mem::take(commands),
+            Err(poisoned) => std::mem::take(poisoned.into_inner()),
+        };
+        let mut extended = Vec::new();
+        for cmd in commands {
+            match handle_system_command(dispatcher, &mut self.translation, cmd) {
+                Ok(Some(cmd)) => extended.push(cmd),
+                Ok(None) => {}
+                Err(e) => dispatcher.stats_mut().record_vrpn_error(&e),
+            }
+        }
+        extended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{id_types::SequenceNumber, Description, GenericBody, TimeVal},
+        handler::{Handler, HandlerCode},
+    };
+    use bytes::Bytes;
+    use std::{sync::Arc, time::Duration};
+
+    #[derive(Debug)]
+    struct Record(Arc<Mutex<Vec<Bytes>>>);
+
+    impl Handler for Record {
+        fn handle(&mut self, msg: &GenericMessage) -> Result<HandlerCode> {
+            self.0.lock()?.push(msg.body.clone().into_inner());
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    fn remote_message(sender: i32, body: &'static [u8]) -> Bytes {
+        GenericMessage::from_header_and_body(
+            MessageHeader::new(Some(TimeVal::default()), MessageTypeId(0), SenderId(sender)),
+            GenericBody::new(Bytes::from_static(body)),
+        )
+        .into_sequenced_message(SequenceNumber(0))
+        .try_into_buf()
+        .unwrap()
+    }
+
+    /// A receiver that knows about remote type 0 and the given remote senders.
+    fn described(dispatcher: &mut TypeDispatcher, senders: &[&'static [u8]]) -> ProtocolReceiver {
+        let mut receiver = ProtocolReceiver::new();
+        let types = std::iter::once(SystemCommand::TypeDescription(
+            Description::from_id_and_name(MessageTypeId(0), Bytes::from_static(b"pose")),
+        ));
+        let senders = senders.iter().enumerate().map(|(i, name)| {
+            SystemCommand::SenderDescription(Description::from_id_and_name(
+                SenderId(i as i32),
+                Bytes::from_static(name),
+            ))
+        });
+        for desc in types.chain(senders) {
+            handle_system_command(dispatcher, receiver.translation_tables_mut(), desc).unwrap();
+        }
+        receiver
+    }
+
+    #[test]
+    fn incoming_latest_value_only() {
+        let mut dispatcher = TypeDispatcher::new();
+        let mut receiver = described(&mut dispatcher, &[b"Tracker0", b"Tracker1"]);
+        let pose = receiver
+            .translation_tables()
+            .map_remote_message_to_local(GenericMessage::from_header_and_body(
+                MessageHeader::new(None, MessageTypeId(0), SenderId(0)),
+                GenericBody::default(),
+            ))
+            .map(|msg| LocalId(msg.header.message_type))
+            .unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        dispatcher
+            .add_handler(Box::new(Record(Arc::clone(&received))), Some(pose), None)
+            .unwrap();
+        dispatcher.set_latest_value_only(pose, Some(LatestValueOnly::OutgoingAndIncoming));
+
+        let now = Instant::now();
+        for msg in [
+            remote_message(0, b"old"),
+            remote_message(1, b"other"),
+            remote_message(0, b"new"),
+        ] {
+            receiver.receive(&msg, now);
+        }
+        receiver.dispatch(&mut dispatcher, now, |_| {}).unwrap();
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![Bytes::from_static(b"other"), Bytes::from_static(b"new")]
+        );
+        assert_eq!(dispatcher.stats().superseded_messages(), 1);
+        assert_eq!(receiver.received(), 3);
+
+        // Only outgoing: all are dispatched.
+        dispatcher.set_latest_value_only(pose, Some(LatestValueOnly::Outgoing));
+        receiver.receive(&remote_message(0, b"a"), now);
+        receiver.receive(&remote_message(0, b"b"), now);
+        receiver.dispatch(&mut dispatcher, now, |_| {}).unwrap();
+        assert_eq!(received.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn latency_from_read() {
+        let mut dispatcher = TypeDispatcher::new();
+        dispatcher.stats_mut().set_latency_instrumentation(true);
+        let mut receiver = described(&mut dispatcher, &[b"Tracker0"]);
+
+        let wait = Duration::from_millis(50);
+        receiver.receive(&remote_message(0, b"a"), Instant::now() - wait);
+        receiver
+            .dispatch(&mut dispatcher, Instant::now(), |_| {})
+            .unwrap();
+        let latency = dispatcher.stats().decode_latency().unwrap();
+        assert_eq!(latency.count(), 1);
+        assert!(latency.min().unwrap() >= wait);
+    }
+
+    #[test]
+    fn quarantine() {
+        use crate::codec::{MessageSizeLimit, OversizePolicy};
+        let mut dispatcher = TypeDispatcher::new();
+        let mut receiver = described(&mut dispatcher, &[b"Tracker0"]).with_decoder(
+            MessageDecoder::new()
+                .with_limit(MessageSizeLimit::new(40).with_policy(OversizePolicy::Skip)),
+        );
+        receiver.set_parse_error_limit(ParseErrorLimit::new(1, Duration::from_secs(60)));
+        let oversized = remote_message(0, b"0123456789abcdef0123456789abcdef");
+
+        // Skipping an oversized message is survivable...
+        let now = Instant::now();
+        receiver.receive(&oversized, now);
+        receiver.receive(&remote_message(0, b"ok"), now);
+        receiver.dispatch(&mut dispatcher, now, |_| {}).unwrap();
+        assert_eq!(receiver.skipped(), 1);
+        assert_eq!(receiver.received(), 1);
+
+        // ...but not too many of them.
+        receiver.receive(&oversized, now);
+        assert!(matches!(
+            receiver.dispatch(&mut dispatcher, now, |_| {}),
+            Err(VrpnError::Quarantined(_))
+        ));
+        assert_eq!(dispatcher.stats().errors().count(ErrorKind::Parse), 2);
+    }
+}
@@ -0,0 +1,458 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Queueing, expiring, shaping and sequencing the messages an endpoint sends.
+
+use super::{
+    outgoing_trace::{OutgoingNames, OutgoingTrace},
+    shaper::Shaper,
+};
+use crate::{
+    buffer_unbuffer::BufferSize,
+    clock::Instant,
+    data_types::{
+        id_types::{SenderId, SequenceNumber},
+        ClassOfService, GenericMessage, MessageHeader, MessageSize, MessageTypeId, ProtocolProfile,
+    },
+    vrpn_async::BandwidthLimit,
+    Result,
+};
+use bytes::{Bytes, BytesMut};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+/// A message waiting to be sent, with the time after which it is no longer worth sending.
+#[derive(Debug, Clone)]
+pub(crate) struct QueuedMessage {
+    pub(crate) msg: GenericMessage,
+    pub(crate) deadline: Option<Instant>,
+    /// Drop this if a newer message of the same type and sender is queued before it is sent.
+    pub(crate) latest_only: bool,
+}
+
+impl QueuedMessage {
+    /// Queue a message at `now`, expiring after `max_age` if it is low-latency.
+    pub(crate) fn new(
+        msg: GenericMessage,
+        class: ClassOfService,
+        max_age: Option<Duration>,
+        now: Instant,
+    ) -> QueuedMessage {
+        let deadline = max_age
+            .filter(|_| class.contains(ClassOfService::LOW_LATENCY))
+            .map(|age| now + age);
+        QueuedMessage {
+            msg,
+            deadline,
+            latest_only: false,
+        }
+    }
+
+    /// Mark as superseded by any newer message of the same type and sender queued before it is sent.
+    pub(crate) fn latest_value_only(self) -> QueuedMessage {
+        QueuedMessage {
+            latest_only: true,
+            ..self
+        }
+    }
+
+    fn supersede_key(&self) -> Option<(MessageTypeId, SenderId)> {
+        self.latest_only
+            .then_some((self.msg.header.message_type, self.msg.header.sender))
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| now > deadline)
+    }
+}
+
+impl From<GenericMessage> for QueuedMessage {
+    fn from(msg: GenericMessage) -> QueuedMessage {
+        QueuedMessage {
+            msg,
+            deadline: None,
+            latest_only: false,
+        }
+    }
+}
+
+/// Progress of a `ProtocolSender`, shared with whatever drives it for stats and diagnostics.
+#[derive(Debug, Default)]
+pub(crate) struct SendCounters {
+    /// Messages dropped for expiring, not yet recorded in the stats.
+    pub(crate) expired: AtomicU64,
+    /// Messages dropped for being superseded, not yet recorded in the stats.
+    pub(crate) superseded: AtomicU64,
+    /// Messages ever queued.
+    pub(crate) queued: AtomicU64,
+    /// Messages ever taken off the queue, whether framed or dropped.
+    pub(crate) finished: AtomicU64,
+    /// Messages ever framed for writing.
+    pub(crate) written: AtomicU64,
+    /// Bytes written by the driver but not yet flushed.
+    pub(crate) unflushed_bytes: AtomicUsize,
+    pub(crate) last_written: Mutex<Option<MessageHeader>>,
+    /// Whether to log each message written, and the pings awaiting pongs.
+    pub(crate) trace: OutgoingTrace,
+    /// Messages written, not yet recorded in the stats.
+    pub(crate) unrecorded_messages: AtomicU64,
+    /// Bytes written, not yet recorded in the stats.
+    pub(crate) unrecorded_bytes: AtomicU64,
+    /// Messages delayed by the bandwidth limit, not yet recorded in the stats.
+    pub(crate) throttled: AtomicU64,
+    // Here rather than in the sender, so the limit can change while a driver owns the sender.
+    shaper: Mutex<Shaper>,
+}
+
+impl SendCounters {
+    /// Messages queued but not yet written or dropped.
+    pub(crate) fn pending(&self) -> u64 {
+        let finished = self.finished.load(Ordering::Relaxed);
+        self.queued.load(Ordering::Relaxed).saturating_sub(finished)
+    }
+
+    /// Apply a new bandwidth limit, starting with a full allowance.
+    pub(crate) fn set_bandwidth_limit(&self, limit: BandwidthLimit) {
+        *self.shaper.lock().unwrap_or_else(PoisonError::into_inner) = Shaper::new(limit);
+    }
+
+    /// How long to wait before writing a message of `size` bytes.
+    fn shaping_delay(&self, size: usize, now: Instant) -> Duration {
+        self.shaper
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .delay(size, now)
+    }
+
+    pub(crate) fn last_written(&self) -> Option<MessageHeader> {
+        self.last_written
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+/// What a `ProtocolSender` has for its driver to do next.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transmit {
+    /// A sequenced and framed message, to write now.
+    Frame(Bytes),
+    /// Nothing may be written before this time, unless a system message is queued meanwhile.
+    WaitUntil(Instant),
+    /// Nothing is queued.
+    Idle,
+}
+
+/// Sans-IO sending side of a reliable connection: queues messages,
+/// and hands them back sequenced and framed once they may be written.
+///
+/// Low-latency messages still queued past their maximum age are dropped,
+/// as are "latest value only" messages a newer one supersedes,
+/// and user messages are held back to stay within the bandwidth limit.
+/// A driver calls `poll_transmit` until it has nothing to write,
+/// and calls it again once more is queued or the time it returned comes.
+#[derive(Debug)]
+pub struct ProtocolSender {
+    queue: VecDeque<QueuedMessage>,
+    /// A user message the bandwidth limit allows out at the given time.
+    /// System messages queued meanwhile are written first, other user messages wait behind it.
+    throttled: Option<(GenericMessage, Instant)>,
+    next_sequence: u32,
+    profile: ProtocolProfile,
+    max_send_age: Option<Duration>,
+    // Learned even while not tracing, so tracing can be enabled at any time.
+    names: OutgoingNames,
+    counters: Arc<SendCounters>,
+}
+
+impl ProtocolSender {
+    /// Create a sender numbering its messages from `next_sequence`.
+    pub fn new(next_sequence: SequenceNumber) -> ProtocolSender {
+        ProtocolSender {
+            queue: VecDeque::new(),
+            throttled: None,
+            next_sequence: next_sequence.0,
+            profile: ProtocolProfile::VRPN,
+            max_send_age: None,
+            names: OutgoingNames::default(),
+            counters: Arc::default(),
+        }
+    }
+
+    /// Frame messages with a protocol profile other than `ProtocolProfile::VRPN`.
+    pub fn with_profile(self, profile: ProtocolProfile) -> ProtocolSender {
+        ProtocolSender { profile, ..self }
+    }
+
+    /// Drop low-latency messages not written within `max_age` of being queued:
+    /// see `LowLatencyConfig::max_send_age`.
+    pub fn with_max_send_age(self, max_age: Option<Duration>) -> ProtocolSender {
+        ProtocolSender {
+            max_send_age: max_age,
+            ..self
+        }
+    }
+
+    /// Continue numbering from a handshake that sent messages of its own.
+    pub(crate) fn set_next_sequence(&mut self, next_sequence: SequenceNumber) {
+        self.next_sequence = next_sequence.0;
+    }
+
+    /// Delay writing user messages to stay within a limit, starting with a full allowance.
+    pub fn set_bandwidth_limit(&self, limit: BandwidthLimit) {
+        self.counters.set_bandwidth_limit(limit);
+    }
+
+    pub(crate) fn counters(&self) -> Arc<SendCounters> {
+        Arc::clone(&self.counters)
+    }
+
+    /// Queue a message at `now`, to be dropped if low-latency and not written within the maximum age.
+    pub fn queue(&mut self, msg: GenericMessage, class: ClassOfService, now: Instant) {
+        self.push(QueuedMessage::new(msg, class, self.max_send_age, now));
+    }
+
+    /// Like `queue`, but dropping the message if a newer one
+    /// of the same type from the same sender is queued before it is written.
+    pub fn queue_latest_value(&mut self, msg: GenericMessage, class: ClassOfService, now: Instant) {
+        self.push(QueuedMessage::new(msg, class, self.max_send_age, now).latest_value_only());
+    }
+
+    pub(crate) fn push(&mut self, msg: QueuedMessage) {
+        if let Some(key) = msg.supersede_key() {
+            let before = self.queue.len();
+            self.queue
+                .retain(|queued| queued.supersede_key() != Some(key));
+            let superseded = (before - self.queue.len()) as u64;
+            self.counters
+                .superseded
+                .fetch_add(superseded, Ordering::Relaxed);
+            self.counters
+                .finished
+                .fetch_add(superseded, Ordering::Relaxed);
+        }
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+        self.queue.push_back(msg);
+    }
+
+    /// Messages queued but not yet written or dropped.
+    pub fn pending(&self) -> u64 {
+        self.counters.pending()
+    }
+
+    /// Take the next message that may be written at `now`, sequenced and framed.
+    pub fn poll_transmit(&mut self, now: Instant) -> Result<Transmit> {
+        loop {
+            if let Some((msg, allowed)) = self.throttled.take() {
+                if now >= allowed {
+                    // Sequenced only now, after any system messages written while waiting.
+                    return self.frame(msg).map(Transmit::Frame);
+                }
+                self.throttled = Some((msg, allowed));
+                let system = self
+                    .queue
+                    .iter()
+                    .position(|queued| queued.msg.header.message_type.is_system_message());
+                match system.and_then(|i| self.queue.remove(i)) {
+                    Some(queued) => match self.take(queued, now) {
+                        Some(msg) => return self.frame(msg).map(Transmit::Frame),
+                        None => continue,
+                    },
+                    None => return Ok(Transmit::WaitUntil(allowed)),
+                }
+            }
+            let msg = match self.queue.pop_front() {
+                Some(queued) => match self.take(queued, now) {
+                    Some(msg) => msg,
+                    None => continue,
+                },
+                None => return Ok(Transmit::Idle),
+            };
+            if !msg.header.message_type.is_system_message() {
+                let size = MessageSize::try_from_unpadded_body_size(msg.body.buffer_size())?;
+                let delay = self
+                    .counters
+                    .shaping_delay(self.profile.padded_message_size(size), now);
+                if delay > Duration::ZERO {
+                    self.counters.throttled.fetch_add(1, Ordering::Relaxed);
+                    self.throttled = Some((msg, now + delay));
+                    continue;
+                }
+            }
+            return self.frame(msg).map(Transmit::Frame);
+        }
+    }
+
+    /// Take a message off the queue, returning it if it has not expired.
+    fn take(&self, msg: QueuedMessage, now: Instant) -> Option<GenericMessage> {
+        self.counters.finished.fetch_add(1, Ordering::Relaxed);
+        if msg.is_expired(now) {
+            self.counters.expired.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(msg.msg)
+    }
+
+    /// Sequence and frame a message, counting it as written.
+    fn frame(&mut self, msg: GenericMessage) -> Result<Bytes> {
+        let seq = self.next_sequence;
+        self.next_sequence = seq.wrapping_add(1);
+        self.names.learn(&msg);
+        let header = msg.header.clone();
+        let msg = msg.into_sequenced_message(SequenceNumber(seq));
+        let mut buf =
+            BytesMut::with_capacity(self.profile.padded_message_size(msg.message_size()?));
+        msg.buffer_to_with_profile(&mut buf, &self.profile)?;
+
+        let counters = &self.counters;
+        if counters.trace.is_enabled() {
+            counters.trace.sent(&self.names, &header, seq, buf.len());
+        }
+        counters.written.fetch_add(1, Ordering::Relaxed);
+        counters.unrecorded_messages.fetch_add(1, Ordering::Relaxed);
+        counters
+            .unrecorded_bytes
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        *counters
+            .last_written
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(header);
+        Ok(buf.freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::MessageDecoder,
+        data_types::{GenericBody, Message, TimeVal},
+    };
+
+    fn test_message(body: &'static [u8]) -> GenericMessage {
+        GenericMessage::from_header_and_body(
+            MessageHeader::new(Some(TimeVal::default()), MessageTypeId(1), SenderId(2)),
+            GenericBody::new(Bytes::from_static(body)),
+        )
+    }
+
+    /// Everything the sender will write at `now`, decoded again.
+    fn transmit_all(sender: &mut ProtocolSender, now: Instant) -> Vec<GenericMessage> {
+        let mut decoder = MessageDecoder::new();
+        while let Transmit::Frame(frame) = sender.poll_transmit(now).unwrap() {
+            decoder.extend_from_slice(&frame);
+        }
+        std::iter::from_fn(|| decoder.decode_next().unwrap())
+            .map(|msg| msg.into_inner())
+            .collect()
+    }
+
+    #[test]
+    fn drops_expired() {
+        let start = Instant::now();
+        let mut sender = ProtocolSender::new(SequenceNumber(1))
+            .with_max_send_age(Some(Duration::from_millis(10)));
+        sender.queue(test_message(b"stale"), ClassOfService::LOW_LATENCY, start);
+        sender.queue(test_message(b"reliable"), ClassOfService::RELIABLE, start);
+        let later = start + Duration::from_millis(20);
+        sender.queue(test_message(b"fresh"), ClassOfService::LOW_LATENCY, later);
+        let mut decoder = MessageDecoder::new();
+        while let Transmit::Frame(frame) = sender.poll_transmit(later).unwrap() {
+            decoder.extend_from_slice(&frame);
+        }
+
+        let counters = sender.counters();
+        assert_eq!(counters.expired.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.written.load(Ordering::Relaxed), 2);
+        assert_eq!(counters.finished.load(Ordering::Relaxed), 3);
+        assert_eq!(sender.pending(), 0);
+        assert_eq!(counters.last_written(), Some(test_message(b"fresh").header));
+        // Expired messages don't use up sequence numbers.
+        assert_eq!(
+            decoder.decode_next().unwrap(),
+            Some(test_message(b"reliable").into_sequenced_message(SequenceNumber(1)))
+        );
+        assert_eq!(
+            decoder.decode_next().unwrap(),
+            Some(test_message(b"fresh").into_sequenced_message(SequenceNumber(2)))
+        );
+    }
+
+    #[test]
+    fn drops_superseded() {
+        let now = Instant::now();
+        let mut sender = ProtocolSender::new(SequenceNumber(0));
+        let other_sender = GenericMessage::from_header_and_body(
+            MessageHeader::new(Some(TimeVal::default()), MessageTypeId(1), SenderId(3)),
+            GenericBody::new(Bytes::from_static(b"other")),
+        );
+        sender.queue_latest_value(test_message(b"old"), ClassOfService::RELIABLE, now);
+        sender.queue_latest_value(other_sender.clone(), ClassOfService::RELIABLE, now);
+        sender.queue(test_message(b"reliable"), ClassOfService::RELIABLE, now);
+        sender.queue_latest_value(test_message(b"new"), ClassOfService::RELIABLE, now);
+        let counters = sender.counters();
+        assert_eq!(counters.superseded.load(Ordering::Relaxed), 1);
+        assert_eq!(sender.pending(), 3);
+
+        assert_eq!(
+            transmit_all(&mut sender, now),
+            vec![
+                other_sender,
+                test_message(b"reliable"),
+                test_message(b"new")
+            ]
+        );
+        assert_eq!(counters.finished.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn throttles_user_messages() {
+        let start = Instant::now();
+        let mut sender = ProtocolSender::new(SequenceNumber(0));
+        sender.set_bandwidth_limit(BandwidthLimit::default().with_messages_per_sec(10));
+        // A second's worth goes out at once, the rest wait their turn.
+        for _ in 0..10 {
+            sender.queue(test_message(b"report"), ClassOfService::RELIABLE, start);
+        }
+        sender.queue(test_message(b"last"), ClassOfService::RELIABLE, start);
+        assert_eq!(transmit_all(&mut sender, start).len(), 10);
+        let allowed = match sender.poll_transmit(start).unwrap() {
+            Transmit::WaitUntil(allowed) => allowed,
+            other => panic!("expected to wait, got {:?}", other),
+        };
+        assert!(allowed > start);
+        let counters = sender.counters();
+        assert_eq!(counters.throttled.load(Ordering::Relaxed), 1);
+
+        // System messages go out while the report waits, and are sequenced in the order written.
+        let system = GenericMessage::from_header_and_body(
+            MessageHeader::new(Some(TimeVal::default()), MessageTypeId(-1), SenderId(2)),
+            GenericBody::new(Bytes::from_static(b"system")),
+        );
+        sender.queue(system.clone(), ClassOfService::RELIABLE, start);
+        sender.queue(test_message(b"behind"), ClassOfService::RELIABLE, start);
+        let mut decoder = MessageDecoder::new();
+        while let Transmit::Frame(frame) = sender.poll_transmit(start).unwrap() {
+            decoder.extend_from_slice(&frame);
+        }
+        assert_eq!(
+            decoder.decode_next().unwrap(),
+            Some(system.into_sequenced_message(SequenceNumber(10)))
+        );
+        assert_eq!(decoder.decode_next().unwrap(), None);
+
+        assert_eq!(
+            transmit_all(&mut sender, allowed),
+            vec![test_message(b"last")]
+        );
+        assert_eq!(counters.throttled.load(Ordering::Relaxed), 2);
+        assert_eq!(counters.unrecorded_messages.load(Ordering::Relaxed), 12);
+    }
+}
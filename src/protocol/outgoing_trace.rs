@@ -0,0 +1,246 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Logging a compact record of each message sent, to debug "the server never saw my message".
+//!
+//! Each record has the message type and sender names, the sequence number, and the size on the wire.
+//! With the `tracing` feature, records are `tracing` events at info level with target
+//! `vrpn::outgoing`, otherwise they are printed to stderr.
+//!
+//! The remote side answers each ping only after handling everything sent before it,
+//! so when a pong arrives, that is logged too, with the sequence number of the ping it answers:
+//! everything up to that one has been received.
+
+use crate::{
+    data_types::{
+        constants, id_types::SenderId, GenericMessage, MessageHeader, MessageTypeId,
+        MessageTypeName, SenderName,
+    },
+    endpoint::{parse_system_message, SystemCommand},
+    message_display::{NameLookup, SenderLabel, TypeLabel},
+    ping::{PING_MESSAGE, PONG_MESSAGE},
+    TypeDispatcher,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+/// Pings remembered while waiting for their pongs: older ones are forgotten.
+const MAX_UNANSWERED_PINGS: usize = 64;
+
+/// Whether to trace outgoing messages, shared by both halves of an endpoint.
+#[derive(Debug, Default)]
+pub(crate) struct OutgoingTrace {
+    enabled: AtomicBool,
+    /// Sequence numbers of pings sent but not yet answered, oldest first.
+    pings: Mutex<VecDeque<u32>>,
+}
+
+impl OutgoingTrace {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            self.lock_pings().clear();
+        }
+    }
+
+    fn lock_pings(&self) -> std::sync::MutexGuard<'_, VecDeque<u32>> {
+        self.pings.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Log a message just written with the given sequence number and size.
+    pub(crate) fn sent(
+        &self,
+        names: &OutgoingNames,
+        header: &MessageHeader,
+        seq: u32,
+        size: usize,
+    ) {
+        let message_type = names.message_type(header.message_type);
+        if let Some(name) = &message_type.name {
+            if *name == PING_MESSAGE.0 {
+                let mut pings = self.lock_pings();
+                if pings.len() == MAX_UNANSWERED_PINGS {
+                    pings.pop_front();
+                }
+                pings.push_back(seq);
+            }
+        }
+        log_sent(&message_type, &names.sender(header.sender), seq, size);
+    }
+
+    /// The local ID of pong messages, to recognize them with `received`.
+    pub(crate) fn pong_type(&self, dispatcher: &TypeDispatcher) -> Option<MessageTypeId> {
+        if self.is_enabled() {
+            dispatcher.get_type_id(PONG_MESSAGE).map(|id| id.0)
+        } else {
+            None
+        }
+    }
+
+    /// Note a received message (with local IDs): if it is a pong, log which ping it answers.
+    pub(crate) fn received(&self, msg: &GenericMessage, pong_type: MessageTypeId) {
+        if msg.header.message_type == pong_type {
+            let answered = self.lock_pings().pop_front();
+            log_pong(answered);
+        }
+    }
+}
+
+/// Names of the IDs used in outgoing messages, learned from the descriptions sent before them.
+#[derive(Debug, Default)]
+pub(crate) struct OutgoingNames {
+    senders: HashMap<SenderId, SenderName>,
+    message_types: HashMap<MessageTypeId, MessageTypeName>,
+}
+
+impl OutgoingNames {
+    /// Learn names from a message about to be written, if it's a description.
+    pub(crate) fn learn(&mut self, msg: &GenericMessage) {
+        let message_type = msg.header.message_type;
+        if message_type != constants::SENDER_DESCRIPTION
+            && message_type != constants::TYPE_DESCRIPTION
+        {
+            return;
+        }
+        match parse_system_message(msg.clone()) {
+            Ok(SystemCommand::SenderDescription(desc)) => {
+                self.senders.insert(desc.which, SenderName(desc.name));
+            }
+            Ok(SystemCommand::TypeDescription(desc)) => {
+                self.message_types
+                    .insert(desc.which, MessageTypeName(desc.name));
+            }
+            _ => {}
+        }
+    }
+
+    fn message_type(&self, id: MessageTypeId) -> TypeLabel {
+        TypeLabel::new(id, self)
+    }
+
+    fn sender(&self, id: SenderId) -> SenderLabel {
+        SenderLabel::new(id, self)
+    }
+}
+
+impl NameLookup for OutgoingNames {
+    fn sender_name(&self, id: SenderId) -> Option<SenderName> {
+        self.senders.get(&id).cloned()
+    }
+
+    fn message_type_name(&self, id: MessageTypeId) -> Option<MessageTypeName> {
+        self.message_types.get(&id).cloned()
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn log_sent(message_type: &TypeLabel, sender: &SenderLabel, seq: u32, size: usize) {
+    tracing::info!(
+        target: "vrpn::outgoing",
+        seq,
+        size,
+        %sender,
+        %message_type,
+        "sent"
+    );
+}
+
+#[cfg(not(feature = "tracing"))]
+fn log_sent(message_type: &TypeLabel, sender: &SenderLabel, seq: u32, size: usize) {
+    eprintln!(
+        "[vrpn::outgoing] sent seq {} ({} bytes): {} from {}",
+        seq, size, message_type, sender
+    );
+}
+
+#[cfg(feature = "tracing")]
+fn log_pong(answered: Option<u32>) {
+    match answered {
+        Some(seq) => tracing::info!(
+            target: "vrpn::outgoing",
+            acknowledged_through = seq,
+            "pong"
+        ),
+        None => tracing::info!(target: "vrpn::outgoing", "pong for an unrecorded ping"),
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+fn log_pong(answered: Option<u32>) {
+    match answered {
+        Some(seq) => eprintln!(
+            "[vrpn::outgoing] pong: received everything through seq {}",
+            seq
+        ),
+        None => eprintln!("[vrpn::outgoing] pong for an unrecorded ping"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{id_types::LocalId, GenericBody, Message};
+    use bytes::Bytes;
+
+    fn message(message_type: MessageTypeId) -> GenericMessage {
+        GenericMessage::from_header_and_body(
+            MessageHeader::new(None, message_type, SenderId(0)),
+            GenericBody::new(Bytes::new()),
+        )
+    }
+
+    #[test]
+    fn pings_and_pongs() {
+        let mut dispatcher = TypeDispatcher::new();
+        let sender = dispatcher.register_sender("Tracker0").unwrap().into_inner();
+        let ping = dispatcher.register_type(PING_MESSAGE).unwrap().into_inner();
+        let pong = dispatcher.register_type(PONG_MESSAGE).unwrap().into_inner();
+        let mut names = OutgoingNames::default();
+        for desc in dispatcher.pack_all_descriptions().unwrap() {
+            names.learn(&desc);
+        }
+        assert_eq!(names.sender(sender.0).to_string(), "Tracker0");
+        assert_eq!(
+            names.message_type(ping.0).to_string(),
+            "vrpn_Base ping_message"
+        );
+        assert_eq!(
+            names
+                .message_type(constants::SENDER_DESCRIPTION)
+                .to_string(),
+            "SenderDescription"
+        );
+        assert_eq!(names.sender(SenderId(7)).to_string(), "sender 7");
+
+        let trace = OutgoingTrace::default();
+        assert_eq!(trace.pong_type(&dispatcher), None);
+        trace.set_enabled(true);
+        assert_eq!(trace.pong_type(&dispatcher), Some(pong.0));
+        let ping_msg = message(ping.0);
+        trace.sent(&names, &ping_msg.header, 5, 24);
+        trace.sent(&names, &ping_msg.header, 9, 24);
+        trace.sent(
+            &names,
+            &message(LocalId(MessageTypeId(42)).0).header,
+            10,
+            24,
+        );
+        assert_eq!(*trace.lock_pings(), vec![5, 9]);
+        trace.received(&message(pong.0), pong.0);
+        assert_eq!(*trace.lock_pings(), vec![9]);
+        trace.received(&ping_msg, pong.0);
+        assert_eq!(*trace.lock_pings(), vec![9]);
+        trace.set_enabled(false);
+        assert!(trace.lock_pings().is_empty());
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Counting an endpoint's parse errors against its `ParseErrorLimit`.
+
+use crate::{clock::Instant, vrpn_async::ParseErrorLimit};
+use std::collections::VecDeque;
+
+/// The times of an endpoint's recent parse errors, checked against its limit.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ParseErrorWindow {
+    limit: ParseErrorLimit,
+    recent: VecDeque<Instant>,
+}
+
+impl ParseErrorWindow {
+    pub(crate) fn limit(&self) -> ParseErrorLimit {
+        self.limit
+    }
+
+    pub(crate) fn set_limit(&mut self, limit: ParseErrorLimit) {
+        self.limit = limit;
+    }
+
+    /// Note some parse errors, returning true if the limit is now exceeded.
+    pub(crate) fn record(&mut self, count: u64, now: Instant) -> bool {
+        if count == 0 {
+            return false;
+        }
+        while let Some(&oldest) = self.recent.front() {
+            if now.saturating_duration_since(oldest) < self.limit.window {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        // Only the newest max_errors + 1 matter.
+        let keep = self.limit.max_errors as usize + 1;
+        for _ in 0..count.min(keep as u64) {
+            self.recent.push_back(now);
+        }
+        while self.recent.len() > keep {
+            self.recent.pop_front();
+        }
+        self.recent.len() > self.limit.max_errors as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn window() {
+        let start = Instant::now();
+        let mut window = ParseErrorWindow::default();
+        window.set_limit(ParseErrorLimit::new(3, Duration::from_secs(1)));
+        assert!(!window.record(0, start));
+        assert!(!window.record(2, start));
+        assert!(!window.record(1, start + Duration::from_millis(500)));
+        // The first two have aged out.
+        assert!(!window.record(2, start + Duration::from_millis(1200)));
+        assert!(window.record(1, start + Duration::from_millis(1300)));
+        assert!(window.record(100, start + Duration::from_secs(10)));
+
+        assert!(ParseErrorLimit::new(3, Duration::ZERO).validate().is_err());
+        assert!(ParseErrorLimit::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Applying a `BandwidthLimit` to the messages an endpoint sends.
+
+use crate::{clock::Instant, vrpn_async::BandwidthLimit};
+use std::time::Duration;
+
+/// Tokens accrue at `rate` per second, up to one second's worth.
+///
+/// Taking more than are available leaves a debt, paid off before anything else is taken,
+/// so a message bigger than the bucket still goes out eventually.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, now: Instant) -> TokenBucket {
+        let rate = f64::from(rate.max(1));
+        TokenBucket {
+            rate,
+            tokens: rate,
+            refilled: now,
+        }
+    }
+
+    /// Take tokens, returning how long to wait before the debt, if any, is paid off.
+    fn take(&mut self, count: f64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+        self.tokens -= count;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Applies a `BandwidthLimit` to the messages an endpoint sends.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Shaper {
+    bytes: Option<TokenBucket>,
+    messages: Option<TokenBucket>,
+}
+
+impl Shaper {
+    /// Start applying a limit, with full buckets.
+    pub(crate) fn new(limit: BandwidthLimit) -> Shaper {
+        let now = Instant::now();
+        Shaper {
+            bytes: limit.bytes_per_sec.map(|rate| TokenBucket::new(rate, now)),
+            messages: limit
+                .messages_per_sec
+                .map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+
+    /// Account for a message of `size` bytes, returning how long to wait before sending it.
+    pub(crate) fn delay(&mut self, size: usize, now: Instant) -> Duration {
+        let bytes = self
+            .bytes
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.take(size as f64, now));
+        let messages = self
+            .messages
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.take(1.0, now));
+        bytes.max(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_buckets() {
+        let start = Instant::now();
+        let mut unlimited = Shaper::new(BandwidthLimit::default());
+        assert_eq!(unlimited.delay(1_000_000, start), Duration::ZERO);
+
+        let mut shaper = Shaper::new(BandwidthLimit::default().with_messages_per_sec(10));
+        // A second's worth goes out at once...
+        for _ in 0..10 {
+            assert_eq!(shaper.delay(100, start), Duration::ZERO);
+        }
+        // ...then one every 100ms.
+        assert_eq!(shaper.delay(100, start), Duration::from_millis(100));
+        let later = start + Duration::from_millis(100);
+        assert_eq!(shaper.delay(100, later), Duration::from_millis(100));
+
+        // Oversized messages leave a debt.
+        let mut shaper = Shaper::new(BandwidthLimit::default().with_bytes_per_sec(1000));
+        assert_eq!(shaper.delay(3000, start), Duration::from_secs(2));
+        let later = start + Duration::from_secs(2);
+        assert_eq!(shaper.delay(500, later), Duration::from_millis(500));
+    }
+}
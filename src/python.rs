@@ -0,0 +1,369 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Python bindings, for scripting and test rigs.
+//!
+//! The connection runs on its own thread (see `vrpn_async_std::threaded`), without the GIL.
+//! Python code subscribes to devices, then calls `poll` to take what was received as dicts;
+//! with a timeout, `poll` waits for the first report with the GIL released.
+//!
+//! ```python
+//! import vrpn
+//!
+//! connection = vrpn.Connection("localhost")
+//! connection.subscribe_tracker("Tracker0")
+//! while True:
+//!     for report in connection.poll(timeout=1.0):
+//!         print(report["device"], report["pos"])
+//! ```
+//!
+//! To build the extension module, e.g. with `maturin`,
+//! enable the `python` and `pyo3/extension-module` features, and build the library as a cdylib:
+//!
+//! ```sh
+//! cargo rustc --lib --release --features python,pyo3/extension-module --crate-type cdylib
+//! ```
+
+use crate::{
+    analog::{AnalogReport, ChangeChannelRequest, ChangeChannelsRequest},
+    buffer_unbuffer::UnbufferFrom,
+    button::ButtonChange,
+    connection::Connection,
+    data_types::{
+        id_types::{LocalId, SenderId},
+        ClassOfService, SenderName, TimeVal, TypedMessage, TypedMessageBody,
+    },
+    handler::{HandlerCode, TypedHandler},
+    tracker::PoseReport,
+    vrpn_async_std::{connection_ip::ConnectionIp, threaded::ThreadedConnection},
+    ServerInfo, VrpnError,
+};
+use pyo3::{exceptions::PyRuntimeError, prelude::*, types::PyDict};
+use std::{
+    fmt,
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+impl From<VrpnError> for PyErr {
+    fn from(e: VrpnError) -> PyErr {
+        PyRuntimeError::new_err(e.to_string())
+    }
+}
+
+/// A received report, waiting to become a dict.
+#[derive(Debug)]
+enum Report {
+    Tracker(SenderName, TypedMessage<PoseReport>),
+    Button(SenderName, TypedMessage<ButtonChange>),
+    Analog(SenderName, TypedMessage<AnalogReport>),
+}
+
+fn seconds(time: TimeVal) -> f64 {
+    f64::from(time.seconds().0) + f64::from(time.microseconds().0) * 1.0e-6
+}
+
+impl Report {
+    fn into_dict(self, py: Python<'_>) -> PyResult<Bound<'_, PyDict>> {
+        let dict = PyDict::new(py);
+        let (device, kind, time) = match &self {
+            Report::Tracker(device, msg) => (device, "tracker", msg.header.time),
+            Report::Button(device, msg) => (device, "button", msg.header.time),
+            Report::Analog(device, msg) => (device, "analog", msg.header.time),
+        };
+        dict.set_item("device", String::from_utf8_lossy(&device.0))?;
+        dict.set_item("type", kind)?;
+        dict.set_item("time", seconds(time))?;
+        match self {
+            Report::Tracker(_, msg) => {
+                let PoseReport { sensor, pos, quat } = msg.body;
+                dict.set_item("sensor", sensor.0)?;
+                dict.set_item("pos", (pos.x, pos.y, pos.z))?;
+                dict.set_item("quat", (quat.v.x, quat.v.y, quat.v.z, quat.s))?;
+            }
+            Report::Button(_, msg) => {
+                dict.set_item("button", msg.body.button)?;
+                dict.set_item("pressed", msg.body.pressed)?;
+            }
+            Report::Analog(_, msg) => {
+                dict.set_item("values", msg.body.values)?;
+            }
+        }
+        Ok(dict)
+    }
+}
+
+/// Forwards one device's messages of one type to the shared report channel.
+struct Forwarder<T: TypedMessageBody> {
+    device: SenderName,
+    wrap: fn(SenderName, TypedMessage<T>) -> Report,
+    tx: Sender<Report>,
+}
+
+impl<T: TypedMessageBody> fmt::Debug for Forwarder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Forwarder")
+            .field("device", &self.device)
+            .finish()
+    }
+}
+
+impl<T> TypedHandler for Forwarder<T>
+where
+    T: TypedMessageBody + UnbufferFrom + fmt::Debug + Clone + Send + Sync,
+{
+    type Item = T;
+    fn handle_typed(&mut self, msg: &TypedMessage<T>) -> crate::Result<HandlerCode> {
+        let report = (self.wrap)(self.device.clone(), msg.clone());
+        Ok(match self.tx.send(report) {
+            Ok(()) => HandlerCode::ContinueProcessing,
+            Err(_) => HandlerCode::RemoveThisHandler,
+        })
+    }
+}
+
+/// A client connection to a VRPN server, reconnecting whenever it is lost.
+#[pyclass(name = "Connection", module = "vrpn")]
+pub struct PyConnection {
+    threaded: ThreadedConnection,
+    tx: Sender<Report>,
+    rx: Mutex<Receiver<Report>>,
+}
+
+impl PyConnection {
+    fn subscribe<T>(
+        &self,
+        device: &str,
+        wrap: fn(SenderName, TypedMessage<T>) -> Report,
+    ) -> crate::Result<()>
+    where
+        T: TypedMessageBody + UnbufferFrom + fmt::Debug + Clone + Send + Sync + 'static,
+    {
+        let device = SenderName::from(device);
+        let connection = self.threaded.connection();
+        let sender = connection.register_sender(device.clone())?;
+        connection.add_typed_handler(
+            Box::new(Forwarder {
+                device,
+                wrap,
+                tx: self.tx.clone(),
+            }),
+            Some(sender),
+        )?;
+        Ok(())
+    }
+
+    /// Take the reports received so far, first waiting up to `timeout` for one.
+    fn take_reports(&self, timeout: Option<Duration>) -> Vec<Report> {
+        let rx = self.rx.lock().unwrap_or_else(PoisonError::into_inner);
+        let first = match timeout {
+            Some(timeout) => match rx.recv_timeout(timeout) {
+                Ok(report) => Some(report),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+            },
+            None => None,
+        };
+        first.into_iter().chain(rx.try_iter()).collect()
+    }
+
+    fn sender(&self, device: &str) -> crate::Result<LocalId<SenderId>> {
+        self.threaded
+            .connection()
+            .register_sender(SenderName::from(device))
+    }
+}
+
+#[pymethods]
+impl PyConnection {
+    /// Connect to a server, such as "localhost" or "tcp://host:3883".
+    #[new]
+    fn new(server: &str) -> PyResult<PyConnection> {
+        let connection = ConnectionIp::client_builder(server.parse::<ServerInfo>()?)
+            .reconnect(true)
+            .build()?;
+        let (tx, rx) = channel();
+        Ok(PyConnection {
+            threaded: ThreadedConnection::spawn(connection)?,
+            tx,
+            rx: Mutex::new(rx),
+        })
+    }
+
+    /// Receive the poses reported by a tracker.
+    fn subscribe_tracker(&self, device: &str) -> PyResult<()> {
+        Ok(self.subscribe(device, Report::Tracker)?)
+    }
+
+    /// Receive the changes reported by a button device.
+    fn subscribe_button(&self, device: &str) -> PyResult<()> {
+        Ok(self.subscribe(device, Report::Button)?)
+    }
+
+    /// Receive the channel values reported by an analog device.
+    fn subscribe_analog(&self, device: &str) -> PyResult<()> {
+        Ok(self.subscribe(device, Report::Analog)?)
+    }
+
+    /// Take the reports received so far, as dicts.
+    ///
+    /// With a timeout in seconds, wait up to that long for one if there are none yet.
+    #[pyo3(signature = (timeout=None))]
+    fn poll<'py>(
+        &self,
+        py: Python<'py>,
+        timeout: Option<f64>,
+    ) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        let mut reports = self.take_reports(None);
+        if let (true, Some(timeout)) = (reports.is_empty(), timeout) {
+            let timeout = Duration::try_from_secs_f64(timeout)
+                .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+            reports = py.allow_threads(|| self.take_reports(Some(timeout)));
+        }
+        // Raises once the connection has stopped on an error.
+        self.threaded.poll_events()?;
+        reports
+            .into_iter()
+            .map(|report| report.into_dict(py))
+            .collect()
+    }
+
+    /// Whether the connection is still running: it stops only on error.
+    fn is_running(&self) -> bool {
+        self.threaded.is_running()
+    }
+
+    /// Ask an analog output device to set one channel.
+    fn request_analog_output(&self, device: &str, channel: i32, value: f64) -> PyResult<()> {
+        let sender = self.sender(device)?;
+        self.threaded.connection().pack_message_body(
+            None,
+            sender,
+            ChangeChannelRequest { channel, value },
+            ClassOfService::RELIABLE,
+        )?;
+        Ok(())
+    }
+
+    /// Ask an analog output device to set its channels, starting at channel 0.
+    fn request_analog_outputs(&self, device: &str, values: Vec<f64>) -> PyResult<()> {
+        let sender = self.sender(device)?;
+        self.threaded.connection().pack_message_body(
+            None,
+            sender,
+            ChangeChannelsRequest { values },
+            ClassOfService::RELIABLE,
+        )?;
+        Ok(())
+    }
+}
+
+/// The `vrpn` Python module.
+#[pymodule]
+fn vrpn(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyConnection>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{
+        id_types::{MessageTypeId, Sensor},
+        Quat, Vec3,
+    };
+    use crate::testing::LoopbackServer;
+    use pyo3::types::IntoPyDict;
+
+    #[test]
+    fn report_dict() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let msg = TypedMessage::new(
+                Some(TimeVal::default()),
+                LocalId(MessageTypeId(1)),
+                LocalId(SenderId(2)),
+                PoseReport {
+                    sensor: Sensor(3),
+                    pos: Vec3::new(1.0, 2.0, 3.0),
+                    quat: Quat::from_sv(1.0, Vec3::new(0.0, 0.0, 0.0)),
+                },
+            );
+            let dict = Report::Tracker(SenderName::from("Tracker0"), msg)
+                .into_dict(py)
+                .unwrap();
+            let get = |key| dict.get_item(key).unwrap().unwrap();
+            assert_eq!(get("device").extract::<String>().unwrap(), "Tracker0");
+            assert_eq!(get("type").extract::<String>().unwrap(), "tracker");
+            assert_eq!(get("sensor").extract::<i32>().unwrap(), 3);
+            assert_eq!(
+                get("quat").extract::<(f64, f64, f64, f64)>().unwrap(),
+                (0.0, 0.0, 0.0, 1.0)
+            );
+        });
+    }
+
+    #[test]
+    fn connection() {
+        let server = LoopbackServer::start().unwrap();
+        let sender = server.register::<PoseReport>("Tracker0").unwrap();
+        let pose = PoseReport {
+            sensor: Sensor(1),
+            pos: Vec3::new(1.0, 2.0, 3.0),
+            quat: Quat::identity(),
+        };
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = pyo3::wrap_pymodule!(vrpn)(py);
+            let connection = module
+                .getattr(py, "Connection")
+                .unwrap()
+                .call1(py, (server.url(),))
+                .unwrap();
+            connection
+                .call_method1(py, "subscribe_tracker", ("Tracker0",))
+                .unwrap();
+            let poll = |timeout: f64| {
+                connection
+                    .call_method(
+                        py,
+                        "poll",
+                        (),
+                        Some(&[("timeout", timeout)].into_py_dict(py).unwrap()),
+                    )
+                    .unwrap()
+                    .extract::<Vec<Bound<'_, PyDict>>>(py)
+                    .unwrap()
+            };
+            assert!(poll(0.05).is_empty());
+
+            py.allow_threads(|| server.wait_for_client(Duration::from_secs(5)))
+                .unwrap();
+            server
+                .connection()
+                .pack_message_body(None, sender, pose.clone(), ClassOfService::RELIABLE)
+                .unwrap();
+            let reports = poll(5.0);
+            assert_eq!(reports.len(), 1);
+            let get = |key| reports[0].get_item(key).unwrap().unwrap();
+            assert_eq!(get("device").extract::<String>().unwrap(), "Tracker0");
+            assert_eq!(get("sensor").extract::<i32>().unwrap(), 1);
+            assert_eq!(
+                get("pos").extract::<(f64, f64, f64)>().unwrap(),
+                (1.0, 2.0, 3.0)
+            );
+            assert!(connection
+                .call_method0(py, "is_running")
+                .unwrap()
+                .extract::<bool>(py)
+                .unwrap());
+            assert!(module
+                .getattr(py, "Connection")
+                .unwrap()
+                .call1(py, ("not a server name!",))
+                .is_err());
+        });
+    }
+}
@@ -0,0 +1,375 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Putting sequenced messages received over an unreliable channel back in order.
+//!
+//! Sequence numbers are assigned per channel, not per sender, so a gap means a message
+//! from *some* sender is late or lost. `ReorderBuffer` holds back messages that arrive
+//! after a gap until the gap is filled, the window of held messages is full,
+//! or the oldest held message has waited longer than the timeout:
+//! then the gap is given up on and delivery resumes after it.
+//!
+//! Senders whose messages are only ever "latest value" (e.g. trackers over UDP)
+//! can be exempted with `SenderOrdering::Unordered`: their messages are released right away,
+//! but still fill their place in the sequence so they don't hold back anyone else.
+//!
+//! Messages received without a sequence number (see `SequenceNumberPolicy`)
+//! can't be put in order, so they are released right away without taking a place.
+
+use crate::{
+    clock::Instant,
+    data_types::{id_types::SenderId, Message, SequencedGenericMessage},
+    parse_system_message, Endpoint, EndpointGeneric, Result, TypeDispatcher,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+/// How long a message may be held waiting for a gap to fill, by default.
+pub const DEFAULT_REORDER_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// How many sequence numbers past a gap may be held, by default.
+pub const DEFAULT_REORDER_WINDOW: usize = 64;
+
+/// Whether messages from a sender wait for earlier messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SenderOrdering {
+    /// Held until all earlier messages have been released or given up on.
+    #[default]
+    Ordered,
+    /// Released as soon as they arrive.
+    Unordered,
+}
+
+/// Limits on how long a `ReorderBuffer` waits for a missing message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReorderConfig {
+    /// Maximum number of sequence numbers, starting at the first missing one, to hold.
+    ///
+    /// A message arriving beyond that gives up on the oldest gaps.
+    pub window: usize,
+    /// Maximum time to hold a message waiting for an earlier one.
+    pub timeout: Duration,
+}
+
+impl Default for ReorderConfig {
+    fn default() -> ReorderConfig {
+        ReorderConfig {
+            window: DEFAULT_REORDER_WINDOW,
+            timeout: DEFAULT_REORDER_TIMEOUT,
+        }
+    }
+}
+
+/// Counts of messages that didn't arrive in order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReorderStats {
+    /// Messages that arrived after a gap and were held.
+    pub held: u64,
+    /// Messages given up on, never received.
+    pub lost: u64,
+    /// Messages discarded because their sequence number was already released or given up on.
+    pub discarded: u64,
+}
+
+#[derive(Debug)]
+enum Slot {
+    Missing,
+    Held(Instant, SequencedGenericMessage),
+    /// Already released out of order, but kept so the sequence stays contiguous.
+    Released,
+}
+
+/// Releases sequenced messages in order, within a bounded window and timeout.
+#[derive(Debug, Default)]
+pub struct ReorderBuffer {
+    config: ReorderConfig,
+    orderings: HashMap<SenderId, SenderOrdering>,
+    /// Sequence number of the first slot, once the first message has arrived.
+    next: Option<u32>,
+    slots: VecDeque<Slot>,
+    ready: VecDeque<SequencedGenericMessage>,
+    stats: ReorderStats,
+}
+
+impl ReorderBuffer {
+    /// Create a buffer with the default window and timeout.
+    pub fn new() -> ReorderBuffer {
+        ReorderBuffer::default()
+    }
+
+    /// Create a buffer with the given window and timeout.
+    pub fn with_config(config: ReorderConfig) -> ReorderBuffer {
+        ReorderBuffer {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn config(&self) -> ReorderConfig {
+        self.config
+    }
+
+    /// Set whether messages from a sender (by its ID in the incoming messages) wait for earlier ones.
+    pub fn set_sender_ordering(&mut self, sender: SenderId, ordering: SenderOrdering) {
+        self.orderings.insert(sender, ordering);
+    }
+
+    pub fn sender_ordering(&self, sender: SenderId) -> SenderOrdering {
+        self.orderings.get(&sender).copied().unwrap_or_default()
+    }
+
+    pub fn stats(&self) -> ReorderStats {
+        self.stats
+    }
+
+    /// Number of messages received but not yet released.
+    pub fn held_len(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| matches!(slot, Slot::Held(..)))
+            .count()
+    }
+
+    /// Accept a received message.
+    ///
+    /// Returns false if it was discarded as a duplicate or as arriving too late.
+    pub fn push(&mut self, msg: SequencedGenericMessage, now: Instant) -> bool {
+        let seq = match msg.sequence() {
+            Some(seq) => seq.0,
+            None => {
+                self.ready.push_back(msg);
+                return true;
+            }
+        };
+        let next = *self.next.get_or_insert(seq);
+        let offset = seq.wrapping_sub(next);
+        if offset > u32::MAX / 2 {
+            // Behind the start of the window.
+            self.stats.discarded += 1;
+            return false;
+        }
+        let mut offset = offset as usize;
+        let window = self.config.window.max(1);
+        while offset >= window {
+            self.give_up_front();
+            offset -= 1;
+        }
+        while self.slots.len() <= offset {
+            self.slots.push_back(Slot::Missing);
+        }
+        if !matches!(self.slots[offset], Slot::Missing) {
+            self.stats.discarded += 1;
+            return false;
+        }
+        let unordered =
+            self.sender_ordering(msg.message().header.sender) == SenderOrdering::Unordered;
+        self.slots[offset] = if offset == 0 {
+            Slot::Held(now, msg)
+        } else if unordered {
+            self.ready.push_back(msg);
+            Slot::Released
+        } else {
+            self.stats.held += 1;
+            Slot::Held(now, msg)
+        };
+        self.release_contiguous();
+        true
+    }
+
+    /// Get the next message to deliver, if any, giving up on gaps that have timed out.
+    pub fn pop(&mut self, now: Instant) -> Option<SequencedGenericMessage> {
+        if self.ready.is_empty() {
+            self.expire(now);
+        }
+        self.ready.pop_front()
+    }
+
+    /// When `pop` should next be called if nothing else arrives, because a gap will time out.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.slots
+            .iter()
+            .filter_map(|slot| match slot {
+                Slot::Held(arrived, _) => Some(*arrived + self.config.timeout),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Deliver every message that is ready: system messages go to the endpoint
+    /// (as if read from a stream), and other messages are mapped to local IDs and dispatched.
+    ///
+    /// Returns the number of messages delivered.
+    pub fn dispatch_ready<E: Endpoint>(
+        &mut self,
+        now: Instant,
+        endpoint: &E,
+        dispatcher: &mut TypeDispatcher,
+    ) -> Result<usize> {
+        let mut count = 0;
+        while let Some(msg) = self.pop(now) {
+            let msg = msg.into_inner();
+            if msg.is_system_message() {
+                endpoint.send_system_change(parse_system_message(msg)?)?;
+            } else {
+                dispatcher.call(&endpoint.map_remote_message_to_local(msg)?)?;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Give up on any gap preceding a message that has waited too long.
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.config.timeout;
+        // Everything up to the last expired message is released, skipping the gaps.
+        let last_expired = self.slots.iter().rposition(|slot| match slot {
+            Slot::Held(arrived, _) => now.saturating_duration_since(*arrived) >= timeout,
+            _ => false,
+        });
+        if let Some(pos) = last_expired {
+            for _ in 0..=pos {
+                self.give_up_front();
+            }
+            self.release_contiguous();
+        }
+    }
+
+    /// Remove the first slot, releasing its message or counting it as lost.
+    fn give_up_front(&mut self) {
+        match self.slots.pop_front() {
+            Some(Slot::Held(_, msg)) => self.ready.push_back(msg),
+            Some(Slot::Missing) | None => self.stats.lost += 1,
+            Some(Slot::Released) => {}
+        }
+        self.advance_next();
+    }
+
+    fn release_contiguous(&mut self) {
+        while matches!(
+            self.slots.front(),
+            Some(Slot::Held(..)) | Some(Slot::Released)
+        ) {
+            if let Some(Slot::Held(_, msg)) = self.slots.pop_front() {
+                self.ready.push_back(msg);
+            }
+            self.advance_next();
+        }
+    }
+
+    fn advance_next(&mut self) {
+        if let Some(next) = self.next.as_mut() {
+            *next = next.wrapping_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{
+        id_types::{MessageTypeId, SequenceNumber},
+        GenericBody, GenericMessage, MessageHeader, TimeVal,
+    };
+    use bytes::Bytes;
+
+    fn msg(sender: i32, seq: u32) -> SequencedGenericMessage {
+        GenericMessage::from_header_and_body(
+            MessageHeader::new(Some(TimeVal::default()), MessageTypeId(0), SenderId(sender)),
+            GenericBody::new(Bytes::new()),
+        )
+        .into_sequenced_message(SequenceNumber(seq))
+    }
+
+    fn drain(buf: &mut ReorderBuffer, now: Instant) -> Vec<u32> {
+        std::iter::from_fn(|| buf.pop(now))
+            .map(|m| m.sequence_number.0)
+            .collect()
+    }
+
+    #[test]
+    fn fills_gap() {
+        let now = Instant::now();
+        let mut buf = ReorderBuffer::new();
+        assert!(buf.push(msg(0, 10), now));
+        assert!(buf.push(msg(0, 12), now));
+        assert!(buf.push(msg(0, 13), now));
+        assert_eq!(drain(&mut buf, now), vec![10]);
+        assert_eq!(buf.held_len(), 2);
+        assert_eq!(buf.next_deadline(), Some(now + DEFAULT_REORDER_TIMEOUT));
+
+        assert!(buf.push(msg(0, 11), now));
+        assert_eq!(drain(&mut buf, now), vec![11, 12, 13]);
+        // Duplicates and stragglers are dropped.
+        assert!(!buf.push(msg(0, 12), now));
+        assert_eq!(
+            buf.stats(),
+            ReorderStats {
+                held: 2,
+                lost: 0,
+                discarded: 1
+            }
+        );
+    }
+
+    #[test]
+    fn gives_up_on_timeout_and_window() {
+        let start = Instant::now();
+        let mut buf = ReorderBuffer::with_config(ReorderConfig {
+            window: 4,
+            timeout: Duration::from_millis(10),
+        });
+        buf.push(msg(0, 0), start);
+        buf.push(msg(0, 2), start);
+        assert_eq!(drain(&mut buf, start), vec![0]);
+        let later = start + Duration::from_millis(10);
+        assert_eq!(drain(&mut buf, later), vec![2]);
+        assert!(!buf.push(msg(0, 1), later));
+
+        // Sequence 4 is missing; 8 doesn't fit in the window with it.
+        buf.push(msg(0, 3), later);
+        buf.push(msg(0, 5), later);
+        buf.push(msg(0, 8), later);
+        assert_eq!(drain(&mut buf, later), vec![3, 5]);
+        assert_eq!(buf.stats().lost, 2);
+    }
+
+    #[test]
+    fn unordered_sender_and_wraparound() {
+        let now = Instant::now();
+        let mut buf = ReorderBuffer::new();
+        buf.set_sender_ordering(SenderId(1), SenderOrdering::Unordered);
+        buf.push(msg(0, u32::MAX), now);
+        buf.push(msg(0, 1), now);
+        buf.push(msg(1, 2), now);
+        assert_eq!(drain(&mut buf, now), vec![u32::MAX, 2]);
+        buf.push(msg(0, 0), now);
+        assert_eq!(drain(&mut buf, now), vec![0, 1]);
+        buf.push(msg(0, 3), now);
+        assert_eq!(drain(&mut buf, now), vec![3]);
+        assert_eq!(buf.stats().lost, 0);
+    }
+
+    #[test]
+    fn unnumbered_messages() {
+        use crate::data_types::{ProtocolProfile, SequenceNumberPolicy};
+        let profile =
+            ProtocolProfile::VRPN.with_sequence_numbers(SequenceNumberPolicy::ZeroIsMissing);
+        let unnumbered = || {
+            let mut buf = msg(0, 0).try_into_buf().unwrap();
+            SequencedGenericMessage::try_read_from_buf_with_profile(&mut buf, &profile).unwrap()
+        };
+        let now = Instant::now();
+        let mut buf = ReorderBuffer::new();
+        buf.push(msg(0, 1), now);
+        buf.push(msg(0, 3), now);
+        // Not discarded as duplicates, and not held behind the gap.
+        assert!(buf.push(unnumbered(), now));
+        assert!(buf.push(unnumbered(), now));
+        assert_eq!(drain(&mut buf, now), vec![1, 0, 0]);
+        assert_eq!(buf.held_len(), 1);
+        assert_eq!(buf.stats().discarded, 0);
+    }
+}
@@ -0,0 +1,538 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Machine-readable layout of the structs sent on the wire, to catch accidental changes.
+//!
+//! `dump` renders each struct's fields in wire order, with their types and widths.
+//! A test compares it to `fixtures/schema.txt`, and checks the fixed-size layouts against
+//! what the buffering code actually writes, so a reordered field or a changed width fails
+//! a unit test instead of only showing up when talking to another implementation.
+//!
+//! After an intentional change, regenerate the file with `VRPN_BLESS_FIXTURES=1 cargo test schema`,
+//! and review the diff.
+//!
+//! Not a stable API: it exists for these tests, and for comparing against other implementations.
+
+use crate::data_types::{
+    descriptions::{InnerDescription, UdpInnerDescription},
+    id_types::{MessageTypeId, SenderId},
+    LogFileNames, MessageTypeIdentifier, TypedMessageBody,
+};
+use std::fmt::Write;
+
+/// How a field is encoded. All numbers are big-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    I32,
+    U32,
+    F64,
+    /// An `i32`: 1 for true, 0 for false.
+    Bool32,
+    /// Bytes of this width, ignored when reading.
+    Padding(usize),
+    /// ASCII text of this width.
+    Ascii(usize),
+    /// Bytes up to and including a null terminator.
+    NullTerminated,
+    /// The element repeated as many times as given by the count field before it.
+    Repeated(&'static FieldType),
+    /// The message body, then zeros up to a multiple of 8 bytes.
+    Body,
+    /// Records to the end of the body, each a `u32` tag, a `u32` size, then that many bytes.
+    TaggedRecords,
+}
+
+impl FieldType {
+    /// Width in bytes, or None if it depends on the contents.
+    pub fn width(&self) -> Option<usize> {
+        match self {
+            FieldType::I32 | FieldType::U32 | FieldType::Bool32 => Some(4),
+            FieldType::F64 => Some(8),
+            FieldType::Padding(width) | FieldType::Ascii(width) => Some(*width),
+            FieldType::NullTerminated
+            | FieldType::Repeated(_)
+            | FieldType::Body
+            | FieldType::TaggedRecords => None,
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            FieldType::I32 => "i32".to_string(),
+            FieldType::U32 => "u32".to_string(),
+            FieldType::F64 => "f64".to_string(),
+            FieldType::Bool32 => "bool32".to_string(),
+            FieldType::Padding(_) => "padding".to_string(),
+            FieldType::Ascii(_) => "ascii".to_string(),
+            FieldType::NullTerminated => "null-terminated".to_string(),
+            FieldType::Repeated(element) => format!("repeated {}", element.name()),
+            FieldType::Body => "body".to_string(),
+            FieldType::TaggedRecords => "tagged-records".to_string(),
+        }
+    }
+}
+
+/// One field of a wire struct.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub ty: FieldType,
+}
+
+const fn field(name: &'static str, ty: FieldType) -> Field {
+    Field { name, ty }
+}
+
+/// The layout of a struct sent on the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WireStruct {
+    pub name: &'static str,
+    /// The message type it is the body of, if any.
+    pub message_type: Option<String>,
+    pub fields: &'static [Field],
+}
+
+impl WireStruct {
+    fn new(name: &'static str, fields: &'static [Field]) -> WireStruct {
+        WireStruct {
+            name,
+            message_type: None,
+            fields,
+        }
+    }
+
+    /// The body of messages of type `T`.
+    fn body<T: TypedMessageBody>(name: &'static str, fields: &'static [Field]) -> WireStruct {
+        let message_type = match T::MESSAGE_IDENTIFIER {
+            MessageTypeIdentifier::UserMessageName(name) => {
+                String::from_utf8_lossy(name.0).into_owned()
+            }
+            MessageTypeIdentifier::SystemMessageId(id) => format!("system message {}", id.0),
+        };
+        WireStruct {
+            message_type: Some(message_type),
+            ..WireStruct::new(name, fields)
+        }
+    }
+
+    /// Total width in bytes, if every field has a fixed width.
+    pub fn width(&self) -> Option<usize> {
+        self.fields.iter().map(|f| f.ty.width()).sum()
+    }
+}
+
+const TIME: [Field; 2] = [
+    field("time.seconds", FieldType::I32),
+    field("time.microseconds", FieldType::I32),
+];
+
+const MESSAGE_HEADER: &[Field] = &[
+    TIME[0],
+    TIME[1],
+    field("sender", FieldType::I32),
+    field("message_type", FieldType::I32),
+];
+
+const MESSAGE_FRAME: &[Field] = &[
+    field("length", FieldType::U32),
+    TIME[0],
+    TIME[1],
+    field("sender", FieldType::I32),
+    field("message_type", FieldType::I32),
+    field("sequence", FieldType::U32),
+    field("body", FieldType::Body),
+];
+
+const COOKIE: &[Field] = &[
+    field("magic", FieldType::Ascii(16)),
+    field("separator", FieldType::Ascii(2)),
+    field("log_mode", FieldType::Ascii(1)),
+    field("padding", FieldType::Padding(5)),
+];
+
+const DESCRIPTION: &[Field] = &[
+    field("name.length", FieldType::U32),
+    field("name", FieldType::NullTerminated),
+];
+
+const UDP_DESCRIPTION: &[Field] = &[field("address", FieldType::NullTerminated)];
+
+const LOG_FILE_NAMES: &[Field] = &[
+    field("incoming.length", FieldType::I32),
+    field("outgoing.length", FieldType::I32),
+    field("incoming", FieldType::NullTerminated),
+    field("outgoing", FieldType::NullTerminated),
+];
+
+const EMPTY: &[Field] = &[];
+
+#[cfg(feature = "analog")]
+const ANALOG_REPORT: &[Field] = &[
+    field("num_channels", FieldType::F64),
+    field("values", FieldType::Repeated(&FieldType::F64)),
+];
+
+#[cfg(feature = "analog")]
+const CHANGE_CHANNEL_REQUEST: &[Field] = &[
+    field("channel", FieldType::I32),
+    field("padding", FieldType::Padding(4)),
+    field("value", FieldType::F64),
+];
+
+#[cfg(feature = "analog")]
+const CHANGE_CHANNELS_REQUEST: &[Field] = &[
+    field("num_channels", FieldType::I32),
+    field("padding", FieldType::Padding(4)),
+    field("values", FieldType::Repeated(&FieldType::F64)),
+];
+
+#[cfg(feature = "analog")]
+const NUM_CHANNELS_REPORT: &[Field] = &[
+    field("num_channels", FieldType::I32),
+    field("padding", FieldType::Padding(4)),
+];
+
+#[cfg(feature = "button")]
+const BUTTON_CHANGE: &[Field] = &[
+    field("button", FieldType::I32),
+    field("pressed", FieldType::Bool32),
+];
+
+#[cfg(feature = "button")]
+const BUTTON_STATES: &[Field] = &[
+    field("num_buttons", FieldType::I32),
+    field("pressed", FieldType::Repeated(&FieldType::Bool32)),
+];
+
+#[cfg(feature = "metadata")]
+const DEVICE_METADATA: &[Field] = &[field("records", FieldType::TaggedRecords)];
+
+#[cfg(feature = "text")]
+const TEXT_MESSAGE: &[Field] = &[
+    field("severity", FieldType::U32),
+    field("level", FieldType::U32),
+    field("text", FieldType::NullTerminated),
+];
+
+#[cfg(feature = "tracker")]
+const POSE_REPORT: &[Field] = &[
+    field("sensor", FieldType::I32),
+    field("padding", FieldType::Padding(4)),
+    field("pos.x", FieldType::F64),
+    field("pos.y", FieldType::F64),
+    field("pos.z", FieldType::F64),
+    field("quat.x", FieldType::F64),
+    field("quat.y", FieldType::F64),
+    field("quat.z", FieldType::F64),
+    field("quat.w", FieldType::F64),
+];
+
+#[cfg(feature = "tracker")]
+const WORKSPACE_REPORT: &[Field] = &[
+    field("min.x", FieldType::F64),
+    field("min.y", FieldType::F64),
+    field("min.z", FieldType::F64),
+    field("max.x", FieldType::F64),
+    field("max.y", FieldType::F64),
+    field("max.z", FieldType::F64),
+];
+
+#[cfg(feature = "tracker")]
+const TRANSFORM_REPORT: &[Field] = &[
+    POSE_REPORT[2],
+    POSE_REPORT[3],
+    POSE_REPORT[4],
+    POSE_REPORT[5],
+    POSE_REPORT[6],
+    POSE_REPORT[7],
+    POSE_REPORT[8],
+];
+
+/// The layouts of all wire structs of the enabled features.
+pub fn wire_structs() -> Vec<WireStruct> {
+    #[allow(unused_mut)]
+    let mut structs = vec![
+        WireStruct::new("MessageFrame", MESSAGE_FRAME),
+        WireStruct::new("MessageHeader", MESSAGE_HEADER),
+        WireStruct::new("CookieData", COOKIE),
+        WireStruct::body::<InnerDescription<SenderId>>("SenderDescription", DESCRIPTION),
+        WireStruct::body::<InnerDescription<MessageTypeId>>("TypeDescription", DESCRIPTION),
+        WireStruct::body::<UdpInnerDescription>("UdpDescription", UDP_DESCRIPTION),
+        WireStruct::body::<LogFileNames>("LogFileNames", LOG_FILE_NAMES),
+        WireStruct::body::<crate::ping::Ping>("Ping", EMPTY),
+        WireStruct::body::<crate::ping::Pong>("Pong", EMPTY),
+    ];
+    #[cfg(feature = "analog")]
+    {
+        use crate::analog::{
+            AnalogReport, ChangeChannelRequest, ChangeChannelsRequest, NumChannelsReport,
+        };
+        structs.extend([
+            WireStruct::body::<AnalogReport>("AnalogReport", ANALOG_REPORT),
+            WireStruct::body::<ChangeChannelRequest>(
+                "ChangeChannelRequest",
+                CHANGE_CHANNEL_REQUEST,
+            ),
+            WireStruct::body::<ChangeChannelsRequest>(
+                "ChangeChannelsRequest",
+                CHANGE_CHANNELS_REQUEST,
+            ),
+            WireStruct::body::<NumChannelsReport>("NumChannelsReport", NUM_CHANNELS_REPORT),
+        ]);
+    }
+    #[cfg(feature = "button")]
+    {
+        use crate::button::{ButtonChange, ButtonStates};
+        structs.extend([
+            WireStruct::body::<ButtonChange>("ButtonChange", BUTTON_CHANGE),
+            WireStruct::body::<ButtonStates>("ButtonStates", BUTTON_STATES),
+        ]);
+    }
+    #[cfg(feature = "metadata")]
+    structs.push(WireStruct::body::<crate::metadata::DeviceMetadata>(
+        "DeviceMetadata",
+        DEVICE_METADATA,
+    ));
+    #[cfg(feature = "text")]
+    structs.push(WireStruct::body::<crate::text::TextMessage>(
+        "TextMessage",
+        TEXT_MESSAGE,
+    ));
+    #[cfg(feature = "tracker")]
+    {
+        use crate::tracker::{
+            PoseReport, RequestTrackerToRoom, RequestUnitToSensor, RequestWorkspace,
+            TrackerToRoomReport, UnitToSensorReport, WorkspaceReport,
+        };
+        structs.extend([
+            WireStruct::body::<PoseReport>("PoseReport", POSE_REPORT),
+            WireStruct::body::<RequestWorkspace>("RequestWorkspace", EMPTY),
+            WireStruct::body::<WorkspaceReport>("WorkspaceReport", WORKSPACE_REPORT),
+            WireStruct::body::<RequestTrackerToRoom>("RequestTrackerToRoom", EMPTY),
+            WireStruct::body::<TrackerToRoomReport>("TrackerToRoomReport", TRANSFORM_REPORT),
+            WireStruct::body::<RequestUnitToSensor>("RequestUnitToSensor", EMPTY),
+            WireStruct::body::<UnitToSensorReport>("UnitToSensorReport", POSE_REPORT),
+        ]);
+    }
+    structs
+}
+
+/// Render the layouts of all wire structs of the enabled features as text:
+/// for each struct, a line with its name and message type,
+/// then a line per field with its offset, name, type, and width in bytes.
+///
+/// Offsets and widths that depend on the contents are shown as `?`.
+pub fn dump() -> String {
+    let mut out = String::new();
+    for (i, s) in wire_structs().iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        let _ = match &s.message_type {
+            Some(message_type) => writeln!(out, "{} \"{}\"", s.name, message_type),
+            None => writeln!(out, "{}", s.name),
+        };
+        let mut offset = Some(0);
+        for f in s.fields {
+            let show = |n: Option<usize>| n.map_or_else(|| "?".to_string(), |n| n.to_string());
+            let _ = writeln!(
+                out,
+                "    {:>3} {} {} {}",
+                show(offset),
+                f.name,
+                f.ty.name(),
+                show(f.ty.width())
+            );
+            offset = offset
+                .zip(f.ty.width())
+                .map(|(offset, width)| offset + width);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_unbuffer::BufferTo;
+    use bytes::{BufMut, BytesMut};
+    use std::{fs, path::PathBuf};
+
+    fn schema_path() -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "fixtures", "schema.txt"]
+            .iter()
+            .collect()
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "analog",
+        feature = "button",
+        feature = "metadata",
+        feature = "text",
+        feature = "tracker"
+    ))]
+    fn schema_matches_golden_file() {
+        let path = schema_path();
+        if std::env::var_os("VRPN_BLESS_FIXTURES").is_some() {
+            fs::write(&path, dump()).expect("could not write schema");
+            return;
+        }
+        let expected = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("could not read {}: {}", path.display(), e));
+        assert_eq!(
+            dump(),
+            expected,
+            "wire schema differs from fixtures/schema.txt"
+        );
+    }
+
+    /// The encoding of a fixed-size struct whose numeric fields are numbered in order from 1,
+    /// ignoring padding, and whose booleans are true.
+    fn numbered_encoding(s: &WireStruct) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut n = 0;
+        for f in s.fields {
+            match f.ty {
+                FieldType::Padding(width) => buf.put_bytes(0, width),
+                FieldType::Bool32 => buf.put_i32(1),
+                ty => {
+                    n += 1;
+                    match ty {
+                        FieldType::I32 => buf.put_i32(n),
+                        FieldType::U32 => buf.put_u32(n as u32),
+                        FieldType::F64 => buf.put_f64(f64::from(n)),
+                        ty => panic!("{} has a field of type {:?}", s.name, ty),
+                    }
+                }
+            }
+        }
+        buf
+    }
+
+    /// Check that `body`, filled in as described for `numbered_encoding`, is encoded as its schema says.
+    fn check_layout(name: &str, body: impl BufferTo) {
+        let s = wire_structs()
+            .into_iter()
+            .find(|s| s.name == name)
+            .unwrap_or_else(|| panic!("no schema for {}", name));
+        let mut buf = BytesMut::new();
+        body.buffer_to(&mut buf).unwrap();
+        let mut expected = numbered_encoding(&s);
+        // Padding is ignored when reading, so it may hold anything.
+        let mut offset = 0;
+        for f in s.fields {
+            let width = f.ty.width().expect("fixed width");
+            if let FieldType::Padding(_) = f.ty {
+                expected[offset..offset + width].copy_from_slice(&buf[offset..offset + width]);
+            }
+            offset += width;
+        }
+        assert_eq!(
+            &buf[..],
+            &expected[..],
+            "layout of {} differs from schema",
+            name
+        );
+    }
+
+    #[test]
+    fn fixed_layouts() {
+        use crate::data_types::{MessageHeader, Microseconds, Seconds, TimeVal};
+        let header = MessageHeader::new(
+            Some(TimeVal::new(Seconds(1), Microseconds(2))),
+            MessageTypeId(4),
+            SenderId(3),
+        );
+        check_layout("MessageHeader", header);
+        #[cfg(feature = "analog")]
+        {
+            use crate::analog::*;
+            check_layout(
+                "ChangeChannelRequest",
+                ChangeChannelRequest {
+                    channel: 1,
+                    value: 2.0,
+                },
+            );
+            check_layout("NumChannelsReport", NumChannelsReport { num_channels: 1 });
+        }
+        #[cfg(feature = "button")]
+        check_layout(
+            "ButtonChange",
+            crate::button::ButtonChange {
+                button: 1,
+                pressed: true,
+            },
+        );
+        #[cfg(feature = "tracker")]
+        {
+            use crate::{
+                data_types::{id_types::Sensor, Quat, Vec3},
+                tracker::*,
+            };
+            let pos = Vec3::new(2.0, 3.0, 4.0);
+            let quat = Quat::new(8.0, 5.0, 6.0, 7.0);
+            check_layout(
+                "PoseReport",
+                PoseReport {
+                    sensor: Sensor(1),
+                    pos,
+                    quat,
+                },
+            );
+            check_layout(
+                "WorkspaceReport",
+                WorkspaceReport {
+                    min: Vec3::new(1.0, 2.0, 3.0),
+                    max: Vec3::new(4.0, 5.0, 6.0),
+                },
+            );
+            let transform = Transform {
+                pos: Vec3::new(1.0, 2.0, 3.0),
+                quat: Quat::new(7.0, 4.0, 5.0, 6.0),
+            };
+            check_layout("TrackerToRoomReport", TrackerToRoomReport(transform));
+            check_layout(
+                "UnitToSensorReport",
+                UnitToSensorReport {
+                    sensor: Sensor(1),
+                    transform: Transform { pos, quat },
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn widths() {
+        for s in wire_structs() {
+            assert_eq!(
+                s.fields.is_empty(),
+                s.width() == Some(0),
+                "{} has fields of no width",
+                s.name
+            );
+        }
+        let cookie = wire_structs()
+            .into_iter()
+            .find(|s| s.name == "CookieData")
+            .unwrap();
+        assert_eq!(
+            cookie.width(),
+            Some(crate::data_types::constants::COOKIE_SIZE)
+        );
+        let frame = wire_structs()
+            .into_iter()
+            .find(|s| s.name == "MessageFrame")
+            .unwrap();
+        let header: Option<usize> = frame.fields[..frame.fields.len() - 1]
+            .iter()
+            .map(|f| f.ty.width())
+            .sum();
+        assert_eq!(
+            header,
+            Some(crate::data_types::ProtocolProfile::VRPN.header_size())
+        );
+    }
+}
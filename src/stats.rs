@@ -0,0 +1,640 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Runtime statistics about a connection, for diagnosing performance in deployed systems.
+
+use crate::{
+    buffer_unbuffer::BufferUnbufferError,
+    clock::Instant,
+    data_types::{
+        id_types::{LocalId, MessageTypeId, SenderId},
+        MessageHeader,
+    },
+    VrpnError,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::TryFrom,
+    fmt,
+    time::Duration,
+};
+
+/// How far back `ConnectionStats::send_rate` looks.
+pub const SEND_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Number of buckets in a LatencyHistogram.
+///
+/// Bucket `i` holds samples of less than `2^i` microseconds (and at least `2^(i-1)`),
+/// so the last bucket collects everything over about 9 minutes.
+pub const LATENCY_BUCKETS: usize = 31;
+
+/// A fixed-size histogram of durations, with power-of-two microsecond buckets.
+///
+/// Recording a sample never allocates, so this is cheap enough to leave enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS],
+    count: u64,
+    total_micros: u128,
+    min: Option<Duration>,
+    max: Option<Duration>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> LatencyHistogram {
+        LatencyHistogram::new()
+    }
+}
+
+fn bucket_index(micros: u128) -> usize {
+    let bits = (u128::BITS - micros.leading_zeros()) as usize;
+    bits.min(LATENCY_BUCKETS - 1)
+}
+
+impl LatencyHistogram {
+    /// Create an empty histogram.
+    pub const fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            buckets: [0; LATENCY_BUCKETS],
+            count: 0,
+            total_micros: 0,
+            min: None,
+            max: None,
+        }
+    }
+
+    /// Add a single sample.
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros();
+        self.buckets[bucket_index(micros)] += 1;
+        self.count += 1;
+        self.total_micros += micros;
+        self.min = Some(self.min.map_or(latency, |m| m.min(latency)));
+        self.max = Some(self.max.map_or(latency, |m| m.max(latency)));
+    }
+
+    /// Number of samples recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Smallest sample recorded, if any.
+    pub fn min(&self) -> Option<Duration> {
+        self.min
+    }
+
+    /// Largest sample recorded, if any.
+    pub fn max(&self) -> Option<Duration> {
+        self.max
+    }
+
+    /// Mean of all samples recorded, if any (microsecond resolution).
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let mean = self.total_micros / u128::from(self.count);
+        Some(Duration::from_micros(
+            u64::try_from(mean).unwrap_or(u64::MAX),
+        ))
+    }
+
+    /// Upper bound of the bucket containing the given quantile (in `[0, 1]`), if any samples.
+    ///
+    /// The result is conservative: the true quantile is at most this value,
+    /// except in the final catch-all bucket, where the recorded max is returned.
+    pub fn quantile_upper_bound(&self, quantile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let quantile = quantile.clamp(0.0, 1.0);
+        let target = ((self.count as f64) * quantile).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                if i == LATENCY_BUCKETS - 1 {
+                    return self.max;
+                }
+                return Some(Duration::from_micros(1 << i));
+            }
+        }
+        self.max
+    }
+
+    /// Iterate over `(bucket upper bound, count)` for each bucket.
+    ///
+    /// The upper bound is exclusive, and is `None` for the last (catch-all) bucket.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(i, n)| {
+            let bound = if i == LATENCY_BUCKETS - 1 {
+                None
+            } else {
+                Some(Duration::from_micros(1 << i))
+            };
+            (bound, *n)
+        })
+    }
+
+    /// Remove all samples.
+    pub fn clear(&mut self) {
+        *self = LatencyHistogram::new();
+    }
+}
+
+/// Category of an error encountered by a connection, for counting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// Malformed or unexpected incoming data.
+    Parse,
+    /// Ran out of space serializing a message.
+    OutOfBuffer,
+    /// The connection could not be established with the remote side.
+    Handshake,
+    /// A message handler returned an error.
+    Handler,
+    /// The transport failed or closed.
+    Io,
+    /// Anything else.
+    Other,
+}
+
+impl ErrorKind {
+    /// Every kind, in the order `ErrorCounters::iter` reports them.
+    pub const ALL: [ErrorKind; 6] = [
+        ErrorKind::Parse,
+        ErrorKind::OutOfBuffer,
+        ErrorKind::Handshake,
+        ErrorKind::Handler,
+        ErrorKind::Io,
+        ErrorKind::Other,
+    ];
+
+    /// Categorize an error by its type alone.
+    ///
+    /// Where an error came from can matter more: e.g. the connection counts any failure
+    /// to set up as `Handshake`, and the dispatcher counts any handler failure as `Handler`.
+    pub fn of(err: &VrpnError) -> ErrorKind {
+        match err {
+            VrpnError::BufferUnbuffer(BufferUnbufferError::OutOfBuffer) => ErrorKind::OutOfBuffer,
+            VrpnError::BufferUnbuffer(_)
+            | VrpnError::MessageSizeInvalid(_)
+            | VrpnError::InvalidId(_)
+            | VrpnError::EmptyEntry
+            | VrpnError::NotSystemMessage
+            | VrpnError::UnrecognizedSystemMessage(_)
+            | VrpnError::WrongMessageType(_)
+            | VrpnError::CorruptDatagram => ErrorKind::Parse,
+            VrpnError::VersionMismatch(_) => ErrorKind::Handshake,
+            VrpnError::GenericErrorReturn => ErrorKind::Handler,
+            VrpnError::IoError(_)
+            | VrpnError::EndpointClosed
+            | VrpnError::CouldNotConnect
+            | VrpnError::Timeout => ErrorKind::Io,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    const fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Number of errors of each kind seen by a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorCounters {
+    counts: [u64; ErrorKind::ALL.len()],
+}
+
+impl ErrorCounters {
+    /// Count one error of the given kind.
+    pub fn record(&mut self, kind: ErrorKind) {
+        self.counts[kind.index()] += 1;
+    }
+
+    /// Number of errors of the given kind.
+    pub fn count(&self, kind: ErrorKind) -> u64 {
+        self.counts[kind.index()]
+    }
+
+    /// Number of errors of all kinds.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Each kind with its count.
+    pub fn iter(&self) -> impl Iterator<Item = (ErrorKind, u64)> + '_ {
+        ErrorKind::ALL
+            .iter()
+            .map(move |&kind| (kind, self.count(kind)))
+    }
+
+    pub fn clear(&mut self) {
+        *self = ErrorCounters::default();
+    }
+}
+
+/// Rate of sending over the last `SEND_RATE_WINDOW`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SendRate {
+    pub messages_per_sec: f64,
+    pub bytes_per_sec: f64,
+}
+
+/// Statistics gathered by a connection's receive and send paths.
+///
+/// Retrieve a snapshot with `Connection::stats()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    decode_latency: Option<LatencyHistogram>,
+    errors: ErrorCounters,
+    expired: u64,
+    superseded: u64,
+    bytes_sent: u64,
+    /// Messages and bytes sent at each time recorded, within the last `SEND_RATE_WINDOW`.
+    recent_sends: VecDeque<(Instant, u64, u64)>,
+    throttled: u64,
+    dispatch_queue_depth: usize,
+    dispatch_queue_peak: usize,
+    dispatch_queue_overflows: u64,
+    quarantined_endpoints: u64,
+    last_received: BTreeMap<(LocalId<SenderId>, LocalId<MessageTypeId>), Instant>,
+}
+
+impl ConnectionStats {
+    pub fn new() -> ConnectionStats {
+        ConnectionStats::default()
+    }
+
+    /// Enable or disable timing of each received message,
+    /// from when its bytes are read until its handlers complete.
+    ///
+    /// Disabled by default. Disabling discards any samples collected.
+    pub fn set_latency_instrumentation(&mut self, enabled: bool) {
+        match (enabled, self.decode_latency.is_some()) {
+            (true, false) => self.decode_latency = Some(LatencyHistogram::new()),
+            (false, true) => self.decode_latency = None,
+            _ => {}
+        }
+    }
+
+    /// Is latency instrumentation enabled?
+    pub fn latency_instrumentation_enabled(&self) -> bool {
+        self.decode_latency.is_some()
+    }
+
+    /// Histogram of per-message latency through decode and dispatch, if enabled.
+    pub fn decode_latency(&self) -> Option<&LatencyHistogram> {
+        self.decode_latency.as_ref()
+    }
+
+    /// Hook called when starting to receive a message.
+    ///
+    /// Returns None (without reading the clock) if instrumentation is disabled.
+    pub fn start_timing(&self) -> Option<Instant> {
+        self.decode_latency.map(|_| Instant::now())
+    }
+
+    /// Hook called after a message's handlers have completed,
+    /// passing the value returned by `start_timing()`.
+    pub fn finish_timing(&mut self, start: Option<Instant>) {
+        if let (Some(start), Some(hist)) = (start, self.decode_latency.as_mut()) {
+            hist.record(start.elapsed());
+        }
+    }
+
+    /// Counts of errors by kind. Always collected.
+    pub fn errors(&self) -> &ErrorCounters {
+        &self.errors
+    }
+
+    /// Hook called when an error of a known kind occurs.
+    pub fn record_error(&mut self, kind: ErrorKind) {
+        self.errors.record(kind);
+    }
+
+    /// Hook called when an error occurs, categorized with `ErrorKind::of`.
+    pub fn record_vrpn_error(&mut self, err: &VrpnError) {
+        self.errors.record(ErrorKind::of(err));
+    }
+
+    /// Number of outgoing messages dropped for not being sent within their maximum age.
+    ///
+    /// See `LowLatencyConfig::max_send_age`.
+    pub fn expired_messages(&self) -> u64 {
+        self.expired
+    }
+
+    /// Hook called when outgoing messages expire before being sent.
+    pub fn record_expired(&mut self, count: u64) {
+        self.expired += count;
+    }
+
+    /// Number of messages dropped, outgoing or incoming, because a newer one
+    /// of the same "latest value only" type from the same sender was already queued.
+    ///
+    /// See `Connection::set_latest_value_only`.
+    pub fn superseded_messages(&self) -> u64 {
+        self.superseded
+    }
+
+    /// Hook called when queued messages are dropped for being superseded.
+    pub fn record_superseded(&mut self, count: u64) {
+        self.superseded += count;
+    }
+
+    /// Number of bytes written to the transport, including message headers and padding.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Rate of writing messages to the transport, recently:
+    /// to compare with a `BandwidthLimit`, for instance.
+    pub fn send_rate(&self) -> SendRate {
+        let since = Instant::now().checked_sub(SEND_RATE_WINDOW);
+        let (messages, bytes) = self
+            .recent_sends
+            .iter()
+            .filter(|(at, _, _)| since.is_none_or(|since| *at >= since))
+            .fold((0, 0), |(messages, bytes), (_, m, b)| {
+                (messages + m, bytes + b)
+            });
+        let window = SEND_RATE_WINDOW.as_secs_f64();
+        SendRate {
+            messages_per_sec: messages as f64 / window,
+            bytes_per_sec: bytes as f64 / window,
+        }
+    }
+
+    /// Hook called when messages have been written to the transport.
+    pub fn record_sent(&mut self, messages: u64, bytes: u64) {
+        let now = Instant::now();
+        self.bytes_sent += bytes;
+        self.recent_sends.push_back((now, messages, bytes));
+        while let Some((at, _, _)) = self.recent_sends.front() {
+            if now.duration_since(*at) <= SEND_RATE_WINDOW {
+                break;
+            }
+            self.recent_sends.pop_front();
+        }
+    }
+
+    /// Number of outgoing messages delayed to stay within a `BandwidthLimit`.
+    ///
+    /// See `ConnectionIp::set_bandwidth_limit`.
+    pub fn throttled_messages(&self) -> u64 {
+        self.throttled
+    }
+
+    /// Hook called when outgoing messages are delayed by a bandwidth limit.
+    pub fn record_throttled(&mut self, count: u64) {
+        self.throttled += count;
+    }
+
+    /// Number of messages waiting for dispatch workers, as of the last message dispatched.
+    ///
+    /// Always zero when handlers are called inline: see `TypeDispatcher::set_dispatch_policy`.
+    pub fn dispatch_queue_depth(&self) -> usize {
+        self.dispatch_queue_depth
+    }
+
+    /// Most messages seen waiting for dispatch workers at once.
+    pub fn dispatch_queue_peak(&self) -> usize {
+        self.dispatch_queue_peak
+    }
+
+    /// Number of messages that found their dispatch queue full.
+    ///
+    /// See `TypeDispatcher::set_dispatch_queue`.
+    pub fn dispatch_queue_overflows(&self) -> u64 {
+        self.dispatch_queue_overflows
+    }
+
+    /// Hook called after dispatching a message, with the number of messages then waiting
+    /// for dispatch workers, and how many found their queue full meanwhile.
+    pub fn record_dispatch_queue(&mut self, depth: usize, overflows: u64) {
+        self.dispatch_queue_depth = depth;
+        self.dispatch_queue_peak = self.dispatch_queue_peak.max(depth);
+        self.dispatch_queue_overflows += overflows;
+    }
+
+    /// Number of endpoints shut down for exceeding their `ParseErrorLimit`.
+    ///
+    /// See `ConnectionIp::set_parse_error_limit`.
+    pub fn quarantined_endpoints(&self) -> u64 {
+        self.quarantined_endpoints
+    }
+
+    /// Hook called when an endpoint is quarantined.
+    pub fn record_quarantined(&mut self) {
+        self.quarantined_endpoints += 1;
+    }
+
+    /// When a message of a type was last received from a sender, or None if never.
+    ///
+    /// Kept with local IDs, so a device keeps its history across reconnects.
+    pub fn last_received(
+        &self,
+        sender: LocalId<SenderId>,
+        message_type: LocalId<MessageTypeId>,
+    ) -> Option<Instant> {
+        self.last_received.get(&(sender, message_type)).copied()
+    }
+
+    /// When a message of any type was last received from a sender, or None if never.
+    pub fn last_received_from(&self, sender: LocalId<SenderId>) -> Option<Instant> {
+        self.last_received
+            .range(
+                (sender, LocalId(MessageTypeId(i32::MIN)))
+                    ..=(sender, LocalId(MessageTypeId(i32::MAX))),
+            )
+            .map(|(_, at)| *at)
+            .max()
+    }
+
+    /// When anything was last received, or None if never.
+    pub fn last_received_any(&self) -> Option<Instant> {
+        self.last_received.values().copied().max()
+    }
+
+    /// When each sender was last heard from with each message type, ordered by sender then type.
+    pub fn last_received_times(
+        &self,
+    ) -> impl Iterator<Item = (LocalId<SenderId>, LocalId<MessageTypeId>, Instant)> + '_ {
+        self.last_received
+            .iter()
+            .map(|((sender, message_type), at)| (*sender, *message_type, *at))
+    }
+
+    /// Hook called when a message is received, before it is dispatched.
+    pub fn record_received(&mut self, header: &MessageHeader) {
+        self.last_received.insert(
+            (LocalId(header.sender), LocalId(header.message_type)),
+            Instant::now(),
+        );
+    }
+
+    /// Clear all collected samples and counts, leaving instrumentation enabled if it was.
+    ///
+    /// Last-receive times are kept: they describe the devices, not the interval.
+    pub fn reset(&mut self) {
+        if let Some(hist) = self.decode_latency.as_mut() {
+            hist.clear();
+        }
+        self.errors.clear();
+        self.expired = 0;
+        self.superseded = 0;
+        self.bytes_sent = 0;
+        self.recent_sends.clear();
+        self.throttled = 0;
+        self.dispatch_queue_peak = self.dispatch_queue_depth;
+        self.dispatch_queue_overflows = 0;
+        self.quarantined_endpoints = 0;
+    }
+}
+
+/// What an endpoint was doing when it closed, for debugging disconnects in the field.
+///
+/// Reported with `ConnectionEvent::EndpointClosed`, and logged, when an endpoint goes away.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EndpointDiagnostics {
+    /// How long the endpoint was open.
+    pub uptime: Duration,
+    /// The error that closed the endpoint, if it didn't close cleanly.
+    pub error: Option<String>,
+    /// Number of messages decoded.
+    pub messages_received: u64,
+    /// Header of the last message decoded, with the IDs the remote end assigned.
+    pub last_received: Option<MessageHeader>,
+    /// Bytes read but not yet decoded into a complete message.
+    pub pending_receive_bytes: usize,
+    /// Number of messages written to the transport.
+    pub messages_sent: u64,
+    /// Header of the last message written to the transport.
+    pub last_sent: Option<MessageHeader>,
+    /// Messages queued but not yet written or dropped.
+    pub pending_send_messages: u64,
+    /// Bytes written but not yet flushed to the transport.
+    pub pending_send_bytes: usize,
+}
+
+impl fmt::Display for EndpointDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            Some(e) => write!(f, "closed with error ({})", e)?,
+            None => write!(f, "closed")?,
+        }
+        write!(
+            f,
+            " after {:.3}s: received {} messages (last {:?}, {} bytes pending), \
+             sent {} messages (last {:?}, {} messages and {} bytes pending)",
+            self.uptime.as_secs_f64(),
+            self.messages_received,
+            self.last_received,
+            self.pending_receive_bytes,
+            self.messages_sent,
+            self.last_sent,
+            self.pending_send_messages,
+            self.pending_send_bytes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut hist = LatencyHistogram::new();
+        assert_eq!(hist.count(), 0);
+        assert_eq!(hist.mean(), None);
+        assert_eq!(hist.quantile_upper_bound(0.5), None);
+
+        hist.record(Duration::from_micros(0));
+        hist.record(Duration::from_micros(3));
+        hist.record(Duration::from_micros(100));
+        hist.record(Duration::from_micros(101));
+        assert_eq!(hist.count(), 4);
+        assert_eq!(hist.min(), Some(Duration::from_micros(0)));
+        assert_eq!(hist.max(), Some(Duration::from_micros(101)));
+        assert_eq!(hist.mean(), Some(Duration::from_micros(51)));
+
+        let counts: Vec<u64> = hist.buckets().map(|(_, n)| n).collect();
+        assert_eq!(counts[0], 1);
+        assert_eq!(counts[2], 1);
+        assert_eq!(counts[7], 2);
+        assert_eq!(counts.iter().sum::<u64>(), 4);
+
+        assert_eq!(
+            hist.quantile_upper_bound(0.5),
+            Some(Duration::from_micros(4))
+        );
+        assert_eq!(
+            hist.quantile_upper_bound(1.0),
+            Some(Duration::from_micros(128))
+        );
+    }
+
+    #[test]
+    fn histogram_saturates() {
+        let mut hist = LatencyHistogram::new();
+        let huge = Duration::from_secs(60 * 60 * 24);
+        hist.record(huge);
+        assert_eq!(hist.buckets().last(), Some((None, 1)));
+        assert_eq!(hist.quantile_upper_bound(0.99), Some(huge));
+    }
+
+    #[test]
+    fn stats_hooks() {
+        let mut stats = ConnectionStats::new();
+        assert!(!stats.latency_instrumentation_enabled());
+        assert_eq!(stats.start_timing(), None);
+        stats.finish_timing(Some(Instant::now()));
+        assert!(stats.decode_latency().is_none());
+
+        stats.set_latency_instrumentation(true);
+        let start = stats.start_timing();
+        assert!(start.is_some());
+        stats.finish_timing(start);
+        assert_eq!(stats.decode_latency().map(|h| h.count()), Some(1));
+
+        stats.reset();
+        assert_eq!(stats.decode_latency().map(|h| h.count()), Some(0));
+
+        stats.set_latency_instrumentation(false);
+        assert!(stats.decode_latency().is_none());
+    }
+
+    #[test]
+    fn error_counters() {
+        let mut stats = ConnectionStats::new();
+        assert_eq!(stats.errors().total(), 0);
+        stats.record_vrpn_error(&VrpnError::BufferUnbuffer(BufferUnbufferError::OutOfBuffer));
+        stats.record_vrpn_error(&VrpnError::UnrecognizedSystemMessage(-42));
+        stats.record_vrpn_error(&VrpnError::MessageSizeInvalid(
+            crate::buffer_unbuffer::MessageSizeInvalid(3),
+        ));
+        stats.record_error(ErrorKind::Handshake);
+        assert_eq!(stats.errors().count(ErrorKind::OutOfBuffer), 1);
+        assert_eq!(stats.errors().count(ErrorKind::Parse), 2);
+        assert_eq!(stats.errors().count(ErrorKind::Handshake), 1);
+        assert_eq!(stats.errors().count(ErrorKind::Handler), 0);
+        assert_eq!(stats.errors().total(), 4);
+        assert_eq!(
+            stats.errors().iter().map(|(_, n)| n).collect::<Vec<_>>(),
+            vec![2, 1, 1, 0, 0, 0]
+        );
+
+        stats.record_expired(3);
+        assert_eq!(stats.expired_messages(), 3);
+        stats.record_superseded(2);
+        assert_eq!(stats.superseded_messages(), 2);
+
+        stats.reset();
+        assert_eq!(stats.errors().total(), 0);
+        assert_eq!(stats.expired_messages(), 0);
+        assert_eq!(stats.superseded_messages(), 0);
+    }
+}
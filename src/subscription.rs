@@ -0,0 +1,543 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Typed subscriptions that carry on across a client connection losing and regaining its server.
+//!
+//! Handlers are keyed on local IDs, so they keep receiving messages after a reconnect anyway.
+//! A `Subscription` adds the lifecycle on top: it yields `SubscriptionEvent::Disconnected`
+//! when the remote end goes away and `SubscriptionEvent::Resumed` once it describes
+//! the sender again, so application code can, for instance, stop trusting stale state,
+//! without watching connection events at every subscription site.
+//!
+//! Nothing needs re-subscribing: on reconnecting, the connection re-sends the descriptions
+//! of our senders and types, which is how a server learns what we're interested in.
+//!
+//! A subscription can also keep a bounded history of the latest messages (see `HistoryLimit`),
+//! e.g. to compute velocities from poses, or to catch up a consumer that starts late.
+
+use crate::{
+    buffer_unbuffer::UnbufferFrom,
+    clock::SystemTime,
+    data_types::{
+        id_types::{LocalId, SenderId},
+        SenderName, TypedMessage, TypedMessageBody,
+    },
+    handler::{
+        DescriptionHandler, DescriptionHandlerHandle, HandlerCode, HandlerHandle,
+        RemoteDescription, TypedHandler,
+    },
+    translation_table::TranslationTableExt,
+    Connection, Endpoint, Result,
+};
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    Stream, StreamExt,
+};
+use std::{
+    collections::VecDeque,
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Where a subscription is in the connection lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SubscriptionState {
+    /// The remote end hasn't yet described the sender, or sent anything from it.
+    Waiting,
+    /// The remote end knows the sender, so its messages can arrive.
+    Active,
+    /// The remote end went away: nothing arrives until it comes back.
+    Disconnected,
+}
+
+/// Yielded by a `Subscription`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubscriptionEvent<T: TypedMessageBody> {
+    Message(TypedMessage<T>),
+    /// The remote end went away while the subscription was active.
+    Disconnected,
+    /// The remote end came back after `Disconnected`, and described the sender again
+    /// or sent a message from it.
+    Resumed,
+}
+
+/// Which messages a subscription keeps in its history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HistoryLimit {
+    /// The latest this many messages.
+    Messages(usize),
+    /// The messages timestamped at most this long before the latest one.
+    ///
+    /// Ages are by the sender's timestamps, so nothing expires while no messages arrive.
+    Age(Duration),
+}
+
+/// The latest messages received, oldest first.
+#[derive(Debug)]
+struct History<T: TypedMessageBody> {
+    limit: HistoryLimit,
+    messages: VecDeque<TypedMessage<T>>,
+}
+
+impl<T: TypedMessageBody> History<T> {
+    fn new(limit: HistoryLimit) -> History<T> {
+        History {
+            limit,
+            messages: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, msg: TypedMessage<T>) {
+        let latest = SystemTime::from(msg.header.time);
+        self.messages.push_back(msg);
+        match self.limit {
+            HistoryLimit::Messages(max) => {
+                while self.messages.len() > max {
+                    self.messages.pop_front();
+                }
+            }
+            HistoryLimit::Age(max_age) => {
+                // Messages timestamped after the latest, e.g. from a clock jump, are kept.
+                while let Some(oldest) = self.messages.front() {
+                    match latest.duration_since(SystemTime::from(oldest.header.time)) {
+                        Ok(age) if age > max_age => self.messages.pop_front(),
+                        _ => break,
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// State shared by a subscription and its handlers.
+#[derive(Debug)]
+struct Shared<T: TypedMessageBody> {
+    state: SubscriptionState,
+    tx: UnboundedSender<SubscriptionEvent<T>>,
+    history: Option<History<T>>,
+}
+
+impl<T: TypedMessageBody> Shared<T> {
+    /// Queue an event, returning the handler code: remove the handler if the subscription is gone.
+    fn send(&self, event: SubscriptionEvent<T>) -> HandlerCode {
+        match self.tx.unbounded_send(event) {
+            Ok(()) => HandlerCode::ContinueProcessing,
+            Err(_) => HandlerCode::RemoveThisHandler,
+        }
+    }
+
+    /// The remote end described the sender, or sent a message from it.
+    fn sender_seen(&mut self) -> HandlerCode {
+        match std::mem::replace(&mut self.state, SubscriptionState::Active) {
+            SubscriptionState::Disconnected => self.send(SubscriptionEvent::Resumed),
+            SubscriptionState::Waiting | SubscriptionState::Active => {
+                HandlerCode::ContinueProcessing
+            }
+        }
+    }
+
+    fn remote_lost(&mut self) -> HandlerCode {
+        match self.state {
+            SubscriptionState::Active => {
+                self.state = SubscriptionState::Disconnected;
+                self.send(SubscriptionEvent::Disconnected)
+            }
+            // If the remote end never knew the sender, there was nothing to lose.
+            SubscriptionState::Waiting | SubscriptionState::Disconnected => {
+                HandlerCode::ContinueProcessing
+            }
+        }
+    }
+}
+
+type SharedState<T> = Arc<Mutex<Shared<T>>>;
+
+fn lock<T: TypedMessageBody>(shared: &SharedState<T>) -> std::sync::MutexGuard<'_, Shared<T>> {
+    // The state is always consistent, even if a holder panicked.
+    shared.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct MessageForwarder<T: TypedMessageBody>(SharedState<T>);
+
+impl<T: TypedMessageBody> fmt::Debug for MessageForwarder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MessageForwarder").finish()
+    }
+}
+
+impl<T> TypedHandler for MessageForwarder<T>
+where
+    T: TypedMessageBody + UnbufferFrom + fmt::Debug + Clone + Send + Sync,
+{
+    type Item = T;
+    fn handle_typed(&mut self, msg: &TypedMessage<T>) -> Result<HandlerCode> {
+        let mut shared = lock(&self.0);
+        if shared.sender_seen() == HandlerCode::RemoveThisHandler {
+            return Ok(HandlerCode::RemoveThisHandler);
+        }
+        if let Some(history) = &mut shared.history {
+            history.push(msg.clone());
+        }
+        Ok(shared.send(SubscriptionEvent::Message(msg.clone())))
+    }
+}
+
+struct LifecycleTracker<T: TypedMessageBody> {
+    sender: LocalId<SenderId>,
+    shared: SharedState<T>,
+}
+
+impl<T: TypedMessageBody> fmt::Debug for LifecycleTracker<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LifecycleTracker")
+            .field("sender", &self.sender)
+            .finish()
+    }
+}
+
+impl<T: TypedMessageBody + Send> DescriptionHandler for LifecycleTracker<T> {
+    fn handle_description(&mut self, desc: &RemoteDescription) -> Result<HandlerCode> {
+        let mut shared = lock(&self.shared);
+        Ok(match desc {
+            RemoteDescription::Sender { local_id, .. } if *local_id == self.sender => {
+                shared.sender_seen()
+            }
+            RemoteDescription::RemoteLost(_) => shared.remote_lost(),
+            _ => HandlerCode::ContinueProcessing,
+        })
+    }
+}
+
+type Unsubscribe = Box<dyn FnOnce() -> Result<()> + Send>;
+
+/// A stream of the messages of one type from one sender, with lifecycle events.
+///
+/// Dropping it unsubscribes the next time a message or lifecycle change would be delivered;
+/// call `unsubscribe` to do so right away.
+pub struct Subscription<T: TypedMessageBody> {
+    rx: UnboundedReceiver<SubscriptionEvent<T>>,
+    shared: SharedState<T>,
+    sender: LocalId<SenderId>,
+    unsubscribe: Unsubscribe,
+}
+
+impl<T> Subscription<T>
+where
+    T: TypedMessageBody + UnbufferFrom + fmt::Debug + Clone + Send + Sync + 'static,
+{
+    /// Subscribe to messages of type `T` from the named sender.
+    pub fn new<C: Connection + 'static>(
+        connection: &Arc<C>,
+        sender: impl Into<SenderName>,
+    ) -> Result<Subscription<T>> {
+        let sender = connection.register_sender(sender.into())?;
+        let (tx, rx) = unbounded();
+        let shared = Arc::new(Mutex::new(Shared {
+            state: SubscriptionState::Waiting,
+            tx,
+            history: None,
+        }));
+        let handler = connection.add_typed_handler(
+            Box::new(MessageForwarder(Arc::clone(&shared))),
+            Some(sender),
+        )?;
+        let description = connection.add_description_handler(Box::new(LifecycleTracker {
+            sender,
+            shared: Arc::clone(&shared),
+        }));
+        let description = match description {
+            Ok(description) => description,
+            Err(e) => {
+                connection.remove_handler(handler)?;
+                return Err(e);
+            }
+        };
+        // The remote end may have described the sender before we subscribed.
+        if described(&**connection, sender)? {
+            lock(&shared).sender_seen();
+        }
+        let connection = Arc::clone(connection);
+        Ok(Subscription {
+            rx,
+            shared,
+            sender,
+            unsubscribe: Box::new(move || remove(&*connection, handler, description)),
+        })
+    }
+}
+
+/// Whether the remote end of any endpoint has described the sender.
+fn described<C: Connection>(connection: &C, sender: LocalId<SenderId>) -> Result<bool> {
+    let endpoints = connection.endpoints();
+    let endpoints = endpoints.lock()?;
+    Ok(endpoints
+        .iter()
+        .flatten()
+        .any(|ep| ep.translation_tables().find_by_local_id(sender).is_some()))
+}
+
+fn remove<C: Connection>(
+    connection: &C,
+    handler: HandlerHandle,
+    description: DescriptionHandlerHandle,
+) -> Result<()> {
+    // Either may already have removed itself, if the subscription was dropped.
+    let handler_result = connection.remove_handler(handler);
+    let description_result = connection.remove_description_handler(description);
+    match (handler_result, description_result) {
+        (Err(crate::VrpnError::HandlerNotFound), Err(crate::VrpnError::HandlerNotFound)) => Ok(()),
+        (Err(crate::VrpnError::HandlerNotFound), other) | (other, _) => other,
+    }
+}
+
+impl<T: TypedMessageBody> Subscription<T> {
+    /// The current state, as of the last message or lifecycle change handled.
+    ///
+    /// Events reporting how it got here may still be waiting to be taken from the stream.
+    pub fn state(&self) -> SubscriptionState {
+        lock(&self.shared).state
+    }
+
+    /// The local ID of the sender subscribed to.
+    pub fn sender(&self) -> LocalId<SenderId> {
+        self.sender
+    }
+
+    /// Keep a history of the messages received from now on, bounded as given.
+    ///
+    /// The history is kept whether or not messages are taken from the stream:
+    /// see `history`.
+    pub fn with_history(self, limit: HistoryLimit) -> Subscription<T> {
+        self.set_history(Some(limit));
+        self
+    }
+
+    /// Start keeping a history bounded as given, discarding any kept so far,
+    /// or stop keeping one if None.
+    pub fn set_history(&self, limit: Option<HistoryLimit>) {
+        lock(&self.shared).history = limit.map(History::new);
+    }
+
+    /// The messages in the history, oldest first: empty if none is kept.
+    pub fn history(&self) -> Vec<TypedMessage<T>>
+    where
+        T: Clone,
+    {
+        lock(&self.shared)
+            .history
+            .as_ref()
+            .map(|history| history.messages.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Stop receiving messages, discarding any not yet taken.
+    pub fn unsubscribe(self) -> Result<()> {
+        (self.unsubscribe)()
+    }
+}
+
+impl<T: TypedMessageBody> fmt::Debug for Subscription<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Subscription")
+            .field("sender", &self.sender)
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+impl<T: TypedMessageBody> Unpin for Subscription<T> {}
+
+impl<T: TypedMessageBody> Stream for Subscription<T> {
+    type Item = SubscriptionEvent<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{Description, TimeVal},
+        endpoint::{handle_system_command, SystemCommand},
+        ping::Ping,
+        testing::MockConnection,
+        TranslationTables,
+    };
+    use bytes::Bytes;
+    use futures::FutureExt;
+
+    fn describe(connection: &MockConnection, tables: &mut TranslationTables) {
+        let dispatcher = connection.dispatcher();
+        let mut dispatcher = dispatcher.lock().unwrap();
+        handle_system_command(
+            &mut dispatcher,
+            tables,
+            SystemCommand::SenderDescription(Description::from_id_and_name(
+                SenderId(7),
+                Bytes::from_static(b"Tracker0"),
+            )),
+        )
+        .unwrap();
+    }
+
+    fn lose_remote(connection: &MockConnection, tables: &mut TranslationTables) {
+        let mappings = tables.invalidate_remote();
+        connection
+            .dispatcher()
+            .lock()
+            .unwrap()
+            .call_description_handlers(&RemoteDescription::RemoteLost(mappings))
+            .unwrap();
+    }
+
+    fn ping(connection: &MockConnection, sender: LocalId<SenderId>) -> TypedMessage<Ping> {
+        let message_type = connection
+            .dispatcher()
+            .lock()
+            .unwrap()
+            .type_id_for(Ping::MESSAGE_IDENTIFIER)
+            .unwrap();
+        TypedMessage::new(Some(TimeVal::default()), message_type, sender, Ping)
+    }
+
+    fn next_event(sub: &mut Subscription<Ping>) -> Option<SubscriptionEvent<Ping>> {
+        sub.next().now_or_never().flatten()
+    }
+
+    #[test]
+    fn lifecycle() {
+        let connection = MockConnection::new();
+        let mut tables = TranslationTables::new();
+        let mut sub = Subscription::<Ping>::new(&connection, "Tracker0").unwrap();
+        let msg = ping(&connection, sub.sender());
+        assert_eq!(sub.state(), SubscriptionState::Waiting);
+
+        // Losing a remote that never knew the sender changes nothing.
+        lose_remote(&connection, &mut tables);
+        assert_eq!(sub.state(), SubscriptionState::Waiting);
+
+        describe(&connection, &mut tables);
+        assert_eq!(sub.state(), SubscriptionState::Active);
+        connection.receive(msg.clone()).unwrap();
+        assert_eq!(
+            next_event(&mut sub),
+            Some(SubscriptionEvent::Message(msg.clone()))
+        );
+        assert_eq!(next_event(&mut sub), None);
+
+        lose_remote(&connection, &mut tables);
+        assert_eq!(sub.state(), SubscriptionState::Disconnected);
+        assert_eq!(next_event(&mut sub), Some(SubscriptionEvent::Disconnected));
+
+        // Back again: the same subscription resumes.
+        describe(&connection, &mut tables);
+        connection.receive(msg.clone()).unwrap();
+        assert_eq!(next_event(&mut sub), Some(SubscriptionEvent::Resumed));
+        assert_eq!(next_event(&mut sub), Some(SubscriptionEvent::Message(msg)));
+
+        // Other senders aren't delivered.
+        let other = connection.register_sender("Other").unwrap();
+        connection.receive(ping(&connection, other)).unwrap();
+        assert_eq!(next_event(&mut sub), None);
+
+        sub.unsubscribe().unwrap();
+    }
+
+    #[test]
+    fn already_described() {
+        let connection = MockConnection::new();
+        {
+            let endpoints = connection.endpoints();
+            let mut endpoints = endpoints.lock().unwrap();
+            let endpoint = endpoints[0].as_mut().unwrap();
+            describe(&connection, endpoint.translation_tables_mut());
+        }
+        let sub = Subscription::<Ping>::new(&connection, "Tracker0").unwrap();
+        assert_eq!(sub.state(), SubscriptionState::Active);
+        sub.unsubscribe().unwrap();
+    }
+
+    #[test]
+    fn resumes_on_message() {
+        let connection = MockConnection::new();
+        let mut tables = TranslationTables::new();
+        let mut sub = Subscription::<Ping>::new(&connection, "Tracker0").unwrap();
+        let msg = ping(&connection, sub.sender());
+        connection.receive(msg.clone()).unwrap();
+        assert_eq!(sub.state(), SubscriptionState::Active);
+        lose_remote(&connection, &mut tables);
+        connection.receive(msg.clone()).unwrap();
+        let events: Vec<_> = std::iter::from_fn(|| next_event(&mut sub)).collect();
+        assert_eq!(
+            events,
+            vec![
+                SubscriptionEvent::Message(msg.clone()),
+                SubscriptionEvent::Disconnected,
+                SubscriptionEvent::Resumed,
+                SubscriptionEvent::Message(msg.clone()),
+            ]
+        );
+
+        // Once dropped, its handlers remove themselves the next time they run,
+        // so unsubscribing later is harmless.
+        let unsubscribe = sub.unsubscribe;
+        drop(sub.rx);
+        connection.receive(msg).unwrap();
+        lose_remote(&connection, &mut tables);
+        unsubscribe().unwrap();
+    }
+
+    #[test]
+    fn history() {
+        use crate::data_types::{Microseconds, Seconds};
+        let connection = MockConnection::new();
+        let sub = Subscription::<Ping>::new(&connection, "Tracker0").unwrap();
+        let sender = sub.sender();
+        let at = |seconds, micros| {
+            let mut msg = ping(&connection, sender);
+            msg.header.time = TimeVal::new(Seconds(seconds), Microseconds(micros));
+            msg
+        };
+        let times = |sub: &Subscription<Ping>| -> Vec<_> {
+            sub.history().iter().map(|msg| msg.header.time).collect()
+        };
+        connection.receive(at(1, 0)).unwrap();
+        assert!(sub.history().is_empty());
+
+        let sub = sub.with_history(HistoryLimit::Messages(2));
+        for i in 2..5 {
+            connection.receive(at(i, 0)).unwrap();
+        }
+        assert_eq!(
+            times(&sub),
+            vec![
+                TimeVal::new(Seconds(3), Microseconds(0)),
+                TimeVal::new(Seconds(4), Microseconds(0))
+            ]
+        );
+
+        sub.set_history(Some(HistoryLimit::Age(Duration::from_millis(500))));
+        assert!(sub.history().is_empty());
+        connection.receive(at(10, 0)).unwrap();
+        connection.receive(at(10, 400_000)).unwrap();
+        connection.receive(at(10, 600_000)).unwrap();
+        assert_eq!(
+            times(&sub),
+            vec![
+                TimeVal::new(Seconds(10), Microseconds(400_000)),
+                TimeVal::new(Seconds(10), Microseconds(600_000))
+            ]
+        );
+
+        sub.set_history(None);
+        connection.receive(at(11, 0)).unwrap();
+        assert!(sub.history().is_empty());
+        sub.unsubscribe().unwrap();
+    }
+}
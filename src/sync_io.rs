@@ -10,28 +10,20 @@
 extern crate bytes;
 
 use crate::{
-    buffer_unbuffer::{
-        peek_u32, size_requirement::MayContainSizeRequirement, BytesMutExtras, ConstantBufferSize,
-        SizeRequirement,
-    },
-    data_types::{
-        self, id_types::SequenceNumber, CookieData, GenericMessage, Message, MessageSize,
-        SequencedGenericMessage,
-    },
+    buffer_unbuffer::{BytesMutExtras, ConstantBufferSize},
+    codec::{MessageDecoder, MessageSizeLimit},
+    data_types::{self, id_types::SequenceNumber, CookieData, GenericMessage},
     endpoint::SystemCommand,
     error::VrpnError,
-    handle_system_command, parse_system_message,
+    handshake::Handshake,
+    protocol::ProtocolCore,
     translation_table::TranslationTables,
-    Endpoint, EndpointGeneric, TypeDispatcher,
+    Endpoint, TypeDispatcher,
 };
 use bytes::BytesMut;
 use std::{
     io::{self, Read, Write},
     net::TcpStream,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        mpsc,
-    },
     time::Duration,
 };
 
@@ -55,127 +47,165 @@ where
     Ok(buf)
 }
 
+/// Drive a handshake to completion over a synchronous stream.
+///
+/// Reads only as much as the handshake needs, so the stream is left at the first message.
+pub fn perform_handshake<T>(stream: &mut T, handshake: &mut Handshake) -> Result<(), VrpnError>
+where
+    T: Read + Write,
+{
+    let mut incoming = Vec::new();
+    loop {
+        let outgoing = handshake.advance(&incoming)?;
+        stream.write_all(&outgoing)?;
+        if handshake.is_complete() {
+            stream.flush()?;
+            return Ok(());
+        }
+        incoming.resize(handshake.bytes_needed(), 0);
+        stream.read_exact(&mut incoming)?;
+    }
+}
+
+/// Synchronous driver for a `ProtocolCore` over TCP.
 #[derive(Debug)]
 pub struct EndpointSyncTcp {
-    translation: TranslationTables,
+    core: ProtocolCore,
     stream: TcpStream,
-    system_rx: mpsc::Receiver<SystemCommand>,
-    system_tx: mpsc::Sender<SystemCommand>,
-    seq: AtomicUsize,
+    read_buf: Vec<u8>,
 }
 
 impl EndpointSyncTcp {
+    /// Wrap a stream whose handshake has already been performed.
     pub fn new(stream: TcpStream) -> EndpointSyncTcp {
-        let (system_tx, system_rx) = mpsc::channel();
+        EndpointSyncTcp::from_core(stream, ProtocolCore::after_handshake(SequenceNumber(0)))
+    }
+
+    /// Wrap a stream, performing the handshake as part of polling.
+    pub fn with_handshake(
+        stream: TcpStream,
+        handshake: Handshake,
+    ) -> Result<EndpointSyncTcp, VrpnError> {
+        let mut endpoint = EndpointSyncTcp::from_core(stream, ProtocolCore::new(handshake)?);
+        endpoint.write_outgoing()?;
+        Ok(endpoint)
+    }
+
+    fn from_core(stream: TcpStream, core: ProtocolCore) -> EndpointSyncTcp {
         EndpointSyncTcp {
-            translation: TranslationTables::new(),
+            core,
             stream,
-            system_tx,
-            system_rx,
-            seq: AtomicUsize::new(0),
+            read_buf: vec![0u8; 4096],
         }
     }
 
-    fn read_single_message(&mut self) -> Result<SequencedGenericMessage, VrpnError> {
-        self.stream
-            .set_read_timeout(Some(Duration::from_millis(1)))?;
-        let mut buf = BytesMut::with_capacity(24);
-
-        // Peek the message header and padding
-        self.stream.peek(buf.as_mut()).map_err(|e| {
-            use io::ErrorKind::*;
-            match e.kind() {
-                WouldBlock | TimedOut => VrpnError::from(SizeRequirement::Unknown),
-                // Not a "need more data"
-                _ => e.into(),
-            }
-        })?;
-
-        // Peek the size field, to compute the MessageSize.
-        let total_len = peek_u32(&buf.clone().freeze()).unwrap();
-        let size = MessageSize::try_from_length_field(total_len)?;
-
-        // Read the body of the message
-        let mut msg_buf = BytesMut::with_capacity(size.padded_message_size());
-        self.stream.read_exact(msg_buf.as_mut())?;
-        let mut msg_buf = msg_buf.freeze();
+    /// Change the limit on the size of messages accepted from the remote end:
+    /// see `MessageSizeLimit`.
+    pub fn with_message_size_limit(self, limit: MessageSizeLimit) -> EndpointSyncTcp {
+        let core = self
+            .core
+            .with_decoder(MessageDecoder::new().with_limit(limit));
+        EndpointSyncTcp { core, ..self }
+    }
 
-        // Unbuffer the message.
-        let result = SequencedGenericMessage::try_read_from_buf(&mut msg_buf)?;
-        Ok(result)
+    /// True once the handshake is complete.
+    pub fn is_connected(&self) -> bool {
+        self.core.is_connected()
     }
 
-    pub fn poll_endpoint(&mut self, mut dispatcher: &mut TypeDispatcher) -> Result<(), VrpnError> {
-        loop {
-            match self.read_single_message() {
-                Ok(msg) => {
-                    let msg = self.map_remote_message_to_local(msg.into_inner())?;
-                    if msg.is_system_message() {
-                        self.send_system_change(parse_system_message(msg)?)?;
-                    } else {
-                        dispatcher.call(&msg)?;
-                    }
-                }
-                Err(e) => {
-                    if (&e).try_get_size_requirement().is_some() {
-                        break;
-                    }
-                    return Err(e);
-                }
-            }
+    fn write_outgoing(&mut self) -> Result<(), VrpnError> {
+        if self.core.has_outgoing() {
+            self.stream.write_all(&self.core.take_outgoing())?;
         }
-        // Now, process the system commands that have been queued.
+        Ok(())
+    }
+
+    /// Read whatever is available without blocking long, process it, and send any replies.
+    pub fn poll_endpoint(&mut self, dispatcher: &mut TypeDispatcher) -> Result<(), VrpnError> {
+        self.stream
+            .set_read_timeout(Some(Duration::from_millis(1)))?;
         loop {
-            match self.system_rx.recv_timeout(Duration::from_micros(1)) {
-                Ok(cmd) => {
-                    if handle_system_command(&mut dispatcher, self.translation_tables_mut(), cmd)?
-                        .is_some()
-                    {
-                        // we don't handle any other system commands in this endpoint right now
-                    }
-                }
-                Err(mpsc::RecvTimeoutError::Timeout) => {
-                    // no more commands
+            let n = match self.stream.read(&mut self.read_buf) {
+                Ok(0) => return Err(VrpnError::EndpointClosed),
+                Ok(n) => n,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
                     break;
                 }
-                Err(mpsc::RecvTimeoutError::Disconnected) => {
-                    // todo how to handle this? Might never happen.
-                    break;
-                }
-            }
+                Err(e) => return Err(e.into()),
+            };
+            // Nothing this endpoint does with other system commands right now.
+            let _ = self.core.receive(&self.read_buf[..n], dispatcher)?;
         }
-        Ok(())
+        self.write_outgoing()
     }
 }
 
 impl Endpoint for EndpointSyncTcp {
     fn translation_tables(&self) -> &TranslationTables {
-        &self.translation
+        self.core.translation_tables()
     }
 
     fn translation_tables_mut(&mut self) -> &mut TranslationTables {
-        &mut self.translation
+        self.core.translation_tables_mut()
     }
 
     fn send_system_change(&self, message: SystemCommand) -> Result<(), VrpnError> {
-        println!("send_system_change {:?}", message);
-        self.system_tx
-            .send(message)
-            .map_err(|e| VrpnError::OtherMessage(e.to_string()))?;
-        Ok(())
+        self.core.send_system_change(message)
     }
 
     fn buffer_generic_message(
         &mut self,
         msg: GenericMessage,
-        _class: data_types::ClassOfService,
+        class: data_types::ClassOfService,
     ) -> Result<(), VrpnError> {
-        // Ignore class of service here
-        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
-        let sequenced = msg.into_sequenced_message(SequenceNumber(seq as u32));
-        let buf = sequenced.try_into_buf()?;
+        self.core.buffer_generic_message(msg, class)?;
+        self.write_outgoing()
+    }
+}
 
-        self.stream.write_all(&buf[..])?;
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer_unbuffer::BufferUnbufferError,
+        data_types::{
+            id_types::{MessageTypeId, SenderId},
+            GenericBody, Message, MessageHeader,
+        },
+    };
+    use bytes::Bytes;
+    use std::net::TcpListener;
+
+    #[test]
+    fn message_size_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut endpoint =
+            EndpointSyncTcp::new(stream).with_message_size_limit(MessageSizeLimit::new(64));
+
+        let msg = GenericMessage::from_header_and_body(
+            MessageHeader::new(None, MessageTypeId(0), SenderId(0)),
+            GenericBody::new(Bytes::from(vec![0u8; 128])),
+        )
+        .into_sequenced_message(SequenceNumber(0));
+        peer.write_all(&BytesMut::allocate_and_buffer(msg).unwrap())
+            .unwrap();
+
+        let mut dispatcher = TypeDispatcher::new();
+        let error = loop {
+            if let Err(e) = endpoint.poll_endpoint(&mut dispatcher) {
+                break e;
+            }
+        };
+        assert!(matches!(
+            error,
+            VrpnError::BufferUnbuffer(BufferUnbufferError::MessageTooLarge { max: 64, .. })
+        ));
     }
 }
@@ -0,0 +1,381 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Test doubles, to unit-test handlers and device logic without sockets.
+//!
+//! Enable the `test-util` feature to use these outside this crate, e.g. as a dev-dependency.
+//! A `MockConnection` is a `Connection` with a single `ScriptedEndpoint`: messages "received"
+//! are dispatched to its handlers right away with `receive`, or queued with `script` and
+//! dispatched in order with `run_script`, while everything packed for sending is captured.
+//!
+//! ```
+//! use vrpn::{data_types::ClassOfService, ping::Ping, testing::MockConnection, Connection};
+//! # fn main() -> vrpn::Result<()> {
+//! let connection = MockConnection::new();
+//! let sender = connection.register_sender("Tracker0")?;
+//! connection.pack_message_body(None, sender, Ping, ClassOfService::RELIABLE)?;
+//! assert_eq!(connection.sent_typed::<Ping>()?.len(), 1);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With the `vrpn-async-std` feature, a `LoopbackServer` is a real server on the loopback
+//! interface, to test clients and bindings end to end.
+
+use crate::{
+    buffer_unbuffer::{BufferTo, UnbufferFrom},
+    clock::{SharedClock, SystemClock},
+    connection::{Connection, ConnectionCore, ConnectionStatus},
+    data_types::{
+        ClassOfService, GenericMessage, Message, MessageTypeIdentifier, TypedMessage,
+        TypedMessageBody,
+    },
+    endpoint::{Endpoint, SystemCommand},
+    Result, TranslationTables,
+};
+#[cfg(feature = "vrpn-async-std")]
+use crate::{
+    data_types::{
+        id_types::{LocalId, SenderId},
+        SenderName,
+    },
+    vrpn_async_std::connection_ip::{ConnectionIp, ConnectionIpStream},
+    VrpnError,
+};
+#[cfg(feature = "vrpn-async-std")]
+use async_std::net::TcpListener;
+#[cfg(feature = "vrpn-async-std")]
+use futures::{
+    channel::oneshot,
+    future::{select, Either},
+    StreamExt,
+};
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+#[cfg(feature = "vrpn-async-std")]
+use std::{
+    net::Ipv4Addr,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Messages captured and waiting, shared by a `MockConnection` and its endpoint.
+#[derive(Debug, Default)]
+struct Script {
+    /// Everything packed for sending, including descriptions, oldest first.
+    sent: Vec<GenericMessage>,
+    /// Messages to dispatch as if received, oldest first.
+    incoming: VecDeque<GenericMessage>,
+}
+
+type SharedScript = Arc<Mutex<Script>>;
+
+fn lock(script: &SharedScript) -> MutexGuard<'_, Script> {
+    script.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The endpoint of a `MockConnection`: captures what is sent to it,
+/// and holds the messages scripted as received.
+#[derive(Debug)]
+pub struct ScriptedEndpoint {
+    translation: TranslationTables,
+    script: SharedScript,
+}
+
+impl Endpoint for ScriptedEndpoint {
+    fn translation_tables(&self) -> &TranslationTables {
+        &self.translation
+    }
+    fn translation_tables_mut(&mut self) -> &mut TranslationTables {
+        &mut self.translation
+    }
+    fn send_system_change(&self, _message: SystemCommand) -> Result<()> {
+        Ok(())
+    }
+    fn buffer_generic_message(
+        &mut self,
+        msg: GenericMessage,
+        _class: ClassOfService,
+    ) -> Result<()> {
+        lock(&self.script).sent.push(msg);
+        Ok(())
+    }
+}
+
+/// A connection with a single `ScriptedEndpoint`, always reporting itself connected.
+///
+/// Messages are received already using local IDs: get them from `register_sender`
+/// and `register_type`, as with any connection.
+#[derive(Debug)]
+pub struct MockConnection {
+    core: ConnectionCore<ScriptedEndpoint>,
+    script: SharedScript,
+}
+
+impl MockConnection {
+    /// Create a connection stamping messages with the system time.
+    pub fn new() -> Arc<MockConnection> {
+        MockConnection::with_clock(SystemClock::shared())
+    }
+
+    /// Create a connection with a specific time source, e.g. a `MockClock`.
+    pub fn with_clock(clock: SharedClock) -> Arc<MockConnection> {
+        let script = SharedScript::default();
+        let endpoint = ScriptedEndpoint {
+            translation: TranslationTables::new(),
+            script: Arc::clone(&script),
+        };
+        Arc::new(MockConnection {
+            core: ConnectionCore::new_with_clock(vec![Some(endpoint)], None, None, clock),
+            script,
+        })
+    }
+
+    /// Dispatch a message as if it had been received.
+    pub fn receive<T: TypedMessageBody + BufferTo>(&self, msg: TypedMessage<T>) -> Result<()> {
+        self.receive_generic(GenericMessage::try_from(msg)?)
+    }
+
+    /// Dispatch a generic message as if it had been received.
+    pub fn receive_generic(&self, msg: GenericMessage) -> Result<()> {
+        self.dispatcher().lock()?.call(&msg)?;
+        self.send_deferred()
+    }
+
+    /// Queue a message to dispatch as if received, the next time `run_script` is called.
+    pub fn script<T: TypedMessageBody + BufferTo>(&self, msg: TypedMessage<T>) -> Result<()> {
+        let msg = GenericMessage::try_from(msg)?;
+        lock(&self.script).incoming.push_back(msg);
+        Ok(())
+    }
+
+    /// Dispatch the messages queued with `script`, in order, returning how many there were.
+    ///
+    /// Stops at the first that fails, leaving the rest queued.
+    pub fn run_script(&self) -> Result<usize> {
+        let mut count = 0;
+        loop {
+            let msg = match lock(&self.script).incoming.pop_front() {
+                Some(msg) => msg,
+                None => return Ok(count),
+            };
+            self.receive_generic(msg)?;
+            count += 1;
+        }
+    }
+
+    /// All messages sent so far, including descriptions.
+    pub fn sent_messages(&self) -> Vec<GenericMessage> {
+        lock(&self.script).sent.clone()
+    }
+
+    /// The non-system messages sent so far, ignoring descriptions sent by registration.
+    pub fn sent_user_messages(&self) -> Vec<GenericMessage> {
+        lock(&self.script)
+            .sent
+            .iter()
+            .filter(|msg| !msg.is_system_message())
+            .cloned()
+            .collect()
+    }
+
+    /// The messages of type `T` sent so far, decoded.
+    pub fn sent_typed<T: TypedMessageBody + UnbufferFrom>(&self) -> Result<Vec<TypedMessage<T>>> {
+        let message_type = match T::MESSAGE_IDENTIFIER {
+            MessageTypeIdentifier::UserMessageName(name) => {
+                match self.dispatcher().lock()?.get_type_id(name) {
+                    Some(id) => id.0,
+                    None => return Ok(Vec::new()),
+                }
+            }
+            MessageTypeIdentifier::SystemMessageId(id) => id,
+        };
+        lock(&self.script)
+            .sent
+            .iter()
+            .filter(|msg| msg.header.message_type == message_type)
+            .map(TypedMessage::try_from)
+            .collect()
+    }
+
+    /// Forget the messages sent so far.
+    pub fn clear_sent(&self) {
+        lock(&self.script).sent.clear();
+    }
+}
+
+impl Connection for MockConnection {
+    type SpecificEndpoint = ScriptedEndpoint;
+    fn connection_core(&self) -> &ConnectionCore<ScriptedEndpoint> {
+        &self.core
+    }
+    fn status(&self) -> ConnectionStatus {
+        ConnectionStatus::ClientConnected
+    }
+}
+
+/// A server on the loopback interface, driven on a background thread, that accepts one client.
+///
+/// Register the senders and message types a client looks for with `register` before it connects,
+/// so they are described during setup, then pack messages once `wait_for_client` returns.
+/// Dropping it stops the server.
+#[cfg(feature = "vrpn-async-std")]
+pub struct LoopbackServer {
+    connection: Arc<ConnectionIp>,
+    url: String,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(feature = "vrpn-async-std")]
+impl LoopbackServer {
+    /// Listen on a free port, and start accepting.
+    pub fn start() -> Result<LoopbackServer> {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let url = format!("tcp://{}", listener.local_addr()?);
+        let listener = TcpListener::from(listener);
+        let connection = ConnectionIp::new_server(None, None)?;
+        let driven = Arc::clone(&connection);
+        let (stop, stopped) = oneshot::channel();
+        let thread = thread::Builder::new()
+            .name("vrpn-loopback-server".to_string())
+            .spawn(move || {
+                let serve = async move {
+                    let (stream, _) = listener.accept().await?;
+                    driven.accept_client(stream).await?;
+                    let mut stream = ConnectionIpStream::new(driven);
+                    while let Some(result) = stream.next().await {
+                        result?;
+                    }
+                    Ok::<(), VrpnError>(())
+                };
+                let served = async_std::task::block_on(select(Box::pin(serve), stopped));
+                if let Either::Left((Err(e), _)) = served {
+                    eprintln!("Loopback server: {}", e);
+                }
+            })?;
+        Ok(LoopbackServer {
+            connection,
+            url,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// The server connection, for registering senders and packing messages.
+    pub fn connection(&self) -> &Arc<ConnectionIp> {
+        &self.connection
+    }
+
+    /// Register a sender and the message type `T`, to pack messages of that type from it.
+    pub fn register<T: TypedMessageBody>(
+        &self,
+        sender: impl Into<SenderName>,
+    ) -> Result<LocalId<SenderId>> {
+        if let MessageTypeIdentifier::UserMessageName(name) = T::MESSAGE_IDENTIFIER {
+            self.connection.register_type(name)?;
+        }
+        self.connection.register_sender(sender.into())
+    }
+
+    /// The server name for clients to connect to, as `tcp://127.0.0.1:<port>`.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Block until a client has connected, by when our descriptions are queued for it.
+    pub fn wait_for_client(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while self.connection.status() == ConnectionStatus::Server(0) {
+            if Instant::now() > deadline {
+                return Err(VrpnError::OtherMessage(
+                    "no client connected to the loopback server".to_string(),
+                ));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "vrpn-async-std")]
+impl std::fmt::Debug for LoopbackServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("LoopbackServer")
+            .field("url", &self.url)
+            .finish()
+    }
+}
+
+#[cfg(feature = "vrpn-async-std")]
+impl Drop for LoopbackServer {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            // Fails only if the server already finished.
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::MessageHeader,
+        handler::{HandlerCode, TypedBodylessHandler},
+        ping::Ping,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug)]
+    struct Counter(Arc<AtomicUsize>);
+
+    impl TypedBodylessHandler for Counter {
+        type Item = Ping;
+        fn handle_typed_bodyless(&mut self, _header: &MessageHeader) -> Result<HandlerCode> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    #[test]
+    fn script_and_capture() {
+        let connection = MockConnection::new();
+        let sender = connection.register_sender("Tracker0").unwrap();
+        assert!(!connection.sent_messages().is_empty());
+        assert!(connection.sent_user_messages().is_empty());
+        assert!(connection.sent_typed::<Ping>().unwrap().is_empty());
+
+        let count = Arc::new(AtomicUsize::new(0));
+        connection
+            .add_typed_handler(Box::new(Counter(Arc::clone(&count))), Some(sender))
+            .unwrap();
+        let message_type = connection.register_type(crate::ping::PING_MESSAGE).unwrap();
+        let ping = TypedMessage::new(None, message_type, sender, Ping);
+        connection.script(ping.clone()).unwrap();
+        connection.script(ping.clone()).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        assert_eq!(connection.run_script().unwrap(), 2);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        connection.receive(ping).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert_eq!(connection.run_script().unwrap(), 0);
+
+        connection
+            .pack_message_body(None, sender, Ping, ClassOfService::RELIABLE)
+            .unwrap();
+        let sent = connection.sent_typed::<Ping>().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].header.sender, sender.0);
+        assert_eq!(connection.sent_user_messages().len(), 1);
+        connection.clear_sent();
+        assert!(connection.sent_messages().is_empty());
+    }
+}
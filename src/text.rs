@@ -0,0 +1,346 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Types related to the text messages any VRPN device may send,
+//! and a `TextLogger` for printing them.
+
+use crate::{
+    buffer_unbuffer::{
+        check_buffer_remaining, check_unbuffer_remaining, BufferResult, BufferSize, BufferTo,
+        BufferUnbufferError, ConstantBufferSize, UnbufferFrom, UnbufferResult,
+    },
+    clock::Instant,
+    data_types::{
+        id_types::{LocalId, SenderId},
+        MessageTypeIdentifier, SenderName, StaticMessageTypeName, TypedMessage, TypedMessageBody,
+    },
+    handler::{
+        DescriptionHandler, DescriptionHandlerHandle, HandlerCode, HandlerHandle,
+        RemoteDescription, TypedHandler,
+    },
+    Connection, Result,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Maximum length of a text message, including null terminator, matching `vrpn_MAX_TEXT_LEN`.
+pub const MAX_TEXT_LEN: usize = 1024;
+
+/// Severity of a text message, matching `vrpn_TEXT_SEVERITY`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum TextSeverity {
+    Normal,
+    Warning,
+    Error,
+}
+
+impl TextSeverity {
+    fn from_u32(v: u32) -> UnbufferResult<TextSeverity> {
+        match v {
+            0 => Ok(TextSeverity::Normal),
+            1 => Ok(TextSeverity::Warning),
+            2 => Ok(TextSeverity::Error),
+            _ => Err(BufferUnbufferError::ParseError {
+                parsing_kind: "text severity".to_string(),
+                s: v.to_string(),
+            }),
+        }
+    }
+}
+
+/// A human-readable message from a device: `vrpn_Base text_message`.
+///
+/// # Wire format
+///
+/// Severity and level as `u32`, then the null-terminated text.
+/// For example, a warning with level 0 reading "Hello", as a complete message
+/// (including header, with sender 0 and type 2, at time 1000s):
+///
+/// ```text
+#[doc = include_str!("../fixtures/wire/text.hex")]
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct TextMessage {
+    pub severity: TextSeverity,
+    pub level: u32,
+    /// The message text, without null terminator.
+    pub text: Bytes,
+}
+
+impl TypedMessageBody for TextMessage {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_Base text_message"));
+}
+
+impl BufferSize for TextMessage {
+    fn buffer_size(&self) -> usize {
+        u32::constant_buffer_size() * 2 + self.text.len() + 1
+    }
+}
+
+impl BufferTo for TextMessage {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        check_buffer_remaining(buf, self.buffer_size())?;
+        (self.severity as u32).buffer_to(buf)?;
+        self.level.buffer_to(buf)?;
+        buf.put_slice(&self.text);
+        buf.put_u8(0);
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for TextMessage {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, u32::constant_buffer_size() * 2 + 1)?;
+        let severity = TextSeverity::from_u32(u32::unbuffer_from(buf)?)?;
+        let level = u32::unbuffer_from(buf)?;
+        // The text may span chunks of a non-contiguous buffer.
+        let mut text = BytesMut::new();
+        loop {
+            let room = MAX_TEXT_LEN - text.len();
+            let chunk = &buf.chunk()[..buf.chunk().len().min(room)];
+            if chunk.is_empty() {
+                return Err(BufferUnbufferError::ParseError {
+                    parsing_kind: "text message".to_string(),
+                    s: "missing null terminator".to_string(),
+                });
+            }
+            if let Some(text_len) = chunk.iter().position(|&b| b == 0) {
+                text.extend_from_slice(&chunk[..text_len]);
+                buf.advance(text_len + 1);
+                break;
+            }
+            let len = chunk.len();
+            text.extend_from_slice(chunk);
+            buf.advance(len);
+        }
+        Ok(TextMessage {
+            severity,
+            level,
+            text: text.freeze(),
+        })
+    }
+}
+
+/// How many text messages from each sender a `TextLogger` logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextRateLimit {
+    /// Log at most this many messages from a sender...
+    pub max_messages: u32,
+    /// ...within each interval of this long: the rest are counted, and the count logged later.
+    pub per: Duration,
+}
+
+impl Default for TextRateLimit {
+    fn default() -> TextRateLimit {
+        TextRateLimit {
+            max_messages: 10,
+            per: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RateWindow {
+    start: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+/// Shared map of the names remote endpoints have described for senders.
+type SenderNames = Arc<Mutex<HashMap<LocalId<SenderId>, SenderName>>>;
+
+#[derive(Debug)]
+struct SenderNameRecorder(SenderNames);
+
+impl DescriptionHandler for SenderNameRecorder {
+    fn handle_description(&mut self, desc: &RemoteDescription) -> Result<HandlerCode> {
+        if let RemoteDescription::Sender { name, local_id, .. } = desc {
+            self.0.lock()?.insert(*local_id, name.clone());
+        }
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
+/// Logs text messages from devices, limiting how many from each sender get logged.
+///
+/// Severities map to log levels: with the `tracing` feature, messages are logged as
+/// `tracing` events (at info, warn, and error levels), otherwise printed to stderr.
+#[derive(Debug)]
+pub struct TextLogger {
+    limit: TextRateLimit,
+    names: SenderNames,
+    windows: HashMap<SenderId, RateWindow>,
+}
+
+/// Returned by `TextLogger::add_to_connection`, for removing the logger.
+#[derive(Debug)]
+pub struct TextLoggerHandle {
+    handler: HandlerHandle,
+    names: DescriptionHandlerHandle,
+}
+
+impl TextLoggerHandle {
+    pub fn remove<C: Connection>(self, connection: &C) -> Result<()> {
+        connection.remove_handler(self.handler)?;
+        connection.remove_description_handler(self.names)
+    }
+}
+
+impl TextLogger {
+    /// Create a logger that identifies senders by ID.
+    pub fn new(limit: TextRateLimit) -> TextLogger {
+        TextLogger {
+            limit,
+            names: SenderNames::default(),
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Log text messages from all senders on a connection, by name.
+    pub fn add_to_connection<C: Connection>(
+        connection: &C,
+        limit: TextRateLimit,
+    ) -> Result<TextLoggerHandle> {
+        let logger = TextLogger::new(limit);
+        let names = connection
+            .add_description_handler(Box::new(SenderNameRecorder(Arc::clone(&logger.names))))?;
+        let handler = connection.add_typed_handler(Box::new(logger), None)?;
+        Ok(TextLoggerHandle { handler, names })
+    }
+
+    /// Decide whether to log a message from a sender now.
+    ///
+    /// Returns None to suppress it, or the number of messages suppressed
+    /// since the last one logged.
+    fn admit(&mut self, sender: SenderId, now: Instant) -> Option<u64> {
+        let limit = self.limit;
+        let window = self.windows.entry(sender).or_insert(RateWindow {
+            start: now,
+            logged: 0,
+            suppressed: 0,
+        });
+        if now.saturating_duration_since(window.start) >= limit.per {
+            window.start = now;
+            window.logged = 0;
+        }
+        if window.logged >= limit.max_messages {
+            window.suppressed += 1;
+            return None;
+        }
+        window.logged += 1;
+        Some(std::mem::take(&mut window.suppressed))
+    }
+
+    fn sender_name(&self, sender: SenderId) -> Result<String> {
+        Ok(match self.names.lock()?.get(&LocalId(sender)) {
+            Some(name) => String::from_utf8_lossy(&name.0).into_owned(),
+            None => format!("sender {}", sender.0),
+        })
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn log_text(sender: &str, msg: &TextMessage, suppressed: u64) {
+    if suppressed > 0 {
+        tracing::warn!(sender, "{} text messages suppressed", suppressed);
+    }
+    let text = String::from_utf8_lossy(&msg.text);
+    match msg.severity {
+        TextSeverity::Normal => tracing::info!(sender, level = msg.level, "{}", text),
+        TextSeverity::Warning => tracing::warn!(sender, level = msg.level, "{}", text),
+        TextSeverity::Error => tracing::error!(sender, level = msg.level, "{}", text),
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+fn log_text(sender: &str, msg: &TextMessage, suppressed: u64) {
+    if suppressed > 0 {
+        eprintln!(
+            "[Warning] {}: {} text messages suppressed",
+            sender, suppressed
+        );
+    }
+    eprintln!(
+        "[{:?}] {}: {}",
+        msg.severity,
+        sender,
+        String::from_utf8_lossy(&msg.text)
+    );
+}
+
+impl TypedHandler for TextLogger {
+    type Item = TextMessage;
+    fn handle_typed(&mut self, msg: &TypedMessage<TextMessage>) -> Result<HandlerCode> {
+        let sender = msg.header.sender;
+        if let Some(suppressed) = self.admit(sender, Instant::now()) {
+            log_text(&self.sender_name(sender)?, &msg.body, suppressed);
+        }
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit() {
+        let start = Instant::now();
+        let mut logger = TextLogger::new(TextRateLimit {
+            max_messages: 2,
+            per: Duration::from_secs(1),
+        });
+        let chatty = SenderId(1);
+        let quiet = SenderId(2);
+        assert_eq!(logger.admit(chatty, start), Some(0));
+        assert_eq!(logger.admit(chatty, start), Some(0));
+        assert_eq!(logger.admit(chatty, start), None);
+        assert_eq!(logger.admit(chatty, start), None);
+        // Each sender has its own limit.
+        assert_eq!(logger.admit(quiet, start), Some(0));
+
+        // The next window reports what was suppressed.
+        let later = start + Duration::from_secs(1);
+        assert_eq!(logger.admit(chatty, later), Some(2));
+        assert_eq!(logger.admit(chatty, later), Some(0));
+
+        assert_eq!(logger.sender_name(chatty).unwrap(), "sender 1");
+        logger
+            .names
+            .lock()
+            .unwrap()
+            .insert(LocalId(chatty), SenderName::from("Tracker0"));
+        assert_eq!(logger.sender_name(chatty).unwrap(), "Tracker0");
+    }
+
+    #[test]
+    fn unterminated() {
+        let mut buf = Bytes::from_static(&hex!("00 00 00 00 00 00 00 00 41 42"));
+        assert!(TextMessage::unbuffer_from(&mut buf).is_err());
+        let mut buf = Bytes::from_static(&hex!("00 00 00 07 00 00 00 00 41 00"));
+        assert!(TextMessage::unbuffer_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn split_across_chunks() {
+        let mut buf = Bytes::from_static(&hex!("00 00 00 01 00 00 00 02 41 42"))
+            .chain(Bytes::from_static(&hex!("43 00 ff")));
+        let msg = TextMessage::unbuffer_from(&mut buf).unwrap();
+        assert_eq!(msg.severity, TextSeverity::Warning);
+        assert_eq!(msg.level, 2);
+        assert_eq!(&msg.text[..], b"ABC");
+        assert_eq!(buf.remaining(), 1);
+
+        // Still bounded by the maximum length.
+        let mut buf = Bytes::from_static(&[0; 8])
+            .chain(Bytes::from(vec![b'A'; MAX_TEXT_LEN]))
+            .chain(&[0u8][..]);
+        assert!(TextMessage::unbuffer_from(&mut buf).is_err());
+    }
+}
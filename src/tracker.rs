@@ -4,20 +4,44 @@
 
 //! Types related to the `vrpn_Tracker` device class
 
+pub mod aggregate;
+pub mod filter;
+
 use crate::{
     buffer_unbuffer::{
         buffer::{BufferResult, BufferTo},
         unbuffer::{check_unbuffer_remaining, UnbufferFrom, UnbufferResult},
-        ConstantBufferSize,
+        ConstantBufferSize, EmptyMessage,
     },
     data_types::{
-        id_types::Sensor, message::TypedMessageBody, name_types::StaticMessageTypeName,
-        MessageTypeIdentifier, Quat, Vec3,
+        id_types::{LocalId, MessageTypeId, SenderId, Sensor},
+        message::TypedMessageBody,
+        name_types::StaticMessageTypeName,
+        ClassOfService, MessageTypeIdentifier, Quat, SenderName, TypedMessage, Vec3,
     },
+    device::DeviceClass,
+    handler::{CallbackHandler, HandlerCode, HandlerHandle, TypedHandler},
+    subscription::Subscription,
+    Connection, Result, VrpnError,
 };
 use bytes::{Buf, BufMut};
+use futures::{channel::oneshot, Future};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
 
 /// Position and orientation for trackers.
+///
+/// # Wire format
+///
+/// Sensor and padding as `i32`, then position (x, y, z) and orientation (x, y, z, w) as `f64`.
+/// For example, sensor 0 at (1, 2, 3) with identity orientation,
+/// as a complete message with sender 0 and type 1 at time 1000s:
+///
+/// ```text
+#[doc = include_str!("../fixtures/wire/pose.hex")]
+/// ```
 #[derive(Clone, Debug, PartialEq)]
 pub struct PoseReport {
     /// Sensor id
@@ -90,3 +114,836 @@ impl TypedMessageBody for AccelReport {
     const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
         MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_Tracker Acceleration"));
 }
+
+const REQUEST_WORKSPACE: StaticMessageTypeName =
+    StaticMessageTypeName(b"vrpn_Tracker Request_Tracker_Workspace");
+const WORKSPACE: StaticMessageTypeName = StaticMessageTypeName(b"vrpn_Tracker Workspace");
+
+/// Request for the tracker to send a `WorkspaceReport`.
+///
+/// Has no body.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct RequestWorkspace;
+
+impl EmptyMessage for RequestWorkspace {}
+impl TypedMessageBody for RequestWorkspace {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(REQUEST_WORKSPACE);
+}
+
+/// The volume a tracker can track in: an axis-aligned box, in tracker coordinates.
+///
+/// # Wire format
+///
+/// The minimum corner then the maximum corner, each (x, y, z) as `f64`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WorkspaceReport {
+    /// Corner with the smallest coordinates
+    pub min: Vec3,
+    /// Corner with the largest coordinates
+    pub max: Vec3,
+}
+
+impl TypedMessageBody for WorkspaceReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(WORKSPACE);
+}
+
+impl ConstantBufferSize for WorkspaceReport {
+    fn constant_buffer_size() -> usize {
+        Vec3::constant_buffer_size() * 2
+    }
+}
+
+impl BufferTo for WorkspaceReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        self.min.buffer_to(buf)?;
+        self.max.buffer_to(buf)?;
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for WorkspaceReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        let min = Vec3::unbuffer_from(buf)?;
+        let max = Vec3::unbuffer_from(buf)?;
+        Ok(WorkspaceReport { min, max })
+    }
+}
+
+/// A rigid transform: rotation by `quat`, then translation by `pos`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transform {
+    pub pos: Vec3,
+    pub quat: Quat,
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::identity()
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            pos: Vec3::default(),
+            quat: Quat::identity(),
+        }
+    }
+
+    /// The transform `rhs` followed by `self`.
+    pub fn compose(&self, rhs: &Transform) -> Transform {
+        let rotated = self.quat.rotate(rhs.pos);
+        Transform {
+            pos: Vec3::new(
+                self.pos.x + rotated.x,
+                self.pos.y + rotated.y,
+                self.pos.z + rotated.z,
+            ),
+            quat: self.quat * rhs.quat,
+        }
+    }
+}
+
+impl ConstantBufferSize for Transform {
+    fn constant_buffer_size() -> usize {
+        Vec3::constant_buffer_size() + Quat::constant_buffer_size()
+    }
+}
+
+impl BufferTo for Transform {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        self.pos.buffer_to(buf)?;
+        self.quat.buffer_to(buf)?;
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for Transform {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        let pos = Vec3::unbuffer_from(buf)?;
+        let quat = Quat::unbuffer_from(buf)?;
+        Ok(Transform { pos, quat })
+    }
+}
+
+const REQUEST_TRACKER_TO_ROOM: StaticMessageTypeName =
+    StaticMessageTypeName(b"vrpn_Tracker Request_Tracker_To_Room");
+const TRACKER_TO_ROOM: StaticMessageTypeName = StaticMessageTypeName(b"vrpn_Tracker To_Room");
+const REQUEST_UNIT_TO_SENSOR: StaticMessageTypeName =
+    StaticMessageTypeName(b"vrpn_Tracker Request_Unit_To_Sensor");
+const UNIT_TO_SENSOR: StaticMessageTypeName = StaticMessageTypeName(b"vrpn_Tracker Unit_To_Sensor");
+
+/// Request for the tracker to send a `TrackerToRoomReport`.
+///
+/// Has no body.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct RequestTrackerToRoom;
+
+impl EmptyMessage for RequestTrackerToRoom {}
+impl TypedMessageBody for RequestTrackerToRoom {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(REQUEST_TRACKER_TO_ROOM);
+}
+
+/// The transform from tracker coordinates to room coordinates.
+///
+/// # Wire format
+///
+/// Position (x, y, z) and orientation (x, y, z, w) as `f64`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TrackerToRoomReport(pub Transform);
+
+impl TypedMessageBody for TrackerToRoomReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(TRACKER_TO_ROOM);
+}
+
+impl ConstantBufferSize for TrackerToRoomReport {
+    fn constant_buffer_size() -> usize {
+        Transform::constant_buffer_size()
+    }
+}
+
+impl BufferTo for TrackerToRoomReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        self.0.buffer_to(buf)
+    }
+}
+
+impl UnbufferFrom for TrackerToRoomReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        Ok(TrackerToRoomReport(Transform::unbuffer_from(buf)?))
+    }
+}
+
+/// Request for the tracker to send a `UnitToSensorReport` for each sensor.
+///
+/// Has no body.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct RequestUnitToSensor;
+
+impl EmptyMessage for RequestUnitToSensor {}
+impl TypedMessageBody for RequestUnitToSensor {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(REQUEST_UNIT_TO_SENSOR);
+}
+
+/// The transform from the coordinates of a tracked unit to those of its sensor.
+///
+/// # Wire format
+///
+/// Sensor and padding as `i32`, then position (x, y, z) and orientation (x, y, z, w) as `f64`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct UnitToSensorReport {
+    pub sensor: Sensor,
+    pub transform: Transform,
+}
+
+impl TypedMessageBody for UnitToSensorReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(UNIT_TO_SENSOR);
+}
+
+impl ConstantBufferSize for UnitToSensorReport {
+    fn constant_buffer_size() -> usize {
+        Sensor::constant_buffer_size() * 2 + Transform::constant_buffer_size()
+    }
+}
+
+impl BufferTo for UnitToSensorReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        self.sensor.buffer_to(buf)?;
+        // padding
+        self.sensor.buffer_to(buf)?;
+        self.transform.buffer_to(buf)
+    }
+}
+
+impl UnbufferFrom for UnitToSensorReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        let sensor = Sensor::unbuffer_from(buf)?;
+        let _ = Sensor::unbuffer_from(buf)?;
+        let transform = Transform::unbuffer_from(buf)?;
+        Ok(UnitToSensorReport { sensor, transform })
+    }
+}
+
+/// The latest calibration transforms reported by a tracker.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Calibration {
+    pub tracker_to_room: Option<Transform>,
+    pub unit_to_sensor: HashMap<Sensor, Transform>,
+}
+
+impl Calibration {
+    /// Transform a reported pose (of a sensor, in tracker coordinates)
+    /// to the pose of its unit in room coordinates, using whichever transforms are known.
+    pub fn apply(&self, report: &PoseReport) -> PoseReport {
+        let pose = Transform {
+            pos: report.pos,
+            quat: report.quat,
+        };
+        let pose = match self.unit_to_sensor.get(&report.sensor) {
+            Some(unit_to_sensor) => pose.compose(unit_to_sensor),
+            None => pose,
+        };
+        let pose = match &self.tracker_to_room {
+            Some(tracker_to_room) => tracker_to_room.compose(&pose),
+            None => pose,
+        };
+        PoseReport {
+            sensor: report.sensor,
+            pos: pose.pos,
+            quat: pose.quat,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CalibrationState {
+    calibration: Calibration,
+    /// Whether poses delivered through `TrackerRemote::add_pose_handler` are calibrated.
+    apply: bool,
+}
+
+#[derive(Debug)]
+struct CalibrationHandler<U> {
+    state: Weak<Mutex<CalibrationState>>,
+    update: fn(&mut Calibration, &U),
+}
+
+impl<U> TypedHandler for CalibrationHandler<U>
+where
+    U: TypedMessageBody + UnbufferFrom + std::fmt::Debug,
+{
+    type Item = U;
+    fn handle_typed(&mut self, msg: &TypedMessage<U>) -> Result<HandlerCode> {
+        match self.state.upgrade() {
+            Some(state) => {
+                (self.update)(&mut state.lock()?.calibration, &msg.body);
+                Ok(HandlerCode::ContinueProcessing)
+            }
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Passes poses on to a handler, calibrated if enabled on the `TrackerRemote`.
+#[derive(Debug)]
+struct CalibratedPoseHandler<H> {
+    state: Weak<Mutex<CalibrationState>>,
+    inner: H,
+}
+
+impl<H: TypedHandler<Item = PoseReport>> TypedHandler for CalibratedPoseHandler<H> {
+    type Item = PoseReport;
+    fn handle_typed(&mut self, msg: &TypedMessage<PoseReport>) -> Result<HandlerCode> {
+        let state = match self.state.upgrade() {
+            Some(state) => state,
+            None => return self.inner.handle_typed(msg),
+        };
+        let calibrated = {
+            let state = state.lock()?;
+            if state.apply {
+                Some(TypedMessage::from_header_and_body(
+                    msg.header.clone(),
+                    state.calibration.apply(&msg.body),
+                ))
+            } else {
+                None
+            }
+        };
+        self.inner.handle_typed(calibrated.as_ref().unwrap_or(msg))
+    }
+}
+
+type PendingWorkspaceRequests = Mutex<Vec<oneshot::Sender<WorkspaceReport>>>;
+
+#[derive(Debug)]
+struct WorkspaceHandler {
+    pending: Weak<PendingWorkspaceRequests>,
+}
+
+impl TypedHandler for WorkspaceHandler {
+    type Item = WorkspaceReport;
+    fn handle_typed(&mut self, msg: &TypedMessage<WorkspaceReport>) -> Result<HandlerCode> {
+        match self.pending.upgrade() {
+            Some(pending) => {
+                // One report answers every outstanding request.
+                for tx in pending.lock()?.drain(..) {
+                    // The requester may have given up waiting: that's fine.
+                    let _ = tx.send(msg.body);
+                }
+                Ok(HandlerCode::ContinueProcessing)
+            }
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Client-side access to requests a tracker device answers.
+///
+/// Like `vrpn_Tracker_Remote`, keeps the latest tracker-to-room and unit-to-sensor transforms
+/// the tracker reports, and can apply them to the poses it delivers.
+///
+/// Only holds a weak reference to the connection.
+#[derive(Debug)]
+pub struct TrackerRemote<T: Connection + 'static> {
+    connection: Weak<T>,
+    sender: LocalId<SenderId>,
+    request_workspace_type: LocalId<MessageTypeId>,
+    request_tracker_to_room_type: LocalId<MessageTypeId>,
+    request_unit_to_sensor_type: LocalId<MessageTypeId>,
+    pending: Arc<PendingWorkspaceRequests>,
+    calibration: Arc<Mutex<CalibrationState>>,
+    handlers: Vec<HandlerHandle>,
+}
+
+impl<T: Connection + 'static> TrackerRemote<T> {
+    pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<TrackerRemote<T>> {
+        let request_workspace_type = connection.register_type(REQUEST_WORKSPACE)?;
+        let request_tracker_to_room_type = connection.register_type(REQUEST_TRACKER_TO_ROOM)?;
+        let request_unit_to_sensor_type = connection.register_type(REQUEST_UNIT_TO_SENSOR)?;
+        let pending = Arc::new(Mutex::new(Vec::new()));
+        let calibration = Arc::new(Mutex::new(CalibrationState::default()));
+        let handlers = vec![
+            connection.add_typed_handler(
+                Box::new(WorkspaceHandler {
+                    pending: Arc::downgrade(&pending),
+                }),
+                Some(sender),
+            )?,
+            connection.add_typed_handler(
+                Box::new(CalibrationHandler {
+                    state: Arc::downgrade(&calibration),
+                    update: |calibration, report: &TrackerToRoomReport| {
+                        calibration.tracker_to_room = Some(report.0)
+                    },
+                }),
+                Some(sender),
+            )?,
+            connection.add_typed_handler(
+                Box::new(CalibrationHandler {
+                    state: Arc::downgrade(&calibration),
+                    update: |calibration, report: &UnitToSensorReport| {
+                        calibration
+                            .unit_to_sensor
+                            .insert(report.sensor, report.transform);
+                    },
+                }),
+                Some(sender),
+            )?,
+        ];
+        Ok(TrackerRemote {
+            connection: Arc::downgrade(&connection),
+            sender,
+            request_workspace_type,
+            request_tracker_to_room_type,
+            request_unit_to_sensor_type,
+            pending,
+            calibration,
+            handlers,
+        })
+    }
+
+    pub fn new_from_name(
+        sender: impl Into<SenderName>,
+        connection: Arc<T>,
+    ) -> Result<TrackerRemote<T>> {
+        let sender_id = connection.register_sender(sender)?;
+        Self::new(sender_id, connection)
+    }
+
+    /// Ask the tracker for its workspace.
+    ///
+    /// The request is sent immediately; the returned future resolves once the reply arrives.
+    /// The connection must keep being driven (polled) meanwhile, or the reply will never come.
+    /// Fails with `VrpnError::Cancelled` if this remote is shut down or dropped first.
+    pub fn request_workspace(
+        &self,
+    ) -> Result<impl Future<Output = Result<WorkspaceReport>> + Send + 'static> {
+        let connection = self.connection.upgrade().ok_or(VrpnError::EndpointClosed)?;
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock()?.push(tx);
+        connection.pack_message(
+            TypedMessage::new(
+                Some(connection.message_clock().time_of_day()),
+                self.request_workspace_type,
+                self.sender,
+                RequestWorkspace,
+            ),
+            ClassOfService::RELIABLE,
+        )?;
+        Ok(async move {
+            // Only cancelled if this remote was shut down or dropped.
+            rx.await.map_err(|_| VrpnError::Cancelled)
+        })
+    }
+
+    /// Ask the tracker for its tracker-to-room transform.
+    ///
+    /// The reply updates `calibration()` once it arrives, while the connection is driven.
+    pub fn request_tracker_to_room(&self) -> Result<()> {
+        self.send_request(self.request_tracker_to_room_type, RequestTrackerToRoom)
+    }
+
+    /// Ask the tracker for the unit-to-sensor transform of each of its sensors.
+    ///
+    /// The replies update `calibration()` once they arrive, while the connection is driven.
+    pub fn request_unit_to_sensor(&self) -> Result<()> {
+        self.send_request(self.request_unit_to_sensor_type, RequestUnitToSensor)
+    }
+
+    /// The latest calibration transforms the tracker has reported.
+    pub fn calibration(&self) -> Result<Calibration> {
+        Ok(self.calibration.lock()?.calibration.clone())
+    }
+
+    /// The latest tracker-to-room transform the tracker has reported.
+    pub fn tracker_to_room(&self) -> Result<Option<Transform>> {
+        Ok(self.calibration.lock()?.calibration.tracker_to_room)
+    }
+
+    /// The latest unit-to-sensor transform the tracker has reported for a sensor.
+    pub fn unit_to_sensor(&self, sensor: Sensor) -> Result<Option<Transform>> {
+        Ok(self
+            .calibration
+            .lock()?
+            .calibration
+            .unit_to_sensor
+            .get(&sensor)
+            .copied())
+    }
+
+    /// Set whether poses delivered through `add_pose_handler` have the calibration applied,
+    /// becoming the pose of the unit in room coordinates. Off by default.
+    pub fn set_apply_calibration(&self, apply: bool) -> Result<()> {
+        self.calibration.lock()?.apply = apply;
+        Ok(())
+    }
+
+    /// Deliver this tracker's poses to a handler, calibrated if enabled by `set_apply_calibration`.
+    ///
+    /// The handler stays registered until removed with the returned handle.
+    pub fn add_pose_handler<H>(&self, handler: H) -> Result<HandlerHandle>
+    where
+        H: TypedHandler<Item = PoseReport> + Send + 'static,
+    {
+        let connection = self.connection.upgrade().ok_or(VrpnError::EndpointClosed)?;
+        connection.add_typed_handler(
+            Box::new(CalibratedPoseHandler {
+                state: Arc::downgrade(&self.calibration),
+                inner: handler,
+            }),
+            Some(self.sender),
+        )
+    }
+
+    fn send_request<B>(&self, message_type: LocalId<MessageTypeId>, body: B) -> Result<()>
+    where
+        B: TypedMessageBody + BufferTo,
+    {
+        let connection = self.connection.upgrade().ok_or(VrpnError::EndpointClosed)?;
+        connection.pack_message(
+            TypedMessage::new(
+                Some(connection.message_clock().time_of_day()),
+                message_type,
+                self.sender,
+                body,
+            ),
+            ClassOfService::RELIABLE,
+        )
+    }
+
+    /// Stop listening for replies: outstanding requests fail.
+    pub fn shutdown(self) -> Result<()> {
+        if let Some(connection) = self.connection.upgrade() {
+            for handler in self.handlers {
+                connection.remove_handler(handler)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `vrpn_Tracker` device class, for `DeviceExt::device`.
+#[derive(Debug, Clone, Copy)]
+pub struct Tracker;
+
+impl<C: Connection + 'static> DeviceClass<C> for Tracker {
+    type Proxy = TrackerProxy<C>;
+    fn proxy(
+        connection: &Arc<C>,
+        name: SenderName,
+        sender: LocalId<SenderId>,
+    ) -> Result<TrackerProxy<C>> {
+        Ok(TrackerProxy {
+            connection: Arc::downgrade(connection),
+            name,
+            remote: TrackerRemote::new(sender, Arc::clone(connection))?,
+        })
+    }
+}
+
+/// A tracker device on a connection: see `DeviceExt::device`.
+///
+/// Requests and calibration are those of the `TrackerRemote` it wraps.
+#[derive(Debug)]
+pub struct TrackerProxy<C: Connection + 'static> {
+    connection: Weak<C>,
+    name: SenderName,
+    remote: TrackerRemote<C>,
+}
+
+impl<C: Connection + 'static> TrackerProxy<C> {
+    /// The local ID of the tracker's sender.
+    pub fn sender(&self) -> LocalId<SenderId> {
+        self.remote.sender
+    }
+
+    /// The underlying remote, for calibration.
+    pub fn remote(&self) -> &TrackerRemote<C> {
+        &self.remote
+    }
+
+    /// Call `callback` with each pose, calibrated if enabled on the remote,
+    /// until removed with the returned handle.
+    pub fn on_pose<F>(&self, callback: F) -> Result<HandlerHandle>
+    where
+        F: FnMut(&TypedMessage<PoseReport>) + Send + Sync + 'static,
+    {
+        self.remote.add_pose_handler(CallbackHandler::new(callback))
+    }
+
+    /// A stream of the (uncalibrated) poses, with lifecycle events.
+    pub fn poses(&self) -> Result<Subscription<PoseReport>> {
+        Subscription::new(&self.connection()?, self.name.clone())
+    }
+
+    /// Ask the tracker for its workspace: see `TrackerRemote::request_workspace`.
+    pub fn request_workspace(
+        &self,
+    ) -> Result<impl Future<Output = Result<WorkspaceReport>> + Send + 'static> {
+        self.remote.request_workspace()
+    }
+
+    /// Stop listening for replies to requests.
+    ///
+    /// Callbacks stay registered until removed with their handles.
+    pub fn shutdown(self) -> Result<()> {
+        self.remote.shutdown()
+    }
+
+    fn connection(&self) -> Result<Arc<C>> {
+        self.connection.upgrade().ok_or(VrpnError::EndpointClosed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{buffer_unbuffer::BufferTo, data_types::id_types::IntoId, testing::MockConnection};
+    use bytes::BytesMut;
+    use futures::executor::block_on;
+
+    #[test]
+    fn workspace_wire_format() {
+        let report = WorkspaceReport {
+            min: Vec3::new(-1.0, -2.0, 0.0),
+            max: Vec3::new(1.0, 2.0, 3.0),
+        };
+        let mut buf = BytesMut::new();
+        report.buffer_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), 48);
+        assert_eq!(&buf[40..], &3.0f64.to_be_bytes());
+        let mut buf = buf.freeze();
+        assert_eq!(WorkspaceReport::unbuffer_from(&mut buf).unwrap(), report);
+    }
+
+    #[test]
+    fn request_workspace() {
+        let connection = MockConnection::new();
+        let remote = TrackerRemote::new_from_name("Tracker0", Arc::clone(&connection)).unwrap();
+        let reply = remote.request_workspace().unwrap();
+        let second_reply = remote.request_workspace().unwrap();
+
+        let sent = connection.sent_user_messages();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(
+            sent[0].header.message_type,
+            remote.request_workspace_type.into_id()
+        );
+        assert!(sent[0].body.clone().into_inner().is_empty());
+
+        let report = WorkspaceReport {
+            min: Vec3::new(-1.0, -1.0, 0.0),
+            max: Vec3::new(1.0, 1.0, 2.0),
+        };
+        let workspace_type = connection.register_type(WORKSPACE).unwrap();
+        connection
+            .receive(TypedMessage::new(
+                None,
+                workspace_type,
+                remote.sender,
+                report,
+            ))
+            .unwrap();
+        assert_eq!(block_on(reply).unwrap(), report);
+        assert_eq!(block_on(second_reply).unwrap(), report);
+
+        let abandoned = remote.request_workspace().unwrap();
+        remote.shutdown().unwrap();
+        assert!(matches!(block_on(abandoned), Err(VrpnError::Cancelled)));
+    }
+
+    fn about_z(angle: f64) -> Quat {
+        Quat::new((angle / 2.0).cos(), 0.0, 0.0, (angle / 2.0).sin())
+    }
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!(
+            (a.x - b.x).abs() < 1e-12 && (a.y - b.y).abs() < 1e-12 && (a.z - b.z).abs() < 1e-12,
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn calibration_wire_format() {
+        let report = UnitToSensorReport {
+            sensor: Sensor(2),
+            transform: Transform {
+                pos: Vec3::new(1.0, 2.0, 3.0),
+                quat: Quat::identity(),
+            },
+        };
+        let mut buf = BytesMut::new();
+        report.buffer_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), 64);
+        assert_eq!(&buf[..8], &[0, 0, 0, 2, 0, 0, 0, 2]);
+        let mut buf = buf.freeze();
+        assert_eq!(UnitToSensorReport::unbuffer_from(&mut buf).unwrap(), report);
+
+        let report = TrackerToRoomReport(report.transform);
+        let mut buf = BytesMut::new();
+        report.buffer_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), 56);
+        let mut buf = buf.freeze();
+        assert_eq!(
+            TrackerToRoomReport::unbuffer_from(&mut buf).unwrap(),
+            report
+        );
+    }
+
+    #[derive(Debug)]
+    struct LastPose(Arc<Mutex<Option<PoseReport>>>);
+    impl TypedHandler for LastPose {
+        type Item = PoseReport;
+        fn handle_typed(&mut self, msg: &TypedMessage<PoseReport>) -> Result<HandlerCode> {
+            *self.0.lock()? = Some(msg.body.clone());
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    #[test]
+    fn calibration() {
+        let connection = MockConnection::new();
+        let remote = TrackerRemote::new_from_name("Tracker0", Arc::clone(&connection)).unwrap();
+        remote.request_tracker_to_room().unwrap();
+        remote.request_unit_to_sensor().unwrap();
+        let sent = connection.sent_user_messages();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(
+            sent[1].header.message_type,
+            remote.request_unit_to_sensor_type.into_id()
+        );
+        assert_eq!(remote.tracker_to_room().unwrap(), None);
+
+        // Room is the tracker turned a quarter about z, and raised by 1.
+        let tracker_to_room = Transform {
+            pos: Vec3::new(0.0, 0.0, 1.0),
+            quat: about_z(std::f64::consts::FRAC_PI_2),
+        };
+        // The unit is 1 along the sensor's x axis.
+        let unit_to_sensor = Transform {
+            pos: Vec3::new(1.0, 0.0, 0.0),
+            quat: Quat::identity(),
+        };
+        let to_room_type = connection.register_type(TRACKER_TO_ROOM).unwrap();
+        let to_sensor_type = connection.register_type(UNIT_TO_SENSOR).unwrap();
+        connection
+            .receive(TypedMessage::new(
+                None,
+                to_room_type,
+                remote.sender,
+                TrackerToRoomReport(tracker_to_room),
+            ))
+            .unwrap();
+        connection
+            .receive(TypedMessage::new(
+                None,
+                to_sensor_type,
+                remote.sender,
+                UnitToSensorReport {
+                    sensor: Sensor(0),
+                    transform: unit_to_sensor,
+                },
+            ))
+            .unwrap();
+        assert_eq!(remote.tracker_to_room().unwrap(), Some(tracker_to_room));
+        assert_eq!(
+            remote.unit_to_sensor(Sensor(0)).unwrap(),
+            Some(unit_to_sensor)
+        );
+        assert_eq!(remote.unit_to_sensor(Sensor(1)).unwrap(), None);
+
+        let last = Arc::new(Mutex::new(None));
+        remote
+            .add_pose_handler(LastPose(Arc::clone(&last)))
+            .unwrap();
+        let pose_type = connection
+            .register_type(StaticMessageTypeName(b"vrpn_Tracker Pos_Quat"))
+            .unwrap();
+        let pose = PoseReport {
+            sensor: Sensor(0),
+            pos: Vec3::new(0.0, 2.0, 0.0),
+            quat: Quat::identity(),
+        };
+        let receive = || {
+            connection
+                .receive(TypedMessage::new(
+                    None,
+                    pose_type,
+                    remote.sender,
+                    pose.clone(),
+                ))
+                .unwrap();
+            last.lock().unwrap().take().unwrap()
+        };
+        assert_eq!(receive(), pose);
+
+        remote.set_apply_calibration(true).unwrap();
+        let calibrated = receive();
+        // (1, 2, 0) in tracker coordinates, turned and raised.
+        assert_near(calibrated.pos, Vec3::new(-2.0, 1.0, 1.0));
+        assert_eq!(calibrated.quat, tracker_to_room.quat);
+        assert_eq!(remote.calibration().unwrap().apply(&pose), calibrated);
+        remote.shutdown().unwrap();
+    }
+
+    #[test]
+    fn device_proxy() {
+        use crate::{device::DeviceExt, subscription::SubscriptionEvent};
+        use futures::StreamExt;
+
+        let connection = MockConnection::new();
+        let tracker = connection.device::<Tracker>("Tracker0").unwrap();
+        assert_eq!(
+            tracker.sender(),
+            connection.register_sender("Tracker0").unwrap()
+        );
+        let last = Arc::new(Mutex::new(None));
+        {
+            let last = Arc::clone(&last);
+            tracker
+                .on_pose(move |msg| *last.lock().unwrap() = Some(msg.body.clone()))
+                .unwrap();
+        }
+        let mut poses = tracker.poses().unwrap();
+
+        let pose = PoseReport {
+            sensor: Sensor(1),
+            pos: Vec3::new(1.0, 2.0, 3.0),
+            quat: Quat::identity(),
+        };
+        let pose_type = connection
+            .register_type(StaticMessageTypeName(b"vrpn_Tracker Pos_Quat"))
+            .unwrap();
+        connection
+            .receive(TypedMessage::new(
+                None,
+                pose_type,
+                tracker.sender(),
+                pose.clone(),
+            ))
+            .unwrap();
+        assert_eq!(last.lock().unwrap().take(), Some(pose.clone()));
+        match block_on(poses.next()) {
+            Some(SubscriptionEvent::Message(msg)) => assert_eq!(msg.body, pose),
+            other => panic!("expected a pose, got {:?}", other),
+        }
+
+        let _workspace = tracker.request_workspace().unwrap();
+        assert_eq!(
+            connection.sent_user_messages()[0].header.message_type,
+            tracker.remote().request_workspace_type.into_id()
+        );
+        tracker.shutdown().unwrap();
+    }
+}
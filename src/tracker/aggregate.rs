@@ -0,0 +1,279 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Merging poses from many trackers, possibly on different connections, into one stream.
+//!
+//! This is the "motion capture aggregator" case: subscribe to each tracker with a label
+//! using `PoseAggregator::add_tracker`, drive the connections as usual,
+//! then either drain every report in timestamp order or take time-aligned frames
+//! holding the latest pose of each labeled sensor.
+
+use super::PoseReport;
+use crate::{
+    data_types::{id_types::Sensor, SenderName, TimeVal, TypedMessage},
+    handler::{HandlerCode, TypedHandler},
+    Connection, Result,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+/// A pose report, labeled with the tracker it came from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabeledPose {
+    /// The label given when subscribing to the tracker.
+    pub label: Arc<str>,
+    /// Timestamp of the report, from the server.
+    pub time: TimeVal,
+    pub report: PoseReport,
+}
+
+/// The latest pose of each labeled sensor, as of some time.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AlignedFrame {
+    pub time: TimeVal,
+    /// Keyed by tracker label and sensor.
+    ///
+    /// Each pose is the most recent one with a timestamp no later than `time`:
+    /// check `LabeledPose::time` to see how stale it is.
+    pub poses: BTreeMap<(Arc<str>, Sensor), LabeledPose>,
+}
+
+type SharedQueue = Arc<Mutex<VecDeque<LabeledPose>>>;
+type Unsubscribe = Box<dyn FnOnce() -> Result<()> + Send>;
+
+#[derive(Debug)]
+struct AggregatingHandler {
+    label: Arc<str>,
+    queue: SharedQueue,
+}
+
+impl TypedHandler for AggregatingHandler {
+    type Item = PoseReport;
+    fn handle_typed(&mut self, msg: &TypedMessage<PoseReport>) -> Result<HandlerCode> {
+        self.queue.lock()?.push_back(LabeledPose {
+            label: Arc::clone(&self.label),
+            time: msg.header.time,
+            report: msg.body.clone(),
+        });
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
+/// Collects pose reports from any number of trackers on any number of connections.
+pub struct PoseAggregator {
+    queue: SharedQueue,
+    /// Received, but newer than the last frame taken.
+    pending: Vec<LabeledPose>,
+    latest: BTreeMap<(Arc<str>, Sensor), LabeledPose>,
+    subscriptions: Vec<Unsubscribe>,
+}
+
+impl Default for PoseAggregator {
+    fn default() -> PoseAggregator {
+        PoseAggregator::new()
+    }
+}
+
+impl PoseAggregator {
+    pub fn new() -> PoseAggregator {
+        PoseAggregator {
+            queue: Arc::default(),
+            pending: Vec::new(),
+            latest: BTreeMap::new(),
+            subscriptions: Vec::new(),
+        }
+    }
+
+    /// Subscribe to pose reports from a tracker, labeling them with the given label.
+    ///
+    /// Labels should be unique: sensors of trackers sharing a label are merged.
+    pub fn add_tracker<C: Connection + 'static>(
+        &mut self,
+        label: impl Into<String>,
+        connection: &Arc<C>,
+        sender: impl Into<SenderName>,
+    ) -> Result<()> {
+        let sender = connection.register_sender(sender.into())?;
+        let handle = connection.add_typed_handler(
+            Box::new(AggregatingHandler {
+                label: Arc::from(label.into()),
+                queue: Arc::clone(&self.queue),
+            }),
+            Some(sender),
+        )?;
+        let connection = Arc::clone(connection);
+        self.subscriptions
+            .push(Box::new(move || connection.remove_handler(handle)));
+        Ok(())
+    }
+
+    /// Number of trackers subscribed to.
+    pub fn len(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    /// True if not subscribed to any trackers.
+    pub fn is_empty(&self) -> bool {
+        self.subscriptions.is_empty()
+    }
+
+    /// Move newly received reports into `pending`, keeping it sorted by time.
+    fn collect(&mut self) -> Result<()> {
+        self.pending.extend(self.queue.lock()?.drain(..));
+        // Stable, so reports with equal timestamps stay in arrival order.
+        self.pending.sort_by_key(|pose| pose.time);
+        Ok(())
+    }
+
+    /// Take all reports received so far, merged in timestamp order.
+    ///
+    /// Also updates the latest poses used for frames.
+    pub fn drain(&mut self) -> Result<Vec<LabeledPose>> {
+        self.collect()?;
+        let poses = std::mem::take(&mut self.pending);
+        for pose in &poses {
+            self.latest
+                .insert((Arc::clone(&pose.label), pose.report.sensor), pose.clone());
+        }
+        Ok(poses)
+    }
+
+    /// Get the latest pose of each labeled sensor as of the given time.
+    ///
+    /// Reports timestamped after `time` are kept for a later frame,
+    /// so frames can be taken slightly in the past to let slower servers catch up.
+    pub fn frame_at(&mut self, time: TimeVal) -> Result<AlignedFrame> {
+        self.collect()?;
+        let split = self.pending.partition_point(|pose| pose.time <= time);
+        for pose in self.pending.drain(..split) {
+            self.latest
+                .insert((Arc::clone(&pose.label), pose.report.sensor), pose);
+        }
+        Ok(AlignedFrame {
+            time,
+            poses: self.latest.clone(),
+        })
+    }
+
+    /// Unsubscribe from all trackers.
+    pub fn shutdown(self) -> Result<()> {
+        for unsubscribe in self.subscriptions {
+            unsubscribe()?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for PoseAggregator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PoseAggregator")
+            .field("pending", &self.pending.len())
+            .field("latest", &self.latest.len())
+            .field("subscriptions", &self.subscriptions.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{Microseconds, Quat, Seconds, StaticMessageTypeName, Vec3},
+        testing::MockConnection,
+    };
+
+    fn report(sensor: i32, x: f64) -> PoseReport {
+        PoseReport {
+            sensor: Sensor(sensor),
+            pos: Vec3::new(x, 0.0, 0.0),
+            quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+        }
+    }
+
+    fn at(sec: i32) -> TimeVal {
+        TimeVal::new(Seconds(sec), Microseconds(0))
+    }
+
+    fn send(connection: &MockConnection, sender: &str, time: i32, report: PoseReport) {
+        let sender = connection.register_sender(sender).unwrap();
+        let message_type = connection
+            .register_type(StaticMessageTypeName(b"vrpn_Tracker Pos_Quat"))
+            .unwrap();
+        connection
+            .receive(TypedMessage::new(
+                Some(at(time)),
+                message_type,
+                sender,
+                report,
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn merges_across_connections() {
+        let a = MockConnection::new();
+        let b = MockConnection::new();
+        let mut aggregator = PoseAggregator::new();
+        aggregator.add_tracker("head", &a, "Tracker0").unwrap();
+        aggregator.add_tracker("hands", &b, "Tracker0").unwrap();
+        aggregator.add_tracker("feet", &b, "Tracker1").unwrap();
+        assert_eq!(aggregator.len(), 3);
+
+        send(&a, "Tracker0", 3, report(0, 3.0));
+        send(&b, "Tracker0", 1, report(0, 1.0));
+        send(&b, "Tracker0", 2, report(1, 2.0));
+        send(&b, "Tracker1", 5, report(0, 5.0));
+        send(&b, "Unsubscribed", 4, report(0, 4.0));
+
+        let poses = aggregator.drain().unwrap();
+        let summary: Vec<_> = poses
+            .iter()
+            .map(|pose| (&*pose.label, pose.report.sensor.0, pose.report.pos.x))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("hands", 0, 1.0),
+                ("hands", 1, 2.0),
+                ("head", 0, 3.0),
+                ("feet", 0, 5.0)
+            ]
+        );
+
+        aggregator.shutdown().unwrap();
+        send(&a, "Tracker0", 6, report(0, 6.0));
+    }
+
+    #[test]
+    fn time_aligned_frames() {
+        let connection = MockConnection::new();
+        let mut aggregator = PoseAggregator::new();
+        aggregator
+            .add_tracker("rigid", &connection, "Tracker0")
+            .unwrap();
+
+        send(&connection, "Tracker0", 1, report(0, 1.0));
+        send(&connection, "Tracker0", 3, report(0, 3.0));
+        send(&connection, "Tracker0", 2, report(1, 2.0));
+
+        let frame = aggregator.frame_at(at(2)).unwrap();
+        assert_eq!(frame.poses.len(), 2);
+        let rigid: Arc<str> = Arc::from("rigid");
+        assert_eq!(
+            frame.poses[&(Arc::clone(&rigid), Sensor(0))].report.pos.x,
+            1.0
+        );
+        assert_eq!(
+            frame.poses[&(Arc::clone(&rigid), Sensor(1))].report.pos.x,
+            2.0
+        );
+
+        // The newer report was held for a later frame.
+        let frame = aggregator.frame_at(at(10)).unwrap();
+        assert_eq!(frame.poses[&(rigid, Sensor(0))].report.pos.x, 3.0);
+        assert!(aggregator.drain().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,445 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Smoothing filters for tracker poses, e.g. to remove jitter before rendering.
+//!
+//! Filters keep state per sensor (`PerSensorFilter`), and can be applied
+//! to a handler (`FilteredPoseHandler`) or a stream of reports (`filter_pose_stream`).
+
+use super::PoseReport;
+use crate::{
+    data_types::{id_types::Sensor, Quat, TimeVal, TypedMessage, Vec3},
+    handler::{HandlerCode, TypedHandler},
+    Result,
+};
+use futures::{Stream, StreamExt};
+use std::{collections::HashMap, f64::consts::PI};
+
+/// Time step assumed before the first one is known, or when timestamps don't advance.
+const FALLBACK_DT: f64 = 1.0 / 60.0;
+
+/// A stateful filter for a single sensor's pose.
+pub trait PoseFilter: Send + Sync {
+    /// Filter a pose measured at the given time (in seconds, with any epoch).
+    fn filter(&mut self, time: f64, pos: Vec3, quat: Quat) -> (Vec3, Quat);
+
+    /// Forget all state, e.g. after tracking was lost.
+    fn reset(&mut self);
+}
+
+fn lerp(a: Vec3, b: Vec3, alpha: f64) -> Vec3 {
+    Vec3::new(
+        a.x + (b.x - a.x) * alpha,
+        a.y + (b.y - a.y) * alpha,
+        a.z + (b.z - a.z) * alpha,
+    )
+}
+
+fn distance(a: Vec3, b: Vec3) -> f64 {
+    ((b.x - a.x).powi(2) + (b.y - a.y).powi(2) + (b.z - a.z).powi(2)).sqrt()
+}
+
+/// Normalized linear interpolation, taking the shorter way around.
+fn nlerp(a: Quat, b: Quat, alpha: f64) -> Quat {
+    let sign = if a.dot(&b) < 0.0 { -1.0 } else { 1.0 };
+    let s = a.s + (sign * b.s - a.s) * alpha;
+    let v = lerp(
+        a.v,
+        Vec3::new(sign * b.v.x, sign * b.v.y, sign * b.v.z),
+        alpha,
+    );
+    Quat::from_sv(s, v).normalize().unwrap_or(b)
+}
+
+/// Angle of the rotation between two unit quaternions, in radians.
+fn angle_between(a: Quat, b: Quat) -> f64 {
+    2.0 * a.dot(&b).abs().min(1.0).acos()
+}
+
+fn time_to_seconds(time: TimeVal) -> f64 {
+    f64::from(time.seconds().0) + f64::from(time.microseconds().0) * 1.0e-6
+}
+
+/// Simple exponential smoothing: each output moves a fixed fraction toward the input.
+///
+/// Cheap and predictable, but lags behind fast motion as much as it smooths jitter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExponentialSmoothing {
+    /// Fraction of the way to move toward each new sample: 1 means no smoothing.
+    pub alpha: f64,
+    state: Option<(Vec3, Quat)>,
+}
+
+impl ExponentialSmoothing {
+    pub fn new(alpha: f64) -> ExponentialSmoothing {
+        ExponentialSmoothing {
+            alpha: alpha.clamp(0.0, 1.0),
+            state: None,
+        }
+    }
+}
+
+impl PoseFilter for ExponentialSmoothing {
+    fn filter(&mut self, _time: f64, pos: Vec3, quat: Quat) -> (Vec3, Quat) {
+        let out = match self.state {
+            Some((prev_pos, prev_quat)) => (
+                lerp(prev_pos, pos, self.alpha),
+                nlerp(prev_quat, quat, self.alpha),
+            ),
+            None => (pos, quat),
+        };
+        self.state = Some(out);
+        out
+    }
+
+    fn reset(&mut self) {
+        self.state = None;
+    }
+}
+
+/// Smoothing factor of a first-order low-pass filter with the given cutoff frequency.
+fn low_pass_alpha(cutoff: f64, dt: f64) -> f64 {
+    let tau = 1.0 / (2.0 * PI * cutoff);
+    1.0 / (1.0 + tau / dt)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct OneEuroState {
+    time: f64,
+    dt: f64,
+    raw_pos: Vec3,
+    raw_quat: Quat,
+    pos: Vec3,
+    quat: Quat,
+    speed: f64,
+    angular_speed: f64,
+}
+
+/// The "1€ filter" (Casiez et al., CHI 2012): smooths heavily when still,
+/// and less as speed increases, trading jitter for lag only when it isn't noticeable.
+///
+/// Position and orientation are filtered separately, based on linear and angular speed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OneEuroFilter {
+    /// Cutoff frequency (Hz) when still: lower means less jitter.
+    pub min_cutoff: f64,
+    /// How much the cutoff rises with speed: higher means less lag.
+    pub beta: f64,
+    /// Cutoff frequency (Hz) for the speed estimate.
+    pub derivative_cutoff: f64,
+    state: Option<OneEuroState>,
+}
+
+impl OneEuroFilter {
+    pub fn new(min_cutoff: f64, beta: f64) -> OneEuroFilter {
+        OneEuroFilter {
+            min_cutoff,
+            beta,
+            derivative_cutoff: 1.0,
+            state: None,
+        }
+    }
+}
+
+impl PoseFilter for OneEuroFilter {
+    fn filter(&mut self, time: f64, pos: Vec3, quat: Quat) -> (Vec3, Quat) {
+        let state = match &mut self.state {
+            Some(state) => state,
+            None => {
+                self.state = Some(OneEuroState {
+                    time,
+                    dt: FALLBACK_DT,
+                    raw_pos: pos,
+                    raw_quat: quat,
+                    pos,
+                    quat,
+                    speed: 0.0,
+                    angular_speed: 0.0,
+                });
+                return (pos, quat);
+            }
+        };
+        if time > state.time {
+            state.dt = time - state.time;
+        }
+        state.time = time;
+        let dt = state.dt;
+
+        let d_alpha = low_pass_alpha(self.derivative_cutoff, dt);
+        let speed = distance(state.raw_pos, pos) / dt;
+        state.speed += (speed - state.speed) * d_alpha;
+        let angular_speed = angle_between(state.raw_quat, quat) / dt;
+        state.angular_speed += (angular_speed - state.angular_speed) * d_alpha;
+
+        let pos_alpha = low_pass_alpha(self.min_cutoff + self.beta * state.speed, dt);
+        let quat_alpha = low_pass_alpha(self.min_cutoff + self.beta * state.angular_speed, dt);
+        state.pos = lerp(state.pos, pos, pos_alpha);
+        state.quat = nlerp(state.quat, quat, quat_alpha);
+        state.raw_pos = pos;
+        state.raw_quat = quat;
+        (state.pos, state.quat)
+    }
+
+    fn reset(&mut self) {
+        self.state = None;
+    }
+}
+
+/// Applies a separate instance of a filter to each sensor.
+///
+/// Sensors use a copy of the default filter, unless one was given for them specifically.
+#[derive(Clone, Debug)]
+pub struct PerSensorFilter<F: PoseFilter + Clone> {
+    default: F,
+    parameters: HashMap<Sensor, F>,
+    active: HashMap<Sensor, F>,
+}
+
+impl<F: PoseFilter + Clone> PerSensorFilter<F> {
+    pub fn new(default: F) -> PerSensorFilter<F> {
+        PerSensorFilter {
+            default,
+            parameters: HashMap::new(),
+            active: HashMap::new(),
+        }
+    }
+
+    /// Use a differently-configured filter for one sensor.
+    pub fn with_sensor(mut self, sensor: Sensor, filter: F) -> PerSensorFilter<F> {
+        self.active.remove(&sensor);
+        self.parameters.insert(sensor, filter);
+        self
+    }
+
+    /// Filter a report, based on the previous ones from the same sensor.
+    pub fn filter_report(&mut self, time: TimeVal, report: &PoseReport) -> PoseReport {
+        let default = &self.default;
+        let parameters = &self.parameters;
+        let filter = self
+            .active
+            .entry(report.sensor)
+            .or_insert_with(|| parameters.get(&report.sensor).unwrap_or(default).clone());
+        let (pos, quat) = filter.filter(time_to_seconds(time), report.pos, report.quat);
+        PoseReport {
+            sensor: report.sensor,
+            pos,
+            quat,
+        }
+    }
+
+    /// Filter a report message, keeping its header.
+    pub fn filter_message(&mut self, msg: &TypedMessage<PoseReport>) -> TypedMessage<PoseReport> {
+        let body = self.filter_report(msg.header.time, &msg.body);
+        TypedMessage::from_header_and_body(msg.header.clone(), body)
+    }
+
+    /// Forget the state of all sensors.
+    pub fn reset(&mut self) {
+        self.active.clear();
+    }
+}
+
+/// Wraps a pose handler, so it receives filtered reports.
+#[derive(Debug)]
+pub struct FilteredPoseHandler<H, F: PoseFilter + Clone> {
+    inner: H,
+    filters: PerSensorFilter<F>,
+}
+
+impl<H, F> FilteredPoseHandler<H, F>
+where
+    H: TypedHandler<Item = PoseReport>,
+    F: PoseFilter + Clone,
+{
+    pub fn new(inner: H, filters: PerSensorFilter<F>) -> FilteredPoseHandler<H, F> {
+        FilteredPoseHandler { inner, filters }
+    }
+}
+
+impl<H, F> TypedHandler for FilteredPoseHandler<H, F>
+where
+    H: TypedHandler<Item = PoseReport>,
+    F: PoseFilter + Clone,
+{
+    type Item = PoseReport;
+    fn handle_typed(&mut self, msg: &TypedMessage<PoseReport>) -> Result<HandlerCode> {
+        let filtered = self.filters.filter_message(msg);
+        self.inner.handle_typed(&filtered)
+    }
+}
+
+/// Adapt a stream of pose reports to yield filtered reports.
+pub fn filter_pose_stream<S, F>(
+    stream: S,
+    mut filters: PerSensorFilter<F>,
+) -> impl Stream<Item = TypedMessage<PoseReport>>
+where
+    S: Stream<Item = TypedMessage<PoseReport>>,
+    F: PoseFilter + Clone,
+{
+    stream.map(move |msg| filters.filter_message(&msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{
+        id_types::{LocalId, MessageTypeId, SenderId},
+        Microseconds, Seconds,
+    };
+    use futures::{executor::block_on, stream};
+
+    const RATE: f64 = 100.0;
+
+    /// Deterministic noise in [-amplitude, amplitude].
+    struct Noise(u64);
+    impl Noise {
+        fn next(&mut self, amplitude: f64) -> f64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((self.0 >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0) * amplitude
+        }
+    }
+
+    fn time_at(i: usize) -> TimeVal {
+        let usec = (i as f64 / RATE * 1.0e6) as i64;
+        TimeVal::new(
+            Seconds(1000 + (usec / 1_000_000) as i32),
+            Microseconds((usec % 1_000_000) as i32),
+        )
+    }
+
+    fn rotation_about_z(angle: f64) -> Quat {
+        Quat::new((angle / 2.0).cos(), 0.0, 0.0, (angle / 2.0).sin())
+    }
+
+    /// Run a filter over a trajectory sampled at RATE, returning the mean position error
+    /// of the last half of the samples (after settling).
+    fn mean_error<F: PoseFilter + Clone>(
+        filter: F,
+        truth: impl Fn(f64) -> Vec3,
+        noise_amplitude: f64,
+    ) -> (f64, f64) {
+        let mut filters = PerSensorFilter::new(filter);
+        let mut noise = Noise(1);
+        let n = 400;
+        let mut raw_error = 0.0;
+        let mut filtered_error = 0.0;
+        for i in 0..n {
+            let t = i as f64 / RATE;
+            let actual = truth(t);
+            let measured = Vec3::new(
+                actual.x + noise.next(noise_amplitude),
+                actual.y + noise.next(noise_amplitude),
+                actual.z + noise.next(noise_amplitude),
+            );
+            let report = PoseReport {
+                sensor: Sensor(0),
+                pos: measured,
+                quat: Quat::identity(),
+            };
+            let filtered = filters.filter_report(time_at(i), &report);
+            if i >= n / 2 {
+                raw_error += distance(actual, measured);
+                filtered_error += distance(actual, filtered.pos);
+            }
+        }
+        (raw_error / (n / 2) as f64, filtered_error / (n / 2) as f64)
+    }
+
+    #[test]
+    fn reduces_jitter_when_still() {
+        let still = |_| Vec3::new(1.0, 2.0, 3.0);
+        let (raw, smoothed) = mean_error(ExponentialSmoothing::new(0.1), still, 0.01);
+        assert!(smoothed < raw / 2.0, "{} vs {}", smoothed, raw);
+        let (raw, smoothed) = mean_error(OneEuroFilter::new(1.0, 0.5), still, 0.01);
+        assert!(smoothed < raw / 2.0, "{} vs {}", smoothed, raw);
+    }
+
+    #[test]
+    fn one_euro_lags_less_when_moving() {
+        // 1 m/s, with a little noise.
+        let moving = |t: f64| Vec3::new(t, 0.0, 0.0);
+        let (_, exponential) = mean_error(ExponentialSmoothing::new(0.05), moving, 0.001);
+        let (_, one_euro) = mean_error(OneEuroFilter::new(1.0, 5.0), moving, 0.001);
+        assert!(
+            one_euro < exponential / 2.0,
+            "{} vs {}",
+            one_euro,
+            exponential
+        );
+    }
+
+    #[test]
+    fn orientation() {
+        let mut filters = PerSensorFilter::new(OneEuroFilter::new(1.0, 0.5));
+        let mut noise = Noise(2);
+        let truth = rotation_about_z(1.0);
+        let mut last = Quat::identity();
+        for i in 0..200 {
+            let measured = rotation_about_z(1.0 + noise.next(0.05));
+            // Also exercise the double cover: q and -q are the same rotation.
+            let measured = if i % 2 == 0 {
+                measured
+            } else {
+                Quat::from_sv(
+                    -measured.s,
+                    Vec3::new(-measured.v.x, -measured.v.y, -measured.v.z),
+                )
+            };
+            let report = PoseReport {
+                sensor: Sensor(0),
+                pos: Vec3::default(),
+                quat: measured,
+            };
+            last = filters.filter_report(time_at(i), &report).quat;
+            assert!(last.is_normalized(1.0e-9));
+        }
+        assert!(angle_between(last, truth) < 0.02);
+    }
+
+    #[test]
+    fn per_sensor() {
+        // Sensor 1 isn't smoothed at all.
+        let mut filters = PerSensorFilter::new(ExponentialSmoothing::new(0.5))
+            .with_sensor(Sensor(1), ExponentialSmoothing::new(1.0));
+        let report = |sensor, x| PoseReport {
+            sensor: Sensor(sensor),
+            pos: Vec3::new(x, 0.0, 0.0),
+            quat: Quat::identity(),
+        };
+        let msgs: Vec<_> = [
+            report(0, 0.0),
+            report(1, 0.0),
+            report(0, 1.0),
+            report(1, 1.0),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, r)| {
+            TypedMessage::new(
+                Some(time_at(i)),
+                LocalId(MessageTypeId(1)),
+                LocalId(SenderId(0)),
+                r.clone(),
+            )
+        })
+        .collect();
+        let filtered: Vec<_> = block_on(
+            filter_pose_stream(stream::iter(msgs.clone()), filters.clone()).collect::<Vec<_>>(),
+        );
+        assert_eq!(filtered[2].body.pos.x, 0.5);
+        assert_eq!(filtered[3].body.pos.x, 1.0);
+        assert_eq!(filtered[3].header, msgs[3].header);
+
+        // Same thing without the stream.
+        for msg in &msgs[..3] {
+            filters.filter_message(msg);
+        }
+        filters.reset();
+        assert_eq!(filters.filter_message(&msgs[2]).body.pos.x, 1.0);
+    }
+}
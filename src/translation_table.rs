@@ -4,10 +4,14 @@
 
 //! Code for associating names and local IDs with their remote equivalents.
 
-use std::convert::TryFrom;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    hash::{Hash, Hasher},
+};
 
 use crate::{
-    data_types::{id_types::*, GenericMessage},
+    data_types::{id_types::*, GenericMessage, Message, MessageHeader},
     type_dispatcher::TryIntoDescriptionMessage,
     Result, VrpnError,
 };
@@ -54,10 +58,31 @@ impl<T: TryIntoDescriptionMessage + UnwrappedId> TryFrom<Entry<T>> for GenericMe
     }
 }
 
+/// Index a slot under a name or local ID, unless a lower slot already has it:
+/// the lowest remote ID wins, as with the C++ implementation's linear search.
+fn index_at<K: Eq + Hash>(index: &mut HashMap<K, usize>, key: K, slot: usize) {
+    let winner = index.entry(key).or_insert(slot);
+    *winner = (*winner).min(slot);
+}
+
 /// A structure mapping names and local IDs to their remote equivalents
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+///
+/// Entries are stored densely by remote ID, with indices by name and by local ID,
+/// so all lookups are constant-time regardless of how many IDs are registered.
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct TranslationTable<T: UnwrappedId> {
     entries: Vec<Option<Entry<T>>>,
+    /// Index into `entries` by name
+    by_name: HashMap<Bytes, usize>,
+    /// Index into `entries` by local ID
+    by_local_id: HashMap<LocalId<T>, usize>,
+}
+
+// By hand, as the indices are not `Hash`: they follow from the entries anyway.
+impl<T: UnwrappedId> Hash for TranslationTable<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.entries.hash(state);
+    }
 }
 
 impl<T: UnwrappedId> Default for TranslationTable<T> {
@@ -71,9 +96,48 @@ impl<T: UnwrappedId> TranslationTable<T> {
     pub fn new() -> TranslationTable<T> {
         TranslationTable {
             entries: Vec::new(),
+            by_name: HashMap::new(),
+            by_local_id: HashMap::new(),
+        }
+    }
+
+    /// Remove the index entries pointing to the given slot, if it's occupied,
+    /// falling back to any other entry with the same name or local ID that they shadowed.
+    fn unindex(&mut self, index: usize) {
+        let (name, local_id) = match self.entries.get(index) {
+            Some(Some(old)) => (old.name.clone(), old.local_id),
+            _ => return,
+        };
+        if self.by_name.get(&name) == Some(&index) {
+            match self.first_other(index, |entry| entry.name == name) {
+                Some(other) => self.by_name.insert(name, other),
+                None => self.by_name.remove(&name),
+            };
+        }
+        self.unindex_local_id(index, local_id);
+    }
+
+    /// Remove the local ID index entry pointing to the given slot, as for `unindex`.
+    fn unindex_local_id(&mut self, index: usize, local_id: LocalId<T>) {
+        if self.by_local_id.get(&local_id) == Some(&index) {
+            match self.first_other(index, |entry| entry.local_id == local_id) {
+                Some(other) => self.by_local_id.insert(local_id, other),
+                None => self.by_local_id.remove(&local_id),
+            };
         }
     }
 
+    /// The first occupied slot other than `except` whose entry matches.
+    fn first_other(&self, except: usize, matches: impl Fn(&Entry<T>) -> bool) -> Option<usize> {
+        self.entries
+            .iter()
+            .enumerate()
+            .find_map(|(i, entry)| match entry {
+                Some(entry) if i != except && matches(entry) => Some(i),
+                _ => None,
+            })
+    }
+
     fn determine_remote_id_range(&self, id: RemoteId<T>) -> CategorizedId {
         categorize_id(id.into_id(), self.entries.len())
     }
@@ -106,6 +170,9 @@ impl<T: UnwrappedId> TranslationTable<T> {
             }
             InArray(v) => v as usize,
         };
+        self.unindex(index);
+        index_at(&mut self.by_name, name.clone(), index);
+        index_at(&mut self.by_local_id, local_id, index);
         let new_entry = Entry::new(name, local_id, remote_id);
         self.entries[index] = Some(new_entry);
         Ok(remote_id)
@@ -114,38 +181,29 @@ impl<T: UnwrappedId> TranslationTable<T> {
     /// Adds a local ID to a name that was already in the table.
     /// Returns true if the name has been found, false if not found.
     pub(crate) fn add_local_id(&mut self, name: Bytes, local_id: LocalId<T>) -> bool {
-        let find_result = self.entries.iter().position(|ref x| match x {
-            Some(entry) => entry.name == name,
-            _ => false,
-        });
-        match find_result {
-            Some(i) => {
-                if let Some(entry) = &mut self.entries[i] {
-                    entry.set_local_id(local_id);
-                }
-                true
-            }
-            None => false,
+        let index = match self.by_name.get(&name) {
+            Some(&i) => i,
+            None => return false,
+        };
+        if let Some(entry) = &mut self.entries[index] {
+            let old_local_id = entry.local_id();
+            entry.set_local_id(local_id);
+            self.unindex_local_id(index, old_local_id);
+            index_at(&mut self.by_local_id, local_id, index);
         }
+        true
     }
 
-    /// Attempts to find an entry satisfying your predicate,
-    /// returning a shared borrow of it if found.
-    pub(crate) fn find_by_predicate<F>(&self, f: F) -> Option<&Entry<T>>
-    where
-        F: Fn(&Entry<T>) -> bool,
-    {
-        let result = self.entries.iter().position(|x| match x {
-            Some(entry) => f(entry),
-            _ => false,
-        });
-        match result {
-            Some(i) => match &self.entries[i] {
-                Some(entry) => Some(entry),
-                None => None,
-            },
-            None => None,
-        }
+    /// Gets a shared borrow of an entry, given its local ID.
+    pub(crate) fn find_by_local_id(&self, local_id: LocalId<T>) -> Option<&Entry<T>> {
+        let index = *self.by_local_id.get(&local_id)?;
+        self.entries.get(index)?.as_ref()
+    }
+
+    /// The name the remote end described a remote ID with, if any.
+    pub fn remote_name(&self, id: RemoteId<T>) -> Option<&Bytes> {
+        let index = usize::try_from(id.get()).ok()?;
+        self.entries.get(index)?.as_ref().map(|entry| &entry.name)
     }
 
     /// Get an iterator to non-None table entries.
@@ -154,9 +212,21 @@ impl<T: UnwrappedId> TranslationTable<T> {
         self.entries.iter().flatten()
     }
 
+    /// Number of remote IDs with a mapping in this table.
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    /// True if no remote IDs are mapped.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Deletes every entry in the table
     pub fn clear(&mut self) {
-        self.entries.clear()
+        self.entries.clear();
+        self.by_name.clear();
+        self.by_local_id.clear();
     }
 }
 
@@ -179,6 +249,56 @@ impl TranslationTables {
         self.types.clear();
         self.senders.clear();
     }
+
+    /// Drop all remote ID mappings, e.g. because the remote end went away
+    /// and will assign new IDs if it comes back.
+    ///
+    /// Local IDs, and everything keyed on them such as handlers, are unaffected:
+    /// the remote end's descriptions re-bind them by name.
+    pub fn invalidate_remote(&mut self) -> InvalidatedMappings {
+        let invalidated = InvalidatedMappings {
+            senders: self.senders.len(),
+            types: self.types.len(),
+        };
+        self.clear();
+        invalidated
+    }
+
+    /// Convert a message with remote sender and type IDs to one with local IDs.
+    ///
+    /// System messages are returned unchanged.
+    pub(crate) fn map_remote_message_to_local(
+        &self,
+        msg: GenericMessage,
+    ) -> Result<GenericMessage> {
+        if msg.is_system_message() {
+            // no mapping applied to system messages
+            return Ok(msg);
+        }
+        let LocalId(new_type) = self
+            .types
+            .map_to_local_id(RemoteId(msg.header.message_type))
+            .ok()
+            .flatten()
+            .ok_or_else(|| VrpnError::OtherMessage("Could not map type to local".to_string()))?;
+        let LocalId(new_sender) = self
+            .senders
+            .map_to_local_id(RemoteId(msg.header.sender))
+            .ok()
+            .flatten()
+            .ok_or_else(|| VrpnError::OtherMessage("Could not map sender to local".to_string()))?;
+        Ok(GenericMessage::from_header_and_body(
+            MessageHeader::new(Some(msg.header.time), new_type, new_sender),
+            msg.body,
+        ))
+    }
+}
+
+/// How many remote mappings were dropped when a set of translation tables was invalidated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct InvalidatedMappings {
+    pub senders: usize,
+    pub types: usize,
 }
 
 impl Default for TranslationTables {
@@ -234,8 +354,7 @@ impl<T: UnwrappedId> InMutTranslationTables for T where
 pub(crate) trait TranslationTableExt<I: UnwrappedId>: AsRef<TranslationTable<I>> {
     /// Gets a shared borrow of an entry, given its local ID.
     fn find_by_local_id(&self, local_id: LocalId<I>) -> Option<&Entry<I>> {
-        (self.as_ref() as &TranslationTable<I>)
-            .find_by_predicate(|entry| entry.local_id() == local_id)
+        (self.as_ref() as &TranslationTable<I>).find_by_local_id(local_id)
     }
 }
 
@@ -255,5 +374,157 @@ mod tests {
                 LocalId(SenderId(0)),
             )
             .expect("Failed adding remote entry");
+
+        // Usable as a key, equal tables hashing alike.
+        let mut set = std::collections::HashSet::new();
+        assert!(set.insert(table.clone()));
+        assert!(!set.insert(table));
+        assert!(set.insert(TranslationTable::new()));
+    }
+
+    #[test]
+    fn indexed_lookups() {
+        use super::*;
+        use crate::data_types::id_types::{MessageTypeId, RemoteId};
+        let mut table: TranslationTable<MessageTypeId> = TranslationTable::new();
+        for i in 0..500 {
+            table
+                .add_remote_entry(
+                    Bytes::from(format!("type{}", i)),
+                    RemoteId(MessageTypeId(i)),
+                    LocalId(MessageTypeId(1000 + i)),
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            table.map_to_local_id(RemoteId(MessageTypeId(321))).unwrap(),
+            Some(LocalId(MessageTypeId(1321)))
+        );
+        let entry = table
+            .find_by_local_id(LocalId(MessageTypeId(1321)))
+            .unwrap();
+        assert_eq!(entry.remote_id, RemoteId(MessageTypeId(321)));
+
+        // Re-map a name to a new local ID: old local ID is no longer found.
+        assert!(table.add_local_id(Bytes::from_static(b"type5"), LocalId(MessageTypeId(7))));
+        assert!(table
+            .find_by_local_id(LocalId(MessageTypeId(1005)))
+            .is_none());
+        assert_eq!(
+            table
+                .find_by_local_id(LocalId(MessageTypeId(7)))
+                .map(|e| e.remote_id),
+            Some(RemoteId(MessageTypeId(5)))
+        );
+        assert!(!table.add_local_id(Bytes::from_static(b"nope"), LocalId(MessageTypeId(8))));
+
+        // Replacing a remote entry drops the old indices.
+        table
+            .add_remote_entry(
+                Bytes::from_static(b"replaced"),
+                RemoteId(MessageTypeId(5)),
+                LocalId(MessageTypeId(9)),
+            )
+            .unwrap();
+        assert!(table.find_by_local_id(LocalId(MessageTypeId(7))).is_none());
+        assert!(!table.add_local_id(Bytes::from_static(b"type5"), LocalId(MessageTypeId(8))));
+
+        table.clear();
+        assert!(table.find_by_local_id(LocalId(MessageTypeId(9))).is_none());
+    }
+
+    #[test]
+    fn shadowed_duplicates() {
+        use super::*;
+        use crate::data_types::id_types::{MessageTypeId, RemoteId};
+        let mut table: TranslationTable<MessageTypeId> = TranslationTable::new();
+        let name = Bytes::from_static(b"dup");
+        for remote in [4, 2] {
+            table
+                .add_remote_entry(
+                    name.clone(),
+                    RemoteId(MessageTypeId(remote)),
+                    LocalId(MessageTypeId(7)),
+                )
+                .unwrap();
+        }
+        // The lowest remote ID wins, whatever order they were added in.
+        assert_eq!(
+            table
+                .find_by_local_id(LocalId(MessageTypeId(7)))
+                .map(|e| e.remote_id),
+            Some(RemoteId(MessageTypeId(2)))
+        );
+
+        // Replacing the winner falls back to the entry it shadowed.
+        table
+            .add_remote_entry(
+                Bytes::from_static(b"other"),
+                RemoteId(MessageTypeId(2)),
+                LocalId(MessageTypeId(8)),
+            )
+            .unwrap();
+        assert_eq!(
+            table
+                .find_by_local_id(LocalId(MessageTypeId(7)))
+                .map(|e| e.remote_id),
+            Some(RemoteId(MessageTypeId(4)))
+        );
+        assert!(table.add_local_id(name, LocalId(MessageTypeId(9))));
+        assert_eq!(
+            table.map_to_local_id(RemoteId(MessageTypeId(4))).unwrap(),
+            Some(LocalId(MessageTypeId(9)))
+        );
+        assert!(table.find_by_local_id(LocalId(MessageTypeId(7))).is_none());
+    }
+
+    #[test]
+    fn invalidate_and_rebind() {
+        use super::*;
+        use crate::data_types::id_types::{MessageTypeId, RemoteId, SenderId};
+        let mut tables = TranslationTables::new();
+        let senders: &mut TranslationTable<SenderId> = tables.as_mut();
+        senders
+            .add_remote_entry(
+                Bytes::from_static(b"Tracker0"),
+                RemoteId(SenderId(0)),
+                LocalId(SenderId(3)),
+            )
+            .unwrap();
+        let types: &mut TranslationTable<MessageTypeId> = tables.as_mut();
+        for i in 0..2 {
+            types
+                .add_remote_entry(
+                    Bytes::from(format!("type{}", i)),
+                    RemoteId(MessageTypeId(i)),
+                    LocalId(MessageTypeId(10 + i)),
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            tables.invalidate_remote(),
+            InvalidatedMappings {
+                senders: 1,
+                types: 2
+            }
+        );
+        let senders: &mut TranslationTable<SenderId> = tables.as_mut();
+        assert!(senders.is_empty());
+        assert!(senders.map_to_local_id(RemoteId(SenderId(0))).is_err());
+
+        // After reconnecting, the same name may get a different remote ID,
+        // but still maps to the same local ID.
+        senders
+            .add_remote_entry(
+                Bytes::from_static(b"Tracker0"),
+                RemoteId(SenderId(2)),
+                LocalId(SenderId(3)),
+            )
+            .unwrap();
+        assert_eq!(
+            senders.map_to_local_id(RemoteId(SenderId(2))).unwrap(),
+            Some(LocalId(SenderId(3)))
+        );
+        assert_eq!(tables.invalidate_remote().senders, 1);
     }
 }
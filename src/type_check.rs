@@ -0,0 +1,228 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Strict mode: noticing when a remote end's messages don't decode as the local type of the same name.
+//!
+//! Two builds of a device that disagree on a message layout still agree on its name,
+//! so without this, every such message fails its typed handlers with a parse error,
+//! closing the endpoint. In strict mode, the dispatcher decodes each message of a type
+//! with typed handlers before calling them: those that fail are dropped and counted as
+//! parse errors, and the first for each type is reported once as a `TypeMismatch`.
+
+use crate::{
+    buffer_unbuffer::{BufferSize, UnbufferFrom},
+    data_types::{
+        id_types::{LocalId, MessageTypeId},
+        GenericMessage, MessageTypeIdentifier, MessageTypeName, TypedMessage, TypedMessageBody,
+    },
+    Result,
+};
+use std::{collections::HashMap, convert::TryFrom, fmt};
+
+/// Messages of a type with typed handlers whose bodies don't decode as the local type.
+///
+/// Most likely the remote end was built with a different version of the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeMismatch {
+    pub message_type: MessageTypeName,
+    /// Size of the body of the first message that failed to decode.
+    pub body_size: usize,
+    /// Smallest and largest body sizes of the messages of this type that did decode, if any.
+    pub decoded_sizes: Option<(usize, usize)>,
+    /// Why the body failed to decode.
+    pub error: String,
+}
+
+impl fmt::Display for TypeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "messages of type {} don't decode as the local type, likely a version mismatch: \
+             a body of {} bytes failed ({})",
+            self.message_type, self.body_size, self.error
+        )?;
+        match self.decoded_sizes {
+            Some((min, max)) if min == max => write!(f, ", those of {} bytes decoded", min),
+            Some((min, max)) => write!(f, ", those of {} to {} bytes decoded", min, max),
+            None => write!(f, ", none decoded"),
+        }
+    }
+}
+
+/// How to decode the bodies of a message type, and what the local type calls it.
+#[derive(Debug)]
+struct Decoder {
+    decode: fn(&GenericMessage) -> Result<()>,
+    name: MessageTypeName,
+}
+
+fn decode<T: TypedMessageBody + UnbufferFrom>(msg: &GenericMessage) -> Result<()> {
+    TypedMessage::<T>::try_from(msg).map(drop)
+}
+
+/// What has been seen of the bodies of one message type since the remote end described it.
+#[derive(Debug, Default)]
+struct BodySizes {
+    decoded: Option<(usize, usize)>,
+    reported: bool,
+}
+
+/// The bookkeeping behind strict mode, kept by the `TypeDispatcher`.
+#[derive(Debug, Default)]
+pub(crate) struct TypeChecker {
+    enabled: bool,
+    /// How to decode each message type with typed handlers.
+    decoders: HashMap<LocalId<MessageTypeId>, Decoder>,
+    sizes: HashMap<LocalId<MessageTypeId>, BodySizes>,
+    /// Mismatches found but not yet taken.
+    mismatches: Vec<TypeMismatch>,
+}
+
+impl TypeChecker {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.sizes.clear();
+        }
+    }
+
+    /// Note that messages of a type have a typed handler decoding them as `T`.
+    ///
+    /// System messages are never checked: both ends share their layout.
+    pub(crate) fn add_decoder<T: TypedMessageBody + UnbufferFrom>(
+        &mut self,
+        message_type: LocalId<MessageTypeId>,
+    ) {
+        if let MessageTypeIdentifier::UserMessageName(name) = T::MESSAGE_IDENTIFIER {
+            self.decoders.insert(
+                message_type,
+                Decoder {
+                    decode: decode::<T>,
+                    name: name.into(),
+                },
+            );
+        }
+    }
+
+    /// Whether to dispatch a message: false if strict and it doesn't decode as its local type.
+    pub(crate) fn check(&mut self, msg: &GenericMessage) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        let message_type = LocalId(msg.header.message_type);
+        let decoder = match self.decoders.get(&message_type) {
+            Some(decoder) => decoder,
+            None => return true,
+        };
+        let size = msg.body.buffer_size();
+        let result = (decoder.decode)(msg);
+        let sizes = self.sizes.entry(message_type).or_default();
+        match result {
+            Ok(()) => {
+                sizes.decoded = Some(match sizes.decoded {
+                    Some((min, max)) => (min.min(size), max.max(size)),
+                    None => (size, size),
+                });
+                true
+            }
+            Err(e) => {
+                if !sizes.reported {
+                    sizes.reported = true;
+                    let mismatch = TypeMismatch {
+                        message_type: decoder.name.clone(),
+                        body_size: size,
+                        decoded_sizes: sizes.decoded,
+                        error: e.to_string(),
+                    };
+                    eprintln!("Strict mode: {}", mismatch);
+                    self.mismatches.push(mismatch);
+                }
+                false
+            }
+        }
+    }
+
+    /// Forget what was seen from a remote end that went away: the next may be another version.
+    pub(crate) fn remote_lost(&mut self) {
+        self.sizes.clear();
+    }
+
+    pub(crate) fn take_mismatches(&mut self) -> Vec<TypeMismatch> {
+        std::mem::take(&mut self.mismatches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{id_types::SenderId, GenericBody, Message, MessageHeader},
+        handler::{HandlerCode, RemoteDescription, TypedBodylessHandler},
+        ping::{Ping, PING_MESSAGE},
+        stats::ErrorKind,
+        TranslationTables, TypeDispatcher,
+    };
+    use bytes::Bytes;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[derive(Debug)]
+    struct Counter(Arc<AtomicUsize>);
+
+    impl TypedBodylessHandler for Counter {
+        type Item = Ping;
+        fn handle_typed_bodyless(&mut self, _header: &MessageHeader) -> Result<HandlerCode> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    #[test]
+    fn strict_types() {
+        let mut dispatcher = TypeDispatcher::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        dispatcher
+            .add_typed_handler(Box::new(Counter(Arc::clone(&count))), None)
+            .unwrap();
+        let ping = dispatcher.get_type_id(PING_MESSAGE).unwrap();
+        let message = |body: &'static [u8]| {
+            GenericMessage::from_header_and_body(
+                MessageHeader::new(None, ping.0, SenderId(0)),
+                GenericBody::new(Bytes::from_static(body)),
+            )
+        };
+        let longer = message(b"\0\0\0\x01");
+
+        dispatcher.call(&message(b"")).unwrap();
+        assert!(dispatcher.call(&longer).is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        dispatcher.set_strict_types(true);
+        dispatcher.call(&message(b"")).unwrap();
+        dispatcher.call(&longer).unwrap();
+        dispatcher.call(&longer).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(dispatcher.stats().errors().count(ErrorKind::Parse), 2);
+        let mismatches = dispatcher.take_type_mismatches();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].message_type, PING_MESSAGE);
+        assert_eq!(mismatches[0].body_size, 4);
+        assert_eq!(mismatches[0].decoded_sizes, Some((0, 0)));
+        assert!(dispatcher.take_type_mismatches().is_empty());
+
+        // A new remote end is checked afresh.
+        let lost = RemoteDescription::RemoteLost(TranslationTables::new().invalidate_remote());
+        dispatcher.call_description_handlers(&lost).unwrap();
+        dispatcher.call(&longer).unwrap();
+        let mismatches = dispatcher.take_type_mismatches();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].decoded_sizes, None);
+    }
+}
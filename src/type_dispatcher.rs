@@ -3,29 +3,39 @@
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
 use crate::{
-    buffer_unbuffer::constants::GENERIC,
+    buffer_unbuffer::{constants::GENERIC, UnbufferFrom},
     data_types::{
         constants,
         id_types::*,
-        message::{GenericMessage, TypedMessageBody},
+        message::{GenericMessage, TypedMessage, TypedMessageBody},
         name_types::{IdWithNameAndDescription, MessageTypeName, SenderName},
-        Description, MessageTypeIdentifier,
+        Description, MessageTypeIdentifier, UserSystemMessageType,
+    },
+    dispatch_executor::{
+        DispatchPolicy, DispatchQueueConfig, DispatchQueueOverflow, Executor, SharedHandler,
     },
     handler::*,
+    message_display::WithNames,
     name_registration::{
         ExtraDataById, InsertOrGet, IntoCorrespondingName, IterableNameRegistration,
         LocalNameRegistration, NameRegistrationContainer, PerIdData,
     },
+    stats::{ConnectionStats, ErrorKind},
+    type_check::{TypeChecker, TypeMismatch},
+    validation::{run_validators, ValidationPolicy, Validator, ValidatorEntry},
     Result, VrpnError,
 };
 use bytes::Bytes;
 use futures::future::LocalBoxFuture;
 
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
     convert::{TryFrom, TryInto},
     fmt,
     hash::Hash,
+    sync::{Arc, Mutex, PoisonError},
+    thread::{self, ThreadId},
 };
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
@@ -74,11 +84,28 @@ impl HandlerHandleInner {
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct HandlerHandle(Option<LocalId<MessageTypeId>>, HandlerHandleInnerType);
 
+/// A way to refer to a description handler added to a TypeDispatcher, to remove it later.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct DescriptionHandlerHandle(HandlerHandleInnerType);
+
+struct DescriptionCallbackEntry {
+    handle: DescriptionHandlerHandle,
+    handler: Box<dyn DescriptionHandler + Send>,
+}
+
+impl fmt::Debug for DescriptionCallbackEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DescriptionCallbackEntry")
+            .field("handle", &self.handle)
+            .finish()
+    }
+}
+
 /// Type storing a boxed callback function, an optional sender ID filter,
 /// and the unique-per-CallbackCollection handle that can be used to unregister a handler.
 struct MsgCallbackEntry {
     handle: HandlerHandleInner,
-    pub handler: Box<dyn Handler + Send>,
+    pub handler: Arc<SharedHandler>,
     pub sender_filter: Option<LocalId<SenderId>>,
 }
 
@@ -99,15 +126,21 @@ impl MsgCallbackEntry {
     ) -> MsgCallbackEntry {
         MsgCallbackEntry {
             handle,
-            handler,
+            handler: SharedHandler::new(handler),
             sender_filter,
         }
     }
 
-    /// Invokes the callback with the given msg, if the sender filter (if not None) matches.
-    pub fn call(&mut self, msg: &GenericMessage) -> Result<HandlerCode> {
+    /// Invokes the callback with the given msg through the executor,
+    /// if the sender filter (if not None) matches.
+    pub fn call(
+        &mut self,
+        msg: &GenericMessage,
+        executor: &mut Executor,
+        replaceable: bool,
+    ) -> Result<HandlerCode> {
         if id_filter_matches(self.sender_filter, LocalId(msg.header.sender)) {
-            self.handler.handle(msg)
+            executor.call(&self.handler, msg, replaceable)
         } else {
             Ok(HandlerCode::ContinueProcessing)
         }
@@ -119,7 +152,7 @@ impl MsgCallbackEntry {
 #[derive(Debug)]
 struct CallbackCollection {
     name: Bytes,
-    callbacks: Vec<Option<MsgCallbackEntry>>,
+    callbacks: Vec<MsgCallbackEntry>,
     next_handle: HandlerHandleInnerType,
 }
 impl Default for CallbackCollection {
@@ -149,36 +182,61 @@ impl CallbackCollection {
         }
         let handle = HandlerHandleInner(self.next_handle);
         self.callbacks
-            .push(Some(MsgCallbackEntry::new(handle, handler, sender)));
+            .push(MsgCallbackEntry::new(handle, handler, sender));
         self.next_handle += 1;
         Ok(handle)
     }
 
-    /// Remove a callback
+    /// Remove a callback, failing if it was never added, or has already been removed,
+    /// including by asking for it.
     fn remove(&mut self, handle: HandlerHandleInner) -> Result<()> {
         let index = self
             .callbacks
             .iter()
-            .position(|x| {
-                x.as_ref()
-                    .map(|handler| handler.handle == handle)
-                    .unwrap_or(false)
-            })
+            .position(|entry| entry.handle == handle)
             .ok_or(VrpnError::HandlerNotFound)?;
-        self.callbacks.remove(index);
+        let entry = self.callbacks.remove(index);
+        if entry.handler.is_removed() {
+            return Err(VrpnError::HandlerNotFound);
+        }
         Ok(())
     }
 
+    /// Whether a callback is still registered, not having asked to be removed.
+    fn contains(&self, handle: HandlerHandleInner) -> bool {
+        self.callbacks
+            .iter()
+            .any(|entry| entry.handle == handle && !entry.handler.is_removed())
+    }
+
     /// Call all callbacks (subject to sender filters) and remove the callbacks who ask for it.
     fn call(&mut self, msg: &GenericMessage) -> Result<()> {
-        for entry in &mut self.callbacks.iter_mut() {
-            if let Some(unwrapped_entry) = entry {
-                if unwrapped_entry.call(msg)? == HandlerCode::RemoveThisHandler {
-                    entry.take();
+        self.call_with(msg, &mut Executor::default(), false)
+    }
+
+    /// Call all callbacks as the executor decides, removing those that have asked for it.
+    ///
+    /// Stops at the first error, leaving later callbacks uncalled.
+    fn call_with(
+        &mut self,
+        msg: &GenericMessage,
+        executor: &mut Executor,
+        replaceable: bool,
+    ) -> Result<()> {
+        let mut result = Ok(());
+        self.callbacks.retain_mut(|entry| {
+            if result.is_err() {
+                return true;
+            }
+            match entry.call(msg, executor, replaceable) {
+                Ok(code) => code == HandlerCode::ContinueProcessing,
+                Err(e) => {
+                    result = Err(e);
+                    true
                 }
             }
-        }
-        Ok(())
+        });
+        result
     }
 }
 
@@ -243,6 +301,76 @@ impl<I: TryIntoDescriptionMessage + UnwrappedId> TryIntoDescriptionMessage for L
     }
 }
 
+/// Sender and message type names to register ahead of any others, in a fixed order,
+/// so that their local IDs are the same from run to run.
+///
+/// Otherwise, IDs are assigned in order of registration, which may vary between runs
+/// when devices are created from different tasks.
+/// Names not listed here still get IDs after these, in order of registration.
+///
+/// ```
+/// use vrpn::{type_dispatcher::IdAssignment, TypeDispatcher};
+/// let ids = IdAssignment::new()
+///     .with_sender("Tracker1")
+///     .with_sender("Tracker0")
+///     .sorted();
+/// let dispatcher = TypeDispatcher::with_id_assignment(&ids).unwrap();
+/// let t0 = dispatcher.get_sender_id("Tracker0").unwrap();
+/// let t1 = dispatcher.get_sender_id("Tracker1").unwrap();
+/// assert!(t0 < t1);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdAssignment {
+    senders: Vec<SenderName>,
+    message_types: Vec<MessageTypeName>,
+}
+
+impl IdAssignment {
+    /// Create an empty assignment.
+    pub fn new() -> IdAssignment {
+        IdAssignment::default()
+    }
+
+    /// Add a sender name, to be registered after those already added.
+    pub fn with_sender(mut self, name: impl Into<SenderName>) -> IdAssignment {
+        self.senders.push(name.into());
+        self
+    }
+
+    /// Add a message type name, to be registered after those already added.
+    pub fn with_message_type(mut self, name: impl Into<MessageTypeName>) -> IdAssignment {
+        self.message_types.push(name.into());
+        self
+    }
+
+    /// Sort (and de-duplicate) the names, so the order they were added in doesn't matter either.
+    pub fn sorted(mut self) -> IdAssignment {
+        self.senders.sort();
+        self.senders.dedup();
+        self.message_types.sort();
+        self.message_types.dedup();
+        self
+    }
+
+    /// The sender names, in order of registration.
+    pub fn senders(&self) -> &[SenderName] {
+        &self.senders
+    }
+
+    /// The message type names, in order of registration.
+    pub fn message_types(&self) -> &[MessageTypeName] {
+        &self.message_types
+    }
+}
+
+/// The local IDs of the names registered together by `Connection::register_all`,
+/// in the order of the names.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegisteredIds {
+    pub senders: Vec<LocalId<SenderId>>,
+    pub message_types: Vec<LocalId<MessageTypeId>>,
+}
+
 pub(crate) fn try_register_system_senders_and_messages(
     sender_registration: &mut impl LocalNameRegistration<IdType = SenderId>,
     message_type_registration: &mut impl LocalNameRegistration<IdType = MessageTypeId>,
@@ -255,6 +383,61 @@ pub(crate) fn try_register_system_senders_and_messages(
     Ok(())
 }
 
+/// Records which thread, if any, is calling the handlers of a dispatcher.
+///
+/// Shared with the `ConnectionCore` owning the dispatcher, so it can tell a handler
+/// calling back into its connection, which must not lock the dispatcher again, from any other caller.
+#[derive(Debug, Default)]
+pub(crate) struct DispatchMarker(Mutex<Option<ThreadId>>);
+
+impl DispatchMarker {
+    /// Whether the current thread is calling handlers.
+    pub(crate) fn is_current_thread(&self) -> bool {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) == Some(thread::current().id())
+    }
+
+    /// Run `f` marked as calling handlers on the current thread.
+    fn run<R>(&self, f: impl FnOnce() -> R) -> R {
+        let previous = self
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replace(thread::current().id());
+        let result = f();
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = previous;
+        result
+    }
+}
+
+/// What to do when code on this side registers the same sender name a second time.
+///
+/// Names registered because the remote side described them never count:
+/// those always map to the local ID already used for the name,
+/// including when a reconnected remote describes it again under a new remote ID.
+/// Message types always alias, since every handler and message of a type looks it up by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateNamePolicy {
+    /// Return the ID already registered for the name.
+    #[default]
+    ReturnExisting,
+    /// Fail with `VrpnError::AlreadyRegistered`,
+    /// e.g. to catch two devices accidentally sharing a name.
+    /// Registering a retired sender again still revives it.
+    Error,
+}
+
+/// Which queues keep only the newest pending message of a type from each sender.
+///
+/// Suits messages like tracker poses, where each one makes the previous obsolete,
+/// when the network or consumer is slower than the producer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatestValueOnly {
+    /// Messages waiting to be sent.
+    Outgoing,
+    /// Messages waiting to be sent, and those received but not yet dispatched.
+    OutgoingAndIncoming,
+}
+
 /// Structure holding and dispatching generic and message-filtered callbacks.
 ///
 /// Unlike in the mainline C++ code, this does **not** handle "system" message types.
@@ -269,6 +452,23 @@ pub struct TypeDispatcher {
     generic_callbacks: CallbackCollection,
     /// Index is the local sender ID
     senders: NameRegistrationContainer<SenderId>,
+    description_handlers: Vec<DescriptionCallbackEntry>,
+    next_description_handle: HandlerHandleInnerType,
+    stats: ConnectionStats,
+    validators: HashMap<LocalId<MessageTypeId>, Vec<ValidatorEntry>>,
+    retired_senders: HashSet<LocalId<SenderId>>,
+    user_system_callbacks: HashMap<UserSystemMessageType, CallbackCollection>,
+    latest_value_only: HashMap<LocalId<MessageTypeId>, LatestValueOnly>,
+    /// Senders registered with `register_local_sender`.
+    local_senders: HashSet<LocalId<SenderId>>,
+    /// Message types registered with `register_local_type`.
+    local_types: HashSet<LocalId<MessageTypeId>>,
+    duplicate_names: DuplicateNamePolicy,
+    dispatching: Arc<DispatchMarker>,
+    executor: Executor,
+    /// Messages that found their dispatch queue full, not yet taken.
+    dispatch_queue_overflows: u64,
+    type_checker: TypeChecker,
 }
 
 impl Default for TypeDispatcher {
@@ -283,12 +483,55 @@ impl TypeDispatcher {
             message_types: PerIdData::new(NameRegistrationContainer::default()),
             generic_callbacks: CallbackCollection::new(/* Bytes::from_static(GENERIC) */),
             senders: NameRegistrationContainer::default(),
+            description_handlers: Vec::new(),
+            next_description_handle: 0,
+            stats: ConnectionStats::new(),
+            validators: HashMap::new(),
+            retired_senders: HashSet::new(),
+            user_system_callbacks: HashMap::new(),
+            latest_value_only: HashMap::new(),
+            local_senders: HashSet::new(),
+            local_types: HashSet::new(),
+            duplicate_names: DuplicateNamePolicy::default(),
+            dispatching: Arc::default(),
+            executor: Executor::default(),
+            dispatch_queue_overflows: 0,
+            type_checker: TypeChecker::default(),
         };
 
         try_register_system_senders_and_messages(&mut disp.senders, &mut disp.message_types);
         disp
     }
 
+    /// Create a dispatcher with the given names registered first, so their IDs are predictable.
+    pub fn with_id_assignment(ids: &IdAssignment) -> Result<TypeDispatcher> {
+        let mut disp = TypeDispatcher::new();
+        disp.assign_ids(ids)?;
+        Ok(disp)
+    }
+
+    /// Register all the names in an `IdAssignment`, in its order.
+    ///
+    /// Fails if any of them are already registered, since their IDs would then
+    /// depend on when that happened.
+    pub fn assign_ids(&mut self, ids: &IdAssignment) -> Result<()> {
+        for name in ids.senders() {
+            if let RegisterMapping::Found(_) = self.register_sender(name.clone())? {
+                return Err(VrpnError::AlreadyRegistered(
+                    String::from_utf8_lossy(&name.0).into_owned(),
+                ));
+            }
+        }
+        for name in ids.message_types() {
+            if let RegisterMapping::Found(_) = self.register_type(name.clone())? {
+                return Err(VrpnError::AlreadyRegistered(
+                    String::from_utf8_lossy(&name.0).into_owned(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
     /// Get a mutable borrow of the CallbackCollection associated with the supplied MessageTypeId
     /// (or the generic callbacks for None)
     fn get_type_callbacks_mut(
@@ -296,7 +539,10 @@ impl TypeDispatcher {
         type_id_filter: Option<LocalId<MessageTypeId>>,
     ) -> Result<&'_ mut CallbackCollection> {
         match type_id_filter {
-            Some(id) => self.message_types.try_get_data_mut(id.into_id()),
+            Some(id) => match UserSystemMessageType::try_from(id.into_id()) {
+                Ok(user) => Ok(self.user_system_callbacks.entry(user).or_default()),
+                Err(_) => self.message_types.try_get_data_mut(id.into_id()),
+            },
             None => Ok(&mut self.generic_callbacks),
         }
     }
@@ -312,26 +558,140 @@ impl TypeDispatcher {
 
     /// Calls add_type if get_type_id() returns None.
     /// Returns the corresponding MessageTypeId in all cases.
+    ///
+    /// Fails if the name contains an embedded null.
     pub fn register_type(
         &mut self,
         name: impl Into<MessageTypeName>,
     ) -> Result<RegisterMapping<MessageTypeId>> {
+        let name: MessageTypeName = name.into();
+        name.check_valid()?;
         Ok(self.message_types.try_insert_or_get(name)?.into())
     }
 
     /// Calls add_sender if get_sender_id() returns None.
+    ///
+    /// Fails if the name contains an embedded null.
     pub fn register_sender(
         &mut self,
         name: impl Into<SenderName>,
     ) -> Result<RegisterMapping<SenderId>> {
+        let name: SenderName = name.into();
+        name.check_valid()?;
         Ok(self.senders.try_insert_or_get(name)?.into())
     }
 
+    /// Register a sender name on behalf of code on this side,
+    /// applying the `DuplicateNamePolicy` if it was already registered that way.
+    ///
+    /// Returns `NewMapping` the first time, even if a remote end's description registered
+    /// the name already: nothing has described our ID for it until then.
+    /// Does not revive a retired sender: see `revive_sender`.
+    pub fn register_local_sender(
+        &mut self,
+        name: impl Into<SenderName>,
+    ) -> Result<RegisterMapping<SenderId>> {
+        let name: SenderName = name.into();
+        let id = self.register_sender(name.clone())?.into_inner();
+        if self.local_senders.insert(id) {
+            return Ok(RegisterMapping::NewMapping(id));
+        }
+        if self.duplicate_names == DuplicateNamePolicy::Error && !self.is_sender_retired(id) {
+            return Err(VrpnError::AlreadyRegistered(
+                String::from_utf8_lossy(&name.0).into_owned(),
+            ));
+        }
+        Ok(RegisterMapping::Found(id))
+    }
+
+    /// Register a message type name on behalf of code on this side.
+    ///
+    /// As with `register_local_sender`, returns `NewMapping` the first time,
+    /// even if a remote end's description registered the name already.
+    pub fn register_local_type(
+        &mut self,
+        name: impl Into<MessageTypeName>,
+    ) -> Result<RegisterMapping<MessageTypeId>> {
+        let id = self.register_type(name)?.into_inner();
+        Ok(if self.local_types.insert(id) {
+            RegisterMapping::NewMapping(id)
+        } else {
+            RegisterMapping::Found(id)
+        })
+    }
+
+    /// Check that all the names could be registered on behalf of code on this side,
+    /// without registering any: that they are valid, and allowed by the `DuplicateNamePolicy`,
+    /// including when a sender is listed twice.
+    pub fn check_registrable(&self, names: &IdAssignment) -> Result<()> {
+        let mut listed = HashSet::new();
+        for name in names.senders() {
+            name.check_valid()?;
+            if self.duplicate_names == DuplicateNamePolicy::Error {
+                let taken = self.get_sender_id(name.clone()).is_some_and(|id| {
+                    self.local_senders.contains(&id) && !self.is_sender_retired(id)
+                });
+                if taken || !listed.insert(name) {
+                    return Err(VrpnError::AlreadyRegistered(
+                        String::from_utf8_lossy(&name.0).into_owned(),
+                    ));
+                }
+            }
+        }
+        for name in names.message_types() {
+            name.check_valid()?;
+        }
+        Ok(())
+    }
+
+    pub fn duplicate_name_policy(&self) -> DuplicateNamePolicy {
+        self.duplicate_names
+    }
+
+    pub fn set_duplicate_name_policy(&mut self, policy: DuplicateNamePolicy) {
+        self.duplicate_names = policy;
+    }
+
     /// Returns the ID for the sender name, if found.
     pub fn get_sender_id(&self, name: impl Into<SenderName>) -> Option<LocalId<SenderId>> {
         self.senders.try_get_id_by_name(name)
     }
 
+    /// Returns the name registered for a type ID, if any.
+    pub fn get_type_name(&self, id: LocalId<MessageTypeId>) -> Option<MessageTypeName> {
+        self.message_types
+            .as_ref()
+            .try_get_name(id)
+            .map(|name| MessageTypeName(name.as_ref().clone()))
+    }
+
+    /// Returns the name registered for a sender ID, if any.
+    pub fn get_sender_name(&self, id: LocalId<SenderId>) -> Option<SenderName> {
+        self.senders
+            .try_get_name(id)
+            .map(|name| SenderName(name.as_ref().clone()))
+    }
+
+    /// Mark a sender as no longer in use, e.g. because its device was removed.
+    ///
+    /// Its ID stays reserved for its name: see `Connection::retire_sender`.
+    pub fn retire_sender(&mut self, id: LocalId<SenderId>) -> Result<()> {
+        if self.senders.try_get_name(id).is_none() {
+            return Err(VrpnError::InvalidId(id.get()));
+        }
+        self.retired_senders.insert(id);
+        Ok(())
+    }
+
+    /// Mark a retired sender as in use again, returning false if it wasn't retired.
+    pub fn revive_sender(&mut self, id: LocalId<SenderId>) -> bool {
+        self.retired_senders.remove(&id)
+    }
+
+    pub fn is_sender_retired(&self, id: LocalId<SenderId>) -> bool {
+        self.retired_senders.contains(&id)
+    }
+
     pub fn add_handler(
         &mut self,
         handler: Box<dyn Handler + Send>,
@@ -358,28 +718,303 @@ impl TypeDispatcher {
     where
         T: TypedHandler + Handler + Sized,
     {
-        let message_type = match T::Item::MESSAGE_IDENTIFIER {
-            MessageTypeIdentifier::UserMessageName(name) => self.register_type(name)?.into_inner(),
-            MessageTypeIdentifier::SystemMessageId(id) => LocalId(id),
-        };
+        let message_type = self.type_id_for(T::Item::MESSAGE_IDENTIFIER)?;
+        self.type_checker.add_decoder::<T::Item>(message_type);
         self.add_handler(handler, Some(message_type), sender_filter)
     }
 
+    /// Note that handlers for a message type decode it as `T`, for strict mode.
+    pub(crate) fn add_type_check<T: TypedMessageBody + UnbufferFrom>(
+        &mut self,
+        message_type: LocalId<MessageTypeId>,
+    ) {
+        self.type_checker.add_decoder::<T>(message_type);
+    }
+
+    /// Whether strict mode is on: see `set_strict_types`.
+    pub fn strict_types(&self) -> bool {
+        self.type_checker.is_enabled()
+    }
+
+    /// Check that messages of types with typed handlers decode as the local type before
+    /// calling any of their handlers, e.g. to diagnose a remote end built with another version
+    /// of a message.
+    ///
+    /// Messages that don't decode are dropped and counted as parse errors, instead of failing
+    /// their handlers and closing the endpoint. The first for each type, per remote end,
+    /// is logged and kept for `take_type_mismatches`. Costs decoding such messages twice.
+    pub fn set_strict_types(&mut self, strict: bool) {
+        self.type_checker.set_enabled(strict);
+    }
+
+    /// Take the type mismatches found in strict mode since last time.
+    pub fn take_type_mismatches(&mut self) -> Vec<TypeMismatch> {
+        self.type_checker.take_mismatches()
+    }
+
+    /// Keep only the newest pending message of a type from each sender,
+    /// in the queues chosen, or in none for `None`.
+    pub fn set_latest_value_only(
+        &mut self,
+        message_type: LocalId<MessageTypeId>,
+        mode: Option<LatestValueOnly>,
+    ) {
+        match mode {
+            Some(mode) => self.latest_value_only.insert(message_type, mode),
+            None => self.latest_value_only.remove(&message_type),
+        };
+    }
+
+    pub fn latest_value_only(
+        &self,
+        message_type: LocalId<MessageTypeId>,
+    ) -> Option<LatestValueOnly> {
+        self.latest_value_only.get(&message_type).copied()
+    }
+
+    /// Get the local ID of a message type, registering user message types if needed.
+    pub fn type_id_for(
+        &mut self,
+        identifier: MessageTypeIdentifier,
+    ) -> Result<LocalId<MessageTypeId>> {
+        match identifier {
+            MessageTypeIdentifier::UserMessageName(name) => {
+                Ok(self.register_type(name)?.into_inner())
+            }
+            MessageTypeIdentifier::SystemMessageId(id) => Ok(LocalId(id)),
+        }
+    }
+
+    /// Where handlers are called for the messages dispatched.
+    pub fn dispatch_policy(&self) -> DispatchPolicy {
+        self.executor.policy()
+    }
+
+    /// Choose where handlers are called, e.g. to keep a slow handler for one device
+    /// from delaying messages from every other device sharing the connection.
+    ///
+    /// Starts any worker threads needed. Messages already queued for the workers
+    /// of the previous policy are still handled, and those workers then stop.
+    /// Fails for a thread pool of no threads.
+    pub fn set_dispatch_policy(&mut self, policy: DispatchPolicy) -> Result<()> {
+        self.executor = Executor::new(policy, self.executor.queue_config())?;
+        Ok(())
+    }
+
+    /// How many messages may wait for dispatch workers, and what happens when there are more.
+    pub fn dispatch_queue(&self) -> DispatchQueueConfig {
+        self.executor.queue_config()
+    }
+
+    /// Bound the queues of messages waiting for dispatch workers, when handlers are not
+    /// called inline, so a slow handler cannot make memory grow without bound.
+    ///
+    /// Applies to queues already started too. Fails for a capacity of zero.
+    pub fn set_dispatch_queue(&mut self, config: DispatchQueueConfig) -> Result<()> {
+        self.executor.set_queue_config(config)
+    }
+
+    /// Take the count of messages that found their dispatch queue full since last time,
+    /// if any, for reporting as an event.
+    pub fn take_dispatch_queue_overflow(&mut self) -> Option<DispatchQueueOverflow> {
+        match std::mem::take(&mut self.dispatch_queue_overflows) {
+            0 => None,
+            messages => Some(DispatchQueueOverflow {
+                messages,
+                policy: self.executor.queue_config().overflow,
+            }),
+        }
+    }
+
+    /// Check every message of the validator's type before its handlers are called,
+    /// after any validators added earlier.
+    pub fn add_validator<V: Validator + 'static>(
+        &mut self,
+        validator: Box<V>,
+        policy: ValidationPolicy,
+    ) -> Result<LocalId<MessageTypeId>> {
+        let message_type = self.type_id_for(V::Item::MESSAGE_IDENTIFIER)?;
+        self.validators
+            .entry(message_type)
+            .or_default()
+            .push(ValidatorEntry::new(validator, policy));
+        Ok(message_type)
+    }
+
+    /// Remove all validators for a message type.
+    pub fn remove_validators(&mut self, message_type: LocalId<MessageTypeId>) {
+        self.validators.remove(&message_type);
+    }
+
+    /// Remove a handler, so it is dropped and never called again.
+    ///
+    /// Fails with `VrpnError::HandlerNotFound` if the handler was already removed,
+    /// including by returning `HandlerCode::RemoveThisHandler`.
     pub fn remove_handler(&mut self, handler_handle: HandlerHandle) -> Result<()> {
         let HandlerHandle(message_type, inner) = handler_handle;
         self.get_type_callbacks_mut(message_type)?
             .remove(HandlerHandleInner(inner))
     }
 
+    /// Whether a handler is still registered: neither removed with `remove_handler`,
+    /// nor having returned `HandlerCode::RemoveThisHandler`.
+    pub fn has_handler(&self, handler_handle: HandlerHandle) -> bool {
+        let HandlerHandle(message_type, inner) = handler_handle;
+        let callbacks = match message_type {
+            Some(message_type) => match UserSystemMessageType::try_from(message_type.0) {
+                Ok(user) => self.user_system_callbacks.get(&user),
+                Err(_) => self.message_types.try_get_data(message_type.0).ok(),
+            },
+            None => Some(&self.generic_callbacks),
+        };
+        callbacks.is_some_and(|callbacks| callbacks.contains(HandlerHandleInner(inner)))
+    }
+
+    /// Add a handler called whenever a remote endpoint describes a sender or message type.
+    pub fn add_description_handler(
+        &mut self,
+        handler: Box<dyn DescriptionHandler + Send>,
+    ) -> Result<DescriptionHandlerHandle> {
+        if self.description_handlers.len() > MAX_VEC_USIZE {
+            return Err(VrpnError::TooManyHandlers);
+        }
+        let handle = DescriptionHandlerHandle(self.next_description_handle);
+        self.next_description_handle += 1;
+        self.description_handlers
+            .push(DescriptionCallbackEntry { handle, handler });
+        Ok(handle)
+    }
+
+    pub fn remove_description_handler(&mut self, handle: DescriptionHandlerHandle) -> Result<()> {
+        let index = self
+            .description_handlers
+            .iter()
+            .position(|entry| entry.handle == handle)
+            .ok_or(VrpnError::HandlerNotFound)?;
+        self.description_handlers.remove(index);
+        Ok(())
+    }
+
+    /// Call the description handlers, removing those that ask for it.
+    pub(crate) fn call_description_handlers(&mut self, desc: &RemoteDescription) -> Result<()> {
+        if let RemoteDescription::RemoteLost(_) = desc {
+            self.type_checker.remote_lost();
+        }
+        let mut result = Ok(());
+        let dispatching = Arc::clone(&self.dispatching);
+        let handlers = &mut self.description_handlers;
+        dispatching.run(|| {
+            handlers.retain_mut(|entry| {
+                if result.is_err() {
+                    return true;
+                }
+                match entry.handler.handle_description(desc) {
+                    Ok(code) => code == HandlerCode::ContinueProcessing,
+                    Err(e) => {
+                        result = Err(e);
+                        true
+                    }
+                }
+            });
+        });
+        result
+    }
+
+    /// Decode a generic message as `T`, checking that its type ID is the one registered for `T`.
+    pub fn try_decode<T>(&self, msg: &GenericMessage) -> Result<TypedMessage<T>>
+    where
+        T: TypedMessageBody + UnbufferFrom,
+    {
+        let expected = match T::MESSAGE_IDENTIFIER {
+            MessageTypeIdentifier::UserMessageName(name) => self.get_type_id(name),
+            MessageTypeIdentifier::SystemMessageId(id) => Some(LocalId(id)),
+        };
+        if expected != Some(LocalId(msg.header.message_type)) {
+            return Err(VrpnError::WrongMessageType(msg.header.message_type.get()));
+        }
+        TypedMessage::try_from(msg)
+    }
+
     /// Akin to vrpn_TypeDispatcher::doCallbacksFor
+    ///
+    /// Validators for the message type run first, and may drop or replace the message.
+    /// Handlers are then called as chosen with `set_dispatch_policy`.
     pub fn call(&mut self, msg: &GenericMessage) -> Result<()> {
-        self.generic_callbacks.call(msg)?;
+        self.stats.record_received(&msg.header);
+        for _ in 0..self.executor.take_errors() {
+            self.stats.record_error(ErrorKind::Handler);
+        }
+        if !self.type_checker.check(msg) {
+            self.stats.record_error(ErrorKind::Parse);
+            return Ok(());
+        }
+        let validated = match self.validators.get(&LocalId(msg.header.message_type)) {
+            Some(entries) => match run_validators(entries, msg)? {
+                Some(validated) => validated,
+                None => return Ok(()),
+            },
+            None => Cow::Borrowed(msg),
+        };
+        let dispatching = Arc::clone(&self.dispatching);
+        let result = dispatching.run(|| self.call_handlers(&validated));
+        let overflows = self.executor.take_overflows();
+        self.dispatch_queue_overflows += overflows;
+        self.stats
+            .record_dispatch_queue(self.executor.queue_depth(), overflows);
+        if let Err(e) = &result {
+            self.stats.record_error(ErrorKind::Handler);
+            eprintln!("Error handling {}: {}", validated.with_names(&*self), e);
+        }
+        result
+    }
+
+    /// Call the handlers added for a message in the range reserved by `UserSystemMessageType`.
+    ///
+    /// Unlike `call`, there are no validators or generic handlers, and no sender filtering:
+    /// the sender ID is whatever the remote end sent, unmapped.
+    pub fn call_user_system_message(&mut self, msg: &GenericMessage) -> Result<()> {
+        let user = UserSystemMessageType::try_from(msg.header.message_type)?;
+        let dispatching = Arc::clone(&self.dispatching);
+        let result = match self.user_system_callbacks.get_mut(&user) {
+            Some(callbacks) => dispatching.run(|| callbacks.call(msg)),
+            None => {
+                eprintln!("No handler for user system message {:?}", user);
+                Ok(())
+            }
+        };
+        if let Err(e) = &result {
+            self.stats.record_error(ErrorKind::Handler);
+            eprintln!("Error handling {}: {}", msg.with_names(&*self), e);
+        }
+        result
+    }
+
+    fn call_handlers(&mut self, msg: &GenericMessage) -> Result<()> {
+        let replaceable = self.latest_value_only(LocalId(msg.header.message_type))
+            == Some(LatestValueOnly::OutgoingAndIncoming);
+        self.generic_callbacks
+            .call_with(msg, &mut self.executor, replaceable)?;
         if let Ok(mapping) = self.message_types.try_get_data_mut(msg.header.message_type) {
-            mapping.call(msg)?;
+            mapping.call_with(msg, &mut self.executor, replaceable)?;
         }
         Ok(())
     }
 
+    /// The marker showing which thread, if any, is calling this dispatcher's handlers.
+    pub(crate) fn dispatch_marker(&self) -> Arc<DispatchMarker> {
+        Arc::clone(&self.dispatching)
+    }
+
+    /// Access the statistics gathered while receiving and dispatching messages.
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    /// Mutable access to the statistics, e.g. to enable instrumentation or record samples.
+    pub fn stats_mut(&mut self) -> &mut ConnectionStats {
+        &mut self.stats
+    }
+
     /// caution: expensive
     fn senders_iter(&'_ self) -> impl Iterator<Item = (LocalId<SenderId>, SenderName)> + '_ {
         self.senders
@@ -416,7 +1051,7 @@ impl TypeDispatcher {
 mod tests {
     use crate::data_types::{
         message::{GenericBody, GenericMessage, Message},
-        MessageHeader, TimeVal,
+        MessageHeader, StaticSenderName, TimeVal,
     };
     use crate::type_dispatcher::*;
     use std::sync::{Arc, Mutex};
@@ -548,4 +1183,540 @@ mod tests {
         dispatcher.call(&msg2).unwrap();
         assert_eq!(*val.lock().unwrap(), 10);
     }
+
+    #[test]
+    fn unknown_message_type() {
+        let mut dispatcher = TypeDispatcher::new();
+        let msg = GenericMessage::from_header_and_body(
+            MessageHeader::new(
+                Some(TimeVal::get_time_of_day()),
+                MessageTypeId(1000),
+                SenderId(0),
+            ),
+            GenericBody::default(),
+        );
+        // No handlers for that type, nothing registered: nothing to do.
+        dispatcher.call(&msg).unwrap();
+    }
+
+    #[test]
+    fn register_names() {
+        let mut dispatcher = TypeDispatcher::new();
+        let id = dispatcher.register_sender("Tracker0").unwrap().into_inner();
+        assert_eq!(
+            dispatcher
+                .register_sender(StaticSenderName(b"Tracker0"))
+                .unwrap(),
+            RegisterMapping::Found(id)
+        );
+        assert_eq!(dispatcher.get_sender_id(String::from("Tracker0")), Some(id));
+        assert!(dispatcher.register_sender("Track\0er0").is_err());
+        assert!(dispatcher.register_type("bad\0type").is_err());
+
+        assert_eq!(
+            dispatcher.get_sender_name(id),
+            Some(SenderName::from("Tracker0"))
+        );
+        assert_eq!(dispatcher.get_sender_name(LocalId(SenderId(1000))), None);
+        let type_id = dispatcher.register_type("MyType").unwrap().into_inner();
+        assert_eq!(
+            dispatcher.get_type_name(type_id),
+            Some(MessageTypeName::from("MyType"))
+        );
+    }
+
+    #[cfg(feature = "tracker")]
+    #[test]
+    fn try_decode() {
+        use crate::{
+            data_types::descriptions::UdpInnerDescription, ping::Ping, tracker::PoseReport,
+        };
+        use std::convert::TryFrom;
+        let mut dispatcher = TypeDispatcher::new();
+        let ping_type = dispatcher
+            .register_type("vrpn_Base ping_message")
+            .unwrap()
+            .into_inner();
+        let sender = dispatcher.register_sender("Tracker0").unwrap().into_inner();
+        let typed = TypedMessage::new(None, ping_type, sender, Ping);
+        let msg = GenericMessage::try_from(&typed).unwrap();
+
+        assert_eq!(dispatcher.try_decode::<Ping>(&msg).unwrap(), typed);
+        // Not registered, so certainly not this type.
+        assert!(matches!(
+            dispatcher.try_decode::<PoseReport>(&msg),
+            Err(VrpnError::WrongMessageType(_))
+        ));
+        dispatcher.register_type("vrpn_Tracker Pos_Quat").unwrap();
+        assert!(matches!(
+            dispatcher.try_decode::<PoseReport>(&msg),
+            Err(VrpnError::WrongMessageType(_))
+        ));
+
+        // System message types are checked even without a dispatcher.
+        let desc = TypedMessage::<UdpInnerDescription>::try_from(&msg);
+        assert!(matches!(desc, Err(VrpnError::WrongMessageType(_))));
+    }
+
+    #[test]
+    fn deterministic_ids() {
+        let ids = IdAssignment::new()
+            .with_sender("Tracker1")
+            .with_sender("Tracker0")
+            .with_message_type("vrpn_Tracker Pos_Quat")
+            .with_message_type("vrpn_Analog Channel")
+            .sorted();
+        let mut a = TypeDispatcher::with_id_assignment(&ids).unwrap();
+        let mut b = TypeDispatcher::new();
+        b.register_sender("Extra").unwrap();
+        // Already registered: can't guarantee its id.
+        assert!(matches!(
+            b.assign_ids(&ids.clone().with_sender("Extra")),
+            Err(VrpnError::AlreadyRegistered(_))
+        ));
+        let mut b = TypeDispatcher::with_id_assignment(&ids).unwrap();
+
+        // Registering in a different order doesn't change the assigned ids.
+        for name in &["Tracker0", "Tracker1"] {
+            a.register_sender(*name).unwrap();
+        }
+        for name in &["Tracker1", "Tracker0"] {
+            b.register_sender(*name).unwrap();
+        }
+        for name in &["Tracker0", "Tracker1"] {
+            assert_eq!(a.get_sender_id(*name), b.get_sender_id(*name));
+        }
+        assert!(a.get_sender_id("Tracker0") < a.get_sender_id("Tracker1"));
+        assert_eq!(
+            a.get_type_id("vrpn_Analog Channel"),
+            b.get_type_id("vrpn_Analog Channel")
+        );
+        assert!(a.get_type_id("vrpn_Analog Channel") < a.get_type_id("vrpn_Tracker Pos_Quat"));
+    }
+
+    #[derive(Debug)]
+    struct RecordDescriptions(Arc<Mutex<Vec<RemoteDescription>>>);
+    impl DescriptionHandler for RecordDescriptions {
+        fn handle_description(&mut self, desc: &RemoteDescription) -> Result<HandlerCode> {
+            self.0.lock()?.push(desc.clone());
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    #[test]
+    fn description_handlers() {
+        use crate::{
+            endpoint::{handle_system_command, SystemCommand},
+            translation_table::TranslationTables,
+        };
+        let mut dispatcher = TypeDispatcher::new();
+        let mut tables = TranslationTables::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handle = dispatcher
+            .add_description_handler(Box::new(RecordDescriptions(Arc::clone(&seen))))
+            .unwrap();
+
+        let sender = Description::from_id_and_name(SenderId(7), Bytes::from_static(b"Tracker0"));
+        handle_system_command(
+            &mut dispatcher,
+            &mut tables,
+            SystemCommand::SenderDescription(sender),
+        )
+        .unwrap();
+        let local_id = dispatcher.get_sender_id("Tracker0").unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![RemoteDescription::Sender {
+                name: SenderName::from("Tracker0"),
+                local_id,
+                remote_id: RemoteId(SenderId(7)),
+            }]
+        );
+
+        dispatcher.remove_description_handler(handle).unwrap();
+        let message_type =
+            Description::from_id_and_name(MessageTypeId(3), Bytes::from_static(b"custom"));
+        handle_system_command(
+            &mut dispatcher,
+            &mut tables,
+            SystemCommand::TypeDescription(message_type),
+        )
+        .unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn duplicate_sender_names() {
+        use crate::{
+            endpoint::{handle_system_command, SystemCommand},
+            translation_table::{TranslationTable, TranslationTables},
+        };
+        let mut dispatcher = TypeDispatcher::new();
+        dispatcher.set_duplicate_name_policy(DuplicateNamePolicy::Error);
+        let id = dispatcher
+            .register_local_sender("Tracker0")
+            .unwrap()
+            .into_inner();
+
+        // The remote describes it, then describes it again under a new ID after reconnecting.
+        let mut tables = TranslationTables::new();
+        for remote in [SenderId(4), SenderId(9)] {
+            tables.invalidate_remote();
+            let desc = Description::from_id_and_name(remote, Bytes::from_static(b"Tracker0"));
+            handle_system_command(
+                &mut dispatcher,
+                &mut tables,
+                SystemCommand::SenderDescription(desc),
+            )
+            .unwrap();
+            let table: &TranslationTable<SenderId> = tables.as_ref();
+            assert_eq!(table.map_to_local_id(RemoteId(remote)).unwrap(), Some(id));
+        }
+
+        // A name first described by the remote may still be registered locally once.
+        let desc = Description::from_id_and_name(SenderId(5), Bytes::from_static(b"Button0"));
+        handle_system_command(
+            &mut dispatcher,
+            &mut tables,
+            SystemCommand::SenderDescription(desc),
+        )
+        .unwrap();
+        assert!(matches!(
+            dispatcher.register_local_sender("Button0"),
+            Ok(RegisterMapping::NewMapping(_))
+        ));
+        assert!(matches!(
+            dispatcher.register_local_type("vrpn_Button Change"),
+            Ok(RegisterMapping::NewMapping(_))
+        ));
+        assert!(matches!(
+            dispatcher.register_local_type("vrpn_Button Change"),
+            Ok(RegisterMapping::Found(_))
+        ));
+
+        assert!(matches!(
+            dispatcher.register_local_sender("Tracker0"),
+            Err(VrpnError::AlreadyRegistered(_))
+        ));
+        dispatcher.retire_sender(id).unwrap();
+        assert_eq!(
+            dispatcher
+                .register_local_sender("Tracker0")
+                .unwrap()
+                .into_inner(),
+            id
+        );
+
+        dispatcher.set_duplicate_name_policy(DuplicateNamePolicy::ReturnExisting);
+        assert_eq!(
+            dispatcher
+                .register_local_sender("Tracker0")
+                .unwrap()
+                .into_inner(),
+            id
+        );
+    }
+
+    #[derive(Debug)]
+    struct RecordMessages(Arc<Mutex<Vec<GenericMessage>>>);
+    impl Handler for RecordMessages {
+        fn handle(&mut self, msg: &GenericMessage) -> Result<HandlerCode> {
+            self.0.lock()?.push(msg.clone());
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    #[test]
+    fn user_system_messages() {
+        use crate::{
+            endpoint::{handle_system_command, parse_system_message, SystemCommand},
+            translation_table::TranslationTables,
+        };
+        const COMPRESSION: UserSystemMessageType = UserSystemMessageType::new(3);
+        let mut dispatcher = TypeDispatcher::new();
+        let mut tables = TranslationTables::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let everything = Arc::new(Mutex::new(Vec::new()));
+        let handle = dispatcher
+            .add_handler(
+                Box::new(RecordMessages(Arc::clone(&seen))),
+                Some(LocalId(COMPRESSION.id())),
+                None,
+            )
+            .unwrap();
+        dispatcher
+            .add_handler(
+                Box::new(RecordMessages(Arc::clone(&everything))),
+                None,
+                None,
+            )
+            .unwrap();
+
+        // Neither the sender nor the type is mapped, or even described.
+        let msg = GenericMessage::from_header_and_body(
+            MessageHeader::new(Some(TimeVal::default()), COMPRESSION.id(), SenderId(42)),
+            GenericBody::new(Bytes::from_static(b"zstd")),
+        );
+        let cmd = parse_system_message(msg.clone()).unwrap();
+        assert_eq!(cmd, SystemCommand::UserSystemMessage(msg.clone()));
+        assert!(handle_system_command(&mut dispatcher, &mut tables, cmd)
+            .unwrap()
+            .is_none());
+        assert_eq!(*seen.lock().unwrap(), vec![msg.clone()]);
+        assert!(everything.lock().unwrap().is_empty());
+
+        // Other reserved IDs without handlers are ignored.
+        let other = GenericMessage::from_header_and_body(
+            MessageHeader::new(
+                Some(TimeVal::default()),
+                UserSystemMessageType::new(4).id(),
+                SenderId(0),
+            ),
+            GenericBody::default(),
+        );
+        dispatcher.call_user_system_message(&other).unwrap();
+
+        dispatcher.remove_handler(handle).unwrap();
+        dispatcher.call_user_system_message(&msg).unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    /// Sends the sender and first body byte of each message, after waiting for the gate if any.
+    struct Recorder {
+        tx: std::sync::mpsc::Sender<(IdType, u8)>,
+        gate: Option<Mutex<std::sync::mpsc::Receiver<()>>>,
+    }
+    impl Handler for Recorder {
+        fn handle(&mut self, msg: &GenericMessage) -> Result<HandlerCode> {
+            if let Some(gate) = &self.gate {
+                let _ = gate.lock()?.recv();
+            }
+            let byte = msg.body.clone().into_inner()[0];
+            let _ = self.tx.send((msg.header.sender.0, byte));
+            Ok(if byte == 0xff {
+                HandlerCode::RemoveThisHandler
+            } else {
+                HandlerCode::ContinueProcessing
+            })
+        }
+    }
+
+    #[test]
+    fn dispatch_policies() {
+        use std::{sync::mpsc::channel, time::Duration};
+        let mut dispatcher = TypeDispatcher::new();
+        assert_eq!(dispatcher.dispatch_policy(), DispatchPolicy::Inline);
+        assert!(dispatcher
+            .set_dispatch_policy(DispatchPolicy::ThreadPool(0))
+            .is_err());
+        dispatcher
+            .set_dispatch_policy(DispatchPolicy::PerSender)
+            .unwrap();
+        let message_type = dispatcher.register_type("Test").unwrap().into_inner();
+        let (tx, rx) = channel();
+        let (open_gate, gate) = channel();
+        let mut gate = Some(Mutex::new(gate));
+        for sender in 1..=2 {
+            let recorder = Recorder {
+                tx: tx.clone(),
+                gate: gate.take(),
+            };
+            dispatcher
+                .add_handler(
+                    Box::new(recorder),
+                    Some(message_type),
+                    Some(LocalId(SenderId(sender))),
+                )
+                .unwrap();
+        }
+        let msg = |sender, byte| {
+            GenericMessage::from_header_and_body(
+                MessageHeader::new(None, message_type.0, SenderId(sender)),
+                GenericBody::new(Bytes::from(vec![byte])),
+            )
+        };
+        let recv = || rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Sender 1's handler is stuck, without holding up sender 2.
+        dispatcher.call(&msg(1, 0)).unwrap();
+        for byte in 0..3 {
+            dispatcher.call(&msg(2, byte)).unwrap();
+        }
+        assert_eq!((recv(), recv(), recv()), ((2, 0), (2, 1), (2, 2)));
+        open_gate.send(()).unwrap();
+        assert_eq!(recv(), (1, 0));
+
+        // Removal requested on a worker takes effect for later messages.
+        dispatcher.call(&msg(2, 0xff)).unwrap();
+        assert_eq!(recv(), (2, 0xff));
+        dispatcher.call(&msg(2, 3)).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn dispatch_queue_overflow() {
+        use crate::dispatch_executor::{
+            DispatchQueueConfig, DispatchQueueOverflow, OverflowPolicy,
+        };
+        use std::{
+            sync::mpsc::{channel, Receiver, Sender},
+            time::Duration,
+        };
+        /// Reports each message as soon as it starts handling it, then waits for the gate.
+        struct Stuck {
+            started: Sender<u8>,
+            gate: Mutex<Receiver<()>>,
+        }
+        impl Handler for Stuck {
+            fn handle(&mut self, msg: &GenericMessage) -> Result<HandlerCode> {
+                let _ = self.started.send(msg.body.clone().into_inner()[0]);
+                let _ = self.gate.lock()?.recv();
+                Ok(HandlerCode::ContinueProcessing)
+            }
+        }
+
+        let mut dispatcher = TypeDispatcher::new();
+        assert_eq!(dispatcher.dispatch_queue(), DispatchQueueConfig::default());
+        assert!(dispatcher
+            .set_dispatch_queue(DispatchQueueConfig {
+                capacity: 0,
+                overflow: OverflowPolicy::Block,
+            })
+            .is_err());
+        dispatcher
+            .set_dispatch_queue(DispatchQueueConfig {
+                capacity: 2,
+                overflow: OverflowPolicy::Disconnect,
+            })
+            .unwrap();
+        dispatcher
+            .set_dispatch_policy(DispatchPolicy::PerSender)
+            .unwrap();
+        let message_type = dispatcher.register_type("Test").unwrap().into_inner();
+        let (started_tx, started) = channel();
+        let (open_gate, gate) = channel();
+        dispatcher
+            .add_handler(
+                Box::new(Stuck {
+                    started: started_tx,
+                    gate: Mutex::new(gate),
+                }),
+                Some(message_type),
+                None,
+            )
+            .unwrap();
+        let msg = |byte| {
+            GenericMessage::from_header_and_body(
+                MessageHeader::new(None, message_type.0, SenderId(1)),
+                GenericBody::new(Bytes::from(vec![byte])),
+            )
+        };
+        let recv = || started.recv_timeout(Duration::from_secs(5)).unwrap();
+        dispatcher.set_latest_value_only(message_type, Some(LatestValueOnly::OutgoingAndIncoming));
+
+        // The worker is stuck on the first message, and two more fill its queue.
+        dispatcher.call(&msg(0)).unwrap();
+        assert_eq!(recv(), 0);
+        dispatcher.call(&msg(1)).unwrap();
+        dispatcher.call(&msg(2)).unwrap();
+        assert_eq!(dispatcher.stats().dispatch_queue_depth(), 2);
+        assert!(matches!(
+            dispatcher.call(&msg(3)),
+            Err(VrpnError::DispatchQueueFull)
+        ));
+        assert_eq!(dispatcher.stats().dispatch_queue_overflows(), 1);
+
+        // Make room by dropping the oldest queued message.
+        dispatcher
+            .set_dispatch_queue(DispatchQueueConfig {
+                capacity: 2,
+                overflow: OverflowPolicy::DropOldestLatestValue,
+            })
+            .unwrap();
+        dispatcher.call(&msg(3)).unwrap();
+        assert_eq!(dispatcher.stats().dispatch_queue_depth(), 2);
+        assert_eq!(dispatcher.stats().dispatch_queue_peak(), 2);
+        assert_eq!(
+            dispatcher.take_dispatch_queue_overflow(),
+            Some(DispatchQueueOverflow {
+                messages: 2,
+                policy: OverflowPolicy::DropOldestLatestValue,
+            })
+        );
+        assert_eq!(dispatcher.take_dispatch_queue_overflow(), None);
+
+        for _ in 0..3 {
+            open_gate.send(()).unwrap();
+        }
+        assert_eq!((recv(), recv()), (2, 3));
+    }
+
+    /// Counts its calls, and asks to be removed after the first if `once`.
+    struct Counter {
+        calls: Arc<Mutex<u32>>,
+        once: bool,
+        /// Dropped along with the handler.
+        _alive: Arc<()>,
+    }
+    impl Handler for Counter {
+        fn handle(&mut self, _msg: &GenericMessage) -> Result<HandlerCode> {
+            *self.calls.lock()? += 1;
+            Ok(if self.once {
+                HandlerCode::RemoveThisHandler
+            } else {
+                HandlerCode::ContinueProcessing
+            })
+        }
+    }
+
+    #[test]
+    fn handler_removes_itself() {
+        let mut dispatcher = TypeDispatcher::new();
+        let message_type = dispatcher.register_type("Test").unwrap().into_inner();
+        let alive = Arc::new(());
+        let once_calls = Arc::new(Mutex::new(0));
+        let always_calls = Arc::new(Mutex::new(0));
+        let once = dispatcher
+            .add_handler(
+                Box::new(Counter {
+                    calls: Arc::clone(&once_calls),
+                    once: true,
+                    _alive: Arc::clone(&alive),
+                }),
+                Some(message_type),
+                None,
+            )
+            .unwrap();
+        let always = dispatcher
+            .add_handler(
+                Box::new(Counter {
+                    calls: Arc::clone(&always_calls),
+                    once: false,
+                    _alive: Arc::new(()),
+                }),
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(dispatcher.has_handler(once));
+        let msg = GenericMessage::from_header_and_body(
+            MessageHeader::new(None, message_type.0, SenderId(0)),
+            GenericBody::default(),
+        );
+        for _ in 0..3 {
+            dispatcher.call(&msg).unwrap();
+        }
+        assert_eq!(*once_calls.lock().unwrap(), 1);
+        assert_eq!(*always_calls.lock().unwrap(), 3);
+        // Deregistered and dropped.
+        assert!(!dispatcher.has_handler(once));
+        assert!(dispatcher.has_handler(always));
+        assert_eq!(Arc::strong_count(&alive), 1);
+        assert!(matches!(
+            dispatcher.remove_handler(once),
+            Err(VrpnError::HandlerNotFound)
+        ));
+        dispatcher.remove_handler(always).unwrap();
+        assert!(!dispatcher.has_handler(always));
+    }
 }
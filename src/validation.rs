@@ -0,0 +1,277 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Checking the contents of incoming messages before any handler sees them.
+//!
+//! A misbehaving device (or driver) can send NaNs or denormalized quaternions,
+//! which otherwise propagate deep into an application before anything notices.
+//! Validators registered with `TypeDispatcher::add_validator` (or `Connection::add_validator`)
+//! run on every message of their type, in the order added, before its handlers;
+//! the `ValidationPolicy` decides what happens to an invalid message.
+
+use crate::{
+    buffer_unbuffer::{BufferTo, UnbufferFrom},
+    data_types::{GenericMessage, TypedMessage, TypedMessageBody},
+    Result,
+};
+use std::{borrow::Cow, convert::TryFrom, fmt};
+
+/// What to do with a message that fails validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationPolicy {
+    /// Log it, and drop the message without calling any handlers.
+    Reject,
+    /// Log it, and pass the message on unchanged.
+    Warn,
+    /// Pass on the message as repaired by `Validator::fix`,
+    /// or log and drop it if it can't be repaired.
+    Fix,
+}
+
+/// A check on the body of one type of message.
+pub trait Validator: Send + Sync {
+    type Item: TypedMessageBody + UnbufferFrom + BufferTo + fmt::Debug;
+
+    /// Describe what's wrong with the body, or return None if nothing is.
+    fn validate(&self, body: &Self::Item) -> Option<String>;
+
+    /// Repair an invalid body, for `ValidationPolicy::Fix`.
+    ///
+    /// The default can't repair anything.
+    fn fix(&self, _body: &Self::Item) -> Option<Self::Item> {
+        None
+    }
+}
+
+/// Outcome of validating one message.
+enum Verdict {
+    Pass,
+    Drop,
+    Replace(GenericMessage),
+}
+
+/// Object-safe form of `Validator`, working on generic messages.
+trait GenericValidator: Send + Sync {
+    fn check(&self, msg: &GenericMessage, policy: ValidationPolicy) -> Result<Verdict>;
+}
+
+impl<V: Validator> GenericValidator for V {
+    fn check(&self, msg: &GenericMessage, policy: ValidationPolicy) -> Result<Verdict> {
+        // Leave bodies that don't parse for the handlers to report.
+        let typed = match TypedMessage::<V::Item>::try_from(msg) {
+            Ok(typed) => typed,
+            Err(_) => return Ok(Verdict::Pass),
+        };
+        let problem = match self.validate(&typed.body) {
+            Some(problem) => problem,
+            None => return Ok(Verdict::Pass),
+        };
+        match policy {
+            ValidationPolicy::Reject => {
+                eprintln!("Dropping invalid message: {}: {:?}", problem, typed.body);
+                Ok(Verdict::Drop)
+            }
+            ValidationPolicy::Warn => {
+                eprintln!("Invalid message: {}: {:?}", problem, typed.body);
+                Ok(Verdict::Pass)
+            }
+            ValidationPolicy::Fix => match self.fix(&typed.body) {
+                Some(fixed) => Ok(Verdict::Replace(
+                    GenericMessage::from_header_and_typed_body(msg.header.clone(), &fixed)?,
+                )),
+                None => {
+                    eprintln!(
+                        "Dropping invalid message that could not be fixed: {}: {:?}",
+                        problem, typed.body
+                    );
+                    Ok(Verdict::Drop)
+                }
+            },
+        }
+    }
+}
+
+/// A validator registered for a message type, with its policy.
+pub(crate) struct ValidatorEntry {
+    validator: Box<dyn GenericValidator>,
+    policy: ValidationPolicy,
+}
+
+impl ValidatorEntry {
+    pub(crate) fn new<V: Validator + 'static>(
+        validator: Box<V>,
+        policy: ValidationPolicy,
+    ) -> ValidatorEntry {
+        ValidatorEntry { validator, policy }
+    }
+}
+
+impl fmt::Debug for ValidatorEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ValidatorEntry")
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+/// Run each validator in turn, each seeing the output of the previous one.
+///
+/// Returns the message to dispatch, or None if it was dropped.
+pub(crate) fn run_validators<'a>(
+    entries: &[ValidatorEntry],
+    msg: &'a GenericMessage,
+) -> Result<Option<Cow<'a, GenericMessage>>> {
+    let mut replaced: Option<GenericMessage> = None;
+    for entry in entries {
+        let current = replaced.as_ref().unwrap_or(msg);
+        match entry.validator.check(current, entry.policy)? {
+            Verdict::Pass => {}
+            Verdict::Drop => return Ok(None),
+            Verdict::Replace(fixed) => replaced = Some(fixed),
+        }
+    }
+    Ok(Some(match replaced {
+        Some(fixed) => Cow::Owned(fixed),
+        None => Cow::Borrowed(msg),
+    }))
+}
+
+/// Rejects analog reports with NaN or infinite channel values.
+#[cfg(feature = "analog")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FiniteAnalogValues;
+
+#[cfg(feature = "analog")]
+impl Validator for FiniteAnalogValues {
+    type Item = crate::analog::AnalogReport;
+
+    fn validate(&self, body: &Self::Item) -> Option<String> {
+        body.values
+            .iter()
+            .position(|v| !v.is_finite())
+            .map(|channel| format!("analog channel {} is not finite", channel))
+    }
+}
+
+/// Checks tracker poses for finite positions and unit-length orientations.
+///
+/// Can fix orientations that are finite but not normalized.
+#[cfg(feature = "tracker")]
+#[derive(Debug, Clone, Copy)]
+pub struct NormalizedPose {
+    /// How far the squared norm of the orientation may be from 1.
+    pub tolerance: f64,
+}
+
+#[cfg(feature = "tracker")]
+impl Default for NormalizedPose {
+    fn default() -> NormalizedPose {
+        NormalizedPose { tolerance: 1e-3 }
+    }
+}
+
+#[cfg(feature = "tracker")]
+fn is_finite_vec3(v: &crate::data_types::Vec3) -> bool {
+    v.x.is_finite() && v.y.is_finite() && v.z.is_finite()
+}
+
+#[cfg(feature = "tracker")]
+impl Validator for NormalizedPose {
+    type Item = crate::tracker::PoseReport;
+
+    fn validate(&self, body: &Self::Item) -> Option<String> {
+        if !is_finite_vec3(&body.pos) {
+            return Some("position is not finite".to_string());
+        }
+        let norm_squared = body.quat.norm_squared();
+        if !norm_squared.is_finite() {
+            return Some("orientation is not finite".to_string());
+        }
+        if (norm_squared - 1.0).abs() > self.tolerance {
+            return Some(format!(
+                "orientation is not normalized (squared norm {})",
+                norm_squared
+            ));
+        }
+        None
+    }
+
+    fn fix(&self, body: &Self::Item) -> Option<Self::Item> {
+        if !is_finite_vec3(&body.pos) {
+            return None;
+        }
+        let mut fixed = body.clone();
+        fixed.quat = body.quat.normalize()?;
+        Some(fixed)
+    }
+}
+
+#[cfg(all(test, feature = "tracker"))]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{id_types::Sensor, MessageHeader, Quat, TimeVal, Vec3},
+        handler::{Handler, HandlerCode},
+        tracker::PoseReport,
+        TypeDispatcher,
+    };
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug)]
+    struct Collect(Arc<Mutex<Vec<PoseReport>>>);
+    impl Handler for Collect {
+        fn handle(&mut self, msg: &GenericMessage) -> Result<HandlerCode> {
+            let typed = TypedMessage::<PoseReport>::try_from(msg)?;
+            self.0.lock()?.push(typed.body);
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    fn pose(pos: Vec3, quat: Quat) -> PoseReport {
+        PoseReport {
+            sensor: Sensor(0),
+            pos,
+            quat,
+        }
+    }
+
+    fn dispatch_all(policy: ValidationPolicy, poses: &[PoseReport]) -> Result<Vec<PoseReport>> {
+        let mut dispatcher = TypeDispatcher::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        dispatcher.add_validator(Box::new(NormalizedPose::default()), policy)?;
+        let message_type = dispatcher.type_id_for(PoseReport::MESSAGE_IDENTIFIER)?;
+        let sender = dispatcher.register_sender("Tracker0")?.into_inner();
+        dispatcher.add_handler(
+            Box::new(Collect(Arc::clone(&received))),
+            Some(message_type),
+            None,
+        )?;
+        for body in poses {
+            let header = MessageHeader::new(Some(TimeVal::default()), message_type.0, sender.0);
+            dispatcher.call(&GenericMessage::from_header_and_typed_body(header, body)?)?;
+        }
+        let received = received.lock()?.clone();
+        Ok(received)
+    }
+
+    #[test]
+    fn policies() {
+        let good = pose(Vec3::new(1.0, 2.0, 3.0), Quat::identity());
+        let unnormalized = pose(Vec3::default(), Quat::new(2.0, 0.0, 0.0, 0.0));
+        let nan = pose(Vec3::new(f64::NAN, 0.0, 0.0), Quat::identity());
+        let poses = [good.clone(), unnormalized.clone(), nan.clone()];
+
+        assert_eq!(
+            dispatch_all(ValidationPolicy::Reject, &poses).unwrap(),
+            vec![good.clone()]
+        );
+        let warned = dispatch_all(ValidationPolicy::Warn, &poses).unwrap();
+        assert_eq!(warned.len(), 3);
+        assert!(warned[2].pos.x.is_nan());
+        assert_eq!(
+            dispatch_all(ValidationPolicy::Fix, &poses).unwrap(),
+            vec![good, pose(Vec3::default(), Quat::identity())]
+        );
+    }
+}
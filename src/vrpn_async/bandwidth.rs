@@ -0,0 +1,48 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Bounding the rate at which messages are sent, e.g. to share a constrained link.
+
+/// Limits on the rate an endpoint sends user messages. Unlimited by default.
+///
+/// Each limit is a token bucket holding up to one second's worth, so a burst after a quiet
+/// period goes out at once. System messages (descriptions, and the like) are never delayed
+/// nor counted: they are few, and the connection depends on them.
+///
+/// ```
+/// use vrpn::vrpn_async::BandwidthLimit;
+/// let limit = BandwidthLimit::default()
+///     .with_bytes_per_sec(64 * 1024)
+///     .with_messages_per_sec(500);
+/// assert!(!limit.is_unlimited());
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BandwidthLimit {
+    /// Bytes per second on the wire, including message headers and padding.
+    pub bytes_per_sec: Option<u32>,
+    /// Messages per second.
+    pub messages_per_sec: Option<u32>,
+}
+
+impl BandwidthLimit {
+    /// Send no more than this many bytes per second.
+    pub fn with_bytes_per_sec(self, rate: u32) -> BandwidthLimit {
+        BandwidthLimit {
+            bytes_per_sec: Some(rate),
+            ..self
+        }
+    }
+
+    /// Send no more than this many messages per second.
+    pub fn with_messages_per_sec(self, rate: u32) -> BandwidthLimit {
+        BandwidthLimit {
+            messages_per_sec: Some(rate),
+            ..self
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.bytes_per_sec.is_none() && self.messages_per_sec.is_none()
+    }
+}
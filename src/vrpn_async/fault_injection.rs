@@ -0,0 +1,286 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! A transport wrapper that misbehaves on purpose, for testing how the rest of the crate copes.
+//!
+//! `FaultyTransport` wraps anything `AsyncRead` and/or `AsyncWrite`, and can
+//! split reads and writes into small pieces, delay them, silently drop writes,
+//! and flip bits in what it reads. Faults are chosen by a seeded generator,
+//! so a failing run can be repeated exactly.
+//!
+//! ```
+//! use futures::{executor::block_on, io::Cursor, AsyncReadExt, AsyncWriteExt};
+//! use vrpn::vrpn_async::fault_injection::{FaultConfig, FaultyTransport};
+//! let config = FaultConfig::new(1).with_max_chunk(3).with_delay_chance(0.5);
+//! let mut writer = FaultyTransport::new(Cursor::new(Vec::new()), config);
+//! block_on(writer.write_all(b"hello world")).unwrap();
+//! let mut reader = FaultyTransport::new(Cursor::new(writer.into_inner().into_inner()), config);
+//! let mut read = Vec::new();
+//! block_on(reader.read_to_end(&mut read)).unwrap();
+//! assert_eq!(read, b"hello world");
+//! ```
+
+use futures::{ready, AsyncRead, AsyncWrite};
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+/// Which faults a `FaultyTransport` injects, and how often.
+///
+/// Chances are probabilities from 0 (never) to 1 (every time). Everything is off by default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultConfig {
+    /// Seed for choosing faults.
+    pub seed: u64,
+    /// Read and write at most this many bytes at a time.
+    pub max_chunk: Option<usize>,
+    /// Chance that a read or write is not ready the first time it's polled.
+    ///
+    /// The task is woken right away, so this delays by an executor round trip, not a fixed time.
+    pub delay_chance: f64,
+    /// Chance that a write is discarded, while reporting success.
+    pub drop_chance: f64,
+    /// Chance that a read has one bit flipped.
+    pub bit_error_chance: f64,
+}
+
+impl FaultConfig {
+    /// No faults, with the given seed.
+    pub fn new(seed: u64) -> FaultConfig {
+        FaultConfig {
+            seed,
+            max_chunk: None,
+            delay_chance: 0.0,
+            drop_chance: 0.0,
+            bit_error_chance: 0.0,
+        }
+    }
+
+    pub fn with_max_chunk(self, max_chunk: usize) -> FaultConfig {
+        FaultConfig {
+            max_chunk: Some(max_chunk.max(1)),
+            ..self
+        }
+    }
+
+    pub fn with_delay_chance(self, delay_chance: f64) -> FaultConfig {
+        FaultConfig {
+            delay_chance,
+            ..self
+        }
+    }
+
+    pub fn with_drop_chance(self, drop_chance: f64) -> FaultConfig {
+        FaultConfig {
+            drop_chance,
+            ..self
+        }
+    }
+
+    pub fn with_bit_error_chance(self, bit_error_chance: f64) -> FaultConfig {
+        FaultConfig {
+            bit_error_chance,
+            ..self
+        }
+    }
+}
+
+impl Default for FaultConfig {
+    fn default() -> FaultConfig {
+        FaultConfig::new(0)
+    }
+}
+
+/// How many faults of each kind a `FaultyTransport` has injected.
+///
+/// Shared, so it can still be checked once the transport has been moved into an endpoint.
+#[derive(Debug, Default)]
+pub struct FaultCounts {
+    pub delays: AtomicU64,
+    pub drops: AtomicU64,
+    pub bit_errors: AtomicU64,
+}
+
+impl FaultCounts {
+    fn record(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Wraps a transport, injecting faults into reads and writes as configured.
+#[derive(Debug)]
+pub struct FaultyTransport<T> {
+    inner: T,
+    config: FaultConfig,
+    rng: u64,
+    /// Set while a delayed read or write is waiting to be polled again.
+    delayed: bool,
+    counts: Arc<FaultCounts>,
+}
+
+impl<T> FaultyTransport<T> {
+    pub fn new(inner: T, config: FaultConfig) -> FaultyTransport<T> {
+        FaultyTransport {
+            inner,
+            config,
+            // xorshift gets stuck at zero.
+            rng: config.seed | 1,
+            delayed: false,
+            counts: Arc::default(),
+        }
+    }
+
+    /// The faults injected so far.
+    pub fn counts(&self) -> Arc<FaultCounts> {
+        Arc::clone(&self.counts)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn happens(&mut self, chance: f64) -> bool {
+        // The top 53 bits, as a fraction in [0, 1).
+        let sample = (self.next_random() >> 11) as f64 / (1u64 << 53) as f64;
+        sample < chance
+    }
+
+    /// Whether to return `Pending` this time around.
+    fn delay(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.delayed {
+            self.delayed = false;
+            return false;
+        }
+        if self.happens(self.config.delay_chance) {
+            self.delayed = true;
+            FaultCounts::record(&self.counts.delays);
+            cx.waker().wake_by_ref();
+            return true;
+        }
+        false
+    }
+
+    fn chunk_len(&self, len: usize) -> usize {
+        self.config.max_chunk.map_or(len, |max| len.min(max))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for FaultyTransport<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.delay(cx) {
+            return Poll::Pending;
+        }
+        let len = this.chunk_len(buf.len());
+        let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..len]))?;
+        if n > 0 && this.happens(this.config.bit_error_chance) {
+            let bit = (this.next_random() % (n as u64 * 8)) as usize;
+            buf[bit / 8] ^= 1 << (bit % 8);
+            FaultCounts::record(&this.counts.bit_errors);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for FaultyTransport<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.delay(cx) {
+            return Poll::Pending;
+        }
+        let len = this.chunk_len(buf.len());
+        if len > 0 && this.happens(this.config.drop_chance) {
+            FaultCounts::record(&this.counts.drops);
+            return Poll::Ready(Ok(len));
+        }
+        Pin::new(&mut this.inner).poll_write(cx, &buf[..len])
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::Cursor, AsyncReadExt, AsyncWriteExt};
+
+    fn round_trip(data: &[u8], write_config: FaultConfig, read_config: FaultConfig) -> Vec<u8> {
+        let mut writer = FaultyTransport::new(Cursor::new(Vec::new()), write_config);
+        let mut read = Vec::new();
+        block_on(async {
+            writer.write_all(data).await?;
+            let written = writer.into_inner().into_inner();
+            let mut reader = FaultyTransport::new(Cursor::new(written), read_config);
+            reader.read_to_end(&mut read).await
+        })
+        .unwrap_or_default();
+        read
+    }
+
+    #[test]
+    fn faults() {
+        let data: Vec<u8> = (0..=255).collect();
+        let benign = FaultConfig::new(7).with_max_chunk(5).with_delay_chance(0.5);
+        assert_eq!(round_trip(&data, benign, benign), data);
+
+        let dropping = FaultConfig::new(7).with_max_chunk(5).with_drop_chance(0.2);
+        let read = round_trip(&data, dropping, FaultConfig::default());
+        assert!(read.len() < data.len());
+        assert_eq!(read.len() % 5, data.len() % 5);
+
+        let flipping = FaultConfig::new(7)
+            .with_max_chunk(5)
+            .with_bit_error_chance(0.2);
+        let read = round_trip(&data, FaultConfig::default(), flipping);
+        assert_eq!(read.len(), data.len());
+        assert_ne!(read, data);
+
+        // The same seed injects the same faults.
+        assert_eq!(round_trip(&data, FaultConfig::default(), flipping), read);
+    }
+
+    #[test]
+    fn counts() {
+        let mut transport = FaultyTransport::new(
+            Cursor::new(Vec::new()),
+            FaultConfig::new(3).with_max_chunk(1).with_drop_chance(0.5),
+        );
+        let counts = transport.counts();
+        block_on(transport.write_all(&[0u8; 100])).unwrap();
+        let drops = counts.drops.load(Ordering::Relaxed);
+        assert!(drops > 0);
+        assert_eq!(
+            transport.into_inner().into_inner().len() as u64,
+            100 - drops
+        );
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Opt-in settings that trade CPU time (or completeness) for lower latency.
+
+#[cfg(not(target_arch = "wasm32"))]
+use socket2::SockRef;
+#[cfg(not(target_arch = "wasm32"))]
+use std::io;
+use std::time::Duration;
+
+/// Options for latency-critical installations. Everything is off (OS/runtime default) by default.
+///
+/// ```
+/// use std::time::Duration;
+/// use vrpn::vrpn_async::LowLatencyConfig;
+/// let config = LowLatencyConfig::default()
+///     .with_busy_poll(Duration::from_micros(200))
+///     .with_recv_buffer_size(256 * 1024);
+/// assert!(config.busy_poll.is_some());
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LowLatencyConfig {
+    /// Kernel receive buffer size to request for sockets.
+    pub recv_buffer_size: Option<usize>,
+    /// Kernel send buffer size to request for sockets.
+    pub send_buffer_size: Option<usize>,
+    /// When a read would block, keep retrying for up to this long before yielding to the executor.
+    ///
+    /// Avoids a wakeup (and possibly a thread switch) for data arriving shortly,
+    /// at the cost of spinning a CPU core while waiting.
+    pub busy_poll: Option<Duration>,
+    /// Drop outgoing `ClassOfService::LOW_LATENCY` messages not written within this long of being queued.
+    ///
+    /// When the link stalls, stale pose data is worse than none: this keeps a backlog
+    /// of it from being sent once the link recovers. Dropped messages are counted
+    /// in `ConnectionStats::expired_messages`.
+    pub max_send_age: Option<Duration>,
+}
+
+impl LowLatencyConfig {
+    /// Spin for up to `budget` before yielding when no data is available.
+    pub fn with_busy_poll(self, budget: Duration) -> LowLatencyConfig {
+        LowLatencyConfig {
+            busy_poll: Some(budget),
+            ..self
+        }
+    }
+
+    /// Request a kernel receive buffer of the given size.
+    pub fn with_recv_buffer_size(self, size: usize) -> LowLatencyConfig {
+        LowLatencyConfig {
+            recv_buffer_size: Some(size),
+            ..self
+        }
+    }
+
+    /// Request a kernel send buffer of the given size.
+    pub fn with_send_buffer_size(self, size: usize) -> LowLatencyConfig {
+        LowLatencyConfig {
+            send_buffer_size: Some(size),
+            ..self
+        }
+    }
+
+    /// Drop low-latency messages that could not be sent within `max_age`.
+    pub fn with_max_send_age(self, max_age: Duration) -> LowLatencyConfig {
+        LowLatencyConfig {
+            max_send_age: Some(max_age),
+            ..self
+        }
+    }
+
+    /// Apply the socket-level settings (buffer sizes) to a socket.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn apply_to_socket(&self, sock: SockRef<'_>) -> io::Result<()> {
+        if let Some(size) = self.recv_buffer_size {
+            sock.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            sock.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn socket_buffers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sock = SockRef::from(&listener);
+        let original = sock.recv_buffer_size().unwrap();
+        LowLatencyConfig::default()
+            .apply_to_socket(SockRef::from(&listener))
+            .unwrap();
+        assert_eq!(sock.recv_buffer_size().unwrap(), original);
+
+        // The OS may round (or double) the request.
+        let requested = original * 2;
+        LowLatencyConfig::default()
+            .with_recv_buffer_size(requested)
+            .apply_to_socket(SockRef::from(&listener))
+            .unwrap();
+        assert!(sock.recv_buffer_size().unwrap() > original);
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! The writing counterpart to `MessageStream`, for any `AsyncWrite`.
+
+use crate::{
+    buffer_unbuffer::BytesMutExtras, data_types::SequencedGenericMessage, Result, VrpnError,
+};
+use bytes::{Buf, BytesMut};
+use futures::{
+    io::{ReadHalf, WriteHalf},
+    ready, task, AsyncRead, AsyncReadExt, AsyncWrite, Sink,
+};
+use pin_project_lite::pin_project;
+use std::pin::Pin;
+
+use super::MessageStream;
+
+/// Buffered bytes beyond which `poll_ready` writes before accepting more messages.
+const HIGH_WATER_MARK: usize = 64 * 1024;
+
+pin_project! {
+    /// Sink serializing messages to an `AsyncWrite`, such as a TCP, TLS, or Unix socket stream.
+    ///
+    /// Messages are buffered: flush to make sure they've been written.
+    #[derive(Debug)]
+    pub struct MessageSink<W> {
+        #[pin]
+        writer: W,
+        buf: BytesMut,
+    }
+}
+
+impl<W: AsyncWrite> MessageSink<W> {
+    pub fn new(writer: W) -> MessageSink<W> {
+        MessageSink {
+            writer,
+            buf: BytesMut::with_capacity(2048),
+        }
+    }
+
+    /// Get the underlying writer back, discarding any unflushed messages.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Write out buffered bytes, without flushing the writer.
+    fn poll_write_buf(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Result<()>> {
+        let mut this = self.project();
+        while !this.buf.is_empty() {
+            let n = ready!(this.writer.as_mut().poll_write(cx, this.buf))?;
+            if n == 0 {
+                return task::Poll::Ready(Err(VrpnError::EndpointClosed));
+            }
+            this.buf.advance(n);
+        }
+        task::Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite> Sink<SequencedGenericMessage> for MessageSink<W> {
+    type Error = VrpnError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Result<()>> {
+        if self.buf.len() >= HIGH_WATER_MARK {
+            ready!(self.poll_write_buf(cx))?;
+        }
+        task::Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SequencedGenericMessage) -> Result<()> {
+        let this = self.project();
+        this.buf.reserve_and_buffer(&item)?;
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Result<()>> {
+        ready!(self.as_mut().poll_write_buf(cx))?;
+        ready!(self.project().writer.poll_flush(cx))?;
+        task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        ready!(self.project().writer.poll_close(cx))?;
+        task::Poll::Ready(Ok(()))
+    }
+}
+
+pub trait AsyncWriteMessagesExt: AsyncWrite + Sized {
+    /// Adapt writer to serialize messages.
+    fn message_sink(self) -> MessageSink<Self>;
+}
+
+impl<T: AsyncWrite> AsyncWriteMessagesExt for T {
+    fn message_sink(self) -> MessageSink<Self> {
+        MessageSink::new(self)
+    }
+}
+
+/// Apply message framing to a bidirectional stream of any kind,
+/// returning a stream of incoming messages and a sink for outgoing ones.
+///
+/// The two halves may be used from different tasks.
+pub fn framed_messages<T: AsyncRead + AsyncWrite>(
+    io: T,
+) -> (MessageStream<ReadHalf<T>>, MessageSink<WriteHalf<T>>) {
+    let (reader, writer) = io.split();
+    (MessageStream::new(reader), MessageSink::new(writer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{
+            id_types::{MessageTypeId, SenderId, SequenceNumber},
+            GenericBody, GenericMessage, Message, MessageHeader, TimeVal,
+        },
+        vrpn_async::AsyncReadMessagesExt,
+    };
+    use bytes::Bytes;
+    use futures::{executor::block_on, io::Cursor, SinkExt, StreamExt};
+
+    fn test_message(seq: u32) -> SequencedGenericMessage {
+        GenericMessage::from_header_and_body(
+            MessageHeader::new(Some(TimeVal::default()), MessageTypeId(1), SenderId(2)),
+            GenericBody::new(Bytes::from(vec![seq as u8; seq as usize])),
+        )
+        .into_sequenced_message(SequenceNumber(seq))
+    }
+
+    #[test]
+    fn roundtrip_in_memory() {
+        let messages: Vec<_> = (0..10).map(test_message).collect();
+        let mut sink = Cursor::new(Vec::new()).message_sink();
+        block_on(async {
+            for msg in &messages {
+                sink.feed(msg.clone()).await.unwrap();
+            }
+            sink.flush().await.unwrap();
+        });
+        let written = sink.into_inner().into_inner();
+
+        let decoded: Vec<_> = block_on(
+            Cursor::new(written)
+                .messages()
+                .map(|msg| msg.unwrap())
+                .collect(),
+        );
+        assert_eq!(decoded, messages);
+    }
+
+    #[cfg(all(unix, feature = "async-std"))]
+    #[test]
+    fn duplex_unix_socket() {
+        use async_std::os::unix::net::UnixStream;
+        block_on(async {
+            let (a, b) = UnixStream::pair().unwrap();
+            let (mut a_rx, mut a_tx) = framed_messages(a);
+            let (mut b_rx, mut b_tx) = framed_messages(b);
+
+            a_tx.send(test_message(1)).await.unwrap();
+            b_tx.send(test_message(2)).await.unwrap();
+            assert_eq!(b_rx.next().await.unwrap().unwrap(), test_message(1));
+            assert_eq!(a_rx.next().await.unwrap().unwrap(), test_message(2));
+
+            // Dropping one side ends the other's stream.
+            drop(a_rx);
+            drop(a_tx);
+            assert!(b_rx.next().await.is_none());
+        });
+    }
+}
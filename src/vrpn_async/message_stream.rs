@@ -2,10 +2,15 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-use std::borrow::BorrowMut;
+use std::{borrow::BorrowMut, time::Duration};
 
-use crate::{buffer_unbuffer::BufferUnbufferError, data_types::SequencedGenericMessage, Result};
-use bytes::{Buf, BytesMut};
+use crate::{
+    buffer_unbuffer::BufferUnbufferError,
+    clock::Instant,
+    codec::{MessageDecoder, MessageSizeLimit},
+    data_types::SequencedGenericMessage,
+    Result,
+};
 use futures::{ready, task, AsyncRead, AsyncReadExt, Stream};
 use pin_project_lite::pin_project;
 
@@ -15,6 +20,8 @@ enum MessageStreamState {
     Reading,
     Parsing,
     Error,
+    /// The underlying stream reached end of file.
+    Closed,
 }
 pin_project! {
     #[derive(Debug)]
@@ -23,19 +30,56 @@ pin_project! {
         stream: R,
         state: MessageStreamState,
         mini_buf: [u8; 1024],
-        buf: BytesMut,
+        decoder: MessageDecoder,
+        busy_poll: Option<Duration>,
+        skipped: u64,
+        read_at: Option<Instant>,
     }
 }
 
 impl<'a, R: AsyncReadExt + Unpin> MessageStream<R> {
     pub fn new(stream: R) -> MessageStream<R> {
+        Self::with_limit(stream, MessageSizeLimit::default())
+    }
+
+    /// Create a message stream enforcing the given limit on incoming message size.
+    pub fn with_limit(stream: R, limit: MessageSizeLimit) -> MessageStream<R> {
         MessageStream {
             stream,
             state: MessageStreamState::Reading,
             mini_buf: [0u8; 1024],
-            buf: BytesMut::with_capacity(2048),
+            decoder: MessageDecoder::with_capacity(2048).with_limit(limit),
+            busy_poll: None,
+            skipped: 0,
+            read_at: None,
         }
     }
+
+    /// When no data is available, retry reading for up to `budget` before yielding.
+    ///
+    /// See `LowLatencyConfig::busy_poll`.
+    pub fn with_busy_poll(self, budget: Option<Duration>) -> MessageStream<R> {
+        MessageStream {
+            busy_poll: budget,
+            ..self
+        }
+    }
+
+    /// Number of bytes read but not yet decoded into a complete message.
+    pub fn buffered_len(&self) -> usize {
+        self.decoder.buffered_len()
+    }
+
+    /// Number of oversized messages skipped, with `OversizePolicy::Skip`.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// When the last read returning data did so: the arrival time of the messages
+    /// decoded from it, and of the last message yielded.
+    pub fn read_at(&self) -> Option<Instant> {
+        self.read_at
+    }
 }
 
 impl<R> Stream for MessageStream<R>
@@ -54,14 +98,28 @@ where
             // println!("State: {:?}", state);
             match state {
                 MessageStreamState::Reading => {
-                    match ready!(pinned
+                    let mut poll = pinned
                         .stream
                         .as_mut()
-                        .poll_read(cx, pinned.mini_buf.borrow_mut()))
-                    {
+                        .poll_read(cx, pinned.mini_buf.borrow_mut());
+                    if let (task::Poll::Pending, Some(budget)) = (&poll, *pinned.busy_poll) {
+                        let start = Instant::now();
+                        while poll.is_pending() && start.elapsed() < budget {
+                            std::hint::spin_loop();
+                            poll = pinned
+                                .stream
+                                .as_mut()
+                                .poll_read(cx, pinned.mini_buf.borrow_mut());
+                        }
+                    }
+                    match ready!(poll) {
+                        Ok(0) => {
+                            *state = MessageStreamState::Closed;
+                        }
                         Ok(n) => {
                             // println!("Read {} bytes from stream", n);
-                            pinned.buf.extend_from_slice(&pinned.mini_buf[..n]);
+                            *pinned.read_at = Some(Instant::now());
+                            pinned.decoder.extend_from_slice(&pinned.mini_buf[..n]);
                             *state = MessageStreamState::Parsing;
                         }
                         Err(e) => {
@@ -70,36 +128,27 @@ where
                         }
                     }
                 }
-                MessageStreamState::Parsing => {
-                    let mut existing_bytes = std::io::Cursor::new(&*pinned.buf);
-
-                    match SequencedGenericMessage::try_read_from_buf(&mut existing_bytes) {
-                        Ok(sgm) => {
-                            // consume the bytes from the original buffer.
-                            let consumed = pinned.buf.remaining() - existing_bytes.remaining();
-                            pinned.buf.advance(consumed);
-                            // println!(
-                            //     "consumed {} bytes, {} remain in buffer",
-                            //     consumed,
-                            //     pinned.buf.remaining()
-                            // );
-                            // println!("{:?}", pinned.buf);
-
-                            // Queue an immediate wakeup since the buf may contain more.
-                            cx.waker().wake_by_ref();
-                            return task::Poll::Ready(Some(Ok(sgm)));
-                        }
-                        Err(BufferUnbufferError::NeedMoreData(_requirement)) => {
-                            // println!("need more data: {} bytes", _requirement);
-                            *state = MessageStreamState::Reading;
-                        }
-                        Err(e) => {
-                            *state = MessageStreamState::Error;
-                            return task::Poll::Ready(Some(Err(e.into())));
-                        }
+                MessageStreamState::Parsing => match pinned.decoder.decode_next() {
+                    Ok(Some(sgm)) => {
+                        // Queue an immediate wakeup since the buf may contain more.
+                        cx.waker().wake_by_ref();
+                        return task::Poll::Ready(Some(Ok(sgm)));
                     }
-                }
-                MessageStreamState::Error => {
+                    Ok(None) => {
+                        *state = MessageStreamState::Reading;
+                    }
+                    Err(BufferUnbufferError::MessageTooLarge { .. })
+                        if pinned.decoder.is_skipping() =>
+                    {
+                        // Recoverable: the decoder discards the message as it arrives.
+                        *pinned.skipped += 1;
+                    }
+                    Err(e) => {
+                        *state = MessageStreamState::Error;
+                        return task::Poll::Ready(Some(Err(e.into())));
+                    }
+                },
+                MessageStreamState::Error | MessageStreamState::Closed => {
                     // once in this state we never escape
                     return task::Poll::Ready(None);
                 }
@@ -110,6 +159,9 @@ where
 
 pub trait AsyncReadMessagesExt: AsyncRead + Unpin + Sized {
     fn messages(self) -> MessageStream<Self>;
+
+    /// Adapt stream to parse messages, enforcing a limit on message size.
+    fn messages_with_limit(self, limit: MessageSizeLimit) -> MessageStream<Self>;
 }
 
 impl<T: AsyncRead + Unpin> AsyncReadMessagesExt for T {
@@ -117,4 +169,91 @@ impl<T: AsyncRead + Unpin> AsyncReadMessagesExt for T {
     fn messages(self) -> MessageStream<Self> {
         MessageStream::new(self)
     }
+
+    fn messages_with_limit(self, limit: MessageSizeLimit) -> MessageStream<Self> {
+        MessageStream::with_limit(self, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, StreamExt};
+    use std::{io, pin::Pin};
+
+    /// A reader that is not ready the first few times it's polled.
+    struct SlowReader {
+        not_ready: usize,
+        data: io::Cursor<Vec<u8>>,
+    }
+
+    impl AsyncRead for SlowReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut task::Context<'_>,
+            buf: &mut [u8],
+        ) -> task::Poll<io::Result<usize>> {
+            if self.not_ready > 0 {
+                self.not_ready -= 1;
+                cx.waker().wake_by_ref();
+                return task::Poll::Pending;
+            }
+            task::Poll::Ready(io::Read::read(&mut self.data, buf))
+        }
+    }
+
+    const MSG: [u8; 40] = hex!(
+        "00 00 00 25 5b eb 33 2e 00 0c 58 b1 00 00 00 01 ff ff ff ff 00 00 00 01"
+        "00 00 00 09 54 72 61 63 6b 65 72 30 00 00 00 00");
+
+    #[test]
+    fn busy_poll() {
+        let waker = futures::task::noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        let reader = || SlowReader {
+            not_ready: 3,
+            data: io::Cursor::new(MSG.to_vec()),
+        };
+
+        // Without busy-polling, we yield to the executor.
+        let mut stream = MessageStream::new(reader());
+        assert!(stream.poll_next_unpin(&mut cx).is_pending());
+        assert!(block_on(stream.next()).unwrap().is_ok());
+
+        // With it, the first poll spins until the data is there.
+        let mut stream = MessageStream::new(reader()).with_busy_poll(Some(Duration::from_secs(10)));
+        match stream.poll_next_unpin(&mut cx) {
+            task::Poll::Ready(Some(Ok(msg))) => assert_eq!(msg.sequence_number.0, 1),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_at() {
+        let mut stream = MessageStream::new(SlowReader {
+            not_ready: 3,
+            data: io::Cursor::new(MSG.to_vec()),
+        });
+        assert_eq!(stream.read_at(), None);
+        let before = Instant::now();
+        assert!(block_on(stream.next()).unwrap().is_ok());
+        // Once the data arrived, not when we started waiting for it.
+        assert!(stream.read_at().unwrap() >= before);
+    }
+
+    #[test]
+    fn corrupted_input() {
+        use crate::vrpn_async::fault_injection::{FaultConfig, FaultyTransport};
+        let data: Vec<u8> = MSG.iter().cycle().take(MSG.len() * 50).copied().collect();
+        for seed in 0..20 {
+            let config = FaultConfig::new(seed)
+                .with_max_chunk(13)
+                .with_bit_error_chance(0.1);
+            let reader = FaultyTransport::new(futures::io::Cursor::new(data.clone()), config);
+            // Corruption may end the stream early, but must not hang or panic.
+            let received = block_on(MessageStream::new(reader).collect::<Vec<_>>());
+            assert!(received.len() <= 50);
+            assert!(received.iter().take_while(|msg| msg.is_ok()).count() >= 1);
+        }
+    }
 }
@@ -2,7 +2,20 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-pub mod bytes_mut_reader;
+pub mod bandwidth;
 pub mod cookie;
+pub mod fault_injection;
+pub mod low_latency;
+pub mod message_sink;
 pub mod message_stream;
+pub mod parse_errors;
+pub mod split_by_sender;
+pub mod typed_message_sink;
+pub use crate::async_io::{read_into_bytes_mut, read_n_into_bytes_mut, BytesMutReader};
+pub use bandwidth::BandwidthLimit;
+pub use low_latency::LowLatencyConfig;
+pub use message_sink::{framed_messages, AsyncWriteMessagesExt, MessageSink};
 pub use message_stream::{AsyncReadMessagesExt, MessageStream};
+pub use parse_errors::ParseErrorLimit;
+pub use split_by_sender::{split_by_sender, SplitBySender};
+pub use typed_message_sink::TypedMessageSink;
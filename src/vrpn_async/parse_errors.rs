@@ -0,0 +1,66 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Quarantining a remote end that keeps sending data we can't decode.
+
+use std::{net::SocketAddr, time::Duration};
+
+/// How many parse errors an endpoint may see within a window before it is quarantined:
+/// shut down, and reported as `ConnectionEvent::EndpointQuarantined`.
+///
+/// Most parse errors close an endpoint right away, but some are survived by skipping
+/// the message: those over a `MessageSizeLimit` with `OversizePolicy::Skip`, and those failing
+/// a strict type check. A remote end sending little else would keep us busy decoding
+/// and discarding without end, so past this limit it is cut off instead.
+///
+/// The default allows 1000 errors within a second: far more than a misbehaving device
+/// reporting at any usual rate, but far less than a stream of garbage.
+///
+/// ```
+/// use std::time::Duration;
+/// use vrpn::vrpn_async::ParseErrorLimit;
+/// let limit = ParseErrorLimit::new(10, Duration::from_secs(5));
+/// assert!(limit.validate().is_ok());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseErrorLimit {
+    /// Most parse errors allowed within the window.
+    pub max_errors: u32,
+    /// How far back errors are counted.
+    pub window: Duration,
+}
+
+impl Default for ParseErrorLimit {
+    fn default() -> ParseErrorLimit {
+        ParseErrorLimit {
+            max_errors: 1000,
+            window: Duration::from_secs(1),
+        }
+    }
+}
+
+impl ParseErrorLimit {
+    pub fn new(max_errors: u32, window: Duration) -> ParseErrorLimit {
+        ParseErrorLimit { max_errors, window }
+    }
+
+    /// Check that the window is not empty.
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.window.is_zero() {
+            return Err(crate::VrpnError::Config(
+                "parse error window must not be zero".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// An endpoint was shut down for exceeding its `ParseErrorLimit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quarantined {
+    /// The address of the remote end, if connected over TCP.
+    pub peer: Option<SocketAddr>,
+    /// The limit exceeded.
+    pub limit: ParseErrorLimit,
+}
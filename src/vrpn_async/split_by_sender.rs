@@ -0,0 +1,208 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Demultiplexing a stream of messages into a stream per sender (device).
+//!
+//! `split_by_sender` yields a new sub-stream each time a sender is first seen,
+//! so each device can get its own processing pipeline, e.g. a task per sub-stream.
+//!
+//! # Backpressure
+//!
+//! Each sub-stream buffers up to a fixed number of messages.
+//! Once a sub-stream is full, the splitter stops reading its input until there is room,
+//! so one slow consumer stalls every sender, rather than messages being dropped
+//! or buffered without bound. Consume every sub-stream promptly, or drop those not needed:
+//! messages for a dropped sub-stream are discarded.
+//!
+//! Sub-streams only receive messages while the splitter itself is being polled,
+//! and end once its input ends.
+
+use crate::data_types::{id_types::SenderId, Message};
+use futures::{
+    channel::mpsc::{self, Receiver, Sender},
+    ready, Stream,
+};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Stream yielding a sub-stream for each sender seen in the input: see `split_by_sender`.
+pub struct SplitBySender<S, M> {
+    stream: Pin<Box<S>>,
+    capacity: usize,
+    channels: HashMap<SenderId, Sender<M>>,
+    /// A message waiting for room in its sender's sub-stream.
+    pending: Option<M>,
+}
+
+/// Split a stream of messages into a sub-stream per sender,
+/// each buffering up to `capacity` messages.
+///
+/// Yields each sender with its sub-stream the first time the sender appears:
+/// see the module documentation for how the sub-streams make progress.
+pub fn split_by_sender<S, M>(stream: S, capacity: usize) -> SplitBySender<S, M>
+where
+    S: Stream<Item = M>,
+    M: Message,
+{
+    SplitBySender {
+        stream: Box::pin(stream),
+        capacity,
+        channels: HashMap::new(),
+        pending: None,
+    }
+}
+
+impl<S, M> SplitBySender<S, M> {
+    /// Number of senders seen so far.
+    pub fn senders(&self) -> usize {
+        self.channels.len()
+    }
+}
+
+impl<S, M> fmt::Debug for SplitBySender<S, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SplitBySender")
+            .field("capacity", &self.capacity)
+            .field("senders", &self.channels.keys().collect::<Vec<_>>())
+            .field("pending", &self.pending.is_some())
+            .finish()
+    }
+}
+
+// No field is structurally pinned: the input stream is boxed.
+impl<S, M> Unpin for SplitBySender<S, M> {}
+
+impl<S, M> Stream for SplitBySender<S, M>
+where
+    S: Stream<Item = M>,
+    M: Message,
+{
+    type Item = (SenderId, Receiver<M>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            let msg = match this.pending.take() {
+                Some(msg) => msg,
+                None => match ready!(this.stream.as_mut().poll_next(cx)) {
+                    Some(msg) => msg,
+                    None => {
+                        // Ends the sub-streams, once they've yielded what they have.
+                        this.channels.clear();
+                        return Poll::Ready(None);
+                    }
+                },
+            };
+            let sender = msg.header_ref().sender;
+            match this.channels.entry(sender) {
+                Entry::Occupied(mut entry) => {
+                    let tx = entry.get_mut();
+                    match tx.poll_ready(cx) {
+                        // An error here means the sub-stream was dropped: discard the message.
+                        Poll::Ready(Ok(())) => {
+                            let _ = tx.start_send(msg);
+                        }
+                        Poll::Ready(Err(_)) => {}
+                        Poll::Pending => {
+                            this.pending = Some(msg);
+                            return Poll::Pending;
+                        }
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    let (mut tx, rx) = mpsc::channel(this.capacity);
+                    // A new channel always has room for one message.
+                    let _ = tx.try_send(msg);
+                    entry.insert(tx);
+                    return Poll::Ready(Some((sender, rx)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{
+        id_types::MessageTypeId, GenericBody, GenericMessage, MessageHeader, TimeVal,
+    };
+    use bytes::Bytes;
+    use futures::{executor::block_on, stream, FutureExt, StreamExt};
+
+    fn message(sender: i32, body: &'static [u8]) -> GenericMessage {
+        GenericMessage::from_header_and_body(
+            MessageHeader::new(Some(TimeVal::default()), MessageTypeId(1), SenderId(sender)),
+            GenericBody::new(Bytes::from_static(body)),
+        )
+    }
+
+    #[test]
+    fn per_sender() {
+        let messages = vec![
+            message(0, b"a"),
+            message(1, b"b"),
+            message(0, b"c"),
+            message(2, b"d"),
+            message(1, b"e"),
+        ];
+        let splitter = split_by_sender(stream::iter(messages.clone()), messages.len());
+        let subs: Vec<_> = block_on(splitter.collect());
+        let senders: Vec<_> = subs.iter().map(|(sender, _)| *sender).collect();
+        assert_eq!(senders, vec![SenderId(0), SenderId(1), SenderId(2)]);
+
+        // The input ended, so each sub-stream ends after its messages.
+        for (sender, rx) in subs {
+            let expected: Vec<_> = messages
+                .iter()
+                .filter(|msg| msg.header.sender == sender)
+                .cloned()
+                .collect();
+            assert_eq!(block_on(rx.collect::<Vec<_>>()), expected);
+        }
+    }
+
+    #[test]
+    fn backpressure() {
+        let messages: Vec<_> = (0..10).map(|_| message(0, b"x")).collect();
+        let mut splitter = split_by_sender(stream::iter(messages).chain(stream::pending()), 1);
+        let (_, mut rx) = block_on(splitter.next()).unwrap();
+
+        // The sub-stream fills up, and the splitter stops reading.
+        assert!(splitter.next().now_or_never().is_none());
+        assert!(splitter.pending.is_some());
+        let mut received = 0;
+        while rx.next().now_or_never().is_some() {
+            received += 1;
+        }
+        assert!(received > 0 && received < 10);
+
+        // Once there's room, it resumes.
+        assert!(splitter.next().now_or_never().is_none());
+        while rx.next().now_or_never().is_some() {
+            received += 1;
+        }
+        assert!(received > 2);
+    }
+
+    #[test]
+    fn dropped_sub_stream() {
+        let mut messages: Vec<_> = (0..10).map(|_| message(0, b"x")).collect();
+        messages.push(message(1, b"y"));
+        let mut splitter = split_by_sender(stream::iter(messages), 1);
+        let (_, rx) = block_on(splitter.next()).unwrap();
+        drop(rx);
+
+        // Messages for sender 0 are discarded instead of stalling sender 1.
+        let (sender, rx) = block_on(splitter.next()).unwrap();
+        assert_eq!(sender, SenderId(1));
+        assert!(block_on(splitter.next()).is_none());
+        assert_eq!(block_on(rx.collect::<Vec<_>>()), vec![message(1, b"y")]);
+        assert_eq!(splitter.senders(), 0);
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Sending typed messages straight to a framed transport, without a connection.
+//!
+//! `TypedMessageSink` does the little a connection would for a peer that only listens:
+//! it assigns each message type an ID from its name, describes each sender and type
+//! to the remote end before its first message, and numbers the messages.
+//! Device and server code producing reports as a stream can then use `SinkExt::send_all`.
+
+use crate::{
+    buffer_unbuffer::BufferTo,
+    data_types::{
+        id_types::{LocalId, MessageTypeId, SenderId, SequenceNumber},
+        name_types::MessageTypeIdentifier,
+        GenericMessage, SenderName, SequencedGenericMessage, TypedMessage, TypedMessageBody,
+    },
+    type_dispatcher::TryIntoDescriptionMessage,
+    Result, TypeDispatcher, VrpnError,
+};
+use futures::{future::poll_fn, ready, Sink};
+use pin_project_lite::pin_project;
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::TryFrom,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+pin_project! {
+    /// Sink of typed messages, encoding them into a sink of framed messages
+    /// such as a `MessageSink`.
+    ///
+    /// The type ID in each message's header is replaced by the one this sink assigned
+    /// to the body's type name. Senders must be registered with `register_sender` first,
+    /// to give them a name to describe.
+    ///
+    /// Messages, along with any descriptions they need, are held until the inner sink is ready
+    /// for them: `poll_ready` only accepts another message once the inner sink has taken
+    /// everything held, so a slow transport slows the producer rather than messages piling up.
+    /// As with the inner sink, flush to make sure messages have been written.
+    ///
+    /// ```
+    /// use futures::{executor::block_on, io::Cursor, stream, SinkExt};
+    /// use vrpn::{
+    ///     data_types::{id_types::Sensor, MessageTypeId, Quat, TypedMessage, Vec3},
+    ///     tracker::PoseReport,
+    ///     vrpn_async::{AsyncWriteMessagesExt, TypedMessageSink},
+    /// };
+    /// let mut sink = TypedMessageSink::new(Cursor::new(Vec::new()).message_sink());
+    /// let tracker = sink.register_sender("Tracker0")?;
+    /// let mut reports = stream::iter((0..3).map(|i| {
+    ///     let report = PoseReport {
+    ///         sensor: Sensor(i),
+    ///         pos: Vec3::default(),
+    ///         quat: Quat::identity(),
+    ///     };
+    ///     // The sink fills in the type ID.
+    ///     Ok(TypedMessage::new(None, MessageTypeId(0), tracker, report))
+    /// }));
+    /// block_on(sink.send_all(&mut reports))?;
+    /// assert_eq!(sink.sent(), 3);
+    /// # Ok::<(), vrpn::VrpnError>(())
+    /// ```
+    #[derive(Debug)]
+    pub struct TypedMessageSink<S> {
+        #[pin]
+        inner: S,
+        names: TypeDispatcher,
+        described_senders: HashSet<LocalId<SenderId>>,
+        described_types: HashSet<LocalId<MessageTypeId>>,
+        // Messages encoded but not yet taken by the inner sink.
+        pending: VecDeque<GenericMessage>,
+        next_sequence: u32,
+        sent: u64,
+    }
+}
+
+impl<S> TypedMessageSink<S>
+where
+    S: Sink<SequencedGenericMessage, Error = VrpnError>,
+{
+    pub fn new(inner: S) -> TypedMessageSink<S> {
+        TypedMessageSink {
+            inner,
+            names: TypeDispatcher::new(),
+            described_senders: HashSet::new(),
+            described_types: HashSet::new(),
+            pending: VecDeque::new(),
+            next_sequence: 0,
+            sent: 0,
+        }
+    }
+
+    /// Register a sender (device) name, getting the ID to put in the headers of its messages.
+    ///
+    /// It is described to the remote end before its first message.
+    pub fn register_sender(&mut self, name: impl Into<SenderName>) -> Result<LocalId<SenderId>> {
+        Ok(self.names.register_sender(name)?.into_inner())
+    }
+
+    /// Number of typed messages handed to the inner sink, not counting descriptions.
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Get the inner sink back, discarding any messages it hasn't taken yet.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Encode a message, queueing it after any descriptions it needs.
+    fn encode<T>(self: Pin<&mut Self>, mut msg: TypedMessage<T>) -> Result<()>
+    where
+        T: TypedMessageBody + BufferTo,
+    {
+        let this = self.project();
+        let sender = LocalId(msg.header.sender);
+        if !this.described_senders.contains(&sender) {
+            let name = this
+                .names
+                .get_sender_name(sender)
+                .ok_or_else(|| VrpnError::InvalidId(sender.0 .0))?;
+            this.pending
+                .push_back(sender.try_into_description_message(name.0)?);
+            this.described_senders.insert(sender);
+        }
+        let message_type = match T::MESSAGE_IDENTIFIER {
+            MessageTypeIdentifier::UserMessageName(name) => {
+                let message_type = this.names.register_type(name.clone())?.into_inner();
+                if this.described_types.insert(message_type) {
+                    this.pending
+                        .push_back(message_type.try_into_description_message(name.0)?);
+                }
+                message_type
+            }
+            MessageTypeIdentifier::SystemMessageId(id) => LocalId(id),
+        };
+        msg.header.message_type = message_type.0;
+        this.pending.push_back(GenericMessage::try_from(msg)?);
+        *this.sent += 1;
+        Ok(())
+    }
+
+    /// Hand held messages to the inner sink, as it is ready for them.
+    fn poll_drain(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let mut this = self.project();
+        while let Some(msg) = this.pending.pop_front() {
+            if this.inner.as_mut().poll_ready(cx)?.is_pending() {
+                this.pending.push_front(msg);
+                return Poll::Pending;
+            }
+            let seq = SequenceNumber(*this.next_sequence);
+            *this.next_sequence = this.next_sequence.wrapping_add(1);
+            this.inner
+                .as_mut()
+                .start_send(msg.into_sequenced_message(seq))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_flush_all(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.as_mut().poll_drain(cx))?;
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close_all(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.as_mut().poll_drain(cx))?;
+        self.project().inner.poll_close(cx)
+    }
+
+    /// Hand everything held to the inner sink, and flush it.
+    ///
+    /// Like `SinkExt::flush`, which can't be used without naming a message type,
+    /// since this is a `Sink` for every type.
+    pub async fn flush(&mut self) -> Result<()>
+    where
+        S: Unpin,
+    {
+        poll_fn(|cx| Pin::new(&mut *self).poll_flush_all(cx)).await
+    }
+
+    /// Hand everything held to the inner sink, and close it.
+    ///
+    /// Like `SinkExt::close`: see `flush`.
+    pub async fn close(&mut self) -> Result<()>
+    where
+        S: Unpin,
+    {
+        poll_fn(|cx| Pin::new(&mut *self).poll_close_all(cx)).await
+    }
+}
+
+impl<S, T> Sink<TypedMessage<T>> for TypedMessageSink<S>
+where
+    S: Sink<SequencedGenericMessage, Error = VrpnError>,
+    T: TypedMessageBody + BufferTo,
+{
+    type Error = VrpnError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.as_mut().poll_drain(cx))?;
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: TypedMessage<T>) -> Result<()> {
+        self.encode(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush_all(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_close_all(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{constants, id_types::Sensor, Quat, Vec3},
+        endpoint::{parse_system_message, SystemCommand},
+        tracker::PoseReport,
+        vrpn_async::{AsyncReadMessagesExt, AsyncWriteMessagesExt},
+    };
+    use futures::{executor::block_on, io::Cursor, SinkExt, StreamExt};
+
+    fn report(sender: LocalId<SenderId>, sensor: i32) -> TypedMessage<PoseReport> {
+        let body = PoseReport {
+            sensor: Sensor(sensor),
+            pos: Vec3::new(1.0, 2.0, 3.0),
+            quat: Quat::identity(),
+        };
+        // The type ID given here is replaced.
+        TypedMessage::new(None, MessageTypeId(99), sender, body)
+    }
+
+    #[test]
+    fn describes_before_first_use() {
+        let mut sink = TypedMessageSink::new(Cursor::new(Vec::new()).message_sink());
+        let tracker0 = sink.register_sender("Tracker0").unwrap();
+        let tracker1 = sink.register_sender("Tracker1").unwrap();
+        block_on(async {
+            sink.feed(report(tracker0, 0)).await.unwrap();
+            sink.feed(report(tracker0, 1)).await.unwrap();
+            sink.feed(report(tracker1, 0)).await.unwrap();
+            sink.flush().await.unwrap();
+        });
+        assert_eq!(sink.sent(), 3);
+        assert!(block_on(sink.feed(report(LocalId(SenderId(42)), 0))).is_err());
+
+        let written = sink.into_inner().into_inner().into_inner();
+        let messages: Vec<SequencedGenericMessage> = block_on(
+            Cursor::new(written)
+                .messages()
+                .map(|msg| msg.unwrap())
+                .collect(),
+        );
+        let kinds: Vec<MessageTypeId> = messages
+            .iter()
+            .map(|msg| msg.message().header.message_type)
+            .collect();
+        let pose_type = kinds[2];
+        assert_eq!(
+            kinds,
+            [
+                constants::SENDER_DESCRIPTION,
+                constants::TYPE_DESCRIPTION,
+                pose_type,
+                pose_type,
+                constants::SENDER_DESCRIPTION,
+                pose_type
+            ]
+        );
+        match parse_system_message(messages[1].message().clone()).unwrap() {
+            SystemCommand::TypeDescription(desc) => {
+                assert_eq!(desc.which, pose_type);
+                assert_eq!(&desc.name[..], b"vrpn_Tracker Pos_Quat");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let sequence: Vec<u32> = messages.iter().map(|msg| msg.sequence_number.0).collect();
+        assert_eq!(sequence, (0..6).collect::<Vec<_>>());
+        let decoded = TypedMessage::<PoseReport>::try_from(messages[5].message()).unwrap();
+        assert_eq!(decoded.header.sender, tracker1.0);
+    }
+
+    #[cfg(all(unix, feature = "async-std"))]
+    #[test]
+    fn send_all_over_duplex_socket() {
+        use crate::{data_types::Message, vrpn_async::framed_messages};
+        use async_std::os::unix::net::UnixStream;
+        use futures::stream;
+        // Enough to fill the socket buffers, so the sender has to wait for the receiver.
+        const COUNT: usize = 20_000;
+        block_on(async {
+            let (a, b) = UnixStream::pair().unwrap();
+            let (_, a_tx) = framed_messages(a);
+            let (b_rx, _) = framed_messages(b);
+            let mut sink = TypedMessageSink::new(a_tx);
+            let tracker = sink.register_sender("Tracker0").unwrap();
+            // Dropping the sink when done ends the receiver's stream.
+            let sending = async move {
+                let mut reports = stream::iter((0..COUNT).map(|i| Ok(report(tracker, i as i32))));
+                sink.send_all(&mut reports).await.unwrap();
+                sink.close().await.unwrap();
+            };
+            let receiving = b_rx
+                .map(|msg| msg.unwrap())
+                .filter(|msg| futures::future::ready(!msg.message().is_system_message()))
+                .collect::<Vec<_>>();
+            let ((), received) = futures::join!(sending, receiving);
+            assert_eq!(received.len(), COUNT);
+            let last = TypedMessage::<PoseReport>::try_from(received[COUNT - 1].message()).unwrap();
+            assert_eq!(last.body.sensor, Sensor(COUNT as i32 - 1));
+        });
+    }
+}
@@ -13,10 +13,10 @@ use async_std::{
     net::{TcpListener, TcpStream, UdpSocket},
 };
 use bytes::{BufMut, Bytes, BytesMut};
-use socket2::{SockAddr, SockRef};
+use socket2::SockRef;
 
 use crate::{
-    vrpn_async::cookie::{read_and_check_nonfile_cookie, send_nonfile_cookie},
+    handshake::{futures_io::perform_handshake, Handshake},
     Result, Scheme, ServerInfo, VrpnError,
 };
 
@@ -26,30 +26,6 @@ pub struct ConnectResults {
     pub(crate) udp: Option<UdpSocket>,
 }
 
-pub(crate) fn make_tcp_socket(addr: SocketAddr) -> io::Result<socket2::Socket> {
-    use socket2::*;
-    let domain = if addr.is_ipv4() {
-        Domain::IPV4
-    } else {
-        Domain::IPV6
-    };
-    let sock = socket2::Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
-    sock.set_nonblocking(true)?;
-    sock.set_nodelay(true)?;
-
-    if cfg!(windows) {
-        if addr.is_ipv4() {
-            let any = std::net::Ipv4Addr::new(0, 0, 0, 0);
-            let addr = std::net::SocketAddrV4::new(any, 0);
-            sock.bind(&socket2::SockAddr::from(addr))?;
-        } else {
-            unimplemented!();
-        }
-    }
-    sock.set_reuse_address(true)?;
-    Ok(sock)
-}
-
 async fn make_udp_socket() -> io::Result<UdpSocket> {
     let any = std::net::Ipv4Addr::new(0, 0, 0, 0);
     let addr = SocketAddr::new(IpAddr::V4(any), 0);
@@ -69,9 +45,11 @@ pub(crate) struct UdpConnect {
     lobbed_buf: Bytes,
 }
 async fn outgoing_tcp_connect(addr: std::net::SocketAddr) -> Result<TcpStream> {
-    let sock = make_tcp_socket(addr)?;
-    sock.connect(&SockAddr::from(addr))?;
-    Ok(TcpStream::from(std::net::TcpStream::from(sock)))
+    // Let async-std do the connecting: a raw non-blocking connect just reports "in progress",
+    // with no way to await its completion.
+    let tcp = TcpStream::connect(addr).await?;
+    tcp.set_nodelay(true)?;
+    Ok(tcp)
 }
 
 async fn lobbing(
@@ -98,8 +76,7 @@ async fn handshake(
     udp: Option<UdpSocket>,
 ) -> Result<ConnectResults> {
     let mut tcp = tcp;
-    send_nonfile_cookie(&mut tcp).await?;
-    read_and_check_nonfile_cookie(&mut tcp).await?;
+    perform_handshake(&mut tcp, &mut Handshake::client()).await?;
     Ok(ConnectResults {
         server_info,
         tcp,
@@ -109,7 +86,10 @@ async fn handshake(
 
 async fn connect_tcp_and_udp(server: ServerInfo) -> Result<ConnectResults> {
     let udp = make_udp_socket().await?;
-    let addr = "localhost".to_socket_addrs()?.next().unwrap();
+    let addr = "localhost"
+        .to_socket_addrs()?
+        .next()
+        .ok_or(VrpnError::CouldNotConnect)?;
     let addr = SocketAddr::new(addr.ip(), 0);
     let tcp_listener = TcpListener::bind(&addr).await?;
     let port = udp.local_addr()?.port();
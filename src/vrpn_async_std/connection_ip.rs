@@ -2,20 +2,65 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-use crate::{connection::*, data_types::log::LogFileNames, Result, ServerInfo};
-use async_std::net::TcpListener;
-use futures::{future::BoxFuture, FutureExt, Stream};
+use crate::{
+    buffer_unbuffer::UnbufferFrom,
+    clock::{SharedClock, SystemClock},
+    codec::MessageSizeLimit,
+    connection::*,
+    data_types::{
+        constants,
+        id_types::{LocalId, SenderId},
+        log::LogFileNames,
+        SenderName, TypedMessage, TypedMessageBody,
+    },
+    endpoint::Endpoint,
+    extensions::{AgreedExtensions, ExtensionOffer, Extensions, EXTENSION_OFFER},
+    handler::{DescriptionHandler, HandlerCode, RemoteDescription},
+    handshake::futures_io,
+    health::{Health, HealthConfig},
+    ping::{self, PingConfig, PingEvent, UnresponsiveAction},
+    stats::{EndpointDiagnostics, ErrorKind},
+    subscription::Subscription,
+    vrpn_async::{parse_errors::Quarantined, BandwidthLimit, LowLatencyConfig, ParseErrorLimit},
+    Result, Scheme, ServerInfo, VrpnError,
+};
+use async_std::net::{TcpListener, TcpStream};
+use futures::{future::BoxFuture, task::AtomicWaker, FutureExt, Stream, StreamExt};
 use std::{
-    net::SocketAddr,
-    sync::{Arc, Mutex},
+    collections::{BTreeSet, VecDeque},
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError, Weak,
+    },
     task::Poll,
+    time::Duration,
 };
 
 use super::{
     connect::{connect, ConnectResults},
     endpoint_ip::EndpointIp,
+    retry::{retry_with_backoff, with_timeout, Backoff},
 };
 
+/// Records the names of described senders, other than the system one.
+#[derive(Debug)]
+struct SenderNameCollector {
+    names: Arc<Mutex<BTreeSet<SenderName>>>,
+}
+
+impl DescriptionHandler for SenderNameCollector {
+    fn handle_description(&mut self, desc: &RemoteDescription) -> Result<HandlerCode> {
+        if let RemoteDescription::Sender { name, .. } = desc {
+            if name.0[..] != constants::CONTROL.0[..] {
+                self.names.lock()?.insert(name.clone());
+            }
+        }
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
 pub(crate) enum ConnectionIpInfo {
     /// This variant stores the server info for reconnecting
     ClientConnectionInfo(ServerInfo),
@@ -34,27 +79,326 @@ impl ConnectionIpInfo {
         }
     }
 }
+/// How often a connection-owned ping client is checked, even without incoming traffic,
+/// unless its ping interval is shorter.
+const PING_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+fn ping_check_interval(config: &PingConfig) -> Duration {
+    PING_CHECK_INTERVAL.min(config.interval)
+}
+
+/// How often the health check is made, even without incoming traffic,
+/// unless the maximum silence is shorter.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+struct HealthState {
+    config: HealthConfig,
+    /// The health last reported, or at creation.
+    reported: Health,
+    /// Keeps us polled to notice silence, if limited.
+    timer: Option<BoxFuture<'static, ()>>,
+}
+
+impl HealthState {
+    fn new(config: HealthConfig, initial: Health) -> Mutex<HealthState> {
+        Mutex::new(HealthState {
+            config,
+            reported: initial,
+            timer: None,
+        })
+    }
+}
+
+enum PingState {
+    Disabled,
+    /// Will start pinging this sender once connected.
+    WaitingForConnection(LocalId<SenderId>, PingConfig),
+    Active {
+        client: ping::Client<ConnectionIp>,
+        timer: BoxFuture<'static, ()>,
+    },
+}
+
+/// A connection over TCP, and optionally UDP, as a client of one server or a server for many clients.
+///
+/// Messages may be packed from any number of threads or tasks at once, through a shared `Arc`.
+/// Each endpoint has a single task writing its socket, fed by a queue of whole messages:
+/// each is framed into one buffer and written in full before the next, so frames are never
+/// interleaved, and their sequence numbers follow the order they were queued.
 pub struct ConnectionIp {
     core: ConnectionCore<EndpointIp>,
     server_tcp: Option<Mutex<TcpListener>>,
     // server_acceptor: Arc<Mutex<Option<ConnectionIpAcceptor>>>,
     client_info: Mutex<ConnectionIpInfo>,
+    /// Used to hand out references to ourself, e.g. to the ping client.
+    weak_self: Weak<ConnectionIp>,
+    ping: Mutex<PingState>,
+    health: Mutex<HealthState>,
+    events: Mutex<VecDeque<ConnectionEvent>>,
+    /// Applied to messages received on each new endpoint.
+    message_size_limit: Mutex<MessageSizeLimit>,
+    low_latency: LowLatencyConfig,
+    /// Whether a client should connect again after losing its server.
+    reconnect: bool,
+    /// Set once a client has connected for the first time.
+    has_connected: AtomicBool,
+    /// Whether to log each message sent: applied to each new endpoint.
+    trace_outgoing: AtomicBool,
+    /// Applied to each new endpoint.
+    bandwidth_limit: Mutex<BandwidthLimit>,
+    /// Offered to each new endpoint.
+    extensions: Mutex<Extensions>,
+    /// Applied to each new endpoint.
+    parse_error_limit: Mutex<ParseErrorLimit>,
+    /// Applied to each client a server accepts.
+    duplicate_client_policy: Mutex<DuplicateClientPolicy>,
+    /// Woken when a server accepts a client, so the new endpoint gets polled.
+    driver: AtomicWaker,
 }
 
 const DEFAULT_PORT: u16 = 3883;
 
+/// How long each attempt to reconnect to a lost server may take.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client accepted by a server may take to complete the handshake.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Builder for a client `ConnectionIp`, for when the defaults of `ConnectionIp::new_client` aren't enough.
+pub struct ConnectionIpClientBuilder {
+    server: ServerInfo,
+    local_log_names: Option<LogFileNames>,
+    remote_log_names: Option<LogFileNames>,
+    ping_sender: Option<SenderName>,
+    ping_config: PingConfig,
+    health_config: HealthConfig,
+    clock: SharedClock,
+    message_clock: Option<SharedClock>,
+    message_size_limit: MessageSizeLimit,
+    low_latency: LowLatencyConfig,
+    reconnect: bool,
+    trace_outgoing: bool,
+    bandwidth_limit: BandwidthLimit,
+    extensions: Extensions,
+    parse_error_limit: ParseErrorLimit,
+    #[cfg(feature = "text")]
+    log_text: Option<crate::text::TextRateLimit>,
+}
+
+impl ConnectionIpClientBuilder {
+    /// Set the names of the log files to record locally.
+    pub fn local_log_names(mut self, names: Option<LogFileNames>) -> Self {
+        self.local_log_names = names;
+        self
+    }
+
+    /// Set the names of the log files to request the server record.
+    pub fn remote_log_names(mut self, names: Option<LogFileNames>) -> Self {
+        self.remote_log_names = names;
+        self
+    }
+
+    /// Have the connection own a ping client for the given (device) sender,
+    /// started automatically once connected.
+    ///
+    /// Pong latency and responsiveness changes are reported as `ConnectionEvent::Ping`.
+    pub fn with_ping(mut self, sender: impl Into<SenderName>) -> Self {
+        self.ping_sender = Some(sender.into());
+        self
+    }
+
+    /// Set the ping interval, unresponsive threshold, and unresponsive action
+    /// of the ping client enabled with `with_ping`.
+    ///
+    /// Validated when building. Can be changed later with `ConnectionIp::set_ping_config`.
+    pub fn ping_config(mut self, config: PingConfig) -> Self {
+        self.ping_config = config;
+        self
+    }
+
+    /// Choose which checks `ConnectionIp::health` makes: see `HealthConfig`.
+    ///
+    /// Validated when building. Can be changed later with `ConnectionIp::set_health_config`.
+    pub fn health_config(mut self, config: HealthConfig) -> Self {
+        self.health_config = config;
+        self
+    }
+
+    /// Timestamp outgoing messages with a different time source than `clock`:
+    /// see `Connection::set_message_clock`.
+    pub fn message_clock(mut self, clock: SharedClock) -> Self {
+        self.message_clock = Some(clock);
+        self
+    }
+
+    /// Use a specific time source instead of the system clock.
+    pub fn clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Set the limit on the size of messages accepted from the server,
+    /// and what to do with messages exceeding it.
+    pub fn message_size_limit(mut self, limit: MessageSizeLimit) -> Self {
+        self.message_size_limit = limit;
+        self
+    }
+
+    /// Trade CPU time for lower receive latency: see `LowLatencyConfig`.
+    pub fn low_latency(mut self, config: LowLatencyConfig) -> Self {
+        self.low_latency = config;
+        self
+    }
+
+    /// Connect to the server again if the connection to it is lost.
+    ///
+    /// Remote IDs are invalidated when the server goes away, reported as
+    /// `ConnectionEvent::RemoteIdsInvalidated`. Once reconnected, our descriptions are re-sent,
+    /// `ConnectionEvent::Reconnected` is reported, and existing handlers receive messages again
+    /// as the server re-describes its senders and types.
+    /// A `subscription::Subscription` reports each of these transitions in its own stream.
+    pub fn reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// Log a compact record of each message sent: see `ConnectionIp::set_trace_outgoing`.
+    pub fn trace_outgoing(mut self, enabled: bool) -> Self {
+        self.trace_outgoing = enabled;
+        self
+    }
+
+    /// Bound the rate of sending user messages: see `ConnectionIp::set_bandwidth_limit`.
+    pub fn bandwidth_limit(mut self, limit: BandwidthLimit) -> Self {
+        self.bandwidth_limit = limit;
+        self
+    }
+
+    /// Offer the server optional extensions: see `ConnectionIp::set_extensions`.
+    pub fn extensions(mut self, extensions: Extensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Shut down the connection to the server if it sends too much we can't decode:
+    /// see `ConnectionIp::set_parse_error_limit`.
+    ///
+    /// Validated when building.
+    pub fn parse_error_limit(mut self, limit: ParseErrorLimit) -> Self {
+        self.parse_error_limit = limit;
+        self
+    }
+
+    /// Log text messages from the server's devices with a `TextLogger`, limited as given,
+    /// or not at all if None.
+    ///
+    /// On by default, with the default `TextRateLimit`.
+    #[cfg(feature = "text")]
+    pub fn log_text(mut self, limit: Option<crate::text::TextRateLimit>) -> Self {
+        self.log_text = limit;
+        self
+    }
+
+    /// Create the connection and start connecting.
+    pub fn build(self) -> Result<Arc<ConnectionIp>> {
+        let ConnectionIpClientBuilder {
+            server,
+            local_log_names,
+            remote_log_names,
+            ping_sender,
+            ping_config,
+            health_config,
+            clock,
+            message_clock,
+            message_size_limit,
+            low_latency,
+            reconnect,
+            trace_outgoing,
+            bandwidth_limit,
+            extensions,
+            parse_error_limit,
+            #[cfg(feature = "text")]
+            log_text,
+        } = self;
+        health_config.validate()?;
+        parse_error_limit.validate()?;
+        let endpoints: Vec<Option<EndpointIp>> = Vec::new();
+        let health = Health::assess(
+            &health_config,
+            ConnectionStatus::ClientConnecting,
+            0,
+            None,
+            false,
+        );
+        let ret = Arc::new_cyclic(|weak_self| ConnectionIp {
+            core: ConnectionCore::new_with_clock(
+                endpoints,
+                local_log_names,
+                remote_log_names,
+                clock,
+            ),
+            // server_acceptor: None,
+            client_info: Mutex::new(ConnectionIpInfo::ClientConnectionSetupFuture(
+                connect(server).boxed(),
+            )),
+            server_tcp: None,
+            weak_self: weak_self.clone(),
+            ping: Mutex::new(PingState::Disabled),
+            health: HealthState::new(health_config, health),
+            events: Mutex::new(VecDeque::new()),
+            message_size_limit: Mutex::new(message_size_limit),
+            low_latency,
+            reconnect,
+            has_connected: AtomicBool::new(false),
+            trace_outgoing: AtomicBool::new(trace_outgoing),
+            bandwidth_limit: Mutex::new(bandwidth_limit),
+            extensions: Mutex::new(Extensions::empty()),
+            parse_error_limit: Mutex::new(parse_error_limit),
+            duplicate_client_policy: Mutex::new(DuplicateClientPolicy::default()),
+            driver: AtomicWaker::new(),
+        });
+        ret.set_message_clock(message_clock)?;
+        ret.set_extensions(extensions)?;
+        if let Some(sender) = ping_sender {
+            ping_config.validate()?;
+            let sender = ret.register_sender(sender)?;
+            *ret.ping.lock()? = PingState::WaitingForConnection(sender, ping_config);
+        }
+        #[cfg(feature = "text")]
+        if let Some(limit) = log_text {
+            crate::text::TextLogger::add_to_connection(&*ret, limit)?;
+        }
+        ret.send_all_descriptions()?;
+        Ok(ret)
+    }
+}
+
 impl ConnectionIp {
     /// Create a new ConnectionIp that is a server.
     pub fn new_server(
         local_log_names: Option<LogFileNames>,
         _addr: Option<SocketAddr>,
     ) -> Result<Arc<ConnectionIp>> {
-        let conn = Arc::new(ConnectionIp {
+        let conn = Arc::new_cyclic(|weak_self| ConnectionIp {
             core: ConnectionCore::new(Vec::new(), local_log_names, None),
             // server_acceptor: Arc::new(Mutex::new(None)),
             // server_tcp: Some(Mutex::new(server_tcp)),
             server_tcp: None,
             client_info: Mutex::new(ConnectionIpInfo::Server),
+            weak_self: weak_self.clone(),
+            ping: Mutex::new(PingState::Disabled),
+            health: HealthState::new(HealthConfig::default(), Health::default()),
+            events: Mutex::new(VecDeque::new()),
+            message_size_limit: Mutex::new(MessageSizeLimit::default()),
+            low_latency: LowLatencyConfig::default(),
+            reconnect: false,
+            has_connected: AtomicBool::new(false),
+            trace_outgoing: AtomicBool::new(false),
+            bandwidth_limit: Mutex::new(BandwidthLimit::default()),
+            extensions: Mutex::new(Extensions::empty()),
+            parse_error_limit: Mutex::new(ParseErrorLimit::default()),
+            duplicate_client_policy: Mutex::new(DuplicateClientPolicy::default()),
+            driver: AtomicWaker::new(),
         });
         // {
         //     let accepter = ConnectionIpAcceptor::new(Arc::downgrade(&conn), addr)?;
@@ -70,21 +414,503 @@ impl ConnectionIp {
         local_log_names: Option<LogFileNames>,
         remote_log_names: Option<LogFileNames>,
     ) -> Result<Arc<ConnectionIp>> {
-        let endpoints: Vec<Option<EndpointIp>> = Vec::new();
-        // let connect = Connect::new(server)?;
-        let ret = Arc::new(ConnectionIp {
-            core: ConnectionCore::new(endpoints, local_log_names, remote_log_names),
-            // server_acceptor: None,
-            client_info: Mutex::new(ConnectionIpInfo::ClientConnectionSetupFuture(
-                connect(server).boxed(),
-            )),
-            server_tcp: None,
-        });
-        ret.send_all_descriptions()?;
-        Ok(ret)
+        ConnectionIp::client_builder(server)
+            .local_log_names(local_log_names)
+            .remote_log_names(remote_log_names)
+            .build()
+    }
+
+    /// Connect a client over TCP to a server on this machine at the default port,
+    /// subscribing to the messages of type `T` from each of the named devices, in order.
+    ///
+    /// The client reconnects whenever the server goes away, and the subscriptions resume.
+    /// As with any connection, nothing is received until it is driven,
+    /// e.g. with a `ConnectionIpStream` or a `ThreadedConnection`.
+    ///
+    /// ```no_run
+    /// # use vrpn::{tracker::PoseReport, vrpn_async_std::connection_ip::ConnectionIp};
+    /// let (connection, subscriptions) =
+    ///     ConnectionIp::localhost::<PoseReport, _>(["Tracker0", "Tracker1"])?;
+    /// # Ok::<(), vrpn::VrpnError>(())
+    /// ```
+    pub fn localhost<T, I>(devices: I) -> Result<(Arc<ConnectionIp>, Vec<Subscription<T>>)>
+    where
+        T: TypedMessageBody + UnbufferFrom + fmt::Debug + Clone + Send + Sync + 'static,
+        I: IntoIterator,
+        I::Item: Into<SenderName>,
+    {
+        let server = ServerInfo::new(
+            SocketAddr::from((Ipv4Addr::LOCALHOST, DEFAULT_PORT)),
+            Scheme::TcpOnly,
+        );
+        let connection = ConnectionIp::client_builder(server)
+            .reconnect(true)
+            .build()?;
+        let subscriptions = devices
+            .into_iter()
+            .map(|device| Subscription::new(&connection, device))
+            .collect::<Result<_>>()?;
+        Ok((connection, subscriptions))
+    }
+
+    /// Start building a client ConnectionIp, to customize options such as pinging.
+    pub fn client_builder(server: ServerInfo) -> ConnectionIpClientBuilder {
+        ConnectionIpClientBuilder {
+            server,
+            local_log_names: None,
+            remote_log_names: None,
+            ping_sender: None,
+            ping_config: PingConfig::default(),
+            health_config: HealthConfig::default(),
+            clock: SystemClock::shared(),
+            message_clock: None,
+            message_size_limit: MessageSizeLimit::default(),
+            low_latency: LowLatencyConfig::default(),
+            reconnect: false,
+            trace_outgoing: false,
+            bandwidth_limit: BandwidthLimit::default(),
+            extensions: Extensions::empty(),
+            parse_error_limit: ParseErrorLimit::default(),
+            #[cfg(feature = "text")]
+            log_text: Some(Default::default()),
+        }
+    }
+
+    /// Collect the names of the senders (devices) the remote side describes within a time window.
+    ///
+    /// Drives the connection for the whole window, so call this before spawning anything else
+    /// that drives it. Best used right after creating a client, since the server describes
+    /// its senders as the connection is established.
+    pub async fn list_devices(self: &Arc<Self>, window: Duration) -> Result<Vec<SenderName>> {
+        let names = Arc::new(Mutex::new(BTreeSet::new()));
+        let handle = self.add_description_handler(Box::new(SenderNameCollector {
+            names: Arc::clone(&names),
+        }))?;
+        let mut stream = ConnectionIpStream::new(Arc::clone(self));
+        let driven = async_std::future::timeout(window, async {
+            while let Some(result) = stream.next().await {
+                result?;
+            }
+            Ok(())
+        })
+        .await;
+        self.remove_description_handler(handle)?;
+        if let Ok(Err(e)) = driven {
+            return Err(e);
+        }
+        let names = std::mem::take(&mut *names.lock()?);
+        Ok(names.into_iter().collect())
+    }
+
+    /// Remove and return all events that have occurred since the last call.
+    pub fn take_events(&self) -> Result<Vec<ConnectionEvent>> {
+        Ok(self.events.lock()?.drain(..).collect())
+    }
+
+    fn pop_event(&self) -> Result<Option<ConnectionEvent>> {
+        Ok(self.events.lock()?.pop_front())
+    }
+
+    fn push_event(&self, event: ConnectionEvent) -> Result<()> {
+        self.events.lock()?.push_back(event);
+        Ok(())
+    }
+
+    /// If this is a client that should reconnect and has lost its server, start connecting again.
+    ///
+    /// Returns true if a new connection attempt was started.
+    fn start_reconnect(&self) -> Result<bool> {
+        if !self.reconnect {
+            return Ok(false);
+        }
+        let mut client_info = self.client_info.lock()?;
+        if let ConnectionIpInfo::ClientConnectionInfo(server) = &*client_info {
+            if self.endpoints().lock()?.is_empty() {
+                eprintln!("Lost connection to {:?}, reconnecting", server);
+                let server = server.clone();
+                *client_info = ConnectionIpInfo::ClientConnectionSetupFuture(
+                    retry_with_backoff(Backoff::default(), move || {
+                        with_timeout(RECONNECT_TIMEOUT, connect(server.clone()))
+                    })
+                    .boxed(),
+                );
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// The configuration of the connection-owned ping client, if enabled with `with_ping`.
+    pub fn ping_config(&self) -> Result<Option<PingConfig>> {
+        Ok(match &*self.ping.lock()? {
+            PingState::Disabled => None,
+            PingState::WaitingForConnection(_, config) => Some(*config),
+            PingState::Active { client, .. } => Some(client.config()?),
+        })
+    }
+
+    /// Change the ping interval, unresponsive threshold, and unresponsive action
+    /// of the connection-owned ping client, e.g. to ping more often while debugging.
+    ///
+    /// Fails if the configuration is invalid, or pinging wasn't enabled with `with_ping`.
+    pub fn set_ping_config(&self, config: PingConfig) -> Result<()> {
+        config.validate()?;
+        match &mut *self.ping.lock()? {
+            PingState::Disabled => Err(VrpnError::Config("pinging is not enabled".to_string())),
+            PingState::WaitingForConnection(_, waiting) => {
+                *waiting = config;
+                Ok(())
+            }
+            PingState::Active { client, .. } => client.set_config(config),
+        }
+    }
+
+    /// Add a client connected to this server, once it completes the handshake.
+    ///
+    /// If the server already has endpoints for the client's host, applies the
+    /// `DuplicateClientPolicy` and reports `ConnectionEvent::DuplicateClient`.
+    /// Returns false if the client was rejected as a duplicate.
+    ///
+    /// ```no_run
+    /// # async fn serve(listener: async_std::net::TcpListener) -> vrpn::Result<()> {
+    /// use vrpn::vrpn_async_std::connection_ip::ConnectionIp;
+    /// let server = ConnectionIp::new_server(None, None)?;
+    /// loop {
+    ///     let (stream, _) = listener.accept().await?;
+    ///     server.accept_client(stream).await?;
+    /// }
+    /// # }
+    /// ```
+    pub async fn accept_client(&self, mut stream: TcpStream) -> Result<bool> {
+        if !matches!(*self.client_info.lock()?, ConnectionIpInfo::Server) {
+            return Err(VrpnError::Config(
+                "only a server can accept clients".to_string(),
+            ));
+        }
+        futures_io::server(&mut stream, Some(ACCEPT_TIMEOUT)).await?;
+        let host = stream.peer_addr()?.ip();
+        let policy = *self.duplicate_client_policy.lock()?;
+        let duplicate = {
+            let endpoints = self.endpoints();
+            let mut endpoints = endpoints.lock()?;
+            let mut existing = 0;
+            for ep in endpoints.iter().flatten() {
+                if ep.peer_addr().map(|peer| peer.ip()) == Some(host) {
+                    existing += 1;
+                    if policy == DuplicateClientPolicy::DropOld {
+                        // Closes, and is reported, the next time it is polled.
+                        ep.shutdown();
+                    }
+                }
+            }
+            if existing == 0 || policy != DuplicateClientPolicy::RejectNew {
+                let limit = *self.message_size_limit.lock()?;
+                let mut endpoint = EndpointIp::new(stream, None, limit, &self.low_latency);
+                self.set_up_endpoint(&mut endpoint)?;
+                endpoints.push(Some(endpoint));
+            }
+            (existing > 0).then_some(DuplicateClient {
+                host,
+                existing,
+                policy,
+            })
+        };
+        let accepted = match duplicate {
+            Some(duplicate) => {
+                eprintln!(
+                    "Client from {} already had {} endpoints: {:?}",
+                    host, duplicate.existing, policy
+                );
+                self.push_event(ConnectionEvent::DuplicateClient(duplicate))?;
+                policy != DuplicateClientPolicy::RejectNew
+            }
+            None => true,
+        };
+        self.driver.wake();
+        Ok(accepted)
+    }
+
+    /// Apply the per-endpoint settings to a new endpoint, and describe our IDs to it.
+    fn set_up_endpoint(&self, endpoint: &mut EndpointIp) -> Result<()> {
+        endpoint.set_trace_outgoing(self.trace_outgoing.load(Ordering::Relaxed));
+        endpoint.set_bandwidth_limit(*self.bandwidth_limit.lock()?);
+        endpoint.set_parse_error_limit(*self.parse_error_limit.lock()?);
+        let dispatcher = self.dispatcher();
+        let dispatcher = dispatcher.lock()?;
+        // A new remote end knows none of our IDs yet.
+        endpoint.send_all_descriptions(&dispatcher)?;
+        let extensions = *self.extensions.lock()?;
+        if !extensions.is_empty() {
+            let sender = dispatcher
+                .get_sender_id(constants::CONTROL)
+                .ok_or_else(|| VrpnError::OtherMessage("no control sender".to_string()))?;
+            let message_type = dispatcher.get_type_id(EXTENSION_OFFER).ok_or_else(|| {
+                VrpnError::OtherMessage("extension offer not registered".to_string())
+            })?;
+            let offer = ExtensionOffer::new(extensions);
+            endpoint.offer_extensions(TypedMessage::new(None, message_type, sender, offer))?;
+        }
+        Ok(())
+    }
+
+    /// The extensions this connection offers each new endpoint.
+    pub fn extensions(&self) -> Result<Extensions> {
+        Ok(*self.extensions.lock()?)
+    }
+
+    /// Offer each endpoint opened from now on some optional extensions: see `extensions`.
+    ///
+    /// Each only applies to endpoints whose remote end is also this crate, and offers it too,
+    /// as reported by `agreed_extensions` and `ConnectionEvent::ExtensionsAgreed`:
+    /// the C++ implementation ignores the offer. None are offered by default.
+    pub fn set_extensions(&self, extensions: Extensions) -> Result<()> {
+        if !extensions.is_empty() {
+            // Registered ahead of time, so it is described before any offer.
+            self.register_type(EXTENSION_OFFER)?;
+        }
+        *self.extensions.lock()? = extensions;
+        Ok(())
+    }
+
+    /// The extensions agreed with each open endpoint whose remote end has offered some.
+    pub fn agreed_extensions(&self) -> Result<Vec<AgreedExtensions>> {
+        let endpoints = self.endpoints();
+        let endpoints = endpoints.lock()?;
+        Ok(endpoints
+            .iter()
+            .flatten()
+            .filter_map(|endpoint| {
+                Some(AgreedExtensions {
+                    peer: endpoint.peer_addr(),
+                    extensions: endpoint.agreed_extensions()?,
+                })
+            })
+            .collect())
+    }
+
+    /// The limit on the size of messages accepted on each endpoint: see `set_message_size_limit`.
+    pub fn message_size_limit(&self) -> Result<MessageSizeLimit> {
+        Ok(*self.message_size_limit.lock()?)
+    }
+
+    /// Change the limit on the size of messages accepted on each endpoint,
+    /// and what to do with messages exceeding it, e.g. so a server can refuse
+    /// clients claiming huge messages.
+    ///
+    /// Applies to endpoints set up later: for a server, the clients it accepts from now on.
+    pub fn set_message_size_limit(&self, limit: MessageSizeLimit) -> Result<()> {
+        *self.message_size_limit.lock()? = limit;
+        Ok(())
+    }
+
+    /// What this server does when a client connects from a host it already has endpoints for.
+    pub fn duplicate_client_policy(&self) -> Result<DuplicateClientPolicy> {
+        Ok(*self.duplicate_client_policy.lock()?)
+    }
+
+    /// Choose what this server does when a client connects from a host it already has
+    /// endpoints for, e.g. to let a restarted client take over from its stale connection
+    /// rather than waiting for that to time out.
+    pub fn set_duplicate_client_policy(&self, policy: DuplicateClientPolicy) -> Result<()> {
+        *self.duplicate_client_policy.lock()? = policy;
+        Ok(())
+    }
+
+    /// Check whether this connection is doing okay, like `vrpn_Connection::doing_okay()`:
+    /// see `health` for the problems that make it not.
+    pub fn doing_okay(&self) -> Result<bool> {
+        Ok(self.health()?.doing_okay())
+    }
+
+    /// Assess the health of this connection now: its sockets and handshake,
+    /// how recently it received anything, and the responsiveness seen by its ping client.
+    ///
+    /// While driven, changes are also reported as `ConnectionEvent::HealthChanged`.
+    pub fn health(&self) -> Result<Health> {
+        let config = self.health.lock()?.config;
+        self.assess_health(&config)
+    }
+
+    /// The checks made by `health`.
+    pub fn health_config(&self) -> Result<HealthConfig> {
+        Ok(self.health.lock()?.config)
+    }
+
+    /// Change the checks made by `health`, e.g. to limit how long the server may be silent.
+    pub fn set_health_config(&self, config: HealthConfig) -> Result<()> {
+        config.validate()?;
+        let mut health = self.health.lock()?;
+        health.config = config;
+        // Restarted with the new interval when next driven.
+        health.timer = None;
+        Ok(())
+    }
+
+    fn assess_health(&self, config: &HealthConfig) -> Result<Health> {
+        let status = self.status();
+        let (endpoints, newest_uptime) = {
+            let endpoints = self.endpoints();
+            let endpoints = endpoints.lock()?;
+            let open = endpoints.iter().flatten();
+            (
+                open.clone().count(),
+                open.map(|ep| ep.diagnostics().uptime).min(),
+            )
+        };
+        // Only silent since the newest endpoint opened, if it has received nothing yet.
+        let silence = match newest_uptime {
+            Some(uptime) => {
+                let last = self.dispatcher().lock()?.stats().last_received_any();
+                Some(last.map_or(uptime, |at| at.elapsed().min(uptime)))
+            }
+            None => None,
+        };
+        let unresponsive = match &*self.ping.lock()? {
+            PingState::Active { client, .. } => client.is_unresponsive()?,
+            _ => false,
+        };
+        Ok(Health::assess(
+            config,
+            status,
+            endpoints,
+            silence,
+            unresponsive,
+        ))
+    }
+
+    /// Assess the health of this connection, reporting any change,
+    /// and keep a timer running to notice silence if limited.
+    fn check_health(&self, cx: &mut std::task::Context<'_>) -> Result<()> {
+        let config = {
+            let mut health = self.health.lock()?;
+            if let Some(max_silence) = health.config.max_silence {
+                let interval = HEALTH_CHECK_INTERVAL.min(max_silence);
+                let timer = health
+                    .timer
+                    .get_or_insert_with(|| async_std::task::sleep(interval).boxed());
+                while timer.as_mut().poll(cx).is_ready() {
+                    *timer = async_std::task::sleep(interval).boxed();
+                }
+            }
+            health.config
+        };
+        let current = self.assess_health(&config)?;
+        let changed = {
+            let mut health = self.health.lock()?;
+            if health.reported != current {
+                health.reported = current.clone();
+                true
+            } else {
+                false
+            }
+        };
+        if changed {
+            self.push_event(ConnectionEvent::HealthChanged(current))?;
+        }
+        Ok(())
+    }
+
+    /// Log a compact record of each message sent: its type and sender names,
+    /// sequence number, and size.
+    ///
+    /// With the `tracing` feature, these are info-level events with target `vrpn::outgoing`,
+    /// otherwise they are printed to stderr. Each pong received is logged too,
+    /// with the sequence number of the ping it answers, so that everything sent up to that ping
+    /// is known to have been received. Applies to the current endpoints and any later ones.
+    pub fn set_trace_outgoing(&self, enabled: bool) -> Result<()> {
+        self.trace_outgoing.store(enabled, Ordering::Relaxed);
+        for endpoint in self.endpoints().lock()?.iter().flatten() {
+            endpoint.set_trace_outgoing(enabled);
+        }
+        Ok(())
+    }
+
+    /// Delay sending user messages as needed to stay within a limit, e.g. to share a constrained link.
+    ///
+    /// Applies to each endpoint separately, current and later ones, starting with a full
+    /// allowance. Messages delayed are counted in `ConnectionStats::throttled_messages`,
+    /// and the rate actually sent is `ConnectionStats::send_rate`. Queued messages wait their
+    /// turn, so combine with `LowLatencyConfig::max_send_age` or `set_latest_value_only`
+    /// to keep a backlog of stale reports from building up.
+    pub fn set_bandwidth_limit(&self, limit: BandwidthLimit) -> Result<()> {
+        *self.bandwidth_limit.lock()? = limit;
+        for endpoint in self.endpoints().lock()?.iter().flatten() {
+            endpoint.set_bandwidth_limit(limit);
+        }
+        Ok(())
+    }
+
+    /// The limit on parse errors each endpoint may see: see `set_parse_error_limit`.
+    pub fn parse_error_limit(&self) -> Result<ParseErrorLimit> {
+        Ok(*self.parse_error_limit.lock()?)
+    }
+
+    /// Shut down any endpoint whose remote end causes more parse errors than the limit allows,
+    /// rather than spending ever more time decoding and discarding what it sends.
+    ///
+    /// Applies to each endpoint separately, current and later ones.
+    /// Each is reported as `ConnectionEvent::EndpointQuarantined`, and counted in
+    /// `ConnectionStats::quarantined_endpoints`. A client set to `reconnect` connects again,
+    /// after the usual backoff. Fails if the limit is invalid.
+    pub fn set_parse_error_limit(&self, limit: ParseErrorLimit) -> Result<()> {
+        limit.validate()?;
+        *self.parse_error_limit.lock()? = limit;
+        for endpoint in self.endpoints().lock()?.iter_mut().flatten() {
+            endpoint.set_parse_error_limit(limit);
+        }
+        Ok(())
+    }
+
+    /// Start the ping client if it's waiting for a connection.
+    fn start_ping(&self) -> Result<()> {
+        let mut ping = self.ping.lock()?;
+        if let PingState::WaitingForConnection(sender, config) = *ping {
+            if let Some(connection) = self.weak_self.upgrade() {
+                let client = ping::Client::with_config(sender, connection, config)?;
+                *ping = PingState::Active {
+                    client,
+                    timer: async_std::task::sleep(ping_check_interval(&config)).boxed(),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the ping client, if active, and collect its events.
+    fn drive_ping(&self, cx: &mut std::task::Context<'_>) -> Result<()> {
+        let mut disconnect = false;
+        {
+            let mut ping = self.ping.lock()?;
+            if let PingState::Active { client, timer } = &mut *ping {
+                let config = client.config()?;
+                // Keep a timer running so we get polled even if no messages arrive.
+                while timer.as_mut().poll(cx).is_ready() {
+                    *timer = async_std::task::sleep(ping_check_interval(&config)).boxed();
+                }
+                client.check_ping_cycle()?;
+                let new_events = client.take_events()?;
+                disconnect = config.action == UnresponsiveAction::Disconnect
+                    && new_events
+                        .iter()
+                        .any(|e| matches!(e, PingEvent::Unresponsive { .. }));
+                self.events
+                    .lock()?
+                    .extend(new_events.into_iter().map(ConnectionEvent::Ping));
+            }
+        }
+        if disconnect {
+            eprintln!("Disconnecting from unresponsive remote host");
+            for endpoint in self.endpoints().lock()?.iter().flatten() {
+                endpoint.shutdown();
+            }
+            // Get polled again to notice the endpoints closing.
+            cx.waker().wake_by_ref();
+        }
+        Ok(())
     }
 
     pub fn poll_endpoints(&self, cx: &mut std::task::Context<'_>) -> Poll<Result<Option<()>>> {
+        self.driver.register(cx.waker());
         // eprintln!("in <ConnectionIp as Future>::poll");
         // if let Some(listener_mutex) = &self.server_tcp {
         //     let listener = listener_mutex.lock()?;
@@ -111,6 +937,7 @@ impl ConnectionIp {
         // }
 
         // Connect/reconnect if needed.
+        let mut just_connected = false;
         {
             let mut client_info = self.client_info.lock()?;
             let ep_arc = self.endpoints();
@@ -118,14 +945,36 @@ impl ConnectionIp {
             if let ConnectionIpInfo::ClientConnectionSetupFuture(f) = &mut *client_info {
                 match f.as_mut().poll(cx) {
                     Poll::Ready(Ok(results)) => {
-                        endpoints.push(Some(EndpointIp::new(results.tcp, results.udp)));
-                        *client_info = ConnectionIpInfo::ClientConnectionInfo(results.server_info)
+                        let mut endpoint = EndpointIp::new(
+                            results.tcp,
+                            results.udp,
+                            *self.message_size_limit.lock()?,
+                            &self.low_latency,
+                        );
+                        self.set_up_endpoint(&mut endpoint)?;
+                        endpoints.push(Some(endpoint));
+                        *client_info = ConnectionIpInfo::ClientConnectionInfo(results.server_info);
+                        just_connected = true;
+                    }
+                    Poll::Ready(Err(e)) => {
+                        let kind = match ErrorKind::of(&e) {
+                            ErrorKind::Io => ErrorKind::Io,
+                            _ => ErrorKind::Handshake,
+                        };
+                        self.dispatcher().lock()?.stats_mut().record_error(kind);
+                        return Poll::Ready(Err(e));
                     }
-                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
                     Poll::Pending => return Poll::Pending,
                 }
             };
         }
+        if just_connected {
+            if self.has_connected.swap(true, Ordering::SeqCst) {
+                self.push_event(ConnectionEvent::Reconnected)?;
+            }
+            // Must not hold the endpoint or dispatcher locks: this registers and sends.
+            self.start_ping()?;
+        }
 
         // let mut acceptor = self.server_acceptor.lock()?;
         // match &mut (*acceptor) {
@@ -139,33 +988,101 @@ impl ConnectionIp {
         //     },
         //     None => (),
         // }
+        let is_server = matches!(*self.client_info.lock()?, ConnectionIpInfo::Server);
         let endpoints = self.endpoints();
         let dispatcher = self.dispatcher();
-        {
+        let mut invalidated = Vec::new();
+        let mut closed = Vec::new();
+        let mut quarantined = Vec::new();
+        let mut agreed = Vec::new();
+        let mismatches;
+        let overflow;
+        let result = {
             let mut endpoints = endpoints.lock()?;
             let mut dispatcher = dispatcher.lock()?;
             let mut got_not_ready = false;
             // Go through and poll each endpoint, "taking" the ones that are closed.
             for ep in endpoints.iter_mut() {
-                let ready = match ep {
-                    Some(endpoint) => endpoint.poll_endpoint(&mut dispatcher, cx).is_ready(),
-                    _ => true,
+                let status = match ep {
+                    Some(endpoint) => {
+                        let status = endpoint.poll_endpoint(&mut dispatcher, cx);
+                        if let Some(extensions) = endpoint.take_newly_agreed_extensions() {
+                            agreed.push(AgreedExtensions {
+                                peer: endpoint.peer_addr(),
+                                extensions,
+                            });
+                        }
+                        status
+                    }
+                    _ => Poll::Ready(Ok(())),
                 };
-                if ready {
-                    let _ = ep.take();
+                if let Poll::Ready(status) = status {
+                    if let Some(mut endpoint) = ep.take() {
+                        if let Err(VrpnError::Quarantined(limit)) = &status {
+                            dispatcher.stats_mut().record_quarantined();
+                            quarantined.push(Quarantined {
+                                peer: endpoint.peer_addr(),
+                                limit: *limit,
+                            });
+                        }
+                        let diagnostics = EndpointDiagnostics {
+                            error: status.err().map(|e| e.to_string()),
+                            ..endpoint.diagnostics()
+                        };
+                        closed.push(diagnostics);
+                        let mappings = endpoint.translation_tables_mut().invalidate_remote();
+                        let lost = RemoteDescription::RemoteLost(mappings);
+                        if let Err(e) = dispatcher.call_description_handlers(&lost) {
+                            dispatcher.stats_mut().record_vrpn_error(&e);
+                        }
+                        invalidated.push(mappings);
+                    }
                 } else {
                     got_not_ready = true;
                 }
             }
             // Now, retain only the non-taken endpoints in the vector.
             endpoints.retain(|ep| ep.is_some());
+            mismatches = dispatcher.take_type_mismatches();
+            overflow = dispatcher.take_dispatch_queue_overflow();
 
-            if got_not_ready {
+            // A server keeps waiting for clients.
+            if got_not_ready || is_server {
                 Poll::Pending
             } else {
                 Poll::Ready(Ok(Some(())))
             }
+        };
+        // Again, only after releasing the endpoint and dispatcher locks.
+        self.send_deferred()?;
+        let lost_endpoint = !invalidated.is_empty();
+        for mismatch in mismatches {
+            self.push_event(ConnectionEvent::TypeMismatch(mismatch))?;
+        }
+        if let Some(overflow) = overflow {
+            self.push_event(ConnectionEvent::DispatchQueueOverflow(overflow))?;
+        }
+        for extensions in agreed {
+            self.push_event(ConnectionEvent::ExtensionsAgreed(extensions))?;
+        }
+        for quarantine in quarantined {
+            self.push_event(ConnectionEvent::EndpointQuarantined(quarantine))?;
         }
+        for diagnostics in closed {
+            self.push_event(ConnectionEvent::EndpointClosed(diagnostics))?;
+        }
+        for mappings in invalidated {
+            self.push_event(ConnectionEvent::RemoteIdsInvalidated(mappings))?;
+        }
+        if lost_endpoint && self.start_reconnect()? {
+            self.check_health(cx)?;
+            // Get polled again to drive the new connection attempt.
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        self.drive_ping(cx)?;
+        self.check_health(cx)?;
+        result
     }
 }
 
@@ -177,8 +1094,12 @@ impl Connection for ConnectionIp {
 
     fn status(&self) -> ConnectionStatus {
         let ep = self.endpoints();
-        let endpoints = ep.lock().unwrap();
-        let info = self.client_info.lock().unwrap();
+        // A poisoned lock still holds usable data for a status report.
+        let endpoints = ep.lock().unwrap_or_else(PoisonError::into_inner);
+        let info = self
+            .client_info
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
         info.status(endpoints.len())
     }
 }
@@ -193,6 +1114,40 @@ impl ConnectionIpStream {
     }
 }
 
+/// Stream that drives a connection, yielding its events (such as ping results) as they occur.
+///
+/// Ends once all endpoints have closed and any remaining events have been yielded.
+pub struct ConnectionIpEventStream {
+    connection: Arc<ConnectionIp>,
+}
+
+impl ConnectionIpEventStream {
+    pub fn new(connection: Arc<ConnectionIp>) -> ConnectionIpEventStream {
+        ConnectionIpEventStream { connection }
+    }
+}
+
+impl Stream for ConnectionIpEventStream {
+    type Item = Result<ConnectionEvent>;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let polled = self.connection.poll_endpoints(cx);
+        match self.connection.pop_event() {
+            Ok(Some(event)) => return Poll::Ready(Some(Ok(event))),
+            Ok(None) => {}
+            Err(e) => return Poll::Ready(Some(Err(e))),
+        }
+        match polled {
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(Ok(_)) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
 impl Stream for ConnectionIpStream {
     type Item = Result<()>;
 
@@ -205,17 +1160,19 @@ impl Stream for ConnectionIpStream {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "tracker"))]
 mod tests {
     use super::*;
     use crate::{
-        data_types::{Message, StaticMessageTypeName, StaticSenderName, TypedMessage},
+        data_types::{
+            GenericMessage, Message, StaticMessageTypeName, StaticSenderName, TypedMessage,
+        },
         handler::{HandlerCode, TypedHandler},
         tracker::*,
     };
     use std::sync::{
         atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        Arc,
     };
 
     #[derive(Debug)]
@@ -318,4 +1275,547 @@ mod tests {
         futures::executor::block_on(function(&flag)).unwrap();
         assert!(flag.load(Ordering::SeqCst));
     }
+
+    #[test]
+    fn localhost() {
+        let (conn, subscriptions) =
+            ConnectionIp::localhost::<PoseReport, _>(["Tracker0", "Tracker1"]).unwrap();
+        assert_eq!(conn.status(), ConnectionStatus::ClientConnecting);
+        let senders: Vec<_> = subscriptions.iter().map(|sub| sub.sender()).collect();
+        assert_eq!(
+            senders,
+            vec![
+                conn.register_sender("Tracker0").unwrap(),
+                conn.register_sender("Tracker1").unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn list_devices() {
+        use crate::{
+            handshake::{futures_io::perform_handshake, Handshake},
+            TypeDispatcher,
+        };
+        async_std::task::block_on(async {
+            // A minimal server: handshake, describing its senders, then stay open.
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = async_std::task::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut dispatcher = TypeDispatcher::new();
+                dispatcher.register_sender("Tracker0").unwrap();
+                dispatcher.register_sender("Button0").unwrap();
+                let mut handshake = Handshake::server()
+                    .with_descriptions(dispatcher.pack_all_descriptions().unwrap());
+                perform_handshake(&mut stream, &mut handshake)
+                    .await
+                    .unwrap();
+                async_std::task::sleep(Duration::from_secs(2)).await;
+            });
+
+            let server_info = format!("tcp://127.0.0.1:{}", port)
+                .parse::<ServerInfo>()
+                .unwrap();
+            let conn = ConnectionIp::new_client(server_info, None, None).unwrap();
+            let devices = conn.list_devices(Duration::from_millis(500)).await.unwrap();
+            assert_eq!(
+                devices,
+                vec![SenderName::from("Button0"), SenderName::from("Tracker0")]
+            );
+            server.cancel().await;
+        });
+    }
+
+    #[test]
+    fn ping_watchdog_disconnects() {
+        use crate::handshake::{futures_io::perform_handshake, Handshake};
+        async_std::task::block_on(async {
+            // A server that never answers pings.
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = async_std::task::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                perform_handshake(&mut stream, &mut Handshake::server())
+                    .await
+                    .unwrap();
+                async_std::task::sleep(Duration::from_secs(5)).await;
+            });
+
+            let server_info = format!("tcp://127.0.0.1:{}", port)
+                .parse::<ServerInfo>()
+                .unwrap();
+            let invalid = PingConfig {
+                interval: Duration::ZERO,
+                ..Default::default()
+            };
+            assert!(ConnectionIp::client_builder(server_info.clone())
+                .with_ping("Tracker0")
+                .ping_config(invalid)
+                .build()
+                .is_err());
+            let conn = ConnectionIp::client_builder(server_info)
+                .with_ping("Tracker0")
+                .build()
+                .unwrap();
+            let config = PingConfig {
+                interval: Duration::from_millis(20),
+                unresponsive_threshold: Duration::from_millis(100),
+                action: UnresponsiveAction::Disconnect,
+            };
+            assert!(conn.set_ping_config(invalid).is_err());
+            conn.set_ping_config(config).unwrap();
+            assert_eq!(conn.ping_config().unwrap(), Some(config));
+
+            let mut events = ConnectionIpEventStream::new(Arc::clone(&conn));
+            let mut unresponsive = false;
+            let closed = async_std::future::timeout(Duration::from_secs(3), async {
+                while let Some(event) = events.next().await {
+                    match event.unwrap() {
+                        ConnectionEvent::Ping(PingEvent::Unresponsive { .. }) => {
+                            unresponsive = true
+                        }
+                        ConnectionEvent::EndpointClosed(_) => return true,
+                        _ => {}
+                    }
+                }
+                false
+            })
+            .await;
+            assert_eq!(closed, Ok(true));
+            assert!(unresponsive);
+            server.cancel().await;
+        });
+    }
+
+    #[test]
+    fn duplicate_clients() {
+        use futures::AsyncReadExt;
+        async_std::task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = ConnectionIp::new_server(None, None).unwrap();
+            assert_eq!(
+                server.duplicate_client_policy().unwrap(),
+                DuplicateClientPolicy::KeepBoth
+            );
+
+            // Connect a client, performing both sides of the handshake.
+            let connect = || async {
+                let addr = listener.local_addr().unwrap();
+                let client = async {
+                    let mut stream = TcpStream::connect(addr).await.unwrap();
+                    futures_io::client(&mut stream, None).await.unwrap();
+                    stream
+                };
+                let accept = async {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    server.accept_client(stream).await.unwrap()
+                };
+                futures::join!(client, accept)
+            };
+            async fn next_event(events: &mut ConnectionIpEventStream) -> ConnectionEvent {
+                async_std::future::timeout(Duration::from_secs(3), events.next())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .unwrap()
+            }
+            let mut events = ConnectionIpEventStream::new(Arc::clone(&server));
+
+            let (mut first, accepted) = connect().await;
+            assert!(accepted);
+            assert_eq!(server.status(), ConnectionStatus::Server(1));
+
+            server
+                .set_duplicate_client_policy(DuplicateClientPolicy::RejectNew)
+                .unwrap();
+            let (mut rejected, accepted) = connect().await;
+            assert!(!accepted);
+            assert_eq!(server.status(), ConnectionStatus::Server(1));
+            let host = first.local_addr().unwrap().ip();
+            assert_eq!(
+                next_event(&mut events).await,
+                ConnectionEvent::DuplicateClient(DuplicateClient {
+                    host,
+                    existing: 1,
+                    policy: DuplicateClientPolicy::RejectNew
+                })
+            );
+            let mut buf = Vec::new();
+            rejected.read_to_end(&mut buf).await.unwrap();
+
+            // Take over from the first client.
+            server
+                .set_duplicate_client_policy(DuplicateClientPolicy::DropOld)
+                .unwrap();
+            let (_second, accepted) = connect().await;
+            assert!(accepted);
+            assert_eq!(
+                next_event(&mut events).await,
+                ConnectionEvent::DuplicateClient(DuplicateClient {
+                    host,
+                    existing: 1,
+                    policy: DuplicateClientPolicy::DropOld
+                })
+            );
+            assert!(matches!(
+                next_event(&mut events).await,
+                ConnectionEvent::EndpointClosed(_)
+            ));
+            assert_eq!(server.status(), ConnectionStatus::Server(1));
+            first.read_to_end(&mut buf).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn extensions_negotiated() {
+        async_std::task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = ConnectionIp::new_server(None, None).unwrap();
+            server
+                .set_extensions(Extensions::LATEST_VALUE | Extensions::METADATA)
+                .unwrap();
+            let accepting = {
+                let server = Arc::clone(&server);
+                async_std::task::spawn(async move {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    server.accept_client(stream).await.unwrap();
+                    let mut stream = ConnectionIpStream::new(server);
+                    while stream.next().await.is_some() {}
+                })
+            };
+
+            let server_info = format!("tcp://127.0.0.1:{}", port)
+                .parse::<ServerInfo>()
+                .unwrap();
+            let client = ConnectionIp::client_builder(server_info)
+                .extensions(Extensions::COMPRESSION | Extensions::METADATA)
+                .build()
+                .unwrap();
+            assert_eq!(
+                client.extensions().unwrap(),
+                Extensions::COMPRESSION | Extensions::METADATA
+            );
+            let driving = async_std::task::spawn({
+                let mut stream = ConnectionIpStream::new(Arc::clone(&client));
+                async move { while stream.next().await.is_some() {} }
+            });
+
+            fn agreed(conn: &ConnectionIp) -> bool {
+                let endpoints = conn.endpoints();
+                let endpoints = endpoints.lock().unwrap();
+                let mut endpoints = endpoints.iter().flatten().peekable();
+                endpoints.peek().is_some()
+                    && endpoints.all(|ep| ep.agreed_extensions() == Some(Extensions::METADATA))
+            }
+            let negotiated = async {
+                while !(agreed(&client) && agreed(&server)) {
+                    async_std::task::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            async_std::future::timeout(Duration::from_secs(3), negotiated)
+                .await
+                .unwrap();
+            let agreed = client.agreed_extensions().unwrap();
+            assert_eq!(agreed.len(), 1);
+            assert_eq!(agreed[0].extensions, Extensions::METADATA);
+            assert_eq!(
+                agreed[0].peer,
+                Some(SocketAddr::from(([127, 0, 0, 1], port)))
+            );
+            let events = client.take_events().unwrap();
+            assert!(events.iter().any(|event| matches!(
+                event,
+                ConnectionEvent::ExtensionsAgreed(AgreedExtensions { extensions, .. })
+                    if *extensions == Extensions::METADATA
+            )));
+            driving.cancel().await;
+            accepting.cancel().await;
+        });
+    }
+
+    #[test]
+    fn quarantine_after_parse_errors() {
+        use crate::{
+            buffer_unbuffer::BytesMutExtras,
+            codec::OversizePolicy,
+            data_types::{
+                id_types::{MessageTypeId, SequenceNumber},
+                GenericBody, MessageHeader,
+            },
+            handshake::{futures_io::perform_handshake, Handshake},
+        };
+        use bytes::{Bytes, BytesMut};
+        use futures::AsyncWriteExt;
+        async_std::task::block_on(async {
+            // A server that completes the handshake, then sends nothing but oversized messages.
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = async_std::task::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                perform_handshake(&mut stream, &mut Handshake::server())
+                    .await
+                    .unwrap();
+                let mut buf = BytesMut::new();
+                for i in 0..10 {
+                    let msg = GenericMessage::from_header_and_body(
+                        MessageHeader::new(None, MessageTypeId(0), SenderId(0)),
+                        GenericBody::new(Bytes::from(vec![0u8; 128])),
+                    )
+                    .into_sequenced_message(SequenceNumber(i));
+                    buf.reserve_and_buffer(&msg).unwrap();
+                }
+                stream.write_all(&buf).await.unwrap();
+                async_std::task::sleep(Duration::from_secs(5)).await;
+            });
+
+            let server_info = format!("tcp://127.0.0.1:{}", port)
+                .parse::<ServerInfo>()
+                .unwrap();
+            let limit = ParseErrorLimit::new(3, Duration::from_secs(60));
+            let conn = ConnectionIp::client_builder(server_info)
+                .message_size_limit(MessageSizeLimit::new(64).with_policy(OversizePolicy::Skip))
+                .parse_error_limit(limit)
+                .build()
+                .unwrap();
+            assert_eq!(conn.parse_error_limit().unwrap(), limit);
+            assert!(conn
+                .set_parse_error_limit(ParseErrorLimit::new(3, Duration::ZERO))
+                .is_err());
+
+            async fn next_quarantine(events: &mut ConnectionIpEventStream) -> Option<Quarantined> {
+                while let Some(event) = events.next().await {
+                    if let Ok(ConnectionEvent::EndpointQuarantined(quarantined)) = event {
+                        return Some(quarantined);
+                    }
+                }
+                None
+            }
+            let mut events = ConnectionIpEventStream::new(Arc::clone(&conn));
+            let quarantined =
+                async_std::future::timeout(Duration::from_secs(3), next_quarantine(&mut events))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(quarantined.limit, limit);
+            assert_eq!(quarantined.peer.unwrap().port(), port);
+            let stats = conn.stats().unwrap();
+            assert_eq!(stats.quarantined_endpoints(), 1);
+            assert!(stats.errors().count(ErrorKind::Parse) > 3);
+            assert!(conn.endpoints().lock().unwrap().is_empty());
+            server.cancel().await;
+        });
+    }
+
+    #[test]
+    fn server_message_size_limit() {
+        use crate::{
+            buffer_unbuffer::BytesMutExtras,
+            data_types::{
+                id_types::{MessageTypeId, SequenceNumber},
+                GenericBody, MessageHeader,
+            },
+        };
+        use bytes::{Bytes, BytesMut};
+        use futures::AsyncWriteExt;
+        async_std::task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = ConnectionIp::new_server(None, None).unwrap();
+            assert_eq!(
+                server.message_size_limit().unwrap(),
+                MessageSizeLimit::default()
+            );
+            let limit = MessageSizeLimit::new(64);
+            server.set_message_size_limit(limit).unwrap();
+            assert_eq!(server.message_size_limit().unwrap(), limit);
+
+            let client = async {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                futures_io::client(&mut stream, None).await.unwrap();
+                stream
+            };
+            let accept = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                server.accept_client(stream).await.unwrap()
+            };
+            let (mut client, accepted) = futures::join!(client, accept);
+            assert!(accepted);
+            assert_eq!(server.status(), ConnectionStatus::Server(1));
+
+            let msg = GenericMessage::from_header_and_body(
+                MessageHeader::new(None, MessageTypeId(0), SenderId(0)),
+                GenericBody::new(Bytes::from(vec![0u8; 128])),
+            )
+            .into_sequenced_message(SequenceNumber(0));
+            let mut buf = BytesMut::new();
+            buf.reserve_and_buffer(&msg).unwrap();
+            client.write_all(&buf).await.unwrap();
+
+            // The client claiming too large a message is dropped.
+            let mut events = ConnectionIpEventStream::new(Arc::clone(&server));
+            let dropped = async {
+                while let Some(event) = events.next().await {
+                    if let Ok(ConnectionEvent::EndpointClosed(diagnostics)) = event {
+                        return diagnostics;
+                    }
+                }
+                panic!("events ended");
+            };
+            let diagnostics = async_std::future::timeout(Duration::from_secs(3), dropped)
+                .await
+                .unwrap();
+            assert_eq!(diagnostics.messages_received, 0);
+            assert_eq!(server.status(), ConnectionStatus::Server(0));
+            assert_eq!(server.stats().unwrap().errors().count(ErrorKind::Parse), 1);
+        });
+    }
+
+    #[test]
+    fn health_transitions() {
+        use crate::{
+            handshake::{futures_io::perform_handshake, Handshake},
+            health::HealthProblem,
+        };
+        async_std::task::block_on(async {
+            // A server that completes the handshake, then never sends anything.
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = async_std::task::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                perform_handshake(&mut stream, &mut Handshake::server())
+                    .await
+                    .unwrap();
+                async_std::task::sleep(Duration::from_secs(5)).await;
+            });
+
+            let server_info = format!("tcp://127.0.0.1:{}", port)
+                .parse::<ServerInfo>()
+                .unwrap();
+            assert!(ConnectionIp::client_builder(server_info.clone())
+                .health_config(HealthConfig {
+                    max_silence: Some(Duration::ZERO)
+                })
+                .build()
+                .is_err());
+            let conn = ConnectionIp::new_client(server_info, None, None).unwrap();
+            assert!(!conn.doing_okay().unwrap());
+            assert_eq!(
+                conn.health().unwrap().problems(),
+                &[HealthProblem::Connecting]
+            );
+
+            async fn next_health(events: &mut ConnectionIpEventStream) -> Option<Health> {
+                while let Some(event) = events.next().await {
+                    if let ConnectionEvent::HealthChanged(health) = event.unwrap() {
+                        return Some(health);
+                    }
+                }
+                None
+            }
+            let mut events = ConnectionIpEventStream::new(Arc::clone(&conn));
+            let timeout = Duration::from_secs(3);
+            let health = async_std::future::timeout(timeout, next_health(&mut events))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(health.doing_okay());
+            assert!(conn.doing_okay().unwrap());
+
+            let config = HealthConfig {
+                max_silence: Some(Duration::from_millis(100)),
+            };
+            conn.set_health_config(config).unwrap();
+            assert_eq!(conn.health_config().unwrap(), config);
+            let health = async_std::future::timeout(timeout, next_health(&mut events))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(health.problems(), &[HealthProblem::Silent]);
+            assert!(!conn.doing_okay().unwrap());
+            server.cancel().await;
+        });
+    }
+
+    #[test]
+    fn concurrent_senders_never_interleave() {
+        use crate::{
+            data_types::{id_types::Sensor, ClassOfService, Quat, Vec3},
+            handshake::{futures_io::perform_handshake, Handshake},
+            vrpn_async::AsyncReadMessagesExt,
+        };
+        use std::{collections::HashMap, convert::TryFrom, thread};
+        const THREADS: i32 = 8;
+        const MESSAGES: i32 = 500;
+        async_std::task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            // A peer decoding every user message it receives.
+            let peer = async_std::task::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                perform_handshake(&mut stream, &mut Handshake::server())
+                    .await
+                    .unwrap();
+                stream
+                    .messages()
+                    .map(|msg| msg.unwrap())
+                    .filter(|msg| futures::future::ready(!msg.message().is_system_message()))
+                    .take((THREADS * MESSAGES) as usize)
+                    .collect::<Vec<_>>()
+                    .await
+            });
+
+            let server_info = format!("tcp://127.0.0.1:{}", port)
+                .parse::<ServerInfo>()
+                .unwrap();
+            let conn = ConnectionIp::new_client(server_info, None, None).unwrap();
+            let sender = conn.register_sender("Tracker0").unwrap();
+            let driver = async_std::task::spawn(
+                ConnectionIpEventStream::new(Arc::clone(&conn)).for_each(|_| async {}),
+            );
+            while conn.status() != ConnectionStatus::ClientConnected {
+                async_std::task::sleep(Duration::from_millis(10)).await;
+            }
+
+            let threads: Vec<_> = (0..THREADS)
+                .map(|t| {
+                    let conn = Arc::clone(&conn);
+                    thread::spawn(move || {
+                        for i in 0..MESSAGES {
+                            let report = PoseReport {
+                                sensor: Sensor(t),
+                                pos: Vec3::new(f64::from(i), 0.0, 0.0),
+                                quat: Quat::identity(),
+                            };
+                            conn.pack_message_body(None, sender, report, ClassOfService::RELIABLE)
+                                .unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            let received = async_std::future::timeout(Duration::from_secs(10), peer)
+                .await
+                .expect("peer should receive every message");
+
+            // All decode, in order for each thread, numbered in the order written.
+            let mut next: HashMap<i32, f64> = HashMap::new();
+            let mut last_seq = None;
+            for msg in received {
+                assert!(last_seq < Some(msg.sequence_number.0));
+                last_seq = Some(msg.sequence_number.0);
+                let report = TypedMessage::<PoseReport>::try_from(msg.into_inner()).unwrap();
+                let expected = next.entry(report.body.sensor.0).or_insert(0.0);
+                assert_eq!(report.body.pos.x, *expected);
+                *expected += 1.0;
+            }
+            assert_eq!(next.len(), THREADS as usize);
+            assert!(next.values().all(|&n| n == f64::from(MESSAGES)));
+            // The peer hung up once it had everything.
+            driver.await;
+        });
+    }
 }
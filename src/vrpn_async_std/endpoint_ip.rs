@@ -3,154 +3,539 @@
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
 use super::{
-    endpoints::{merge_status, poll_and_dispatch, EndpointRx, EndpointStatus, ToEndpointStatus},
+    endpoints::{merge_status, EndpointStatus, ToEndpointStatus},
     UnboundedMessageSender,
 };
 use crate::{
-    data_types::{ClassOfService, GenericMessage},
+    clock::Instant,
+    codec::{MessageDecoder, MessageSizeLimit},
+    data_types::{id_types::SequenceNumber, ClassOfService, GenericMessage, TypedMessage},
     endpoint::*,
-    error::to_other_error,
-    vrpn_async::MessageStream,
-    Result, TranslationTables, TypeDispatcher,
+    extensions::{ExtensionNegotiation, ExtensionOffer, Extensions, EXTENSION_OFFER},
+    protocol::{ProtocolReceiver, ProtocolSender, QueuedMessage, SendCounters},
+    stats::EndpointDiagnostics,
+    vrpn_async::{BandwidthLimit, LowLatencyConfig, ParseErrorLimit},
+    Result, TranslationTables, TypeDispatcher, VrpnError,
 };
 use async_std::net::{TcpStream, UdpSocket};
-use futures::{channel::mpsc, ready, Future, Stream, StreamExt};
+use futures::{channel::mpsc, ready, task::AtomicWaker, AsyncRead, AsyncWrite, Future};
+use socket2::SockRef;
 
 use std::{
-    ops::DerefMut,
-    sync::{Arc, Mutex},
-};
-use std::{
+    convert::TryFrom,
+    fmt, io,
+    net::SocketAddr,
     pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
+    time::Duration,
 };
 
+/// Size of each read from the transport.
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Most bytes read in one go before dispatching the messages in them,
+/// so a peer sending faster than we dispatch cannot make the batch grow without bound.
+const MAX_BATCH_BYTES: usize = 64 * 1024;
+
 /// mock so we can have the member.
 
 #[derive(Debug)]
 struct MessageFramedUdp(UdpSocket);
 
+/// The reading side of an endpoint's reliable transport: usually a `TcpStream`.
+struct TransportReader(Pin<Box<dyn AsyncRead + Send>>);
+
+impl fmt::Debug for TransportReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TransportReader").finish()
+    }
+}
+
+impl AsyncRead for TransportReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.as_mut().poll_read(cx, buf)
+    }
+}
+
+/// Shared by the two halves of an endpoint, so each notices when the other shuts down.
+#[derive(Debug, Default)]
+struct Shutdown {
+    triggered: AtomicBool,
+    read_waker: AtomicWaker,
+    write_waker: AtomicWaker,
+}
+
+impl Shutdown {
+    fn trigger(&self) {
+        if !self.triggered.swap(true, Ordering::SeqCst) {
+            self.read_waker.wake();
+            self.write_waker.wake();
+        }
+    }
+
+    fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+}
+
+/// The receiving half of an `EndpointIp`: reads from the transport, and drives a `ProtocolReceiver`
+/// to decode and dispatch incoming messages.
+///
+/// Owns the translation tables, since only incoming messages need translating.
+/// Any replies it needs to send are queued to the write half without locking.
 #[derive(Debug)]
-pub struct EndpointIp {
-    translation: TranslationTables,
-    reliable_tx: Pin<Box<UnboundedMessageSender>>,
-    reliable_rx: Arc<Mutex<EndpointRx<MessageStream<TcpStream>>>>,
+pub struct EndpointIpReadHalf {
+    receiver: ProtocolReceiver,
+    reader: TransportReader,
+    read_buf: Box<[u8]>,
+    busy_poll: Option<Duration>,
+    #[allow(dead_code)] // todo: not yet used for sending
     low_latency_channel: Option<MessageFramedUdp>,
-    system_rx: Option<Pin<Box<mpsc::UnboundedReceiver<SystemCommand>>>>,
-    system_tx: Option<Pin<Box<mpsc::UnboundedSender<SystemCommand>>>>,
+    reliable_tx: mpsc::UnboundedSender<QueuedMessage>,
+    max_send_age: Option<Duration>,
+    /// Which extensions the remote end and we offered: see `extensions`.
+    extensions: ExtensionNegotiation,
+    /// Progress of the write half, including messages it dropped not yet recorded in the stats.
+    send_counters: Arc<SendCounters>,
+    opened: Instant,
+    shutdown: Arc<Shutdown>,
+}
+
+/// The sending half of an `EndpointIp`: drives a `ProtocolSender` to sequence queued messages,
+/// and writes them out.
+///
+/// Poll it (or await it) to make progress on writing.
+/// It completes once shut down and all queued messages are written, or on error.
+/// Dropping it shuts down the read half as well.
+#[derive(Debug)]
+pub struct EndpointIpWriteHalf {
+    reliable_tx: Pin<Box<UnboundedMessageSender>>,
+    max_send_age: Option<Duration>,
+    shutdown: Arc<Shutdown>,
+}
+
+#[derive(Debug)]
+pub struct EndpointIp {
+    read: EndpointIpReadHalf,
+    write: EndpointIpWriteHalf,
+    /// The address of the remote end, if over TCP.
+    peer: Option<SocketAddr>,
 }
 
 impl EndpointIp {
-    pub(crate) fn new(reliable_stream: TcpStream, udp: Option<UdpSocket>) -> EndpointIp {
-        let reliable_tx = UnboundedMessageSender::new(reliable_stream.clone());
-        let reliable_rx = EndpointRx::from_reader(reliable_stream);
-        let (system_tx, system_rx) = mpsc::unbounded();
-        EndpointIp {
-            translation: TranslationTables::new(),
-            reliable_tx,
-            reliable_rx,
-            low_latency_channel: udp.map(MessageFramedUdp),
-            system_tx: Some(Box::pin(system_tx)),
-            system_rx: Some(Box::pin(system_rx)),
+    pub(crate) fn new(
+        reliable_stream: TcpStream,
+        udp: Option<UdpSocket>,
+        limit: MessageSizeLimit,
+        low_latency: &LowLatencyConfig,
+    ) -> EndpointIp {
+        if let Err(e) = low_latency.apply_to_socket(SockRef::from(&reliable_stream)) {
+            eprintln!("Could not apply low-latency socket options: {}", e);
         }
+        let peer = reliable_stream.peer_addr().ok();
+        let mut endpoint = EndpointIp::from_transport(
+            reliable_stream.clone(),
+            reliable_stream,
+            limit,
+            low_latency,
+        );
+        endpoint.read.low_latency_channel = udp.map(MessageFramedUdp);
+        endpoint.peer = peer;
+        endpoint
     }
 
-    fn poll_system_rx(
-        &mut self,
-        mut dispatcher: &mut TypeDispatcher,
-        cx: &mut Context<'_>,
-    ) -> Poll<Result<EndpointStatus>> {
-        match self.system_rx.as_mut() {
-            Some(rx) => match ready!(rx.as_mut().poll_next(cx)) {
-                None => Poll::Ready(Ok(EndpointStatus::Closed)),
-                Some(cmd) => {
-                    if let Some(cmd) =
-                        handle_system_command(&mut dispatcher, self.translation_tables_mut(), cmd)?
-                    {
-                        match cmd {
-                            ExtendedSystemCommand::UdpDescription(desc) => {
-                                eprintln!("UdpDescription: {:?}", desc);
-                            }
-                            ExtendedSystemCommand::LogDescription(desc) => {
-                                eprintln!("LogDescription: {:?}", desc);
-                            }
-                            ExtendedSystemCommand::DisconnectMessage => {
-                                eprintln!("DisconnectMessage");
-                            }
-                        }
-                    }
-                    Poll::Ready(Ok(EndpointStatus::Open))
-                }
+    /// Create an endpoint communicating over any reliable transport, already past the handshake,
+    /// rather than a TCP socket.
+    ///
+    /// Socket options in `low_latency` are not applied: only those affecting reading and sending are used.
+    /// Useful for testing, e.g. with a `FaultyTransport`.
+    pub fn from_transport<R, W>(
+        reader: R,
+        writer: W,
+        limit: MessageSizeLimit,
+        low_latency: &LowLatencyConfig,
+    ) -> EndpointIp
+    where
+        R: AsyncRead + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+    {
+        let reliable_tx =
+            UnboundedMessageSender::new(writer, ProtocolSender::new(SequenceNumber(1)));
+        let receiver = ProtocolReceiver::new()
+            .with_decoder(MessageDecoder::with_capacity(2048).with_limit(limit));
+        let shutdown = Arc::new(Shutdown::default());
+        EndpointIp {
+            read: EndpointIpReadHalf {
+                receiver,
+                reader: TransportReader(Box::pin(reader)),
+                read_buf: vec![0; READ_BUFFER_SIZE].into_boxed_slice(),
+                busy_poll: low_latency.busy_poll,
+                low_latency_channel: None,
+                reliable_tx: reliable_tx.channel(),
+                max_send_age: low_latency.max_send_age,
+                extensions: ExtensionNegotiation::default(),
+                send_counters: reliable_tx.counters(),
+                opened: Instant::now(),
+                shutdown: Arc::clone(&shutdown),
+            },
+            write: EndpointIpWriteHalf {
+                reliable_tx,
+                max_send_age: low_latency.max_send_age,
+                shutdown,
             },
-            None => Poll::Ready(Ok(EndpointStatus::Closed)),
+            peer: None,
         }
     }
 
+    /// The address of the remote end, if connected over TCP.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// Shut down both halves of the endpoint: it closes the next time it is polled.
+    pub fn shutdown(&self) {
+        self.read.shutdown();
+    }
+
+    /// Log each message sent, and each pong received, while enabled: see `ConnectionIp::set_trace_outgoing`.
+    pub fn set_trace_outgoing(&self, enabled: bool) {
+        self.read.send_counters.trace.set_enabled(enabled);
+    }
+
+    /// Delay sending user messages to stay within a limit: see `ConnectionIp::set_bandwidth_limit`.
+    pub fn set_bandwidth_limit(&self, limit: BandwidthLimit) {
+        self.read.send_counters.set_bandwidth_limit(limit);
+    }
+
+    /// Shut down the endpoint if the remote end causes too many parse errors:
+    /// see `ConnectionIp::set_parse_error_limit`.
+    pub fn set_parse_error_limit(&mut self, limit: ParseErrorLimit) {
+        self.read.receiver.set_parse_error_limit(limit);
+    }
+
+    /// Offer the remote end some extensions, with an offer using local IDs.
+    ///
+    /// They apply once the remote end offers too: see `agreed_extensions`.
+    pub fn offer_extensions(&mut self, offer: TypedMessage<ExtensionOffer>) -> Result<()> {
+        let extensions = offer.body.extensions;
+        self.buffer_generic_message(GenericMessage::try_from(offer)?, ClassOfService::RELIABLE)?;
+        self.read.extensions.offered(extensions);
+        Ok(())
+    }
+
+    /// The extensions both ends offered, once both have: see `extensions`.
+    pub fn agreed_extensions(&self) -> Option<Extensions> {
+        self.read.extensions.agreed()
+    }
+
+    /// The agreed extensions, only the first time this is called after they are known.
+    pub(crate) fn take_newly_agreed_extensions(&mut self) -> Option<Extensions> {
+        self.read.extensions.take_newly_agreed()
+    }
+
+    /// Split into halves that can be owned and driven by separate tasks.
+    ///
+    /// Shutting down either half, or the remote end closing, shuts down both.
+    pub fn into_split(self) -> (EndpointIpReadHalf, EndpointIpWriteHalf) {
+        (self.read, self.write)
+    }
+
     pub(crate) fn poll_endpoint(
         &mut self,
         dispatcher: &mut TypeDispatcher,
         cx: &mut Context<'_>,
     ) -> Poll<Result<()>> {
-        let channel_rx_arc = Arc::clone(&self.reliable_rx);
-        let mut channel_rx = channel_rx_arc.lock().map_err(to_other_error)?;
+        let read_status = self.read.poll_read(dispatcher, cx).to_endpoint_status();
+        let write_status = self.write.poll_write(cx).to_endpoint_status();
+        if let EndpointStatus::Closed = write_status {
+            println!("Remote end of reliable connection has shut down.");
+        }
+        merge_status(read_status, write_status).into()
+    }
 
-        //
-        let mut endpoint_status =
-            poll_and_dispatch(self, channel_rx.deref_mut(), dispatcher, cx).to_endpoint_status();
+    /// What this endpoint has been doing, e.g. to report why it closed.
+    pub fn diagnostics(&self) -> EndpointDiagnostics {
+        self.read.diagnostics()
+    }
+}
 
-        match self.reliable_tx.as_mut().poll(cx) {
-            Poll::Ready(Ok(())) => {
-                println!("Remote end of reliable connection has shut down.");
-                endpoint_status = merge_status(endpoint_status, EndpointStatus::Closed);
-            }
-            Poll::Ready(Err(e)) => endpoint_status = EndpointStatus::ClosedError(e),
-            Poll::Pending => {}
+impl EndpointIpReadHalf {
+    /// Read and dispatch all available messages.
+    ///
+    /// Ready once the remote end closes, on error, or once either half is shut down.
+    pub fn poll_read(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<()>> {
+        self.shutdown.read_waker.register(cx.waker());
+        if self.shutdown.is_triggered() {
+            return Poll::Ready(Ok(()));
         }
+        let endpoint_status = self.poll_receive(dispatcher, cx).to_endpoint_status();
+        self.record_send_counters(dispatcher);
+
         // todo UDP here.
 
-        // Now, process the messages we sent ourself.
-        loop {
-            match self.poll_system_rx(dispatcher, cx) {
-                Poll::Ready(Ok(new_status)) => {
-                    endpoint_status = merge_status(endpoint_status, new_status)
+        if endpoint_status.is_closed() {
+            self.shutdown.trigger();
+        }
+        endpoint_status.into()
+    }
+
+    /// Read everything available (up to a limit), then dispatch the messages it completes,
+    /// so superseded values can be skipped.
+    ///
+    /// Is only ready when the transport is closed, or on error.
+    fn poll_receive(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<()>> {
+        let mut read = 0;
+        let mut closed = false;
+        let mut read_error = None;
+        while read < MAX_BATCH_BYTES {
+            match self.poll_read_transport(cx) {
+                Poll::Ready(Ok(0)) => {
+                    closed = true;
+                    break;
+                }
+                Poll::Ready(Ok(n)) => {
+                    self.receiver.receive(&self.read_buf[..n], Instant::now());
+                    read += n;
+                }
+                Poll::Ready(Err(e)) => {
+                    read_error = Some(VrpnError::from(e));
+                    break;
                 }
-                Poll::Ready(Err(_e)) => {}
                 Poll::Pending => break,
             }
         }
-        if endpoint_status.is_closed() {
+
+        let send_counters = Arc::clone(&self.send_counters);
+        let trace = &send_counters.trace;
+        let pong_type = trace.pong_type(dispatcher);
+        let offer_type = dispatcher.get_type_id(EXTENSION_OFFER);
+        let mut peer_offer = None;
+        let extended = self.receiver.dispatch(dispatcher, Instant::now(), |msg| {
+            if let Some(pong_type) = pong_type {
+                trace.received(msg, pong_type);
+            }
+            if offer_type.map(|id| id.0) == Some(msg.header.message_type) {
+                peer_offer = TypedMessage::<ExtensionOffer>::try_from(msg).ok();
+            }
+        });
+        if let Some(offer) = peer_offer {
+            self.extensions.peer_offered(&offer.body);
+        }
+        for cmd in extended? {
+            match cmd {
+                ExtendedSystemCommand::UdpDescription(desc) => {
+                    eprintln!("UdpDescription: {:?}", desc);
+                }
+                ExtendedSystemCommand::LogDescription(desc) => {
+                    eprintln!("LogDescription: {:?}", desc);
+                }
+                ExtendedSystemCommand::DisconnectMessage => {
+                    eprintln!("DisconnectMessage");
+                }
+            }
+        }
+        if let Some(e) = read_error {
+            dispatcher.stats_mut().record_vrpn_error(&e);
+            Poll::Ready(Err(e))
+        } else if closed {
+            Poll::Ready(Ok(()))
+        } else if read >= MAX_BATCH_BYTES {
+            // There may be more to read already: come back for it.
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Read from the transport, retrying for the busy-poll budget if nothing is available.
+    fn poll_read_transport(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mut poll = Pin::new(&mut self.reader).poll_read(cx, &mut self.read_buf);
+        if let (Poll::Pending, Some(budget)) = (&poll, self.busy_poll) {
+            let start = Instant::now();
+            while poll.is_pending() && start.elapsed() < budget {
+                std::hint::spin_loop();
+                poll = Pin::new(&mut self.reader).poll_read(cx, &mut self.read_buf);
+            }
+        }
+        poll
+    }
+
+    /// Record what the write half did since last time in the stats.
+    fn record_send_counters(&self, dispatcher: &mut TypeDispatcher) {
+        let expired = self.send_counters.expired.swap(0, Ordering::Relaxed);
+        if expired > 0 {
+            dispatcher.stats_mut().record_expired(expired);
+        }
+        let superseded = self.send_counters.superseded.swap(0, Ordering::Relaxed);
+        if superseded > 0 {
+            dispatcher.stats_mut().record_superseded(superseded);
+        }
+        let sent = self
+            .send_counters
+            .unrecorded_messages
+            .swap(0, Ordering::Relaxed);
+        if sent > 0 {
+            let bytes = self
+                .send_counters
+                .unrecorded_bytes
+                .swap(0, Ordering::Relaxed);
+            dispatcher.stats_mut().record_sent(sent, bytes);
+        }
+        let throttled = self.send_counters.throttled.swap(0, Ordering::Relaxed);
+        if throttled > 0 {
+            dispatcher.stats_mut().record_throttled(throttled);
+        }
+    }
+
+    /// Shut down both halves of the endpoint.
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// What both halves of this endpoint have been doing, e.g. to report why it closed.
+    ///
+    /// The `error` is left for the caller to fill in.
+    pub fn diagnostics(&self) -> EndpointDiagnostics {
+        let counters = &self.send_counters;
+        EndpointDiagnostics {
+            uptime: self.opened.elapsed(),
+            error: None,
+            messages_received: self.receiver.received(),
+            last_received: self.receiver.last_header().cloned(),
+            pending_receive_bytes: self.receiver.buffered_len(),
+            messages_sent: counters.written.load(Ordering::Relaxed),
+            last_sent: counters.last_written(),
+            pending_send_messages: counters.pending(),
+            pending_send_bytes: counters.unflushed_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl EndpointIpWriteHalf {
+    /// Queue a message to be sequenced and sent.
+    pub fn send(&mut self, msg: GenericMessage) -> Result<()> {
+        self.send_with_class(msg, ClassOfService::RELIABLE)
+    }
+
+    /// Queue a message to be sequenced and sent,
+    /// dropping it if low-latency and not sent within the configured maximum age.
+    pub fn send_with_class(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
+        let msg = QueuedMessage::new(msg, class, self.max_send_age, Instant::now());
+        self.reliable_tx.as_mut().unbounded_send(msg)
+    }
+
+    /// Like `send_with_class`, but dropping the message if a newer one
+    /// of the same type from the same sender is queued before it is written.
+    pub fn send_latest_value(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
+        let msg =
+            QueuedMessage::new(msg, class, self.max_send_age, Instant::now()).latest_value_only();
+        self.reliable_tx.as_mut().unbounded_send(msg)
+    }
+
+    /// Shut down both halves of the endpoint.
+    ///
+    /// Messages already queued are still written: keep polling until complete.
+    pub fn shutdown(&mut self) {
+        self.shutdown.trigger();
+        self.reliable_tx.close();
+    }
+
+    /// Write queued messages.
+    ///
+    /// Ready once shut down and all queued messages are written, or on error.
+    pub fn poll_write(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.shutdown.write_waker.register(cx.waker());
+        if self.shutdown.is_triggered() {
             self.reliable_tx.close();
         }
+        let result = ready!(self.reliable_tx.as_mut().poll(cx));
+        self.shutdown.trigger();
+        Poll::Ready(result)
+    }
+}
 
-        endpoint_status.into()
+impl Future for EndpointIpWriteHalf {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.poll_write(cx)
     }
 }
 
-impl Endpoint for EndpointIp {
+impl Drop for EndpointIpWriteHalf {
+    fn drop(&mut self) {
+        self.shutdown.trigger();
+    }
+}
+
+impl Endpoint for EndpointIpReadHalf {
     fn translation_tables(&self) -> &TranslationTables {
-        &self.translation
+        self.receiver.translation_tables()
     }
 
     fn translation_tables_mut(&mut self) -> &mut TranslationTables {
-        &mut self.translation
+        self.receiver.translation_tables_mut()
     }
 
     fn send_system_change(&self, message: SystemCommand) -> Result<()> {
         println!("send_system_change {:?}", message);
-        if let Some(tx) = self.system_tx.clone().as_deref_mut() {
-            tx.unbounded_send(message).map_err(to_other_error)?;
+        self.receiver.send_system_change(message)
+    }
+
+    fn buffer_generic_message(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
+        if self.shutdown.is_triggered() {
+            return Err(VrpnError::EndpointClosed);
         }
+        self.reliable_tx
+            .unbounded_send(QueuedMessage::new(
+                msg,
+                class,
+                self.max_send_age,
+                Instant::now(),
+            ))
+            .map_err(|_| VrpnError::EndpointClosed)?;
         Ok(())
     }
+}
+
+impl Endpoint for EndpointIp {
+    fn translation_tables(&self) -> &TranslationTables {
+        self.read.translation_tables()
+    }
+
+    fn translation_tables_mut(&mut self) -> &mut TranslationTables {
+        self.read.translation_tables_mut()
+    }
+
+    fn send_system_change(&self, message: SystemCommand) -> Result<()> {
+        self.read.send_system_change(message)
+    }
 
     fn buffer_generic_message(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
-        if class.contains(ClassOfService::RELIABLE) || self.low_latency_channel.is_none() {
-            // We either need reliable, or don't have low-latency
-            self.reliable_tx.as_mut().unbounded_send(msg)
-        } else {
-            // have and can use low-latency
-            unimplemented!()
-        }
+        // todo: use the low-latency channel when permitted by the class of service.
+        // Sending over UDP isn't implemented yet, and reliable is always acceptable.
+        self.write.send_with_class(msg, class)
+    }
+
+    fn buffer_latest_value(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
+        self.write.send_latest_value(msg, class)
     }
 
     fn send_all_descriptions(&mut self, dispatcher: &TypeDispatcher) -> Result<()> {
@@ -165,17 +550,32 @@ impl Endpoint for EndpointIp {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{vrpn_async::cookie, ServerInfo, VrpnError};
-    use async_std::net::TcpStream;
-    use futures::executor::block_on;
+    use crate::{
+        data_types::{
+            id_types::{MessageTypeId, SenderId},
+            Description, GenericBody, Message, MessageHeader,
+        },
+        handler::{Handler, HandlerCode},
+        handshake::{futures_io, Handshake},
+        vrpn_async::{
+            fault_injection::{FaultConfig, FaultyTransport},
+            AsyncReadMessagesExt, MessageSink,
+        },
+        ServerInfo, VrpnError,
+    };
+    use async_std::net::{TcpListener, TcpStream};
+    use bytes::Bytes;
+    use futures::{
+        executor::block_on, future::poll_fn, AsyncWriteExt, SinkExt, StreamExt, TryStreamExt,
+    };
+    use std::sync::Mutex;
 
     async fn connect_and_handshake(server_info: ServerInfo) -> crate::Result<TcpStream> {
         let mut stream = TcpStream::connect(server_info.socket_addr).await?;
         stream.set_nodelay(true)?;
 
         // We first write our cookie, then read and check the server's cookie, before the loop.
-        cookie::send_nonfile_cookie(&mut stream).await?;
-        cookie::read_and_check_nonfile_cookie(&mut stream).await?;
+        futures_io::perform_handshake(&mut stream, &mut Handshake::client()).await?;
         Ok(stream)
     }
     #[ignore] // because it requires an external server to be running.
@@ -184,7 +584,12 @@ mod tests {
         let server = "tcp://127.0.0.1:3883".parse::<ServerInfo>().unwrap();
         let result: Result<EndpointIp> = block_on(async {
             let tcp = connect_and_handshake(server).await?;
-            Ok(EndpointIp::new(tcp, None))
+            Ok(EndpointIp::new(
+                tcp,
+                None,
+                MessageSizeLimit::default(),
+                &LowLatencyConfig::default(),
+            ))
         });
         result.unwrap();
     }
@@ -196,18 +601,283 @@ mod tests {
         let result: Result<()> = block_on(async {
             let tcp = connect_and_handshake(server).await.unwrap();
 
-            let ep = EndpointIp::new(tcp, None);
-            let rx = Arc::clone(&ep.reliable_rx);
-            for _i in 0..4 {
-                let msg = rx
-                    .lock()?
-                    .next()
-                    .await
-                    .ok_or(VrpnError::GenericErrorReturn)?;
+            let ep = EndpointIp::new(
+                tcp,
+                None,
+                MessageSizeLimit::default(),
+                &LowLatencyConfig::default(),
+            );
+            let (mut read, _write) = ep.into_split();
+            let mut dispatcher = TypeDispatcher::new();
+            let received = Arc::new(Mutex::new(Vec::new()));
+            dispatcher.add_handler(Box::new(Record(Arc::clone(&received))), None, None)?;
+            read_until(&mut read, &mut dispatcher, &received, 4).await?;
+            for msg in received.lock()?.iter() {
                 eprintln!("Received message {:?}", msg);
             }
             Ok(())
         });
         result.unwrap();
     }
+
+    async fn endpoint_and_peer() -> Result<(EndpointIp, TcpStream)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let stream = TcpStream::connect(listener.local_addr()?).await?;
+        let (peer, _) = listener.accept().await?;
+        let ep = EndpointIp::new(
+            stream,
+            None,
+            MessageSizeLimit::default(),
+            &LowLatencyConfig::default(),
+        );
+        Ok((ep, peer))
+    }
+
+    #[derive(Debug)]
+    struct Record(Arc<Mutex<Vec<Bytes>>>);
+
+    impl Handler for Record {
+        fn handle(&mut self, msg: &GenericMessage) -> Result<HandlerCode> {
+            self.0.lock()?.push(msg.body.clone().into_inner());
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    /// Dispatch incoming messages until `count` have been recorded.
+    async fn read_until(
+        read: &mut EndpointIpReadHalf,
+        dispatcher: &mut TypeDispatcher,
+        received: &Mutex<Vec<Bytes>>,
+        count: usize,
+    ) -> Result<()> {
+        poll_fn(|cx| match read.poll_read(dispatcher, cx) {
+            Poll::Ready(result) => Poll::Ready(result.and(Err(VrpnError::EndpointClosed))),
+            Poll::Pending if received.lock().unwrap().len() < count => Poll::Pending,
+            Poll::Pending => Poll::Ready(Ok(())),
+        })
+        .await
+    }
+
+    /// Describe the remote type 1, and senders 0 to `senders`, to the read half.
+    fn describe(read: &mut EndpointIpReadHalf, dispatcher: &mut TypeDispatcher, senders: i32) {
+        let types = std::iter::once(SystemCommand::TypeDescription(
+            Description::from_id_and_name(MessageTypeId(1), Bytes::from_static(b"custom")),
+        ));
+        let senders = (0..senders).map(|i| {
+            SystemCommand::SenderDescription(Description::from_id_and_name(
+                SenderId(i),
+                Bytes::from(format!("Sender{}", i)),
+            ))
+        });
+        for desc in types.chain(senders) {
+            handle_system_command(dispatcher, read.translation_tables_mut(), desc).unwrap();
+        }
+    }
+
+    fn test_message() -> GenericMessage {
+        GenericMessage::from_header_and_body(
+            MessageHeader::new(None, MessageTypeId(1), SenderId(2)),
+            GenericBody::new(Bytes::from_static(b"abcd")),
+        )
+    }
+
+    #[test]
+    fn round_trip_through_faults() {
+        let config = FaultConfig::new(42)
+            .with_max_chunk(7)
+            .with_delay_chance(0.3);
+        let messages: Vec<GenericMessage> = (0..20)
+            .map(|i| {
+                GenericMessage::from_header_and_body(
+                    MessageHeader::new(None, MessageTypeId(1), SenderId(i)),
+                    GenericBody::new(Bytes::from(vec![i as u8; i as usize])),
+                )
+            })
+            .collect();
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let stream = TcpStream::connect(listener.local_addr()?).await?;
+            let (peer, _) = listener.accept().await?;
+            let reader = FaultyTransport::new(stream.clone(), config);
+            let writer = FaultyTransport::new(stream, FaultConfig { seed: 43, ..config });
+            let delays = reader.counts();
+            let ep = EndpointIp::from_transport(
+                reader,
+                writer,
+                MessageSizeLimit::default(),
+                &LowLatencyConfig::default(),
+            );
+            let (mut read, mut write) = ep.into_split();
+
+            // In through the faulty reader...
+            let mut dispatcher = TypeDispatcher::new();
+            describe(&mut read, &mut dispatcher, messages.len() as i32);
+            let received = Arc::new(Mutex::new(Vec::new()));
+            dispatcher.add_handler(Box::new(Record(Arc::clone(&received))), None, None)?;
+            let mut sink = MessageSink::new(peer.clone());
+            for (i, msg) in messages.iter().enumerate() {
+                sink.feed(msg.clone().into_sequenced_message(SequenceNumber(i as u32)))
+                    .await?;
+            }
+            sink.flush().await?;
+            read_until(&mut read, &mut dispatcher, &received, messages.len()).await?;
+            let bodies: Vec<Bytes> = messages
+                .iter()
+                .map(|msg| msg.body.clone().into_inner())
+                .collect();
+            assert_eq!(*received.lock()?, bodies);
+            assert!(delays.delays.load(Ordering::Relaxed) > 0);
+
+            // ...and back out through the faulty writer.
+            for msg in &messages {
+                write.send(msg.clone())?;
+            }
+            write.shutdown();
+            (&mut write).await?;
+            let received: Vec<GenericMessage> = peer
+                .clone()
+                .messages()
+                .take(messages.len())
+                .map(|msg| msg.map(|msg| msg.into_inner()))
+                .try_collect()
+                .await?;
+            assert_eq!(received, messages);
+            Ok::<(), VrpnError>(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn split_write_then_shutdown() {
+        block_on(async {
+            let (ep, peer) = endpoint_and_peer().await?;
+            let (mut read, mut write) = ep.into_split();
+            let msg = test_message();
+            write.send(msg.clone())?;
+            write.shutdown();
+            // Queued messages still get written before completing.
+            (&mut write).await?;
+            assert!(write.send(msg.clone()).is_err());
+
+            let received = peer.messages().next().await.unwrap()?;
+            assert_eq!(received.into_inner(), msg);
+
+            // The read half noticed the shutdown.
+            let mut dispatcher = TypeDispatcher::new();
+            poll_fn(|cx| read.poll_read(&mut dispatcher, cx)).await?;
+            Ok::<(), VrpnError>(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn split_peer_close() {
+        block_on(async {
+            let (ep, peer) = endpoint_and_peer().await?;
+            let (mut read, write) = ep.into_split();
+            drop(peer);
+            let mut dispatcher = TypeDispatcher::new();
+            poll_fn(|cx| read.poll_read(&mut dispatcher, cx)).await?;
+            // The write half shuts down in turn.
+            write.await?;
+            assert!(read
+                .buffer_generic_message(test_message(), ClassOfService::RELIABLE)
+                .is_err());
+            Ok::<(), VrpnError>(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn diagnostics_on_error() {
+        block_on(async {
+            let (mut ep, mut peer) = endpoint_and_peer().await?;
+            let mut dispatcher = TypeDispatcher::new();
+            let msg = test_message();
+            ep.buffer_generic_message(msg.clone(), ClassOfService::RELIABLE)?;
+
+            // A message with IDs never described, then the start of another.
+            let mut data = msg
+                .clone()
+                .into_sequenced_message(SequenceNumber(0))
+                .try_into_buf()?
+                .to_vec();
+            data.extend_from_within(..5);
+            peer.write_all(&data).await?;
+            let result = poll_fn(|cx| ep.poll_endpoint(&mut dispatcher, cx)).await;
+            assert!(result.is_err());
+
+            let diagnostics = ep.diagnostics();
+            assert_eq!(diagnostics.error, None);
+            assert_eq!(diagnostics.messages_received, 1);
+            assert_eq!(diagnostics.last_received, Some(msg.header.clone()));
+            assert_eq!(diagnostics.pending_receive_bytes, 5);
+            assert_eq!(diagnostics.messages_sent, 1);
+            assert_eq!(diagnostics.last_sent, Some(msg.header));
+            assert_eq!(diagnostics.pending_send_messages, 0);
+            Ok::<(), VrpnError>(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn batch_limit() {
+        use futures::task::{waker, ArcWake};
+        use std::sync::atomic::AtomicBool;
+
+        #[derive(Default)]
+        struct Woken(AtomicBool);
+
+        impl ArcWake for Woken {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        /// A peer that never stops sending the same message.
+        struct Endless(Bytes, usize);
+
+        impl AsyncRead for Endless {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                for byte in buf.iter_mut() {
+                    *byte = self.0[self.1];
+                    self.1 = (self.1 + 1) % self.0.len();
+                }
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+
+        let msg = test_message()
+            .into_sequenced_message(SequenceNumber(0))
+            .try_into_buf()
+            .unwrap();
+        let msg_len = msg.len();
+        let ep = EndpointIp::from_transport(
+            Endless(msg, 0),
+            futures::io::sink(),
+            MessageSizeLimit::default(),
+            &LowLatencyConfig::default(),
+        );
+        let (mut read, _write) = ep.into_split();
+        let mut dispatcher = TypeDispatcher::new();
+        describe(&mut read, &mut dispatcher, 3);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        dispatcher
+            .add_handler(Box::new(Record(Arc::clone(&received))), None, None)
+            .unwrap();
+
+        let woken = Arc::new(Woken::default());
+        let waker = waker(Arc::clone(&woken));
+        let mut cx = Context::from_waker(&waker);
+        assert!(read.poll_read(&mut dispatcher, &mut cx).is_pending());
+        let count = received.lock().unwrap().len();
+        assert!(count > 0);
+        assert!(count <= MAX_BATCH_BYTES / msg_len);
+        // Asks to be polled again for the rest.
+        assert!(woken.0.load(Ordering::SeqCst));
+    }
 }
@@ -2,58 +2,8 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-use crate::{
-    data_types::{GenericMessage, Message, SequencedGenericMessage},
-    endpoint::*,
-    vrpn_async::{AsyncReadMessagesExt, MessageStream},
-    Result, TypeDispatcher, VrpnError,
-};
-
-use futures::{ready, AsyncRead, Stream, StreamExt};
-use std::{
-    fmt::Debug,
-    pin::Pin,
-    sync::{Arc, Mutex},
-    task::{Context, Poll},
-};
-
-#[derive(Debug)]
-pub(crate) struct EndpointRx<T> {
-    stream: Pin<Box<T>>,
-    error: Option<VrpnError>,
-}
-
-impl<T> EndpointRx<T> where T: Stream<Item = SequencedGenericMessage> {}
-
-impl<U: AsyncRead + Unpin> EndpointRx<MessageStream<U>> {
-    pub(crate) fn from_reader(reader: U) -> Arc<Mutex<EndpointRx<MessageStream<U>>>> {
-        Arc::new(Mutex::new(EndpointRx {
-            stream: Box::pin(AsyncReadMessagesExt::messages(reader)),
-            error: None,
-        }))
-    }
-}
-
-impl<T: Stream<Item = Result<SequencedGenericMessage>>> Stream for EndpointRx<T> {
-    type Item = GenericMessage;
-
-    fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut Context<'_>,
-    ) -> Poll<Option<Self::Item>> {
-        if self.error.is_some() {
-            return Poll::Ready(None);
-        }
-        match ready!(self.stream.as_mut().poll_next(cx)) {
-            Some(Err(e)) => {
-                self.error = Some(e);
-                Poll::Ready(None)
-            }
-            Some(Ok(sgm)) => Poll::Ready(Some(sgm.into_inner())),
-            None => Poll::Ready(None),
-        }
-    }
-}
+use crate::VrpnError;
+use std::task::Poll;
 
 #[derive(Debug)]
 pub(crate) enum EndpointStatus {
@@ -127,57 +77,3 @@ impl From<EndpointStatus> for std::task::Poll<std::result::Result<(), VrpnError>
         }
     }
 }
-
-/// Given a stream of GenericMessage, poll the stream and dispatch received messages.
-///
-/// Is only ready when the stream is closed.
-pub(crate) fn poll_and_dispatch<T, U>(
-    endpoint: &mut T,
-    stream: &mut U,
-    dispatcher: &mut TypeDispatcher,
-    cx: &mut Context<'_>,
-) -> Poll<std::result::Result<(), VrpnError>>
-where
-    T: Endpoint,
-    U: Stream<Item = GenericMessage> + Unpin,
-{
-    const MAX_PER_TICK: usize = 10;
-    let mut closed = false;
-    // for i in 0..MAX_PER_TICK {
-    loop {
-        let poll_result = stream.poll_next_unpin(cx);
-        match poll_result {
-            Poll::Ready(Some(msg)) => {
-                let msg = endpoint.map_remote_message_to_local(msg)?;
-                if msg.is_system_message() {
-                    endpoint.send_system_change(parse_system_message(msg)?)?;
-                } else {
-                    dispatcher.call(&msg)?;
-                }
-            }
-            Poll::Ready(None) => {
-                // connection closed
-                closed = true;
-                break;
-            }
-            Poll::Pending => {
-                break;
-            }
-        }
-        // If this is the last iteration, the loop will break even
-        // though there could still be messages to read. Because we did
-        // not reach `Async::NotReady`, we have to notify ourselves
-        // in order to tell the executor to schedule the task again.
-        // if i + 1 == MAX_PER_TICK {
-        //     task::current().notify();
-        // }
-    }
-    if closed {
-        eprintln!("poll_and_dispatch decided the channel was closed");
-        Poll::Ready(Ok(()))
-    } else {
-        // eprintln!("poll_and_dispatch decided that it's not ready");
-        // task::current().notify();
-        Poll::Pending
-    }
-}
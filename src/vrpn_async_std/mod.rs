@@ -8,6 +8,10 @@ pub mod connect;
 pub mod connection_ip;
 pub mod endpoint_ip;
 mod endpoints;
+#[cfg(windows)]
+pub mod named_pipe;
+pub mod retry;
+pub mod threaded;
 mod unbounded_message_sender;
 
 pub(crate) use unbounded_message_sender::UnboundedMessageSender;
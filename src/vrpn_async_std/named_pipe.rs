@@ -0,0 +1,443 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Windows named pipe transport, for connections between processes on the same host.
+//!
+//! Uses the same cookie handshake and message framing as TCP, or a Unix socket:
+//! `connect_named_pipe` and `NamedPipeListener::accept` perform the handshake,
+//! then apply `framed_messages`.
+//!
+//! async-std has no named pipes, so each pipe is read and written with overlapped I/O
+//! on the blocking thread pool.
+
+use crate::{
+    handshake::futures_io,
+    vrpn_async::{framed_messages, MessageSink, MessageStream},
+    Result,
+};
+use async_std::task;
+use blocking::{Task, Unblock};
+use futures::{
+    io::{ReadHalf, WriteHalf},
+    AsyncRead, AsyncWrite,
+};
+use std::{
+    ffi::OsStr,
+    io::{self, Read, Write},
+    iter, mem,
+    os::windows::{
+        ffi::OsStrExt,
+        io::{AsRawHandle, FromRawHandle, OwnedHandle, RawHandle},
+    },
+    pin::Pin,
+    ptr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use windows_sys::Win32::{
+    Foundation::{
+        ERROR_BROKEN_PIPE, ERROR_IO_PENDING, ERROR_PIPE_BUSY, ERROR_PIPE_CONNECTED, FALSE,
+        GENERIC_READ, GENERIC_WRITE, HANDLE, INVALID_HANDLE_VALUE, TRUE, WAIT_OBJECT_0,
+    },
+    Storage::FileSystem::{
+        CreateFileW, ReadFile, WriteFile, FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED,
+        OPEN_EXISTING, PIPE_ACCESS_DUPLEX,
+    },
+    System::{
+        Pipes::{
+            ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+        },
+        Threading::{CreateEventW, SetEvent, WaitForMultipleObjects, INFINITE},
+        IO::{CancelIoEx, GetOverlappedResult, OVERLAPPED},
+    },
+};
+
+/// Pipe name used when none is specified.
+pub const DEFAULT_PIPE_NAME: &str = r"\\.\pipe\vrpn";
+
+/// How long to wait before retrying a busy pipe.
+const BUSY_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Size of the buffers the system keeps for each direction of a pipe.
+const PIPE_BUFFER_SIZE: u32 = 64 * 1024;
+
+/// A manual-reset event, for waiting on overlapped I/O.
+#[derive(Debug)]
+struct Event(OwnedHandle);
+
+impl Event {
+    fn new() -> io::Result<Event> {
+        // Safety: no security attributes or name are passed.
+        let handle = unsafe { CreateEventW(ptr::null(), TRUE, FALSE, ptr::null()) };
+        if handle == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: the handle was just created, and nothing else owns it.
+        Ok(Event(unsafe {
+            OwnedHandle::from_raw_handle(handle as RawHandle)
+        }))
+    }
+
+    fn raw(&self) -> HANDLE {
+        self.0.as_raw_handle() as HANDLE
+    }
+}
+
+/// One end of a pipe, opened for overlapped I/O.
+#[derive(Debug)]
+struct Pipe {
+    handle: OwnedHandle,
+    /// Set once the pipe is dropped, to end any operation still waiting on the other end.
+    closed: Event,
+}
+
+impl Pipe {
+    /// Take ownership of a handle returned by `CreateFileW` or `CreateNamedPipeW`.
+    fn from_raw(handle: HANDLE) -> io::Result<Pipe> {
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: the handle is valid, and the caller passes its ownership on.
+        let handle = unsafe { OwnedHandle::from_raw_handle(handle as RawHandle) };
+        Ok(Pipe {
+            handle,
+            closed: Event::new()?,
+        })
+    }
+
+    fn raw(&self) -> HANDLE {
+        self.handle.as_raw_handle() as HANDLE
+    }
+
+    /// Start an overlapped operation, then block until it finishes or the pipe is closed.
+    ///
+    /// Returns the number of bytes transferred.
+    fn overlapped<F>(&self, event: &Event, start: F) -> io::Result<usize>
+    where
+        F: FnOnce(HANDLE, *mut OVERLAPPED) -> i32,
+    {
+        // Safety: an all-zero OVERLAPPED is the documented initial state.
+        let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+        overlapped.hEvent = event.raw();
+        if start(self.raw(), &mut overlapped) == FALSE {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(ERROR_IO_PENDING as i32) {
+                return Err(err);
+            }
+            let events = [event.raw(), self.closed.raw()];
+            // Safety: both handles stay open for the duration of the wait.
+            let woken = unsafe { WaitForMultipleObjects(2, events.as_ptr(), FALSE, INFINITE) };
+            if woken != WAIT_OBJECT_0 {
+                // Safety: cancels only this operation, which is still waited on below.
+                unsafe { CancelIoEx(self.raw(), &overlapped) };
+            }
+        }
+        let mut transferred = 0;
+        // Safety: `overlapped` lives until the operation is done, which this waits for.
+        if unsafe { GetOverlappedResult(self.raw(), &overlapped, &mut transferred, TRUE) } == FALSE
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(transferred as usize)
+    }
+
+    /// Wait for a client to open this server end.
+    fn accept(&self) -> io::Result<()> {
+        let event = Event::new()?;
+        // Safety: the pipe handle and `overlapped` are valid for the call.
+        match self.overlapped(&event, |h, overlapped| unsafe {
+            ConnectNamedPipe(h, overlapped)
+        }) {
+            // The client connected before we started waiting.
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_CONNECTED as i32) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// End any operation in progress, and fail any new one.
+    fn close(&self) {
+        // Safety: the event handle is valid.
+        unsafe { SetEvent(self.closed.raw()) };
+    }
+}
+
+/// The reading side of a pipe, run on the blocking thread pool.
+#[derive(Debug)]
+struct PipeReader {
+    pipe: Arc<Pipe>,
+    event: Event,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(u32::MAX as usize) as u32;
+        // Safety: the buffer is valid for `len` bytes until `overlapped` returns.
+        match self.pipe.overlapped(&self.event, |h, overlapped| unsafe {
+            ReadFile(h, buf.as_mut_ptr(), len, ptr::null_mut(), overlapped)
+        }) {
+            // The other end closed the pipe.
+            Err(e) if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) => Ok(0),
+            result => result,
+        }
+    }
+}
+
+/// The writing side of a pipe, run on the blocking thread pool.
+#[derive(Debug)]
+struct PipeWriter {
+    pipe: Arc<Pipe>,
+    event: Event,
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(u32::MAX as usize) as u32;
+        // Safety: the buffer is valid for `len` bytes until `overlapped` returns.
+        self.pipe.overlapped(&self.event, |h, overlapped| unsafe {
+            WriteFile(h, buf.as_ptr(), len, ptr::null_mut(), overlapped)
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A connected named pipe, from either end, as an async byte stream.
+///
+/// Reads and writes proceed independently, as on a socket.
+#[derive(Debug)]
+pub struct NamedPipeStream {
+    pipe: Arc<Pipe>,
+    reader: Unblock<PipeReader>,
+    writer: Unblock<PipeWriter>,
+}
+
+impl NamedPipeStream {
+    fn new(pipe: Arc<Pipe>) -> io::Result<NamedPipeStream> {
+        let buffer_size = PIPE_BUFFER_SIZE as usize;
+        Ok(NamedPipeStream {
+            reader: Unblock::with_capacity(
+                buffer_size,
+                PipeReader {
+                    pipe: Arc::clone(&pipe),
+                    event: Event::new()?,
+                },
+            ),
+            writer: Unblock::with_capacity(
+                buffer_size,
+                PipeWriter {
+                    pipe: Arc::clone(&pipe),
+                    event: Event::new()?,
+                },
+            ),
+            pipe,
+        })
+    }
+
+    /// Open the client end of a named pipe, waiting while all its instances are busy.
+    pub async fn connect(name: &str) -> io::Result<NamedPipeStream> {
+        let name = wide(name);
+        loop {
+            // Safety: the name is nul-terminated, and no security attributes are passed.
+            let handle = unsafe {
+                CreateFileW(
+                    name.as_ptr(),
+                    GENERIC_READ | GENERIC_WRITE,
+                    0,
+                    ptr::null(),
+                    OPEN_EXISTING,
+                    FILE_FLAG_OVERLAPPED,
+                    0,
+                )
+            };
+            match Pipe::from_raw(handle) {
+                Ok(pipe) => return NamedPipeStream::new(Arc::new(pipe)),
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => {
+                    task::sleep(BUSY_RETRY_INTERVAL).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for NamedPipeStream {
+    fn drop(&mut self) {
+        // Otherwise, a read waiting on the other end would keep the pipe open.
+        self.pipe.close();
+    }
+}
+
+impl AsyncRead for NamedPipeStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for NamedPipeStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_close(cx)
+    }
+}
+
+/// Incoming messages and the sink for outgoing ones, on a pipe that has completed the handshake.
+pub type FramedNamedPipe = (
+    MessageStream<ReadHalf<NamedPipeStream>>,
+    MessageSink<WriteHalf<NamedPipeStream>>,
+);
+
+/// Connect to a server listening on a named pipe, and perform the handshake,
+/// failing with `VrpnError::Timeout` if it takes longer than `timeout`.
+pub async fn connect_named_pipe(name: &str, timeout: Option<Duration>) -> Result<FramedNamedPipe> {
+    let mut pipe = NamedPipeStream::connect(name).await?;
+    futures_io::client(&mut pipe, timeout).await?;
+    Ok(framed_messages(pipe))
+}
+
+/// Accepts clients on a named pipe, one pipe instance per client.
+#[derive(Debug)]
+pub struct NamedPipeListener {
+    name: Vec<u16>,
+    next: Arc<Pipe>,
+    /// Waiting for a client to open `next`, kept should `accept` be dropped before one does.
+    accepting: Option<Task<io::Result<()>>>,
+}
+
+impl NamedPipeListener {
+    /// Create the first instance of the named pipe.
+    ///
+    /// Fails if another process already owns a pipe by that name.
+    pub fn bind(name: &str) -> Result<NamedPipeListener> {
+        let name = wide(name);
+        let next = Arc::new(create_instance(&name, true)?);
+        Ok(NamedPipeListener {
+            name,
+            next,
+            accepting: None,
+        })
+    }
+
+    /// Wait for a client to connect, and perform the handshake,
+    /// failing with `VrpnError::Timeout` if it takes longer than `timeout`.
+    pub async fn accept(&mut self, timeout: Option<Duration>) -> Result<FramedNamedPipe> {
+        let waiting = Arc::clone(&self.next);
+        let accepting = self
+            .accepting
+            .get_or_insert_with(|| blocking::unblock(move || waiting.accept()));
+        let accepted = accepting.await;
+        self.accepting = None;
+        accepted?;
+        // Create the next instance before handing this one out, so clients are never turned away.
+        let next = Arc::new(create_instance(&self.name, false)?);
+        let mut pipe = NamedPipeStream::new(mem::replace(&mut self.next, next))?;
+        futures_io::server(&mut pipe, timeout).await?;
+        Ok(framed_messages(pipe))
+    }
+}
+
+impl Drop for NamedPipeListener {
+    fn drop(&mut self) {
+        // Ends an accept still waiting after its future was dropped.
+        self.next.close();
+    }
+}
+
+/// Create an instance of the server end of a named pipe.
+fn create_instance(name: &[u16], first: bool) -> io::Result<Pipe> {
+    let mut open_mode = PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED;
+    if first {
+        open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+    }
+    // Safety: the name is nul-terminated, and no security attributes are passed.
+    let handle = unsafe {
+        CreateNamedPipeW(
+            name.as_ptr(),
+            open_mode,
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES,
+            PIPE_BUFFER_SIZE,
+            PIPE_BUFFER_SIZE,
+            0,
+            ptr::null(),
+        )
+    };
+    Pipe::from_raw(handle)
+}
+
+/// A nul-terminated UTF-16 copy of a pipe name.
+fn wide(name: &str) -> Vec<u16> {
+    OsStr::new(name)
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{
+        id_types::{MessageTypeId, SenderId, SequenceNumber},
+        GenericBody, GenericMessage, Message, MessageHeader, SequencedGenericMessage, TimeVal,
+    };
+    use bytes::Bytes;
+    use futures::{join, SinkExt, StreamExt};
+
+    fn test_message(seq: u32) -> SequencedGenericMessage {
+        GenericMessage::from_header_and_body(
+            MessageHeader::new(Some(TimeVal::default()), MessageTypeId(1), SenderId(2)),
+            GenericBody::new(Bytes::from(vec![seq as u8; seq as usize])),
+        )
+        .into_sequenced_message(SequenceNumber(seq))
+    }
+
+    #[test]
+    fn pipe_roundtrip() {
+        task::block_on(async {
+            let name = format!(r"\\.\pipe\vrpn-test-{}", std::process::id());
+            let timeout = Some(Duration::from_secs(5));
+            let mut listener = NamedPipeListener::bind(&name).unwrap();
+            let (server, client) =
+                join!(listener.accept(timeout), connect_named_pipe(&name, timeout));
+            let ((mut server_rx, mut server_tx), (mut client_rx, mut client_tx)) =
+                (server.unwrap(), client.unwrap());
+
+            // Both directions at once: a read waiting on one end doesn't hold up writes.
+            client_tx.send(test_message(1)).await.unwrap();
+            server_tx.send(test_message(2)).await.unwrap();
+            assert_eq!(server_rx.next().await.unwrap().unwrap(), test_message(1));
+            assert_eq!(client_rx.next().await.unwrap().unwrap(), test_message(2));
+
+            // Dropping one end ends the other's stream.
+            drop(client_rx);
+            drop(client_tx);
+            assert!(server_rx.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn second_listener_rejected() {
+        let name = format!(r"\\.\pipe\vrpn-test-bind-{}", std::process::id());
+        let _listener = NamedPipeListener::bind(&name).unwrap();
+        assert!(NamedPipeListener::bind(&name).is_err());
+    }
+}
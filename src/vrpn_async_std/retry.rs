@@ -0,0 +1,142 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Timeouts and retries for connection futures.
+//!
+//! Only errors for which `VrpnError::is_retryable` is true get retried:
+//! trying again won't help with, say, a server speaking the wrong protocol version.
+
+use crate::{Result, VrpnError};
+use std::{future::Future, time::Duration};
+
+/// Run a future, failing with `VrpnError::Timeout` if it hasn't finished within `duration`.
+pub async fn with_timeout<T, F>(duration: Duration, future: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    async_std::future::timeout(duration, future)
+        .await
+        .unwrap_or(Err(VrpnError::Timeout))
+}
+
+/// How often, and how patiently, `retry_with_backoff` tries again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    /// Delay before the first retry.
+    pub initial_delay: Duration,
+    /// The delay doubles after each retry, up to this.
+    pub max_delay: Duration,
+    /// Give up after this many attempts in total, or never if None.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before the retry following the given (zero-based) attempt.
+    fn delay_after(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        self.initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+/// Call `make_future` and await the result, calling it again after a growing delay
+/// as long as it fails with a retryable error.
+///
+/// Returns the first success, the first non-retryable error,
+/// or the last error once `backoff.max_attempts` is reached.
+pub async fn retry_with_backoff<T, F, Fut>(backoff: Backoff, mut make_future: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        let err = match make_future().await {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+        attempt += 1;
+        if !err.is_retryable() || backoff.max_attempts.is_some_and(|max| attempt >= max) {
+            return Err(err);
+        }
+        let delay = backoff.delay_after(attempt - 1);
+        eprintln!("{}, retrying in {:?}", err, delay);
+        async_std::task::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{cookie::check_ver_nonfile_compatible, Version};
+    use std::cell::Cell;
+
+    fn quick(max_attempts: u32) -> Backoff {
+        Backoff {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+            max_attempts: Some(max_attempts),
+        }
+    }
+
+    #[test]
+    fn retries_only_retryable() {
+        async_std::task::block_on(async {
+            let calls = Cell::new(0);
+            let result = retry_with_backoff(quick(5), || async {
+                calls.set(calls.get() + 1);
+                if calls.get() < 3 {
+                    Err(VrpnError::CouldNotConnect)
+                } else {
+                    Ok(calls.get())
+                }
+            })
+            .await;
+            assert_eq!(result.unwrap(), 3);
+
+            calls.set(0);
+            let result: Result<()> = retry_with_backoff(quick(5), || async {
+                calls.set(calls.get() + 1);
+                check_ver_nonfile_compatible(Version { major: 6, minor: 0 })?;
+                Ok(())
+            })
+            .await;
+            assert!(matches!(result, Err(VrpnError::VersionMismatch(_))));
+            assert_eq!(calls.get(), 1);
+
+            calls.set(0);
+            let result: Result<()> = retry_with_backoff(quick(2), || async {
+                calls.set(calls.get() + 1);
+                with_timeout(Duration::from_millis(1), futures::future::pending()).await
+            })
+            .await;
+            assert!(matches!(result, Err(VrpnError::Timeout)));
+            assert_eq!(calls.get(), 2);
+        });
+    }
+
+    #[test]
+    fn delays() {
+        let backoff = Backoff {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            max_attempts: None,
+        };
+        assert_eq!(backoff.delay_after(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay_after(1), Duration::from_millis(200));
+        assert_eq!(backoff.delay_after(2), Duration::from_millis(300));
+        assert_eq!(backoff.delay_after(40), Duration::from_millis(300));
+    }
+}
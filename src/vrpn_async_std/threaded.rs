@@ -0,0 +1,353 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Running a connection on a dedicated background thread, for applications without an async runtime.
+//!
+//! Game engines and other frame-based applications usually can't host an async runtime
+//! in their main loop. A `ThreadedConnection` drives a `ConnectionIp` on its own thread,
+//! and hands messages and events over through `std::sync::mpsc` channels,
+//! to be drained without blocking, e.g. once per frame.
+//!
+//! Handlers added to the connection run on the background thread:
+//! use `ThreadedConnection::typed_channel` to receive messages on the application's thread instead.
+//! Sending works from any thread, through the `Connection` methods of `connection()`.
+
+use super::connection_ip::{ConnectionIp, ConnectionIpEventStream};
+use crate::{
+    buffer_unbuffer::UnbufferFrom,
+    connection::{Connection, ConnectionEvent},
+    data_types::{
+        id_types::{LocalId, SenderId},
+        TypedMessage, TypedMessageBody,
+    },
+    handler::{HandlerCode, HandlerHandle, TypedHandler},
+    Result, VrpnError,
+};
+use futures::{channel::oneshot, StreamExt};
+use std::{
+    fmt,
+    sync::{
+        mpsc::{channel, Receiver, Sender, TryRecvError},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Forwards messages to a channel, removing itself once the receiver is gone.
+struct ChannelForwarder<T: TypedMessageBody> {
+    tx: Sender<TypedMessage<T>>,
+}
+
+impl<T: TypedMessageBody> fmt::Debug for ChannelForwarder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChannelForwarder").finish()
+    }
+}
+
+impl<T> TypedHandler for ChannelForwarder<T>
+where
+    T: TypedMessageBody + UnbufferFrom + fmt::Debug + Clone + Send,
+{
+    type Item = T;
+    fn handle_typed(&mut self, msg: &TypedMessage<T>) -> Result<HandlerCode> {
+        Ok(match self.tx.send(msg.clone()) {
+            Ok(()) => HandlerCode::ContinueProcessing,
+            Err(_) => HandlerCode::RemoveThisHandler,
+        })
+    }
+}
+
+/// A connection driven by a background thread.
+///
+/// Dropping it stops the thread, waiting for it to finish.
+pub struct ThreadedConnection {
+    connection: Arc<ConnectionIp>,
+    events: Mutex<Receiver<Result<ConnectionEvent>>>,
+    /// The error that stopped the thread, received along with events not yet taken.
+    error: Mutex<Option<VrpnError>>,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ThreadedConnection {
+    /// Start driving the connection on a new thread.
+    ///
+    /// The connection should not be driven anywhere else from now on.
+    pub fn spawn(connection: Arc<ConnectionIp>) -> Result<ThreadedConnection> {
+        let (events_tx, events) = channel();
+        let (stop, stopped) = oneshot::channel();
+        let driven = Arc::clone(&connection);
+        let thread = thread::Builder::new()
+            .name("vrpn-connection".to_string())
+            .spawn(move || {
+                async_std::task::block_on(async move {
+                    let mut stream = ConnectionIpEventStream::new(driven).take_until(stopped);
+                    while let Some(event) = stream.next().await {
+                        // An error leaves the connection unusable, so stop driving it.
+                        let failed = event.is_err();
+                        if events_tx.send(event).is_err() || failed {
+                            break;
+                        }
+                    }
+                })
+            })?;
+        Ok(ThreadedConnection {
+            connection,
+            events: Mutex::new(events),
+            error: Mutex::new(None),
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// The connection, for registering senders and types, adding handlers, and sending.
+    pub fn connection(&self) -> &Arc<ConnectionIp> {
+        &self.connection
+    }
+
+    /// Receive the messages of type `T`, optionally only from one sender, through a channel.
+    ///
+    /// The handler forwarding to the channel removes itself once the receiver is dropped
+    /// and another message arrives.
+    pub fn typed_channel<T>(
+        &self,
+        sender: Option<LocalId<SenderId>>,
+    ) -> Result<(Receiver<TypedMessage<T>>, HandlerHandle)>
+    where
+        T: TypedMessageBody + UnbufferFrom + fmt::Debug + Clone + Send + Sync + 'static,
+    {
+        let (tx, rx) = channel();
+        let handle = self
+            .connection
+            .add_typed_handler(Box::new(ChannelForwarder { tx }), sender)?;
+        Ok((rx, handle))
+    }
+
+    /// Take the events that occurred since the last call, without blocking.
+    ///
+    /// Returns the error that stopped the background thread, if any,
+    /// once the events before it have been taken.
+    pub fn poll_events(&self) -> Result<Vec<ConnectionEvent>> {
+        let rx = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut error = self.error.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(e) = error.take() {
+            return Err(e);
+        }
+        let mut events = Vec::new();
+        loop {
+            match rx.try_recv() {
+                Ok(Ok(event)) => events.push(event),
+                Ok(Err(e)) if events.is_empty() => return Err(e),
+                Ok(Err(e)) => {
+                    // Report it next time, after these.
+                    *error = Some(e);
+                    return Ok(events);
+                }
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return Ok(events),
+            }
+        }
+    }
+
+    /// Whether the background thread is still driving the connection.
+    ///
+    /// It stops on error, or once a connection without reconnecting loses its endpoints.
+    pub fn is_running(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Stop the background thread and wait for it to finish.
+    pub fn stop(mut self) -> Result<()> {
+        self.stop_thread()
+    }
+
+    fn stop_thread(&mut self) -> Result<()> {
+        if let Some(stop) = self.stop.take() {
+            // Fails only if the thread already finished.
+            let _ = stop.send(());
+        }
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| VrpnError::OtherMessage("connection thread panicked".to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for ThreadedConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ThreadedConnection")
+            .field("running", &self.is_running())
+            .finish()
+    }
+}
+
+impl Drop for ThreadedConnection {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop_thread() {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{id_types::SequenceNumber, GenericMessage, TimeVal},
+        handshake::{futures_io::perform_handshake, Handshake},
+        ping::Ping,
+        subscription::{Subscription, SubscriptionState},
+        vrpn_async::AsyncWriteMessagesExt,
+        ServerInfo, TypeDispatcher,
+    };
+    use async_std::net::TcpListener;
+    use futures::SinkExt;
+    use std::{convert::TryFrom, time::Duration};
+
+    #[test]
+    fn messages_and_events() {
+        let listener = async_std::task::block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // A minimal server: handshake, describing a sender and type, send a message, and close.
+        let (subscribed, wait_for_subscriber) = channel();
+        let server = thread::spawn(move || {
+            async_std::task::block_on(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut dispatcher = TypeDispatcher::new();
+                let sender = dispatcher.register_sender("Tracker0").unwrap().into_inner();
+                let message_type = dispatcher.type_id_for(Ping::MESSAGE_IDENTIFIER).unwrap();
+                let mut handshake = Handshake::server()
+                    .with_descriptions(dispatcher.pack_all_descriptions().unwrap());
+                perform_handshake(&mut stream, &mut handshake)
+                    .await
+                    .unwrap();
+                wait_for_subscriber.recv().unwrap();
+                let msg = TypedMessage::new(Some(TimeVal::default()), message_type, sender, Ping);
+                let msg = GenericMessage::try_from(msg).unwrap();
+                let mut sink = stream.message_sink();
+                sink.send(msg.into_sequenced_message(SequenceNumber(0)))
+                    .await
+                    .unwrap();
+                async_std::task::sleep(Duration::from_millis(200)).await;
+            })
+        });
+
+        let server_info = format!("tcp://127.0.0.1:{}", port)
+            .parse::<ServerInfo>()
+            .unwrap();
+        let connection = ConnectionIp::new_client(server_info, None, None).unwrap();
+        let sender = connection.register_sender("Tracker0").unwrap();
+        let threaded = ThreadedConnection::spawn(connection).unwrap();
+        let (rx, _) = threaded.typed_channel::<Ping>(Some(sender)).unwrap();
+        // Descriptions are applied after the messages read along with them,
+        // so only have the server send once they've been.
+        let described = Subscription::<Ping>::new(threaded.connection(), "Tracker0").unwrap();
+        while described.state() == SubscriptionState::Waiting {
+            thread::sleep(Duration::from_millis(10));
+        }
+        subscribed.send(()).unwrap();
+        let msg = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(msg.header.sender, sender.0);
+        server.join().unwrap();
+
+        // The server closed, and without reconnecting, the thread finishes.
+        let mut events = Vec::new();
+        for _ in 0..50 {
+            events.extend(threaded.poll_events().unwrap());
+            if !threaded.is_running() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        events.extend(threaded.poll_events().unwrap());
+        assert!(!threaded.is_running());
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, ConnectionEvent::EndpointClosed(_))));
+        threaded.stop().unwrap();
+    }
+
+    #[test]
+    fn error_after_events() {
+        use crate::{
+            buffer_unbuffer::BytesMutExtras,
+            data_types::{cookie::Version, CookieData},
+        };
+        use bytes::BytesMut;
+        use futures::AsyncWriteExt;
+
+        let listener = async_std::task::block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Accept a client and close, then answer its reconnection with an incompatible version.
+        let server = thread::spawn(move || {
+            async_std::task::block_on(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                perform_handshake(&mut stream, &mut Handshake::server())
+                    .await
+                    .unwrap();
+                drop(stream);
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let cookie = CookieData {
+                    version: Version {
+                        major: 99,
+                        minor: 0,
+                    },
+                    log_mode: None,
+                };
+                stream
+                    .write_all(&BytesMut::allocate_and_buffer(cookie).unwrap())
+                    .await
+                    .unwrap();
+                async_std::task::sleep(Duration::from_millis(200)).await;
+            })
+        });
+
+        let server_info = format!("tcp://127.0.0.1:{}", port)
+            .parse::<ServerInfo>()
+            .unwrap();
+        let connection = ConnectionIp::client_builder(server_info)
+            .reconnect(true)
+            .build()
+            .unwrap();
+        let threaded = ThreadedConnection::spawn(connection).unwrap();
+        for _ in 0..50 {
+            if !threaded.is_running() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(!threaded.is_running());
+        server.join().unwrap();
+
+        // The events from losing the first connection come first, then the error.
+        let events = threaded.poll_events().unwrap();
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, ConnectionEvent::EndpointClosed(_))));
+        assert!(matches!(
+            threaded.poll_events(),
+            Err(VrpnError::VersionMismatch(_))
+        ));
+        assert!(threaded.poll_events().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stop_while_connecting() {
+        // Nothing accepts, so the connection stays connecting until stopped.
+        let listener = async_std::task::block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_info = format!("tcp://127.0.0.1:{}", port)
+            .parse::<ServerInfo>()
+            .unwrap();
+        let connection = ConnectionIp::new_client(server_info, None, None).unwrap();
+        let threaded = ThreadedConnection::spawn(connection).unwrap();
+        assert!(threaded.is_running());
+        assert!(threaded.poll_events().unwrap().is_empty());
+        threaded.stop().unwrap();
+        drop(listener);
+    }
+}
@@ -3,8 +3,9 @@
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
 use crate::{
-    data_types::{id_types::SequenceNumber, GenericMessage},
+    clock::Instant,
     error::to_other_error,
+    protocol::{ProtocolSender, QueuedMessage, SendCounters, Transmit},
     Result, VrpnError,
 };
 use futures::{
@@ -14,50 +15,109 @@ use futures::{
 use std::{
     fmt::Debug,
     pin::Pin,
+    sync::{atomic::Ordering, Arc},
     task::{Context, Poll},
 };
 
-/// The actual async function underlying UnboundedMessageSender
+/// The actual async function underlying UnboundedMessageSender: drives a `ProtocolSender`.
+///
+/// The only writer of `stream`: every message is framed into one buffer and written with
+/// `write_all` before the next is taken, so frames from different senders can't interleave.
+/// Everything already queued is handed to the protocol before writing, so superseded values are never written.
 async fn sender<T: AsyncWrite>(
     stream: T,
-    channel_rx: mpsc::UnboundedReceiver<GenericMessage>,
+    channel_rx: mpsc::UnboundedReceiver<QueuedMessage>,
+    mut protocol: ProtocolSender,
 ) -> Result<()> {
-    let mut seq: u32 = 0;
-    let mut channel_rx = channel_rx;
+    let counters = protocol.counters();
     let mut stream = Box::pin(BufWriter::new(stream));
-    while let Some(msg) = channel_rx.next().await {
-        seq += 1;
-        let msg = msg.into_sequenced_message(SequenceNumber(seq));
-        let buf = msg.try_into_buf()?;
-        stream.write_all(&buf).await?;
+    let mut channel_rx = channel_rx;
+    let mut closed = false;
+    loop {
+        while !closed {
+            match channel_rx.try_next() {
+                Ok(Some(msg)) => protocol.push(msg),
+                Ok(None) => closed = true,
+                Err(_) => break,
+            }
+        }
+        let allowed = match protocol.poll_transmit(Instant::now())? {
+            Transmit::Frame(buf) => {
+                stream.write_all(&buf).await?;
+                counters
+                    .unflushed_bytes
+                    .store(stream.buffer().len(), Ordering::Relaxed);
+                continue;
+            }
+            Transmit::WaitUntil(allowed) => Some(allowed),
+            Transmit::Idle if closed => break,
+            Transmit::Idle => None,
+        };
+        // Nothing more to write right now: send what we have before waiting.
+        flush(stream.as_mut(), &counters).await?;
+        let next = match allowed {
+            Some(allowed) => {
+                let remaining = allowed.saturating_duration_since(Instant::now());
+                if closed {
+                    async_std::task::sleep(remaining).await;
+                    continue;
+                }
+                // A system message queued meanwhile may go out first.
+                match async_std::future::timeout(remaining, channel_rx.next()).await {
+                    Ok(next) => next,
+                    Err(_) => continue,
+                }
+            }
+            None => channel_rx.next().await,
+        };
+        match next {
+            Some(msg) => protocol.push(msg),
+            None => closed = true,
+        }
     }
+    flush(stream.as_mut(), &counters).await
+}
+
+async fn flush<T: AsyncWrite>(
+    mut stream: Pin<&mut BufWriter<T>>,
+    counters: &SendCounters,
+) -> Result<()> {
+    stream.flush().await?;
+    counters.unflushed_bytes.store(0, Ordering::Relaxed);
     Ok(())
 }
 
 type FusedBoxFuture<'a, T> = Pin<Box<dyn FusedFuture<Output = T> + Send + 'a>>;
 
 /// A structure that lets you send messages to some stream just like an unbounded channel
+///
+/// Any number of handles from `channel` may queue messages concurrently:
+/// they are written whole, one at a time, in the order queued.
 pub(crate) struct UnboundedMessageSender {
-    channel_tx: mpsc::UnboundedSender<GenericMessage>,
+    channel_tx: mpsc::UnboundedSender<QueuedMessage>,
     send_future: FusedBoxFuture<'static, Result<()>>,
+    counters: Arc<SendCounters>,
 }
 
 impl UnboundedMessageSender {
-    /// Create a future that pumps transmission of sequenced messages to an AsyncWrite implementation.
+    /// Create a future that pumps transmission of messages from `protocol` to an AsyncWrite implementation.
     pub(crate) fn new<T: 'static + AsyncWrite + Send>(
         writer: T,
+        protocol: ProtocolSender,
     ) -> Pin<Box<UnboundedMessageSender>> {
         let (channel_tx, channel_rx) = mpsc::unbounded();
+        let counters = protocol.counters();
         Box::pin(UnboundedMessageSender {
             channel_tx,
-            send_future: Box::pin(sender(writer, channel_rx).fuse()),
+            send_future: Box::pin(sender(writer, channel_rx, protocol).fuse()),
+            counters,
         })
     }
 }
 
 impl UnboundedMessageSender {
     /// Queues a message to be sequenced and sent.
-    pub(crate) fn unbounded_send(self: Pin<&mut Self>, msg: GenericMessage) -> Result<()> {
+    pub(crate) fn unbounded_send(self: Pin<&mut Self>, msg: QueuedMessage) -> Result<()> {
         if self.is_terminated() {
             return Err(VrpnError::EndpointClosed);
         }
@@ -67,6 +127,16 @@ impl UnboundedMessageSender {
         Ok(())
     }
 
+    /// Get another handle for queueing messages, e.g. for a different task.
+    pub(crate) fn channel(&self) -> mpsc::UnboundedSender<QueuedMessage> {
+        self.channel_tx.clone()
+    }
+
+    /// Get the counts of messages queued, written, and dropped, shared with the sending task.
+    pub(crate) fn counters(&self) -> Arc<SendCounters> {
+        Arc::clone(&self.counters)
+    }
+
     /// Closes the channel feeding this this sender
     pub(crate) fn close(&mut self) {
         if !self.is_terminated() {
@@ -99,3 +169,101 @@ impl FusedFuture for UnboundedMessageSender {
         self.send_future.is_terminated() || self.channel_tx.is_closed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{
+            id_types::{MessageTypeId, SenderId, SequenceNumber},
+            GenericBody, GenericMessage, Message, MessageHeader, TimeVal,
+        },
+        vrpn_async::{AsyncReadMessagesExt, BandwidthLimit},
+    };
+    use bytes::Bytes;
+    use futures::{executor::block_on, io::Cursor};
+    use std::time::Duration;
+
+    fn test_message(body: &'static [u8]) -> GenericMessage {
+        GenericMessage::from_header_and_body(
+            MessageHeader::new(Some(TimeVal::default()), MessageTypeId(1), SenderId(2)),
+            GenericBody::new(Bytes::from_static(body)),
+        )
+    }
+
+    #[test]
+    fn shapes_bandwidth() {
+        let mut written = Vec::new();
+        let protocol = ProtocolSender::new(SequenceNumber(1));
+        protocol.set_bandwidth_limit(BandwidthLimit::default().with_messages_per_sec(100));
+        let counters = protocol.counters();
+        let (tx, rx) = mpsc::unbounded();
+        // A second's worth goes out at once, the rest wait their turn.
+        for _ in 0..102 {
+            tx.unbounded_send(QueuedMessage::from(test_message(b"report")))
+                .unwrap();
+        }
+        drop(tx);
+        let start = Instant::now();
+        block_on(sender(Cursor::new(&mut written), rx, protocol)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(counters.throttled.load(Ordering::Relaxed), 2);
+        assert_eq!(counters.unrecorded_messages.load(Ordering::Relaxed), 102);
+        assert_eq!(counters.unflushed_bytes.load(Ordering::Relaxed), 0);
+        assert_eq!(
+            counters.unrecorded_bytes.load(Ordering::Relaxed),
+            written.len() as u64
+        );
+    }
+
+    #[test]
+    fn system_messages_bypass_throttling() {
+        let mut written = Vec::new();
+        let protocol = ProtocolSender::new(SequenceNumber(1));
+        protocol.set_bandwidth_limit(BandwidthLimit::default().with_messages_per_sec(10));
+        let counters = protocol.counters();
+        let (tx, rx) = mpsc::unbounded();
+        let system = GenericMessage::from_header_and_body(
+            MessageHeader::new(Some(TimeVal::default()), MessageTypeId(-1), SenderId(2)),
+            GenericBody::new(Bytes::from_static(b"system")),
+        );
+        // The last report waits 100ms for the limit to allow it.
+        for _ in 0..10 {
+            tx.unbounded_send(QueuedMessage::from(test_message(b"report")))
+                .unwrap();
+        }
+        tx.unbounded_send(QueuedMessage::from(test_message(b"last")))
+            .unwrap();
+        let queue_system = async {
+            async_std::task::sleep(Duration::from_millis(20)).await;
+            tx.unbounded_send(QueuedMessage::from(system.clone()))
+                .unwrap();
+            tx.close_channel();
+        };
+        let ((), result) = block_on(async {
+            futures::join!(
+                queue_system,
+                sender(Cursor::new(&mut written), rx, protocol)
+            )
+        });
+        result.unwrap();
+        assert_eq!(counters.throttled.load(Ordering::Relaxed), 1);
+
+        let received: Vec<_> = block_on(
+            Cursor::new(written)
+                .messages()
+                .map(|msg| msg.unwrap())
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(received.len(), 12);
+        // Sent ahead of the throttled report, and sequenced in the order written.
+        assert_eq!(
+            received[10],
+            system.into_sequenced_message(SequenceNumber(11))
+        );
+        assert_eq!(
+            received[11],
+            test_message(b"last").into_sequenced_message(SequenceNumber(12))
+        );
+    }
+}
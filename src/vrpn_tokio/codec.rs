@@ -3,36 +3,47 @@
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
 use crate::{
-    buffer_unbuffer::BufferSize, codec::maybe_decode_one,
-    data_types::message::SequencedGenericMessage, Result, VrpnError,
+    buffer_unbuffer::BytesMutExtras,
+    codec::{decode_one_from_bytes_mut, MessageSizeLimit, SizeCheck},
+    data_types::{message::SequencedGenericMessage, ProtocolProfile},
+    Result, VrpnError,
 };
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::BytesMut;
 use tokio_util::codec::{Decoder, Encoder, Framed};
 
 /// Codec providing VRPN message framing.
 ///
 /// Serializes/deserializes generic messages.
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
-pub struct FramedMessageCodec;
+///
+/// Incoming messages larger than its `MessageSizeLimit` are an error,
+/// reported as soon as their length field arrives.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FramedMessageCodec {
+    size_check: SizeCheck,
+}
+
+impl FramedMessageCodec {
+    /// A codec with the given limit on incoming message size.
+    pub fn with_limit(limit: MessageSizeLimit) -> FramedMessageCodec {
+        FramedMessageCodec {
+            size_check: SizeCheck::new(limit),
+        }
+    }
+
+    /// The limit on incoming message size.
+    pub fn limit(&self) -> MessageSizeLimit {
+        self.size_check.limit()
+    }
+}
 
 impl Decoder for FramedMessageCodec {
     type Item = SequencedGenericMessage;
     type Error = VrpnError;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
-        let initial_len = src.len();
-        if initial_len == 0 {
-            // short-circuit if we have run out of stuff.
+        if !self.size_check.check(src, &ProtocolProfile::VRPN)? {
             return Ok(None);
         }
-        let mut inner_buf = src.clone();
-        match maybe_decode_one(&mut inner_buf)? {
-            Some(msg) => {
-                let consumed = initial_len - inner_buf.len();
-                src.advance(consumed);
-                Ok(Some(msg))
-            }
-            None => Ok(None),
-        }
+        Ok(decode_one_from_bytes_mut(src)?)
     }
 }
 
@@ -43,19 +54,30 @@ impl Encoder<SequencedGenericMessage> for FramedMessageCodec {
         item: SequencedGenericMessage,
         dst: &mut BytesMut,
     ) -> std::result::Result<(), Self::Error> {
-        dst.reserve(item.buffer_size());
-        let buf = item.try_into_buf()?;
-        dst.put(buf);
+        dst.reserve_and_buffer(&item)?;
         Ok(())
     }
 }
 
 pub type MessageFramed<T> = Framed<T, FramedMessageCodec>;
 
+/// Apply VRPN message framing to any bidirectional stream:
+/// a TCP stream, but also e.g. a TLS stream, a Unix socket, or an in-memory `tokio::io::duplex`.
 pub fn apply_message_framing<T: tokio::io::AsyncRead + tokio::io::AsyncWrite>(
     stream: T,
 ) -> MessageFramed<T> {
-    Decoder::framed(FramedMessageCodec {}, stream)
+    apply_message_framing_with_limit(stream, MessageSizeLimit::default())
+}
+
+/// Like `apply_message_framing`, with a different limit on incoming message size.
+///
+/// A `Framed` ends its stream after an error, so even with `OversizePolicy::Skip`
+/// (`crate::codec::OversizePolicy`), an oversized message ends it.
+pub fn apply_message_framing_with_limit<T: tokio::io::AsyncRead + tokio::io::AsyncWrite>(
+    stream: T,
+    limit: MessageSizeLimit,
+) -> MessageFramed<T> {
+    Decoder::framed(FramedMessageCodec::with_limit(limit), stream)
 }
 
 #[cfg(test)]
@@ -83,7 +105,7 @@ mod tests {
     fn individual_decode() {
         for msg_bytes in &get_test_messages() {
             let mut data = BytesMut::from(&msg_bytes[..]);
-            let decoded = FramedMessageCodec.decode(&mut data);
+            let decoded = FramedMessageCodec::default().decode(&mut data);
             assert!(decoded.is_ok());
             let decoded = decoded.unwrap();
             assert!(decoded.is_some());
@@ -99,9 +121,24 @@ mod tests {
         }
         let mut data = BytesMut::from(&all_bytes[..]);
         let mut decoded = Vec::new();
-        decoded.push(FramedMessageCodec.decode(&mut data).unwrap().unwrap());
-        decoded.push(FramedMessageCodec.decode(&mut data).unwrap().unwrap());
-        decoded.push(FramedMessageCodec.decode(&mut data).unwrap().unwrap());
+        decoded.push(
+            FramedMessageCodec::default()
+                .decode(&mut data)
+                .unwrap()
+                .unwrap(),
+        );
+        decoded.push(
+            FramedMessageCodec::default()
+                .decode(&mut data)
+                .unwrap()
+                .unwrap(),
+        );
+        decoded.push(
+            FramedMessageCodec::default()
+                .decode(&mut data)
+                .unwrap()
+                .unwrap(),
+        );
 
         assert_eq!(
             &to_sender_inner_desc(&decoded[0]).body.name[..],
@@ -117,4 +154,115 @@ mod tests {
             &b"VRPN_Connection_Got_First_Connection"[..]
         );
     }
+
+    #[test]
+    fn decode_partial() {
+        for msg_bytes in &get_test_messages() {
+            for split in 0..msg_bytes.len() {
+                let mut data = BytesMut::from(&msg_bytes[..split]);
+                assert!(FramedMessageCodec::default()
+                    .decode(&mut data)
+                    .unwrap()
+                    .is_none());
+                assert_eq!(data.len(), split);
+                data.extend_from_slice(&msg_bytes[split..]);
+                assert!(FramedMessageCodec::default()
+                    .decode(&mut data)
+                    .unwrap()
+                    .is_some());
+                assert_eq!(data.len(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_coalesced_split_anywhere() {
+        let all_bytes: Vec<u8> = get_test_messages().into_iter().flatten().collect();
+        for split in 0..all_bytes.len() {
+            let mut data = BytesMut::from(&all_bytes[..split]);
+            let mut decoded = Vec::new();
+            while let Some(msg) = FramedMessageCodec::default().decode(&mut data).unwrap() {
+                decoded.push(msg);
+            }
+            data.extend_from_slice(&all_bytes[split..]);
+            while let Some(msg) = FramedMessageCodec::default().decode(&mut data).unwrap() {
+                decoded.push(msg);
+            }
+            assert_eq!(decoded.len(), 3);
+            assert_eq!(data.len(), 0);
+            assert_eq!(
+                &to_sender_inner_desc(&decoded[1]).body.name[..],
+                &b"Tracker0"[..]
+            );
+        }
+    }
+
+    #[test]
+    fn decode_oversized() {
+        use crate::{
+            buffer_unbuffer::BufferUnbufferError,
+            codec::{MessageSizeLimit, OversizePolicy},
+        };
+        let messages = get_test_messages();
+        let limit = messages[1].len();
+
+        let mut codec = FramedMessageCodec::with_limit(MessageSizeLimit::new(limit));
+        let mut data = BytesMut::from(&messages[0][..]);
+        assert!(matches!(
+            codec.decode(&mut data),
+            Err(VrpnError::BufferUnbuffer(
+                BufferUnbufferError::MessageTooLarge { max, .. }
+            )) if max == limit
+        ));
+
+        // Skipped over, with the message after it decoded.
+        let mut codec = FramedMessageCodec::with_limit(
+            MessageSizeLimit::new(limit).with_policy(OversizePolicy::Skip),
+        );
+        let mut data = BytesMut::from(&messages[0][..]);
+        data.extend_from_slice(&messages[1]);
+        assert!(codec.decode(&mut data).is_err());
+        let msg = codec.decode(&mut data).unwrap().unwrap();
+        assert_eq!(&to_sender_inner_desc(&msg).body.name[..], &b"Tracker0"[..]);
+        assert_eq!(data.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn duplex_roundtrip() {
+        use futures::{SinkExt, StreamExt};
+        let (a, b) = tokio::io::duplex(64);
+        let mut a = apply_message_framing(a);
+        let mut b = apply_message_framing(b);
+
+        let messages: Vec<_> = get_test_messages()
+            .into_iter()
+            .map(|msg_bytes| {
+                FramedMessageCodec::default()
+                    .decode(&mut BytesMut::from(&msg_bytes[..]))
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect();
+
+        // Larger than the duplex buffer in total, so this exercises partial reads and writes.
+        let sending = async {
+            for msg in &messages {
+                a.send(msg.clone()).await.unwrap();
+            }
+            a
+        };
+        let receiving = async {
+            let mut received = Vec::new();
+            for _ in 0..messages.len() {
+                received.push(b.next().await.unwrap().unwrap());
+            }
+            received
+        };
+        let (a, received) = tokio::join!(sending, receiving);
+        assert_eq!(received, messages);
+
+        // Dropping one end ends the other's stream.
+        drop(a);
+        assert!(b.next().await.is_none());
+    }
 }
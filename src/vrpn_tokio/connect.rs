@@ -2,11 +2,12 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-use super::cookie::{read_and_check_nonfile_cookie, send_nonfile_cookie};
+use super::cookie::perform_handshake;
 use crate::{
     buffer_unbuffer::{BytesMutExtras, ConstantBufferSize, UnbufferFrom},
     data_types::{cookie::check_ver_nonfile_compatible, CookieData},
-    Result, Scheme, ServerInfo, VrpnError, ConnectionStatus,
+    handshake::Handshake,
+    ConnectionStatus, Result, Scheme, ServerInfo, VrpnError,
 };
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::ready;
@@ -40,7 +41,9 @@ pub fn make_tcp_socket(addr: SocketAddr) -> io::Result<socket2::Socket> {
             let addr = std::net::SocketAddrV4::new(any, 0);
             sock.bind(&socket2::SockAddr::from(addr))?;
         } else {
-            unimplemented!();
+            return Err(io::Error::other(
+                "IPv6 outgoing connections are not supported on Windows",
+            ));
         }
     }
     sock.set_reuse_address(true)?;
@@ -73,9 +76,7 @@ pub async fn outgoing_handshake<T>(socket: &mut T) -> Result<()>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
 {
-    send_nonfile_cookie(socket).await?;
-    read_and_check_nonfile_cookie(socket).await
-    // TODO can pack log description here if we're enabling remote logging.
+    perform_handshake(socket, &mut Handshake::client()).await
     // TODO if we have permission to use UDP, open an incoming socket and notify the other end about it here.
 }
 
@@ -93,12 +94,7 @@ pub async fn incoming_handshake<T>(socket: &mut T) -> Result<()>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
 {
-    // If connection is incoming
-    read_and_check_nonfile_cookie(socket).await?;
-    send_nonfile_cookie(socket).await
-
-    // TODO can pack log description here if we're enabling remote logging.
-    // TODO should send descriptions here.
+    perform_handshake(socket, &mut Handshake::server()).await
 }
 
 /// A separate future, because couldn't get a boxed future built with combinators
@@ -351,7 +347,10 @@ const MILLIS_BETWEEN_ATTEMPTS: u64 = 500;
 
 async fn connect_tcp_and_udp(server: ServerInfo) -> Result<ConnectResults> {
     let udp = make_udp_socket()?;
-    let addr = "localhost".to_socket_addrs()?.next().unwrap();
+    let addr = "localhost"
+        .to_socket_addrs()?
+        .next()
+        .ok_or(VrpnError::CouldNotConnect)?;
     let addr = SocketAddr::new(addr.ip(), 0);
     let tcp_listener = TcpListener::bind(&addr).await?;
     let port = udp.local_addr()?.port();
@@ -560,12 +559,12 @@ mod tests {
     }
 
     #[test]
-    fn sync_connect() {
+    fn sync_connect() -> Result<()> {
         use crate::buffer_unbuffer::buffer::BufferTo;
 
         let addr: SocketAddr = "127.0.0.1:3883".parse().unwrap();
 
-        let mut sock = make_tcp_socket(addr.clone()).expect("failure making the socket");
+        let mut sock = make_tcp_socket(addr.clone())?;
         // sock.connect(&SockAddr::from(&addr)).unwrap();
 
         let cookie = CookieData::make_cookie();
@@ -577,6 +576,7 @@ mod tests {
         sock.read_exact(&mut read_buf).unwrap();
         let mut read_buf = Bytes::from(read_buf);
         let parsed_cookie: CookieData = UnbufferFrom::unbuffer_from(&mut read_buf).unwrap();
-        check_ver_nonfile_compatible(parsed_cookie.version).unwrap();
+        check_ver_nonfile_compatible(parsed_cookie.version)?;
+        Ok(())
     }
 }
@@ -121,9 +121,13 @@ where
         let poll_result = stream.poll_next_unpin(cx);
         match poll_result {
             Poll::Ready(Some(msg)) => {
+                // The framed stream reads and decodes within this poll, so the message
+                // arrived just now, not when we started waiting for it.
+                let start = dispatcher.stats().start_timing();
                 let msg = endpoint.map_remote_message_to_local(msg)?;
                 if let Some(nonsystem_msg) = endpoint.passthrough_nonsystem_message(msg)? {
                     dispatcher.call(&nonsystem_msg)?;
+                    dispatcher.stats_mut().finish_timing(start);
                 }
             }
             Poll::Ready(None) => {
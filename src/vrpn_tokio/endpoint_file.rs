@@ -27,7 +27,7 @@ impl EndpointFile {
         read_and_check_file_cookie(&mut file).await?;
         Ok(EndpointFile {
             translation: TranslationTables::new(),
-            file: FramedMessageCodec.framed(file),
+            file: FramedMessageCodec::default().framed(file),
             system_tx,
             system_rx,
         })
@@ -13,4 +13,4 @@ pub mod endpoint_ip;
 pub mod ping;
 // pub mod util;
 
-pub use self::codec::apply_message_framing;
+pub use self::codec::{apply_message_framing, apply_message_framing_with_limit};
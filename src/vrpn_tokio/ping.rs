@@ -7,12 +7,12 @@ use crate::{
         id_types::{LocalId, SenderId},
         name_types::SenderName,
     },
-    ping::Client as RawClient,
+    ping::{Client as RawClient, PingConfig},
     Connection, Result,
 };
 use futures::{ready, Stream};
+use std::sync::Arc;
 use std::task::Poll;
-use std::{sync::Arc, time::Duration};
 use tokio::time::{interval, Interval};
 
 pub struct Client<T: Connection + 'static> {
@@ -22,9 +22,10 @@ pub struct Client<T: Connection + 'static> {
 
 impl<T: Connection + 'static> Client<T> {
     fn new_impl(client: RawClient<T>) -> Result<Client<T>> {
+        let period = client.config()?.interval;
         Ok(Client {
             client,
-            interval: interval(Duration::from_secs(1)),
+            interval: interval(period),
         })
     }
     pub fn new(sender: LocalId<SenderId>, connection: Arc<T>) -> Result<Client<T>> {
@@ -37,6 +38,26 @@ impl<T: Connection + 'static> Client<T> {
     ) -> Result<Client<T>> {
         Client::new_impl(RawClient::new_from_name(sender, connection)?)
     }
+
+    pub fn with_config(
+        sender: LocalId<SenderId>,
+        connection: Arc<T>,
+        config: PingConfig,
+    ) -> Result<Client<T>> {
+        Client::new_impl(RawClient::with_config(sender, connection, config)?)
+    }
+
+    /// The current ping interval, unresponsive threshold, and unresponsive action.
+    pub fn config(&self) -> Result<PingConfig> {
+        self.client.config()
+    }
+
+    /// Change the ping interval, unresponsive threshold, and unresponsive action while running.
+    pub fn set_config(&mut self, config: PingConfig) -> Result<()> {
+        self.client.set_config(config)?;
+        self.interval = interval(config.interval);
+        Ok(())
+    }
 }
 
 impl<T: Connection + 'static> Stream for Client<T> {
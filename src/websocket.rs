@@ -0,0 +1,263 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! VRPN over WebSocket, for clients running in a browser, which can't open sockets.
+//!
+//! Each binary WebSocket message carries the next chunk of the byte stream a TCP connection
+//! would: the handshake cookies, then messages, split anywhere. So servers need nothing new:
+//! put a WebSocket-to-TCP proxy, such as websockify, in front of their port.
+//! Only the reliable channel is used, as browsers can't send UDP either.
+//!
+//! `EndpointWebSocket` drives a `ProtocolCore` over any stream and sink of binary messages,
+//! and `connect` opens one from a browser (only built for wasm32).
+
+use crate::{
+    data_types::{ClassOfService, GenericMessage},
+    endpoint::SystemCommand,
+    handshake::Handshake,
+    protocol::ProtocolCore,
+    translation_table::TranslationTables,
+    Endpoint, Result, TypeDispatcher,
+};
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use std::{
+    io,
+    task::{Context, Poll},
+};
+
+/// A client endpoint over a WebSocket, given as a stream and sink of binary messages.
+#[derive(Debug)]
+pub struct EndpointWebSocket<S> {
+    core: ProtocolCore,
+    socket: S,
+}
+
+impl<S> EndpointWebSocket<S>
+where
+    S: Stream<Item = io::Result<Vec<u8>>> + Sink<Vec<u8>, Error = io::Error> + Unpin,
+{
+    /// Wrap a newly opened WebSocket, performing the client handshake as part of polling.
+    pub fn new(socket: S) -> Result<EndpointWebSocket<S>> {
+        EndpointWebSocket::with_handshake(socket, Handshake::client())
+    }
+
+    /// Wrap a newly opened WebSocket, performing the given handshake as part of polling.
+    pub fn with_handshake(socket: S, handshake: Handshake) -> Result<EndpointWebSocket<S>> {
+        Ok(EndpointWebSocket {
+            core: ProtocolCore::new(handshake)?,
+            socket,
+        })
+    }
+
+    /// True once the handshake is complete.
+    pub fn is_connected(&self) -> bool {
+        self.core.is_connected()
+    }
+
+    /// Send whatever is buffered, then process and dispatch whatever has been received.
+    ///
+    /// Messages buffered through `Endpoint` are only sent when this is next polled.
+    ///
+    /// Is only ready when the WebSocket is closed.
+    pub fn poll_endpoint(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<()>> {
+        if let Poll::Ready(Err(e)) = self.poll_send(cx) {
+            return Poll::Ready(Err(e));
+        }
+        loop {
+            match self.socket.poll_next_unpin(cx) {
+                Poll::Ready(Some(data)) => {
+                    // Nothing this endpoint does with other system commands right now.
+                    let _ = self.core.receive(&data?, dispatcher)?;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => break,
+            }
+        }
+        // Receiving may have produced replies, such as the rest of the handshake.
+        match self.poll_send(cx) {
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            _ => Poll::Pending,
+        }
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.core.has_outgoing() {
+            ready!(self.socket.poll_ready_unpin(cx))?;
+            self.socket
+                .start_send_unpin(self.core.take_outgoing().to_vec())?;
+        }
+        Poll::Ready(Ok(ready!(self.socket.poll_flush_unpin(cx))?))
+    }
+}
+
+impl<S> Endpoint for EndpointWebSocket<S> {
+    fn translation_tables(&self) -> &TranslationTables {
+        self.core.translation_tables()
+    }
+
+    fn translation_tables_mut(&mut self) -> &mut TranslationTables {
+        self.core.translation_tables_mut()
+    }
+
+    fn send_system_change(&self, message: SystemCommand) -> Result<()> {
+        self.core.send_system_change(message)
+    }
+
+    fn buffer_generic_message(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
+        self.core.buffer_generic_message(msg, class)
+    }
+}
+
+/// Open a WebSocket from the browser to a URL like `ws://localhost:3884`,
+/// as an endpoint that still has to perform the handshake.
+#[cfg(target_arch = "wasm32")]
+pub async fn connect(url: &str) -> Result<EndpointWebSocket<ws_stream_wasm::WsStreamIo>> {
+    let (_, stream) = ws_stream_wasm::WsMeta::connect(url, None)
+        .await
+        .map_err(crate::error::to_other_error)?;
+    EndpointWebSocket::new(ws_stream_wasm::WsStreamIo::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{GenericBody, Message, MessageHeader, TimeVal},
+        handler::{Handler, HandlerCode},
+        EndpointGeneric,
+    };
+    use bytes::Bytes;
+    use futures::{
+        channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        task::noop_waker_ref,
+    };
+    use std::{
+        pin::Pin,
+        sync::{Arc, Mutex},
+    };
+
+    /// One side of an in-memory WebSocket.
+    struct Frames {
+        rx: UnboundedReceiver<Vec<u8>>,
+        tx: UnboundedSender<Vec<u8>>,
+    }
+
+    impl Stream for Frames {
+        type Item = io::Result<Vec<u8>>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.rx.poll_next_unpin(cx).map(|frame| frame.map(Ok))
+        }
+    }
+
+    impl Sink<Vec<u8>> for Frames {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> io::Result<()> {
+            self.tx
+                .unbounded_send(item)
+                .map_err(|_| io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[derive(Debug)]
+    struct Collect(Arc<Mutex<Vec<GenericMessage>>>);
+    impl Handler for Collect {
+        fn handle(&mut self, msg: &GenericMessage) -> Result<HandlerCode> {
+            self.0.lock()?.push(msg.clone());
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    #[test]
+    fn receives_through_frames() {
+        let (to_client, client_rx) = unbounded();
+        let (client_tx, mut from_client) = unbounded();
+        let mut client = EndpointWebSocket::new(Frames {
+            rx: client_rx,
+            tx: client_tx,
+        })
+        .unwrap();
+        let mut dispatcher = TypeDispatcher::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        dispatcher
+            .add_handler(Box::new(Collect(Arc::clone(&received))), None, None)
+            .unwrap();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        // The server's side is the TCP connection behind a proxy: just a byte stream.
+        let mut server_dispatcher = TypeDispatcher::new();
+        let mut server = ProtocolCore::new(Handshake::server()).unwrap();
+        let sender = server_dispatcher
+            .register_sender("Tracker0")
+            .unwrap()
+            .into_inner();
+        let message_type = server_dispatcher
+            .register_type("custom")
+            .unwrap()
+            .into_inner();
+        server
+            .new_local_id(&Bytes::from_static(b"Tracker0"), sender)
+            .unwrap();
+        server
+            .new_local_id(&Bytes::from_static(b"custom"), message_type)
+            .unwrap();
+        server
+            .buffer_generic_message(
+                GenericMessage::from_header_and_body(
+                    MessageHeader::new(Some(TimeVal::default()), message_type.0, sender.0),
+                    GenericBody::new(Bytes::from_static(b"data")),
+                ),
+                ClassOfService::RELIABLE,
+            )
+            .unwrap();
+
+        assert!(client.poll_endpoint(&mut dispatcher, &mut cx).is_pending());
+        let cookie = from_client.try_next().unwrap().unwrap();
+        server.receive(&cookie, &mut server_dispatcher).unwrap();
+        assert!(server.is_connected());
+
+        // Frames need not line up with messages.
+        let reply = server.take_outgoing();
+        for chunk in reply.chunks(7) {
+            to_client.unbounded_send(chunk.to_vec()).unwrap();
+        }
+        assert!(client.poll_endpoint(&mut dispatcher, &mut cx).is_pending());
+        assert!(client.is_connected());
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            assert_eq!(
+                received[0].header.sender,
+                dispatcher.get_sender_id("Tracker0").unwrap().0
+            );
+            assert_eq!(
+                received[0].body,
+                GenericBody::new(Bytes::from_static(b"data"))
+            );
+        }
+
+        drop(to_client);
+        assert!(matches!(
+            client.poll_endpoint(&mut dispatcher, &mut cx),
+            Poll::Ready(Ok(()))
+        ));
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Replays inputs that once made (or could make) a decoder panic.
+//!
+//! Every file in `fixtures/fuzz_regressions/` is raw bytes, as a fuzzer writes a crashing input,
+//! and is fed to every public decoder: whole, through the message decoder in small chunks,
+//! and as the body of each message decoded from it.
+//! Decoders may reject the input with an error, but must not panic.
+//!
+//! To keep a fixed crash fixed, copy the input into that directory,
+//! named for what it exercises.
+
+extern crate bytes;
+extern crate vrpn;
+
+use bytes::Bytes;
+use std::{convert::TryFrom, fs, panic, path::PathBuf};
+use vrpn::{
+    buffer_unbuffer::UnbufferFrom,
+    codec::MessageDecoder,
+    data_types::{
+        CookieData, GenericMessage, Message, ProtocolProfile, TypedMessage, TypedMessageBody,
+    },
+    endpoint::parse_system_message,
+    handshake::Handshake,
+    DeviceInfo, ServerInfo,
+};
+
+fn corpus() -> Vec<(PathBuf, Vec<u8>)> {
+    let dir: PathBuf = [env!("CARGO_MANIFEST_DIR"), "fixtures", "fuzz_regressions"]
+        .iter()
+        .collect();
+    let mut files: Vec<_> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("could not read {}: {}", dir.display(), e))
+        .map(|entry| entry.expect("could not read corpus entry").path())
+        .collect();
+    files.sort();
+    files
+        .into_iter()
+        .map(|path| {
+            let input = fs::read(&path).expect("could not read corpus file");
+            (path, input)
+        })
+        .collect()
+}
+
+/// Feed the input to a message decoder in chunks, collecting what it decodes.
+fn decode_messages(input: &[u8], chunk: usize, profile: ProtocolProfile) -> Vec<GenericMessage> {
+    let mut decoder = MessageDecoder::new().with_profile(profile);
+    let mut messages = Vec::new();
+    for piece in input.chunks(chunk) {
+        decoder.extend_from_slice(piece);
+        // Bounded, since the decoder may keep reporting the same error.
+        for _ in 0..input.len() + 1 {
+            match decoder.decode_next() {
+                Ok(Some(msg)) => messages.push(msg.into_inner()),
+                Ok(None) | Err(_) => break,
+            }
+        }
+    }
+    messages
+}
+
+fn body<T: TypedMessageBody + UnbufferFrom>(input: &[u8], messages: &[GenericMessage]) {
+    let _ = T::unbuffer_from(&mut Bytes::copy_from_slice(input));
+    for msg in messages {
+        let _ = TypedMessage::<T>::try_from(msg);
+    }
+}
+
+fn replay(input: &[u8]) {
+    let mut messages = Vec::new();
+    for profile in [
+        ProtocolProfile::VRPN,
+        ProtocolProfile::with_alignment(4).expect("valid alignment"),
+    ] {
+        for chunk in [1, 3, 7, input.len().max(1)] {
+            messages.extend(decode_messages(input, chunk, profile));
+        }
+    }
+    for msg in &messages {
+        if msg.is_system_message() {
+            let _ = parse_system_message(msg.clone());
+        }
+    }
+
+    let _ = CookieData::unbuffer_from(&mut Bytes::copy_from_slice(input));
+    let _ = Handshake::client().advance(input);
+    let _ = Handshake::server().advance(input);
+
+    let text = String::from_utf8_lossy(input);
+    let _ = text.parse::<CookieData>();
+    let _ = text.parse::<ServerInfo>();
+    let _ = text.parse::<DeviceInfo>();
+
+    #[cfg(feature = "analog")]
+    {
+        use vrpn::analog::*;
+        body::<AnalogReport>(input, &messages);
+        body::<ChangeChannelRequest>(input, &messages);
+        body::<ChangeChannelsRequest>(input, &messages);
+        body::<NumChannelsReport>(input, &messages);
+    }
+    #[cfg(feature = "button")]
+    {
+        use vrpn::button::*;
+        body::<ButtonChange>(input, &messages);
+        body::<ButtonStates>(input, &messages);
+    }
+    #[cfg(feature = "metadata")]
+    body::<vrpn::metadata::DeviceMetadata>(input, &messages);
+    #[cfg(feature = "text")]
+    body::<vrpn::text::TextMessage>(input, &messages);
+    #[cfg(feature = "tracker")]
+    {
+        use vrpn::tracker::*;
+        body::<PoseReport>(input, &messages);
+        body::<WorkspaceReport>(input, &messages);
+    }
+}
+
+#[test]
+fn replay_corpus() {
+    let corpus = corpus();
+    assert!(!corpus.is_empty(), "no inputs in the corpus");
+    let panicked: Vec<_> = corpus
+        .iter()
+        .filter(|(_, input)| panic::catch_unwind(|| replay(input)).is_err())
+        .map(|(path, _)| path.display().to_string())
+        .collect();
+    assert!(panicked.is_empty(), "decoders panicked on: {:?}", panicked);
+}
@@ -0,0 +1,238 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Soak test: millions of analog reports from a server to a `ConnectionIp` client,
+//! in-process over loopback TCP, checking that memory in use stays bounded.
+//!
+//! Long-running, so ignored by default. Run it in release mode with
+//! `cargo test --release --features vrpn-async-std,analog --test soak -- --ignored --nocapture`,
+//! and set `VRPN_SOAK_MESSAGES` to change the number of reports (default two million).
+//!
+//! A counting global allocator tracks the bytes allocated and not yet freed by this process.
+//! Once warmed up, that should level off: any steady growth means buffers, queued messages,
+//! or handlers are being kept around. Handlers are added and removed throughout,
+//! so leaking their storage shows up too.
+
+extern crate async_std;
+extern crate vrpn;
+
+use async_std::{net::TcpListener, task};
+use futures::{SinkExt, StreamExt};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    convert::TryFrom,
+    env,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use vrpn::{
+    analog::AnalogReport,
+    data_types::{id_types::SequenceNumber, GenericMessage, TypedMessage, TypedMessageBody},
+    handler::{HandlerCode, TypedHandler},
+    handshake::{futures_io::perform_handshake, Handshake},
+    subscription::{Subscription, SubscriptionState},
+    vrpn_async::AsyncWriteMessagesExt,
+    vrpn_async_std::connection_ip::{ConnectionIp, ConnectionIpEventStream},
+    Connection, Result, ServerInfo, TypeDispatcher, VrpnError,
+};
+
+/// Counts the bytes currently allocated, and the most ever allocated at once.
+struct CountingAllocator;
+
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let live = LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK_BYTES.fetch_max(live, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const DEVICE: &str = "Analog0";
+const CHANNELS: usize = 16;
+/// How much memory in use may grow between the end of warm-up and the end of the run.
+const GROWTH_LIMIT: usize = 4 * 1024 * 1024;
+/// How much memory may be in use at once, above what was in use before starting.
+const PEAK_LIMIT: usize = 64 * 1024 * 1024;
+
+fn live_bytes() -> usize {
+    LIVE_BYTES.load(Ordering::Relaxed)
+}
+
+fn message_count() -> usize {
+    env::var("VRPN_SOAK_MESSAGES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(2_000_000)
+}
+
+#[derive(Debug)]
+struct CountReports {
+    received: Arc<AtomicUsize>,
+}
+
+impl TypedHandler for CountReports {
+    type Item = AnalogReport;
+    fn handle_typed(&mut self, _msg: &TypedMessage<AnalogReport>) -> Result<HandlerCode> {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
+#[derive(Debug)]
+struct Ignore;
+
+impl TypedHandler for Ignore {
+    type Item = AnalogReport;
+    fn handle_typed(&mut self, _msg: &TypedMessage<AnalogReport>) -> Result<HandlerCode> {
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
+/// Accept one client, describe the device, and once told to go,
+/// stream `count` reports as fast as it takes them.
+/// Stays open until `done` is set, so the client sees everything before the connection closes.
+async fn serve(
+    listener: TcpListener,
+    count: usize,
+    go: mpsc::Receiver<()>,
+    done: Arc<AtomicBool>,
+) -> Result<()> {
+    let (mut stream, _) = listener.accept().await?;
+    let mut dispatcher = TypeDispatcher::new();
+    let sender = dispatcher.register_sender(DEVICE)?.into_inner();
+    let message_type = dispatcher.type_id_for(AnalogReport::MESSAGE_IDENTIFIER)?;
+    let mut handshake = Handshake::server().with_descriptions(dispatcher.pack_all_descriptions()?);
+    perform_handshake(&mut stream, &mut handshake).await?;
+    go.recv()
+        .map_err(|e| VrpnError::OtherMessage(e.to_string()))?;
+
+    let mut sink = stream.clone().message_sink();
+    for i in 0..count {
+        let mut values = vec![0.0; CHANNELS];
+        values[0] = i as f64;
+        let msg = TypedMessage::new(None, message_type, sender, AnalogReport { values });
+        let msg = GenericMessage::try_from(msg)?.into_sequenced_message(SequenceNumber(i as u32));
+        sink.feed(msg).await?;
+        if i % 1000 == 999 {
+            sink.flush().await?;
+        }
+    }
+    sink.flush().await?;
+    while !done.load(Ordering::Relaxed) {
+        task::sleep(Duration::from_millis(10)).await;
+    }
+    Ok(())
+}
+
+#[ignore] // because it takes a long time: see the module documentation.
+#[test]
+fn roundtrip_memory_stays_bounded() -> Result<()> {
+    let count = message_count();
+    let warm_up = count / 10;
+    let baseline = live_bytes();
+
+    let listener = task::block_on(TcpListener::bind("127.0.0.1:0"))?;
+    let server_info = format!("tcp://{}", listener.local_addr()?).parse::<ServerInfo>()?;
+    let (go_tx, go) = mpsc::channel();
+    let done = Arc::new(AtomicBool::new(false));
+    let server = {
+        let done = Arc::clone(&done);
+        thread::spawn(move || task::block_on(serve(listener, count, go, done)))
+    };
+
+    let connection = ConnectionIp::new_client(server_info, None, None)?;
+    let sender = connection.register_sender(DEVICE)?;
+    let received = Arc::new(AtomicUsize::new(0));
+    connection.add_typed_handler(
+        Box::new(CountReports {
+            received: Arc::clone(&received),
+        }),
+        Some(sender),
+    )?;
+    let driver =
+        task::spawn(ConnectionIpEventStream::new(Arc::clone(&connection)).for_each(|_| async {}));
+    // Only start once the device has been described.
+    let described = Subscription::<AnalogReport>::new(&connection, DEVICE)?;
+    while described.state() == SubscriptionState::Waiting {
+        thread::sleep(Duration::from_millis(1));
+    }
+    drop(described);
+    go_tx
+        .send(())
+        .map_err(|e| VrpnError::OtherMessage(e.to_string()))?;
+
+    let start = Instant::now();
+    let mut warmed_up = None;
+    let mut last_progress = (0, Instant::now());
+    while received.load(Ordering::Relaxed) < count {
+        // Churn handlers while messages are being dispatched.
+        let handle = connection.add_typed_handler(Box::new(Ignore), Some(sender))?;
+        thread::sleep(Duration::from_millis(1));
+        connection.remove_handler(handle)?;
+
+        let now = received.load(Ordering::Relaxed);
+        if warmed_up.is_none() && now >= warm_up {
+            warmed_up = Some(live_bytes());
+        }
+        if now != last_progress.0 {
+            last_progress = (now, Instant::now());
+        } else if last_progress.1.elapsed() > Duration::from_secs(10) {
+            return Err(VrpnError::OtherMessage(format!(
+                "stalled after receiving {} of {} reports",
+                now, count
+            )));
+        }
+    }
+    let elapsed = start.elapsed();
+    let warmed_up = warmed_up.unwrap_or(baseline);
+    let end = live_bytes();
+
+    done.store(true, Ordering::Relaxed);
+    server
+        .join()
+        .map_err(|_| VrpnError::OtherMessage("server thread panicked".to_string()))??;
+    task::block_on(driver);
+
+    let peak = PEAK_BYTES.load(Ordering::Relaxed);
+    println!(
+        "{} reports in {:.1} s ({:.0}/s); live bytes: {} at start, {} after warm-up, {} at end, \
+         {} at peak",
+        count,
+        elapsed.as_secs_f64(),
+        count as f64 / elapsed.as_secs_f64(),
+        baseline,
+        warmed_up,
+        end,
+        peak
+    );
+    assert!(
+        end.saturating_sub(warmed_up) < GROWTH_LIMIT,
+        "memory in use grew by {} bytes after warm-up",
+        end - warmed_up
+    );
+    assert!(
+        peak.saturating_sub(baseline) < PEAK_LIMIT,
+        "memory in use peaked {} bytes above the start",
+        peak - baseline
+    );
+    Ok(())
+}